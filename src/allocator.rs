@@ -1,9 +1,14 @@
 use spin::RwLock;
+use alloc::format;
+use alloc::sync::Arc;
+use crate::fs;
 use crate::memory;
+use crate::memory::reclaim;
 
 use core::alloc::GlobalAlloc;
 use core::alloc::{Layout, LayoutError};
 use core::alloc::{AllocError, Allocator};
+use core::marker::PhantomData;
 use core::mem;
 use core::mem::{align_of, size_of};
 use core::ops::Deref;
@@ -408,6 +413,18 @@ impl HoleList {
         size_of::<usize>() * 2
     }
 
+    /// Returns the size of the largest hole, the biggest allocation that could possibly succeed.
+    pub fn largest_hole(&self) -> usize {
+        let mut largest = 0;
+        let mut hole = self.first.next;
+        while let Some(h) = hole {
+            let h = unsafe { h.as_ref() };
+            largest = largest.max(h.size);
+            hole = h.next;
+        }
+        largest
+    }
+
     /// Returns information about the first hole for test purposes.
     #[cfg(test)]
     pub fn first_hole(&self) -> Option<(*const u8, usize)> {
@@ -622,16 +639,28 @@ fn deallocate(list: &mut HoleList, addr: *mut u8, size: usize) {
 
 /// A fixed size heap backed by a linked list of free memory blocks.
 pub struct Heap {
+    size: usize,
     used: usize,
     holes: HoleList,
 }
 
+/// How much of the kernel heap is in use, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub total: usize,
+    pub allocated: usize,
+    pub free: usize,
+    /// Free memory can be split up, so this is the largest allocation that could succeed.
+    pub largest_free_block: usize,
+}
+
 unsafe impl Send for Heap {}
 
 impl Heap {
     /// Creates an empty heap. All allocate calls will return `None`.
     pub const fn empty() -> Heap {
         Heap {
+            size: 0,
             used: 0,
             holes: HoleList::empty(),
         }
@@ -666,6 +695,17 @@ impl Heap {
     pub unsafe fn init(&mut self, heap_bottom: *mut u8, heap_size: usize) {
         self.used = 0;
         self.holes = HoleList::new(heap_bottom, heap_size);
+        self.size = self.holes.top as usize - self.holes.bottom as usize;
+    }
+
+    /// Walks the free list to find the largest free block, so this is O(n) in the number of free blocks.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            total: self.size,
+            allocated: self.used,
+            free: self.size - self.used,
+            largest_free_block: self.holes.largest_hole(),
+        }
     }
 
     /// Allocates a chunk of the given size with the given alignment. Returns a pointer to the
@@ -739,13 +779,26 @@ impl Deref for LockedHeap {
 
 unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-	without_interrupts(|| {
-            self.0
-		.lock()
-		.allocate_first_fit(layout)
-		.ok()
-		.map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
-	})
+	let try_allocate = || without_interrupts(|| {
+	    let mut heap = self.0.lock();
+	    heap.allocate_first_fit(layout).map_err(|_| heap.stats())
+	});
+
+	// Caches holding onto heap they could give back are asked to before the allocation's failed. The heap has to be
+	// unlocked first, as they give it back by freeing.
+	let mut allocation = try_allocate();
+	if allocation.is_err() && reclaim::reclaim(layout.size() as u64) > 0 {
+	    allocation = try_allocate();
+	}
+
+	match allocation {
+	    Ok(allocation) => allocation.as_ptr(),
+	    Err(stats) => {
+		// Logged once the heap is unlocked, in case logging needs to allocate
+		log::error!("Kernel heap exhausted allocating {} bytes (align {}): {:?}", layout.size(), layout.align(), stats);
+		core::ptr::null_mut()
+	    },
+	}
     }
 
 	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -780,9 +833,325 @@ pub fn align_up(addr: *mut u8, align: usize) -> *mut u8 {
     addr.wrapping_add(offset)
 }
 
+// Slabs are taken from the heap a page at a time, and aligned to their size, so that the slab an object is in can be
+// found from the object's address
+const SLAB_SIZE: usize = 4096;
+// Empty slabs kept back for a cache to grow into, rather than given back to the heap straight away. The shrinker gives
+// them back when memory is short.
+const MAX_EMPTY_SLABS: usize = 8;
+
+// Sits at the start of each slab, with the objects after it
+struct Slab {
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+    // Slabs with room in them are kept on a list, so allocating never has to look at full ones
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct SlabList {
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    objects: usize,
+    // Of the slabs on the partial list, how many have nothing in them
+    empty: usize,
+}
+
+unsafe impl Send for SlabList {}
+
+impl SlabList {
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+	slab.as_mut().prev = None;
+	slab.as_mut().next = self.partial;
+	if let Some(mut next) = self.partial {
+	    next.as_mut().prev = Some(slab);
+	}
+	self.partial = Some(slab);
+    }
+
+    unsafe fn unlink(&mut self, mut slab: NonNull<Slab>) {
+	let (prev, next) = (slab.as_ref().prev, slab.as_ref().next);
+	match prev {
+	    Some(mut prev) => prev.as_mut().next = next,
+	    None => self.partial = next,
+	}
+	if let Some(mut next) = next {
+	    next.as_mut().prev = prev;
+	}
+
+	slab.as_mut().prev = None;
+	slab.as_mut().next = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub slabs: usize,
+    pub objects: usize,
+    pub objects_per_slab: usize,
+}
+
+/// A cache of objects the size of a `T`, carved out of page sized slabs. Lots of small objects of the same size coming
+/// and going through the heap leave it full of holes too small for anything else, whereas here a freed object's room
+/// only ever goes to another object of the same size, and a slab goes back to the heap whole once it's empty.
+///
+/// Slabs come from the heap rather than straight from the page allocator, as a cache may need to grow while memory's
+/// own locks are held, e.g. whilst forking an address space.
+pub struct SlabCache<T> {
+    slabs: Mutex<SlabList>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = if align_of::<T>() > align_of::<FreeObject>() {
+	align_of::<T>()
+    } else {
+	align_of::<FreeObject>()
+    };
+    const OBJECT_SIZE: usize = if size_of::<T>() > size_of::<FreeObject>() {
+	(size_of::<T>() + Self::OBJECT_ALIGN - 1) & !(Self::OBJECT_ALIGN - 1)
+    } else {
+	size_of::<FreeObject>()
+    };
+    const FIRST_OBJECT: usize = (size_of::<Slab>() + Self::OBJECT_ALIGN - 1) & !(Self::OBJECT_ALIGN - 1);
+    const OBJECTS_PER_SLAB: usize = (SLAB_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+
+    pub const fn new() -> SlabCache<T> {
+	assert!(Self::FIRST_OBJECT + Self::OBJECT_SIZE <= SLAB_SIZE, "Object too big to go in a slab");
+	SlabCache {
+	    slabs: Mutex::new(SlabList {
+		partial: None,
+		slabs: 0,
+		objects: 0,
+		empty: 0,
+	    }),
+	    _marker: PhantomData,
+	}
+    }
+
+    fn slab_layout() -> Layout {
+	Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+    }
+
+    fn new_slab() -> Option<NonNull<Slab>> {
+	let slab = NonNull::new(unsafe { ALLOCATOR.alloc(Self::slab_layout()) })?.cast::<Slab>();
+	let base = slab.as_ptr() as usize;
+
+	// Threaded back to front, so that objects are handed out in address order
+	let mut free = None;
+	for i in (0 .. Self::OBJECTS_PER_SLAB).rev() {
+	    let object = (base + Self::FIRST_OBJECT + i * Self::OBJECT_SIZE) as *mut FreeObject;
+	    unsafe {
+		object.write(FreeObject { next: free });
+	    }
+	    free = NonNull::new(object);
+	}
+
+	unsafe {
+	    slab.as_ptr().write(Slab {
+		free,
+		in_use: 0,
+		prev: None,
+		next: None,
+	    });
+	}
+	Some(slab)
+    }
+
+    /// Room for one `T`, uninitialised. Only fails if the heap has no room for another slab.
+    pub fn allocate_object(&self) -> Option<NonNull<T>> {
+	without_interrupts(|| {
+	    let mut list = self.slabs.lock();
+	    let mut slab = match list.partial {
+		Some(slab) => slab,
+		None => {
+		    let slab = Self::new_slab()?;
+		    unsafe {
+			list.push(slab);
+		    }
+		    list.slabs += 1;
+		    list.empty += 1;
+		    slab
+		},
+	    };
+
+	    // Every slab on the partial list has at least one free object
+	    let object = unsafe {
+		if slab.as_ref().in_use == 0 {
+		    list.empty -= 1;
+		}
+		let object = slab.as_ref().free.unwrap();
+		slab.as_mut().free = object.as_ref().next;
+		slab.as_mut().in_use += 1;
+		if slab.as_ref().free.is_none() {
+		    list.unlink(slab);
+		}
+		object
+	    };
+
+	    list.objects += 1;
+	    Some(object.cast())
+	})
+    }
+
+    /// # Safety
+    ///
+    /// `object` must have come from [`allocate_object`][Self::allocate_object] on this cache, and must not be used
+    /// again. Whatever was in it isn't dropped.
+    pub unsafe fn free_object(&self, object: NonNull<T>) {
+	without_interrupts(|| unsafe {
+	    let mut list = self.slabs.lock();
+	    let mut slab = NonNull::new_unchecked((object.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut Slab);
+	    let was_full = slab.as_ref().free.is_none();
+
+	    let free = object.cast::<FreeObject>();
+	    free.as_ptr().write(FreeObject { next: slab.as_ref().free });
+	    slab.as_mut().free = Some(free);
+	    slab.as_mut().in_use -= 1;
+	    list.objects -= 1;
+
+	    if was_full {
+		list.push(slab);
+	    }
+
+	    // A few empty slabs are kept, so that a cache going back and forth around a slab boundary doesn't keep going
+	    // to the heap
+	    if slab.as_ref().in_use == 0 {
+		if list.empty < MAX_EMPTY_SLABS {
+		    list.empty += 1;
+		} else {
+		    list.unlink(slab);
+		    list.slabs -= 1;
+		    ALLOCATOR.dealloc(slab.as_ptr().cast(), Self::slab_layout());
+		}
+	    }
+	})
+    }
+
+    /// Gives every empty slab back to the heap, returning how many there were.
+    pub fn release_empty_slabs(&self) -> usize {
+	without_interrupts(|| unsafe {
+	    // Reclaim may have been reached from an allocation made with the cache locked
+	    let mut list = match self.slabs.try_lock() {
+		Some(list) => list,
+		None => return 0,
+	    };
+
+	    let mut released = 0;
+	    let mut next = list.partial;
+	    while let Some(slab) = next {
+		next = slab.as_ref().next;
+		if slab.as_ref().in_use == 0 {
+		    list.unlink(slab);
+		    list.slabs -= 1;
+		    list.empty -= 1;
+		    ALLOCATOR.dealloc(slab.as_ptr().cast(), Self::slab_layout());
+		    released += 1;
+		}
+	    }
+	    released
+	})
+    }
+
+    fn fits(layout: Layout) -> bool {
+	layout.size() <= Self::OBJECT_SIZE && layout.align() <= Self::OBJECT_ALIGN
+    }
+
+    pub fn stats(&self) -> SlabStats {
+	without_interrupts(|| {
+	    let list = self.slabs.lock();
+	    SlabStats {
+		slabs: list.slabs,
+		objects: list.objects,
+		objects_per_slab: Self::OBJECTS_PER_SLAB,
+	    }
+	})
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+// Lets a collection keep its nodes in slabs, given a T the size of a node. Anything that doesn't fit, such as a Vec's
+// buffer once it's grown, comes from the heap as usual.
+unsafe impl<T> Allocator for &'static SlabCache<T> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+	if layout.size() == 0 {
+	    return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
+	}
+
+	let ptr = if SlabCache::<T>::fits(layout) {
+	    self.allocate_object().map(|object| object.cast::<u8>())
+	} else {
+	    NonNull::new(unsafe { ALLOCATOR.alloc(layout) })
+	};
+
+	ptr.map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+	    .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+	if layout.size() == 0 {
+	    return;
+	}
+
+	if SlabCache::<T>::fits(layout) {
+	    self.free_object(ptr.cast());
+	} else {
+	    ALLOCATOR.dealloc(ptr.as_ptr(), layout);
+	}
+    }
+}
+
+// Slabs go back to the heap, a page at a time
+struct SlabShrinker<T: 'static> {
+    name: &'static str,
+    cache: &'static SlabCache<T>,
+}
+
+impl<T: 'static> reclaim::Shrinker for SlabShrinker<T> where SlabCache<T>: Sync {
+    fn name(&self) -> &str {
+	self.name
+    }
+
+    fn shrink(&self, _target: u64) -> u64 {
+	(self.cache.release_empty_slabs() * SLAB_SIZE) as u64
+    }
+}
+
 #[global_allocator]
 pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+pub fn stats() -> HeapStats {
+    without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+pub fn init_sysfs() {
+    fs::sysfs::add_attribute("/kernel/heap", || {
+	let stats = stats();
+	format!("total {}\nallocated {}\nfree {}\nlargest_free_block {}\n",
+		stats.total, stats.allocated, stats.free, stats.largest_free_block)
+    });
+    fs::sysfs::add_attribute("/kernel/slab/shadow_map", || {
+	let stats = memory::user_address_space::SHADOW_MAP_CACHE.stats();
+	format!("slabs {}\nobjects {}\nobjects_per_slab {}\n", stats.slabs, stats.objects, stats.objects_per_slab)
+    });
+}
+
+pub fn register_shrinkers() {
+    reclaim::register_shrinker(Arc::new(SlabShrinker {
+	name: "shadow map slab cache",
+	cache: &memory::user_address_space::SHADOW_MAP_CACHE,
+    }));
+}
+
 pub fn init() {
     let mut w = KERNEL_HEAP_START.write();
     *w = memory::kernel_allocate_early(KERNEL_HEAP_SIZE as u64)
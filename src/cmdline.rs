@@ -0,0 +1,42 @@
+use spin::Once;
+
+use crate::sys::loglevel;
+
+// Left where the bootloader put it. Bootloader reclaimable memory is never reclaimed, so it's there for good, and
+// nothing here needs the heap, so options can be read from the very start of boot.
+static CMDLINE: Once<&'static str> = Once::new();
+
+// What the kernel logs unless told otherwise
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Trace;
+
+pub fn init(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline);
+}
+
+// Options are separated by whitespace, and are either key=value, or just key, which gives an empty value. If an
+// option is given more than once, the last one wins.
+pub fn get(key: &str) -> Option<&'static str> {
+    CMDLINE.get()?
+	.split_whitespace()
+	.filter_map(|option| match option.split_once('=') {
+	    Some((k, v)) => (k == key).then_some(v),
+	    None => (option == key).then_some(""),
+	})
+	.last()
+}
+
+// Sets how much is logged from loglevel=, which takes a level as /dev/loglevel does. Needs the logger to be set
+// already, to be able to complain about a level it doesn't know.
+pub fn set_log_level() {
+    log::set_max_level(DEFAULT_LOG_LEVEL);
+
+    let level = match get("loglevel") {
+	Some(level) => level,
+	None => return,
+    };
+
+    match loglevel::parse_level(level) {
+	Some(filter) => log::set_max_level(filter),
+	None => log::warn!("Unknown log level {}, using {}", level, DEFAULT_LOG_LEVEL),
+    }
+}
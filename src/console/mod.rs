@@ -1,22 +1,28 @@
+use crate::cmdline;
 use crate::driver;
 use crate::memory;
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
 use spin::{Once, RwLock};
-use bytes::{Bytes, BytesMut, BufMut};
+use bytes::{BytesMut, BufMut};
 use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::ffi::{c_int, c_uint};
 use core::task::Context;
 use core::task::Poll;
 use alloc::boxed::Box;
 use futures_util::future::BoxFuture;
-use x86_64::VirtAddr;
+use core::mem;
 use core::task::Waker;
 use core::future::poll_fn;
 use futures_util::FutureExt;
 use spin::Mutex;
 
 use crate::sys::ioctl;
+use crate::scheduler;
+use crate::scheduler::signal;
 use crate::sys::syscall::{CanonicalError, PollEvents};
 use crate::vfs;
 
@@ -32,12 +38,38 @@ struct Termios {
     obaud: c_uint,
 }
 
+// struct winsize, as TIOCGWINSZ and TIOCSWINSZ pass it
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct WinSize {
+    row: u16,
+    col: u16,
+    xpixel: u16,
+    ypixel: u16,
+}
+
+const NUM_VTS: usize = 4;
+
+// Enough to redraw a full screen or two of text when switching back to a VT
+const SCROLLBACK_SIZE: usize = 16 * 1024;
+
 // Although strictly speaking a subsystem, not a device, this is implemented as a device. This allows us to make use of devfs,
 // allowing for reading and writing from usermode.
+//
+// There is one of these per virtual terminal. Each has its own input buffer, termios state and foreground group, but they
+// share the framebuffer, which only the active VT draws to.
 pub struct ConsoleDevice {
+    vt: usize,
+    scrollback: RwLock<BytesMut>,
+
     pub key_buffer: RwLock<BytesMut>,
     pgrp: RwLock<u64>,
 
+    // Set by TIOCSWINSZ. Until then, the size is taken from the framebuffer console
+    winsize: RwLock<Option<WinSize>>,
+    // The size the foreground group last knew of, so it can be sent SIGWINCH when that changes
+    last_winsize: RwLock<WinSize>,
+
     // Input flags
     crnl: RwLock<bool>,
     nlcr: RwLock<bool>,
@@ -47,6 +79,7 @@ pub struct ConsoleDevice {
     pub local_loopback: RwLock<bool>,
 
     read_waker: RwLock<Option<Waker>>,
+    epoll_watchers: vfs::epoll::EpollWatchers,
 
     fsi: Mutex<vfs::filesystem::FileSystemInstance>,
 }
@@ -54,6 +87,81 @@ unsafe impl Send for ConsoleDevice { }
 unsafe impl Sync for ConsoleDevice { }
 
 impl ConsoleDevice {
+    // winsize is the size the foreground group is taken to already know of
+    fn new(vt: usize, winsize: WinSize) -> Self {
+	ConsoleDevice {
+	    vt,
+	    scrollback: RwLock::new(BytesMut::new()),
+	    key_buffer: RwLock::new(BytesMut::new()),
+	    local_loopback: RwLock::new(true),
+	    pgrp: RwLock::new(0),
+	    winsize: RwLock::new(None),
+	    last_winsize: RwLock::new(winsize),
+	    canonical: RwLock::new(true),
+	    crnl: RwLock::new(false),
+	    nlcr: RwLock::new(false),
+	    read_waker: RwLock::new(None),
+	    epoll_watchers: vfs::epoll::EpollWatchers::new(),
+	    fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
+	}
+    }
+
+    fn is_active(&self) -> bool {
+	ACTIVE_VT.load(Ordering::SeqCst) == self.vt
+    }
+
+    // Records output in the scrollback, and draws it if this is the VT currently on screen
+    fn output(&self, s: &str) {
+	{
+	    let mut scrollback = self.scrollback.write();
+	    scrollback.extend_from_slice(s.as_bytes());
+
+	    if scrollback.len() > SCROLLBACK_SIZE {
+		// Trim on a character boundary, so that the replay is still valid UTF-8
+		let mut excess = scrollback.len() - SCROLLBACK_SIZE;
+		while excess < scrollback.len() && (scrollback[excess] & 0xC0) == 0x80 {
+		    excess += 1;
+		}
+		let _ = scrollback.split_to(excess);
+	    }
+	}
+
+	if self.is_active() {
+	    let printk = crate::PRINTK.get().expect("Unable to get printk");
+	    printk.write_str(s);
+	    self.check_winsize();
+	}
+    }
+
+    fn redraw(&self) {
+	let printk = crate::PRINTK.get().expect("Unable to get printk");
+	let scrollback = self.scrollback.read();
+
+	printk.clear();
+	printk.write_str(str::from_utf8(&scrollback).unwrap_or(""));
+    }
+
+    fn get_winsize(&self) -> WinSize {
+	match *self.winsize.read() {
+	    Some(winsize) => winsize,
+	    None => framebuffer_winsize(),
+	}
+    }
+
+    // The foreground group, if the size has changed since it was last told, as it then needs sending SIGWINCH
+    fn winsize_changed(&self) -> Option<u64> {
+	let winsize = self.get_winsize();
+	let last_winsize = mem::replace(&mut *self.last_winsize.write(), winsize);
+
+	(winsize != last_winsize).then(|| *self.pgrp.read())
+    }
+
+    fn check_winsize(&self) {
+	if let Some(pgrp) = self.winsize_changed() {
+	    scheduler::signal_process_group(pgrp, signal::SIGWINCH);
+	}
+    }
+
     fn register_key(&self, k: char) {
 	{
 	    let mut key_buffer = self.key_buffer.write();
@@ -62,15 +170,18 @@ impl ConsoleDevice {
 
 	let local_loopback = self.local_loopback.read();
 	if *local_loopback {
-	    let printk = crate::PRINTK.get().expect("Unable to get printk");
-	    printk.write_char(k);
+	    let mut buf = [0u8; 4];
+	    self.output(k.encode_utf8(&mut buf));
 	}
 
-	let mut read_waker = self.read_waker.write();
-	if let Some(waker) = read_waker.take() {
-	    waker.wake();
-	    *read_waker = None;
+	{
+	    let mut read_waker = self.read_waker.write();
+	    if let Some(waker) = read_waker.take() {
+		waker.wake();
+		*read_waker = None;
+	    }
 	}
+	self.epoll_watchers.notify();
     }
 }
 
@@ -80,11 +191,18 @@ impl vfs::filesystem::VNode for ConsoleDevice {
     }
     
     fn kind(&self) -> vfs::filesystem::VNodeKind {
-	vfs::filesystem::VNodeKind::Directory
+	vfs::filesystem::VNodeKind::CharDevice
     }
 	
     fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
-	Err(CanonicalError::Inval)
+	Ok(vfs::filesystem::Stat {
+	    file_name: format!("tty{}", self.vt + 1),
+	    size: None,
+	    inode: 0,
+	    kind: vfs::filesystem::VNodeKind::CharDevice,
+	    mode: 0o620,
+	    modified: None,
+	})
     }
 
     fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
@@ -103,6 +221,10 @@ impl vfs::filesystem::VNode for ConsoleDevice {
 	unimplemented!();
     }
 
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
     fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
 	*(self.fsi.lock()) = fsi;
     }
@@ -159,8 +281,7 @@ impl vfs::filesystem::FileHandle for ConsoleDevice {
 	async move {
 	    // TODO - this should handle ONLCR and doesn't.
 	    // At present, due to the way printk works, all \n implies \r, which isn't correct
-	    let printk = crate::PRINTK.get().expect("Unable to get printk");
-	    printk.write_str(str::from_utf8(&buf).map_err(|_| CanonicalError::Inval)?);
+	    self.output(str::from_utf8(&buf).map_err(|_| CanonicalError::Inval)?);
 
 	    Ok(buf.len() as u64)
 	}.boxed()
@@ -204,18 +325,19 @@ impl vfs::filesystem::FileHandle for ConsoleDevice {
     }
 
     fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
-	unimplemented!()
+	vfs::filesystem::VNode::stat(&*self)
     }
 
-    fn ioctl(self: Arc<Self>, ioctl: ioctl::IoCtl, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+    fn ioctl(self: Arc<Self>, request: u64, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
 	async move {
-	    match ioctl {
-		ioctl::IoCtl::TCGETS => {
+	    match request {
+		ioctl::TCGETS => {
 		    // For now, we'll stub this out
 		    Ok(0)
 		},
-		ioctl::IoCtl::TCSETS => {
-		    let termios = memory::copy_value_from_user::<Termios>(VirtAddr::new(arg)).unwrap();
+		ioctl::TCSETS => {
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<Termios>() as u64)?;
+		    let termios = memory::copy_value_from_user::<Termios>(arg).map_err(|_| CanonicalError::Fault)?;
 
 		    // Input flags
 		    let mut crnl = self.crnl.write();
@@ -231,26 +353,34 @@ impl vfs::filesystem::FileHandle for ConsoleDevice {
 
 		    Ok(0)
 		},
-		ioctl::IoCtl::TIOCGWINSZ => {
-		    let printk = crate::PRINTK.get().expect("Unable to get printk");
-
-		    let read_buf = Bytes::copy_from_slice(&[
-			printk.get_rows(),
-			printk.get_cols(),
-			0, 0]);
-		    memory::copy_to_user(VirtAddr::new(arg), read_buf.as_ref()).unwrap();
+		ioctl::TIOCGWINSZ => {
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<WinSize>() as u64)?;
+		    memory::copy_value_to_user::<WinSize>(arg, &self.get_winsize()).map_err(|_| CanonicalError::Fault)?;
+		    Ok(0)
+		},
+		ioctl::TIOCSWINSZ => {
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<WinSize>() as u64)?;
+		    let new_winsize = memory::copy_value_from_user::<WinSize>(arg)
+			.map_err(|_| CanonicalError::Fault)?;
+
+		    *self.winsize.write() = Some(new_winsize);
+		    // Only notifies the foreground group if something actually changed
+		    self.check_winsize();
+
 		    Ok(0)
 		},
-		ioctl::IoCtl::TIOCGPGRP => {
+		ioctl::TIOCGPGRP => {
 		    let pgrp = self.pgrp.read();
 		    Ok(*pgrp)
 		},
-		ioctl::IoCtl::TIOCSPGRP => {
-		    let mut pgrp = self.pgrp.write();
-		    *pgrp = memory::copy_value_from_user::<c_int>(VirtAddr::new(arg)).unwrap() as u64;
+		ioctl::TIOCSPGRP => {
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<c_int>() as u64)?;
+		    let new_pgrp = memory::copy_value_from_user::<c_int>(arg).map_err(|_| CanonicalError::Fault)?;
+		    *self.pgrp.write() = new_pgrp as u64;
 
 		    Ok(0)
 		},
+		_ => Err(CanonicalError::NoTty),
 	    }
 	}.boxed()
     }
@@ -258,25 +388,163 @@ impl vfs::filesystem::FileHandle for ConsoleDevice {
     fn seek(&self, _offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
 	Err(CanonicalError::SPipe)
     }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	// Truncating a terminal is a no-op
+	async move {
+	    Ok(())
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+
+    fn epoll_watchers(&self) -> Option<&vfs::epoll::EpollWatchers> {
+	Some(&self.epoll_watchers)
+    }
 }
 
-static CONSOLE: Once<Arc<ConsoleDevice>> = Once::new();
+// The size of the framebuffer console, in characters and in pixels
+fn framebuffer_winsize() -> WinSize {
+    let printk = crate::PRINTK.get().expect("Unable to get printk");
+    WinSize {
+	row: printk.get_rows() as u16,
+	col: printk.get_cols() as u16,
+	xpixel: printk.get_width() as u16,
+	ypixel: printk.get_height() as u16,
+    }
+}
+
+static CONSOLES: Once<Vec<Arc<ConsoleDevice>>> = Once::new();
+static ACTIVE_VT: AtomicUsize = AtomicUsize::new(0);
 
 pub fn init() {
-    let device = Arc::new(ConsoleDevice {
-	key_buffer: RwLock::new(BytesMut::new()),
-	local_loopback: RwLock::new(true),
-	pgrp: RwLock::new(0),
-	canonical: RwLock::new(true),
-	crnl: RwLock::new(false),
-	nlcr: RwLock::new(false),
-	read_waker: RwLock::new(None),
-	fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
-    });
-    CONSOLE.call_once(|| device.clone());
-    driver::register_devfs(String::from("console"), device);
+    let consoles = (0 .. NUM_VTS)
+	.map(|vt| Arc::new(ConsoleDevice::new(vt, framebuffer_winsize())))
+	.collect::<Vec<Arc<ConsoleDevice>>>();
+    CONSOLES.call_once(|| consoles.clone());
+
+    // The system console is the first VT, unless console= names another, e.g. tty2
+    let system_vt = match cmdline::get("console") {
+	Some(console) => match console.strip_prefix("tty").and_then(|vt| vt.parse::<usize>().ok()) {
+	    Some(vt) if (1 ..= NUM_VTS).contains(&vt) => vt - 1,
+	    _ => {
+		log::warn!("No console {}, using tty1", console);
+		0
+	    },
+	},
+	None => 0,
+    };
+    driver::register_devfs(String::from("console"), consoles[system_vt].clone());
+    for (vt, console) in consoles.into_iter().enumerate() {
+	driver::register_devfs(format!("tty{}", vt + 1), console);
+    }
 }
 
+// Keypresses always go to whichever VT is currently on screen
 pub fn register_keypress(k: char) {
-    CONSOLE.get().expect("Attempted to register keypress before Console subsystem initialised").register_key(k);
+    let consoles = CONSOLES.get().expect("Attempted to register keypress before Console subsystem initialised");
+    consoles[ACTIVE_VT.load(Ordering::SeqCst)].register_key(k);
+}
+
+// Makes vt the one on screen and taking keypresses, returning it unless it already was
+fn activate_vt(vt: usize) -> Option<&'static Arc<ConsoleDevice>> {
+    let consoles = CONSOLES.get().expect("Attempted to switch VT before Console subsystem initialised");
+    if vt >= consoles.len() || ACTIVE_VT.swap(vt, Ordering::SeqCst) == vt {
+	return None;
+    }
+
+    Some(&consoles[vt])
+}
+
+pub fn switch_vt(vt: usize) {
+    let Some(console) = activate_vt(vt) else {
+	return;
+    };
+
+    console.redraw();
+
+    // Replaying the scrollback can't bring back what a full-screen program had drawn, but they all redraw on SIGWINCH
+    *console.last_winsize.write() = console.get_winsize();
+    scheduler::signal_process_group(*console.pgrp.read(), signal::SIGWINCH);
+}
+
+#[test]
+fn winsize_matches_struct_winsize() {
+    let winsize = WinSize {
+	row: 0x0102,
+	col: 0x0304,
+	xpixel: 0x0506,
+	ypixel: 0x0708,
+    };
+
+    let bytes = unsafe { mem::transmute::<WinSize, [u8; 8]>(winsize) };
+    assert_eq!(bytes, [0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 0x08, 0x07]);
+}
+
+#[test]
+fn a_resize_is_reported_to_the_foreground_group_once() {
+    let size = |row, col| WinSize {
+	row,
+	col,
+	xpixel: col * 8,
+	ypixel: row * 16,
+    };
+    let console = ConsoleDevice::new(0, size(25, 80));
+    *console.pgrp.write() = 0x1961;
+
+    // As TIOCSWINSZ sets it
+    *console.winsize.write() = Some(size(25, 80));
+    assert_eq!(console.winsize_changed(), None);
+
+    *console.winsize.write() = Some(size(50, 132));
+    assert_eq!(console.winsize_changed(), Some(0x1961));
+    assert_eq!(console.winsize_changed(), None);
+
+    // Whoever is in the foreground when it changes is told, not whoever was when it was last set
+    *console.pgrp.write() = 0x1962;
+    *console.winsize.write() = Some(size(25, 80));
+    assert_eq!(console.winsize_changed(), Some(0x1962));
+}
+
+#[test]
+fn each_vt_keeps_its_own_input_and_scrollback() {
+    let size = WinSize {
+	row: 25,
+	col: 80,
+	xpixel: 640,
+	ypixel: 400,
+    };
+    let consoles = CONSOLES.call_once(|| (0 .. NUM_VTS)
+	.map(|vt| Arc::new(ConsoleDevice::new(vt, size)))
+	.collect());
+    // With echo off, as otherwise typing would draw to the framebuffer
+    for console in consoles {
+	*console.local_loopback.write() = false;
+    }
+
+    assert!(activate_vt(1).is_some());
+    assert!(activate_vt(1).is_none());
+    register_keypress('a');
+    assert_eq!(&consoles[1].key_buffer.read()[..], b"a");
+    assert!(consoles[0].key_buffer.read().is_empty());
+
+    // Only the VT on screen draws, but those in the background still keep what's written to them
+    consoles[0].output("first");
+    consoles[2].output("third");
+    assert_eq!(&consoles[0].scrollback.read()[..], b"first");
+    assert_eq!(&consoles[2].scrollback.read()[..], b"third");
+    assert!(consoles[1].scrollback.read().is_empty());
+
+    assert!(activate_vt(2).is_some());
+    register_keypress('b');
+    assert_eq!(&consoles[1].key_buffer.read()[..], b"a");
+    assert_eq!(&consoles[2].key_buffer.read()[..], b"b");
+
+    // Out of range is ignored
+    assert!(activate_vt(NUM_VTS).is_none());
+    assert_eq!(ACTIVE_VT.load(Ordering::SeqCst), 2);
 }
@@ -8,14 +8,24 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use futures_util::future::BoxFuture;
 use futures_util::FutureExt;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::sys::acpi;
-use crate::sys::syscall::CanonicalError;
+use crate::sys::syscall::{CanonicalError, PollEvents};
 use crate::vfs;
+use crate::vfs::filesystem::VNode;
 use crate::sys::syscall::SyscallResult;
 
+// Returned by Driver::init. A driver which needs something that isn't up yet defers, and the device is
+// offered to the drivers again whenever a driver is registered or another device binds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    Bound,
+    Defer,
+}
+
 pub trait Driver {
-    fn init(&self, info: &dyn DeviceTypeIdentifier);
+    fn init(&self, info: &dyn DeviceTypeIdentifier) -> ProbeResult;
     fn check_device(&self, info: &dyn DeviceTypeIdentifier) -> bool;
     fn check_new_device(&self, info: &dyn DeviceTypeIdentifier) -> bool;
 }
@@ -48,7 +58,10 @@ impl vfs::filesystem::VNode for DevFSRootVNode {
     }
 
     fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
-	unimplemented!();
+	Ok(Arc::new(DevFSRootHandle {
+	    root: self.clone(),
+	    cookie: AtomicU64::new(0),
+	}))
     }
     
     fn filesystem(&self) -> Arc<dyn vfs::filesystem::FileSystem> {
@@ -63,23 +76,94 @@ impl vfs::filesystem::VNode for DevFSRootVNode {
 	unimplemented!();
     }
 
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
     fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
 	*(self.fsi.lock()) = fsi;
     }
 }
 
+struct DevFSRootHandle {
+    root: Arc<DevFSRootVNode>,
+    cookie: AtomicU64,
+}
+impl vfs::filesystem::FileHandle for DevFSRootHandle {
+    fn read(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	self.root.stat()
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
+	match offset {
+	    vfs::filesystem::SeekFrom::Set(n) if n >= 0 => {
+		self.cookie.store(n as u64, Ordering::SeqCst);
+		Ok(n as u64)
+	    },
+	    _ => Err(CanonicalError::Inval),
+	}
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	let root: Arc<dyn vfs::filesystem::VNode> = self.root.clone();
+	self.root.fs.clone().readdir(self.root.fsi(), &root, self.cookie.load(Ordering::SeqCst))
+    }
+}
+
+struct DevFSEntry {
+    // Assigned in order of registration and never reused, so makes for a stable readdir cookie
+    id: u64,
+    vnode: Arc<dyn vfs::filesystem::VNode>,
+}
+
 pub struct DevFS {
-    file_table: RwLock<BTreeMap<String, Arc<dyn vfs::filesystem::VNode>>>,
+    file_table: RwLock<BTreeMap<String, DevFSEntry>>,
+    next_id: AtomicU64,
 }
 impl DevFS {
     pub fn new() -> DevFS {
 	DevFS {
-	    file_table: RwLock::new(BTreeMap::new())
+	    file_table: RwLock::new(BTreeMap::new()),
+	    next_id: AtomicU64::new(1),
 	}
     }
 
     pub fn add_device(&self, vnode: Arc<dyn vfs::filesystem::VNode>, mount: String) {
-	self.file_table.write().insert(mount, vnode);
+	let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+	self.file_table.write().insert(mount, DevFSEntry {
+	    id,
+	    vnode,
+	});
     }
 }
 impl vfs::filesystem::FileSystem for DevFS {
@@ -93,7 +177,7 @@ impl vfs::filesystem::FileSystem for DevFS {
     fn lookup(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance, _parent: &Arc<dyn vfs::filesystem::VNode>, name: &str) -> BoxFuture<'static, Result<Arc<dyn vfs::filesystem::VNode>, CanonicalError>> {
 	let device_vnode = {
 	    match self.file_table.read().get(name) {
-		Some(vnode) => vnode.clone(),
+		Some(entry) => entry.vnode.clone(),
 		None => return async move {
 		    Err(CanonicalError::Access)
 		}.boxed(),
@@ -106,11 +190,32 @@ impl vfs::filesystem::FileSystem for DevFS {
 	    Ok(device_vnode)
 	}.boxed()
     }
+
+    fn readdir(self: Arc<Self>, _fsi: vfs::filesystem::FileSystemInstance, _dir: &Arc<dyn vfs::filesystem::VNode>, cookie: u64) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	let mut entries = self.file_table.read().iter()
+	    .filter(|(_, entry)| entry.id > cookie)
+	    .map(|(name, entry)| vfs::filesystem::DirEntry {
+		inode: entry.vnode.inode(),
+		kind: entry.vnode.kind(),
+		name: name.clone(),
+		cookie: entry.id,
+	    })
+	    .collect::<Vec<vfs::filesystem::DirEntry>>();
+	entries.sort_by_key(|e| e.cookie);
+
+	async move {
+	    Ok(entries)
+	}.boxed()
+    }
 }
 
-static DRIVER_TABLE: Once<RwLock<Vec<Box<dyn Driver + Send + Sync>>>> = Once::new();
+static DRIVER_TABLE: Once<RwLock<Vec<Arc<dyn Driver + Send + Sync>>>> = Once::new();
 static BUS_TABLE: Once<RwLock<Vec<Arc<Mutex<dyn Bus + Send + Sync>>>>> = Once::new();
 static DEVFS: Once<Arc<DevFS>> = Once::new();
+// Devices no driver has bound yet, either because none matched or because the driver deferred
+static PENDING_DEVICES: Mutex<Vec<PendingDevice>> = Mutex::new(Vec::new());
+// Run at shutdown to stop devices, DMA in particular, before the machine is powered off or reset
+static SHUTDOWN_HOOKS: Mutex<Vec<Box<dyn Fn() + Send + Sync>>> = Mutex::new(Vec::new());
 
 pub fn init() {
     DRIVER_TABLE.call_once(|| RwLock::new(Vec::new()));
@@ -139,8 +244,24 @@ pub fn get_devfs() -> Arc<DevFS> {
 }
 
 pub fn register_driver(driver: Box<dyn Driver + Send + Sync>) {
-    let mut driver_table = DRIVER_TABLE.get().expect("Driver table is not yet initialised").write();
-    driver_table.push(driver);
+    {
+	let mut driver_table = DRIVER_TABLE.get().expect("Driver table is not yet initialised").write();
+	driver_table.push(Arc::from(driver));
+    }
+
+    retry_pending_devices();
+}
+
+pub fn register_shutdown_hook(hook: Box<dyn Fn() + Send + Sync>) {
+    SHUTDOWN_HOOKS.lock().push(hook);
+}
+
+// Devices are stopped in the reverse of the order they were brought up in, so buses go after what's on them
+pub fn shutdown_devices() {
+    let hooks = SHUTDOWN_HOOKS.lock();
+    for hook in hooks.iter().rev() {
+	hook();
+    }
 }
 
 pub fn register_bus_and_enumerate(bus: Arc<Mutex<dyn Bus + Send + Sync>>) {
@@ -149,21 +270,8 @@ pub fn register_bus_and_enumerate(bus: Arc<Mutex<dyn Bus + Send + Sync>>) {
 	locked_bus.enumerate()
     };
 
-    for found_device in enumerated_bus_devices.iter() {
-	let driver_tbl = DRIVER_TABLE.get().expect("Attempted to access driver table before it is initialised").read();
-
-	let driver = match driver_tbl.iter()
-	    .find(|d| d.check_device(found_device.as_ref()) &&
-		  d.check_new_device(found_device.as_ref())) {
-		Some(d) => d,
-		None => {
-//		    log::info!("No driver installed or attempted to init twice for {}", found_device);
-		    continue;
-		},
-	    };
-
-	log::info!("Found new device {}", found_device);
-	driver.init(found_device.as_ref());
+    for found_device in enumerated_bus_devices.into_iter() {
+	enumerate_device(found_device);
     }
 
     let mut bus_tbl = BUS_TABLE.get().expect("Attempted to access bus table before it is initialised").write();
@@ -171,18 +279,160 @@ pub fn register_bus_and_enumerate(bus: Arc<Mutex<dyn Bus + Send + Sync>>) {
 }
 
 pub fn enumerate_device(device_identifier: Box<dyn DeviceTypeIdentifier>) {
-    let driver_tbl = DRIVER_TABLE.get().expect("Attempted to access driver table before it is initialised").read();
-
-    let driver = match driver_tbl.iter()
-	.find(|d| d.check_device(device_identifier.as_ref()) &&
-	      d.check_new_device(device_identifier.as_ref())) {
-	    Some(d) => d,
-	    None => {
-//		log::info!("No driver installed or attempted to init twice for {}", device_identifier);
-		return;
-	    },
+    if probe_device(device_identifier) {
+	retry_pending_devices();
+    }
+}
+
+// Device identifiers are only ever handed from one probe to the next, never shared
+struct PendingDevice(Box<dyn DeviceTypeIdentifier>);
+unsafe impl Send for PendingDevice {}
+
+// Returns true if a driver bound the device. Otherwise, it's kept to be offered again later
+fn probe_device(device_identifier: Box<dyn DeviceTypeIdentifier>) -> bool {
+    let driver = {
+	let driver_tbl = DRIVER_TABLE.get().expect("Attempted to access driver table before it is initialised").read();
+
+	driver_tbl.iter()
+	    .find(|d| d.check_device(device_identifier.as_ref()) &&
+		  d.check_new_device(device_identifier.as_ref()))
+	    .cloned()
+    };
+
+    // The table isn't held while the driver starts, as starting it may register more drivers
+    let result = driver.map(|driver| {
+	log::info!("Found new device {}", device_identifier);
+	driver.init(device_identifier.as_ref())
+    });
+
+    match result {
+	Some(ProbeResult::Bound) => true,
+	Some(ProbeResult::Defer) => {
+	    log::info!("Deferring probe of {}", device_identifier);
+	    PENDING_DEVICES.lock().push(PendingDevice(device_identifier));
+	    false
+	},
+	None => {
+	    PENDING_DEVICES.lock().push(PendingDevice(device_identifier));
+	    false
+	},
+    }
+}
+
+// Keeps going until a pass binds nothing, as each device that binds might be what another was waiting on
+fn retry_pending_devices() {
+    loop {
+	let pending = core::mem::take(&mut *PENDING_DEVICES.lock());
+	if pending.is_empty() {
+	    return;
+	}
+
+	let mut any_bound = false;
+	for PendingDevice(device_identifier) in pending {
+	    any_bound |= probe_device(device_identifier);
+	}
+
+	if !any_bound {
+	    return;
+	}
+    }
+}
+
+#[test]
+fn deferred_probe_binds_once_dependency_registers() {
+    static DEPENDENCY_UP: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+    static INIT_CALLS: AtomicU64 = AtomicU64::new(0);
+
+    struct TestDevice;
+    impl fmt::Display for TestDevice {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	    write!(f, "test device")
+	}
+    }
+    impl DeviceTypeIdentifier for TestDevice {
+	fn as_any(&self) -> &dyn Any {
+	    self
+	}
+    }
+
+    // Can't bind until the dependency's driver is up. Once bound, it registers a driver of its own, as bus drivers do
+    struct Dependent;
+    impl Driver for Dependent {
+	fn init(&self, _info: &dyn DeviceTypeIdentifier) -> ProbeResult {
+	    INIT_CALLS.fetch_add(1, Ordering::SeqCst);
+	    if !DEPENDENCY_UP.load(Ordering::SeqCst) {
+		return ProbeResult::Defer;
+	    }
+
+	    register_driver(Box::new(Dependency));
+	    ProbeResult::Bound
+	}
+
+	fn check_device(&self, info: &dyn DeviceTypeIdentifier) -> bool {
+	    info.as_any().is::<TestDevice>()
+	}
+
+	fn check_new_device(&self, _info: &dyn DeviceTypeIdentifier) -> bool {
+	    true
+	}
+    }
+
+    // Matches no devices, and only needs registering
+    struct Dependency;
+    impl Driver for Dependency {
+	fn init(&self, _info: &dyn DeviceTypeIdentifier) -> ProbeResult {
+	    ProbeResult::Bound
+	}
+
+	fn check_device(&self, _info: &dyn DeviceTypeIdentifier) -> bool {
+	    false
+	}
+
+	fn check_new_device(&self, _info: &dyn DeviceTypeIdentifier) -> bool {
+	    false
+	}
+    }
+
+    DRIVER_TABLE.call_once(|| RwLock::new(Vec::new()));
+    register_driver(Box::new(Dependent));
+    enumerate_device(Box::new(TestDevice));
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 1);
+
+    DEPENDENCY_UP.store(true, Ordering::SeqCst);
+    register_driver(Box::new(Dependency));
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 2);
+
+    // Bound, so no longer pending
+    retry_pending_devices();
+    assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn readdir_resumes_from_its_cookie_when_a_device_is_added() {
+    use crate::vfs::filesystem::FileSystem;
+
+    let fs = Arc::new(DevFS::new());
+    let root = fs.clone().root(vfs::filesystem::FileSystemInstance(0x1958));
+    for name in ["tty0", "kmsg", "random"] {
+	fs.add_device(root.clone(), String::from(name));
+    }
+
+    // One entry at a time, as getdents does when the buffer only fits one, and with a device turning up part way
+    // through which sorts ahead of every other name
+    let dir = root.clone().open().unwrap();
+    let mut seen: Vec<String> = Vec::new();
+    loop {
+	let entries = dir.clone().readdir().now_or_never().unwrap().unwrap();
+	let Some(entry) = entries.into_iter().next() else {
+	    break;
 	};
+	dir.seek(vfs::filesystem::SeekFrom::Set(entry.cookie as i64)).unwrap();
+	seen.push(entry.name);
+
+	if seen.len() == 2 {
+	    fs.add_device(root.clone(), String::from("aaa"));
+	}
+    }
 
-    log::info!("Found new device {}", device_identifier);
-    driver.init(device_identifier.as_ref());
+    assert_eq!(seen, ["tty0", "kmsg", "random", "aaa"]);
 }
@@ -0,0 +1,134 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hint;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::VirtAddr;
+
+use crate::driver;
+use crate::drivers::pcie;
+use crate::memory;
+use crate::sys::time;
+
+mod port;
+
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_PI: u64 = 0x0C;
+const HBA_VS: u64 = 0x10;
+const HBA_CAP2: u64 = 0x24;
+const HBA_BOHC: u64 = 0x28;
+
+const HBA_CAP_NP_MASK: u32 = 0x1F;
+const HBA_CAP2_BOH: u32 = 1 << 0;
+const HBA_GHC_IE: u32 = 1 << 1;
+const HBA_GHC_AE: u32 = 1 << 31;
+const HBA_BOHC_BOS: u32 = 1 << 0;
+const HBA_BOHC_OOS: u32 = 1 << 1;
+
+const PORT_REGISTERS_BASE: u64 = 0x100;
+const PORT_REGISTERS_SIZE: u64 = 0x80;
+
+// The BIOS is allowed up to two seconds to finish what it's doing once asked to give up the HBA
+const BIOS_HANDOFF_TIMEOUT_NS: u64 = 2 * time::NANOSECONDS_PER_SECOND;
+
+// ABAR, where the HBA's registers are
+const ABAR_SLOT: u8 = 5;
+
+fn hba_read(abar: VirtAddr, reg: u64) -> u32 {
+    unsafe {
+	read_volatile((abar + reg).as_ptr::<u32>())
+    }
+}
+
+fn hba_write(abar: VirtAddr, reg: u64, value: u32) {
+    unsafe {
+	write_volatile((abar + reg).as_mut_ptr::<u32>(), value);
+    }
+}
+
+// Takes ownership of the HBA from the firmware, if it supports handing it over
+fn bios_handoff(abar: VirtAddr) {
+    if hba_read(abar, HBA_CAP2) & HBA_CAP2_BOH == 0 {
+	return;
+    }
+
+    hba_write(abar, HBA_BOHC, hba_read(abar, HBA_BOHC) | HBA_BOHC_OOS);
+
+    let deadline = time::get_monotonic_ns() + BIOS_HANDOFF_TIMEOUT_NS;
+    while hba_read(abar, HBA_BOHC) & HBA_BOHC_BOS != 0 {
+	if time::get_monotonic_ns() > deadline {
+	    log::warn!("BIOS did not release AHCI controller, taking it anyway");
+	    return;
+	}
+	hint::spin_loop();
+    }
+}
+
+fn implemented_ports(abar: VirtAddr) -> Vec<u32> {
+    let pi = hba_read(abar, HBA_PI);
+    (0 .. 32).filter(|port| pi & (1 << port) != 0).collect()
+}
+
+fn port_base(abar: VirtAddr, port: u32) -> VirtAddr {
+    abar + PORT_REGISTERS_BASE + port as u64 * PORT_REGISTERS_SIZE
+}
+
+pub fn init() {
+    let ahci_driver = AhciDriver {};
+    driver::register_driver(Box::new(ahci_driver));
+}
+
+pub struct AhciDriver {}
+impl driver::Driver for AhciDriver {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
+	let pci_info = if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
+	    pci_info
+	} else {
+	    return driver::ProbeResult::Bound;
+	};
+
+	let bar = pcie::get_bar(pci_info.clone(), ABAR_SLOT).expect("Unable to find AHCI ABAR");
+
+	// ABAR is always memory space
+	let (abar_phys, abar_size) = bar.unwrap_mem();
+	let abar = memory::allocate_mmio(abar_phys, abar_size).expect("Unable to map AHCI registers");
+	pcie::enable_bus_mastering(pci_info.clone());
+
+	bios_handoff(abar);
+
+	// Polled for now, so interrupts stay off
+	let ghc = hba_read(abar, HBA_GHC);
+	hba_write(abar, HBA_GHC, (ghc | HBA_GHC_AE) & !HBA_GHC_IE);
+
+	let vs = hba_read(abar, HBA_VS);
+	let ports = implemented_ports(abar);
+	log::info!("AHCI {}.{} controller, {} ports ({} implemented)",
+		   vs >> 16, vs & 0xFFFF,
+		   (hba_read(abar, HBA_CAP) & HBA_CAP_NP_MASK) + 1, ports.len());
+
+	for port in ports.iter() {
+	    port::probe_port(port_base(abar, *port), *port);
+	}
+
+	driver::register_shutdown_hook(Box::new(move || {
+	    for port in ports.iter() {
+		port::stop_port(port_base(abar, *port));
+	    }
+	}));
+
+	driver::ProbeResult::Bound
+    }
+
+    fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
+	    pci_info.base_class == 0x01 &&
+		pci_info.sub_class == 0x06
+	} else {
+	    false
+	}
+    }
+
+    fn check_new_device(&self, _info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	true // Not yet implemented
+    }
+}
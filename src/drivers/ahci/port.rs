@@ -0,0 +1,392 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::cmp;
+use core::hint;
+use core::ptr::{read_volatile, write_volatile};
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use futures_util::future::BoxFuture;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+use crate::sys::block;
+use crate::sys::syscall;
+use crate::sys::time;
+use crate::utils::async_mutex::AsyncMutex;
+
+// Port registers, relative to the start of the port's register block
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0C;
+const PORT_IS: u64 = 0x10;
+const PORT_IE: u64 = 0x14;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+const PORT_CMD_ST: u32 = 1 << 0;
+const PORT_CMD_SUD: u32 = 1 << 1;
+const PORT_CMD_POD: u32 = 1 << 2;
+const PORT_CMD_FRE: u32 = 1 << 4;
+const PORT_CMD_FR: u32 = 1 << 14;
+const PORT_CMD_CR: u32 = 1 << 15;
+
+const PORT_IS_TFES: u32 = 1 << 30;
+
+const PORT_TFD_ERR: u32 = 1 << 0;
+const PORT_TFD_DRQ: u32 = 1 << 3;
+const PORT_TFD_BSY: u32 = 1 << 7;
+
+const PORT_SSTS_DET_PRESENT: u32 = 3;
+const PORT_SSTS_IPM_ACTIVE: u32 = 1;
+
+const SATA_SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_H2D_COMMAND: u8 = 1 << 7;
+const FIS_DEVICE_LBA: u8 = 1 << 6;
+
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+// Everything the HBA needs for a port lives in one page. The command list has to be 1KiB aligned, the received FIS
+// area 256 bytes, and the command table 128 bytes. Only slot 0 is used, so there's one command table.
+const COMMAND_LIST_OFFSET: u64 = 0x000;
+const RECEIVED_FIS_OFFSET: u64 = 0x400;
+const COMMAND_TABLE_OFFSET: u64 = 0x500;
+const PRDT_OFFSET: u64 = COMMAND_TABLE_OFFSET + 0x80;
+
+const COMMAND_HEADER_WRITE: u32 = 1 << 6;
+const COMMAND_HEADER_PRDTL_SHIFT: u32 = 16;
+const H2D_FIS_LENGTH_DWORDS: u32 = 5;
+
+// Data goes through a bounce buffer of this many sectors. It's physically contiguous, so needs just the one PRD.
+const MAX_SECTORS_PER_COMMAND: u64 = 128;
+const BUFFER_SIZE: u64 = MAX_SECTORS_PER_COMMAND * 512;
+
+const COMMAND_TIMEOUT_NS: u64 = 5 * time::NANOSECONDS_PER_SECOND;
+
+struct PortRegisters(VirtAddr);
+
+impl PortRegisters {
+    fn read(&self, reg: u64) -> u32 {
+	unsafe {
+	    read_volatile((self.0 + reg).as_ptr::<u32>())
+	}
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+	unsafe {
+	    write_volatile((self.0 + reg).as_mut_ptr::<u32>(), value);
+	}
+    }
+
+    // Spins until the bits in mask are all clear, giving up after the command timeout
+    fn wait_clear(&self, reg: u64, mask: u32) -> bool {
+	let deadline = time::get_monotonic_ns() + COMMAND_TIMEOUT_NS;
+	while self.read(reg) & mask != 0 {
+	    if time::get_monotonic_ns() > deadline {
+		return false;
+	    }
+	    hint::spin_loop();
+	}
+
+	true
+    }
+}
+
+struct AhciPort {
+    regs: PortRegisters,
+    port_num: u32,
+    structures: VirtAddr,
+    structures_phys: PhysAddr,
+    buffer: VirtAddr,
+    buffer_phys: PhysAddr,
+}
+
+impl AhciPort {
+    fn new(regs: PortRegisters, port_num: u32) -> AhciPort {
+	let (structures, structures_phys) = memory::kernel_allocate(
+	    4096, memory::MemoryAllocationType::Dma)
+	    .expect("Unable to allocate AHCI port structures");
+	let (buffer, buffer_phys) = memory::kernel_allocate(
+	    BUFFER_SIZE, memory::MemoryAllocationType::Dma)
+	    .expect("Unable to allocate AHCI bounce buffer");
+
+	unsafe {
+	    slice::from_raw_parts_mut(structures.as_mut_ptr::<u8>(), 4096).fill(0);
+	}
+
+	AhciPort {
+	    regs,
+	    port_num,
+	    structures,
+	    structures_phys: structures_phys[0],
+	    buffer,
+	    buffer_phys: buffer_phys[0],
+	}
+    }
+
+    fn stop(&self) -> bool {
+	let cmd = self.regs.read(PORT_CMD);
+	self.regs.write(PORT_CMD, cmd & !PORT_CMD_ST);
+	if !self.regs.wait_clear(PORT_CMD, PORT_CMD_CR) {
+	    return false;
+	}
+
+	let cmd = self.regs.read(PORT_CMD);
+	self.regs.write(PORT_CMD, cmd & !PORT_CMD_FRE);
+	self.regs.wait_clear(PORT_CMD, PORT_CMD_FR)
+    }
+
+    fn start(&self) -> bool {
+	let clb = self.structures_phys + COMMAND_LIST_OFFSET;
+	let fb = self.structures_phys + RECEIVED_FIS_OFFSET;
+	self.regs.write(PORT_CLB, clb.as_u64() as u32);
+	self.regs.write(PORT_CLBU, (clb.as_u64() >> 32) as u32);
+	self.regs.write(PORT_FB, fb.as_u64() as u32);
+	self.regs.write(PORT_FBU, (fb.as_u64() >> 32) as u32);
+
+	// Errors left over from before we took the port over are written back to clear them. Completion is polled for,
+	// so the port doesn't interrupt.
+	self.regs.write(PORT_SERR, 0xFFFF_FFFF);
+	self.regs.write(PORT_IS, 0xFFFF_FFFF);
+	self.regs.write(PORT_IE, 0);
+
+	let cmd = self.regs.read(PORT_CMD);
+	self.regs.write(PORT_CMD, cmd | PORT_CMD_FRE | PORT_CMD_SUD | PORT_CMD_POD);
+
+	if !self.regs.wait_clear(PORT_TFD, PORT_TFD_BSY | PORT_TFD_DRQ) {
+	    return false;
+	}
+
+	let cmd = self.regs.read(PORT_CMD);
+	self.regs.write(PORT_CMD, cmd | PORT_CMD_ST);
+	true
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+	unsafe {
+	    slice::from_raw_parts_mut(self.buffer.as_mut_ptr::<u8>(), BUFFER_SIZE as usize)
+	}
+    }
+
+    // Runs one command in slot 0, moving length bytes between the device and the bounce buffer, and waits for it
+    fn issue(&mut self, command: u8, lba: u64, sectors: u16, length: usize, write: bool) -> Result<(), syscall::CanonicalError> {
+	if !self.regs.wait_clear(PORT_TFD, PORT_TFD_BSY | PORT_TFD_DRQ) {
+	    log::warn!("AHCI port {} is stuck busy", self.port_num);
+	    self.recover();
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	let table_phys = self.structures_phys + COMMAND_TABLE_OFFSET;
+	let header = unsafe {
+	    slice::from_raw_parts_mut((self.structures + COMMAND_LIST_OFFSET).as_mut_ptr::<u32>(), 8)
+	};
+	header.fill(0);
+	header[0] = H2D_FIS_LENGTH_DWORDS | (1 << COMMAND_HEADER_PRDTL_SHIFT) | if write { COMMAND_HEADER_WRITE } else { 0 };
+	header[2] = table_phys.as_u64() as u32;
+	header[3] = (table_phys.as_u64() >> 32) as u32;
+
+	let fis = unsafe {
+	    slice::from_raw_parts_mut((self.structures + COMMAND_TABLE_OFFSET).as_mut_ptr::<u8>(), 0x80)
+	};
+	fis.fill(0);
+	fis[0] = FIS_TYPE_REG_H2D;
+	fis[1] = FIS_H2D_COMMAND;
+	fis[2] = command;
+	fis[4] = lba as u8;
+	fis[5] = (lba >> 8) as u8;
+	fis[6] = (lba >> 16) as u8;
+	fis[7] = FIS_DEVICE_LBA;
+	fis[8] = (lba >> 24) as u8;
+	fis[9] = (lba >> 32) as u8;
+	fis[10] = (lba >> 40) as u8;
+	fis[12] = sectors as u8;
+	fis[13] = (sectors >> 8) as u8;
+
+	let prd = unsafe {
+	    slice::from_raw_parts_mut((self.structures + PRDT_OFFSET).as_mut_ptr::<u32>(), 4)
+	};
+	prd[0] = self.buffer_phys.as_u64() as u32;
+	prd[1] = (self.buffer_phys.as_u64() >> 32) as u32;
+	prd[2] = 0;
+	prd[3] = (length - 1) as u32;
+
+	// The HBA must see the command before it's told about it
+	fence(Ordering::SeqCst);
+	self.regs.write(PORT_IS, 0xFFFF_FFFF);
+	self.regs.write(PORT_CI, 1);
+
+	// TODO: poll in the background and let other work happen, rather than spinning until the command finishes
+	let deadline = time::get_monotonic_ns() + COMMAND_TIMEOUT_NS;
+	loop {
+	    if self.regs.read(PORT_IS) & PORT_IS_TFES != 0 {
+		log::warn!("AHCI port {}: command {:#X} failed, TFD = {:#X}", self.port_num, command, self.regs.read(PORT_TFD));
+		self.recover();
+		return Err(syscall::CanonicalError::Io);
+	    }
+
+	    if self.regs.read(PORT_CI) & 1 == 0 {
+		break;
+	    }
+
+	    if time::get_monotonic_ns() > deadline {
+		log::warn!("AHCI port {}: command {:#X} timed out", self.port_num, command);
+		self.recover();
+		return Err(syscall::CanonicalError::Io);
+	    }
+	    hint::spin_loop();
+	}
+	fence(Ordering::SeqCst);
+
+	if self.regs.read(PORT_TFD) & PORT_TFD_ERR != 0 {
+	    log::warn!("AHCI port {}: command {:#X} failed, TFD = {:#X}", self.port_num, command, self.regs.read(PORT_TFD));
+	    self.recover();
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	Ok(())
+    }
+
+    // Restarting the port is what clears a task file error, and drops whatever command it was stuck on
+    fn recover(&self) {
+	if !self.stop() || !self.start() {
+	    log::warn!("AHCI port {} could not be restarted", self.port_num);
+	}
+    }
+}
+
+struct AhciDrive {
+    port: AsyncMutex<AhciPort>,
+    model: String,
+    size_in_sectors: u64,
+}
+
+impl block::BlockDevice for AhciDrive {
+    fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	Box::pin(async move {
+	    if size == 0 || offset + size > self.size_in_sectors {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let mut port = self.port.lock().await;
+	    let mut data = Vec::with_capacity((size * 512) as usize);
+	    let mut sector = offset;
+	    while sector < offset + size {
+		let sectors = cmp::min(offset + size - sector, MAX_SECTORS_PER_COMMAND);
+		let length = (sectors * 512) as usize;
+		port.issue(ATA_CMD_READ_DMA_EXT, sector, sectors as u16, length, false)?;
+		data.extend_from_slice(&port.buffer()[.. length]);
+
+		sector += sectors;
+	    }
+
+	    Ok(Bytes::from(data))
+	})
+    }
+
+    fn write(self: Arc<Self>, offset: u64, data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    let size = data.len() as u64 / 512;
+	    if data.is_empty() || !data.len().is_multiple_of(512) || offset + size > self.size_in_sectors {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let mut port = self.port.lock().await;
+	    for (i, chunk) in data.chunks(BUFFER_SIZE as usize).enumerate() {
+		let sector = offset + i as u64 * MAX_SECTORS_PER_COMMAND;
+		port.buffer()[.. chunk.len()].copy_from_slice(chunk);
+		port.issue(ATA_CMD_WRITE_DMA_EXT, sector, (chunk.len() / 512) as u16, chunk.len(), true)?;
+	    }
+
+	    Ok(())
+	})
+    }
+
+    fn flush(self: Arc<Self>) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    // No data moves, the length only keeps the PRD entry valid
+	    let mut port = self.port.lock().await;
+	    port.issue(ATA_CMD_FLUSH_CACHE_EXT, 0, 0, 512, false)
+	})
+    }
+
+    fn size_in_sectors(&self) -> u64 {
+	self.size_in_sectors
+    }
+
+    fn model(&self) -> String {
+	self.model.clone()
+    }
+}
+
+// IDENTIFY data is little endian words, but the model number has the two characters in each word the other way round
+fn identify_model(ident: &[u8]) -> String {
+    let model = ident[54 .. 94].chunks_exact(2)
+	.flat_map(|c| [c[1], c[0]])
+	.collect::<Vec<u8>>();
+    String::from(String::from_utf8_lossy(&model).trim())
+}
+
+fn identify_size_in_sectors(ident: &[u8]) -> u64 {
+    let lba48 = u16::from_le_bytes([ident[166], ident[167]]) & (1 << 10) != 0;
+    if lba48 {
+	u64::from_le_bytes(ident[200 .. 208].try_into().unwrap())
+    } else {
+	u32::from_le_bytes(ident[120 .. 124].try_into().unwrap()) as u64
+    }
+}
+
+// Brings up the port if there's a disk on the other end of it, and registers the disk
+pub fn probe_port(port_base: VirtAddr, port_num: u32) {
+    let regs = PortRegisters(port_base);
+
+    let ssts = regs.read(PORT_SSTS);
+    if ssts & 0xF != PORT_SSTS_DET_PRESENT || (ssts >> 8) & 0xF != PORT_SSTS_IPM_ACTIVE {
+	return;
+    }
+
+    let sig = regs.read(PORT_SIG);
+    if sig != SATA_SIG_ATA {
+	log::info!("AHCI port {}: unsupported device, signature {:#X}", port_num, sig);
+	return;
+    }
+
+    let mut port = AhciPort::new(regs, port_num);
+    if !port.stop() || !port.start() {
+	log::warn!("AHCI port {}: unable to start command engine", port_num);
+	return;
+    }
+
+    if port.issue(ATA_CMD_IDENTIFY, 0, 0, 512, false).is_err() {
+	log::warn!("AHCI port {}: IDENTIFY failed", port_num);
+	return;
+    }
+
+    let model = identify_model(&port.buffer()[.. 512]);
+    let size_in_sectors = identify_size_in_sectors(&port.buffer()[.. 512]);
+    log::info!("AHCI port {}: {} - {} MiB", port_num, model, size_in_sectors / (1024 * 2));
+
+    block::register_disk(Arc::new(AhciDrive {
+	port: AsyncMutex::new(port),
+	model,
+	size_in_sectors,
+    }));
+}
+
+// Stops the port fetching commands and writing FISes, so the HBA doesn't touch memory after shutdown
+pub fn stop_port(port_base: VirtAddr) {
+    let regs = PortRegisters(port_base);
+    let cmd = regs.read(PORT_CMD);
+    regs.write(PORT_CMD, cmd & !(PORT_CMD_ST | PORT_CMD_FRE));
+}
@@ -1,8 +1,11 @@
 use crate::driver;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use spin::{Once, RwLock};
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::sys::acpi::{namespace, resources};
 use crate::memory;
@@ -10,12 +13,20 @@ use crate::interrupts;
 
 const ENABLE_CNF: u64 = 0x01;
 
+const COUNTER_CLK_PERIOD_SHIFT: u64 = 32;
+// The spec caps the tick at 100ns
+const COUNTER_CLK_PERIOD_MAX_FS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
 const HPET_COUNTER_SET_ACCUMULATOR: u64 = 1 << 6;
 const HPET_COUNTER_PERIODIC: u64 = 1 << 3;
 const HPET_COUNTER_NON_PERIODIC: u64 = !HPET_COUNTER_PERIODIC;
 const HPET_COUNTER_ENABLED: u64 = 1 << 2;
 const HPET_COUNTER_LEVEL_TRIGGERED: u64 = 1 << 1;
 
+// How far a 32 bit comparator can be set ahead of the main counter without the comparison becoming ambiguous
+const HPET_MAX_32BIT_DELTA: u64 = 1 << 31;
+
 #[allow(dead_code)]
 struct HpetCounter {
     pub configuration_capability_register: *mut u64,
@@ -25,16 +36,36 @@ struct HpetCounter {
 
 struct TimerCallback(u8, Box<dyn Fn() + Send + Sync>);
 
+// Identifies a pending one-shot, so it can be cancelled
+#[derive(Clone, Copy, Debug)]
+pub struct OneshotHandle {
+    deadline: u64,
+    id: u64,
+}
+
 struct Hpet {
     counter_64: bool,
+    // Femtoseconds per main counter tick
+    counter_period_fs: u64,
     general_capabilities_register: *const u64,
     general_configuration_register: *mut u64,
     general_interrupt_status_register: *mut u64,
     main_counter_value_register: *mut u64,
+    // The main counter's value when we took it over, which is our idea of boot
+    main_counter_base: u64,
+    // 32 bit main counters wrap every few minutes, so we count the wraps ourselves. This relies on the counter being
+    // read at least once per wrap, which the timer interrupt sees to.
+    main_counter_wraps: AtomicU64,
+    main_counter_last: AtomicU64,
     counters: Vec<HpetCounter>,
     callbacks: Vec<TimerCallback>,
     free_counters: Vec<u8>,
     periodic_counters: Vec<u8>,
+    // One-shots all share a single comparator, which is kept set for whichever is due first. They're keyed by deadline,
+    // then by an ID to tell apart ones due at the same time.
+    oneshot_counter: Option<u8>,
+    oneshots: BTreeMap<(u64, u64), Box<dyn Fn() + Send + Sync>>,
+    next_oneshot_id: u64,
 }
 
 unsafe impl Send for Hpet {}
@@ -49,14 +80,21 @@ impl Hpet {
 
 	let mut hpet = Hpet {
 	    counter_64: false,
+	    counter_period_fs: 0,
 	    general_capabilities_register: virt_addr.as_ptr(),
 	    general_configuration_register: (virt_addr + 0x10).as_mut_ptr(),
 	    general_interrupt_status_register: (virt_addr + 0x20).as_mut_ptr(),
 	    main_counter_value_register: (virt_addr + 0xF0).as_mut_ptr(),
+	    main_counter_base: 0,
+	    main_counter_wraps: AtomicU64::new(0),
+	    main_counter_last: AtomicU64::new(0),
 	    counters: Vec::new(),
 	    callbacks: Vec::new(),
 	    free_counters: Vec::new(),
 	    periodic_counters: Vec::new(),
+	    oneshot_counter: None,
+	    oneshots: BTreeMap::new(),
+	    next_oneshot_id: 0,
 	};
 
 	let num_counters = unsafe {
@@ -78,9 +116,18 @@ impl Hpet {
 	};
 	hpet.counter_64 = counter_64 == 1;
 
+	hpet.counter_period_fs = unsafe {
+	    read_volatile::<u64>(hpet.general_capabilities_register) >> COUNTER_CLK_PERIOD_SHIFT
+	};
+	if hpet.counter_period_fs == 0 || hpet.counter_period_fs > COUNTER_CLK_PERIOD_MAX_FS {
+	    panic!("HPET reports a tick of {}fs, which can't be right", hpet.counter_period_fs);
+	}
+	log::info!("HPET running at {}Hz", 10_u64.pow(15) / hpet.counter_period_fs);
+
 	unsafe {
 	    write_volatile::<u64>(hpet.general_configuration_register, ENABLE_CNF);
 	}
+	hpet.main_counter_base = hpet.read_main_counter();
 
 	let mut possible_routings: u32 = 0xFFFF_FFFF;
 	// Disable all counters
@@ -111,11 +158,46 @@ impl Hpet {
 	    }
 	}
 
+	// The scheduler tick runs from here, so it's kept on the BSP rather than being balanced with everything else
+	interrupts::InterruptRoute::Gsi(gsi).set_affinity(interrupts::bsp_apic_id());
 	interrupts::enable_gsi(gsi, &hpet_handler);
 
 	hpet
     }
 
+    // Reads the main counter, extended to 64 bits if it's only a 32 bit one. Must be called with interrupts disabled.
+    fn read_main_counter(&self) -> u64 {
+	let value = unsafe {
+	    read_volatile::<u64>(self.main_counter_value_register)
+	};
+
+	if self.counter_64 {
+	    return value;
+	}
+
+	let value = value & 0xFFFF_FFFF;
+	let last = self.main_counter_last.swap(value, Ordering::Relaxed);
+	if value < last {
+	    self.main_counter_wraps.fetch_add(1, Ordering::Relaxed);
+	}
+
+	(self.main_counter_wraps.load(Ordering::Relaxed) << 32) | value
+    }
+
+    // Both are done in 128 bits, as in femtoseconds anything over a few hours overflows 64
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+	((ticks as u128 * self.counter_period_fs as u128) / FEMTOSECONDS_PER_NANOSECOND) as u64
+    }
+
+    // Rounds up, so waiting this many ticks is never shorter than asked for
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+	(ns as u128 * FEMTOSECONDS_PER_NANOSECOND).div_ceil(self.counter_period_fs as u128) as u64
+    }
+
+    pub fn now_ns(&self) -> u64 {
+	self.ticks_to_ns(self.read_main_counter().wrapping_sub(self.main_counter_base))
+    }
+
     pub fn find_timer_interrupting(&mut self) -> Option<u64> {
 	let mut gisr = unsafe {
 	    read_volatile::<u64>(self.general_interrupt_status_register)
@@ -163,11 +245,7 @@ impl Hpet {
 	}
 	config |= HPET_COUNTER_ENABLED | HPET_COUNTER_LEVEL_TRIGGERED;
 
-	let counter_divider = unsafe {
-	    (read_volatile::<u64>(self.general_capabilities_register) & 0xFFFF_FFFF_0000_0000) >> 32
-	};
-
-	let time_in_ticks = (time_ms * 10_u64.pow(12)) / counter_divider;
+	let time_in_ticks = self.ns_to_ticks(time_ms.saturating_mul(1_000_000));
 	let main_counter_val = unsafe {
 	    read_volatile::<u64>(self.main_counter_value_register)
 	};
@@ -186,14 +264,87 @@ impl Hpet {
 	}
     }
 
-    #[allow(dead_code)]
-    pub fn add_oneshot_ms(&mut self, time_ms: u64, callback: Box<dyn Fn() + Send + Sync>) {
-	if let Some(counter) = self.free_counters.pop() {
-	    self.callbacks.push(TimerCallback(counter, callback));
-	    self.set_timer(counter, time_ms, false);
+    // Sets the one-shot comparator for the earliest deadline, or turns it off if nothing's pending. A comparator set
+    // for a time the main counter has already passed won't fire until it comes round again, so deadlines which are
+    // due, or about to be, are pushed out a little, and further if the counter gets there while it's being set.
+    fn arm_oneshot(&mut self) {
+	let counter = match self.oneshot_counter {
+	    Some(counter) => counter,
+	    None => return,
+	};
+	let comparator = &self.counters[counter as usize];
+
+	let deadline = match self.oneshots.first_key_value() {
+	    Some(((deadline, _), _)) => *deadline,
+	    None => {
+		unsafe {
+		    write_volatile::<u64>(comparator.configuration_capability_register, 0);
+		}
+		return;
+	    },
+	};
+
+	let config = HPET_COUNTER_ENABLED | HPET_COUNTER_LEVEL_TRIGGERED;
+	let max_delta = if self.counter_64 { u64::MAX / 2 } else { HPET_MAX_32BIT_DELTA };
+	let mut margin = self.ns_to_ticks(1000).max(1);
+	loop {
+	    let now = self.read_main_counter();
+	    // Deadlines too far out for the comparator are got to in steps, each interrupt setting it again
+	    let target = deadline.max(now + margin).min(now + max_delta);
+	    let comparator_val = if self.counter_64 { target } else { target & 0xFFFF_FFFF };
+
+	    unsafe {
+		write_volatile::<u64>(comparator.configuration_capability_register, config);
+		write_volatile::<u64>(comparator.comparator_value_register, comparator_val);
+	    }
+
+	    if self.read_main_counter() < target {
+		return;
+	    }
+	    margin *= 2;
 	}
     }
 
+    // None if there's no comparator free for one-shots
+    pub fn add_oneshot(&mut self, deadline_ticks: u64, callback: Box<dyn Fn() + Send + Sync>) -> Option<OneshotHandle> {
+	if self.oneshot_counter.is_none() {
+	    self.oneshot_counter = Some(self.free_counters.pop()?);
+	}
+
+	let handle = OneshotHandle {
+	    deadline: deadline_ticks,
+	    id: self.next_oneshot_id,
+	};
+	self.next_oneshot_id += 1;
+
+	self.oneshots.insert((handle.deadline, handle.id), callback);
+	self.arm_oneshot();
+	Some(handle)
+    }
+
+    // Returns false if the one-shot has already run
+    pub fn cancel_oneshot(&mut self, handle: OneshotHandle) -> bool {
+	let cancelled = self.oneshots.remove(&(handle.deadline, handle.id)).is_some();
+	if cancelled {
+	    self.arm_oneshot();
+	}
+
+	cancelled
+    }
+
+    fn run_expired_oneshots(&mut self) {
+	let now = self.read_main_counter();
+	while let Some(oneshot) = self.oneshots.first_entry() {
+	    if oneshot.key().0 > now {
+		break;
+	    }
+
+	    (oneshot.remove())();
+	}
+
+	self.arm_oneshot();
+    }
+
     pub fn add_recurring_ms(&mut self, time_ms: u64, callback: Box<dyn Fn() + Send + Sync>) {
 	if let Some(counter) = self.free_counters.pop() {
 	    self.callbacks.push(TimerCallback(counter, callback));
@@ -204,18 +355,13 @@ impl Hpet {
 
     pub fn handle_triggered_callbacks(&mut self) {
 	if let Some(counter) = self.find_timer_interrupting() {
-	    if self.periodic_counters.contains(&(counter as u8)) {
+	    if self.oneshot_counter == Some(counter as u8) {
+		self.run_expired_oneshots();
+	    } else {
 		self.callbacks
 		    .iter()
 		    .filter(|callback| callback.0 == counter as u8)
 		    .for_each(|callback| callback.1());
-	    } else {
-		self.callbacks
-		    .extract_if(.., |callback| callback.0 == counter as u8)
-		    .map(|callback| callback.1())
-		    .for_each(drop);
-
-		self.free_counters.push(counter as u8);
 	    }
 	}
     }
@@ -223,15 +369,68 @@ impl Hpet {
 
 fn hpet_handler() {
     let mut hpet = HPET.get().expect("Attempted to initialise HPET device before initialising driver").write();
+    // Keeps the wrap count of a 32 bit main counter up to date
+    hpet.read_main_counter();
     hpet.handle_triggered_callbacks();
 }
 
+// Nanoseconds since the HPET was brought up, or 0 if it hasn't been yet
+pub fn now_ns() -> u64 {
+    without_interrupts(|| {
+	match HPET.get() {
+	    Some(hpet) => hpet.read().now_ns(),
+	    None => 0,
+	}
+    })
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    without_interrupts(|| {
+	HPET.get().expect("Attempted to initialise HPET device before initialising driver").read().ns_to_ticks(ns)
+    })
+}
+
 #[allow(dead_code)]
-pub fn add_oneshot(time_ms: u64, callback: Box<dyn Fn() + Send + Sync>) {
-    let mut hpet = HPET.get().expect("Attempted to initialise HPET device before initialising driver").write();
-    hpet.add_oneshot_ms(time_ms, callback);
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    without_interrupts(|| {
+	HPET.get().expect("Attempted to initialise HPET device before initialising driver").read().ticks_to_ns(ticks)
+    })
+}
+
+// As above, but gives up rather than waiting if the HPET is locked, for logging from inside timer callbacks
+pub fn try_now_ns() -> Option<u64> {
+    without_interrupts(|| {
+	HPET.get()?.try_read().map(|hpet| hpet.now_ns())
+    })
 }
 
+// The main counter, in ticks, which is what one-shot deadlines are given in
+pub fn now_ticks() -> u64 {
+    without_interrupts(|| {
+	HPET.get().expect("Attempted to initialise HPET device before initialising driver").read().read_main_counter()
+    })
+}
+
+// Runs the callback, from the timer interrupt, once the main counter reaches deadline_ticks. Returns None if there's no
+// timer free to run it.
+pub fn add_oneshot(deadline_ticks: u64, callback: Box<dyn Fn() + Send + Sync>) -> Option<OneshotHandle> {
+    // The timer interrupt takes the same lock
+    without_interrupts(|| {
+	let mut hpet = HPET.get().expect("Attempted to initialise HPET device before initialising driver").write();
+	hpet.add_oneshot(deadline_ticks, callback)
+    })
+}
+
+// Stops a one-shot which hasn't run yet from running. Returns false if it already has.
+#[allow(dead_code)]
+pub fn cancel_oneshot(handle: OneshotHandle) -> bool {
+    without_interrupts(|| {
+	let mut hpet = HPET.get().expect("Attempted to initialise HPET device before initialising driver").write();
+	hpet.cancel_oneshot(handle)
+    })
+}
+
+#[allow(dead_code)]
 pub fn add_periodic(time_ms: u64, callback: Box<dyn Fn() + Send + Sync>) {
     let mut hpet = HPET.get().expect("Attempted to initialise HPET device before initialising driver").write();
     hpet.add_recurring_ms(time_ms, callback);
@@ -246,7 +445,7 @@ pub fn init() {
 
 pub struct HpetDriver {}
 impl driver::Driver for HpetDriver {
-    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
 	let system_bus_identifier = if let Some(sb_info) = info.as_any().downcast_ref::<namespace::SystemBusDeviceIdentifier>() {
 	    sb_info
 	} else {
@@ -274,6 +473,8 @@ impl driver::Driver for HpetDriver {
 	    x86_64::instructions::port::PortWrite::write_to_port(0x43, 0x7A_u8);
 	    x86_64::instructions::port::PortWrite::write_to_port(0x43, 0xBA_u8);
 	}
+
+	driver::ProbeResult::Bound
     }
 
     fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
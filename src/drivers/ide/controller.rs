@@ -3,7 +3,6 @@ use core::arch::asm;
 use core::ascii;
 use core::slice;
 use alloc::sync::Arc;
-use spin::{Mutex, MutexGuard};
 use x86_64::instructions::port::Port;
 use itertools::Itertools;
 use alloc::vec;
@@ -17,6 +16,7 @@ use crate::dma::arena;
 use crate::memory;
 use crate::sys::block;
 use crate::sys::syscall;
+use crate::utils::async_mutex::AsyncMutex;
 
 const IDE_CTL_REG: u16 = 0;
 const IDE_CTL_NIEN: u8 = 1 << 1;
@@ -30,8 +30,31 @@ const IDE_DRIVE_HEAD_DRIVE_SEL_SECONDARY: u8 = 1 << 4;
 const IDE_CMD_REG: u16 = 7;
 const IDE_CMD_IDENTIFY: u8 = 0xEC;
 const IDE_CMD_PACKET_IDENTIFY: u8 = 0xA1;
+const IDE_CMD_READ_PIO: u8 = 0x20;
 const IDE_CMD_READ_PIO_EXT: u8 = 0x24;
+const IDE_CMD_READ_DMA: u8 = 0xC8;
 const IDE_CMD_READ_DMA_EXT: u8 = 0x25;
+const IDE_CMD_WRITE_PIO: u8 = 0x30;
+const IDE_CMD_WRITE_PIO_EXT: u8 = 0x34;
+const IDE_CMD_CACHE_FLUSH: u8 = 0xE7;
+const IDE_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const IDE_CMD_PACKET: u8 = 0xA0;
+
+const ATAPI_PACKET_SIZE: usize = 12;
+const ATAPI_SECTOR_SIZE: u64 = 2048;
+// Most the drive is allowed to hand over per DRQ block, a whole number of sectors under 64KiB
+const ATAPI_BYTE_COUNT_LIMIT: u16 = 0xF800;
+const ATAPI_MAX_SECTORS_PER_COMMAND: u64 = 16;
+// The first command after a disc is inserted or the drive is reset fails with UNIT ATTENTION, so probing retries
+const ATAPI_PROBE_ATTEMPTS: usize = 3;
+
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+// LBA28 only reaches the first 128GiB, and moves at most 256 sectors per command
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA28_MAX_SECTORS: u64 = 256;
+const LBA48_MAX_SECTORS: u64 = 65536;
 
 const IDE_STATUS_REG: u16 = 7;
 const IDE_STATUS_ERR: u8 = 1;
@@ -50,6 +73,7 @@ const IDE_REG_SECCOUNT: u16 = 2;
 
 const IDE_DATA_REG: u16 = 0;
 const IDE_ERR_REG: u16 = 1;
+const IDE_FEATURES_REG: u16 = 1;
 
 const IDE_BUSMASTER_PRDT_REG: u16 = 0x04;
 const IDE_BUSMASTER_COMMAND_REG: u16 = 0x00;
@@ -271,22 +295,22 @@ impl IdeController {
 
 	ide_controller.reset();
 
-	let locked = Arc::new(Mutex::new(ide_controller));
+	let locked = Arc::new(AsyncMutex::new(ide_controller));
 	if let Some(ide_drive) = IdeDrive::new(locked.clone(), 0) {
 	    let model = ide_drive.ident.get_model();
-	    let size = ide_drive.ident.get_size_in_sectors();
+	    let size = ide_drive.size_in_sectors;
 
 	    log::info!("Drive 0: {} - {} MiB", model, size / (1024 * 2));
 	    let device_arc = Arc::new(ide_drive);
-	    block::register_block_device(device_arc);
+	    block::register_disk(device_arc);
 	}
 	if let Some(ide_drive) = IdeDrive::new(locked, 1) {
 	    let model = ide_drive.ident.get_model();
-	    let size = ide_drive.ident.get_size_in_sectors();
+	    let size = ide_drive.size_in_sectors;
 	    log::info!("Drive 1: {} - {} MiB", model, size / (1024 * 2));
 
 	    let device_arc = Arc::new(ide_drive);
-	    block::register_block_device(device_arc);
+	    block::register_disk(device_arc);
 	}
     }
 
@@ -306,10 +330,12 @@ impl IdeController {
 }
 
 struct IdeDrive {
-    controller: Arc<Mutex<IdeController>>,
+    controller: Arc<AsyncMutex<IdeController>>,
     drive_num: u8,
     ident: IdentifyStruct,
     drive_type: DriveType,
+    // In 512 byte sectors. For packet devices this comes from the medium, rather than IDENTIFY
+    size_in_sectors: u64,
 }
 
 unsafe impl Send for IdeDrive { }
@@ -317,8 +343,14 @@ unsafe impl Sync for IdeDrive { }
 
 impl block::BlockDevice for IdeDrive {
     fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
-	if self.drive_type != DriveType::Ata {
-	    return Box::pin(async move { Err(syscall::CanonicalError::Io) });
+	if size == 0 || offset + size > self.size_in_sectors {
+	    return Box::pin(async move { Err(syscall::CanonicalError::Inval) });
+	}
+
+	match self.drive_type {
+	    DriveType::Atapi | DriveType::Satapi => return Box::pin(async move { self.clone().atapi_read(offset, size).await }),
+	    DriveType::Sata => return Box::pin(async move { Err(syscall::CanonicalError::Io) }),
+	    DriveType::Ata => (),
 	}
 	let mode = self.ident.get_mode();
 
@@ -327,10 +359,32 @@ impl block::BlockDevice for IdeDrive {
 	    _ => Box::pin(async move { self.clone().dma_read(offset, size).await }),
 	}
     }
+
+    // Writes are always PIO, as the bus master is only ever set up to read
+    fn write(self: Arc<Self>, offset: u64, data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	if self.drive_type != DriveType::Ata {
+	    return Box::pin(async move { Err(syscall::CanonicalError::RoFs) });
+	}
+
+	let size = data.len() as u64 / 512;
+	if data.is_empty() || !data.len().is_multiple_of(512) || offset + size > self.size_in_sectors {
+	    return Box::pin(async move { Err(syscall::CanonicalError::Inval) });
+	}
+
+	Box::pin(async move { self.clone().pio_write(offset, data).await })
+    }
+
+    fn size_in_sectors(&self) -> u64 {
+	self.size_in_sectors
+    }
+
+    fn model(&self) -> String {
+	self.ident.get_model()
+    }
 }
 
 impl IdeDrive {
-    pub fn new(controller: Arc<Mutex<IdeController>>, drive_num: u8) -> Option<IdeDrive> {
+    pub fn new(controller: Arc<AsyncMutex<IdeController>>, drive_num: u8) -> Option<IdeDrive> {
 	let mut ide_drive = IdeDrive {
 	    controller,
 	    drive_num,
@@ -338,6 +392,7 @@ impl IdeDrive {
 		..Default::default()
 	    },
 	    drive_type: DriveType::Ata,
+	    size_in_sectors: 0,
 	};
 
 	if !ide_drive.check_exists_and_set_type() {
@@ -345,12 +400,18 @@ impl IdeDrive {
 	}
 
 	ide_drive.set_ident();
+	ide_drive.size_in_sectors = match ide_drive.drive_type {
+	    DriveType::Atapi | DriveType::Satapi => ide_drive.atapi_size_in_sectors()?,
+	    _ => ide_drive.ident.get_size_in_sectors(),
+	};
+
 	Some(ide_drive)
     }
 
-    fn select(&self, ctl: &MutexGuard<'_, IdeController>) {
+    // In LBA28 mode, the top four bits of the address go in with the drive select
+    fn select(&self, ctl: &IdeController, lba_high: u8) {
 	// TODO: make port a shared, locked resource
-	let select_cmd = IDE_DRIVE_HEAD_BASE | if self.drive_num == 0 {
+	let select_cmd = IDE_DRIVE_HEAD_BASE | (lba_high & 0x0F) | if self.drive_num == 0 {
 	    IDE_DRIVE_HEAD_DRIVE_SEL_PRIMARY
 	} else {
 	    IDE_DRIVE_HEAD_DRIVE_SEL_SECONDARY
@@ -363,8 +424,9 @@ impl IdeDrive {
     }
 
     fn check_exists_and_set_type(&mut self) -> bool {
-	let ctl = self.controller.lock();
-	self.select(&ctl);
+	// Nothing else can have the controller yet, as the drives haven't been registered
+	let ctl = self.controller.try_lock().expect("IDE controller locked during probing");
+	self.select(&ctl, 0);
 	unsafe {
 	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
 	    cmd_reg.write(IDE_CMD_IDENTIFY);
@@ -415,8 +477,8 @@ impl IdeDrive {
     }
 
     fn set_ident(&mut self) {
-	let ctl = self.controller.lock();
-	self.select(&ctl);
+	let ctl = self.controller.try_lock().expect("IDE controller locked during probing");
+	self.select(&ctl, 0);
 
 	let cmd = match self.drive_type {
 	    DriveType::Atapi => IDE_CMD_PACKET_IDENTIFY,
@@ -450,16 +512,19 @@ impl IdeDrive {
 	};
     }
     
-    async fn pio_read(&self, offset: u64, size: u64) -> Result<Bytes, syscall::CanonicalError> {
-	let ctl = self.controller.lock();
-	self.select_drive_and_set_xfer_params(&ctl, offset, size);
-
-	unsafe {
-	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
-	    cmd_reg.write(IDE_CMD_READ_PIO_EXT);
+    // Picks LBA28 where it can reach, as older drives don't have anything else
+    fn use_lba48(&self, offset: u64, size: u64) -> Result<bool, syscall::CanonicalError> {
+	if offset + size <= LBA28_LIMIT && size <= LBA28_MAX_SECTORS {
+	    Ok(false)
+	} else if self.ident.is_lba48() && size <= LBA48_MAX_SECTORS {
+	    Ok(true)
+	} else {
+	    Err(syscall::CanonicalError::Inval)
 	}
+    }
 
-	for _ in 0 .. 1000000 { unsafe { asm!("nop"); } }
+    // Waits for the drive to be ready to move the next sector
+    fn wait_for_data(&self, ctl: &IdeController) -> Result<(), syscall::CanonicalError> {
 	unsafe {
 	    let mut status_reg = Port::<u8>::new(ctl.io_base + IDE_STATUS_REG);
 
@@ -471,50 +536,221 @@ impl IdeDrive {
 	    }
 
 	    let status = status_reg.read();
-	    if status & IDE_STATUS_ERR != 0 {
+	    if status & (IDE_STATUS_ERR | IDE_STATUS_DF) != 0 {
 		let mut err_reg = Port::<u8>::new(ctl.io_base + IDE_ERR_REG);
-		let err = err_reg.read();
-		panic!("Read failure: {:X}", err);
+		log::warn!("IDE drive {} transfer failed, status {:X}, error {:X}", self.drive_num, status, err_reg.read());
+		return Err(syscall::CanonicalError::Io);
 	    }
 	    if status & IDE_STATUS_DRQ == 0 {
-		panic!("Read failure");
+		log::warn!("IDE drive {} not ready for data, status {:X}", self.drive_num, status);
+		return Err(syscall::CanonicalError::Io);
 	    }
-	    if status & IDE_STATUS_DF != 0 {
-		panic!("Read failure");
+	}
+
+	Ok(())
+    }
+
+    async fn pio_read(&self, offset: u64, size: u64) -> Result<Bytes, syscall::CanonicalError> {
+	let ctl = self.controller.lock().await;
+	let lba48 = self.use_lba48(offset, size)?;
+	self.select_drive_and_set_xfer_params(&ctl, offset, size, lba48);
+
+	unsafe {
+	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
+	    cmd_reg.write(if lba48 { IDE_CMD_READ_PIO_EXT } else { IDE_CMD_READ_PIO });
+	}
+
+	for _ in 0 .. 1000000 { unsafe { asm!("nop"); } }
+
+	// Each sector has to be waited for separately
+	let mut data = vec::Vec::with_capacity((size * 512) as usize);
+	let mut data_reg = Port::<u16>::new(ctl.io_base + IDE_DATA_REG);
+	for _ in 0 .. size {
+	    self.wait_for_data(&ctl)?;
+	    for _ in 0 .. 256 {
+		let word = unsafe {
+		    data_reg.read()
+		};
+		data.extend_from_slice(&word.to_le_bytes());
 	    }
 	}
 
-	let buf_ptr = memory::kernel_allocate(
-	    size,
-	    memory::MemoryAllocationType::Ram)
-	    .expect("Unable to allocate heap").0.as_mut_ptr::<u16>();
+	Ok(bytes::Bytes::from(data))
+    }
 
-	let buf_u16 = unsafe {
-	    slice::from_raw_parts_mut(buf_ptr, (size / 2) as usize)
-	};
+    async fn pio_write(&self, offset: u64, data: Bytes) -> Result<(), syscall::CanonicalError> {
+	let ctl = self.controller.lock().await;
+	let size = data.len() as u64 / 512;
+	let lba48 = self.use_lba48(offset, size)?;
+	self.select_drive_and_set_xfer_params(&ctl, offset, size, lba48);
+
+	unsafe {
+	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
+	    cmd_reg.write(if lba48 { IDE_CMD_WRITE_PIO_EXT } else { IDE_CMD_WRITE_PIO });
+	}
+
+	for _ in 0 .. 1000000 { unsafe { asm!("nop"); } }
 
 	let mut data_reg = Port::<u16>::new(ctl.io_base + IDE_DATA_REG);
-	for i in 0 .. (size / 2) {
-	    buf_u16[i as usize] = unsafe {
-		data_reg.read()
+	for sector in data.chunks_exact(512) {
+	    self.wait_for_data(&ctl)?;
+	    for word in sector.chunks_exact(2) {
+		unsafe {
+		    data_reg.write(u16::from_le_bytes([word[0], word[1]]));
+		}
+	    }
+	}
+
+	// The data may only have reached the drive's cache so far
+	unsafe {
+	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
+	    cmd_reg.write(if lba48 { IDE_CMD_CACHE_FLUSH_EXT } else { IDE_CMD_CACHE_FLUSH });
+	}
+
+	let status = self.wait_not_busy(&ctl);
+	if status & (IDE_STATUS_ERR | IDE_STATUS_DF) != 0 {
+	    log::warn!("IDE drive {} cache flush failed, status {:X}", self.drive_num, status);
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	Ok(())
+    }
+
+    fn wait_not_busy(&self, ctl: &IdeController) -> u8 {
+	unsafe {
+	    let mut status_reg = Port::<u8>::new(ctl.io_base + IDE_STATUS_REG);
+
+	    loop {
+		let status = status_reg.read();
+		if status & IDE_STATUS_BSY == 0 {
+		    return status;
+		}
+	    }
+	}
+    }
+
+    // Sends a SCSI command to a packet device, and reads back whatever it answers with, using PIO
+    fn packet_command(&self, ctl: &IdeController, packet: &[u8; ATAPI_PACKET_SIZE], length: usize) -> Result<vec::Vec<u8>, syscall::CanonicalError> {
+	self.select(ctl, 0);
+
+	unsafe {
+	    let mut features_reg = Port::<u8>::new(ctl.io_base + IDE_FEATURES_REG);
+	    let mut byte_count_lo_reg = Port::<u8>::new(ctl.io_base + IDE_REG_CYL_LO);
+	    let mut byte_count_hi_reg = Port::<u8>::new(ctl.io_base + IDE_REG_CYL_HI);
+	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
+
+	    features_reg.write(0);  // PIO, not DMA
+	    byte_count_lo_reg.write(ATAPI_BYTE_COUNT_LIMIT as u8);
+	    byte_count_hi_reg.write((ATAPI_BYTE_COUNT_LIMIT >> 8) as u8);
+	    cmd_reg.write(IDE_CMD_PACKET);
+	}
+
+	// The drive asks for the packet the same way it asks for data
+	self.wait_for_data(ctl)?;
+	let mut data_reg = Port::<u16>::new(ctl.io_base + IDE_DATA_REG);
+	for word in packet.chunks_exact(2) {
+	    unsafe {
+		data_reg.write(u16::from_le_bytes([word[0], word[1]]));
+	    }
+	}
+
+	// Then hands over the answer in as many blocks as it likes, saying how big each is
+	let mut data = vec::Vec::with_capacity(length);
+	loop {
+	    unsafe {
+		let mut ctl_reg = Port::<u8>::new(ctl.control_base);
+		ctl_reg.read();
+		ctl_reg.read();
+		ctl_reg.read();
+		ctl_reg.read();
+	    }
+
+	    let status = self.wait_not_busy(ctl);
+	    if status & (IDE_STATUS_ERR | IDE_STATUS_DF) != 0 {
+		let sense_key = unsafe {
+		    let mut err_reg = Port::<u8>::new(ctl.io_base + IDE_ERR_REG);
+		    err_reg.read() >> 4
+		};
+		log::info!("ATAPI drive {} command {:X} failed, sense key {:X}", self.drive_num, packet[0], sense_key);
+		return Err(syscall::CanonicalError::Io);
+	    }
+	    if status & IDE_STATUS_DRQ == 0 {
+		break;
+	    }
+
+	    let byte_count = unsafe {
+		let mut byte_count_lo_reg = Port::<u8>::new(ctl.io_base + IDE_REG_CYL_LO);
+		let mut byte_count_hi_reg = Port::<u8>::new(ctl.io_base + IDE_REG_CYL_HI);
+		byte_count_lo_reg.read() as usize | ((byte_count_hi_reg.read() as usize) << 8)
 	    };
+	    for _ in 0 .. byte_count.div_ceil(2) {
+		let word = unsafe {
+		    data_reg.read()
+		};
+		data.extend_from_slice(&word.to_le_bytes());
+	    }
 	}
 
-	// Marshall into a Bytes
-	let data_from = unsafe {
-	    slice::from_raw_parts(buf_ptr as *const u8, size as usize)
-	};
-	Ok(bytes::Bytes::from(data_from))
+	if data.len() < length {
+	    log::info!("ATAPI drive {} command {:X} returned {} bytes, expected {}", self.drive_num, packet[0], data.len(), length);
+	    return Err(syscall::CanonicalError::Io);
+	}
+	data.truncate(length);
+
+	Ok(data)
     }
 
-    async fn dma_read(&self, offset: u64, size: u64) -> Result<Bytes, syscall::CanonicalError> {
-	let mut ctl = self.controller.lock();
+    // Packet devices don't give their size in IDENTIFY, as it depends on what's in them. No medium means nothing to
+    // register.
+    fn atapi_size_in_sectors(&self) -> Option<u64> {
+	let ctl = self.controller.try_lock().expect("IDE controller locked during probing");
+	let packet = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+	for _ in 0 .. ATAPI_PROBE_ATTEMPTS {
+	    if let Ok(capacity) = self.packet_command(&ctl, &packet, 8) {
+		let last_lba = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]) as u64;
+		let block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]) as u64;
+		if block_size != ATAPI_SECTOR_SIZE {
+		    log::info!("ATAPI drive {} has unsupported {} byte sectors", self.drive_num, block_size);
+		    return None;
+		}
+
+		return Some((last_lba + 1) * (ATAPI_SECTOR_SIZE / 512));
+	    }
+	}
+
+	log::info!("ATAPI drive {} has no medium", self.drive_num);
+	None
+    }
+
+    // Reads the 2048 byte sectors covering the 512 byte ones asked for
+    async fn atapi_read(&self, offset: u64, size: u64) -> Result<Bytes, syscall::CanonicalError> {
+	let ctl = self.controller.lock().await;
+
+	let sectors_per_block = ATAPI_SECTOR_SIZE / 512;
+	let first_block = offset / sectors_per_block;
+	let last_block = (offset + size - 1) / sectors_per_block;
+
+	let mut data = vec::Vec::with_capacity(((last_block + 1 - first_block) * ATAPI_SECTOR_SIZE) as usize);
+	let mut block = first_block;
+	while block <= last_block {
+	    let blocks = core::cmp::min(last_block + 1 - block, ATAPI_MAX_SECTORS_PER_COMMAND);
+	    let lba = (block as u32).to_be_bytes();
+	    let length = (blocks as u16).to_be_bytes();
+	    let packet = [SCSI_READ_10, 0, lba[0], lba[1], lba[2], lba[3], 0, length[0], length[1], 0, 0, 0];
+	    data.extend_from_slice(&self.packet_command(&ctl, &packet, (blocks * ATAPI_SECTOR_SIZE) as usize)?);
 
-	// We don't (yet) support multiple PRDs per transfer
-	if size > 65536 {
-	    panic!("Attempted to transfer more than max size");
+	    block += blocks;
 	}
 
+	let start = ((offset % sectors_per_block) * 512) as usize;
+	Ok(Bytes::from(data).slice(start .. start + (size * 512) as usize))
+    }
+
+    async fn dma_read(&self, offset: u64, size: u64) -> Result<Bytes, syscall::CanonicalError> {
+	let mut ctl = self.controller.lock().await;
+	let lba48 = self.use_lba48(offset, size)?;
+
 	let (buf_virt, buf_phys) = memory::kernel_allocate(
 	    size * 512, memory::MemoryAllocationType::Dma)
 	    .expect("Unable to allocate a PDRT memory region");
@@ -594,11 +830,11 @@ impl IdeDrive {
 	    prdt_status_reg.write(status);
 	}
 
-	self.select_drive_and_set_xfer_params(&ctl, offset, size);
+	self.select_drive_and_set_xfer_params(&ctl, offset, size, lba48);
 	
 	unsafe {
 	    let mut cmd_reg = Port::<u8>::new(ctl.io_base + IDE_CMD_REG);
-	    cmd_reg.write(IDE_CMD_READ_DMA_EXT);
+	    cmd_reg.write(if lba48 { IDE_CMD_READ_DMA_EXT } else { IDE_CMD_READ_DMA });
 	}
 
 	unsafe {
@@ -667,20 +903,22 @@ impl IdeDrive {
 	Ok(bytes::Bytes::from(data_from))
     }
 
-    fn select_drive_and_set_xfer_params(&self, ctl: &MutexGuard<'_, IdeController>, offset: u64, size: u64) {
-	self.select(ctl);
+    // LBA48 registers are two deep: the high order bytes are written first, and pushed back by the low order ones.
+    // A count of 0 means the most the command can move, 256 or 65536 sectors, which the truncation gives for free.
+    fn select_drive_and_set_xfer_params(&self, ctl: &IdeController, offset: u64, size: u64, lba48: bool) {
+	self.select(ctl, if lba48 { 0 } else { (offset >> 24) as u8 });
 
-	if self.ident.is_lba48() {
+	if lba48 {
 	    unsafe {
 		let mut lba3_reg = Port::<u8>::new(ctl.io_base + IDE_REG_LBA0);
 		let mut lba4_reg = Port::<u8>::new(ctl.io_base + IDE_REG_LBA1);
 		let mut lba5_reg = Port::<u8>::new(ctl.io_base + IDE_REG_LBA2);
 		let mut seccount1_reg = Port::<u8>::new(ctl.io_base + IDE_REG_SECCOUNT);
 
+		seccount1_reg.write((size >> 8) as u8);
 		lba3_reg.write((offset >> 24) as u8);
 		lba4_reg.write((offset >> 32) as u8);
 		lba5_reg.write((offset >> 40) as u8);
-		seccount1_reg.write((size >> 8) as u8);
 	    }
 	}
 
@@ -690,10 +928,35 @@ impl IdeDrive {
 	    let mut lba2_reg = Port::<u8>::new(ctl.io_base + IDE_REG_LBA2);
 	    let mut seccount0_reg = Port::<u8>::new(ctl.io_base + IDE_REG_SECCOUNT);
 	    
+	    seccount0_reg.write(size as u8);
 	    lba0_reg.write(offset as u8);
 	    lba1_reg.write((offset >> 8) as u8);
 	    lba2_reg.write((offset >> 16) as u8);
-	    seccount0_reg.write(size as u8);
+	}
+    }
+}
+
+// Aborts any DMA transfer in progress on the channel, so nothing is written to memory after shutdown
+pub fn stop_busmaster(busmaster_base: u32) {
+    unsafe {
+	let mut command_reg = Port::<u8>::new(busmaster_base as u16 + IDE_BUSMASTER_COMMAND_REG);
+	let value = command_reg.read();
+	command_reg.write(value & !IDE_BUSMASTER_COMMAND_START);
+    }
+}
+
+// Reading the status register deasserts the channel's INTRQ, and the bus master's interrupt bit is write-1-to-clear
+pub fn acknowledge_interrupt(io_base: u16, busmaster_base: Option<u32>) {
+    unsafe {
+	let mut status_reg = Port::<u8>::new(io_base + IDE_STATUS_REG);
+	status_reg.read();
+    }
+
+    if let Some(busmaster_base) = busmaster_base {
+	unsafe {
+	    let mut status_reg = Port::<u8>::new(busmaster_base as u16 + IDE_BUSMASTER_STATUS_REG);
+	    let status = status_reg.read();
+	    status_reg.write(status | IDE_BUSMASTER_STATUS_INTERRUPT);
 	}
     }
 }
@@ -1,7 +1,10 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::drivers::pcie;
 use crate::driver;
+use crate::sys::acpi::{namespace, resources};
 
 mod controller;
 
@@ -10,26 +13,120 @@ pub fn init() {
     driver::register_driver(Box::new(ide_driver));
 }
 
+// Where the channels of a compatibility mode controller would be, if the firmware doesn't describe them
+const LEGACY_CHANNELS: [IdeChannel; 2] = [
+    IdeChannel { io_base: 0x1F0, control_base: 0x3F6 },
+    IdeChannel { io_base: 0x170, control_base: 0x376 },
+];
+
+// Programming interface bits saying each channel is in native PCI mode, rather than compatibility mode
+const IDE_INTERFACE_PRIMARY_NATIVE: u8 = 1 << 0;
+const IDE_INTERFACE_SECONDARY_NATIVE: u8 = 1 << 2;
+
+// Set as each compatibility mode channel is taken. Those are at the legacy ports whichever controller they belong to,
+// so if there's more than one compatibility mode controller, only the first to probe can have them
+static COMPAT_CHANNELS_CLAIMED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdeChannel {
+    io_base: u16,
+    control_base: u16,
+}
+
+// Each PNP0600 device is a single channel, with the command block first in its _CRS and the control
+// block second
+fn channel_from_resources(device_resources: &[resources::Resource]) -> Option<IdeChannel> {
+    let io_ranges = resources::io_ranges(device_resources);
+    if io_ranges.len() < 2 {
+	log::warn!("IDE channel _CRS has {} I/O ranges, expected 2", io_ranges.len());
+	return None;
+    }
+
+    log::info!("ACPI IDE channel at {:#X}/{:#X}, IRQs {:?}", io_ranges[0].base, io_ranges[1].base, resources::irqs(device_resources));
+    Some(IdeChannel {
+	io_base: io_ranges[0].base,
+	control_base: io_ranges[1].base,
+    })
+}
+
+fn acpi_channels() -> Vec<IdeChannel> {
+    let devices = match namespace::find_devices("PNP0600") {
+	Ok(devices) => devices,
+	Err(e) => {
+	    log::warn!("Unable to search ACPI namespace for IDE channels: {:?}", e);
+	    return Vec::new();
+	},
+    };
+
+    let mut channels = Vec::new();
+    for device in devices {
+	match resources::get_resources(device) {
+	    Ok(device_resources) => channels.extend(channel_from_resources(&device_resources)),
+	    Err(e) => log::warn!("Unable to read _CRS for IDE channel: {:?}", e),
+	}
+    }
+
+    channels
+}
+
+// A compatibility mode channel decodes the legacy command block ports, so it's whichever channel ACPI puts there,
+// whatever order the firmware lists them in. ACPI may move the control block, though
+fn compat_channel(acpi_channels: &[IdeChannel], legacy: IdeChannel) -> IdeChannel {
+    acpi_channels.iter()
+	.find(|c| c.io_base == legacy.io_base)
+	.copied()
+	.unwrap_or(legacy)
+}
+
+// index is 0 for the primary channel, 1 for the secondary
+fn claim_compat_channel(acpi_channels: &[IdeChannel], index: usize) -> Option<IdeChannel> {
+    if COMPAT_CHANNELS_CLAIMED[index].swap(true, Ordering::SeqCst) {
+	log::info!("IDE channel at {:#X} already belongs to another controller", LEGACY_CHANNELS[index].io_base);
+	return None;
+    }
+
+    Some(compat_channel(acpi_channels, LEGACY_CHANNELS[index]))
+}
+
+// A native mode channel's command block is in the BAR given, and its control block in the one after. The control
+// block BAR covers four ports, with the device control/alternate status register at offset 2.
+fn native_channel(pci_info: &pcie::PciDeviceType, command_slot: u8) -> Option<IdeChannel> {
+    let command_bar = pcie::get_bar(pci_info.clone(), command_slot)?;
+    let control_bar = pcie::get_bar(pci_info.clone(), command_slot + 1)?;
+
+    let channel = IdeChannel {
+	io_base: command_bar.unwrap_io() as u16,
+	control_base: control_bar.unwrap_io() as u16 + 2,
+    };
+    log::info!("Native mode IDE channel at {:#X}/{:#X}", channel.io_base, channel.control_base);
+
+    Some(channel)
+}
+
 pub struct IdeDriver {}
 impl driver::Driver for IdeDriver {
-    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
 	let pci_info = if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
 	    pci_info
 	} else {
-	    return;
+	    return driver::ProbeResult::Bound;
 	};
 
 	let interface = pci_info.interface;
+	let primary_native = (interface & IDE_INTERFACE_PRIMARY_NATIVE) != 0;
+	let secondary_native = (interface & IDE_INTERFACE_SECONDARY_NATIVE) != 0;
 
-	// For now, we only support compatibility mode
-	if (interface & 1) == 1 {
-	    unimplemented!();
-	}
+	// Channels in compatibility mode are where ACPI or convention puts them, native mode ones are in the BARs
+	let acpi_channels = if !primary_native || !secondary_native {
+	    acpi_channels()
+	} else {
+	    Vec::new()
+	};
 
-	let control_primary_base = 0x3F6;
-	let control_secondary_base = 0x376;
-	let io_primary_base = 0x1F0;
-	let io_secondary_base = 0x170;
+	let channels = [
+	    if primary_native { native_channel(pci_info, 0) } else { claim_compat_channel(&acpi_channels, 0) },
+	    if secondary_native { native_channel(pci_info, 2) } else { claim_compat_channel(&acpi_channels, 1) },
+	];
 
 	// This device supports bus mastering
 	let (busmaster_primary_base, busmaster_secondary_base) = if (interface & 0x80) != 0 {
@@ -38,15 +135,44 @@ impl driver::Driver for IdeDriver {
 
 	    // Assume I/O space for now. It might not be, but assume it is
 	    let busmaster_base = bar.unwrap_io();
+	    driver::register_shutdown_hook(Box::new(move || {
+		controller::stop_busmaster(busmaster_base);
+		controller::stop_busmaster(busmaster_base + 0x08);
+	    }));
+
 	    (Some(busmaster_base), Some(busmaster_base + 0x08))
 	} else {
 	    (None, None)
 	};
 
-	log::info!("Primary IDE Bus:");
-	controller::detect_drives(control_primary_base, io_primary_base, busmaster_primary_base);
-	log::info!("Secondary IDE Bus:");
-	controller::detect_drives(control_secondary_base, io_secondary_base, busmaster_secondary_base);
+	// The bus master registers for each channel follow on from each other in the same order as the channels
+	let busmaster_bases = [busmaster_primary_base, busmaster_secondary_base];
+
+	// Native mode channels interrupt through the PCI interrupt pin rather than IRQ14/15. Drives are polled with
+	// interrupts masked, but the line may be shared, so anything a channel does raise is acknowledged.
+	let native_channels = channels.iter().zip(busmaster_bases)
+	    .zip([primary_native, secondary_native])
+	    .filter_map(|((channel, busmaster_base), native)| if native { channel.map(|c| (c, busmaster_base)) } else { None })
+	    .collect::<Vec<_>>();
+	if !native_channels.is_empty() {
+	    if let Some(interrupt_route) = &pci_info.interrupt_mapping {
+		pcie::enable_interrupts(pci_info.clone());
+		interrupt_route.register_handler(Box::new(move || {
+		    for (channel, busmaster_base) in native_channels.iter() {
+			controller::acknowledge_interrupt(channel.io_base, *busmaster_base);
+		    }
+		}));
+	    }
+	}
+
+	for (i, channel) in channels.iter().enumerate() {
+	    if let Some(channel) = channel {
+		log::info!("IDE Bus {}:", i);
+		controller::detect_drives(channel.control_base, channel.io_base, busmaster_bases[i]);
+	    }
+	}
+
+	driver::ProbeResult::Bound
     }
 
     fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
@@ -62,3 +188,24 @@ impl driver::Driver for IdeDriver {
 	true // Not yet implemented
     }
 }
+
+#[test]
+fn compat_channels_are_matched_by_io_base() {
+    use alloc::vec;
+    use resources::Resource;
+
+    let fixed_io = |address, length| Resource::FixedIo { address, length };
+    let irq = |irq| Resource::Irq { triggering: 0, polarity: 0, sharing: false, wake_capability: false, irqs: vec![irq] };
+
+    // Secondary first, with its control block moved, as firmware is free to list them in any order
+    let acpi = [
+	vec![fixed_io(0x170, 8), fixed_io(0x374, 1), irq(15)],
+	vec![fixed_io(0x1F0, 8), fixed_io(0x3F6, 1), irq(14)],
+	vec![fixed_io(0x1E8, 8)],
+    ].iter().filter_map(|r| channel_from_resources(r)).collect::<Vec<IdeChannel>>();
+    assert_eq!(acpi.len(), 2);
+
+    assert_eq!(compat_channel(&acpi, LEGACY_CHANNELS[0]), IdeChannel { io_base: 0x1F0, control_base: 0x3F6 });
+    assert_eq!(compat_channel(&acpi, LEGACY_CHANNELS[1]), IdeChannel { io_base: 0x170, control_base: 0x374 });
+    assert_eq!(compat_channel(&[], LEGACY_CHANNELS[1]), LEGACY_CHANNELS[1]);
+}
@@ -1,13 +1,19 @@
+mod ahci;
 pub mod hpet;
 pub mod pcie;
 mod ide;
+mod nvme;
+pub mod rtc;
 mod usb;
 mod usbhid;
 
 pub fn init() {
     hpet::init();
+    rtc::init();
     pcie::init();
     ide::init();
+    ahci::init();
+    nvme::init();
     usb::init();
     usbhid::init();
 }
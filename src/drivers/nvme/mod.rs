@@ -0,0 +1,553 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::cmp;
+use core::hint;
+use core::ptr::{read_volatile, write_volatile};
+use core::slice;
+use futures_util::future::BoxFuture;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::driver;
+use crate::drivers::pcie;
+use crate::memory;
+use crate::sys::block;
+use crate::sys::syscall;
+use crate::sys::time;
+use crate::utils::async_mutex::AsyncMutex;
+use crate::utils::completion::Completion;
+
+mod queue;
+
+const NVME_CAP: u64 = 0x00;
+const NVME_VS: u64 = 0x08;
+const NVME_INTMS: u64 = 0x0C;
+const NVME_CC: u64 = 0x14;
+const NVME_CSTS: u64 = 0x1C;
+const NVME_AQA: u64 = 0x24;
+const NVME_ASQ: u64 = 0x28;
+const NVME_ACQ: u64 = 0x30;
+
+const NVME_CC_EN: u32 = 1 << 0;
+const NVME_CC_SHN_NORMAL: u32 = 1 << 14;
+const NVME_CC_IOSQES: u32 = 6 << 16;  // 64 byte submission entries
+const NVME_CC_IOCQES: u32 = 4 << 20;  // 16 byte completion entries
+
+const NVME_CSTS_RDY: u32 = 1 << 0;
+const NVME_CSTS_CFS: u32 = 1 << 1;
+const NVME_CSTS_SHST_MASK: u32 = 3 << 2;
+const NVME_CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+const ADMIN_CREATE_IO_SQ: u32 = 0x01;
+const ADMIN_CREATE_IO_CQ: u32 = 0x05;
+const ADMIN_IDENTIFY: u32 = 0x06;
+const ADMIN_SET_FEATURES: u32 = 0x09;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+const NVM_FLUSH: u32 = 0x00;
+const NVM_WRITE: u32 = 0x01;
+const NVM_READ: u32 = 0x02;
+
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+const IO_QUEUE_ID: u16 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+// Data goes through a physically contiguous bounce buffer this big, described by a PRP list when it's more than two
+// pages. The controller may allow less per command than this.
+const BUFFER_PAGES: u64 = 16;
+const BUFFER_SIZE: u64 = BUFFER_PAGES * PAGE_SIZE;
+
+const SHUTDOWN_TIMEOUT_NS: u64 = time::NANOSECONDS_PER_SECOND;
+
+fn reg_read(regs: VirtAddr, reg: u64) -> u32 {
+    unsafe {
+	read_volatile((regs + reg).as_ptr::<u32>())
+    }
+}
+
+fn reg_write(regs: VirtAddr, reg: u64, value: u32) {
+    unsafe {
+	write_volatile((regs + reg).as_mut_ptr::<u32>(), value);
+    }
+}
+
+fn reg_read_64(regs: VirtAddr, reg: u64) -> u64 {
+    unsafe {
+	read_volatile((regs + reg).as_ptr::<u64>())
+    }
+}
+
+fn reg_write_64(regs: VirtAddr, reg: u64, value: u64) {
+    unsafe {
+	write_volatile((regs + reg).as_mut_ptr::<u64>(), value);
+    }
+}
+
+// Waits for CSTS.RDY to match ready, for as long as CAP.TO says the controller may take
+fn wait_ready(regs: VirtAddr, ready: bool) -> bool {
+    let timeout_ns = ((reg_read_64(regs, NVME_CAP) >> 24) & 0xFF) * 500 * 1_000_000;
+    let deadline = time::get_monotonic_ns() + timeout_ns;
+    loop {
+	let csts = reg_read(regs, NVME_CSTS);
+	if csts & NVME_CSTS_CFS != 0 {
+	    log::warn!("NVMe controller fatal status");
+	    return false;
+	}
+	if (csts & NVME_CSTS_RDY != 0) == ready {
+	    return true;
+	}
+	if time::get_monotonic_ns() > deadline {
+	    return false;
+	}
+	hint::spin_loop();
+    }
+}
+
+fn with_prp1(mut command: queue::Command, addr: PhysAddr) -> queue::Command {
+    command[6] = addr.as_u64() as u32;
+    command[7] = (addr.as_u64() >> 32) as u32;
+    command
+}
+
+// The I/O queue, and the memory commands on it move data through. Shared by all of a controller's namespaces.
+struct IoQueue {
+    queue: queue::QueuePair,
+    buffer: VirtAddr,
+    buffer_phys: PhysAddr,
+    // Filled in for each command which needs more than two pages
+    prp_list: VirtAddr,
+    prp_list_phys: PhysAddr,
+    // Most bytes the controller will move in one command
+    max_transfer: u64,
+    // Completed by the controller's interrupt, if it has one we can use, and polled for if not
+    interrupt: Option<Arc<Completion<()>>>,
+}
+
+impl IoQueue {
+    fn buffer(&mut self) -> &mut [u8] {
+	unsafe {
+	    slice::from_raw_parts_mut(self.buffer.as_mut_ptr::<u8>(), BUFFER_SIZE as usize)
+	}
+    }
+
+    async fn run(&mut self, command: queue::Command) -> Result<u32, syscall::CanonicalError> {
+	match self.interrupt.clone() {
+	    Some(interrupt) => self.queue.run_with_interrupt(command, &interrupt).await,
+	    None => self.queue.run(command),
+	}
+    }
+
+    // Moves blocks between the namespace and memory starting offset bytes into the first of pages, and carrying on
+    // through the rest. The offset must be a multiple of 4
+    async fn transfer_pages(&mut self, opcode: u32, nsid: u32, lba: u64, blocks: u64, block_size: u64, pages: &[PhysAddr], offset: u64) -> Result<(), syscall::CanonicalError> {
+	let n_pages = (offset + blocks * block_size).div_ceil(PAGE_SIZE) as usize;
+	let prp2 = match n_pages {
+	    0 | 1 => 0,
+	    2 => pages[1].as_u64(),
+	    _ => {
+		// The first page is in the command itself, the list has the rest
+		let prps = unsafe {
+		    slice::from_raw_parts_mut(self.prp_list.as_mut_ptr::<u64>(), n_pages - 1)
+		};
+		for (prp, page) in prps.iter_mut().zip(&pages[1 .. n_pages]) {
+		    *prp = page.as_u64();
+		}
+		self.prp_list_phys.as_u64()
+	    },
+	};
+
+	let mut command = with_prp1([0; 16], pages[0] + offset);
+	command[0] = opcode;
+	command[1] = nsid;
+	command[8] = prp2 as u32;
+	command[9] = (prp2 >> 32) as u32;
+	command[10] = lba as u32;
+	command[11] = (lba >> 32) as u32;
+	command[12] = (blocks - 1) as u32;
+
+	self.run(command).await.map(|_| ())
+    }
+
+    // Moves blocks between the namespace and the start of the bounce buffer
+    async fn transfer(&mut self, opcode: u32, nsid: u32, lba: u64, blocks: u64, block_size: u64) -> Result<(), syscall::CanonicalError> {
+	let pages = (0 .. BUFFER_PAGES)
+	    .map(|i| self.buffer_phys + i * PAGE_SIZE)
+	    .collect::<Vec<PhysAddr>>();
+	self.transfer_pages(opcode, nsid, lba, blocks, block_size, &pages, 0).await
+    }
+}
+
+struct NvmeNamespace {
+    io: Arc<AsyncMutex<IoQueue>>,
+    nsid: u32,
+    block_size: u64,
+    block_count: u64,
+    model: String,
+}
+
+impl NvmeNamespace {
+    fn sectors_per_block(&self) -> u64 {
+	self.block_size / 512
+    }
+
+    async fn read_blocks(&self, io: &mut IoQueue, first_block: u64, count: u64) -> Result<Vec<u8>, syscall::CanonicalError> {
+	let max_blocks = io.max_transfer / self.block_size;
+	let mut data = Vec::with_capacity((count * self.block_size) as usize);
+	let mut block = first_block;
+	while block < first_block + count {
+	    let blocks = cmp::min(first_block + count - block, max_blocks);
+	    io.transfer(NVM_READ, self.nsid, block, blocks, self.block_size).await?;
+	    data.extend_from_slice(&io.buffer()[.. (blocks * self.block_size) as usize]);
+
+	    block += blocks;
+	}
+
+	Ok(data)
+    }
+
+    async fn write_blocks(&self, io: &mut IoQueue, first_block: u64, data: &[u8]) -> Result<(), syscall::CanonicalError> {
+	let max_blocks = io.max_transfer / self.block_size;
+	for (i, chunk) in data.chunks((max_blocks * self.block_size) as usize).enumerate() {
+	    io.buffer()[.. chunk.len()].copy_from_slice(chunk);
+	    io.transfer(NVM_WRITE, self.nsid, first_block + i as u64 * max_blocks, chunk.len() as u64 / self.block_size, self.block_size).await?;
+	}
+
+	Ok(())
+    }
+}
+
+impl block::BlockDevice for NvmeNamespace {
+    fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	Box::pin(async move {
+	    if size == 0 || offset + size > self.size_in_sectors() {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let mut io = self.io.lock().await;
+
+	    // The blocks covering the sectors asked for, which may start and end part way through one
+	    let sectors_per_block = self.sectors_per_block();
+	    let first_block = offset / sectors_per_block;
+	    let last_block = (offset + size - 1) / sectors_per_block;
+	    let data = self.read_blocks(&mut io, first_block, last_block + 1 - first_block).await?;
+
+	    let start = ((offset % sectors_per_block) * 512) as usize;
+	    Ok(Bytes::from(data).slice(start .. start + (size * 512) as usize))
+	})
+    }
+
+    // Only whole blocks can go straight to the frames. Anything else needs the bounce buffer to be read into
+    fn read_to_frames(self: Arc<Self>, offset: u64, size: u64, frames: Vec<PhysAddr>, frame_offset: u64) -> Option<BoxFuture<'static, Result<(), syscall::CanonicalError>>> {
+	let sectors_per_block = self.sectors_per_block();
+	if size == 0 || offset % sectors_per_block != 0 || size % sectors_per_block != 0 || frame_offset % 4 != 0 {
+	    return None;
+	}
+
+	Some(Box::pin(async move {
+	    if offset + size > self.size_in_sectors() || (frame_offset + size * 512).div_ceil(PAGE_SIZE) > frames.len() as u64 {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let mut io = self.io.lock().await;
+	    let max_blocks = io.max_transfer / self.block_size;
+	    let first_block = offset / sectors_per_block;
+	    let count = size / sectors_per_block;
+
+	    let mut block = first_block;
+	    while block < first_block + count {
+		let blocks = cmp::min(first_block + count - block, max_blocks);
+		let position = frame_offset + (block - first_block) * self.block_size;
+		let pages = &frames[(position / PAGE_SIZE) as usize ..];
+		io.transfer_pages(NVM_READ, self.nsid, block, blocks, self.block_size, pages, position % PAGE_SIZE).await?;
+
+		block += blocks;
+	    }
+
+	    Ok(())
+	}))
+    }
+
+    fn write(self: Arc<Self>, offset: u64, data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    let size = data.len() as u64 / 512;
+	    if data.is_empty() || !data.len().is_multiple_of(512) || offset + size > self.size_in_sectors() {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let mut io = self.io.lock().await;
+
+	    let sectors_per_block = self.sectors_per_block();
+	    let first_block = offset / sectors_per_block;
+	    let last_block = (offset + size - 1) / sectors_per_block;
+
+	    // Sectors which only cover part of a block have the rest of it read in first, so it can be written back whole
+	    if offset % sectors_per_block == 0 && (offset + size) % sectors_per_block == 0 {
+		self.write_blocks(&mut io, first_block, &data).await
+	    } else {
+		let mut blocks = self.read_blocks(&mut io, first_block, last_block + 1 - first_block).await?;
+		let start = ((offset % sectors_per_block) * 512) as usize;
+		blocks[start .. start + data.len()].copy_from_slice(&data);
+		self.write_blocks(&mut io, first_block, &blocks).await
+	    }
+	})
+    }
+
+    fn flush(self: Arc<Self>) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    let mut io = self.io.lock().await;
+
+	    let mut command = [0; 16];
+	    command[0] = NVM_FLUSH;
+	    command[1] = self.nsid;
+	    io.run(command).await.map(|_| ())
+	})
+    }
+
+    fn size_in_sectors(&self) -> u64 {
+	self.block_count * self.sectors_per_block()
+    }
+
+    fn model(&self) -> String {
+	self.model.clone()
+    }
+}
+
+// Resets the controller, and brings it back up with an admin queue
+fn enable_controller(regs: VirtAddr, doorbell_stride: u64, polled: bool) -> Option<queue::QueuePair> {
+    if reg_read(regs, NVME_CC) & NVME_CC_EN != 0 {
+	reg_write(regs, NVME_CC, reg_read(regs, NVME_CC) & !NVME_CC_EN);
+    }
+    if !wait_ready(regs, false) {
+	log::warn!("NVMe controller did not reset");
+	return None;
+    }
+
+    let admin = queue::QueuePair::new(regs, 0, doorbell_stride);
+    let queue_size = (queue::QUEUE_SIZE - 1) as u32;
+    reg_write(regs, NVME_AQA, (queue_size << 16) | queue_size);
+    reg_write_64(regs, NVME_ASQ, admin.sq_phys.as_u64());
+    reg_write_64(regs, NVME_ACQ, admin.cq_phys.as_u64());
+
+    // Without an interrupt we can take, completions are polled for, and it stays masked. INTMS mustn't be touched
+    // once MSI-X is on, but nothing masks it there.
+    if polled {
+	reg_write(regs, NVME_INTMS, 0xFFFF_FFFF);
+    }
+
+    // NVM command set, 4KiB pages, round robin arbitration
+    reg_write(regs, NVME_CC, NVME_CC_IOSQES | NVME_CC_IOCQES | NVME_CC_EN);
+    if !wait_ready(regs, true) {
+	log::warn!("NVMe controller did not become ready");
+	return None;
+    }
+
+    Some(admin)
+}
+
+fn create_io_queue(admin: &mut queue::QueuePair, regs: VirtAddr, doorbell_stride: u64, polled: bool) -> Option<queue::QueuePair> {
+    let mut set_queues = [0; 16];
+    set_queues[0] = ADMIN_SET_FEATURES;
+    set_queues[10] = FEATURE_NUMBER_OF_QUEUES;
+    set_queues[11] = 0;  // One of each, both counts being zero based
+    admin.run(set_queues).ok()?;
+
+    let io = queue::QueuePair::new(regs, IO_QUEUE_ID, doorbell_stride);
+    let queue_size = (queue::QUEUE_SIZE - 1) as u32;
+
+    let mut create_cq = with_prp1([0; 16], io.cq_phys);
+    create_cq[0] = ADMIN_CREATE_IO_CQ;
+    create_cq[10] = (queue_size << 16) | IO_QUEUE_ID as u32;
+    // On interrupt 0, as that's the one we set up
+    create_cq[11] = if polled { QUEUE_PHYSICALLY_CONTIGUOUS } else { QUEUE_PHYSICALLY_CONTIGUOUS | QUEUE_INTERRUPTS_ENABLED };
+    admin.run(create_cq).ok()?;
+
+    let mut create_sq = with_prp1([0; 16], io.sq_phys);
+    create_sq[0] = ADMIN_CREATE_IO_SQ;
+    create_sq[10] = (queue_size << 16) | IO_QUEUE_ID as u32;
+    create_sq[11] = ((IO_QUEUE_ID as u32) << 16) | QUEUE_PHYSICALLY_CONTIGUOUS;
+    admin.run(create_sq).ok()?;
+
+    Some(io)
+}
+
+fn identify(admin: &mut queue::QueuePair, buffer: (VirtAddr, PhysAddr), cns: u32, nsid: u32) -> Option<&'static [u8]> {
+    let mut command = with_prp1([0; 16], buffer.1);
+    command[0] = ADMIN_IDENTIFY;
+    command[1] = nsid;
+    command[10] = cns;
+    admin.run(command).ok()?;
+
+    Some(unsafe {
+	slice::from_raw_parts(buffer.0.as_ptr::<u8>(), PAGE_SIZE as usize)
+    })
+}
+
+fn probe_controller(regs: VirtAddr, interrupt: Option<Arc<Completion<()>>>) {
+    let cap = reg_read_64(regs, NVME_CAP);
+    let doorbell_stride = 4 << ((cap >> 32) & 0xF);
+    let vs = reg_read(regs, NVME_VS);
+    log::info!("NVMe {}.{} controller", vs >> 16, (vs >> 8) & 0xFF);
+
+    let mut admin = match enable_controller(regs, doorbell_stride, interrupt.is_none()) {
+	Some(a) => a,
+	None => return,
+    };
+
+    let (identify_virt, identify_phys) = memory::kernel_allocate(
+	PAGE_SIZE, memory::MemoryAllocationType::Dma)
+	.expect("Unable to allocate NVMe identify buffer");
+    let identify_buffer = (identify_virt, identify_phys[0]);
+
+    let (model, max_transfer) = match identify(&mut admin, identify_buffer, IDENTIFY_CONTROLLER, 0) {
+	Some(ident) => {
+	    let model = String::from(String::from_utf8_lossy(&ident[24 .. 64]).trim());
+
+	    // MDTS is a power of two multiple of the minimum page size, with zero meaning no limit
+	    let mdts = ident[77] as u64;
+	    let min_page_size = PAGE_SIZE << ((cap >> 48) & 0xF);
+	    let max_transfer = if mdts == 0 { BUFFER_SIZE } else { cmp::min(BUFFER_SIZE, min_page_size << mdts) };
+
+	    (model, max_transfer)
+	},
+	None => {
+	    log::warn!("NVMe IDENTIFY CONTROLLER failed");
+	    return;
+	},
+    };
+
+    let io_queue = match create_io_queue(&mut admin, regs, doorbell_stride, interrupt.is_none()) {
+	Some(q) => q,
+	None => {
+	    log::warn!("Unable to create NVMe I/O queue");
+	    return;
+	},
+    };
+
+    let (buffer, buffer_phys) = memory::kernel_allocate(
+	BUFFER_SIZE, memory::MemoryAllocationType::Dma)
+	.expect("Unable to allocate NVMe bounce buffer");
+    let (prp_list, prp_list_phys) = memory::kernel_allocate(
+	PAGE_SIZE, memory::MemoryAllocationType::Dma)
+	.expect("Unable to allocate NVMe PRP list");
+
+    let io = Arc::new(AsyncMutex::new(IoQueue {
+	queue: io_queue,
+	buffer,
+	buffer_phys: buffer_phys[0],
+	prp_list,
+	prp_list_phys: prp_list_phys[0],
+	max_transfer,
+	interrupt,
+    }));
+
+    let nsids = match identify(&mut admin, identify_buffer, IDENTIFY_ACTIVE_NAMESPACES, 0) {
+	Some(list) => list.chunks_exact(4)
+	    .map(|nsid| u32::from_le_bytes([nsid[0], nsid[1], nsid[2], nsid[3]]))
+	    .take_while(|nsid| *nsid != 0)
+	    .collect::<Vec<u32>>(),
+	None => {
+	    log::warn!("Unable to list NVMe namespaces");
+	    return;
+	},
+    };
+
+    for nsid in nsids {
+	let ident = match identify(&mut admin, identify_buffer, IDENTIFY_NAMESPACE, nsid) {
+	    Some(ident) => ident,
+	    None => continue,
+	};
+
+	let block_count = u64::from_le_bytes(ident[0 .. 8].try_into().unwrap());
+	let format = (ident[26] & 0xF) as usize;
+	let block_size_shift = ident[128 + format * 4 + 2];
+	if !(9 ..= 12).contains(&block_size_shift) {
+	    log::info!("NVMe namespace {}: unsupported block size 2^{}", nsid, block_size_shift);
+	    continue;
+	}
+	let block_size = 1 << block_size_shift;
+
+	log::info!("NVMe namespace {}: {} - {} MiB", nsid, model, (block_count * block_size) / (1024 * 1024));
+	block::register_disk(Arc::new(NvmeNamespace {
+	    io: io.clone(),
+	    nsid,
+	    block_size,
+	    block_count,
+	    model: model.clone(),
+	}));
+    }
+}
+
+// Tells the controller we're going, so it can get everything it's cached onto the media
+fn shutdown_controller(regs: VirtAddr) {
+    if reg_read(regs, NVME_CC) & NVME_CC_EN == 0 {
+	return;
+    }
+
+    reg_write(regs, NVME_CC, reg_read(regs, NVME_CC) | NVME_CC_SHN_NORMAL);
+
+    let deadline = time::get_monotonic_ns() + SHUTDOWN_TIMEOUT_NS;
+    while reg_read(regs, NVME_CSTS) & NVME_CSTS_SHST_MASK != NVME_CSTS_SHST_COMPLETE {
+	if time::get_monotonic_ns() > deadline {
+	    log::warn!("NVMe controller did not finish shutting down");
+	    return;
+	}
+	hint::spin_loop();
+    }
+}
+
+pub fn init() {
+    let nvme_driver = NvmeDriver {};
+    driver::register_driver(Box::new(nvme_driver));
+}
+
+pub struct NvmeDriver {}
+impl driver::Driver for NvmeDriver {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
+	let pci_info = if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
+	    pci_info
+	} else {
+	    return driver::ProbeResult::Bound;
+	};
+
+	let bar = pcie::get_bar(pci_info.clone(), 0).expect("Unable to find NVMe BAR");
+
+	// The registers are always in memory space
+	let (regs_phys, regs_size) = bar.unwrap_mem();
+	let regs = memory::allocate_mmio(regs_phys, regs_size).expect("Unable to map NVMe registers");
+	pcie::enable_bus_mastering(pci_info.clone());
+
+	// I/O completions are signalled on interrupt 0, if the controller can send it by MSI. The admin queue's are too,
+	// but those are only ever polled for.
+	let interrupt = Arc::new(Completion::new());
+	let interrupt_handler = interrupt.clone();
+	let interrupt = pcie::enable_msi(pci_info.clone(), 0, Box::new(move || interrupt_handler.complete(())))
+	    .map(|_| interrupt);
+
+	probe_controller(regs, interrupt);
+	driver::register_shutdown_hook(Box::new(move || shutdown_controller(regs)));
+
+	driver::ProbeResult::Bound
+    }
+
+    fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
+	    pci_info.base_class == 0x01 &&
+		pci_info.sub_class == 0x08 &&
+		pci_info.interface == 0x02
+	} else {
+	    false
+	}
+    }
+
+    fn check_new_device(&self, _info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	true // Not yet implemented
+    }
+}
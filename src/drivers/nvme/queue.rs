@@ -0,0 +1,166 @@
+use core::hint;
+use core::ptr::{read_volatile, write_volatile};
+use core::slice;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+use crate::sys::syscall;
+use crate::sys::time;
+use crate::utils::completion::Completion;
+
+// Entries per queue. A submission queue of these fills exactly one page.
+pub const QUEUE_SIZE: u16 = 64;
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+
+const COMPLETION_PHASE: u32 = 1 << 16;
+const COMPLETION_STATUS_SHIFT: u32 = 17;
+
+const COMMAND_TIMEOUT_NS: u64 = 5 * time::NANOSECONDS_PER_SECOND;
+
+pub type Command = [u32; 16];
+
+// A submission queue and the completion queue it posts to. Commands are run one at a time, and either polled for or
+// waited on with the queue's interrupt.
+pub struct QueuePair {
+    qid: u16,
+    sq: VirtAddr,
+    pub sq_phys: PhysAddr,
+    cq: VirtAddr,
+    pub cq_phys: PhysAddr,
+    sq_tail: u16,
+    cq_head: u16,
+    // The phase tag new completions will have. The controller flips it each time round the queue.
+    phase: bool,
+    sq_doorbell: VirtAddr,
+    cq_doorbell: VirtAddr,
+    next_cid: u16,
+}
+
+impl QueuePair {
+    // Doorbells are after the registers, each queue's pair spaced by the stride the controller asks for
+    pub fn new(regs: VirtAddr, qid: u16, doorbell_stride: u64) -> QueuePair {
+	let (sq, sq_phys) = memory::kernel_allocate(
+	    4096, memory::MemoryAllocationType::Dma)
+	    .expect("Unable to allocate NVMe submission queue");
+	let (cq, cq_phys) = memory::kernel_allocate(
+	    4096, memory::MemoryAllocationType::Dma)
+	    .expect("Unable to allocate NVMe completion queue");
+
+	unsafe {
+	    slice::from_raw_parts_mut(sq.as_mut_ptr::<u8>(), 4096).fill(0);
+	    slice::from_raw_parts_mut(cq.as_mut_ptr::<u8>(), 4096).fill(0);
+	}
+
+	QueuePair {
+	    qid,
+	    sq,
+	    sq_phys: sq_phys[0],
+	    cq,
+	    cq_phys: cq_phys[0],
+	    sq_tail: 0,
+	    cq_head: 0,
+	    phase: true,
+	    sq_doorbell: regs + 0x1000 + (2 * qid as u64) * doorbell_stride,
+	    cq_doorbell: regs + 0x1000 + (2 * qid as u64 + 1) * doorbell_stride,
+	    next_cid: 0,
+	}
+    }
+
+    // Puts a command on the queue and tells the controller about it, giving back its ID
+    fn submit(&mut self, command: &mut Command) -> u16 {
+	let cid = self.next_cid;
+	self.next_cid = self.next_cid.wrapping_add(1);
+	command[0] = (command[0] & 0xFFFF) | ((cid as u32) << 16);
+
+	let entry = unsafe {
+	    slice::from_raw_parts_mut(
+		(self.sq + self.sq_tail as u64 * SUBMISSION_ENTRY_SIZE as u64).as_mut_ptr::<u32>(),
+		SUBMISSION_ENTRY_SIZE / 4)
+	};
+	entry.copy_from_slice(command);
+	self.sq_tail = (self.sq_tail + 1) % QUEUE_SIZE;
+
+	// The command has to be in memory before the controller is told about it
+	fence(Ordering::SeqCst);
+	unsafe {
+	    write_volatile(self.sq_doorbell.as_mut_ptr::<u32>(), self.sq_tail as u32);
+	}
+
+	cid
+    }
+
+    // Takes the next completion off the queue, if the controller has posted one, giving back its DW0 and DW3
+    fn reap(&mut self) -> Option<(u32, u32)> {
+	let completion = self.cq + self.cq_head as u64 * COMPLETION_ENTRY_SIZE as u64;
+	let dw3 = unsafe {
+	    read_volatile((completion + 12u64).as_ptr::<u32>())
+	};
+	if (dw3 & COMPLETION_PHASE != 0) != self.phase {
+	    return None;
+	}
+
+	fence(Ordering::SeqCst);
+	let dw0 = unsafe {
+	    read_volatile(completion.as_ptr::<u32>())
+	};
+
+	self.cq_head += 1;
+	if self.cq_head == QUEUE_SIZE {
+	    self.cq_head = 0;
+	    self.phase = !self.phase;
+	}
+	unsafe {
+	    write_volatile(self.cq_doorbell.as_mut_ptr::<u32>(), self.cq_head as u32);
+	}
+
+	Some((dw0, dw3))
+    }
+
+    fn result(&self, command: &Command, (dw0, dw3): (u32, u32)) -> Result<u32, syscall::CanonicalError> {
+	let status = dw3 >> COMPLETION_STATUS_SHIFT;
+	if status != 0 {
+	    log::warn!("NVMe queue {}: command {:#X} failed, status {:#X}", self.qid, command[0] & 0xFF, status);
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	Ok(dw0)
+    }
+
+    // Submits a command and spins until it completes, giving back the command specific result
+    pub fn run(&mut self, mut command: Command) -> Result<u32, syscall::CanonicalError> {
+	let cid = self.submit(&mut command);
+
+	let deadline = time::get_monotonic_ns() + COMMAND_TIMEOUT_NS;
+	let completion = loop {
+	    if let Some(completion) = self.reap() {
+		break completion;
+	    }
+
+	    if time::get_monotonic_ns() > deadline {
+		log::warn!("NVMe queue {}: command {} timed out", self.qid, cid);
+		return Err(syscall::CanonicalError::Io);
+	    }
+	    hint::spin_loop();
+	};
+
+	self.result(&command, completion)
+    }
+
+    // As run, but sleeps until the queue's interrupt rather than spinning, so other work can go on in the meantime.
+    // The interrupt may have been left over from an earlier command, so the queue is always checked before waiting.
+    pub async fn run_with_interrupt(&mut self, mut command: Command, interrupt: &Completion<()>) -> Result<u32, syscall::CanonicalError> {
+	self.submit(&mut command);
+
+	let completion = loop {
+	    if let Some(completion) = self.reap() {
+		break completion;
+	    }
+
+	    interrupt.wait().await;
+	};
+
+	self.result(&command, completion)
+    }
+}
@@ -2,17 +2,50 @@ use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::boxed::Box;
 use alloc::fmt;
+use alloc::format;
 use core::any::Any;
+use core::ptr::{read_volatile, write_volatile};
 use pci_types::{ConfigRegionAccess, CommandRegister, PciAddress, PciHeader, HeaderType, EndpointHeader, Bar, VendorId, DeviceId, BaseClass, SubClass, Interface};
 use x86_64::instructions::port::{PortGeneric, ReadWriteAccess, WriteOnlyAccess};
 use spin::{Mutex, Once};
 use alloc::sync::Arc;
+use alloc::collections::BTreeSet;
 
 use crate::driver;
+use crate::fs;
 use crate::interrupts;
+use crate::memory;
 use crate::sys::acpi::{uacpi_namespace_node, namespace};
 use crate::utils::vector_map::VecMap;
 
+const PCI_STATUS_COMMAND: u16 = 0x04;
+const PCI_STATUS_CAPABILITIES_LIST: u32 = 1 << (16 + 4);
+const PCI_CAPABILITIES_POINTER: u16 = 0x34;
+const PCI_BAR_BASE: u16 = 0x10;
+const PCI_MAX_BARS: u16 = 6;
+const PCI_BAR_IO: u32 = 1 << 0;
+const PCI_BAR_IO_ADDRESS_MASK: u32 = !0x3;
+const PCI_BAR_MEMORY_TYPE_MASK: u32 = 0x3 << 1;
+const PCI_BAR_MEMORY_TYPE_64: u32 = 0x2 << 1;
+const PCI_BAR_MEMORY_ADDRESS_MASK: u32 = !0xF;
+// Primary, secondary and subordinate bus numbers, one byte each
+const PCI_BRIDGE_BUS_NUMBERS: u16 = 0x18;
+
+pub const PCI_CAPABILITY_MSI: u8 = 0x05;
+pub const PCI_CAPABILITY_MSIX: u8 = 0x11;
+
+// Message control is the top half of a capability's first dword
+const MSI_CONTROL_ENABLE: u32 = 1 << 16;
+const MSI_CONTROL_64BIT: u32 = 1 << (16 + 7);
+const MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE: u32 = 0x7 << (16 + 4);
+
+const MSIX_CONTROL_TABLE_SIZE_MASK: u32 = 0x7FF << 16;
+const MSIX_CONTROL_FUNCTION_MASK: u32 = 1 << (16 + 14);
+const MSIX_CONTROL_ENABLE: u32 = 1 << (16 + 15);
+const MSIX_TABLE_BIR_MASK: u32 = 0x7;
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
 #[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
 pub enum InterruptPin {
     IntA,
@@ -129,11 +162,52 @@ impl driver::Bus for PciBus {
     }
 
     fn enumerate(&mut self) -> Vec<Box<dyn driver::DeviceTypeIdentifier>> {
-	let pci_config_access = PciConfigAccess::new();
 	let mut found_devices = Vec::<Box<dyn driver::DeviceTypeIdentifier>>::new();
+	let mut visited_buses = BTreeSet::<u8>::new();
+	self.scan_bus(self.bus, None, &mut visited_buses, &mut found_devices);
+
+	found_devices
+    }
+}
+
+impl PciBus {
+    // Looks up where a device's interrupt pin goes. ACPI only describes the routing on the root bus, so a device behind
+    // bridges has its pin rotated by its device number at each bridge on the way up, and the root bus slot is looked up.
+    fn route_interrupt(&self, device: u8, function: u8, pin: u8, upstream: Option<(u8, u8)>) -> Option<interrupts::InterruptRoute> {
+	let (device, function, pin) = match upstream {
+	    None => (device, Some(function), pin - 1),
+	    Some((root_device, swizzle)) => (root_device, None, (pin - 1 + device + swizzle) % 4),
+	};
+
+	let interrupt_function = namespace::PciInterruptFunction {
+	    device,
+	    function,
+	    pin: match pin {
+		0 => InterruptPin::IntA,
+		1 => InterruptPin::IntB,
+		2 => InterruptPin::IntC,
+		3 => InterruptPin::IntD,
+		_ => panic!("Malformed interrupt pin"),
+	    },
+	};
+
+	self.routing_table.get(&interrupt_function).cloned()
+    }
+
+    // upstream is the device number of the bridge on the root bus this bus hangs off, along with the sum of the device
+    // numbers of the bridges in between, or None for the root bus itself
+    fn scan_bus(&self, bus: u8, upstream: Option<(u8, u8)>, visited_buses: &mut BTreeSet<u8>, found_devices: &mut Vec<Box<dyn driver::DeviceTypeIdentifier>>) {
+	// Misconfigured bridges can point back at a bus that's already been scanned
+	if !visited_buses.insert(bus) {
+	    log::warn!("PCI bus {:02x} reached twice, not scanning it again", bus);
+	    return;
+	}
+
+	let pci_config_access = PciConfigAccess::new();
 	for device in 0 .. 32 {
 	    for function in 0 .. 8 {
-		let device_header = PciHeader::new(PciAddress::new(0, 0, device, function));
+		let address = PciAddress::new(self.segment, bus, device, function);
+		let device_header = PciHeader::new(address);
 		let (vendor_id, device_id) = device_header.id(pci_config_access);
 
 		if vendor_id == 0xFFFF {
@@ -143,33 +217,38 @@ impl driver::Bus for PciBus {
 		let (_, base_class, sub_class, interface) = device_header.revision_and_class(pci_config_access);
 
 		let mut interrupt_mapping: Option<interrupts::InterruptRoute> = None;
-
-		if device_header.header_type(pci_config_access) == HeaderType::Endpoint {
-		    let endpoint_header = EndpointHeader::from_header(device_header, pci_config_access).expect("Creating endpoint header failed");
-		    
-		    let (device_pin, device_irq) = endpoint_header.interrupt(pci_config_access);
-		    if device_pin != 0 {
-			// Device may use GSIs, consult with ACPI
-			let interrupt_function = namespace::PciInterruptFunction {
-			    device,
-			    function: Some(function),
-			    pin: match device_pin {
-				1 => InterruptPin::IntA,
-				2 => InterruptPin::IntB,
-				3 => InterruptPin::IntC,
-				4 => InterruptPin::IntD,
-				_ => panic!("Malformed interrupt pin"),
-			    },
-			};
-
-			interrupt_mapping = self.routing_table.get(&interrupt_function).cloned();
-		    } else if device_irq != 0xFF {
-			interrupt_mapping = Some(interrupts::InterruptRoute::Irq(device_irq));
-		    }
+		let header_type = device_header.header_type(pci_config_access);
+
+		match header_type {
+		    HeaderType::Endpoint => {
+			let endpoint_header = EndpointHeader::from_header(device_header, pci_config_access).expect("Creating endpoint header failed");
+
+			let (device_pin, device_irq) = endpoint_header.interrupt(pci_config_access);
+			if device_pin != 0 {
+			    // Device may use GSIs, consult with ACPI
+			    interrupt_mapping = self.route_interrupt(device, function, device_pin, upstream);
+			} else if device_irq != 0xFF {
+			    interrupt_mapping = Some(interrupts::InterruptRoute::Irq(device_irq));
+			}
+		    },
+		    HeaderType::PciPciBridge => {
+			let bus_numbers = unsafe { pci_config_access.read(address, PCI_BRIDGE_BUS_NUMBERS) };
+			let secondary_bus = ((bus_numbers >> 8) & 0xFF) as u8;
+
+			// A secondary bus of 0 means the firmware never set the bridge up
+			if secondary_bus != 0 {
+			    let bridge_upstream = match upstream {
+				None => (device, 0),
+				Some((root_device, swizzle)) => (root_device, (swizzle + device) % 4),
+			    };
+			    self.scan_bus(secondary_bus, Some(bridge_upstream), visited_buses, found_devices);
+			}
+		    },
+		    _ => (),
 		}
 
-		found_devices.push(Box::new(PciDeviceType {
-		    address: PciAddress::new(self.segment, self.bus, device, function),
+		let info = PciDeviceType {
+		    address,
 
 		    vendor_id,
 		    device_id,
@@ -179,17 +258,21 @@ impl driver::Bus for PciBus {
 		    interface,
 
 		    interrupt_mapping,
-		}));
+		};
+		let sysfs_path = add_sysfs_attributes(&info);
+		if header_type == HeaderType::Endpoint {
+		    fs::sysfs::add_static_attribute(&format!("{}/resource", sysfs_path), bar_resources(&info));
+		}
+
+		found_devices.push(Box::new(info));
 	    }
 	}
-
-	found_devices
     }
 }
 
 pub struct PciDriver {}
 impl driver::Driver for PciDriver {
-    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
 	let system_bus_identifier = if let Some(sb_info) = info.as_any().downcast_ref::<namespace::SystemBusDeviceIdentifier>() {
 	    sb_info
 	} else {
@@ -209,20 +292,14 @@ impl driver::Driver for PciDriver {
 		    continue;
 		}
 
-		for device in 0 .. 32 {
-		    let device_header = PciHeader::new(PciAddress::new(0, function, device, 0));
-		    let (device_vendor_id, device_device_id) = device_header.id(pci_config_access);
-
-		    if device_vendor_id == 0xFFFF {
-			continue;
-		    }
-
-		    log::info!("Found PCI device, vendor = {:X}, device = {:X}", device_vendor_id, device_device_id);
-		}
+		// Each function of the host bridge looks after the bus with its number
+		driver::register_bus_and_enumerate(Arc::new(Mutex::new(PciBus::new(system_bus_identifier.namespace, 0, function))));
 	    }
 	} else {
 	    driver::register_bus_and_enumerate(Arc::new(Mutex::new(PciBus::new(system_bus_identifier.namespace, 0, 0))));
 	}
+
+	driver::ProbeResult::Bound
     }
 
     fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
@@ -262,3 +339,238 @@ pub fn enable_interrupts(info: PciDeviceType) {
 
     device_header.update_command(pci_config_access, |command| command & !CommandRegister::INTERRUPT_DISABLE);
 }
+
+// Lets the device reach memory, both to have its registers accessed and to do DMA of its own
+pub fn enable_bus_mastering(info: PciDeviceType) {
+    let pci_config_access = PciConfigAccess::new();
+    let mut device_header = PciHeader::new(info.address);
+
+    device_header.update_command(pci_config_access, |command| command | CommandRegister::BUS_MASTER_ENABLE | CommandRegister::MEMORY_ENABLE);
+}
+
+// Walks the device's capability list, giving back the config space offset of the first capability with the given ID
+pub fn find_capability(info: PciDeviceType, id: u8) -> Option<u16> {
+    let pci_config_access = PciConfigAccess::new();
+
+    let status = unsafe { pci_config_access.read(info.address, PCI_STATUS_COMMAND) };
+    if status & PCI_STATUS_CAPABILITIES_LIST == 0 {
+	return None;
+    }
+
+    let mut offset = (unsafe { pci_config_access.read(info.address, PCI_CAPABILITIES_POINTER) } & 0xFC) as u16;
+    // There's only room for 48 capabilities in config space, so any more than that means the list loops
+    for _ in 0 .. 48 {
+	if offset == 0 {
+	    return None;
+	}
+
+	let header = unsafe { pci_config_access.read(info.address, offset) };
+	if header & 0xFF == id as u32 {
+	    return Some(offset);
+	}
+
+	offset = ((header >> 8) & 0xFC) as u16;
+    }
+
+    None
+}
+
+// Has the device signal the given one of its interrupts by MSI-X if it can, or MSI if not, instead of through its
+// interrupt pin. MSI without multiple messages only has interrupt 0. Gives back the IDT vector the handler was put on.
+pub fn enable_msi(info: PciDeviceType, vector: u16, handler: Box<dyn Fn() + Send + Sync>) -> Option<u8> {
+    if let Some(capability) = find_capability(info.clone(), PCI_CAPABILITY_MSIX) {
+	enable_msix_vector(info, capability, vector, handler)
+    } else if let Some(capability) = find_capability(info.clone(), PCI_CAPABILITY_MSI) {
+	if vector != 0 {
+	    return None;
+	}
+	enable_msi_vector(info, capability, handler)
+    } else {
+	None
+    }
+}
+
+fn disable_legacy_interrupts(info: PciDeviceType) {
+    let pci_config_access = PciConfigAccess::new();
+    let mut device_header = PciHeader::new(info.address);
+
+    device_header.update_command(pci_config_access, |command| command | CommandRegister::INTERRUPT_DISABLE);
+}
+
+fn enable_msi_vector(info: PciDeviceType, capability: u16, handler: Box<dyn Fn() + Send + Sync>) -> Option<u8> {
+    let pci_config_access = PciConfigAccess::new();
+    let msi = interrupts::allocate_msi_vector(handler)?;
+
+    unsafe {
+	let control = pci_config_access.read(info.address, capability);
+	pci_config_access.write(info.address, capability + 0x04, msi.address as u32);
+
+	// The data register moves up a dword when there's room for a 64 bit address
+	let data_offset = if control & MSI_CONTROL_64BIT != 0 {
+	    pci_config_access.write(info.address, capability + 0x08, (msi.address >> 32) as u32);
+	    capability + 0x0C
+	} else {
+	    capability + 0x08
+	};
+
+	let data = pci_config_access.read(info.address, data_offset);
+	pci_config_access.write(info.address, data_offset, (data & 0xFFFF_0000) | (msi.data & 0xFFFF));
+
+	pci_config_access.write(info.address, capability,
+				(control & !MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE) | MSI_CONTROL_ENABLE);
+    }
+
+    disable_legacy_interrupts(info);
+    Some(msi.vector)
+}
+
+fn enable_msix_vector(info: PciDeviceType, capability: u16, vector: u16, handler: Box<dyn Fn() + Send + Sync>) -> Option<u8> {
+    let pci_config_access = PciConfigAccess::new();
+
+    let control = unsafe { pci_config_access.read(info.address, capability) };
+    let table_size = ((control & MSIX_CONTROL_TABLE_SIZE_MASK) >> 16) as u16 + 1;
+    if vector >= table_size {
+	return None;
+    }
+
+    // The table lives in one of the device's memory BARs
+    let table = unsafe { pci_config_access.read(info.address, capability + 0x04) };
+    let (bar_phys, _) = match get_bar(info.clone(), (table & MSIX_TABLE_BIR_MASK) as u8)? {
+	bar @ Bar::Memory32 { .. } | bar @ Bar::Memory64 { .. } => bar.unwrap_mem(),
+	Bar::Io { .. } => return None,
+    };
+    let table_phys = bar_phys + (table & !MSIX_TABLE_BIR_MASK) as usize;
+    let table_virt = memory::allocate_mmio(table_phys, table_size as usize * MSIX_TABLE_ENTRY_SIZE as usize).ok()?;
+
+    let msi = interrupts::allocate_msi_vector(handler)?;
+    let entry = (table_virt + vector as u64 * MSIX_TABLE_ENTRY_SIZE).as_mut_ptr::<u32>();
+    unsafe {
+	write_volatile(entry, msi.address as u32);
+	write_volatile(entry.add(1), (msi.address >> 32) as u32);
+	write_volatile(entry.add(2), msi.data);
+	write_volatile(entry.add(3), read_volatile(entry.add(3)) & !MSIX_ENTRY_VECTOR_CONTROL_MASKED);
+
+	pci_config_access.write(info.address, capability,
+				(control & !MSIX_CONTROL_FUNCTION_MASK) | MSIX_CONTROL_ENABLE);
+    }
+
+    disable_legacy_interrupts(info);
+    Some(msi.vector)
+}
+
+fn bar_offset(slot: u8) -> u16 {
+    PCI_BAR_BASE + slot as u16 * 4
+}
+
+// Runs f with the device's I/O and memory decoding turned off, so that BARs can be rewritten without the device
+// briefly claiming whatever address is half written
+fn with_decoding_disabled<T>(info: PciDeviceType, f: impl FnOnce(&PciConfigAccess) -> T) -> T {
+    let pci_config_access = PciConfigAccess::new();
+    let mut device_header = PciHeader::new(info.address);
+
+    let decoding = device_header.command(pci_config_access) & (CommandRegister::IO_ENABLE | CommandRegister::MEMORY_ENABLE);
+    device_header.update_command(pci_config_access, |command| command & !decoding);
+
+    let result = f(&pci_config_access);
+
+    device_header.update_command(pci_config_access, |command| command | decoding);
+    result
+}
+
+// How many bytes a BAR decodes, found by writing all ones to it and seeing which address bits stick. The BAR is left
+// as it was found. None if the slot isn't implemented.
+pub fn get_bar_size(info: PciDeviceType, slot: u8) -> Option<u64> {
+    if slot as u16 >= PCI_MAX_BARS {
+	return None;
+    }
+    let offset = bar_offset(slot);
+
+    with_decoding_disabled(info.clone(), |pci_config_access| unsafe {
+	let original = pci_config_access.read(info.address, offset);
+	pci_config_access.write(info.address, offset, 0xFFFF_FFFF);
+	let mask = pci_config_access.read(info.address, offset);
+	pci_config_access.write(info.address, offset, original);
+
+	// I/O BARs only decode 16 address bits, and the rest may read back as anything
+	if original & PCI_BAR_IO != 0 {
+	    let mask = (mask & PCI_BAR_IO_ADDRESS_MASK) as u16;
+	    return if mask == 0 { None } else { Some((!mask) as u64 + 1) };
+	}
+
+	let mut mask = (mask & PCI_BAR_MEMORY_ADDRESS_MASK) as u64;
+	if original & PCI_BAR_MEMORY_TYPE_MASK == PCI_BAR_MEMORY_TYPE_64 {
+	    if slot as u16 + 1 >= PCI_MAX_BARS {
+		return None;
+	    }
+
+	    let original_high = pci_config_access.read(info.address, offset + 4);
+	    pci_config_access.write(info.address, offset + 4, 0xFFFF_FFFF);
+	    let mask_high = pci_config_access.read(info.address, offset + 4);
+	    pci_config_access.write(info.address, offset + 4, original_high);
+
+	    mask |= (mask_high as u64) << 32;
+	} else {
+	    mask |= 0xFFFF_FFFF_0000_0000;
+	}
+
+	// Nothing sticking means nothing is decoded
+	if mask == 0 || mask == 0xFFFF_FFFF_0000_0000 {
+	    None
+	} else {
+	    Some((!mask).wrapping_add(1))
+	}
+    })
+}
+
+// A line per BAR of where it starts and ends, and its flags, as Linux's resource file has, with an unimplemented BAR
+// (or the top half of a 64 bit one) all zeroes. There's nothing yet to hand out addresses from, so a BAR the firmware
+// never gave one to can't be used, and is only warned about.
+// Describes the device under /sys/bus/pci/devices, returning the directory it's described in
+pub fn add_sysfs_attributes(info: &PciDeviceType) -> String {
+    let address = info.address;
+    let sysfs_path = format!("/bus/pci/devices/{:04x}:{:02x}:{:02x}.{}", address.segment(), address.bus(), address.device(), address.function());
+    fs::sysfs::add_static_attribute(&format!("{}/vendor", sysfs_path), format!("0x{:04x}\n", info.vendor_id));
+    fs::sysfs::add_static_attribute(&format!("{}/device", sysfs_path), format!("0x{:04x}\n", info.device_id));
+    fs::sysfs::add_static_attribute(&format!("{}/class", sysfs_path), format!("0x{:02x}{:02x}{:02x}\n", info.base_class, info.sub_class, info.interface));
+
+    sysfs_path
+}
+
+fn bar_resources(info: &PciDeviceType) -> String {
+    let pci_config_access = PciConfigAccess::new();
+    let unused = "0x0000000000000000 0x0000000000000000 0x0000000000000000\n";
+
+    let mut resources = String::new();
+    let mut slot = 0;
+    while (slot as u16) < PCI_MAX_BARS {
+	let low = unsafe { pci_config_access.read(info.address, bar_offset(slot)) };
+	let is_64 = low & PCI_BAR_IO == 0 && low & PCI_BAR_MEMORY_TYPE_MASK == PCI_BAR_MEMORY_TYPE_64;
+	let (base, flags) = if low & PCI_BAR_IO != 0 {
+	    ((low & PCI_BAR_IO_ADDRESS_MASK) as u64, low & !PCI_BAR_IO_ADDRESS_MASK)
+	} else if is_64 {
+	    let high = unsafe { pci_config_access.read(info.address, bar_offset(slot) + 4) };
+	    (((high as u64) << 32) | (low & PCI_BAR_MEMORY_ADDRESS_MASK) as u64, low & !PCI_BAR_MEMORY_ADDRESS_MASK)
+	} else {
+	    ((low & PCI_BAR_MEMORY_ADDRESS_MASK) as u64, low & !PCI_BAR_MEMORY_ADDRESS_MASK)
+	};
+
+	match get_bar_size(info.clone(), slot) {
+	    Some(size) => {
+		if base == 0 {
+		    log::warn!("{} BAR {} was never assigned an address, so can't be used", info, slot);
+		}
+		resources += &format!("0x{:016x} 0x{:016x} 0x{:016x}\n", base, base + size - 1, flags);
+	    },
+	    None => resources += unused,
+	}
+
+	if is_64 {
+	    resources += unused;
+	    slot += 2;
+	} else {
+	    slot += 1;
+	}
+    }
+
+    resources
+}
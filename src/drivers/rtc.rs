@@ -0,0 +1,704 @@
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use core::ffi::c_int;
+use core::future::poll_fn;
+use core::mem;
+use core::task::{Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::driver;
+use crate::interrupts;
+use crate::memory;
+use crate::scheduler;
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::sys::time;
+use crate::vfs;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+// Set on the address port to keep NMIs masked while we're talking to the CMOS
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
+// Strictly, the FADT tells us where (and whether) the century is, but everything we care about puts it here
+const RTC_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+
+// ioctl requests, as Linux's rtc driver numbers them
+const RTC_AIE_ON: u64 = 0x7001;
+const RTC_AIE_OFF: u64 = 0x7002;
+const RTC_ALM_SET: u64 = 0x4024_7007;
+const RTC_ALM_READ: u64 = 0x8024_7008;
+const RTC_RD_TIME: u64 = 0x8024_7009;
+const RTC_SET_TIME: u64 = 0x4024_700A;
+// Stops the RTC updating while we're setting it
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const HOURS_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+const RTC_IRQ: u8 = 8;
+
+// Flags in what a read of /dev/rtc returns, below the count of interrupts since the last read
+const RTC_IRQF: u64 = 0x80;
+const RTC_AF: u64 = 0x20;
+
+// As struct rtc_time, which is laid out like struct tm
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct RtcTime {
+    tm_sec: c_int,
+    tm_min: c_int,
+    tm_hour: c_int,
+    tm_mday: c_int,
+    tm_mon: c_int,  // 0 - 11
+    tm_year: c_int,  // Years since 1900
+    tm_wday: c_int,
+    tm_yday: c_int,
+    tm_isdst: c_int,
+}
+
+// Register values exactly as read from the CMOS, i.e. possibly BCD and/or 12 hour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_of_month: u8,
+    pub month: u8,
+    pub year: u8,
+    pub century: u8,
+    pub status_b: u8,
+}
+
+// Selecting a register and then reading or writing it is two port accesses, which mustn't be interleaved with another
+// CPU's. Also held across any read-modify-write of a register, and while setting the time. The RTC's IRQ takes it too,
+// so it's only ever held with interrupts disabled.
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
+
+fn with_cmos<T>(f: impl FnOnce() -> T) -> T {
+    without_interrupts(|| {
+	let _guard = CMOS_LOCK.lock();
+	f()
+    })
+}
+
+// Only to be called through with_cmos
+fn read_cmos(register: u8) -> u8 {
+    let mut address_port: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
+
+    unsafe {
+	address_port.write(CMOS_NMI_DISABLE | register);
+	data_port.read()
+    }
+}
+
+fn write_cmos(register: u8, value: u8) {
+    let mut address_port: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data_port: Port<u8> = Port::new(CMOS_DATA_PORT);
+
+    unsafe {
+	address_port.write(CMOS_NMI_DISABLE | register);
+	data_port.write(value);
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_cmos(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn read_registers_once() -> RtcRegisters {
+    while update_in_progress() {
+	core::hint::spin_loop();
+    }
+
+    RtcRegisters {
+	seconds: read_cmos(RTC_SECONDS),
+	minutes: read_cmos(RTC_MINUTES),
+	hours: read_cmos(RTC_HOURS),
+	day_of_month: read_cmos(RTC_DAY_OF_MONTH),
+	month: read_cmos(RTC_MONTH),
+	year: read_cmos(RTC_YEAR),
+	century: read_cmos(RTC_CENTURY),
+	status_b: read_cmos(RTC_STATUS_B),
+    }
+}
+
+// An update can still start part way through reading the registers, even having waited for the last one to finish,
+// so keep going until two reads in a row agree
+pub fn read_registers() -> RtcRegisters {
+    with_cmos(|| {
+	let mut last = read_registers_once();
+	loop {
+	    let current = read_registers_once();
+	    if current == last {
+		return current;
+	    }
+	    last = current;
+	}
+    })
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + ((value >> 4) * 10)
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn decode_value(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+	value
+    } else {
+	bcd_to_binary(value)
+    }
+}
+
+fn encode_value(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+	value
+    } else {
+	binary_to_bcd(value)
+    }
+}
+
+// Gives the hour on a 24 hour clock. The PM bit sits above the hour itself, in either mode.
+fn decode_hours(value: u8, status_b: u8) -> u8 {
+    let hours = decode_value(value & !HOURS_PM, status_b);
+    if status_b & STATUS_B_24_HOUR != 0 {
+	return hours;
+    }
+
+    // 12 hour clocks go 12, 1, ..., 11
+    if value & HOURS_PM != 0 {
+	hours % 12 + 12
+    } else {
+	hours % 12
+    }
+}
+
+fn encode_hours(hours: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_24_HOUR != 0 {
+	return encode_value(hours, status_b);
+    }
+
+    let pm = if hours >= 12 { HOURS_PM } else { 0 };
+    let hours = match hours % 12 {
+	0 => 12,
+	h => h,
+    };
+    encode_value(hours, status_b) | pm
+}
+
+// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar, using Howard Hinnant's days_from_civil
+pub fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of days_since_epoch, giving (year, month, day)
+fn date_from_days_since_epoch(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+fn is_leap_year(year: u64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+// month is 1 - 12
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+	2 if is_leap_year(year) => 29,
+	2 => 28,
+	4 | 6 | 9 | 11 => 30,
+	_ => 31,
+    }
+}
+
+// Converts raw register values to seconds since the Unix epoch. Returns None if they don't make a valid date on or
+// after the epoch, which is what we'll see if the CMOS battery has gone flat.
+pub fn registers_to_unix_time(registers: &RtcRegisters) -> Option<u64> {
+    let decode = |value: u8| decode_value(value, registers.status_b);
+
+    let hours = decode_hours(registers.hours, registers.status_b) as u64;
+    let seconds = decode(registers.seconds) as u64;
+    let minutes = decode(registers.minutes) as u64;
+    let day = decode(registers.day_of_month) as u64;
+    let month = decode(registers.month) as u64;
+    let century = match decode(registers.century) as u64 {
+	c @ 19..=99 => c,
+	// No century register, assume the 21st
+	_ => 20,
+    };
+    let year = century * 100 + decode(registers.year) as u64;
+
+    if seconds > 59 || minutes > 59 || hours > 23 || !(1..=12).contains(&month) || year < 1970 {
+	return None;
+    }
+    if !(1..=days_in_month(year, month)).contains(&day) {
+	return None;
+    }
+
+    Some(days_since_epoch(year, month, day) * SECONDS_PER_DAY + hours * 3600 + minutes * 60 + seconds)
+}
+
+// Converts seconds since the Unix epoch to register values, in whichever format status_b says the RTC is using
+pub fn unix_time_to_registers(now: u64, status_b: u8) -> RtcRegisters {
+    let encode = |value: u64| encode_value(value as u8, status_b);
+
+    let (year, month, day) = date_from_days_since_epoch(now / SECONDS_PER_DAY);
+    let seconds_of_day = now % SECONDS_PER_DAY;
+
+    RtcRegisters {
+	seconds: encode(seconds_of_day % 60),
+	minutes: encode((seconds_of_day / 60) % 60),
+	hours: encode_hours((seconds_of_day / 3600) as u8, status_b),
+	day_of_month: encode(day),
+	month: encode(month),
+	year: encode(year % 100),
+	century: encode(year / 100),
+	status_b,
+    }
+}
+
+// Sets the RTC to the given number of seconds since the Unix epoch
+pub fn write_time(now: u64) {
+    with_cmos(|| {
+	let status_b = read_cmos(RTC_STATUS_B);
+	let registers = unix_time_to_registers(now, status_b);
+
+	write_cmos(RTC_STATUS_B, status_b | STATUS_B_SET);
+	write_cmos(RTC_SECONDS, registers.seconds);
+	write_cmos(RTC_MINUTES, registers.minutes);
+	write_cmos(RTC_HOURS, registers.hours);
+	write_cmos(RTC_DAY_OF_MONTH, registers.day_of_month);
+	write_cmos(RTC_MONTH, registers.month);
+	write_cmos(RTC_YEAR, registers.year);
+	write_cmos(RTC_CENTURY, registers.century);
+	write_cmos(RTC_STATUS_B, status_b & !STATUS_B_SET);
+    });
+}
+
+fn unix_time_to_rtc_time(now: u64) -> RtcTime {
+    let days = now / SECONDS_PER_DAY;
+    let (year, month, day) = date_from_days_since_epoch(days);
+    let seconds_of_day = now % SECONDS_PER_DAY;
+
+    RtcTime {
+	tm_sec: (seconds_of_day % 60) as c_int,
+	tm_min: ((seconds_of_day / 60) % 60) as c_int,
+	tm_hour: (seconds_of_day / 3600) as c_int,
+	tm_mday: day as c_int,
+	tm_mon: (month - 1) as c_int,
+	tm_year: (year - 1900) as c_int,
+	// 1970-01-01 was a Thursday
+	tm_wday: ((days + 4) % 7) as c_int,
+	tm_yday: (days - days_since_epoch(year, 1, 1)) as c_int,
+	tm_isdst: 0,
+    }
+}
+
+fn rtc_time_to_unix_time(rtc_time: &RtcTime) -> Option<u64> {
+    let valid = (0..=59).contains(&rtc_time.tm_sec) &&
+	(0..=59).contains(&rtc_time.tm_min) &&
+	(0..=23).contains(&rtc_time.tm_hour) &&
+	(0..=11).contains(&rtc_time.tm_mon) &&
+	(70..=8099).contains(&rtc_time.tm_year);
+    if !valid {
+	return None;
+    }
+
+    let days_in_month = days_in_month(rtc_time.tm_year as u64 + 1900, rtc_time.tm_mon as u64 + 1);
+    if !(1..=days_in_month as c_int).contains(&rtc_time.tm_mday) {
+	return None;
+    }
+
+    let days = days_since_epoch(rtc_time.tm_year as u64 + 1900, rtc_time.tm_mon as u64 + 1, rtc_time.tm_mday as u64);
+    Some(days * SECONDS_PER_DAY + rtc_time.tm_hour as u64 * 3600 + rtc_time.tm_min as u64 * 60 + rtc_time.tm_sec as u64)
+}
+
+// The alarm goes off once a day, at the given time
+fn set_alarm(hours: u8, minutes: u8, seconds: u8) {
+    with_cmos(|| {
+	let status_b = read_cmos(RTC_STATUS_B);
+	write_cmos(RTC_SECONDS_ALARM, encode_value(seconds, status_b));
+	write_cmos(RTC_MINUTES_ALARM, encode_value(minutes, status_b));
+	write_cmos(RTC_HOURS_ALARM, encode_hours(hours, status_b));
+    });
+}
+
+fn get_alarm() -> (u8, u8, u8) {
+    with_cmos(|| {
+	let status_b = read_cmos(RTC_STATUS_B);
+	(decode_hours(read_cmos(RTC_HOURS_ALARM), status_b),
+	 decode_value(read_cmos(RTC_MINUTES_ALARM), status_b),
+	 decode_value(read_cmos(RTC_SECONDS_ALARM), status_b))
+    })
+}
+
+fn set_alarm_interrupt_enabled(enabled: bool) {
+    with_cmos(|| {
+	let status_b = read_cmos(RTC_STATUS_B);
+	if enabled {
+	    write_cmos(RTC_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+	} else {
+	    write_cmos(RTC_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+	}
+    });
+}
+
+struct AlarmState {
+    // Alarms since /dev/rtc was last read
+    count: u64,
+    waker: Option<Waker>,
+}
+
+pub struct RtcDevice {
+    // Also taken by the IRQ handler, so only ever locked with interrupts disabled
+    alarms: Mutex<AlarmState>,
+    fsi: Mutex<vfs::filesystem::FileSystemInstance>,
+}
+
+impl RtcDevice {
+    fn new() -> Self {
+	RtcDevice {
+	    alarms: Mutex::new(AlarmState {
+		count: 0,
+		waker: None,
+	    }),
+	    fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
+	}
+    }
+
+    fn alarm_fired(&self) {
+	let waker = without_interrupts(|| {
+	    let mut alarms = self.alarms.lock();
+	    alarms.count += 1;
+	    alarms.waker.take()
+	});
+
+	if let Some(waker) = waker {
+	    scheduler::defer_wake(waker);
+	}
+    }
+}
+
+impl vfs::filesystem::VNode for RtcDevice {
+    fn inode(&self) -> u64 {
+	0
+    }
+
+    fn kind(&self) -> vfs::filesystem::VNodeKind {
+	vfs::filesystem::VNodeKind::CharDevice
+    }
+
+    fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(vfs::filesystem::Stat {
+	    file_name: String::from("rtc"),
+	    size: None,
+	    inode: 0,
+	    kind: vfs::filesystem::VNodeKind::CharDevice,
+	    mode: 0o644,
+	    modified: None,
+	})
+    }
+
+    fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
+	Ok(self.clone())
+    }
+
+    fn filesystem(&self) -> Arc<dyn vfs::filesystem::FileSystem> {
+	driver::get_devfs()
+    }
+
+    fn fsi(&self) -> vfs::filesystem::FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn vfs::filesystem::VNode>, CanonicalError> {
+	unimplemented!();
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+impl vfs::filesystem::FileHandle for RtcDevice {
+    // Blocks until the alarm goes off, then returns how many times it has since the last read, as Linux does
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	Box::pin(poll_fn(move |cx| {
+	    if len < mem::size_of::<u64>() as u64 {
+		return Poll::Ready(Err(CanonicalError::Inval));
+	    }
+
+	    without_interrupts(|| {
+		let mut alarms = self.alarms.lock();
+		if alarms.count == 0 {
+		    alarms.waker = Some(cx.waker().clone());
+		    return Poll::Pending;
+		}
+
+		let data = (alarms.count << 8) | RTC_IRQF | RTC_AF;
+		alarms.count = 0;
+		Poll::Ready(Ok(bytes::Bytes::copy_from_slice(&data.to_ne_bytes())))
+	    })
+	}))
+    }
+
+    fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	Box::pin(poll_fn(move |cx| {
+	    if !events.contains(PollEvents::In) {
+		return Poll::Ready(Ok(PollEvents::empty()));
+	    }
+
+	    without_interrupts(|| {
+		let mut alarms = self.alarms.lock();
+		if alarms.count == 0 {
+		    alarms.waker = Some(cx.waker().clone());
+		    Poll::Pending
+		} else {
+		    Poll::Ready(Ok(PollEvents::In))
+		}
+	    })
+	}))
+    }
+
+    fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	vfs::filesystem::VNode::stat(&*self)
+    }
+
+    fn ioctl(self: Arc<Self>, request: u64, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    match request {
+		RTC_RD_TIME => {
+		    let now = registers_to_unix_time(&read_registers()).ok_or(CanonicalError::Io)?;
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<RtcTime>() as u64)?;
+		    memory::copy_value_to_user::<RtcTime>(arg, &unix_time_to_rtc_time(now)).map_err(|_| CanonicalError::Fault)?;
+		    Ok(0)
+		},
+		RTC_SET_TIME => {
+		    if !scheduler::get_current_process().get_credentials().is_privileged() {
+			return Err(CanonicalError::Perm);
+		    }
+
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<RtcTime>() as u64)?;
+		    let rtc_time = memory::copy_value_from_user::<RtcTime>(arg).map_err(|_| CanonicalError::Fault)?;
+		    write_time(rtc_time_to_unix_time(&rtc_time).ok_or(CanonicalError::Inval)?);
+		    Ok(0)
+		},
+		RTC_ALM_READ => {
+		    let (hours, minutes, seconds) = get_alarm();
+		    let alarm = RtcTime {
+			tm_sec: seconds as c_int,
+			tm_min: minutes as c_int,
+			tm_hour: hours as c_int,
+			..RtcTime::default()
+		    };
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<RtcTime>() as u64)?;
+		    memory::copy_value_to_user::<RtcTime>(arg, &alarm).map_err(|_| CanonicalError::Fault)?;
+		    Ok(0)
+		},
+		RTC_ALM_SET => {
+		    // Only the time of day is used, the alarm can't be set for a particular date
+		    let arg = memory::validate_user_ptr(arg, mem::size_of::<RtcTime>() as u64)?;
+		    let alarm = memory::copy_value_from_user::<RtcTime>(arg).map_err(|_| CanonicalError::Fault)?;
+		    if !(0..=23).contains(&alarm.tm_hour) || !(0..=59).contains(&alarm.tm_min) || !(0..=59).contains(&alarm.tm_sec) {
+			return Err(CanonicalError::Inval);
+		    }
+
+		    set_alarm(alarm.tm_hour as u8, alarm.tm_min as u8, alarm.tm_sec as u8);
+		    Ok(0)
+		},
+		RTC_AIE_ON => {
+		    set_alarm_interrupt_enabled(true);
+		    Ok(0)
+		},
+		RTC_AIE_OFF => {
+		    set_alarm_interrupt_enabled(false);
+		    Ok(0)
+		},
+		_ => Err(CanonicalError::NoTty),
+	    }
+	}.boxed()
+    }
+
+    fn seek(&self, _offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
+	Err(CanonicalError::SPipe)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+static RTC_DEVICE: Once<Arc<RtcDevice>> = Once::new();
+// What the RTC said when we booted, in seconds since the Unix epoch, or None if it didn't hold a valid time
+static BOOT_UNIX_TIME: Once<Option<u64>> = Once::new();
+
+// Unlike the realtime clock, this isn't moved by the time being set afterwards
+#[allow(dead_code)]
+pub fn boot_unix_time() -> Option<u64> {
+    BOOT_UNIX_TIME.get().copied().flatten()
+}
+
+fn rtc_irq_handler() {
+    // Reading status C acknowledges the interrupt; until we do, the RTC won't raise another
+    let status_c = with_cmos(|| read_cmos(RTC_STATUS_C));
+    if status_c & STATUS_C_ALARM != 0 {
+	if let Some(rtc) = RTC_DEVICE.get() {
+	    rtc.alarm_fired();
+	}
+    }
+}
+
+// Seeds the realtime clock from the RTC, and makes it available as /dev/rtc
+pub fn init() {
+    let registers = read_registers();
+    match *BOOT_UNIX_TIME.call_once(|| registers_to_unix_time(&registers)) {
+	Some(now) => {
+	    time::set_realtime_ns(now * time::NANOSECONDS_PER_SECOND);
+	    log::info!("RTC time is {} seconds since the epoch", now);
+	},
+	None => log::warn!("RTC holds an invalid time ({:?}), leaving the realtime clock at the epoch", registers),
+    }
+
+    let rtc = RTC_DEVICE.call_once(|| Arc::new(RtcDevice::new())).clone();
+    driver::register_devfs(String::from("rtc"), rtc);
+
+    // Clear anything left pending from before we took over, or the RTC would never interrupt
+    with_cmos(|| read_cmos(RTC_STATUS_C));
+    interrupts::InterruptRoute::Irq(RTC_IRQ).register_handler(Box::new(rtc_irq_handler));
+}
+
+#[test]
+fn set_time_rejects_days_past_end_of_month() {
+    let time = |year: c_int, mon: c_int, mday: c_int| RtcTime {
+	tm_year: year - 1900,
+	tm_mon: mon - 1,
+	tm_mday: mday,
+	..RtcTime::default()
+    };
+
+    assert_eq!(rtc_time_to_unix_time(&time(2023, 1, 31)), Some(1675123200));
+    assert_eq!(rtc_time_to_unix_time(&time(2023, 4, 31)), None);
+    assert_eq!(rtc_time_to_unix_time(&time(2023, 2, 29)), None);
+    assert_eq!(rtc_time_to_unix_time(&time(2024, 2, 29)), Some(1709164800));
+    assert_eq!(rtc_time_to_unix_time(&time(2100, 2, 29)), None);
+    assert_eq!(rtc_time_to_unix_time(&time(2000, 2, 29)), Some(951782400));
+}
+
+#[test]
+fn registers_decode_in_every_format() {
+    let registers = |hours: u8, day_of_month: u8, century: u8, status_b: u8| RtcRegisters {
+	seconds: if status_b & STATUS_B_BINARY != 0 { 30 } else { 0x30 },
+	minutes: if status_b & STATUS_B_BINARY != 0 { 45 } else { 0x45 },
+	hours,
+	day_of_month,
+	month: 2,
+	year: if status_b & STATUS_B_BINARY != 0 { 24 } else { 0x24 },
+	century,
+	status_b,
+    };
+
+    // 2024-02-29 13:45:30, in BCD on a 12 hour clock, and in binary on a 24 hour one with no century register
+    assert_eq!(registers_to_unix_time(&registers(0x81, 0x29, 0x20, 0)), Some(1709214330));
+    assert_eq!(registers_to_unix_time(&registers(13, 29, 0, STATUS_B_BINARY | STATUS_B_24_HOUR)), Some(1709214330));
+
+    // 12 AM is midnight, and 12 PM noon
+    assert_eq!(registers_to_unix_time(&registers(0x12, 0x29, 0x20, 0)), Some(1709164800 + 45 * 60 + 30));
+    assert_eq!(registers_to_unix_time(&registers(0x92, 0x29, 0x20, 0)), Some(1709164800 + 12 * 3600 + 45 * 60 + 30));
+
+    // There's no 30th of February, and a flat battery reads back as all zeros
+    assert_eq!(registers_to_unix_time(&registers(0x81, 0x30, 0x20, 0)), None);
+    assert_eq!(registers_to_unix_time(&RtcRegisters {
+	seconds: 0, minutes: 0, hours: 0, day_of_month: 0, month: 0, year: 0, century: 0, status_b: 0,
+    }), None);
+}
+
+#[test]
+fn unix_time_round_trips_through_the_registers() {
+    assert_eq!(date_from_days_since_epoch(0), (1970, 1, 1));
+    assert_eq!(date_from_days_since_epoch(11016), (2000, 2, 29));
+    assert_eq!(date_from_days_since_epoch(19782), (2024, 2, 29));
+    assert_eq!(date_from_days_since_epoch(47541), (2100, 3, 1));
+    for days in 0 .. 200 * 366 {
+	let (year, month, day) = date_from_days_since_epoch(days);
+	assert_eq!(days_since_epoch(year, month, day), days);
+    }
+
+    // 2024-02-29 13:45:30
+    assert_eq!(unix_time_to_registers(1709214330, 0), RtcRegisters {
+	seconds: 0x30,
+	minutes: 0x45,
+	hours: 0x81,
+	day_of_month: 0x29,
+	month: 0x02,
+	year: 0x24,
+	century: 0x20,
+	status_b: 0,
+    });
+
+    for status_b in [0, STATUS_B_24_HOUR, STATUS_B_BINARY, STATUS_B_BINARY | STATUS_B_24_HOUR] {
+	for now in [0, 43200, 1709164800, 1709214330, 4102444799] {
+	    assert_eq!(registers_to_unix_time(&unix_time_to_registers(now, status_b)), Some(now));
+	}
+    }
+}
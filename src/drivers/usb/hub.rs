@@ -0,0 +1,359 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::driver;
+use crate::drivers::usb::protocol;
+use crate::drivers::usb::usbdevice;
+use crate::fs;
+use crate::scheduler::executor;
+use crate::sys::time;
+use crate::utils::async_mutex::AsyncMutex;
+
+const CLASS_HUB: u8 = 0x09;
+const HUB_DESCRIPTOR: u16 = 0x29;
+
+const GET_STATUS: u8 = 0;
+const CLEAR_FEATURE: u8 = 1;
+const SET_FEATURE: u8 = 3;
+const GET_DESCRIPTOR: u8 = 6;
+
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_ENABLE: u16 = 17;
+const C_PORT_SUSPEND: u16 = 18;
+const C_PORT_OVER_CURRENT: u16 = 19;
+const C_PORT_RESET: u16 = 20;
+
+const PORT_STATUS_CONNECTION: u16 = 1 << 0;
+const PORT_STATUS_ENABLE: u16 = 1 << 1;
+const PORT_STATUS_LOW_SPEED: u16 = 1 << 9;
+const PORT_CHANGE_RESET: u16 = 1 << 4;
+
+// How long a newly connected device is left to settle before it's reset, as the spec asks
+const DEBOUNCE_MS: u64 = 100;
+const RESET_POLL_MS: u64 = 10;
+const RESET_ATTEMPTS: usize = 50;
+// Devices get 10ms after reset before they have to answer
+const RESET_RECOVERY_MS: u64 = 10;
+
+// Only one device can be at address 0 at a time, so a port is only reset while nothing else is being enumerated,
+// whichever hub it's on
+static ENUMERATION_LOCK: AsyncMutex<()> = AsyncMutex::new(());
+// Hubs which have been bound, by controller and address, so that a hub isn't bound twice
+static BOUND_HUBS: Mutex<BTreeSet<(u64, u8)>> = Mutex::new(BTreeSet::new());
+
+struct Hub {
+    device_info: usbdevice::UsbDevice,
+    num_ports: u8,
+    power_good_delay_ms: u64,
+    status_change_endpoint: u8,
+    status_change_interval: u8,
+    // Ports the status change endpoint has reported, and which haven't been looked at yet. Bit n is port n, and bit 0
+    // the hub itself. Set from interrupt context, so only locked with interrupts disabled
+    changed_ports: Mutex<u32>,
+    change_waker: Mutex<Option<Waker>>,
+    // The address of whatever is attached to each port
+    attached: Mutex<BTreeMap<u8, u8>>,
+}
+
+impl Hub {
+    fn new(device_info: usbdevice::UsbDevice) -> Option<Self> {
+	let endpoint = device_info.interface_descriptor.endpoints.values()
+	    .find(|endpoint|
+		  endpoint.direction == protocol::EndpointDirection::In &&
+		  endpoint.transfer_type == protocol::EndpointTransferType::Interrupt)
+	    .cloned()?;
+
+	let mut hub = Hub {
+	    device_info,
+	    num_ports: 0,
+	    power_good_delay_ms: 0,
+	    status_change_endpoint: endpoint.endpoint_number,
+	    status_change_interval: endpoint.interval,
+	    changed_ports: Mutex::new(0),
+	    change_waker: Mutex::new(None),
+	    attached: Mutex::new(BTreeMap::new()),
+	};
+
+	// The descriptor's length depends on the number of ports, so that's read first. Asking for more than the
+	// device has would leave the controller waiting on data which never comes
+	let length = hub.get_hub_descriptor(2)?[0];
+	let descriptor = hub.get_hub_descriptor(length as u16)?;
+	if descriptor.len() < 7 {
+	    return None;
+	}
+
+	// Ports are numbered from 1, and there are at most 31 to fit in the status change bitmap
+	hub.num_ports = descriptor[2].min(31);
+	hub.power_good_delay_ms = descriptor[5] as u64 * 2;
+
+	Some(hub)
+    }
+
+    fn request_type(direction: usbdevice::SetupPacketRequestTypeDirection, recipient: usbdevice::SetupPacketRequestTypeRecipient) -> usbdevice::SetupPacketRequestType {
+	let mut request_type = usbdevice::SetupPacketRequestType::default();
+	request_type.set_direction_from_enum(direction);
+	request_type.set_request_type_from_enum(usbdevice::SetupPacketRequestTypeRequestType::Class);
+	request_type.set_recipient_from_enum(recipient);
+	request_type
+    }
+
+    fn transfer(&self, transfer_type: usbdevice::TransferType) -> Option<Box<[u8]>> {
+	let transfer = usbdevice::UsbTransfer {
+	    transfer_type,
+	    endpoint: 0,
+	    speed: self.device_info.speed,
+	    poll: true,
+	    callback: None,
+	};
+
+	without_interrupts(|| {
+	    self.device_info.hci.lock().transfer(self.device_info.address, transfer)
+	})
+    }
+
+    fn get_hub_descriptor(&self, length: u16) -> Option<Box<[u8]>> {
+	self.transfer(usbdevice::TransferType::ControlRead(usbdevice::SetupPacket {
+	    request_type: Self::request_type(
+		usbdevice::SetupPacketRequestTypeDirection::DeviceToHost, usbdevice::SetupPacketRequestTypeRecipient::Device),
+	    request: GET_DESCRIPTOR,
+	    value: HUB_DESCRIPTOR << 8,
+	    index: 0,
+	    length,
+	}))
+    }
+
+    fn port_feature(&self, request: u8, feature: u16, port: u8) {
+	self.transfer(usbdevice::TransferType::ControlNoData(usbdevice::SetupPacket {
+	    request_type: Self::request_type(
+		usbdevice::SetupPacketRequestTypeDirection::HostToDevice, usbdevice::SetupPacketRequestTypeRecipient::Other),
+	    request,
+	    value: feature,
+	    index: port as u16,
+	    length: 0,
+	}));
+    }
+
+    // Returns the port's status and change bits
+    fn get_port_status(&self, port: u8) -> Option<(u16, u16)> {
+	let status = self.transfer(usbdevice::TransferType::ControlRead(usbdevice::SetupPacket {
+	    request_type: Self::request_type(
+		usbdevice::SetupPacketRequestTypeDirection::DeviceToHost, usbdevice::SetupPacketRequestTypeRecipient::Other),
+	    request: GET_STATUS,
+	    value: 0,
+	    index: port as u16,
+	    length: 4,
+	}))?;
+
+	Some((u16::from_le_bytes([status[0], status[1]]), u16::from_le_bytes([status[2], status[3]])))
+    }
+
+    fn port_path(&self, port: u8) -> String {
+	format!("{}.{}", self.device_info.port_path, port)
+    }
+
+    // Called from interrupt context, with the bitmap of what's changed
+    fn status_changed(&self, bitmap: &[u8]) {
+	let changed = bitmap.iter()
+	    .take(4)
+	    .enumerate()
+	    .fold(0_u32, |changed, (i, byte)| changed | (*byte as u32) << (i * 8));
+	if changed == 0 {
+	    return;
+	}
+
+	*self.changed_ports.lock() |= changed;
+	if let Some(waker) = self.change_waker.lock().take() {
+	    waker.wake();
+	}
+    }
+
+    async fn next_changed_ports(&self) -> u32 {
+	poll_fn(|cx| without_interrupts(|| {
+	    let mut changed_ports = self.changed_ports.lock();
+	    if *changed_ports == 0 {
+		// Registered with the bitmap still locked, so a change can't be missed between checking and sleeping
+		*self.change_waker.lock() = Some(cx.waker().clone());
+		Poll::Pending
+	    } else {
+		Poll::Ready(core::mem::take(&mut *changed_ports))
+	    }
+	})).await
+    }
+
+    // Runs for as long as the hub is plugged in, picking up devices as they come and go. Every port starts out
+    // marked as changed, so that whatever's already plugged in is found the same way
+    async fn watch_ports(self: Arc<Self>) {
+	for port in 1 ..= self.num_ports {
+	    self.port_feature(SET_FEATURE, PORT_POWER, port);
+	}
+	time::sleep_ms(self.power_good_delay_ms).await;
+
+	let all_ports = ((1_u64 << (self.num_ports + 1)) - 2) as u32;
+	without_interrupts(|| *self.changed_ports.lock() |= all_ports);
+
+	let hub = self.clone();
+	let status_change = usbdevice::UsbTransfer {
+	    transfer_type: usbdevice::TransferType::InterruptIn(usbdevice::InterruptTransferDescriptor {
+		frequency_in_ms: self.status_change_interval.max(1),
+		length: (self.num_ports / 8) + 1,
+		coalesce: false,
+	    }),
+	    endpoint: self.status_change_endpoint,
+	    speed: self.device_info.speed,
+	    poll: false,
+	    callback: Some(Arc::new(move |buf: bytes::Bytes| hub.status_changed(&buf))),
+	};
+	without_interrupts(|| {
+	    self.device_info.hci.lock().transfer(self.device_info.address, status_change);
+	});
+
+	loop {
+	    let changed = self.next_changed_ports().await;
+	    for port in 1 ..= self.num_ports {
+		if changed & (1 << port) != 0 {
+		    self.port_changed(port).await;
+		}
+	    }
+	}
+    }
+
+    async fn port_changed(&self, port: u8) {
+	let (status, change) = match self.get_port_status(port) {
+	    Some(s) => s,
+	    None => return,
+	};
+
+	// Acknowledge the changes, or the hub keeps reporting them
+	for (bit, feature) in [(0, C_PORT_CONNECTION), (1, C_PORT_ENABLE), (2, C_PORT_SUSPEND), (3, C_PORT_OVER_CURRENT), (4, C_PORT_RESET)] {
+	    if change & (1 << bit) != 0 {
+		self.port_feature(CLEAR_FEATURE, feature, port);
+	    }
+	}
+
+	let attached = self.attached.lock().get(&port).copied();
+	let connected = status & PORT_STATUS_CONNECTION != 0;
+
+	// A disconnect and reconnect in quick succession shows as still connected, but with the change bit set
+	if let Some(address) = attached {
+	    if !connected || change & 1 != 0 {
+		self.detach(port, address);
+	    }
+	}
+
+	if connected && self.attached.lock().get(&port).is_none() {
+	    self.attach(port).await;
+	}
+    }
+
+    fn detach(&self, port: u8, address: u8) {
+	log::info!("USB device {} unplugged from port {}", address, self.port_path(port));
+	self.attached.lock().remove(&port);
+	BOUND_HUBS.lock().remove(&(self.device_info.hci_number, address));
+
+	let devpath = format!("/bus/usb/devices/{}-{}", self.device_info.hci_number, address);
+	fs::sysfs::emit_uevent("remove", &devpath, "usb", &[]);
+    }
+
+    async fn attach(&self, port: u8) {
+	time::sleep_ms(DEBOUNCE_MS).await;
+
+	let devices = {
+	    let _lock = ENUMERATION_LOCK.lock().await;
+
+	    self.port_feature(SET_FEATURE, PORT_RESET, port);
+	    let mut reset = None;
+	    for _ in 0 .. RESET_ATTEMPTS {
+		time::sleep_ms(RESET_POLL_MS).await;
+		match self.get_port_status(port) {
+		    Some((status, change)) if change & PORT_CHANGE_RESET != 0 => {
+			reset = Some(status);
+			break;
+		    },
+		    _ => (),
+		}
+	    }
+	    self.port_feature(CLEAR_FEATURE, C_PORT_RESET, port);
+
+	    let status = match reset {
+		Some(status) if status & PORT_STATUS_ENABLE != 0 => status,
+		_ => {
+		    log::warn!("USB hub port {} did not come out of reset", self.port_path(port));
+		    return;
+		},
+	    };
+	    time::sleep_ms(RESET_RECOVERY_MS).await;
+
+	    let speed = if status & PORT_STATUS_LOW_SPEED != 0 {
+		usbdevice::PortSpeed::LowSpeed
+	    } else {
+		usbdevice::PortSpeed::FullSpeed
+	    };
+
+	    without_interrupts(|| usbdevice::configure_new_device(
+		&self.device_info.hci, self.device_info.hci_number, &self.port_path(port), speed))
+	};
+
+	if let Some(device) = devices.first() {
+	    log::info!("USB device {} plugged in to port {}", device.address, self.port_path(port));
+	    self.attached.lock().insert(port, device.address);
+	}
+
+	for device in devices {
+	    driver::enumerate_device(Box::new(device));
+	}
+    }
+}
+
+pub fn init() {
+    let hub_driver = HubDriver {};
+    driver::register_driver(Box::new(hub_driver));
+}
+
+pub struct HubDriver {}
+impl driver::Driver for HubDriver {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
+	log::info!("Initialising USB hub");
+
+	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
+	    BOUND_HUBS.lock().insert((usb_info.hci_number, usb_info.address));
+
+	    match Hub::new(usb_info.clone()) {
+		Some(hub) => {
+		    log::info!("  {} ports", hub.num_ports);
+		    if let Err(e) = executor::spawn(Arc::new(hub).watch_ports()) {
+			log::warn!("Unable to start watching USB hub ports: {:?}", e);
+		    }
+		},
+		None => log::warn!("Unable to read USB hub descriptor"),
+	    }
+	}
+
+	driver::ProbeResult::Bound
+    }
+
+    fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
+	    usb_info.interface_descriptor.class == CLASS_HUB
+	} else {
+	    false
+	}
+    }
+
+    // A hub which was unplugged and has come back is new again, but one still plugged in isn't
+    fn check_new_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
+	    !BOUND_HUBS.lock().contains(&(usb_info.hci_number, usb_info.address))
+	} else {
+	    false
+	}
+    }
+}
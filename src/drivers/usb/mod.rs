@@ -1,7 +1,11 @@
 mod uhci;
+mod hub;
+mod msc;
 pub mod usbdevice;
 pub mod protocol;
 
 pub fn init() {
     uhci::init();
+    hub::init();
+    msc::init();
 }
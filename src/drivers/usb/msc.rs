@@ -0,0 +1,453 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use futures_util::future::BoxFuture;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::driver;
+use crate::drivers::usb::protocol;
+use crate::drivers::usb::usbdevice;
+use crate::sys::block::{self, BlockDevice};
+use crate::sys::syscall;
+use crate::utils::async_mutex::AsyncMutex;
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const CBW_SIGNATURE: u32 = 0x43425355;  // "USBC"
+const CSW_SIGNATURE: u32 = 0x53425355;  // "USBS"
+const CBW_LENGTH: usize = 31;
+const CSW_LENGTH: usize = 13;
+const CBW_FLAGS_DATA_IN: u8 = 0x80;
+
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+const BULK_ONLY_RESET: u8 = 0xFF;
+const ENDPOINT_HALT: u16 = 0;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+
+const REQUEST_SENSE_LENGTH: usize = 18;
+const INQUIRY_LENGTH: usize = 36;
+const READ_CAPACITY_LENGTH: usize = 8;
+
+// Each bulk transfer has to fit in one page of DMA arena along with its transfer descriptors, so longer data stages
+// are split up. The device only sees packets, so it can't tell the difference
+const MAX_BULK_TRANSFER: usize = 2048;
+// Longest read or write sent as a single command
+const MAX_BLOCKS_PER_COMMAND: u64 = 64;
+// Media can take a moment to spin up (or be noticed) after the device is plugged in
+const UNIT_READY_ATTEMPTS: usize = 5;
+
+enum DataStage<'a> {
+    None,
+    In(usize),
+    Out(&'a [u8]),
+}
+
+struct BulkEndpoint {
+    number: u8,
+    // The number, with the top bit set for IN endpoints, as requests to the endpoint itself name it
+    address: u8,
+    transfer_descriptor: usbdevice::BulkTransferDescriptor,
+}
+
+impl BulkEndpoint {
+    fn new(endpoint: &protocol::EndpointDescriptor) -> Self {
+	BulkEndpoint {
+	    number: endpoint.endpoint_number,
+	    address: endpoint.endpoint_number | if endpoint.direction == protocol::EndpointDirection::In { 0x80 } else { 0 },
+	    transfer_descriptor: usbdevice::BulkTransferDescriptor {
+		max_packet_size: endpoint.max_packet_size,
+		toggle: Arc::new(AtomicBool::new(false)),
+	    },
+	}
+    }
+}
+
+// A USB Bulk-Only Transport device speaking SCSI, such as a flash drive. Only the first LUN is used
+struct MassStorageDevice {
+    device_info: usbdevice::UsbDevice,
+    bulk_in: BulkEndpoint,
+    bulk_out: BulkEndpoint,
+    next_tag: AtomicU32,
+    block_size: u32,
+    block_count: u64,
+    model: String,
+    // Each command is three transfers which mustn't be interleaved with another's
+    command_lock: AsyncMutex<()>,
+}
+
+impl MassStorageDevice {
+    fn new(device_info: usbdevice::UsbDevice) -> Option<Self> {
+	let find_bulk = |direction: protocol::EndpointDirection| device_info.interface_descriptor.endpoints.values()
+	    .find(|endpoint|
+		  endpoint.direction == direction &&
+		  endpoint.transfer_type == protocol::EndpointTransferType::Bulk)
+	    .map(BulkEndpoint::new);
+
+	let (bulk_in, bulk_out) = match (find_bulk(protocol::EndpointDirection::In), find_bulk(protocol::EndpointDirection::Out)) {
+	    (Some(bulk_in), Some(bulk_out)) => (bulk_in, bulk_out),
+	    _ => {
+		log::warn!("Mass storage device is missing a bulk endpoint");
+		return None;
+	    },
+	};
+
+	let mut device = MassStorageDevice {
+	    device_info,
+	    bulk_in,
+	    bulk_out,
+	    next_tag: AtomicU32::new(1),
+	    block_size: 0,
+	    block_count: 0,
+	    model: String::new(),
+	    command_lock: AsyncMutex::new(()),
+	};
+
+	let inquiry = device.command(
+	    &[SCSI_INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0], DataStage::In(INQUIRY_LENGTH)).ok()?;
+	// Vendor, then product, both padded with spaces
+	device.model = String::from_utf8_lossy(&inquiry[8 .. 32])
+	    .split_whitespace()
+	    .collect::<Vec<&str>>()
+	    .join(" ");
+
+	if !device.wait_until_ready() {
+	    log::warn!("Mass storage device {} has no media ready", device.model);
+	    return None;
+	}
+
+	let capacity = device.command(
+	    &[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataStage::In(READ_CAPACITY_LENGTH)).ok()?;
+	let last_block = u32::from_be_bytes(capacity[0 .. 4].try_into().unwrap());
+	device.block_size = u32::from_be_bytes(capacity[4 .. 8].try_into().unwrap());
+	device.block_count = last_block as u64 + 1;
+
+	if last_block == u32::MAX {
+	    log::warn!("Mass storage device {} is larger than READ CAPACITY(10) can describe, only using the first 2^32 blocks", device.model);
+	}
+
+	// Reads are in 512 byte sectors, which have to divide evenly into the device's blocks
+	if device.block_size < 512 || !device.block_size.is_multiple_of(512) {
+	    log::warn!("Mass storage device {} has unsupported block size {}", device.model, device.block_size);
+	    return None;
+	}
+
+	Some(device)
+    }
+
+    fn wait_until_ready(&self) -> bool {
+	for _ in 0 .. UNIT_READY_ATTEMPTS {
+	    if self.command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], DataStage::None).is_ok() {
+		return true;
+	    }
+
+	    // The device holds on to why the command failed (e.g. unit attention after being plugged in) until asked
+	    let _ = self.command(
+		&[SCSI_REQUEST_SENSE, 0, 0, 0, REQUEST_SENSE_LENGTH as u8, 0], DataStage::In(REQUEST_SENSE_LENGTH));
+	}
+
+	false
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+	self.block_size as u64 / 512
+    }
+
+    fn control(&self, request_type: usbdevice::SetupPacketRequestType, request: u8, value: u16, index: u16) {
+	let transfer = usbdevice::UsbTransfer {
+	    transfer_type: usbdevice::TransferType::ControlNoData(usbdevice::SetupPacket {
+		request_type,
+		request,
+		value,
+		index,
+		length: 0,
+	    }),
+	    endpoint: 0,
+	    speed: self.device_info.speed,
+	    poll: true,
+	    callback: None,
+	};
+
+	without_interrupts(|| {
+	    self.device_info.hci.lock().transfer(self.device_info.address, transfer);
+	});
+    }
+
+    fn clear_halt(&self, endpoint: &BulkEndpoint) {
+	let mut request_type = usbdevice::SetupPacketRequestType::default();
+	request_type.set_direction_from_enum(usbdevice::SetupPacketRequestTypeDirection::HostToDevice);
+	request_type.set_request_type_from_enum(usbdevice::SetupPacketRequestTypeRequestType::Standard);
+	request_type.set_recipient_from_enum(usbdevice::SetupPacketRequestTypeRecipient::Endpoint);
+
+	self.control(request_type, usbdevice::RequestCode::ClearFeature as u8, ENDPOINT_HALT, endpoint.address as u16);
+
+	// Clearing a halt always puts the endpoint back to DATA0
+	endpoint.transfer_descriptor.toggle.store(false, Ordering::SeqCst);
+    }
+
+    // Gets the device back in step after it's stopped making sense, e.g. a bad CSW
+    fn reset_recovery(&self) {
+	let mut request_type = usbdevice::SetupPacketRequestType::default();
+	request_type.set_direction_from_enum(usbdevice::SetupPacketRequestTypeDirection::HostToDevice);
+	request_type.set_request_type_from_enum(usbdevice::SetupPacketRequestTypeRequestType::Class);
+	request_type.set_recipient_from_enum(usbdevice::SetupPacketRequestTypeRecipient::Interface);
+
+	self.control(request_type, BULK_ONLY_RESET, 0, self.device_info.interface_descriptor.interface_number as u16);
+	self.clear_halt(&self.bulk_in);
+	self.clear_halt(&self.bulk_out);
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> Result<(), syscall::CanonicalError> {
+	for chunk in data.chunks(MAX_BULK_TRANSFER) {
+	    let transfer = usbdevice::UsbTransfer {
+		transfer_type: usbdevice::TransferType::BulkWrite(self.bulk_out.transfer_descriptor.clone(), chunk.to_vec()),
+		endpoint: self.bulk_out.number,
+		speed: self.device_info.speed,
+		poll: true,
+		callback: None,
+	    };
+
+	    let written = without_interrupts(|| {
+		self.device_info.hci.lock().transfer(self.device_info.address, transfer)
+	    });
+	    if written.is_none() {
+		return Err(syscall::CanonicalError::Io);
+	    }
+	}
+
+	Ok(())
+    }
+
+    fn bulk_read(&self, len: usize) -> Result<Vec<u8>, syscall::CanonicalError> {
+	let mut data = Vec::with_capacity(len);
+	while data.len() < len {
+	    let chunk_len = cmp::min(len - data.len(), MAX_BULK_TRANSFER);
+	    let transfer = usbdevice::UsbTransfer {
+		transfer_type: usbdevice::TransferType::BulkRead(self.bulk_in.transfer_descriptor.clone(), chunk_len as u16),
+		endpoint: self.bulk_in.number,
+		speed: self.device_info.speed,
+		poll: true,
+		callback: None,
+	    };
+
+	    let read = without_interrupts(|| {
+		self.device_info.hci.lock().transfer(self.device_info.address, transfer)
+	    });
+	    match read {
+		Some(buf) => data.extend_from_slice(&buf),
+		None => return Err(syscall::CanonicalError::Io),
+	    }
+	}
+
+	Ok(data)
+    }
+
+    // Sends a SCSI command, wrapped in a CBW, then moves any data and collects the CSW. Failures are recovered from as
+    // the Bulk-Only Transport spec says, so that the device is ready for the next command either way
+    fn command(&self, cdb: &[u8], data_stage: DataStage) -> Result<Vec<u8>, syscall::CanonicalError> {
+	let tag = self.next_tag.fetch_add(1, Ordering::SeqCst);
+	let (data_transfer_length, flags) = match data_stage {
+	    DataStage::None => (0, 0),
+	    DataStage::In(len) => (len as u32, CBW_FLAGS_DATA_IN),
+	    DataStage::Out(data) => (data.len() as u32, 0),
+	};
+
+	let mut cbw = vec![0_u8; CBW_LENGTH];
+	cbw[0 .. 4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+	cbw[4 .. 8].copy_from_slice(&tag.to_le_bytes());
+	cbw[8 .. 12].copy_from_slice(&data_transfer_length.to_le_bytes());
+	cbw[12] = flags;
+	cbw[13] = 0;  // LUN
+	cbw[14] = cdb.len() as u8;
+	cbw[15 .. 15 + cdb.len()].copy_from_slice(cdb);
+
+	if self.bulk_write(&cbw).is_err() {
+	    self.reset_recovery();
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	// A stall during the data stage still leaves a CSW to collect, once the halt has been cleared
+	let data = match data_stage {
+	    DataStage::None => Some(Vec::new()),
+	    DataStage::In(len) => match self.bulk_read(len) {
+		Ok(data) => Some(data),
+		Err(_) => {
+		    self.clear_halt(&self.bulk_in);
+		    None
+		},
+	    },
+	    DataStage::Out(data) => match self.bulk_write(data) {
+		Ok(()) => Some(Vec::new()),
+		Err(_) => {
+		    self.clear_halt(&self.bulk_out);
+		    None
+		},
+	    },
+	};
+
+	let csw = match self.bulk_read(CSW_LENGTH) {
+	    Ok(csw) => csw,
+	    Err(_) => {
+		self.clear_halt(&self.bulk_in);
+		match self.bulk_read(CSW_LENGTH) {
+		    Ok(csw) => csw,
+		    Err(e) => {
+			self.reset_recovery();
+			return Err(e);
+		    },
+		}
+	    },
+	};
+
+	let signature = u32::from_le_bytes(csw[0 .. 4].try_into().unwrap());
+	let csw_tag = u32::from_le_bytes(csw[4 .. 8].try_into().unwrap());
+	if signature != CSW_SIGNATURE || csw_tag != tag {
+	    self.reset_recovery();
+	    return Err(syscall::CanonicalError::Io);
+	}
+
+	match csw[12] {
+	    CSW_STATUS_PASSED => data.ok_or(syscall::CanonicalError::Io),
+	    CSW_STATUS_FAILED => Err(syscall::CanonicalError::Io),
+	    _ => {
+		// Phase error
+		self.reset_recovery();
+		Err(syscall::CanonicalError::Io)
+	    },
+	}
+    }
+
+    fn read_blocks(&self, first_block: u64, count: u64) -> Result<Vec<u8>, syscall::CanonicalError> {
+	let mut data = Vec::with_capacity((count * self.block_size as u64) as usize);
+	let mut block = first_block;
+	while block < first_block + count {
+	    let blocks = cmp::min(first_block + count - block, MAX_BLOCKS_PER_COMMAND);
+	    let lba = (block as u32).to_be_bytes();
+	    let length = (blocks as u16).to_be_bytes();
+	    data.extend_from_slice(&self.command(
+		&[SCSI_READ_10, 0, lba[0], lba[1], lba[2], lba[3], 0, length[0], length[1], 0],
+		DataStage::In((blocks * self.block_size as u64) as usize))?);
+
+	    block += blocks;
+	}
+
+	Ok(data)
+    }
+
+    fn write_blocks(&self, first_block: u64, data: &[u8]) -> Result<(), syscall::CanonicalError> {
+	for (i, chunk) in data.chunks((MAX_BLOCKS_PER_COMMAND * self.block_size as u64) as usize).enumerate() {
+	    let block = first_block + i as u64 * MAX_BLOCKS_PER_COMMAND;
+	    let blocks = chunk.len() as u64 / self.block_size as u64;
+	    let lba = (block as u32).to_be_bytes();
+	    let length = (blocks as u16).to_be_bytes();
+	    self.command(
+		&[SCSI_WRITE_10, 0, lba[0], lba[1], lba[2], lba[3], 0, length[0], length[1], 0],
+		DataStage::Out(chunk))?;
+	}
+
+	Ok(())
+    }
+}
+
+impl BlockDevice for MassStorageDevice {
+    fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	Box::pin(async move {
+	    if size == 0 || offset + size > self.size_in_sectors() {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let _lock = self.command_lock.lock().await;
+
+	    // The blocks covering the sectors asked for, which may start and end part way through one
+	    let sectors_per_block = self.sectors_per_block();
+	    let first_block = offset / sectors_per_block;
+	    let last_block = (offset + size - 1) / sectors_per_block;
+	    let data = self.read_blocks(first_block, last_block + 1 - first_block)?;
+
+	    let start = ((offset % sectors_per_block) * 512) as usize;
+	    Ok(Bytes::from(data).slice(start .. start + (size * 512) as usize))
+	})
+    }
+
+    fn write(self: Arc<Self>, offset: u64, data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    let size = data.len() as u64 / 512;
+	    if data.is_empty() || !data.len().is_multiple_of(512) || offset + size > self.size_in_sectors() {
+		return Err(syscall::CanonicalError::Inval);
+	    }
+
+	    let _lock = self.command_lock.lock().await;
+
+	    let sectors_per_block = self.sectors_per_block();
+	    let first_block = offset / sectors_per_block;
+	    let last_block = (offset + size - 1) / sectors_per_block;
+
+	    // Sectors which only cover part of a block have the rest of it read in first, so it can be written back whole
+	    if offset % sectors_per_block == 0 && (offset + size) % sectors_per_block == 0 {
+		self.write_blocks(first_block, &data)
+	    } else {
+		let mut blocks = self.read_blocks(first_block, last_block + 1 - first_block)?;
+		let start = ((offset % sectors_per_block) * 512) as usize;
+		blocks[start .. start + data.len()].copy_from_slice(&data);
+		self.write_blocks(first_block, &blocks)
+	    }
+	})
+    }
+
+    fn size_in_sectors(&self) -> u64 {
+	self.block_count * self.sectors_per_block()
+    }
+
+    fn model(&self) -> String {
+	self.model.clone()
+    }
+}
+
+pub fn init() {
+    let msc_driver = MscDriver {};
+    driver::register_driver(Box::new(msc_driver));
+}
+
+pub struct MscDriver {}
+impl driver::Driver for MscDriver {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
+	log::info!("Initialising USB mass storage device");
+
+	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
+	    if let Some(device) = MassStorageDevice::new(usb_info.clone()) {
+		log::info!("  {} - {} blocks of {} bytes", device.model, device.block_count, device.block_size);
+		block::register_disk(Arc::new(device));
+	    }
+	}
+
+	driver::ProbeResult::Bound
+    }
+
+    fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
+	    usb_info.interface_descriptor.class == CLASS_MASS_STORAGE &&
+		usb_info.interface_descriptor.subclass == SUBCLASS_SCSI &&
+		usb_info.interface_descriptor.protocol == PROTOCOL_BULK_ONLY
+	} else {
+	    false
+	}
+    }
+
+    fn check_new_device(&self, _info: &dyn driver::DeviceTypeIdentifier) -> bool {
+	true // Not yet implemented
+    }
+}
@@ -225,7 +225,9 @@ fn parse_rest_of_interface(input: &[u8]) -> IResult<&[u8], (BTreeMap<u8, Endpoin
     for descriptor in descriptors {
 	match descriptor {
 	    Descriptor::EndpointDescriptor(e) => {
-		endpoint_map.insert(e.endpoint_number, e.clone());
+		// Keyed by address rather than number, as an IN and an OUT endpoint may share a number
+		let address = e.endpoint_number | if e.direction == EndpointDirection::In { 0x80 } else { 0 };
+		endpoint_map.insert(address, e.clone());
 	    },
 	    Descriptor::GenericDescriptor(g) => generic_descriptors.push(g),
 	}
@@ -4,6 +4,7 @@ use alloc::vec::Vec;
 use anyhow::{anyhow, Result};
 use bitfield::bitfield;
 use core::arch::asm;
+use core::cmp;
 use core::ptr;
 use core::ops::BitOr;
 use core::slice;
@@ -22,6 +23,7 @@ use crate::drivers::usb::usbdevice;
 const USBCMD: u16 = 0x00;
 const USBSTS: u16 = 0x02;
 const USBINTR: u16 = 0x04;
+const FRNUM: u16 = 0x06;
 const FRBASEADD: u16 = 0x08;
 const PORTSC1: u16 = 0x10;
 const PORTSC2: u16 = 0x12;
@@ -171,6 +173,8 @@ struct UhciTransfer {
     buffer: Option<arena::ArenaTag>,
     buf_length: usize,
     callback: Option<Arc<dyn Fn(bytes::Bytes) + Send + Sync>>,
+    // The toggle the first data TD starts on, which for bulk transfers carries on from the endpoint's last transfer
+    initial_toggle: bool,
 }
 
 unsafe impl Send for UhciTransfer { }
@@ -188,6 +192,7 @@ impl UhciTransfer {
 	    buffer: None,
 	    buf_length: 0,
 	    callback: None,
+	    initial_toggle: false,
 	}
     }
 
@@ -294,7 +299,7 @@ impl UhciTransfer {
 	    TransferDescriptorType::Setup => false,
 	    TransferDescriptorType::Status => true,
 	    TransferDescriptorType::Data => if self.transfer_descriptors.is_empty() {
-		false
+		self.initial_toggle
 	    } else {
 		!self.arena.tag_to_ptr_mut::<TransferDescriptor>(
 		    *self.transfer_descriptors.iter_mut().rev().nth(0).unwrap())
@@ -329,6 +334,11 @@ impl UhciTransfer {
 	self.queue_head_phys
     }
 
+    // The toggle the endpoint's next transfer should start on
+    pub fn next_toggle(&self) -> bool {
+	!self.arena.tag_to_ptr::<TransferDescriptor>(*self.transfer_descriptors.last().unwrap()).toggle()
+    }
+
     pub fn get_owned_buf(&mut self) -> Option<Box<[u8]>> {
 	if let Some(buf_tag) = self.buffer {
 	    let dma_buf = self.arena.tag_to_dma_buffer(buf_tag, self.buf_length);
@@ -356,7 +366,13 @@ pub struct UhciBus<'a> {
 
     next_address: u8,
 
-    recurring_transfers: Vec<UhciTransfer>,
+    recurring_transfers: Vec<RecurringTransfer>,
+}
+
+struct RecurringTransfer {
+    frame: usize,
+    coalesce: bool,
+    transfer: UhciTransfer,
 }
 
 unsafe impl Send for UhciBus<'_> { }
@@ -595,6 +611,33 @@ impl<'a> UhciBus<'a> {
 		    is_low_speed, interrupt_transfer_descriptor.length, transfer.endpoint,
 		    address, 0x69 /* packet_id = IN */, buffer_phys, TransferDescriptorType::Data);
 	    },
+	    usbdevice::TransferType::BulkRead(ref bulk_transfer_descriptor, length) => {
+		uhci_transfer.initial_toggle = bulk_transfer_descriptor.toggle.load(Ordering::SeqCst);
+		let buffer_phys = uhci_transfer.create_transfer_buffer(length as usize);
+
+		for offset in (0 .. length).step_by(bulk_transfer_descriptor.max_packet_size as usize) {
+		    let packet_length = cmp::min(length - offset, bulk_transfer_descriptor.max_packet_size);
+		    uhci_transfer.create_transfer_descriptor(
+			is_low_speed, packet_length.try_into().unwrap(), transfer.endpoint, address,
+			0x69 /* packet_id = IN */, buffer_phys + offset.into(), TransferDescriptorType::Data);
+		}
+	    },
+	    usbdevice::TransferType::BulkWrite(ref bulk_transfer_descriptor, ref buf) => {
+		uhci_transfer.initial_toggle = bulk_transfer_descriptor.toggle.load(Ordering::SeqCst);
+		// Kept in the transfer buffer, rather than just anywhere in the arena, so that a successful write hands
+		// back a buffer, as other transfers do
+		let buffer_phys = uhci_transfer.create_transfer_buffer(buf.len());
+		let buffer_tag = uhci_transfer.buffer.unwrap();
+		uhci_transfer.arena.tag_to_slice_mut(buffer_tag, buf.len()).copy_from_slice(buf);
+
+		let length = buf.len() as u16;
+		for offset in (0 .. length).step_by(bulk_transfer_descriptor.max_packet_size as usize) {
+		    let packet_length = cmp::min(length - offset, bulk_transfer_descriptor.max_packet_size);
+		    uhci_transfer.create_transfer_descriptor(
+			is_low_speed, packet_length.try_into().unwrap(), transfer.endpoint, address,
+			0xe1 /* packet_id = OUT */, buffer_phys + offset.into(), TransferDescriptorType::Data);
+		}
+	    },
 	    _ => unimplemented!(),
 	}
 
@@ -630,10 +673,14 @@ impl<'a> UhciBus<'a> {
 	    self.frame_list[i].set_terminate(false);
 	}
 
+	// A TD which fails is left inactive, but the controller stops there, so any after it would stay active forever
+	let mut failed = false;
 	while !uhci_transfer.is_complete() {
 	    let (ts, n) = uhci_transfer.get_status();
 	    if ts != TransferStatus::Done && ts != TransferStatus::Active {
 		log::info!("  TD {} - Transfer status {:?}", n, ts);
+		failed = true;
+		break;
 	    }
 
 	    unsafe {
@@ -643,7 +690,10 @@ impl<'a> UhciBus<'a> {
 	
 	let (ts, n) = uhci_transfer.get_status();
 	if ts != TransferStatus::Done && ts != TransferStatus::Active {
-	    log::info!("  TD {} - Transfer status {:?}", n, ts);
+	    if !failed {
+		log::info!("  TD {} - Transfer status {:?}", n, ts);
+	    }
+	    failed = true;
 	}
 
 	let halted = unsafe {
@@ -656,7 +706,26 @@ impl<'a> UhciBus<'a> {
 	    panic!("Status is halted");
 	}
 
+	// Put back any recurring transfers the one-shot displaced, so that e.g. a keyboard keeps working
 	self.frame_list.fill_with(Default::default);
+	for recurring in self.recurring_transfers.iter() {
+	    self.frame_list[recurring.frame].set_qh_td_select(true);
+	    self.frame_list[recurring.frame].set_frame_list_pointer_phys(recurring.transfer.queue_head_phys);
+	    fence(Ordering::SeqCst);
+	    self.frame_list[recurring.frame].set_terminate(false);
+	}
+
+	if failed {
+	    return None;
+	}
+
+	match transfer.transfer_type {
+	    usbdevice::TransferType::BulkRead(ref bulk_transfer_descriptor, _) |
+	    usbdevice::TransferType::BulkWrite(ref bulk_transfer_descriptor, _) =>
+		bulk_transfer_descriptor.toggle.store(uhci_transfer.next_toggle(), Ordering::SeqCst),
+	    _ => (),
+	}
+
 	uhci_transfer.get_owned_buf()
     }
 }
@@ -702,7 +771,11 @@ impl usbdevice::UsbHCI for UhciBus<'_> {
 		for i in (0 .. 1024).step_by(interrupt_transfer_descriptor.frequency_in_ms as usize) {
 		    let mut uhci_transfer = self.build_transfer(address, transfer.clone());
 		    let queue_head_phys = uhci_transfer.finalise_and_get_qh(transfer.poll);
-		    self.recurring_transfers.push(uhci_transfer);
+		    self.recurring_transfers.push(RecurringTransfer {
+			frame: i,
+			coalesce: interrupt_transfer_descriptor.coalesce,
+			transfer: uhci_transfer,
+		    });
 
 		    // First, ensure the frame pointer is terminated, so that the controller will not attempt to run
 		    // a partially updated frame pointer
@@ -733,9 +806,9 @@ impl usbdevice::UsbHCI for UhciBus<'_> {
 	addr
     }
     
-    fn interrupt(&mut self) -> (Option<Arc<dyn Fn(bytes::Bytes) + Send + Sync>>, Option<bytes::Bytes>) {
-	let mut callback: Option<Arc<dyn Fn(bytes::Bytes) + Send + Sync>> = None;
-	let mut buffer: Option<bytes::Bytes> = None;
+    fn interrupt(&mut self) -> Vec<(Arc<dyn Fn(bytes::Bytes) + Send + Sync>, bytes::Bytes)> {
+	let mut completed: Vec<CompletedTransfer> = Vec::new();
+	let mut current_frame = 0;
 
 	let (usbint, usberr) = unsafe {
 	    let mut port_sts = Port::<u16>::new(self.base_io + USBSTS);
@@ -746,15 +819,27 @@ impl usbdevice::UsbHCI for UhciBus<'_> {
 
 	// TODO: Proper error and Result handling here
 	if usbint && !usberr {
-	    for transfer in self.recurring_transfers.iter_mut() {
-		if transfer.is_complete() {
-		    transfer.reset_tds();
-		    callback = transfer.get_callback();
-		    buffer = transfer.get_buffer();
+	    // The controller walks the frame list in order, so the oldest completed transfer is the one in the frame
+	    // just after the current one
+	    current_frame = unsafe {
+		let mut port_frnum = Port::<u16>::new(self.base_io + FRNUM);
+		(port_frnum.read() & 0x3ff) as usize
+	    };
+
+	    for recurring in self.recurring_transfers.iter_mut().filter(|r| r.transfer.is_complete()) {
+		recurring.transfer.reset_tds();
+		if let (Some(callback), Some(buffer)) = (recurring.transfer.get_callback(), recurring.transfer.get_buffer()) {
+		    completed.push(CompletedTransfer {
+			frame: recurring.frame,
+			coalesce: recurring.coalesce,
+			callback,
+			buffer,
+		    });
 		}
 	    }
 	} else if usbint && usberr {
-	    for transfer in self.recurring_transfers.iter_mut() {
+	    for recurring in self.recurring_transfers.iter_mut() {
+		let transfer = &mut recurring.transfer;
 		let (ts, n) = transfer.get_status();
 		if ts != TransferStatus::Done && ts != TransferStatus::Active {
 		    log::info!("  TD {} - Transfer status {:?}", n, ts);
@@ -770,10 +855,36 @@ impl usbdevice::UsbHCI for UhciBus<'_> {
 	    port_sts.write(STATUS_INT);
 	};
 
-	(callback, buffer)
+	order_completed(completed, current_frame)
     }
 }
 
+struct CompletedTransfer {
+    frame: usize,
+    coalesce: bool,
+    callback: Arc<dyn Fn(bytes::Bytes) + Send + Sync>,
+    buffer: bytes::Bytes,
+}
+
+// The callbacks to make for what's completed, in the order the controller ran the transfers. That's walking the frame
+// list in order, so the oldest is the one in the frame just after the current one
+fn order_completed(mut completed: Vec<CompletedTransfer>, current_frame: usize) -> Vec<(Arc<dyn Fn(bytes::Bytes) + Send + Sync>, bytes::Bytes)> {
+    completed.sort_by_key(|c| (c.frame + 1023 - current_frame) % 1024);
+
+    let mut calls: Vec<(Arc<dyn Fn(bytes::Bytes) + Send + Sync>, bytes::BytesMut, bool)> = Vec::new();
+    for c in completed {
+	match calls.last_mut() {
+	    Some((last_callback, last_buffer, true)) if c.coalesce && Arc::ptr_eq(last_callback, &c.callback) =>
+		last_buffer.extend_from_slice(&c.buffer),
+	    _ => calls.push((c.callback, bytes::BytesMut::from(&c.buffer[..]), c.coalesce)),
+	}
+    }
+
+    calls.into_iter()
+	.map(|(callback, buffer, _)| (callback, buffer.freeze()))
+	.collect()
+}
+
 pub fn init() {
     let uhci_driver = UhciDriver {};
     driver::register_driver(Box::new(uhci_driver));
@@ -781,13 +892,13 @@ pub fn init() {
 
 pub struct UhciDriver {}
 impl driver::Driver for UhciDriver {
-    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
 	log::info!("Initialising UHCI controller");
 
 	let pci_info = if let Some(pci_info) = info.as_any().downcast_ref::<pcie::PciDeviceType>() {
 	    pci_info
 	} else {
-	    return;
+	    return driver::ProbeResult::Bound;
 	};
 
 	let bar = pcie::get_bar(pci_info.clone(), 4).expect("Unable to find UHCI BAR");
@@ -806,6 +917,9 @@ impl driver::Driver for UhciDriver {
 	}
 
 	usbdevice::register_hci(uhci);
+	driver::register_shutdown_hook(Box::new(move || stop_controller(uhci_base as u16)));
+
+	driver::ProbeResult::Bound
     }
 
     fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
@@ -823,18 +937,59 @@ impl driver::Driver for UhciDriver {
     }
 }
 
+// Halts the schedule and masks interrupts, so the controller stops touching the frame list and transfer descriptors
+fn stop_controller(uhci_base: u16) {
+    unsafe {
+	let mut usbintr_reg = Port::<u16>::new(uhci_base + USBINTR);
+	let mut cmd_reg = Port::<u16>::new(uhci_base + USBCMD);
+	let mut port_sts = Port::<u16>::new(uhci_base + USBSTS);
+
+	usbintr_reg.write(0);
+	cmd_reg.write(cmd_reg.read() & !HOST_CONTROLLER_RUN);
+
+	// The controller finishes the current frame before halting, which takes at most a millisecond
+	for _ in 0 .. 1000000 {
+	    if port_sts.read() & STATUS_HALTED != 0 {
+		break;
+	    }
+	    asm!("nop");
+	}
+    }
+}
+
 fn handle_uhci_interrupts(hci: &Arc<Mutex<Box<dyn usbdevice::UsbHCI>>>) {
-    let (callback, buffer) = hci.lock().interrupt();
+    let completed = hci.lock().interrupt();
 
     // This has to be done outside of the lock, in case it results in more USB traffic
-    if let Some(c) = callback {
+    for (c, b) in completed {
 	let fn_ptr = &*c as *const _ as *const () as usize;
 	if fn_ptr < 0x1_0000_0000 {
             panic!("Invalid USB callback pointer: 0x{:x}", fn_ptr);
 	}
 
-	if let Some(b) = buffer {
-	    c(b);
-	}
+	c(b);
     }
 }
+
+#[test]
+fn queued_reports_reach_the_callback_oldest_first() {
+    let keyboard: Arc<dyn Fn(bytes::Bytes) + Send + Sync> = Arc::new(|_| ());
+    let completed = |coalesce: bool| [(5, "third"), (12, "second"), (11, "first")].into_iter()
+	.map(|(frame, report)| CompletedTransfer {
+	    frame,
+	    coalesce,
+	    callback: keyboard.clone(),
+	    buffer: bytes::Bytes::from_static(report.as_bytes()),
+	})
+	.collect::<Vec<CompletedTransfer>>();
+
+    // The controller is in frame 10, so has wrapped round since running frame 5
+    let calls = order_completed(completed(true), 10);
+    assert_eq!(calls.len(), 1);
+    assert!(Arc::ptr_eq(&calls[0].0, &keyboard));
+    assert_eq!(&calls[0].1[..], b"firstsecondthird");
+
+    let calls = order_completed(completed(false), 10);
+    let reports = calls.iter().map(|(_, buffer)| &buffer[..]).collect::<Vec<&[u8]>>();
+    assert_eq!(reports, [b"first" as &[u8], b"second", b"third"]);
+}
@@ -1,13 +1,17 @@
 use alloc::boxed::Box;
 use alloc::fmt;
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bitfield::bitfield;
 use bytes;
 use core::any::Any;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
 
 use crate::driver;
+use crate::fs;
 use crate::drivers::usb::protocol;
 
 #[derive(PartialEq, Eq, Clone)]
@@ -133,6 +137,17 @@ enum Descriptor {
 pub struct InterruptTransferDescriptor {
     pub frequency_in_ms: u8,
     pub length: u8,
+    // If several instances of this transfer complete before we service the interrupt, hand all of their buffers to
+    // the callback at once (concatenated, oldest first) rather than calling it once per buffer
+    pub coalesce: bool,
+}
+
+#[derive(Clone)]
+pub struct BulkTransferDescriptor {
+    pub max_packet_size: u16,
+    // Bulk endpoints carry on alternating DATA0/DATA1 from one transfer to the next, so whoever owns the endpoint keeps
+    // track of where it's up to. The controller updates it once a transfer is done
+    pub toggle: Arc<AtomicBool>,
 }
 
 #[allow(dead_code)]
@@ -141,8 +156,8 @@ pub enum TransferType {
     ControlRead(SetupPacket),
     ControlWrite(WriteSetupPacket),
     ControlNoData(SetupPacket),
-    BulkWrite,
-    BulkRead,
+    BulkWrite(BulkTransferDescriptor, Vec<u8>),
+    BulkRead(BulkTransferDescriptor, u16),
     InterruptOut,
     InterruptIn(InterruptTransferDescriptor),
 }
@@ -158,9 +173,11 @@ pub struct UsbTransfer {
 
 pub trait UsbHCI: Send + Sync {
     fn get_ports(&self) -> Vec<Port>;
+    // One-shot transfers return None if the transfer failed, e.g. because the endpoint stalled
     fn transfer(&mut self, address: u8, transfer: UsbTransfer) -> Option<Box<[u8]>>;
     fn get_free_address(&mut self) -> u8;
-    fn interrupt(&mut self) -> (Option<Arc<dyn Fn(bytes::Bytes) + Send + Sync>>, Option<bytes::Bytes>);
+    // Returns callbacks to run for any completed transfers, in the order the transfers completed
+    fn interrupt(&mut self) -> Vec<(Arc<dyn Fn(bytes::Bytes) + Send + Sync>, bytes::Bytes)>;
 }
 
 #[derive(Clone)]
@@ -170,6 +187,8 @@ pub struct UsbDevice {
     pub interface_descriptor: protocol::InterfaceDescriptor,
     pub address: u8,
     pub hci: Arc<Mutex<Box<dyn UsbHCI>>>,
+    pub hci_number: u64,
+    pub port_path: String,
     pub speed: PortSpeed,
 }
 
@@ -189,106 +208,142 @@ impl fmt::Display for UsbDevice {
     }
 }
 
+// Addresses are only unique per controller, so sysfs names devices by controller number as well
+static NEXT_HCI_NUMBER: AtomicU64 = AtomicU64::new(0);
+
 pub fn register_hci(locked_hci: Arc<Mutex<Box<dyn UsbHCI>>>) {
+    let hci_number = NEXT_HCI_NUMBER.fetch_add(1, Ordering::SeqCst);
+    let ports = locked_hci.lock().get_ports();
+
     let mut devices: Vec<UsbDevice> = Vec::new();
-    {
-	let mut hci = locked_hci.lock();
-	for port in hci.get_ports() {
-	    if port.status == PortStatus::Disconnected {
-		continue;
-	    }
-
-	    let mut read_request_type = SetupPacketRequestType::default();
-	    read_request_type.set_direction_from_enum(SetupPacketRequestTypeDirection::DeviceToHost);
-	    read_request_type.set_request_type_from_enum(SetupPacketRequestTypeRequestType::Standard);
-	    read_request_type.set_recipient_from_enum(SetupPacketRequestTypeRecipient::Device);
-
-	    let mut write_request_type = SetupPacketRequestType::default();
-	    write_request_type.set_direction_from_enum(SetupPacketRequestTypeDirection::HostToDevice);
-	    write_request_type.set_request_type_from_enum(SetupPacketRequestTypeRequestType::Standard);
-	    write_request_type.set_recipient_from_enum(SetupPacketRequestTypeRecipient::Device);
-
-	    let xfer_config_descriptor = UsbTransfer {
-		transfer_type: TransferType::ControlRead(SetupPacket {
-		    request_type: read_request_type,
-		    request: RequestCode::GetDescriptor as u8,
-		    value: 0x0200,
-		    index: 0,
-		    length: 9,
-		}),
-		endpoint: 0,
-		speed: port.speed,
-		poll: true,
-		callback: None,
-	    };
-	    let configuration_descriptor_slice = hci.transfer(0, xfer_config_descriptor).unwrap();
-	    let (_, configuration_descriptor) = protocol::parse_configuration_descriptor(&configuration_descriptor_slice).unwrap();
-
-	    let device_address = hci.get_free_address();
-
-	    let set_addr = UsbTransfer {
-		transfer_type: TransferType::ControlNoData(SetupPacket {
-		    request_type: write_request_type,
-		    request: RequestCode::SetAddress as u8,
-		    value: device_address.into(),
-		    index: 0,
-		    length: 0,
-		}),
-		endpoint: 0,
-		speed: port.speed,
-		poll: true,
-		callback: None,
-	    };
-	    hci.transfer(0, set_addr);
-
-	    let xfer_descriptors = UsbTransfer {
-		transfer_type: TransferType::ControlRead(SetupPacket {
-		    request_type: read_request_type,
-		    request: RequestCode::GetDescriptor as u8,
-		    value: 0x0200,
-		    index: 0,
-		    length: configuration_descriptor.total_length,
-		}),
-		endpoint: 0,
-		speed: port.speed,
-		poll: true,
-		callback: None,
-	    };
-	    let descriptors = hci.transfer(device_address, xfer_descriptors).unwrap();
-
-	    // Effectively treat each interface as its own device, which it more or less is
-	    let (_, (configuration_descriptor, interface_descriptors)) = protocol::parse_configuration_descriptors(&descriptors).unwrap();
-
-	    let set_configuration = UsbTransfer {
-		transfer_type: TransferType::ControlNoData(SetupPacket {
-		    request_type: write_request_type,
-		    request: RequestCode::SetConfiguration as u8,
-		    value: configuration_descriptor.configuration_value as u16,
-		    index: 0,
-		    length: 0,
-		}),
-		endpoint: 0,
-		speed: port.speed,
-		poll: true,
-		callback: None,
-	    };
-	    hci.transfer(device_address, set_configuration);
-	    
-	    for interface_descriptor in interface_descriptors {
-		let device = UsbDevice {
-		    configuration_descriptor: configuration_descriptor.clone(),
-		    interface_descriptor,
-		    address: device_address,
-		    hci: locked_hci.clone(),
-		    speed: port.speed,
-		};
-
-		devices.push(device);
-	    }
+    for port in ports {
+	if port.status == PortStatus::Disconnected {
+	    continue;
 	}
+
+	devices.extend(configure_new_device(&locked_hci, hci_number, &format!("{}", port.num), port.speed));
     }
 
     for device in devices {
 	driver::enumerate_device(Box::new(device));
     }
 }
+
+// Takes a device which has just been reset, and so answers at address 0, through to being configured. Only one
+// device may be at address 0 at a time, so the caller has to make sure no other port is reset until this returns.
+// Returns one UsbDevice per interface, which is how drivers see them, ready to be enumerated
+pub fn configure_new_device(
+    locked_hci: &Arc<Mutex<Box<dyn UsbHCI>>>, hci_number: u64, port_path: &str, speed: PortSpeed) -> Vec<UsbDevice> {
+    let mut devices: Vec<UsbDevice> = Vec::new();
+    let mut hci = locked_hci.lock();
+
+    let mut read_request_type = SetupPacketRequestType::default();
+    read_request_type.set_direction_from_enum(SetupPacketRequestTypeDirection::DeviceToHost);
+    read_request_type.set_request_type_from_enum(SetupPacketRequestTypeRequestType::Standard);
+    read_request_type.set_recipient_from_enum(SetupPacketRequestTypeRecipient::Device);
+
+    let mut write_request_type = SetupPacketRequestType::default();
+    write_request_type.set_direction_from_enum(SetupPacketRequestTypeDirection::HostToDevice);
+    write_request_type.set_request_type_from_enum(SetupPacketRequestTypeRequestType::Standard);
+    write_request_type.set_recipient_from_enum(SetupPacketRequestTypeRecipient::Device);
+
+    let xfer_config_descriptor = UsbTransfer {
+	transfer_type: TransferType::ControlRead(SetupPacket {
+	    request_type: read_request_type,
+	    request: RequestCode::GetDescriptor as u8,
+	    value: 0x0200,
+	    index: 0,
+	    length: 9,
+	}),
+	endpoint: 0,
+	speed,
+	poll: true,
+	callback: None,
+    };
+    let configuration_descriptor_slice = match hci.transfer(0, xfer_config_descriptor) {
+	Some(slice) => slice,
+	None => {
+	    log::warn!("USB device on port {} did not respond", port_path);
+	    return devices;
+	},
+    };
+    let (_, configuration_descriptor) = protocol::parse_configuration_descriptor(&configuration_descriptor_slice).unwrap();
+
+    let device_address = hci.get_free_address();
+
+    let set_addr = UsbTransfer {
+	transfer_type: TransferType::ControlNoData(SetupPacket {
+	    request_type: write_request_type,
+	    request: RequestCode::SetAddress as u8,
+	    value: device_address.into(),
+	    index: 0,
+	    length: 0,
+	}),
+	endpoint: 0,
+	speed,
+	poll: true,
+	callback: None,
+    };
+    hci.transfer(0, set_addr);
+
+    let xfer_descriptors = UsbTransfer {
+	transfer_type: TransferType::ControlRead(SetupPacket {
+	    request_type: read_request_type,
+	    request: RequestCode::GetDescriptor as u8,
+	    value: 0x0200,
+	    index: 0,
+	    length: configuration_descriptor.total_length,
+	}),
+	endpoint: 0,
+	speed,
+	poll: true,
+	callback: None,
+    };
+    let descriptors = hci.transfer(device_address, xfer_descriptors).unwrap();
+
+    // Effectively treat each interface as its own device, which it more or less is
+    let (_, (configuration_descriptor, interface_descriptors)) = protocol::parse_configuration_descriptors(&descriptors).unwrap();
+
+    let set_configuration = UsbTransfer {
+	transfer_type: TransferType::ControlNoData(SetupPacket {
+	    request_type: write_request_type,
+	    request: RequestCode::SetConfiguration as u8,
+	    value: configuration_descriptor.configuration_value as u16,
+	    index: 0,
+	    length: 0,
+	}),
+	endpoint: 0,
+	speed,
+	poll: true,
+	callback: None,
+    };
+    hci.transfer(device_address, set_configuration);
+
+    let sysfs_path = format!("/bus/usb/devices/{}-{}", hci_number, device_address);
+    fs::sysfs::add_static_attribute(&format!("{}/address", sysfs_path), format!("{}\n", device_address));
+    // Devices behind hubs are named by the path of ports leading to them, e.g. 1.3
+    fs::sysfs::add_static_attribute(&format!("{}/port", sysfs_path), format!("{}\n", port_path));
+    fs::sysfs::add_static_attribute(&format!("{}/speed", sysfs_path), String::from(match speed {
+	PortSpeed::LowSpeed => "1.5\n",
+	PortSpeed::FullSpeed => "12\n",
+    }));
+
+    for interface_descriptor in interface_descriptors {
+	let interface_path = format!("{}/{}:{}", sysfs_path, device_address, interface_descriptor.interface_number);
+	fs::sysfs::add_static_attribute(&format!("{}/class", interface_path), format!("{:02x}\n", interface_descriptor.class));
+	fs::sysfs::add_static_attribute(&format!("{}/subclass", interface_path), format!("{:02x}\n", interface_descriptor.subclass));
+	fs::sysfs::add_static_attribute(&format!("{}/protocol", interface_path), format!("{:02x}\n", interface_descriptor.protocol));
+
+	devices.push(UsbDevice {
+	    configuration_descriptor: configuration_descriptor.clone(),
+	    interface_descriptor,
+	    address: device_address,
+	    hci: locked_hci.clone(),
+	    hci_number,
+	    port_path: String::from(port_path),
+	    speed,
+	});
+    }
+
+    devices
+}
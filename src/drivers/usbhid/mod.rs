@@ -10,6 +10,8 @@ use crate::drivers::usb::usbdevice;
 
 mod protocol;
 
+const BOOT_REPORT_LENGTH: usize = 8;
+
 #[derive(PartialEq, Eq)]
 enum HidProtocol {
     Boot,
@@ -34,11 +36,11 @@ impl Keyboard {
 	    unimplemented!()
 	}
 
-	let (endpoint_num, endpoint) = device_info.interface_descriptor.endpoints.clone().into_iter()
-	    .filter(|(_, endpoint)|
-		    endpoint.direction == usb_protocol::EndpointDirection::In &&
-		    endpoint.transfer_type == usb_protocol::EndpointTransferType::Interrupt)
-	    .nth(0)
+	let endpoint = device_info.interface_descriptor.endpoints.values()
+	    .find(|endpoint|
+		  endpoint.direction == usb_protocol::EndpointDirection::In &&
+		  endpoint.transfer_type == usb_protocol::EndpointTransferType::Interrupt)
+	    .cloned()
 	    .unwrap();
 
 	let set_protocol = usbdevice::UsbTransfer {
@@ -95,7 +97,7 @@ impl Keyboard {
 	    protocol,
 	    hid_descriptor,
 	    poll_interval: endpoint.interval,
-	    endpoint_num,
+	    endpoint_num: endpoint.endpoint_number,
 	    current_active_key: RwLock::new(None),
 	}
     }
@@ -104,7 +106,8 @@ impl Keyboard {
 	let xfer_config_descriptor = usbdevice::UsbTransfer {
 	    transfer_type: usbdevice::TransferType::InterruptIn(usbdevice::InterruptTransferDescriptor {
 		frequency_in_ms: self.poll_interval,
-		length: 8,
+		length: BOOT_REPORT_LENGTH as u8,
+		coalesce: true,
 	    }),
 	    endpoint: self.endpoint_num,
 	    speed: self.device_info.speed,
@@ -117,6 +120,7 @@ impl Keyboard {
     }
 
     pub fn keypresses(&self, kp: protocol::BootKeyPresses) {
+	let alt = kp.lalt || kp.ralt;
 	let most_recent_key: Option<protocol::Key> = kp.keys.into_iter()
 	    .filter(|key| *key != protocol::Key::Unknown)
 	    .collect::<Vec<_>>()
@@ -125,7 +129,12 @@ impl Keyboard {
 
 	let mut current_active_key = self.current_active_key.write();
 	if most_recent_key != *current_active_key {
-	    if let Some(protocol::Key::AsciiKey(mrk)) = most_recent_key { console::register_keypress(mrk) }
+	    match most_recent_key {
+		Some(protocol::Key::AsciiKey(mrk)) => console::register_keypress(mrk),
+		// Alt+Fn switches to virtual terminal n
+		Some(protocol::Key::Function(n)) if alt => console::switch_vt(n as usize - 1),
+		_ => (),
+	    }
 	    *current_active_key = most_recent_key;
 	}
     }
@@ -141,7 +150,7 @@ pub fn init() {
 
 pub struct HidDriver {}
 impl driver::Driver for HidDriver {
-    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) {
+    fn init(&self, info: &dyn driver::DeviceTypeIdentifier) -> driver::ProbeResult {
 	log::info!("Initialising HID device");
 
 	if let Some(usb_info) = info.as_any().downcast_ref::<usbdevice::UsbDevice>() {
@@ -162,14 +171,19 @@ impl driver::Driver for HidDriver {
 		));
 		let dc = device.clone();
 		device.clone().start_with_callback(Arc::new(move |buf| {
-		    let (_, keypresses) = protocol::parse_boot_buffer(buf.as_ref()).unwrap();
-		    dc.keypresses(keypresses);
+		    // Reports which arrived together are coalesced into one buffer, oldest first
+		    for report in buf.chunks_exact(BOOT_REPORT_LENGTH) {
+			let (_, keypresses) = protocol::parse_boot_buffer(report).unwrap();
+			dc.keypresses(keypresses);
+		    }
 		}));
 	    } else if usb_info.interface_descriptor.protocol == 2 {
 		log::info!("  Mouse");
 	    }
 
 	}
+
+	driver::ProbeResult::Bound
     }
 
     fn check_device(&self, info: &dyn driver::DeviceTypeIdentifier) -> bool {
@@ -31,6 +31,7 @@ pub struct HidDescriptor {
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum Key {
     AsciiKey(char),
+    Function(u8),
     #[default]
     Unknown,
 }
@@ -129,6 +130,8 @@ fn parse_key(input: &[u8]) -> IResult<&[u8], Key> {
         0x37 => Key::AsciiKey('.'),
         0x38 => Key::AsciiKey('/'),
 
+        0x3A ..= 0x45 => Key::Function(keypress - 0x3A + 1),  // F1 - F12
+
         _ => Key::Unknown,
     };
 
@@ -136,21 +139,20 @@ fn parse_key(input: &[u8]) -> IResult<&[u8], Key> {
 }
 
 pub fn parse_boot_buffer(input: &[u8]) -> IResult<&[u8], BootKeyPresses> {
-    // TODO - handle modifiers
-    let (input, _) = u8(input)?;
-    let (input, _) = u8(input)?;
+    let (input, modifiers) = u8(input)?;
+    let (input, _) = u8(input)?;  // Reserved
 
     let (input, keypresses) = count(parse_key, 6).parse(input)?;
 
     Ok((input, BootKeyPresses {
-	lctl: false,
-	lshift: false,
-	lalt: false,
-	lsuper: false,
-	rctl: false,
-	rshift: false,
-	ralt: false,
-	rgui: false,
+	lctl: modifiers & 0x01 != 0,
+	lshift: modifiers & 0x02 != 0,
+	lalt: modifiers & 0x04 != 0,
+	lsuper: modifiers & 0x08 != 0,
+	rctl: modifiers & 0x10 != 0,
+	rshift: modifiers & 0x20 != 0,
+	ralt: modifiers & 0x40 != 0,
+	rgui: modifiers & 0x80 != 0,
 
 	keys: keypresses,
     }))
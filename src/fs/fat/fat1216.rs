@@ -10,14 +10,19 @@ use core::sync::atomic::AtomicU64;
 use futures_util::FutureExt;
 use alloc::borrow::ToOwned;
 use core::sync::atomic::Ordering;
+use x86_64::{PhysAddr, VirtAddr};
 
+use crate::drivers::rtc;
+use crate::memory;
 use crate::sys::block;
 use crate::sys::syscall;
 use crate::vfs;
-use crate::sys::ioctl;
 use crate::fs::fat::BootRecord;
 use crate::syscall::CanonicalError;
 
+// Largest run of sectors read_to_user asks the device for at once; a single PRD's worth for IDE DMA
+const MAX_SECTORS_PER_READ: usize = 128;
+
 #[repr(C, packed(1))]
 #[derive(Default, Debug)]
 struct DirectoryEntry {
@@ -59,9 +64,27 @@ pub struct ExtendedBootRecord1216 {
     boot_signature: u16,
 }
 
+// FAT keeps local time, which we take to be UTC, as the RTC is
+fn fat_time_to_unix(date: u16, time: u16) -> Option<u64> {
+    let year = 1980 + (date >> 9) as u64;
+    let month = ((date >> 5) & 0x0F) as u64;
+    let day = (date & 0x1F) as u64;
+    let hours = (time >> 11) as u64;
+    let minutes = ((time >> 5) & 0x3F) as u64;
+    let seconds = (time & 0x1F) as u64 * 2;
+
+    if !(1..=12).contains(&month) || day == 0 || hours > 23 || minutes > 59 || seconds > 59 {
+	return None;
+    }
+
+    Some(rtc::days_since_epoch(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
 struct INode {
     file_name: String,
     file_size: u32,
+    attributes: u8,
+    modified: Option<u64>,
     start_cluster: u32,
     kind: vfs::filesystem::VNodeKind,
     fs: Arc<Fat16Fs>,
@@ -79,9 +102,19 @@ impl vfs::filesystem::VNode for INode {
     }
 
     fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	// FAT has no permissions, only a read only flag
+	let mode = match self.kind {
+	    vfs::filesystem::VNodeKind::Directory => 0o755,
+	    _ => 0o644,
+	};
+
 	Ok(vfs::filesystem::Stat {
 	    file_name: self.file_name.clone(),
 	    size: Some(self.file_size as u64),
+	    inode: self.start_cluster as u64,
+	    kind: self.kind,
+	    mode: if self.attributes & 0x01 != 0 { mode & !0o222 } else { mode },
+	    modified: self.modified,
 	})
     }
 
@@ -152,6 +185,11 @@ impl vfs::filesystem::VNode for INode {
 	}
     }
 
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	// FAT has no concept of symlinks
+	Err(CanonicalError::Inval)
+    }
+
     fn set_fsi(self: Arc<Self>, _fsi: vfs::filesystem::FileSystemInstance) {
 	unimplemented!();
     }
@@ -177,6 +215,10 @@ impl vfs::filesystem::VNode for RootINode {
 	Ok(vfs::filesystem::Stat {
 	    file_name: String::from("/"),
 	    size: Some(self.root_directory_size * 512),
+	    inode: 0xFFFF_FFFF_FFFF_FFFF,
+	    kind: vfs::filesystem::VNodeKind::Directory,
+	    mode: 0o755,
+	    modified: None,
 	})
     }
 
@@ -203,6 +245,11 @@ impl vfs::filesystem::VNode for RootINode {
 	Err(CanonicalError::NoEnt)
     }
 
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	// FAT has no concept of symlinks
+	Err(CanonicalError::Inval)
+    }
+
     fn set_fsi(self: Arc<Self>, _fsi: vfs::filesystem::FileSystemInstance) {
 	unimplemented!();
     }
@@ -211,7 +258,7 @@ impl vfs::filesystem::VNode for RootINode {
 struct FatFileHandle {
     inode: Arc<dyn vfs::filesystem::VNode>,
     block_list: Vec<u64>,
-    dev: Arc<block::GptDevice>,
+    dev: Arc<dyn block::PartitionedDevice + Send + Sync>,
     partition: u32,
     current_offset: AtomicU64,
     block_size: u64,
@@ -221,7 +268,7 @@ impl FatFileHandle {
     pub fn new(
         vnode: Arc<dyn vfs::filesystem::VNode>,
         block_list: Vec<u64>,
-        dev: Arc<block::GptDevice>,
+        dev: Arc<dyn block::PartitionedDevice + Send + Sync>,
         partition: u32,
 	block_size: u64,
     ) -> Self {
@@ -236,6 +283,61 @@ impl FatFileHandle {
     }
 }
 
+// Reads up to to_read bytes of blocks to the user, fetching runs of consecutive blocks with a single device read.
+// Whole blocks go straight into frames, the pinned user buffer and where it starts in the first of them, if the
+// device can DMA there. Anything else, such as the part of the last block that's wanted, is read into a buffer and
+// handed to copy along with where it goes in the user buffer
+async fn read_blocks_to_user<F>(
+    dev: &(dyn block::PartitionedDevice + Send + Sync),
+    partition: u32,
+    blocks: &[u64],
+    block_size: u64,
+    to_read: u64,
+    frames: Option<(&[PhysAddr], u64)>,
+    mut copy: F,
+) -> Result<u64, CanonicalError> where F: FnMut(u64, &[u8]) -> Result<(), CanonicalError> {
+    let mut copied: u64 = 0;
+    let mut idx = 0;
+    while idx < blocks.len() && copied < to_read {
+	let mut run_len = 1;
+	while idx + run_len < blocks.len() && run_len < MAX_SECTORS_PER_READ &&
+	    blocks[idx + run_len] == blocks[idx] + run_len as u64 {
+	    run_len += 1;
+	}
+
+	let whole_blocks = core::cmp::min(run_len as u64, (to_read - copied) / block_size);
+	if let Some((frames, offset)) = frames {
+	    let position = offset + copied;
+	    let direct = match whole_blocks {
+		0 => None,
+		_ => dev.read_to_frames(
+		    partition, blocks[idx], whole_blocks, frames[(position / 4096) as usize ..].to_vec(), position % 4096),
+	    };
+
+	    if let Some(read) = direct {
+		read.await.map_err(|_| CanonicalError::Io)?;
+
+		copied += whole_blocks * block_size;
+		idx += whole_blocks as usize;
+		continue;
+	    }
+	}
+
+	let data = dev
+	    .read(partition, blocks[idx], run_len as u64)
+	    .await
+	    .map_err(|_| CanonicalError::Io)?;
+
+	let len = core::cmp::min(data.len() as u64, to_read - copied);
+	copy(copied, &data[.. len as usize])?;
+
+	copied += len;
+	idx += run_len;
+    }
+
+    Ok(copied)
+}
+
 impl vfs::filesystem::FileHandle for FatFileHandle {
     fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
 	let this = self.clone();
@@ -289,6 +391,37 @@ impl vfs::filesystem::FileHandle for FatFileHandle {
         .boxed()
     }
 
+    fn read_to_user(self: Arc<Self>, buf: VirtAddr, len: u64) -> Option<BoxFuture<'static, Result<u64, CanonicalError>>> {
+	// Only reads starting on a block boundary, so that each block goes to the user whole, bar the last one
+	if self.current_offset.load(Ordering::SeqCst) % self.block_size != 0 {
+	    return None;
+	}
+
+	Some(async move {
+	    let size = self.inode.stat()?.size.unwrap();
+	    let start = self.current_offset.load(Ordering::SeqCst);
+	    if start >= size {
+		return Ok(0);
+	    }
+	    let to_read = core::cmp::min(len, size - start);
+
+	    let first_block = (start / self.block_size) as usize;
+	    let last_block = core::cmp::min(first_block + to_read.div_ceil(self.block_size) as usize, self.block_list.len());
+	    let blocks = self.block_list.get(first_block .. last_block).unwrap_or(&[]);
+
+	    // Held until every read into the frames is done. Kernel tasks have nothing to pin, and just get copies
+	    let pinned = memory::pin_user_buffer(buf, to_read).ok();
+	    let frames = pinned.as_ref().map(|pinned| (pinned.frames.as_slice(), pinned.offset));
+
+	    let copied = read_blocks_to_user(
+		&*self.dev, self.partition, blocks, self.block_size, to_read, frames,
+		|position, data| memory::copy_to_user(buf + position, data).map_err(|_| CanonicalError::Fault)).await?;
+
+	    self.current_offset.fetch_add(copied, Ordering::SeqCst);
+	    Ok(copied)
+	}.boxed())
+    }
+
     fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {	
 	async move {
             Err(CanonicalError::Badf)
@@ -307,9 +440,9 @@ impl vfs::filesystem::FileHandle for FatFileHandle {
 	self.inode.stat()
     }
 
-    fn ioctl(self: Arc<Self>, _ioctl: ioctl::IoCtl, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
 	async move {
-	    Err(CanonicalError::Inval)
+	    Err(CanonicalError::NoTty)
 	}.boxed()
     }
 
@@ -336,6 +469,29 @@ impl vfs::filesystem::FileHandle for FatFileHandle {
             }
 	}
     }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    // FAT support is read only for now
+	    Err(CanonicalError::RoFs)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	if self.inode.kind() != vfs::filesystem::VNodeKind::Directory {
+	    return async move {
+		Err(CanonicalError::NotDir)
+	    }.boxed();
+	}
+
+	// For directories, the offset is the directory entry slot to resume from
+	let cookie = self.current_offset.load(Ordering::SeqCst);
+	self.inode.filesystem().readdir(self.inode.fsi(), &self.inode, cookie)
+    }
+
+    fn fsync(self: Arc<Self>) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	self.inode.filesystem().sync(self.inode.fsi())
+    }
 }
 
 #[allow(dead_code)]
@@ -345,12 +501,12 @@ pub struct Fat16Fs {
 
     fat: RwLock<Vec<u16>>,
 
-    dev: Arc<block::GptDevice>,
+    dev: Arc<dyn block::PartitionedDevice + Send + Sync>,
     partition: u32,
 }
 
 impl Fat16Fs {
-    pub async fn new(dev: Arc<block::GptDevice>, partition: u32, boot_record: BootRecord, extended_boot_record: ExtendedBootRecord1216) -> Option<Fat16Fs> {
+    pub async fn new(dev: Arc<dyn block::PartitionedDevice + Send + Sync>, partition: u32, boot_record: BootRecord, extended_boot_record: ExtendedBootRecord1216) -> Option<Fat16Fs> {
 	// Check signature, double check this is actually FAT
 	if extended_boot_record.signature != 0x28 && extended_boot_record.signature != 0x29 {
 	    return None;
@@ -573,6 +729,8 @@ impl vfs::filesystem::FileSystem for Fat16Fs {
 			let node: Arc<dyn vfs::filesystem::VNode> = Arc::new(INode {
 			    file_name: name,
 			    file_size: directory_entry.file_size,
+			    attributes: directory_entry.attributes,
+			    modified: fat_time_to_unix(directory_entry.modification_date, directory_entry.modification_time),
 			    start_cluster: directory_entry.cluster_low as u32,
 			    kind: vfs::filesystem::VNodeKind::Directory,
 			    fs: this.clone(),
@@ -584,6 +742,8 @@ impl vfs::filesystem::FileSystem for Fat16Fs {
 			let node: Arc<dyn vfs::filesystem::VNode> = Arc::new(INode {
 			    file_name: name,
 			    file_size: directory_entry.file_size,
+			    attributes: directory_entry.attributes,
+			    modified: fat_time_to_unix(directory_entry.modification_date, directory_entry.modification_time),
 			    start_cluster: directory_entry.cluster_low as u32,
 			    kind: vfs::filesystem::VNodeKind::Regular,
 			    fs: this.clone(),
@@ -607,4 +767,117 @@ impl vfs::filesystem::FileSystem for Fat16Fs {
 	    Err(CanonicalError::NoEnt)
 	}.boxed()
     }
+
+    fn readdir(self: Arc<Self>, _fsi: vfs::filesystem::FileSystemInstance, dir: &Arc<dyn vfs::filesystem::VNode>, cookie: u64) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	let this = self.clone();
+	let dir = dir.clone();
+
+	async move {
+	    let directory_file_handle = dir.clone().open()?;
+	    let size = directory_file_handle.clone().stat()?.size.unwrap();
+	    let directory_contents = directory_file_handle.clone().read(size).await?;
+
+	    // The cookie is the index of the directory entry slot to carry on from. FAT never moves an existing
+	    // entry, as deletion only marks the slot, so these remain stable between calls
+	    let mut entries: Vec<vfs::filesystem::DirEntry> = Vec::new();
+	    let mut entry = cookie as usize;
+	    loop {
+		let (maybe_fn, offset) = this.get_filename(directory_contents.clone(), entry);
+		entry += offset;
+
+		let directory_entry = match Fat16Fs::read_dirent(&directory_contents, entry) {
+		    Ok(d) => d,
+		    Err(_) => break,
+		};
+
+		if let Some(file_name) = maybe_fn {
+		    let kind = if directory_entry.attributes & 0x10 != 0 {
+			vfs::filesystem::VNodeKind::Directory
+		    } else {
+			vfs::filesystem::VNodeKind::Regular
+		    };
+
+		    entries.push(vfs::filesystem::DirEntry {
+			inode: directory_entry.cluster_low as u64,
+			kind,
+			name: file_name,
+			cookie: entry as u64 + 1,
+		    });
+		} else if directory_entry.file_name[0].to_u8() == 0x00 {
+		    break;
+		}
+
+		entry += 1;
+	    }
+
+	    Ok(entries)
+	}.boxed()
+    }
+
+    // Nothing is ever dirty in memory, as FAT support is read only and the block cache writes straight through, so this
+    // only has the disk flush its own cache
+    fn sync(self: Arc<Self>, _fsi: vfs::filesystem::FileSystemInstance) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    self.dev.flush().await.map_err(|_| CanonicalError::Io)
+	}.boxed()
+    }
+}
+
+#[test]
+fn whole_blocks_are_read_straight_into_the_frames() {
+    struct Disk {
+	reads: spin::Mutex<Vec<(u64, u64)>>,
+	frame_reads: spin::Mutex<Vec<(u64, u64, Vec<PhysAddr>, u64)>>,
+    }
+
+    impl block::PartitionedDevice for Disk {
+	fn read(&self, _partition: u32, starting_block: u64, size: u64) -> BoxFuture<'_, Result<Bytes, ()>> {
+	    self.reads.lock().push((starting_block, size));
+	    async move {
+		Ok(Bytes::from(alloc::vec![0xAA; (size * 512) as usize]))
+	    }.boxed()
+	}
+
+	fn read_to_frames(&self, _partition: u32, starting_block: u64, size: u64, frames: Vec<PhysAddr>, frame_offset: u64) -> Option<BoxFuture<'_, Result<(), ()>>> {
+	    self.frame_reads.lock().push((starting_block, size, frames, frame_offset));
+	    Some(async move { Ok(()) }.boxed())
+	}
+
+	fn flush(&self) -> BoxFuture<'_, Result<(), ()>> {
+	    async move { Ok(()) }.boxed()
+	}
+    }
+
+    let disk = Disk { reads: spin::Mutex::new(Vec::new()), frame_reads: spin::Mutex::new(Vec::new()) };
+    let frames = [PhysAddr::new(0x1000), PhysAddr::new(0x5000)];
+    let mut copies = Vec::new();
+
+    // Three whole blocks and 100 bytes of a fourth, into a buffer starting 0xF00 into the first frame
+    let copied = read_blocks_to_user(
+	&disk, 0, &[10, 11, 12, 13], 512, 3 * 512 + 100, Some((&frames, 0xF00)),
+	|position, data| {
+	    copies.push((position, data.len()));
+	    Ok(())
+	}).now_or_never().unwrap().unwrap();
+
+    assert_eq!(copied, 3 * 512 + 100);
+    assert_eq!(*disk.frame_reads.lock(), [(10, 3, frames.to_vec(), 0xF00)]);
+    assert_eq!(*disk.reads.lock(), [(13, 1)]);
+    assert_eq!(copies, [(3 * 512, 100)]);
+
+    // Block aligned, so nothing needs copying
+    disk.frame_reads.lock().clear();
+    disk.reads.lock().clear();
+    copies.clear();
+    let copied = read_blocks_to_user(
+	&disk, 0, &[10, 11, 20, 21], 512, 4 * 512, Some((&frames, 0xF00)),
+	|position, data| {
+	    copies.push((position, data.len()));
+	    Ok(())
+	}).now_or_never().unwrap().unwrap();
+
+    assert_eq!(copied, 4 * 512);
+    assert_eq!(*disk.frame_reads.lock(), [(10, 2, frames.to_vec(), 0xF00), (20, 2, frames[1 ..].to_vec(), 0x300)]);
+    assert!(disk.reads.lock().is_empty());
+    assert!(copies.is_empty());
 }
@@ -94,8 +94,11 @@ fn detect_fat_fs(boot_record: BootRecord) -> FatFsType {
     }
 }
 
-pub async fn register_fat_fs(dev: Arc<block::GptDevice>, partition: u32) {    
-    let boot_record_buf_ptr = dev.read(partition, 0, 1).await.expect("Failed to read (possible) FAT boot record").as_ptr();
+// Returns the type of filesystem found, and the filesystem, if it was one we can use. Mounting it is up to the caller.
+pub async fn probe_fat_fs(dev: Arc<dyn block::PartitionedDevice + Send + Sync>, partition: u32) -> Option<(&'static str, Arc<dyn vfs::filesystem::FileSystem>)> {
+    // Kept for as long as the boot record is being read out of it
+    let boot_record_buf = dev.read(partition, 0, 1).await.expect("Failed to read (possible) FAT boot record");
+    let boot_record_buf_ptr = boot_record_buf.as_ptr();
     let boot_record = unsafe {
 	ptr::read(boot_record_buf_ptr as *const BootRecord)
     };
@@ -106,13 +109,12 @@ pub async fn register_fat_fs(dev: Arc<block::GptDevice>, partition: u32) {
 		ptr::read(boot_record_buf_ptr.wrapping_add(0x24) as *const fat1216::ExtendedBootRecord1216)
 	    };
 
-	    if let Some(fs) = fat1216::Fat16Fs::new(dev, partition, boot_record, extended_boot_record).await {
-		// For now, assume this is root. At some point, root detection should be done properly
-		vfs::mount_root(Arc::new(fs)).unwrap();
-	    }
+	    let fs = fat1216::Fat16Fs::new(dev, partition, boot_record, extended_boot_record).await?;
+	    Some(("vfat", Arc::new(fs)))
 	},
 	t => {
 	    log::info!("{:?}", t);
+	    None
 	},
     }
 }
@@ -1 +1,3 @@
 pub mod fat;
+pub mod sysfs;
+pub mod tmpfs;
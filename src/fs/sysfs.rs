@@ -0,0 +1,437 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::{Mutex, Once, RwLock};
+
+use crate::sys::syscall::{CanonicalError, PollEvents, SyscallResult};
+use crate::vfs;
+use crate::vfs::filesystem::{DirEntry, FileHandle, FileSystem, FileSystemInstance, SeekFrom, Stat, VNode, VNodeKind};
+
+// A read only tree of small generated files describing the hardware, mounted at /sys. Drivers add attributes as they
+// find devices, and the contents are generated afresh each time an attribute is opened.
+
+type Generator = Box<dyn Fn() -> String + Send + Sync>;
+
+enum SysFsContent {
+    Directory(RwLock<BTreeMap<String, Arc<SysFsNode>>>),
+    Attribute(Generator),
+}
+
+struct SysFsNode {
+    // Assigned in order of creation and never reused, so doubles as a stable readdir cookie
+    inode: u64,
+    name: String,
+    parent: Weak<SysFsNode>,
+    content: SysFsContent,
+}
+
+impl SysFsNode {
+    fn kind(&self) -> VNodeKind {
+	match self.content {
+	    SysFsContent::Directory(_) => VNodeKind::Directory,
+	    SysFsContent::Attribute(_) => VNodeKind::Regular,
+	}
+    }
+
+    fn children(&self) -> Result<&RwLock<BTreeMap<String, Arc<SysFsNode>>>, CanonicalError> {
+	match self.content {
+	    SysFsContent::Directory(ref children) => Ok(children),
+	    SysFsContent::Attribute(_) => Err(CanonicalError::NotDir),
+	}
+    }
+}
+
+pub struct SysFs {
+    root: Arc<SysFsNode>,
+    nodes: RwLock<BTreeMap<u64, Arc<SysFsNode>>>,
+    next_inode: AtomicU64,
+}
+
+impl SysFs {
+    fn new() -> Self {
+	let root = Arc::new(SysFsNode {
+	    inode: 0,
+	    name: String::new(),
+	    parent: Weak::new(),
+	    content: SysFsContent::Directory(RwLock::new(BTreeMap::new())),
+	});
+
+	let mut nodes = BTreeMap::new();
+	nodes.insert(0, root.clone());
+
+	SysFs {
+	    root,
+	    nodes: RwLock::new(nodes),
+	    next_inode: AtomicU64::new(1),
+	}
+    }
+
+    fn add_node(&self, parent: &Arc<SysFsNode>, name: &str, content: SysFsContent) -> Arc<SysFsNode> {
+	let children = match parent.content {
+	    SysFsContent::Directory(ref children) => children,
+	    SysFsContent::Attribute(_) => panic!("Attempted to add sysfs node {} beneath an attribute", name),
+	};
+
+	let node = Arc::new(SysFsNode {
+	    inode: self.next_inode.fetch_add(1, Ordering::SeqCst),
+	    name: String::from(name),
+	    parent: Arc::downgrade(parent),
+	    content,
+	});
+
+	children.write().insert(String::from(name), node.clone());
+	self.nodes.write().insert(node.inode, node.clone());
+	node
+    }
+
+    // Creates any missing directories along path, then the attribute itself, replacing any existing one
+    fn add_attribute(&self, path: &str, generator: Generator) {
+	let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+	let name = components.pop().expect("Attempted to add sysfs attribute with an empty path");
+
+	let mut dir = self.root.clone();
+	for component in components {
+	    let existing = match dir.content {
+		SysFsContent::Directory(ref children) => children.read().get(component).cloned(),
+		SysFsContent::Attribute(_) => panic!("Sysfs path {} passes through an attribute", path),
+	    };
+
+	    dir = match existing {
+		Some(d) => d,
+		None => self.add_node(&dir, component, SysFsContent::Directory(RwLock::new(BTreeMap::new()))),
+	    };
+	}
+
+	self.add_node(&dir, name, SysFsContent::Attribute(generator));
+    }
+
+    fn get_node(&self, inode: u64) -> Result<Arc<SysFsNode>, CanonicalError> {
+	self.nodes.read().get(&inode).cloned().ok_or(CanonicalError::NoEnt)
+    }
+
+    fn vnode(self: Arc<Self>, node: Arc<SysFsNode>, fsi: FileSystemInstance) -> Arc<dyn VNode> {
+	Arc::new(SysFsVNode {
+	    fs: self,
+	    node,
+	    fsi: Mutex::new(fsi),
+	})
+    }
+}
+
+impl FileSystem for SysFs {
+    fn root(self: Arc<Self>, fsi: FileSystemInstance) -> Arc<dyn VNode> {
+	let root = self.root.clone();
+	self.vnode(root, fsi)
+    }
+
+    fn lookup(self: Arc<Self>, fsi: FileSystemInstance, parent: &Arc<dyn VNode>, name: &str) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	let child = self.get_node(parent.inode()).and_then(|p| {
+	    let child = p.children()?.read().get(name).cloned();
+	    child.ok_or(CanonicalError::NoEnt)
+	});
+
+	async move {
+	    Ok(self.vnode(child?, fsi))
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>, _fsi: FileSystemInstance, dir: &Arc<dyn VNode>, cookie: u64) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	let entries = self.get_node(dir.inode()).and_then(|d| {
+	    let mut entries = d.children()?.read().values()
+		.filter(|child| child.inode > cookie)
+		.map(|child| DirEntry {
+		    inode: child.inode,
+		    kind: child.kind(),
+		    name: child.name.clone(),
+		    cookie: child.inode,
+		})
+		.collect::<Vec<DirEntry>>();
+	    entries.sort_by_key(|e| e.cookie);
+	    Ok(entries)
+	});
+
+	async move {
+	    entries
+	}.boxed()
+    }
+}
+
+struct SysFsVNode {
+    fs: Arc<SysFs>,
+    node: Arc<SysFsNode>,
+    fsi: Mutex<FileSystemInstance>,
+}
+
+impl VNode for SysFsVNode {
+    fn inode(&self) -> u64 {
+	self.node.inode
+    }
+
+    fn kind(&self) -> VNodeKind {
+	self.node.kind()
+    }
+
+    fn stat(&self) -> Result<Stat, CanonicalError> {
+	let size = match self.node.content {
+	    SysFsContent::Directory(_) => None,
+	    SysFsContent::Attribute(ref generator) => Some(generator().len() as u64),
+	};
+
+	Ok(Stat {
+	    file_name: self.node.name.clone(),
+	    size,
+	    inode: self.inode(),
+	    kind: self.kind(),
+	    mode: if self.kind() == VNodeKind::Directory { 0o555 } else { 0o444 },
+	    modified: None,
+	})
+    }
+
+    fn open(self: Arc<Self>) -> Result<Arc<dyn FileHandle>, CanonicalError> {
+	match self.node.content {
+	    SysFsContent::Directory(_) => Ok(Arc::new(SysFsDirHandle {
+		vnode: self.clone(),
+		cookie: AtomicU64::new(0),
+	    })),
+	    SysFsContent::Attribute(ref generator) => Ok(Arc::new(SysFsAttributeHandle {
+		vnode: self.clone(),
+		contents: bytes::Bytes::from(generator()),
+		position: AtomicU64::new(0),
+	    })),
+	}
+    }
+
+    fn filesystem(&self) -> Arc<dyn FileSystem> {
+	self.fs.clone()
+    }
+
+    fn fsi(&self) -> FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn VNode>, CanonicalError> {
+	// The root's parent is found through the mount table
+	match self.node.parent.upgrade() {
+	    Some(parent) => Ok(self.fs.clone().vnode(parent, self.fsi())),
+	    None => Err(CanonicalError::NoEnt),
+	}
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+struct SysFsAttributeHandle {
+    vnode: Arc<SysFsVNode>,
+    // Generated when opened, so that reads in several chunks see a consistent value
+    contents: bytes::Bytes,
+    position: AtomicU64,
+}
+
+impl FileHandle for SysFsAttributeHandle {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    let start = core::cmp::min(self.position.load(Ordering::SeqCst) as usize, self.contents.len());
+	    let end = core::cmp::min(start + len as usize, self.contents.len());
+
+	    self.position.store(end as u64, Ordering::SeqCst);
+	    Ok(self.contents.slice(start .. end))
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::RoFs)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & PollEvents::In)
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	Ok(Stat {
+	    file_name: self.vnode.node.name.clone(),
+	    size: Some(self.contents.len() as u64),
+	    inode: self.vnode.inode(),
+	    kind: VNodeKind::Regular,
+	    mode: 0o444,
+	    modified: None,
+	})
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError> {
+	let new_offset = match offset {
+	    SeekFrom::Set(n) => n,
+	    SeekFrom::Cur(n) => self.position.load(Ordering::SeqCst) as i64 + n,
+	    SeekFrom::End(n) => self.contents.len() as i64 + n,
+	};
+
+	if new_offset < 0 {
+	    return Err(CanonicalError::Inval);
+	}
+
+	self.position.store(new_offset as u64, Ordering::SeqCst);
+	Ok(new_offset as u64)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::RoFs)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+struct SysFsDirHandle {
+    vnode: Arc<SysFsVNode>,
+    cookie: AtomicU64,
+}
+
+impl FileHandle for SysFsDirHandle {
+    fn read(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	self.vnode.stat()
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError> {
+	match offset {
+	    SeekFrom::Set(n) if n >= 0 => {
+		self.cookie.store(n as u64, Ordering::SeqCst);
+		Ok(n as u64)
+	    },
+	    _ => Err(CanonicalError::Inval),
+	}
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	let dir: Arc<dyn VNode> = self.vnode.clone();
+	self.vnode.fs.clone().readdir(self.vnode.fsi(), &dir, self.cookie.load(Ordering::SeqCst))
+    }
+}
+
+static SYSFS: Once<Arc<SysFs>> = Once::new();
+// Every uevent since boot, in order, each already formatted as a blank line terminated block of KEY=value lines
+static UEVENTS: Once<RwLock<Vec<String>>> = Once::new();
+static NEXT_UEVENT_SEQNUM: AtomicU64 = AtomicU64::new(1);
+
+pub fn init() {
+    SYSFS.call_once(|| Arc::new(SysFs::new()));
+    UEVENTS.call_once(|| RwLock::new(Vec::new()));
+
+    add_attribute("/kernel/uevents", || {
+	UEVENTS.get().expect("Attempted to access uevent log before it is initialised").read().concat()
+    });
+}
+
+pub async fn mount_sysfs() -> SyscallResult {
+    let sysfs = SYSFS.get().expect("Attempted to access sysfs before it is initialised");
+    syscall_try!(vfs::mount("/sys", sysfs.clone()).await);
+    syscall_success!(0)
+}
+
+// Adds an attribute whose contents are generated by calling generator each time it's opened
+pub fn add_attribute(path: &str, generator: impl Fn() -> String + Send + Sync + 'static) {
+    SYSFS.get().expect("Attempted to access sysfs before it is initialised").add_attribute(path, Box::new(generator));
+}
+
+// Adds an attribute whose contents never change
+pub fn add_static_attribute(path: &str, value: String) {
+    add_attribute(path, move || value.clone());
+}
+
+// Records that the device at devpath (a path within sysfs) has been added, removed or changed, so that userspace can
+// find out about devices which appear after it has started
+pub fn emit_uevent(action: &str, devpath: &str, subsystem: &str, vars: &[(&str, String)]) {
+    let mut event = format!("ACTION={}\nDEVPATH={}\nSUBSYSTEM={}\nSEQNUM={}\n",
+			    action, devpath, subsystem, NEXT_UEVENT_SEQNUM.fetch_add(1, Ordering::SeqCst));
+    for (key, value) in vars {
+	event.push_str(&format!("{}={}\n", key, value));
+    }
+    event.push('\n');
+
+    log::info!("uevent: {} {}", action, devpath);
+    UEVENTS.get().expect("Attempted to access uevent log before it is initialised").write().push(event);
+}
+
+#[test]
+fn a_pci_device_is_described_under_bus_pci() {
+    use crate::drivers::pcie;
+
+    init();
+    let info = pcie::PciDeviceType {
+	address: pci_types::PciAddress::new(0, 0, 3, 0),
+	vendor_id: 0x8086,
+	device_id: 0x100e,
+	base_class: 0x02,
+	sub_class: 0x00,
+	interface: 0x00,
+	interrupt_mapping: None,
+    };
+    assert_eq!(pcie::add_sysfs_attributes(&info), "/bus/pci/devices/0000:00:03.0");
+
+    let sysfs = SYSFS.get().unwrap().clone();
+    let fsi = FileSystemInstance(0x1971);
+    let lookup = |path: &str| path.split('/').try_fold(sysfs.clone().root(fsi), |dir, name| {
+	sysfs.clone().lookup(fsi, &dir, name).now_or_never().unwrap()
+    });
+    let read = |path: &str| lookup(path).unwrap().open().unwrap().read(64).now_or_never().unwrap().unwrap();
+
+    assert_eq!(&read("bus/pci/devices/0000:00:03.0/vendor")[..], b"0x8086\n");
+    assert_eq!(&read("bus/pci/devices/0000:00:03.0/device")[..], b"0x100e\n");
+    assert_eq!(&read("bus/pci/devices/0000:00:03.0/class")[..], b"0x020000\n");
+    assert!(matches!(lookup("bus/pci/devices/0000:00:04.0"), Err(CanonicalError::NoEnt)));
+}
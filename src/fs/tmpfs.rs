@@ -0,0 +1,496 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::{Mutex, RwLock};
+
+use crate::sys::syscall::{CanonicalError, PollEvents, SyscallResult};
+use crate::vfs;
+use crate::vfs::filesystem::{DirEntry, FileHandle, FileSystem, FileSystemInstance, SeekFrom, Stat, VNode, VNodeKind};
+
+// A filesystem which lives entirely in memory, mounted at /tmp. Everything in it is lost when the machine stops.
+
+enum TmpFsContent {
+    Directory(RwLock<BTreeMap<String, Arc<TmpFsNode>>>),
+    File(RwLock<Vec<u8>>),
+    // The target, which never changes once the link is made
+    Symlink(String),
+}
+
+struct TmpFsNode {
+    // Assigned in order of creation and never reused, so doubles as a stable readdir cookie
+    inode: u64,
+    // Both change when the node is renamed
+    name: RwLock<String>,
+    parent: RwLock<Weak<TmpFsNode>>,
+    mode: u64,
+    content: TmpFsContent,
+}
+
+impl TmpFsNode {
+    fn kind(&self) -> VNodeKind {
+	match self.content {
+	    TmpFsContent::Directory(_) => VNodeKind::Directory,
+	    TmpFsContent::File(_) => VNodeKind::Regular,
+	    TmpFsContent::Symlink(_) => VNodeKind::Symlink,
+	}
+    }
+
+    fn children(&self) -> Result<&RwLock<BTreeMap<String, Arc<TmpFsNode>>>, CanonicalError> {
+	match self.content {
+	    TmpFsContent::Directory(ref children) => Ok(children),
+	    TmpFsContent::File(_) | TmpFsContent::Symlink(_) => Err(CanonicalError::NotDir),
+	}
+    }
+
+    fn data(&self) -> Result<&RwLock<Vec<u8>>, CanonicalError> {
+	match self.content {
+	    TmpFsContent::Directory(_) => Err(CanonicalError::IsDir),
+	    TmpFsContent::File(ref data) => Ok(data),
+	    TmpFsContent::Symlink(_) => Err(CanonicalError::Inval),
+	}
+    }
+
+    fn is_empty_dir(&self) -> bool {
+	match self.content {
+	    TmpFsContent::Directory(ref children) => children.read().is_empty(),
+	    TmpFsContent::File(_) | TmpFsContent::Symlink(_) => false,
+	}
+    }
+
+    // Whether self is node, or somewhere beneath it
+    fn is_within(self: &Arc<Self>, node: &Arc<TmpFsNode>) -> bool {
+	let mut current = Some(self.clone());
+	while let Some(n) = current {
+	    if Arc::ptr_eq(&n, node) {
+		return true;
+	    }
+	    current = n.parent.read().upgrade();
+	}
+
+	false
+    }
+}
+
+pub struct TmpFs {
+    root: Arc<TmpFsNode>,
+    nodes: RwLock<BTreeMap<u64, Arc<TmpFsNode>>>,
+    next_inode: AtomicU64,
+    // Held across each create, unlink and rename, so that nothing changes between checking a name and using it
+    namespace: Mutex<()>,
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+	let root = Arc::new(TmpFsNode {
+	    inode: 0,
+	    name: RwLock::new(String::new()),
+	    parent: RwLock::new(Weak::new()),
+	    mode: 0o1777,
+	    content: TmpFsContent::Directory(RwLock::new(BTreeMap::new())),
+	});
+
+	let mut nodes = BTreeMap::new();
+	nodes.insert(0, root.clone());
+
+	TmpFs {
+	    root,
+	    nodes: RwLock::new(nodes),
+	    next_inode: AtomicU64::new(1),
+	    namespace: Mutex::new(()),
+	}
+    }
+
+    fn get_node(&self, inode: u64) -> Result<Arc<TmpFsNode>, CanonicalError> {
+	self.nodes.read().get(&inode).cloned().ok_or(CanonicalError::NoEnt)
+    }
+
+    fn vnode(self: Arc<Self>, node: Arc<TmpFsNode>, fsi: FileSystemInstance) -> Arc<dyn VNode> {
+	Arc::new(TmpFsVNode {
+	    fs: self,
+	    node,
+	    fsi: Mutex::new(fsi),
+	})
+    }
+
+    fn do_create(&self, dir: u64, name: &str, content: TmpFsContent, mode: u64) -> Result<Arc<TmpFsNode>, CanonicalError> {
+	let _namespace = self.namespace.lock();
+	let parent = self.get_node(dir)?;
+	let mut children = parent.children()?.write();
+	if children.contains_key(name) {
+	    return Err(CanonicalError::Exist);
+	}
+
+	let node = Arc::new(TmpFsNode {
+	    inode: self.next_inode.fetch_add(1, Ordering::SeqCst),
+	    name: RwLock::new(String::from(name)),
+	    parent: RwLock::new(Arc::downgrade(&parent)),
+	    mode: mode & 0o7777,
+	    content,
+	});
+
+	children.insert(String::from(name), node.clone());
+	self.nodes.write().insert(node.inode, node.clone());
+	Ok(node)
+    }
+
+    fn do_unlink(&self, dir: u64, name: &str) -> Result<(), CanonicalError> {
+	let _namespace = self.namespace.lock();
+	let parent = self.get_node(dir)?;
+	let mut children = parent.children()?.write();
+	let node = children.get(name).cloned().ok_or(CanonicalError::NoEnt)?;
+
+	if node.kind() == VNodeKind::Directory && !node.is_empty_dir() {
+	    return Err(CanonicalError::NotEmpty);
+	}
+
+	// Anything which still has it open keeps the node itself alive
+	children.remove(name);
+	self.nodes.write().remove(&node.inode);
+	Ok(())
+    }
+
+    fn do_rename(&self, old_dir: u64, old_name: &str, new_dir: u64, new_name: &str) -> Result<(), CanonicalError> {
+	let _namespace = self.namespace.lock();
+	let old_parent = self.get_node(old_dir)?;
+	let new_parent = self.get_node(new_dir)?;
+
+	let node = old_parent.children()?.read().get(old_name).cloned().ok_or(CanonicalError::NoEnt)?;
+	let existing = new_parent.children()?.read().get(new_name).cloned();
+
+	if let Some(ref target) = existing {
+	    if Arc::ptr_eq(target, &node) {
+		return Ok(());
+	    }
+
+	    match (node.kind(), target.kind()) {
+		(VNodeKind::Directory, VNodeKind::Directory) if !target.is_empty_dir() => return Err(CanonicalError::NotEmpty),
+		(VNodeKind::Directory, VNodeKind::Directory) => (),
+		(VNodeKind::Directory, _) => return Err(CanonicalError::NotDir),
+		(_, VNodeKind::Directory) => return Err(CanonicalError::IsDir),
+		_ => (),
+	    }
+	}
+
+	// A directory can't be moved beneath itself
+	if node.kind() == VNodeKind::Directory && new_parent.is_within(&node) {
+	    return Err(CanonicalError::Inval);
+	}
+
+	old_parent.children()?.write().remove(old_name);
+	if let Some(target) = existing {
+	    self.nodes.write().remove(&target.inode);
+	}
+
+	*node.name.write() = String::from(new_name);
+	*node.parent.write() = Arc::downgrade(&new_parent);
+	new_parent.children()?.write().insert(String::from(new_name), node);
+	Ok(())
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(self: Arc<Self>, fsi: FileSystemInstance) -> Arc<dyn VNode> {
+	let root = self.root.clone();
+	self.vnode(root, fsi)
+    }
+
+    fn lookup(self: Arc<Self>, fsi: FileSystemInstance, parent: &Arc<dyn VNode>, name: &str) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	let child = self.get_node(parent.inode()).and_then(|p| {
+	    let child = p.children()?.read().get(name).cloned();
+	    child.ok_or(CanonicalError::NoEnt)
+	});
+
+	async move {
+	    Ok(self.vnode(child?, fsi))
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>, _fsi: FileSystemInstance, dir: &Arc<dyn VNode>, cookie: u64) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	let entries = self.get_node(dir.inode()).and_then(|d| {
+	    let mut entries = d.children()?.read().iter()
+		.filter(|(_, child)| child.inode > cookie)
+		.map(|(name, child)| DirEntry {
+		    inode: child.inode,
+		    kind: child.kind(),
+		    name: name.clone(),
+		    cookie: child.inode,
+		})
+		.collect::<Vec<DirEntry>>();
+	    entries.sort_by_key(|e| e.cookie);
+	    Ok(entries)
+	});
+
+	async move {
+	    entries
+	}.boxed()
+    }
+
+    fn create(self: Arc<Self>, fsi: FileSystemInstance, dir: &Arc<dyn VNode>, name: &str, kind: VNodeKind, mode: u64) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	let node = match kind {
+	    VNodeKind::Directory => self.do_create(dir.inode(), name, TmpFsContent::Directory(RwLock::new(BTreeMap::new())), mode),
+	    VNodeKind::Regular => self.do_create(dir.inode(), name, TmpFsContent::File(RwLock::new(Vec::new())), mode),
+	    _ => Err(CanonicalError::Inval),
+	};
+
+	async move {
+	    Ok(self.vnode(node?, fsi))
+	}.boxed()
+    }
+
+    // Links always have every permission, as it's what they point at which decides who can use it
+    fn symlink(self: Arc<Self>, fsi: FileSystemInstance, dir: &Arc<dyn VNode>, name: &str, target: &str) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	let node = self.do_create(dir.inode(), name, TmpFsContent::Symlink(String::from(target)), 0o777);
+
+	async move {
+	    Ok(self.vnode(node?, fsi))
+	}.boxed()
+    }
+
+    fn unlink(self: Arc<Self>, _fsi: FileSystemInstance, dir: &Arc<dyn VNode>, name: &str) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	let res = self.do_unlink(dir.inode(), name);
+
+	async move {
+	    res
+	}.boxed()
+    }
+
+    fn rename(self: Arc<Self>, _fsi: FileSystemInstance, old_dir: &Arc<dyn VNode>, old_name: &str, new_dir: &Arc<dyn VNode>, new_name: &str) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	let res = self.do_rename(old_dir.inode(), old_name, new_dir.inode(), new_name);
+
+	async move {
+	    res
+	}.boxed()
+    }
+}
+
+struct TmpFsVNode {
+    fs: Arc<TmpFs>,
+    node: Arc<TmpFsNode>,
+    fsi: Mutex<FileSystemInstance>,
+}
+
+impl VNode for TmpFsVNode {
+    fn inode(&self) -> u64 {
+	self.node.inode
+    }
+
+    fn kind(&self) -> VNodeKind {
+	self.node.kind()
+    }
+
+    fn stat(&self) -> Result<Stat, CanonicalError> {
+	let size = match self.node.content {
+	    TmpFsContent::Directory(_) => None,
+	    TmpFsContent::File(ref data) => Some(data.read().len() as u64),
+	    TmpFsContent::Symlink(ref target) => Some(target.len() as u64),
+	};
+
+	Ok(Stat {
+	    file_name: self.node.name.read().clone(),
+	    size,
+	    inode: self.inode(),
+	    kind: self.kind(),
+	    mode: self.node.mode,
+	    modified: None,
+	})
+    }
+
+    fn open(self: Arc<Self>) -> Result<Arc<dyn FileHandle>, CanonicalError> {
+	match self.node.content {
+	    TmpFsContent::Directory(_) => Ok(Arc::new(TmpFsDirHandle {
+		vnode: self.clone(),
+		cookie: AtomicU64::new(0),
+	    })),
+	    TmpFsContent::File(_) => Ok(Arc::new(TmpFsFileHandle {
+		vnode: self.clone(),
+		position: AtomicU64::new(0),
+	    })),
+	    // Opening a path follows any link at the end of it, so only O_NOFOLLOW gets here
+	    TmpFsContent::Symlink(_) => Err(CanonicalError::Loop),
+	}
+    }
+
+    fn filesystem(&self) -> Arc<dyn FileSystem> {
+	self.fs.clone()
+    }
+
+    fn fsi(&self) -> FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn VNode>, CanonicalError> {
+	// The root's parent is found through the mount table
+	let parent = self.node.parent.read().upgrade();
+	match parent {
+	    Some(parent) => Ok(self.fs.clone().vnode(parent, self.fsi())),
+	    None => Err(CanonicalError::NoEnt),
+	}
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	match self.node.content {
+	    TmpFsContent::Symlink(ref target) => Ok(target.clone()),
+	    _ => Err(CanonicalError::Inval),
+	}
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+struct TmpFsFileHandle {
+    vnode: Arc<TmpFsVNode>,
+    position: AtomicU64,
+}
+
+impl TmpFsFileHandle {
+    fn data(&self) -> &RwLock<Vec<u8>> {
+	self.vnode.node.data().expect("tmpfs file handle opened on something other than a file")
+    }
+}
+
+impl FileHandle for TmpFsFileHandle {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    let data = self.data().read();
+	    let start = core::cmp::min(self.position.load(Ordering::SeqCst) as usize, data.len());
+	    let end = core::cmp::min(start.saturating_add(len as usize), data.len());
+
+	    self.position.store(end as u64, Ordering::SeqCst);
+	    Ok(bytes::Bytes::copy_from_slice(&data[start .. end]))
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    let mut data = self.data().write();
+	    let start = self.position.load(Ordering::SeqCst) as usize;
+	    let end = start.checked_add(buf.len()).ok_or(CanonicalError::Inval)?;
+
+	    // Writing past the end leaves a hole, which reads back as zeroes
+	    if end > data.len() {
+		data.resize(end, 0);
+	    }
+	    data[start .. end].copy_from_slice(&buf);
+
+	    self.position.store(end as u64, Ordering::SeqCst);
+	    Ok(buf.len() as u64)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	self.vnode.stat()
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError> {
+	let new_offset = match offset {
+	    SeekFrom::Set(n) => n,
+	    SeekFrom::Cur(n) => self.position.load(Ordering::SeqCst) as i64 + n,
+	    SeekFrom::End(n) => self.data().read().len() as i64 + n,
+	};
+
+	if new_offset < 0 {
+	    return Err(CanonicalError::Inval);
+	}
+
+	self.position.store(new_offset as u64, Ordering::SeqCst);
+	Ok(new_offset as u64)
+    }
+
+    fn truncate(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    self.data().write().resize(len as usize, 0);
+	    Ok(())
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+struct TmpFsDirHandle {
+    vnode: Arc<TmpFsVNode>,
+    cookie: AtomicU64,
+}
+
+impl FileHandle for TmpFsDirHandle {
+    fn read(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, _buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	self.vnode.stat()
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError> {
+	match offset {
+	    SeekFrom::Set(n) if n >= 0 => {
+		self.cookie.store(n as u64, Ordering::SeqCst);
+		Ok(n as u64)
+	    },
+	    _ => Err(CanonicalError::Inval),
+	}
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::IsDir)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	let dir: Arc<dyn VNode> = self.vnode.clone();
+	self.vnode.fs.clone().readdir(self.vnode.fsi(), &dir, self.cookie.load(Ordering::SeqCst))
+    }
+}
+
+pub async fn mount_tmpfs() -> SyscallResult {
+    syscall_try!(vfs::mount("/tmp", Arc::new(TmpFs::new())).await);
+    syscall_success!(0)
+}
@@ -4,9 +4,12 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use x86_64::instructions::tables::load_tss;
 use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
 use x86_64::registers::model_specific::Msr;
-use spin::Once;
+use alloc::collections::BTreeMap;
+use raw_cpuid::CpuId;
+use spin::RwLock;
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::memory;
 
@@ -16,7 +19,13 @@ const IA32_FSBASE_MSR: u32 = 0xC0000100;
 const IA32_GSBASE_MSR: u32 = 0xC0000101;
 const IA32_KERNELGSBASE_MSR: u32 = 0xC0000102;
 
-pub static IST_FRAME: Once<u64> = Once::new();
+pub const KERNEL_STACK_SIZE: u64 = 1024 * 1024 * 8;
+const DOUBLE_FAULT_STACK_SIZE: u64 = 4096 * 5;
+
+// Every CPU's PCB, by APIC ID
+static PCBS: RwLock<BTreeMap<u32, VirtAddr>> = RwLock::new(BTreeMap::new());
+// CPUs are numbered in the order they're brought up, so the BSP is always 0
+static NEXT_CPU: AtomicU64 = AtomicU64::new(0);
 
 pub struct Selectors {
     code_selector: SegmentSelector,
@@ -37,38 +46,53 @@ pub struct ProcessorControlBlock {
 
     pub tmp_user_stack_ptr: usize,
     pub kernel_cr3: u64,
+
+    // The bottom of the stack syscalls and interrupts run on
+    pub kernel_stack: u64,
+    // The scheduler's number for the CPU, as opposed to its APIC ID
+    pub cpu: u64,
 }
 
-pub fn init() {
+// The x2APIC ID if the CPU gives one, as IDs can be too big for the 8 bits of the older one. Read through CPUID, as
+// the local APIC may not be enabled yet.
+fn cpuid_apic_id() -> u32 {
+    let cpu_id = CpuId::new();
+    if let Some(level) = cpu_id.get_extended_topology_info().and_then(|mut levels| levels.next()) {
+	return level.x2apic_id();
+    }
+
+    cpu_id.get_feature_info().expect("CPUID get features info failed.").initial_local_apic_id() as u32
+}
+
+// Gives the CPU it's run on a PCB of its own, with its own GDT, TSS and stacks, and loads it. The descriptors are
+// appended in the same order every time, so the selectors are the same on every CPU.
+fn init_cpu() {
+    let apic_id = cpuid_apic_id();
+    if PCBS.read().contains_key(&apic_id) {
+	panic!("CPU with APIC ID {} initialised twice", apic_id);
+    }
+
     let (pcb, pcb_ptr) = unsafe {
 	let pcb = memory::kernel_allocate(
 	    size_of::<ProcessorControlBlock>() as u64,
-	    memory::MemoryAllocationType::Ram).expect("Unable to allocate BSP PCB");
+	    memory::MemoryAllocationType::Ram).expect("Unable to allocate PCB");
 
-	(&mut *(pcb.0.as_mut_ptr::<ProcessorControlBlock>()), pcb.0.as_u64())
+	(&mut *(pcb.0.as_mut_ptr::<ProcessorControlBlock>()), pcb.0)
     };
 
     pcb.self_ptr = pcb as *mut ProcessorControlBlock as usize;
+    pcb.cpu = NEXT_CPU.fetch_add(1, Ordering::SeqCst);
 
     pcb.tss = TaskStateSegment::new();
-    pcb.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-	const STACK_SIZE: usize = 4096 * 5;
-	static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-	let stack_start = VirtAddr::from_ptr(&raw const STACK);
-	
-	stack_start + STACK_SIZE as u64
-    };
+    let double_fault_stack = memory::kernel_allocate_stack(DOUBLE_FAULT_STACK_SIZE).expect("Unable to allocate double fault stack");
+    pcb.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack + DOUBLE_FAULT_STACK_SIZE;
 
-    let stack_start = memory::kernel_allocate(
-	1024 * 1024 * 8_u64,    
-	memory::MemoryAllocationType::Ram).expect("Unable to allocate kernel stack").0;
+    let stack_start = memory::kernel_allocate_stack(KERNEL_STACK_SIZE).expect("Unable to allocate kernel stack");
 
     // Both syscalls and interrupts can use the same stack, as only one will ever be running at once - syscalls disable interrupts, and interrupt handlers do too
-    pcb.tss.privilege_stack_table[0] = stack_start + (1024 * 1024 * 8);
-    pcb.tss.interrupt_stack_table[KERNEL_IST_INDEX as usize] = stack_start + (1024 * 1024 * 8);
-
-    IST_FRAME.call_once(|| stack_start.as_u64());
+    pcb.tss.privilege_stack_table[0] = stack_start + KERNEL_STACK_SIZE;
+    pcb.tss.interrupt_stack_table[KERNEL_IST_INDEX as usize] = stack_start + KERNEL_STACK_SIZE;
+    pcb.kernel_stack = stack_start.as_u64();
 
     pcb.gdt = GlobalDescriptorTable::new();
     let code_selector = pcb.gdt.append(Descriptor::kernel_code_segment());
@@ -100,12 +124,23 @@ pub fn init() {
     }
     let mut gsbase_msr = Msr::new(IA32_GSBASE_MSR);
     unsafe {
-	gsbase_msr.write(pcb_ptr);
+	gsbase_msr.write(pcb_ptr.as_u64());
     }
     let mut kernelgsbase_msr = Msr::new(IA32_KERNELGSBASE_MSR);
     unsafe {
-	kernelgsbase_msr.write(pcb_ptr);
+	kernelgsbase_msr.write(pcb_ptr.as_u64());
     }
+
+    PCBS.write().insert(apic_id, pcb_ptr);
+}
+
+pub fn init() {
+    init_cpu();
+}
+
+// Called by each application processor as it starts, once it's on the kernel's page tables
+pub fn init_ap() {
+    init_cpu();
 }
 
 pub fn get_pcb() -> *mut ProcessorControlBlock {
@@ -123,3 +158,16 @@ pub fn get_code_selectors() -> (SegmentSelector, SegmentSelector, SegmentSelecto
 	 (*pcb).gdt_selectors.user_code_selector, (*pcb).gdt_selectors.user_data_selector)
     }
 }
+
+// The start of the current CPU's kernel stack, which is KERNEL_STACK_SIZE bytes long
+pub fn kernel_stack() -> u64 {
+    unsafe {
+	(*get_pcb()).kernel_stack
+    }
+}
+
+pub fn current_cpu() -> u64 {
+    unsafe {
+	(*get_pcb()).cpu
+    }
+}
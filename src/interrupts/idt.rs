@@ -1,14 +1,19 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use lazy_static::lazy_static;
 use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::format;
 use alloc::vec::Vec;
 use alloc::vec;
 use spin::{Once, RwLock};
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use crate::interrupts::local_apic;
 use crate::gdt;
+use crate::memory;
 use crate::scheduler;
+use crate::scheduler::signal;
 use crate::process;
 
 #[repr(C)]
@@ -18,6 +23,15 @@ struct StackFrame {
     stack_frame: InterruptStackFrame,
 }
 
+// As StackFrame, for exceptions which push an error code
+#[repr(C)]
+#[derive(Debug)]
+struct FaultStackFrame {
+    registers: process::GeneralPurposeRegisters,
+    error_code: u64,
+    stack_frame: InterruptStackFrame,
+}
+
 macro_rules! irq_handler_def {
     ($irq:literal) => {
 	paste::item! {
@@ -37,13 +51,11 @@ macro_rules! irq_handler_def {
 			stack_frame.stack_frame.cpu_flags.bits(),
 			&stack_frame.registers);
 
-		    if stack_frame.stack_frame.stack_pointer.as_u64() >= *gdt::IST_FRAME.get().expect(":(") &&
-			stack_frame.stack_frame.stack_pointer.as_u64() <= *gdt::IST_FRAME.get().expect(":(") + (1024 * 1024 * 8) {
+		    if stack_frame.stack_frame.stack_pointer.as_u64() >= gdt::kernel_stack() &&
+			stack_frame.stack_frame.stack_pointer.as_u64() <= gdt::kernel_stack() + gdt::KERNEL_STACK_SIZE {
 			    panic!("Re-entrant IRQ");
 			}
 
-		    local_apic::ack_apic($irq);
-
 		    {
 			let handler_funcs = HANDLER_FUNCS.get().expect("Handler funcs not initialised").read();
 			match handler_funcs.get(&$irq) {
@@ -60,6 +72,10 @@ macro_rules! irq_handler_def {
 			}
 		    }
 
+		    // Only once the handlers are done, as a level triggered interrupt would otherwise be raised again
+		    // straight away, its device not having been seen to yet
+		    local_apic::ack_apic($irq);
+
 		    scheduler::schedule_next();
 		}
 
@@ -97,8 +113,52 @@ macro_rules! irq_handler_def {
     };
 }
 
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
 static HANDLER_FUNCS: Once<RwLock<BTreeMap<u8, Vec<Box<dyn Fn() + Send + Sync>>>>> = Once::new();
 
+// Counts the exception handlers running on the CPU it's given, and says whether one already was. Handlers which panic
+// never leave, so this also catches faults in the panic path. A fault while one is already being handled means the
+// kernel is in no state to recover, and letting it cascade would end in a triple fault and a reset that loses the
+// original message. Kept per CPU, as other CPUs faulting at the same time, say on user page faults, is fine.
+fn enter_fault(depth: &AtomicU64) -> bool {
+    depth.fetch_add(1, Ordering::SeqCst) > 0
+}
+
+fn leave_fault(depth: &AtomicU64) {
+    depth.fetch_sub(1, Ordering::SeqCst);
+}
+
+struct FaultGuard {
+    depth: &'static AtomicU64,
+}
+
+impl FaultGuard {
+    fn enter(name: &str, stack_frame: &InterruptStackFrame) -> Self {
+	let depth = scheduler::fault_depth();
+	if enter_fault(depth) {
+	    nested_fault(name, stack_frame);
+	}
+
+	FaultGuard { depth }
+    }
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+	leave_fault(self.depth);
+    }
+}
+
+fn nested_fault(name: &str, stack_frame: &InterruptStackFrame) -> ! {
+    x86_64::instructions::interrupts::disable();
+    log::error!("EXCEPTION: {} while handling an earlier exception, halting\n{:#?}", name, stack_frame);
+
+    loop {
+	x86_64::instructions::hlt();
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
 	let mut idt = InterruptDescriptorTable::new();
@@ -111,15 +171,21 @@ lazy_static! {
 	idt.device_not_available.set_handler_fn(device_not_available_handler);
 	idt.invalid_tss.set_handler_fn(invalid_tss_handler);
 	idt.breakpoint.set_handler_fn(breakpoint_handler);
-	idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
 	idt.segment_not_present.set_handler_fn(segment_not_present_handler);
 	idt.stack_segment_fault.set_handler_fn(stack_segment_handler);
 
 	unsafe {
+	    let gpf_handler: extern "x86-interrupt" fn(InterruptStackFrame, u64) =
+		core::mem::transmute(gpf_entry as *const () as usize);
 	    idt.general_protection_fault.set_handler_fn(gpf_handler)
 		.set_stack_index(gdt::KERNEL_IST_INDEX);
+	    let invalid_opcode_handler: extern "x86-interrupt" fn(InterruptStackFrame) =
+		core::mem::transmute(invalid_opcode_entry as *const () as usize);
+	    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
 	    idt.double_fault.set_handler_fn(double_fault_handler)
 		.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+	    let page_fault_handler: extern "x86-interrupt" fn(InterruptStackFrame, PageFaultErrorCode) =
+		core::mem::transmute(page_fault_entry as *const () as usize);
 	    idt.page_fault.set_handler_fn(page_fault_handler)
 		.set_stack_index(gdt::KERNEL_IST_INDEX);
 
@@ -140,10 +206,34 @@ lazy_static! {
 	    install_irq_45(&mut idt);
 	    install_irq_46(&mut idt);
 	    install_irq_47(&mut idt);
+
+	    // MSI vectors
+	    install_irq_48(&mut idt);
+	    install_irq_49(&mut idt);
+	    install_irq_50(&mut idt);
+	    install_irq_51(&mut idt);
+	    install_irq_52(&mut idt);
+	    install_irq_53(&mut idt);
+	    install_irq_54(&mut idt);
+	    install_irq_55(&mut idt);
+	    install_irq_56(&mut idt);
+	    install_irq_57(&mut idt);
+	    install_irq_58(&mut idt);
+	    install_irq_59(&mut idt);
+	    install_irq_60(&mut idt);
+	    install_irq_61(&mut idt);
+	    install_irq_62(&mut idt);
+	    install_irq_63(&mut idt);
+
+	    // Local APIC timer
+	    install_irq_64(&mut idt);
+
+	    // IPIs
+	    install_irq_65(&mut idt);
 	}
 
 	// APIC Spurious Interrupts
-	idt[0xFF].set_handler_fn(spurious_interrupt_handler);
+	idt[local_apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
 
 	idt
     };
@@ -170,18 +260,41 @@ pub fn add_handler_to_irq(irq: u8, handler: Box<dyn Fn() + Send + Sync>) {
 
 // Faults
 extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    let _guard = FaultGuard::enter("#DE", &stack_frame);
     log::warn!("EXCEPTION: #DE\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let _guard = FaultGuard::enter("#DB", &stack_frame);
     panic!("EXCEPTION: #DB\n{:#?}", stack_frame);
 }
 
+// Once set, an NMI stops the CPU it lands on for good, as the machine is going down
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOPPED_CPUS: AtomicU64 = AtomicU64::new(0);
+
+pub fn stop_on_nmi() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+pub fn stopped_cpus() -> u64 {
+    STOPPED_CPUS.load(Ordering::SeqCst)
+}
+
 extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    if STOPPING.load(Ordering::SeqCst) {
+	STOPPED_CPUS.fetch_add(1, Ordering::SeqCst);
+	// Interrupts are already off in here, and further NMIs are held off until an iret that never comes
+	loop {
+	    x86_64::instructions::hlt();
+	}
+    }
+
     log::warn!("EXCEPTION: #NMI\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    let _guard = FaultGuard::enter("#OF", &stack_frame);
     log::warn!("EXCEPTION: #OF\n{:#?}", stack_frame);
 }
 
@@ -190,51 +303,282 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    let _guard = FaultGuard::enter("#BR", &stack_frame);
     log::warn!("EXCEPTION: #BR\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    let _guard = FaultGuard::enter("#NM", &stack_frame);
     log::warn!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
 }
 
+// Runs on a stack of its own, as what led here may well have been the kernel's stack running out. Nothing can be
+// recovered by now, and a panic could fault all over again, so just say what happened and stop.
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
     x86_64::instructions::interrupts::disable();
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    let _guard = FaultGuard::enter("DOUBLE FAULT", &stack_frame);
+    log::error!("EXCEPTION: DOUBLE FAULT\nRIP 0x{:x}, RSP 0x{:x}, CR2 0x{:x}\n{:#?}",
+		stack_frame.instruction_pointer.as_u64(),
+		stack_frame.stack_pointer.as_u64(),
+		x86_64::registers::control::Cr2::read_raw(),
+		stack_frame);
+
+    loop {
+	x86_64::instructions::hlt();
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    x86_64::instructions::interrupts::disable();
+fn describe_page_fault(error_code: PageFaultErrorCode) -> String {
+    let mut description = String::from(if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+	"page present"
+    } else {
+	"page not present"
+    });
+
+    description += if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { ", write" } else { ", read" };
+    description += if error_code.contains(PageFaultErrorCode::USER_MODE) { ", user mode" } else { ", kernel mode" };
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+	description += ", instruction fetch";
+    }
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+	description += ", reserved bit set";
+    }
+
+    description
+}
+
+// Leaves a user process which faulted with a signal to deal with, which it's given on the way back out to userspace
+// instead of going back to the instruction that faulted. A fault can't wait, so the signal is unblocked if it was
+// blocked, as Linux does.
+fn deliver_fault_signal(stack_frame: &InterruptStackFrame, registers: &process::GeneralPurposeRegisters, signal: u64) -> ! {
+    let process = scheduler::get_current_process();
+    process.clone().set_registers(
+	stack_frame.stack_pointer.as_u64(),
+	stack_frame.instruction_pointer.as_u64(),
+	stack_frame.cpu_flags.bits(),
+	registers);
+
+    process.clone().signal_mask_unblock(1 << (signal - 1));
+    process.post_signal(signal);
+    scheduler::schedule_next();
+}
+
+extern "C" fn page_fault_inner(frame: &FaultStackFrame) {
+    let guard = FaultGuard::enter("PAGE FAULT", &frame.stack_frame);
     let target_addr = x86_64::registers::control::Cr2::read_raw();
-    panic!("EXCEPTION: PAGE FAULT\nADDR 0x{:x}\n{:#?}\n{:#?}", target_addr, error_code, stack_frame);
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+
+    if !error_code.contains(PageFaultErrorCode::USER_MODE) {
+	if x86_64::VirtAddr::try_new(target_addr).is_ok_and(memory::is_kernel_stack_guard) {
+	    panic!("EXCEPTION: KERNEL STACK OVERFLOW\nADDR 0x{:x}, RIP 0x{:x}\n{:#?}", target_addr, rip, frame.stack_frame);
+	}
+
+	panic!("EXCEPTION: PAGE FAULT\nADDR 0x{:x}, RIP 0x{:x} ({})\n{:#?}",
+	       target_addr, rip, describe_page_fault(error_code), frame.stack_frame);
+    }
+
+    // A process touching a page which is only reserved, or writing to a copy-on-write one, is expected, and just needs
+    // the page backing
+    let rsp = frame.stack_frame.stack_pointer.as_u64();
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let fault = match x86_64::VirtAddr::try_new(target_addr) {
+	Ok(addr) => memory::handle_user_page_fault(addr, write, rsp),
+	Err(_) => memory::UserFault::Unmapped,
+    };
+
+    let (sig, reason) = match fault {
+	memory::UserFault::Resolved => return,
+	memory::UserFault::StackOverflow => (signal::SIGSEGV, "stack overflow"),
+	memory::UserFault::Unmapped => (signal::SIGSEGV, "segfault"),
+	memory::UserFault::Protection => (signal::SIGSEGV, "protection fault"),
+	memory::UserFault::NoBacking => (signal::SIGBUS, "no memory to back page"),
+    };
+
+    log::warn!("PID {}: {} at 0x{:x}, RIP 0x{:x}, RSP 0x{:x} ({})",
+	       scheduler::get_current_pid(), reason, target_addr, rip, rsp, describe_page_fault(error_code));
+    drop(guard);
+    deliver_fault_signal(&frame.stack_frame, &frame.registers, sig);
 }
 
-extern "x86-interrupt" fn gpf_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    x86_64::instructions::interrupts::disable();
-    panic!("EXCEPTION: GPF error code 0x{:x}\n{:#?}", error_code, stack_frame);
+// Saves the registers, as an IRQ does, so that a faulting process can be switched away from to deliver it a signal.
+// $error_code is how many bytes of error code the CPU pushes for the exception, 8 or 0.
+macro_rules! fault_entry_def {
+    ($name:ident, $inner:ident, $error_code:literal) => {
+	#[unsafe(naked)]
+	extern "C" fn $name() {
+	    core::arch::naked_asm!(
+		"test qword ptr [rsp + {cs}], 0x03",
+		"je 2f",
+		"swapgs",
+		"2:",
+
+		"push rax",
+		"push rbx",
+		"push rcx",
+		"push rdx",
+		"push rsi",
+		"push rdi",
+		"push rbp",
+		"push r8",
+		"push r9",
+		"push r10",
+		"push r11",
+		"push r12",
+		"push r13",
+		"push r14",
+		"push r15",
+
+		// An error code leaves the stack 8 bytes off the alignment calls need
+		"mov rdi, rsp",
+		"sub rsp, {error_code}",
+		"call {inner}",
+		"add rsp, {error_code}",
+
+		"pop r15",
+		"pop r14",
+		"pop r13",
+		"pop r12",
+		"pop r11",
+		"pop r10",
+		"pop r9",
+		"pop r8",
+		"pop rbp",
+		"pop rdi",
+		"pop rsi",
+		"pop rdx",
+		"pop rcx",
+		"pop rbx",
+		"pop rax",
+
+		"test qword ptr [rsp + {cs}], 0x03",
+		"je 3f",
+		"swapgs",
+		"3:",
+
+		// Drop the error code
+		"add rsp, {error_code}",
+		"iretq",
+
+		inner = sym $inner,
+		cs = const $error_code + 8,
+		error_code = const $error_code,
+	    );
+	}
+    };
+}
+
+fault_entry_def!(page_fault_entry, page_fault_inner, 8);
+fault_entry_def!(gpf_entry, gpf_inner, 8);
+fault_entry_def!(invalid_opcode_entry, invalid_opcode_inner, 0);
+
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3
+}
+
+// The bytes at rip, to show what the faulting instruction was. No instruction is longer than 15 bytes, but one may
+// end well before a page which isn't mapped.
+fn instruction_bytes(rip: u64, user: bool) -> String {
+    let mut bytes = [0u8; 15];
+    let len = match x86_64::VirtAddr::try_new(rip) {
+	Ok(addr) if user => {
+	    let to_page_end = (4096 - rip % 4096).min(15) as usize;
+	    if memory::copy_from_user_into(addr, &mut bytes).is_ok() {
+		bytes.len()
+	    } else if memory::copy_from_user_into(addr, &mut bytes[.. to_page_end]).is_ok() {
+		to_page_end
+	    } else {
+		0
+	    }
+	},
+	Ok(addr) => memory::peek_kernel(addr, &mut bytes),
+	Err(_) => 0,
+    };
+
+    if len == 0 {
+	return String::from("unreadable");
+    }
+
+    bytes[.. len].iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+// A #GP's error code is the selector it was loading, if that's what went wrong
+fn describe_selector_error(error_code: u64) -> String {
+    if error_code == 0 {
+	return String::from("no selector");
+    }
+
+    let table = match (error_code >> 1) & 0x3 {
+	0 => "GDT",
+	2 => "LDT",
+	_ => "IDT",
+    };
+    let external = if error_code & 0x1 != 0 { ", external event" } else { "" };
+
+    format!("{} index {}{}", table, (error_code >> 3) & 0x1FFF, external)
+}
+
+extern "C" fn gpf_inner(frame: &FaultStackFrame) {
+    let guard = FaultGuard::enter("GPF", &frame.stack_frame);
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    let user = from_user_mode(&frame.stack_frame);
+
+    if !user {
+	x86_64::instructions::interrupts::disable();
+	panic!("EXCEPTION: GPF error code 0x{:x} ({}), RIP 0x{:x} [{}]\n{:#?}",
+	       frame.error_code, describe_selector_error(frame.error_code), rip, instruction_bytes(rip, false),
+	       frame.stack_frame);
+    }
+
+    log::warn!("PID {}: general protection fault, error code 0x{:x} ({}), RIP 0x{:x} [{}]",
+	       scheduler::get_current_pid(), frame.error_code, describe_selector_error(frame.error_code),
+	       rip, instruction_bytes(rip, true));
+    drop(guard);
+    deliver_fault_signal(&frame.stack_frame, &frame.registers, signal::SIGSEGV);
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
     x86_64::instructions::interrupts::disable();
+    let _guard = FaultGuard::enter("#NP", &stack_frame);
     panic!("EXCEPTION: NP error code 0x{:x}\n{:#?}", error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn stack_segment_handler(stack_frame: InterruptStackFrame, error_code: u64) {
     x86_64::instructions::interrupts::disable();
+    let _guard = FaultGuard::enter("#SS", &stack_frame);
     panic!("EXCEPTION: SS error code 0x{:x}\n{:#?}", error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
     x86_64::instructions::interrupts::disable();
+    let _guard = FaultGuard::enter("#TS", &stack_frame);
     panic!("EXCEPTION: #TS error code 0x{:x}\n{:#?}", error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: #UD\n{:#?}", stack_frame);
+extern "C" fn invalid_opcode_inner(frame: &StackFrame) {
+    let guard = FaultGuard::enter("#UD", &frame.stack_frame);
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    let user = from_user_mode(&frame.stack_frame);
+
+    if !user {
+	panic!("EXCEPTION: #UD, RIP 0x{:x} [{}]\n{:#?}", rip, instruction_bytes(rip, false), frame.stack_frame);
+    }
+
+    log::warn!("PID {}: invalid opcode, RIP 0x{:x} [{}]",
+	       scheduler::get_current_pid(), rip, instruction_bytes(rip, true));
+    drop(guard);
+    deliver_fault_signal(&frame.stack_frame, &frame.registers, signal::SIGILL);
 }
 
 // IRQs
+// The local APIC raises this when an interrupt goes away before it can be delivered. Nothing is in service, so it
+// mustn't be EOIed, or whatever interrupt is in service would be ended early.
 extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    log::info!("Spurious interrupt happened :-)");
+    SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn spurious_interrupt_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
 }
 
 irq_handler_def!(32);
@@ -253,3 +597,35 @@ irq_handler_def!(44);
 irq_handler_def!(45);
 irq_handler_def!(46);
 irq_handler_def!(47);
+irq_handler_def!(48);
+irq_handler_def!(49);
+irq_handler_def!(50);
+irq_handler_def!(51);
+irq_handler_def!(52);
+irq_handler_def!(53);
+irq_handler_def!(54);
+irq_handler_def!(55);
+irq_handler_def!(56);
+irq_handler_def!(57);
+irq_handler_def!(58);
+irq_handler_def!(59);
+irq_handler_def!(60);
+irq_handler_def!(61);
+irq_handler_def!(62);
+irq_handler_def!(63);
+irq_handler_def!(64);
+irq_handler_def!(65);
+
+#[test]
+fn fault_during_fault_on_same_cpu_is_nested() {
+    let cpu0 = AtomicU64::new(0);
+    let cpu1 = AtomicU64::new(0);
+
+    assert!(!enter_fault(&cpu0));
+    assert!(!enter_fault(&cpu1));
+    assert!(enter_fault(&cpu0));
+
+    leave_fault(&cpu0);
+    leave_fault(&cpu0);
+    assert!(!enter_fault(&cpu0));
+}
@@ -2,6 +2,7 @@ use spin::{Once, RwLock};
 use core::ptr::{read_volatile, write_volatile};
 use alloc::vec::Vec;
 use alloc::collections::btree_map::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::interrupts::IRQ_BASE;
 use crate::memory;
@@ -99,6 +100,13 @@ impl IoApic {
 	self.write_reg(ioredtbl_hi_idx, ioredtbl_hi);
     }
 
+    pub fn set_destination(&self, gsi: u32, dest_apic: u32) {
+	let ioredtbl_idx = gsi - self.global_system_interrupt_base;
+	let ioredtbl_hi_idx: u8 = (ioredtbl_idx as u8 * 2) + 0x11;
+
+	self.write_reg(ioredtbl_hi_idx, dest_apic << 24);
+    }
+
     pub fn get_irq_for_gsi(&self, gsi: u32) -> Option<u8> {
 	self.gsi_to_irq.get(&gsi).copied()
     }
@@ -118,7 +126,79 @@ impl IoApic {
 static IOAPICS: Once<RwLock<Vec<IoApic>>> = Once::new();
 static IRQ_TO_GSI: Once<RwLock<BTreeMap<u8, u32>>> = Once::new();
 
+// APIC IDs of the CPUs device interrupts can be sent to, BSP first. APs are added as they're brought up
+static CPU_APIC_IDS: RwLock<Vec<u32>> = RwLock::new(Vec::new());
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+// Which CPU each GSI is sent to. Set by set_gsi_affinity, or else picked round-robin the first time the GSI is
+// enabled, and kept from then on so that adding a handler to a shared GSI doesn't move it
+static GSI_DESTINATIONS: RwLock<BTreeMap<u32, Destination>> = RwLock::new(BTreeMap::new());
+
+#[derive(Clone, Copy)]
+struct Destination {
+    apic_id: u32,
+    // Given by set_gsi_affinity, rather than picked round-robin, so never moved by rebalancing
+    pinned: bool,
+}
+
+pub fn add_cpu(apic_id: u32) {
+    CPU_APIC_IDS.write().push(apic_id);
+    rebalance_gsis();
+}
+
+// GSIs picked a CPU round-robin from however many there were when they were enabled, which for anything enabled at
+// boot is only the BSP, so they're dealt out again over every CPU whenever one is added
+fn rebalance_gsis() {
+    let mut destinations = GSI_DESTINATIONS.write();
+    let cpus = CPU_APIC_IDS.read();
+    let ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).read();
+
+    let mut rebalanced = 0;
+    for (gsi, destination) in destinations.iter_mut().filter(|(_, destination)| !destination.pinned) {
+	destination.apic_id = cpus[rebalanced % cpus.len()];
+	rebalanced += 1;
+
+	if let Some(io_apic) = ioapics.iter().find(|apic| (*apic).contains_gsi(*gsi)) {
+	    io_apic.set_destination(*gsi, destination.apic_id);
+	}
+    }
+
+    // Carry on the round-robin from where rebalancing left it
+    NEXT_CPU.store(rebalanced, Ordering::Relaxed);
+}
+
+pub fn get_bsp_apic_id() -> u32 {
+    *CPU_APIC_IDS.read().first().expect("Attempted to get BSP APIC ID before it was added")
+}
+
+fn next_cpu() -> u32 {
+    let cpus = CPU_APIC_IDS.read();
+    cpus[NEXT_CPU.fetch_add(1, Ordering::Relaxed) % cpus.len()]
+}
+
+fn destination_for_gsi(gsi: u32) -> u32 {
+    GSI_DESTINATIONS.write().entry(gsi)
+	.or_insert_with(|| Destination { apic_id: next_cpu(), pinned: false })
+	.apic_id
+}
+
+pub fn set_gsi_affinity(gsi: u32, dest_apic: u32) {
+    GSI_DESTINATIONS.write().insert(gsi, Destination { apic_id: dest_apic, pinned: true });
+
+    let ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).read();
+    let io_apic = ioapics.iter().find(|apic| (*apic).contains_gsi(gsi))
+	.unwrap_or_else(|| panic!("GSI {} not found", gsi));
+
+    io_apic.set_destination(gsi, dest_apic);
+}
+
+pub fn set_irq_affinity(irq: u8, dest_apic: u32) {
+    let gsi = *IRQ_TO_GSI.call_once(|| RwLock::new(BTreeMap::<u8, u32>::new())).read().get(&irq).unwrap();
+    set_gsi_affinity(gsi, dest_apic);
+}
+
 pub fn init_io_apics(bsp_apic_id: u64) {
+    add_cpu(bsp_apic_id as u32);
+
     let io_apic_data = acpi::interrupts::iterate_madt_ioapics().unwrap();
 
     let mut ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).write();
@@ -171,32 +251,88 @@ pub fn init_io_apics(bsp_apic_id: u64) {
     }
 }
 
-pub fn get_irq_for_gsi(gsi: u32) -> u8 {
-    let mut ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).write();
-    let io_apic = ioapics.iter_mut().find(|apic| (*apic).contains_gsi(gsi))
+// None if the GSI hasn't been given a vector yet
+pub fn get_irq_for_gsi(gsi: u32) -> Option<u8> {
+    let ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).read();
+    let io_apic = ioapics.iter().find(|apic| (*apic).contains_gsi(gsi))
 	.unwrap_or_else(|| panic!("GSI {} not found", gsi));
 
-    match io_apic.get_irq_for_gsi(gsi) {
-	Some(irq) => irq,
-	None => unimplemented!(),
-    }
+    io_apic.get_irq_for_gsi(gsi)
+}
+
+// Points a GSI at a vector. It stays masked until enabled.
+pub fn map_gsi(gsi: u32, trigger_mode: TriggerMode, polarity: Polarity, vector: u8) {
+    let dest_apic = destination_for_gsi(gsi);
+
+    let mut ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).write();
+    ioapics.iter_mut().find(|apic| (*apic).contains_gsi(gsi))
+	.unwrap_or_else(|| panic!("GSI {} not found", gsi))
+	.map_interrupt(gsi, dest_apic, trigger_mode, polarity, vector);
 }
 
 pub fn enable_gsi(gsi: u32) {
+    let dest_apic = destination_for_gsi(gsi);
+
     let mut ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).write();
     let io_apic = ioapics.iter_mut().find(|apic| (*apic).contains_gsi(gsi))
 	.unwrap_or_else(|| panic!("GSI {} not found", gsi));
 
+    io_apic.set_destination(gsi, dest_apic);
     io_apic.enable_gsi(gsi);
 }
 
+// Legacy IRQs are mapped as ISA interrupts, edge triggered and active high, unless the MADT overrides them. ACPI's own
+// interrupts follow different rules, so this remaps irq with the trigger mode and polarity given, for whichever of
+// them the MADT doesn't specify. It stays masked until enabled.
+pub fn set_irq_bus_defaults(irq: u8, trigger_mode: TriggerMode, polarity: Polarity) {
+    let io_apic_data = acpi::interrupts::iterate_madt_ioapics().unwrap();
+    let (over_trigger_mode, over_polarity) = match io_apic_data.isos.iter().find(|over| over.source == irq) {
+	Some(over) => (over.trigger_mode().unwrap(), over.polarity().unwrap()),
+	None => (TriggerMode::Conforming, Polarity::Conforming),
+    };
+
+    let trigger_mode = if over_trigger_mode == TriggerMode::Conforming { trigger_mode } else { over_trigger_mode };
+    let polarity = if over_polarity == Polarity::Conforming { polarity } else { over_polarity };
+
+    let gsi = *IRQ_TO_GSI.call_once(|| RwLock::new(BTreeMap::<u8, u32>::new())).read().get(&irq)
+	.unwrap_or_else(|| panic!("IRQ {} not mapped to a GSI", irq));
+    map_gsi(gsi, trigger_mode, polarity, irq + IRQ_BASE);
+}
+
 pub fn enable_irq(irq: u8) {
     let irq_to_gsi = IRQ_TO_GSI.call_once(|| RwLock::new(BTreeMap::<u8, u32>::new())).read();
     let gsi = irq_to_gsi.get(&irq).unwrap();
+    let dest_apic = destination_for_gsi(*gsi);
 
     let mut ioapics = IOAPICS.call_once(|| RwLock::new(Vec::<IoApic>::new())).write();
     let io_apic = ioapics.iter_mut().find(|apic| (*apic).contains_gsi(*gsi))
 	.unwrap_or_else(|| panic!("GSI {} not found", gsi));
 
+    io_apic.set_destination(*gsi, dest_apic);
     io_apic.enable_gsi(*gsi);
 }
+
+#[test]
+fn gsis_are_spread_over_every_cpu() {
+    // Enabled at boot, before there's anything but the BSP
+    add_cpu(0);
+    assert_eq!(destination_for_gsi(16), 0);
+    assert_eq!(destination_for_gsi(17), 0);
+    // As set_gsi_affinity leaves it, without an I/O APIC to program
+    GSI_DESTINATIONS.write().insert(20, Destination { apic_id: 0, pinned: true });
+
+    // Dealt out again as the APs come up
+    add_cpu(1);
+    add_cpu(2);
+    assert_eq!(destination_for_gsi(16), 0);
+    assert_eq!(destination_for_gsi(17), 1);
+
+    // Carrying on round-robin, and kept once picked
+    assert_eq!(destination_for_gsi(18), 2);
+    assert_eq!(destination_for_gsi(19), 0);
+    assert_eq!(destination_for_gsi(18), 2);
+
+    add_cpu(3);
+    let destinations: Vec<(u32, u32)> = GSI_DESTINATIONS.read().iter().map(|(gsi, d)| (*gsi, d.apic_id)).collect();
+    assert_eq!(destinations, [(16, 0), (17, 1), (18, 2), (19, 3), (20, 0)]);
+}
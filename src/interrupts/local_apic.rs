@@ -1,9 +1,10 @@
 use x86_64::registers::model_specific::Msr;
 use raw_cpuid::CpuId;
 use pic8259::ChainedPics;
-use spin::RwLock;
+use spin::{Once, RwLock};
 
 use crate::interrupts::IRQ_BASE;
+use crate::sys::time;
 
 const PIC_2_OFFSET: u8 = IRQ_BASE + 8;
 
@@ -12,12 +13,38 @@ const IA32_APIC_BASE_MSR_IS_BSP: u64 = 1 << 8;
 const IA32_APIC_BASE_MSR_EXTD: u64 = 1 << 10;
 const IA32_APIC_BASE_MSR_ENABLE: u64 = 1 << 11;
 
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
 const IA32_X2APIC_SIVR: u32 = 0x80F;
-const IA32_X2APIC_SIVR_VECTOR: u64 = 0xFF;
 const IA32_X2APIC_SIVR_EN: u64 = 1 << 8;
 
 const IA32_X2APIC_IDR: u32 = 0x802;
 const IA32_X2APIC_EOI: u32 = 0x80B;
+const IA32_X2APIC_ICR: u32 = 0x830;
+
+// Interrupt command register fields, with the destination APIC ID in the top 32 bits
+const ICR_DELIVERY_NMI: u64 = 0x4 << 8;
+const ICR_DELIVERY_INIT: u64 = 0x5 << 8;
+const ICR_DELIVERY_STARTUP: u64 = 0x6 << 8;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_DEST_ALL_EXCLUDING_SELF: u64 = 0x3 << 18;
+const ICR_DEST_SHIFT: u64 = 32;
+
+const IA32_X2APIC_LVT_TIMER: u32 = 0x832;
+const IA32_X2APIC_TIMER_INITIAL_COUNT: u32 = 0x838;
+const IA32_X2APIC_TIMER_CURRENT_COUNT: u32 = 0x839;
+const IA32_X2APIC_TIMER_DIVIDE_CONFIG: u32 = 0x83E;
+
+const LVT_TIMER_MASKED: u64 = 1 << 16;
+const LVT_TIMER_PERIODIC: u64 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u64 = 0x3;
+
+// How long the timer is run against the HPET to find its frequency
+const TIMER_CALIBRATION_NS: u64 = 10_000_000;
+
+// Timer counts per millisecond, with the divider at 16. Every core's timer runs off the same clock, so this is only
+// measured once.
+static TIMER_COUNTS_PER_MS: Once<u64> = Once::new();
 
 static PICS: RwLock<ChainedPics> = RwLock::new(unsafe { ChainedPics::new(IRQ_BASE, PIC_2_OFFSET) });
 
@@ -36,7 +63,7 @@ pub fn init_bsp_local_apic() -> u64 {
     remap_pics();
 
     // Get the base address of the APIC
-    let mut ia32_apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    let ia32_apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
     let base_msr_val = unsafe {
 	ia32_apic_base_msr.read()
     };
@@ -45,15 +72,28 @@ pub fn init_bsp_local_apic() -> u64 {
 	panic!("Attempted to initialise BSP APIC on an AP");
     }
 
+    enable_local_apic()
+}
+
+// The BSP has already checked the APIC supports x2APIC mode, and every CPU's is the same
+pub fn init_ap_local_apic() -> u64 {
+    enable_local_apic()
+}
+
+// Enables the local APIC of the CPU this is run on, in x2APIC mode, and returns its ID
+fn enable_local_apic() -> u64 {
+    let mut ia32_apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+
     // Enable the APIC in X2 mode
     unsafe {
+	let base_msr_val = ia32_apic_base_msr.read();
 	ia32_apic_base_msr.write(base_msr_val | IA32_APIC_BASE_MSR_ENABLE | IA32_APIC_BASE_MSR_EXTD);
     }
 
     // Enable the APIC using the Spurious Interrupt Vector Register
     let mut ia32_x2apic_sivr = Msr::new(IA32_X2APIC_SIVR);
     unsafe {
-	ia32_x2apic_sivr.write(IA32_X2APIC_SIVR_VECTOR | IA32_X2APIC_SIVR_EN);
+	ia32_x2apic_sivr.write(SPURIOUS_VECTOR as u64 | IA32_X2APIC_SIVR_EN);
     }
 
     let ia32_x2apic_idr = Msr::new(IA32_X2APIC_IDR);
@@ -64,6 +104,7 @@ pub fn init_bsp_local_apic() -> u64 {
     }
 }
 
+// Ends the interrupt currently in service. Must be done exactly once per interrupt.
 pub fn ack_apic(interrupt: u8) {
     let mut ia32_x2apic_eoi = Msr::new(IA32_X2APIC_EOI);
     unsafe {
@@ -77,6 +118,82 @@ pub fn ack_apic(interrupt: u8) {
     }
 }
 
+// Resets the CPU with the given APIC ID, leaving it waiting for a startup IPI
+pub fn send_init(apic_id: u32) {
+    let mut ia32_x2apic_icr = Msr::new(IA32_X2APIC_ICR);
+    unsafe {
+	ia32_x2apic_icr.write(((apic_id as u64) << ICR_DEST_SHIFT) | ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+}
+
+// Starts a CPU waiting after an INIT in real mode, at the start of the given page of the first 1MiB
+pub fn send_startup(apic_id: u32, page: u8) {
+    let mut ia32_x2apic_icr = Msr::new(IA32_X2APIC_ICR);
+    unsafe {
+	ia32_x2apic_icr.write(((apic_id as u64) << ICR_DEST_SHIFT) | ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u64);
+    }
+}
+
+// Interrupts every CPU but this one on the given vector
+pub fn send_to_others(vector: u8) {
+    let mut ia32_x2apic_icr = Msr::new(IA32_X2APIC_ICR);
+    unsafe {
+	ia32_x2apic_icr.write(ICR_DEST_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u64);
+    }
+}
+
+// As send_to_others, but an NMI, which is taken even with interrupts disabled
+pub fn send_nmi_to_others() {
+    let mut ia32_x2apic_icr = Msr::new(IA32_X2APIC_ICR);
+    unsafe {
+	ia32_x2apic_icr.write(ICR_DEST_ALL_EXCLUDING_SELF | ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+    }
+}
+
+fn calibrate_timer() -> u64 {
+    let mut divide_config = Msr::new(IA32_X2APIC_TIMER_DIVIDE_CONFIG);
+    let mut lvt_timer = Msr::new(IA32_X2APIC_LVT_TIMER);
+    let mut initial_count = Msr::new(IA32_X2APIC_TIMER_INITIAL_COUNT);
+    let current_count = Msr::new(IA32_X2APIC_TIMER_CURRENT_COUNT);
+
+    let (counted, elapsed_ns) = unsafe {
+	divide_config.write(TIMER_DIVIDE_BY_16);
+	lvt_timer.write(LVT_TIMER_MASKED);
+
+	let start = time::get_monotonic_ns();
+	initial_count.write(u32::MAX as u64);
+	while time::get_monotonic_ns() - start < TIMER_CALIBRATION_NS {
+	    core::hint::spin_loop();
+	}
+	let remaining = current_count.read();
+	let elapsed_ns = time::get_monotonic_ns() - start;
+	initial_count.write(0);
+
+	(u32::MAX as u64 - remaining, elapsed_ns)
+    };
+
+    let counts_per_ms = counted * 1_000_000 / elapsed_ns;
+    log::info!("Local APIC timer running at {}kHz", counts_per_ms);
+    counts_per_ms
+}
+
+// Has this CPU's local APIC timer interrupt on the given vector every period_ns. The HPET has to be running, as the
+// timer's frequency is found by timing it against that.
+pub fn start_timer(vector: u8, period_ns: u64) {
+    let counts_per_ms = *TIMER_COUNTS_PER_MS.call_once(calibrate_timer);
+    let count = (counts_per_ms * period_ns / 1_000_000).clamp(1, u32::MAX as u64);
+
+    let mut divide_config = Msr::new(IA32_X2APIC_TIMER_DIVIDE_CONFIG);
+    let mut lvt_timer = Msr::new(IA32_X2APIC_LVT_TIMER);
+    let mut initial_count = Msr::new(IA32_X2APIC_TIMER_INITIAL_COUNT);
+    unsafe {
+	divide_config.write(TIMER_DIVIDE_BY_16);
+	lvt_timer.write(LVT_TIMER_PERIODIC | vector as u64);
+	// Writing the count is what starts it
+	initial_count.write(count);
+    }
+}
+
 fn remap_pics() {
     let mut pics = PICS.write();
 
@@ -1,6 +1,12 @@
 use alloc::boxed::Box;
+use alloc::format;
+use core::sync::atomic::{AtomicU8, Ordering};
 
+use crate::fs;
+use crate::scheduler;
 use crate::sys::acpi;
+use crate::sys::acpi::interrupts::{Polarity, TriggerMode};
+use crate::sys::time;
 
 mod local_apic;
 mod io_apic;
@@ -8,6 +14,23 @@ mod idt;
 
 const IRQ_BASE: u8 = 32;
 
+// Vectors above the legacy IRQs, handed out to PCI interrupt lines and MSIs as they're asked for
+const DYNAMIC_VECTOR_BASE: u8 = 48;
+const DYNAMIC_VECTOR_END: u8 = 64;
+// Where MSIs are written to, with the destination APIC ID in bits 12-19
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u64 = 12;
+
+static NEXT_DYNAMIC_VECTOR: AtomicU8 = AtomicU8::new(DYNAMIC_VECTOR_BASE);
+
+// Every CPU's local APIC timer interrupts on this
+const APIC_TIMER_VECTOR: u8 = 64;
+// Sent from one CPU to the others
+const IPI_VECTOR: u8 = 65;
+
+// How long stop_other_cpus waits for the others to stop
+const STOP_TIMEOUT_NS: u64 = 100_000_000;
+
 #[derive(Clone, Debug)]
 pub enum InterruptRoute {
     Gsi(u32),
@@ -18,7 +41,8 @@ impl InterruptRoute {
     pub fn register_handler(&self, handler: Box<dyn Fn() + Send + Sync>) {
 	match self {
 	    InterruptRoute::Gsi(gsi) => {
-		let irq = io_apic::get_irq_for_gsi(*gsi);
+		// PCI interrupt lines, which is what ACPI gives GSIs for, are level triggered and active low
+		let irq = vector_for_gsi(*gsi, TriggerMode::Level, Polarity::ActiveLow);
 		log::info!("GSI = {}, IRQ = {}", gsi, irq);
 		idt::add_handler_to_irq(irq, handler);
 
@@ -32,6 +56,100 @@ impl InterruptRoute {
 	    }
 	}
     }
+
+    // Sends the interrupt to a particular CPU, rather than whichever one it was given when it was enabled
+    pub fn set_affinity(&self, apic_id: u32) {
+	match self {
+	    InterruptRoute::Gsi(gsi) => io_apic::set_gsi_affinity(*gsi, apic_id),
+	    InterruptRoute::Irq(irq) => io_apic::set_irq_affinity(*irq, apic_id),
+	}
+    }
+}
+
+// Vectors are never given back, as devices are never removed
+fn allocate_vector() -> Option<u8> {
+    NEXT_DYNAMIC_VECTOR.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |vector| {
+	if vector < DYNAMIC_VECTOR_END {
+	    Some(vector + 1)
+	} else {
+	    None
+	}
+    }).ok()
+}
+
+// GSIs other than the legacy IRQs get a vector the first time a handler is added to them. Devices sharing the GSI then
+// share the vector, and each of their handlers is run in turn on every interrupt, so each has to check whether it was
+// its device that interrupted.
+fn vector_for_gsi(gsi: u32, trigger_mode: TriggerMode, polarity: Polarity) -> u8 {
+    if let Some(vector) = io_apic::get_irq_for_gsi(gsi) {
+	return vector;
+    }
+
+    let vector = allocate_vector().unwrap_or_else(|| panic!("No interrupt vectors left for GSI {}", gsi));
+    io_apic::map_gsi(gsi, trigger_mode, polarity, vector);
+    vector
+}
+
+// An IDT vector for a message signalled interrupt, along with the address and data the device needs to write to raise it
+pub struct MsiVector {
+    pub vector: u8,
+    pub address: u64,
+    pub data: u32,
+}
+
+pub fn allocate_msi_vector(handler: Box<dyn Fn() + Send + Sync>) -> Option<MsiVector> {
+    let vector = allocate_vector()?;
+
+    idt::add_handler_to_irq(vector, handler);
+
+    // Edge triggered, fixed delivery, to the BSP. Physical destination mode only reaches APIC IDs below 256.
+    let dest_apic = io_apic::get_bsp_apic_id() as u64;
+    Some(MsiVector {
+	vector,
+	address: MSI_ADDRESS_BASE | ((dest_apic & 0xFF) << MSI_ADDRESS_DEST_SHIFT),
+	data: vector as u32,
+    })
+}
+
+// Runs the handler on every local APIC timer tick, on whichever CPU the tick is on
+pub fn set_apic_timer_handler(handler: Box<dyn Fn() + Send + Sync>) {
+    idt::add_handler_to_irq(APIC_TIMER_VECTOR, handler);
+}
+
+// Runs the handler on whichever CPU an IPI is sent to
+pub fn set_ipi_handler(handler: Box<dyn Fn() + Send + Sync>) {
+    idt::add_handler_to_irq(IPI_VECTOR, handler);
+}
+
+// Sends an IPI to every CPU other than the one this is called on
+pub fn send_ipi_to_others() {
+    local_apic::send_to_others(IPI_VECTOR);
+}
+
+// Stops every CPU but this one for good, for when the machine is going down. An NMI is used, so that CPUs with
+// interrupts disabled stop too. Gives up waiting after STOP_TIMEOUT_NS, in case one never answers.
+pub fn stop_other_cpus() {
+    let others = scheduler::online_cpu_mask().count_ones() as u64 - 1;
+    idt::stop_on_nmi();
+    local_apic::send_nmi_to_others();
+
+    let deadline = time::get_monotonic_ns() + STOP_TIMEOUT_NS;
+    while idt::stopped_cpus() < others {
+	if time::get_monotonic_ns() >= deadline {
+	    log::warn!("Only {} of {} other CPUs stopped", idt::stopped_cpus(), others);
+	    return;
+	}
+	core::hint::spin_loop();
+    }
+}
+
+// Starts the local APIC timer of the CPU this is called on
+pub fn start_apic_timer(period_ns: u64) {
+    local_apic::start_timer(APIC_TIMER_VECTOR, period_ns);
+}
+
+pub fn init_sysfs() {
+    fs::sysfs::add_attribute("/kernel/irq/spurious", || format!("{}\n", idt::spurious_interrupt_count()));
 }
 
 pub fn init_idt() {
@@ -47,12 +165,42 @@ pub fn init_bsp_apic() {
     io_apic::init_io_apics(bsp_apic_id);
 }
 
+// Readies an application processor to take interrupts. The IDT is shared by every CPU. Once its local APIC is enabled,
+// GSIs may be routed to it as well.
+pub fn init_ap() {
+    idt::init();
+    let apic_id = local_apic::init_ap_local_apic();
+    io_apic::add_cpu(apic_id as u32);
+}
+
+// An INIT leaves an application processor waiting for a startup IPI, which starts it in real mode at the start of the
+// given page of the first 1MiB
+pub fn send_init_ipi(apic_id: u32) {
+    local_apic::send_init(apic_id);
+}
+
+pub fn send_startup_ipi(apic_id: u32, page: u8) {
+    local_apic::send_startup(apic_id, page);
+}
+
+pub fn bsp_apic_id() -> u32 {
+    io_apic::get_bsp_apic_id()
+}
+
 pub fn enable_interrupts() {
     acpi::set_interrupt_model(acpi::uacpi_interrupt_model::UACPI_INTERRUPT_MODEL_IOAPIC).expect("Unable to switch into IO APIC mode");    
 }
 
+// For the interrupts uACPI asks for, the SCI in particular. ACPI interrupts are level triggered and active low unless
+// the MADT says otherwise.
+pub fn enable_acpi_irq(irq: u8, handler: Box<dyn Fn() + Send + Sync>) {
+    io_apic::set_irq_bus_defaults(irq, TriggerMode::Level, Polarity::ActiveLow);
+    InterruptRoute::Irq(irq).register_handler(handler);
+}
+
+// For the HPET, whose interrupts are level triggered and active high
 pub fn enable_gsi(gsi: u32, handler: &'static (dyn Fn() + Send + Sync)) {
-    let irq = io_apic::get_irq_for_gsi(gsi);
+    let irq = vector_for_gsi(gsi, TriggerMode::Level, Polarity::ActiveHigh);
     log::info!("GSI = {}, IRQ = {}", gsi, irq);
     idt::add_handler_to_irq(irq, Box::new(handler));
 
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(allocator_api)]
+#![feature(btreemap_alloc)]
 #![feature(ascii_char)]
 #![feature(ascii_char_variants)]
 #![feature(alloc_layout_extra)]
@@ -12,7 +13,6 @@
 extern crate alloc;
 
 use core::panic::PanicInfo;
-use fixed::{types::extra::U3, FixedU64};
 use spin::Once;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -21,6 +21,7 @@ use alloc::boxed::Box;
 
 use limine::request::{
     EntryPointRequest,
+    ExecutableFileRequest,
     FramebufferRequest,
     MemoryMapRequest,
     HhdmRequest,
@@ -33,6 +34,7 @@ use limine::BaseRevision;
 use limine::memory_map::EntryType;
 
 mod interrupts;
+mod cmdline;
 mod gdt;
 mod memory;
 mod allocator;
@@ -49,6 +51,7 @@ mod dma;
 mod utils;
 mod console;
 mod process;
+mod smp;
 mod vfs;
 
 use crate::sys::syscall;
@@ -78,6 +81,10 @@ static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
 #[link_section = ".requests"]
 static ENTRY_POINT_REQUEST: EntryPointRequest = EntryPointRequest::new().with_entry_point(kmain);
 
+#[used]
+#[link_section = ".requests"]
+static EXECUTABLE_FILE_REQUEST: ExecutableFileRequest = ExecutableFileRequest::new();
+
 #[used]
 #[link_section = ".requests"]
 static STACK_SIZE_REQUEST: StackSizeRequest = StackSizeRequest::new().with_size(200 * 1024);
@@ -92,6 +99,14 @@ static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
 pub static PRINTK: Once<printk::LockedPrintk> = Once::new();
 
+// How long init_setup waits for the root filesystem to appear, and how long it sleeps between looking
+const ROOTFS_WAIT_TIMEOUT_MS: u64 = 30_000;
+const ROOTFS_WAIT_INITIAL_BACKOFF_MS: u64 = 10;
+const ROOTFS_WAIT_MAX_BACKOFF_MS: u64 = 500;
+
+// Run as the first process, unless init= says otherwise
+const DEFAULT_INIT: &str = "/usr/bin/init";
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     // unsafe {
@@ -106,17 +121,22 @@ fn panic(info: &PanicInfo) -> ! {
     // 	printk.clear();
     // }
     log::error!("{}", info);
+    utils::backtrace::log_backtrace();
     loop {}
 }
 
 fn init() {
     assert!(BASE_REVISION.is_supported());
 
+    if let Some(executable_file) = EXECUTABLE_FILE_REQUEST.get_response() {
+	cmdline::init(executable_file.file().string().to_str().unwrap_or(""));
+    }
+
     if let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response() {
 	if let Some(framebuffer) = framebuffer_response.framebuffers().next() {
 	    let kernel_logger = PRINTK.call_once(move || printk::LockedPrintk::new(framebuffer));
 	    log::set_logger(kernel_logger).expect("Logger already set");
-	    log::set_max_level(log::LevelFilter::Trace);
+	    cmdline::set_log_level();
 	} else {
 	    panic!();
 	}
@@ -149,6 +169,7 @@ fn init() {
     memory::init(direct_map_offset, memory_map.entries());
     allocator::init();
     memory::init_full_mode();
+    smp::init();
 
     log::info!("Bringing up BSP");
     gdt::init();
@@ -157,7 +178,7 @@ fn init() {
     drivers::pcie::init_pci_subsystem_for_acpi();
 
     let usable_ram = memory::get_usable_ram();
-    log::info!("Total usable RAM: {} MiB", (FixedU64::<U3>::from_num(usable_ram) / FixedU64::<U3>::from_num(1024 * 1024)));
+    log::info!("Total usable RAM: {}", utils::fixed_point::format_bytes(usable_ram));
 
     let rsdp_addr = RSDP_REQUEST.get_response().expect("Limine did not return RDSP pointer.").address() as u64;
     sys::acpi::init(rsdp_addr - direct_map_offset);
@@ -166,12 +187,23 @@ fn init() {
     vfs::init();
 
     scheduler::init();
+    scheduler::executor::init();
     driver::init();
     console::init();
+    fs::sysfs::init();
+    interrupts::init_sysfs();
+    allocator::init_sysfs();
+    allocator::register_shrinkers();
+    memory::reclaim::init();
+    scheduler::trace::init();
     sys::block::init();
+    sys::kmsg::init();
+    sys::loglevel::init();
+    sys::random::init();
     drivers::init();
 
     driver::configure_drivers();
+    sys::power::init_power_button();
 
     sys::syscall::init();
 }
@@ -184,9 +216,32 @@ extern "C" fn kmain() -> ! {
     // }
 
     scheduler::kthread_start(init_setup);
+    smp::start_aps();
     scheduler::start();
 }
 
+// The disk may take a while to appear, so we back off between looking for it rather than spinning, but give up
+// eventually. Fails with how long was waited.
+fn wait_for_rootfs(
+    mut found: impl FnMut() -> bool,
+    mut elapsed_ms: impl FnMut() -> u64,
+    mut sleep_ms: impl FnMut(u64)) -> Result<(), u64> {
+    let mut backoff_ms = ROOTFS_WAIT_INITIAL_BACKOFF_MS;
+    loop {
+	if found() {
+	    return Ok(());
+	}
+
+	let waited_ms = elapsed_ms();
+	if waited_ms >= ROOTFS_WAIT_TIMEOUT_MS {
+	    return Err(waited_ms);
+	}
+
+	sleep_ms(backoff_ms);
+	backoff_ms = core::cmp::min(backoff_ms * 2, ROOTFS_WAIT_MAX_BACKOFF_MS);
+    }
+}
+
 // TODO - this will need to mount the rootfs, as that can no longer happen in the boot context due to async code
 // TODO - anywhere where a syscall will write to user memory, expectations now break; before, we were snooping memory from current PID. That doens't work any more.
 fn init_setup() -> ! {
@@ -197,6 +252,20 @@ fn init_setup() -> ! {
 	log::info!("Couldn't mount devfs: {}", res.err_num);
     }
 
+    let mount_sysfs_fut = Box::pin(fs::sysfs::mount_sysfs());
+    let res = unsafe { async_kcall::do_kasync(mount_sysfs_fut) };
+
+    if res.err_num != 0 {
+	log::info!("Couldn't mount sysfs: {}", res.err_num);
+    }
+
+    let mount_tmpfs_fut = Box::pin(fs::tmpfs::mount_tmpfs());
+    let res = unsafe { async_kcall::do_kasync(mount_tmpfs_fut) };
+
+    if res.err_num != 0 {
+	log::info!("Couldn't mount tmpfs: {}", res.err_num);
+    }
+
     // // TMP: start console
     // unsafe {
     // 	let console_cstring = CString::new("/dev/console").unwrap();
@@ -210,7 +279,8 @@ fn init_setup() -> ! {
     // }
 
     // Run init
-    let path_cstring = CString::new("/usr/bin/init").unwrap();
+    let init_path = cmdline::get("init").unwrap_or(DEFAULT_INIT);
+    let path_cstring = CString::new(init_path).unwrap();
     let args_strs: Vec<&str> = vec![];
     let env_strs: Vec<&str> = vec!["PATH=/bin", "USER=root"];
 
@@ -236,15 +306,27 @@ fn init_setup() -> ! {
     let args_ptr = args_ptrs.as_ptr() as u64;
     let envvars_ptr = env_ptrs.as_ptr() as u64;
 
-    // Loop until we can stat init executable; that implies the filesystem has been successfully mounted
-    loop {
+    // Wait until we can stat init executable; that implies the filesystem has been successfully mounted
+    let wait_start_ns = sys::time::get_monotonic_ns();
+    let found = || {
 	let (ret, _) = unsafe {
 	    syscall::do_syscall6(0x05, path_ptr, 0, 0, 0, 0, 0)
 	};
-
-	if ret == 0 {
-	    break;
+	ret == 0
+    };
+    let elapsed_ms = || (sys::time::get_monotonic_ns() - wait_start_ns) / 1_000_000;
+    let sleep_ms = |ms: u64| {
+	let sleep = syscall::TimeSpec {
+	    tv_sec: 0,
+	    tv_nsec: (ms * 1_000_000) as i64,
+	};
+	unsafe {
+	    syscall::do_syscall6(0x23, &sleep as *const syscall::TimeSpec as u64, 0, 0, 0, 0, 0);
 	}
+    };
+    if let Err(waited_ms) = wait_for_rootfs(found, elapsed_ms, sleep_ms) {
+	log::error!("Failed to mount rootfs: {} still not found after {}ms", init_path, waited_ms);
+	panic!("No rootfs");
     }
 
     // Actually run init
@@ -254,3 +336,30 @@ fn init_setup() -> ! {
 
     panic!("init exec failed");
 }
+
+#[test]
+fn waiting_for_the_rootfs_backs_off_and_gives_up() {
+    use core::cell::Cell;
+
+    // Only time spent asleep passes
+    let now_ms = Cell::new(0);
+    let sleeps = Cell::new(0);
+    let result = wait_for_rootfs(|| false, || now_ms.get(), |ms| {
+	now_ms.set(now_ms.get() + ms);
+	sleeps.set(sleeps.get() + 1);
+    });
+    assert!(matches!(result, Err(waited_ms) if waited_ms >= ROOTFS_WAIT_TIMEOUT_MS));
+    assert!(now_ms.get() < ROOTFS_WAIT_TIMEOUT_MS + ROOTFS_WAIT_MAX_BACKOFF_MS);
+    // Doubling from 10ms takes 6 sleeps, 630ms in all, before reaching 500ms, and the rest is spent 500ms at a time
+    assert_eq!(sleeps.get(), 6 + (ROOTFS_WAIT_TIMEOUT_MS - 630).div_ceil(ROOTFS_WAIT_MAX_BACKOFF_MS));
+
+    // Turning up on the third look
+    let looks = Cell::new(0);
+    let mut slept = Vec::new();
+    let result = wait_for_rootfs(|| {
+	looks.set(looks.get() + 1);
+	looks.get() == 3
+    }, || 0, |ms| slept.push(ms));
+    assert!(result.is_ok());
+    assert_eq!(slept, [ROOTFS_WAIT_INITIAL_BACKOFF_MS, 2 * ROOTFS_WAIT_INITIAL_BACKOFF_MS]);
+}
@@ -26,6 +26,7 @@ pub struct VenixFrameAllocator {
 
     // Full mode
     free_regions: Option<BTreeMap<u64, MemoryRegion>>,
+    free_frames: u64,
 }
 
 impl VenixFrameAllocator {
@@ -33,7 +34,8 @@ impl VenixFrameAllocator {
 	VenixFrameAllocator {
 	    memory_map,
 	    next: 0,
-	    free_regions: None
+	    free_regions: None,
+	    free_frames: 0,
 	}
     }
 
@@ -62,6 +64,11 @@ impl VenixFrameAllocator {
 	    .sum()
     }
 
+    // There's no accounting in runt mode
+    pub fn get_free_frames(&self) -> Option<u64> {
+	self.free_regions.as_ref().map(|_| self.free_frames)
+    }
+
     pub fn move_to_full_mode(&mut self) {
 	let mut free_regions: BTreeMap<u64, MemoryRegion> = BTreeMap::new();
 
@@ -100,6 +107,9 @@ impl VenixFrameAllocator {
 	    }
 	}
 
+	self.free_frames = free_regions.values()
+	    .map(|r| (r.end - r.start) / 4096)
+	    .sum();
 	self.free_regions = Some(free_regions);
     }
 
@@ -142,8 +152,14 @@ impl VenixFrameAllocator {
 
     // This function assumes size has been rounded to the nearest page
     pub fn allocate_dma_frames(&mut self, size: u64) -> Option<PhysAddr> {
+	self.allocate_frames_below(size, 0x1_0000_0000)
+    }
+
+    // Contiguous frames ending below limit, taken from the lowest region they fit in. Size must be a whole number of
+    // pages.
+    pub fn allocate_frames_below(&mut self, size: u64, limit: u64) -> Option<PhysAddr> {
 	if !size.is_multiple_of(4096) {
-	    panic!("Attempted to allocate DMA frames to a non-page boundary");
+	    panic!("Attempted to allocate frames to a non-page boundary");
 	}
 
 	if let Some(ref mut free_regions) = self.free_regions {
@@ -152,14 +168,14 @@ impl VenixFrameAllocator {
 	    let mut start: Option<u64> = None;
 
 	    for (start_addr, region) in &*free_regions {
-		// If we get to this point and are greater than the 4 gig mark, it means
-		// either we're out of DMA memory, or couldn't find a region large enough.
+		// If we get to this point and are greater than the limit, it means
+		// either we're out of memory below it, or couldn't find a region large enough.
 		// Either way, OOM
 		//
 		// This covers both the case where the whole region is out of bounds,
 		// and also when the region starts in bounds, but isn't big enough to stay
 		// in bounds.
-		if start_addr + size >= 0x1_0000_0000 {
+		if start_addr + size >= limit {
 		    return None;
 		}
 
@@ -182,10 +198,11 @@ impl VenixFrameAllocator {
 		    });
 		}
 
+		self.free_frames -= size / 4096;
 		Some(PhysAddr::new(start_addr))
 	    } else {
 		// If start is None, it means we got to the end of the loop without finding a
-		// large enough region, but without getting out of DMA bounds.
+		// large enough region, but without getting out of bounds.
 		//
 		// Effectively, this is an OOM condition.
 		None
@@ -209,6 +226,7 @@ unsafe impl FrameAllocator<Size4KiB> for VenixFrameAllocator {
                 if end_addr - start_addr == 4096 {
                     // Allocate the first frame and remove the region
                     free_regions.remove(&start_addr); // Remove the region from the map
+		    self.free_frames -= 1;
                     return Some(PhysFrame::containing_address(PhysAddr::new(start_addr)));
                 }
 
@@ -223,6 +241,7 @@ unsafe impl FrameAllocator<Size4KiB> for VenixFrameAllocator {
                 });
 
                 // Return the allocated frame
+		self.free_frames -= 1;
                 return Some(PhysFrame::containing_address(PhysAddr::new(new_end)));
             }
 
@@ -262,6 +281,7 @@ impl FrameDeallocator<Size4KiB> for VenixFrameAllocator {
 
             // Try merging the new region with the existing free regions
             Self::try_merge_regions(free_regions, new_region);
+	    self.free_frames += 1;
 	} else {
 	    panic!("Attempted to deallocate while in runt mode");
 	}
@@ -1,29 +1,37 @@
-use spin::{Once, RwLock};
+use spin::{Mutex, Once, RwLock};
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::structures::paging::{
     frame::PhysFrame,
     mapper::MapToError,
     FrameAllocator,
+    FrameDeallocator,
     Mapper,
     Size4KiB,
     Page,
     PageTable,
     PageTableFlags,
     OffsetPageTable,
+    Translate,
 };
 use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use alloc::vec;
 use alloc::vec::Vec;
+use alloc::collections::{BTreeMap, BTreeSet};
 use limine::memory_map::Entry;
 use alloc::slice;
 use alloc::string::String;
 use alloc::fmt;
+use core::alloc::Allocator;
 use core::error::Error;
 
 mod frame_allocator;
 mod page_allocator;
 pub mod user_address_space;
+pub mod reclaim;
 use crate::scheduler;
 use crate::process;
+use crate::sys::syscall::CanonicalError;
 
 static KERNEL_PAGE_TABLE: RwLock<Option<OffsetPageTable>> = RwLock::new(None);
 static KERNEL_PAGE_FRAME: RwLock<Option<PhysFrame>> = RwLock::new(None);
@@ -32,6 +40,31 @@ static VENIX_PAGE_ALLOCATOR: RwLock<Option<page_allocator::VenixPageAllocator>>
 
 static DIRECT_MAP_OFFSET: Once<u64> = Once::new();
 
+// The unmapped page below each kernel stack, so that running off the end of one faults, rather than quietly
+// overwriting whatever was allocated below it
+static KERNEL_STACK_GUARDS: Mutex<BTreeSet<VirtAddr>> = Mutex::new(BTreeSet::new());
+
+// What a user page must be mapped with for the kernel to copy out of it, or into it, on a process's behalf
+const USER_READABLE_PAGE: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::USER_ACCESSIBLE);
+const USER_WRITABLE_PAGE: PageTableFlags = USER_READABLE_PAGE.union(PageTableFlags::WRITABLE);
+
+// Marks, in both the page tables and the shadow map, a page which the process may write to but which is mapped read
+// only for now, sharing its frame. It's given a frame of its own the first time it's written to
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+// Marks, in the shadow map only, a page which has been reserved but not yet backed by anything. It's given a frame
+// (or the zero frame, if only read) the first time it's touched
+const DEMAND_ZERO: PageTableFlags = PageTableFlags::BIT_10;
+// Page tables above a user page have to allow everything the page itself might later be mapped with
+const USER_PARENT_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+// Backs every page of anonymous memory which hasn't been written to yet
+static ZERO_FRAME: Once<PhysFrame> = Once::new();
+// How many address spaces map each frame which is shared copy-on-write between more than one, as after fork. Any
+// frame not in here (other than the zero frame) belongs to just one
+static FRAME_SHARERS: Mutex<BTreeMap<PhysAddr, u64>> = Mutex::new(BTreeMap::new());
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct MemoryRegion {
     pub start: u64,
@@ -104,6 +137,11 @@ pub fn get_usable_ram() -> u64 {
     r.as_ref().expect("Attempted to read missing frame allocator").get_usable_memory()
 }
 
+pub fn get_free_frames() -> Option<u64> {
+    let r = VENIX_FRAME_ALLOCATOR.read();
+    r.as_ref().expect("Attempted to read missing frame allocator").get_free_frames()
+}
+
 pub fn kernel_allocate_early(size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
     let page_range = {
 	let start = {
@@ -139,6 +177,8 @@ pub fn user_allocate(
     size: u64,
     access_restriction: MemoryAccessRestriction,
     address_space: &mut user_address_space::AddressSpace) -> Result<(VirtAddr, Vec<PhysAddr>), MapToError<Size4KiB>> {
+    reclaim::reclaim_if_low();
+
     let page_range = {
 	let start = match access_restriction {
 	    MemoryAccessRestriction::User => address_space.get_page_range(size),
@@ -158,12 +198,27 @@ pub fn user_allocate(
 
     let frame_range: Vec<PhysFrame> = {
 	let mut range = Vec::new();	    
-	let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
 
 	for _ in page_range {
-	    let frame = frame_allocator.as_mut().expect("Attempted to use missing frame allocator").allocate_frame()
-		.ok_or(MapToError::FrameAllocationFailed)?;
-	    range.push(frame);
+	    // The frame allocator lock is only held per frame, as the OOM killer needs it to free the victim's memory
+	    let frame = reclaim::allocate_or_oom_kill(|| {
+		let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+		frame_allocator.as_mut().expect("Attempted to use missing frame allocator").allocate_frame()
+		    .ok_or(MapToError::FrameAllocationFailed)
+	    });
+
+	    match frame {
+		Ok(frame) => range.push(frame),
+		Err(e) => {
+		    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+		    for frame in range {
+			unsafe {
+			    frame_allocator.as_mut().expect("Attempted to use missing frame allocator").deallocate_frame(frame);
+			}
+		    }
+		    return Err(e);
+		},
+	    }
 	}
 
 	range
@@ -184,11 +239,11 @@ pub fn user_allocate(
     let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
 
     for (page, &frame) in page_range.zip(frame_range.iter()) {
-	address_space.assign_virt_phys(page.start_address(), frame.start_address());
 	let flags = match access_restriction {
 	    MemoryAccessRestriction::User => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
 	    MemoryAccessRestriction::UserByStart(_) => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
 	};
+	address_space.assign_virt_phys(page.start_address(), frame.start_address(), flags);
 
 	unsafe {
 	    mapper.map_to(page, frame, flags, frame_allocator.as_mut().expect("Attempted to use missing frame allocator"))?.flush();
@@ -198,9 +253,329 @@ pub fn user_allocate(
     Ok((page_range.start.start_address(), frame_range.iter().map(|frame| frame.start_address()).collect()))
 }
 
+fn zero_frame() -> PhysFrame {
+    *ZERO_FRAME.call_once(|| {
+	let frame = VENIX_FRAME_ALLOCATOR.write().as_mut().expect("Attempted to use missing frame allocator").allocate_frame()
+	    .expect("Couldn't allocate the zero frame");
+	unsafe {
+	    slice::from_raw_parts_mut(get_ptr_in_hhdm(frame.start_address()).as_mut_ptr::<u8>(), 4096).fill(0);
+	}
+
+	frame
+    })
+}
+
+fn is_zero_frame(phys: PhysAddr) -> bool {
+    ZERO_FRAME.get().is_some_and(|frame| frame.start_address() == phys)
+}
+
+// Notes that one more address space maps a frame
+fn share_frame(phys: PhysAddr) {
+    if !is_zero_frame(phys) {
+	*FRAME_SHARERS.lock().entry(phys).or_insert(1) += 1;
+    }
+}
+
+fn is_frame_shared(phys: PhysAddr) -> bool {
+    is_zero_frame(phys) || FRAME_SHARERS.lock().contains_key(&phys)
+}
+
+// Drops one address space's use of a frame. Returns whether that was the last use, in which case the caller should
+// free it. The zero frame is shared by every address space, so is never freed
+fn release_frame(phys: PhysAddr) -> bool {
+    if is_zero_frame(phys) {
+	return false;
+    }
+
+    let mut sharers = FRAME_SHARERS.lock();
+    match sharers.get_mut(&phys) {
+	Some(count) => {
+	    *count -= 1;
+	    if *count == 1 {
+		sharers.remove(&phys);
+	    }
+	    false
+	},
+	None => true,
+    }
+}
+
+fn user_page_table(address_space: &user_address_space::AddressSpace) -> OffsetPageTable<'static> {
+    let direct_map_offset = DIRECT_MAP_OFFSET.get().expect("No direct map offset");
+    let pt4_ptr = (address_space.get_pt4() + direct_map_offset) as *mut PageTable;
+
+    unsafe {
+	OffsetPageTable::new(&mut *pt4_ptr, VirtAddr::new(*direct_map_offset))
+    }
+}
+
+// As user_allocate, but for memory which starts out zeroed. Rather than each page getting a frame straight away, they
+// all share the zero frame until written to, so that large allocations which are mostly left untouched (e.g. stacks)
+// cost next to nothing
+pub fn user_allocate_anonymous(
+    size: u64,
+    access_restriction: MemoryAccessRestriction,
+    address_space: &mut user_address_space::AddressSpace) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let page_range = {
+	let start = match access_restriction {
+	    MemoryAccessRestriction::User => address_space.get_page_range(size),
+	    MemoryAccessRestriction::UserByStart(addr) => match address_space.get_page_range_from_start(addr, size as usize) {
+		Ok(_) => addr,
+		Err(_) => panic!("Couldn't get memory at 0x{:x}, already allocated", addr.as_u64()),
+	    }
+	};
+
+	let end = start + (size - 1);
+	Page::range_inclusive(Page::<Size4KiB>::containing_address(start), Page::containing_address(end))
+    };
+
+    let zero_frame = zero_frame();
+    let flags = demand_zero_flags(false);
+    let mut mapper = user_page_table(address_space);
+    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+
+    for page in page_range {
+	address_space.assign_virt_phys(page.start_address(), zero_frame.start_address(), flags);
+
+	unsafe {
+	    mapper.map_to_with_table_flags(
+		page, zero_frame, flags, USER_PARENT_TABLE_FLAGS,
+		frame_allocator.as_mut().expect("Attempted to use missing frame allocator"))?.flush();
+	};
+    }
+
+    Ok(page_range.start.start_address())
+}
+
+// The bootloader turns on no-execute if the CPU has it. Without it, NO_EXECUTE is a reserved bit, and mustn't be set.
+fn no_execute_supported() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+// As user_allocate_anonymous, but nothing at all is mapped until the process touches a page, at which point the page
+// fault handler backs it. Page tables are only made for the parts actually used, so reserving a lot costs nothing.
+// Unless executable, the pages are no-execute once backed.
+pub fn user_reserve(
+    size: u64,
+    access_restriction: MemoryAccessRestriction,
+    executable: bool,
+    address_space: &mut user_address_space::AddressSpace) -> VirtAddr {
+    let page_range = {
+	let start = match access_restriction {
+	    MemoryAccessRestriction::User => address_space.get_page_range(size),
+	    MemoryAccessRestriction::UserByStart(addr) => match address_space.get_page_range_from_start(addr, size as usize) {
+		Ok(_) => addr,
+		Err(_) => panic!("Couldn't get memory at 0x{:x}, already allocated", addr.as_u64()),
+	    }
+	};
+
+	let end = start + (size - 1);
+	Page::range_inclusive(Page::<Size4KiB>::containing_address(start), Page::containing_address(end))
+    };
+
+    let flags = if executable || !no_execute_supported() {
+	DEMAND_ZERO
+    } else {
+	DEMAND_ZERO | PageTableFlags::NO_EXECUTE
+    };
+    for page in page_range {
+	address_space.assign_virt_phys(page.start_address(), PhysAddr::new(0), flags);
+    }
+
+    page_range.start.start_address()
+}
+
+// Only a write needs a frame of its own. Anything else shares the zero frame, until it's written to
+fn demand_zero_flags(write: bool) -> PageTableFlags {
+    if write {
+	PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+    } else {
+	PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | COPY_ON_WRITE
+    }
+}
+
+// Backs a reserved page on its first touch. A read just maps the zero frame, copy-on-write, as for anonymous memory,
+// so that reading a stack or heap all the way through still doesn't use up any memory
+fn back_demand_zero_page(
+    address_space: &mut user_address_space::AddressSpace, virt: VirtAddr, write: bool) -> Result<(), CopyError> {
+    let page: Page<Size4KiB> = Page::from_start_address(virt).expect("Malformed start address");
+    let no_execute = address_space.mapped_regions.get(&virt)
+	.map_or(PageTableFlags::empty(), |(_, flags)| *flags & PageTableFlags::NO_EXECUTE);
+    let flags = demand_zero_flags(write) | no_execute;
+    let frame = if write {
+	let frame = reclaim::allocate_or_oom_kill(|| {
+	    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+	    frame_allocator.as_mut().expect("Attempted to use missing frame allocator").allocate_frame()
+		.ok_or(CopyError::TempAllocFailed)
+	})?;
+	unsafe {
+	    slice::from_raw_parts_mut(get_ptr_in_hhdm(frame.start_address()).as_mut_ptr::<u8>(), 4096).fill(0);
+	}
+	frame
+    } else {
+	zero_frame()
+    };
+
+    let mut mapper = user_page_table(address_space);
+    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+    let mapped = unsafe {
+	mapper.map_to_with_table_flags(
+	    page, frame, flags, USER_PARENT_TABLE_FLAGS,
+	    frame_allocator.as_mut().expect("Attempted to use missing frame allocator"))
+    };
+
+    match mapped {
+	Ok(flush) => flush.flush(),
+	Err(_) => {
+	    if write {
+		unsafe {
+		    frame_allocator.as_mut().expect("Attempted to use missing frame allocator").deallocate_frame(frame);
+		}
+	    }
+	    return Err(CopyError::Fault);
+	},
+    }
+
+    address_space.assign_virt_phys(virt, frame.start_address(), flags);
+    Ok(())
+}
+
+// Gives a copy-on-write page a frame of its own, holding a copy of what it showed before, and makes it writable. If
+// everything else sharing the frame has already done the same, or gone, it's just made writable
+fn break_copy_on_write(address_space: &mut user_address_space::AddressSpace, virt: VirtAddr) -> Result<(), CopyError> {
+    let (old_phys, flags) = match address_space.mapped_regions.get(&virt) {
+	Some(&(phys, flags)) if flags.contains(COPY_ON_WRITE) => (phys, flags),
+	_ => return Ok(()),
+    };
+
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let page: Page<Size4KiB> = Page::from_start_address(virt).expect("Malformed start address");
+    let mut mapper = user_page_table(address_space);
+
+    if !is_frame_shared(old_phys) {
+	unsafe {
+	    mapper.update_flags(page, flags).map_err(|_| CopyError::Fault)?.flush();
+	}
+	address_space.assign_virt_phys(virt, old_phys, flags);
+	return Ok(());
+    }
+
+    let frame = reclaim::allocate_or_oom_kill(|| {
+	let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+	frame_allocator.as_mut().expect("Attempted to use missing frame allocator").allocate_frame()
+	    .ok_or(CopyError::TempAllocFailed)
+    })?;
+
+    unsafe {
+	let from = slice::from_raw_parts(get_ptr_in_hhdm(old_phys).as_ptr::<u8>(), 4096);
+	let to = slice::from_raw_parts_mut(get_ptr_in_hhdm(frame.start_address()).as_mut_ptr::<u8>(), 4096);
+	to.copy_from_slice(from);
+    }
+
+    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+    let frame_allocator = frame_allocator.as_mut().expect("Attempted to use missing frame allocator");
+    let remapped = match mapper.unmap(page) {
+	Ok((_, flush)) => {
+	    flush.flush();
+	    unsafe {
+		mapper.map_to_with_table_flags(page, frame, flags, USER_PARENT_TABLE_FLAGS, frame_allocator)
+	    }.map_err(|_| CopyError::Fault)
+	},
+	Err(_) => Err(CopyError::Fault),
+    };
+
+    match remapped {
+	Ok(flush) => flush.flush(),
+	// Nothing has the copy, so it goes straight back
+	Err(e) => {
+	    unsafe {
+		frame_allocator.deallocate_frame(frame);
+	    }
+	    return Err(e);
+	},
+    }
+    address_space.assign_virt_phys(virt, frame.start_address(), flags);
+
+    // The others sharing the old frame may have let go of it while we were copying
+    if release_frame(old_phys) {
+	unsafe {
+	    frame_allocator.deallocate_frame(PhysFrame::containing_address(old_phys));
+	}
+    }
+
+    Ok(())
+}
+
+// Makes sure a page is backed, and if it's about to be written to, that it has a frame of its own to write to. Pages
+// which are neither reserved nor copy-on-write are left alone
+fn fault_in_page(address_space: &mut user_address_space::AddressSpace, virt: VirtAddr, write: bool) -> Result<(), CopyError> {
+    if address_space.mapped_regions.get(&virt).is_some_and(|(_, flags)| flags.contains(DEMAND_ZERO)) {
+	back_demand_zero_page(address_space, virt, write)?;
+    }
+
+    if write {
+	break_copy_on_write(address_space, virt)?;
+    }
+
+    Ok(())
+}
+
+// What a page fault in userspace turned out to be
+pub enum UserFault {
+    // The page was only reserved, or was copy-on-write and being written to, and has now been backed, so the access
+    // can be retried
+    Resolved,
+    // Just below a mapped region, and near the stack pointer, so most likely the stack running off its end
+    StackOverflow,
+    // Nothing is mapped there
+    Unmapped,
+    // Something is mapped there, but not for this kind of access
+    Protection,
+    // The page should have been backed, but there was nothing to back it with
+    NoBacking,
+}
+
+// How far below the stack pointer an access can be and still count as the stack growing, enough for a big push or
+// a function's locals
+const STACK_OVERFLOW_REACH: u64 = 64 * 1024;
+
+// Called on a page fault caused by the running process touching a page it couldn't
+pub fn handle_user_page_fault(addr: VirtAddr, write: bool, rsp: u64) -> UserFault {
+    let process = scheduler::get_current_process();
+    let mut task_type = process.task_type.write();
+    let address_space = match *task_type {
+	process::TaskType::User(ref mut address_space) => address_space,
+	process::TaskType::Kernel => return UserFault::Unmapped,
+    };
+
+    let page = addr.align_down(4096_u64);
+    match address_space.mapped_regions.get(&page) {
+	Some((_, flags)) if flags.contains(DEMAND_ZERO) || (write && flags.contains(COPY_ON_WRITE)) =>
+	    match fault_in_page(address_space, page, write) {
+		Ok(()) => UserFault::Resolved,
+		Err(_) => UserFault::NoBacking,
+	    },
+	Some(_) => UserFault::Protection,
+	None => {
+	    let below_mapping = address_space.mapped_regions.contains_key(&(page + 4096_u64));
+	    let near_stack_pointer = addr.as_u64() < rsp.saturating_add(4096) &&
+		addr.as_u64() >= rsp.saturating_sub(STACK_OVERFLOW_REACH);
+
+	    if below_mapping && near_stack_pointer {
+		UserFault::StackOverflow
+	    } else {
+		UserFault::Unmapped
+	    }
+	},
+    }
+}
+
 pub fn kernel_allocate(
     size: u64,
     alloc_type: MemoryAllocationType) -> Result<(VirtAddr, Vec<PhysAddr>), MapToError<Size4KiB>> {
+    if alloc_type == MemoryAllocationType::Ram || alloc_type == MemoryAllocationType::Dma {
+	reclaim::reclaim_if_low();
+    }
 
     let page_range = {
 	let start = {
@@ -265,6 +640,36 @@ pub fn kernel_allocate(
     Ok((page_range.start.start_address(), frame_range.iter().map(|frame| frame.start_address()).collect()))
 }
 
+// Physically contiguous frames in the first 1MiB, for code which starts in real mode. They aren't mapped anywhere but
+// the HHDM.
+pub fn allocate_low_frames(size: u64) -> Option<PhysAddr> {
+    let aligned_size = size.div_ceil(4096) * 4096;
+
+    let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+    frame_allocator.as_mut().expect("Attempted to use missing frame allocator").allocate_frames_below(aligned_size, 0x10_0000)
+}
+
+// Allocates a kernel stack with an unmapped guard page below it. Returns the bottom of the usable stack.
+pub fn kernel_allocate_stack(size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let (start, _) = kernel_allocate(size + 4096, MemoryAllocationType::Ram)?;
+
+    let guard: Page<Size4KiB> = Page::from_start_address(start).expect("Malformed start address");
+    let (frame, flush) = KERNEL_PAGE_TABLE.write().as_mut().unwrap().unmap(guard).expect("Unable to unmap stack guard page");
+    flush.flush();
+    unsafe {
+	VENIX_FRAME_ALLOCATOR.write().as_mut().expect("Attempted to use missing frame allocator").deallocate_frame(frame);
+    }
+
+    KERNEL_STACK_GUARDS.lock().insert(start);
+    Ok(start + 4096_u64)
+}
+
+// Whether a fault at addr is a kernel stack overflowing. Called from the page fault handler, so it gives up rather than
+// waiting if the list of guard pages is being added to.
+pub fn is_kernel_stack_guard(addr: VirtAddr) -> bool {
+    KERNEL_STACK_GUARDS.try_lock().is_some_and(|guards| guards.contains(&addr.align_down(4096_u64)))
+}
+
 // This function handles MMIO allocation. The reason we use it, rather than calling kernel_allocate directly, is that
 // in theory, an MMIO region may span page boundaries, and the caller should not be expected to properly align.
 //
@@ -287,11 +692,60 @@ pub fn allocate_mmio(
     Ok(virt_addr)
 }
 
+// Copies out as much of the kernel memory at addr as is mapped, returning how many bytes that was. For fault handlers
+// reporting what they found, which mustn't fault again, or wait on a lock whatever faulted might be holding.
+pub fn peek_kernel(addr: VirtAddr, dst: &mut [u8]) -> usize {
+    let page_table = match KERNEL_PAGE_TABLE.try_read() {
+	Some(page_table) => page_table,
+	None => return 0,
+    };
+    let mapper = match page_table.as_ref() {
+	Some(mapper) => mapper,
+	None => return 0,
+    };
+
+    for (i, byte) in dst.iter_mut().enumerate() {
+	let mapped = addr.as_u64().checked_add(i as u64)
+	    .and_then(|a| VirtAddr::try_new(a).ok())
+	    .filter(|a| mapper.translate_addr(*a).is_some());
+	match mapped {
+	    Some(a) => *byte = unsafe { core::ptr::read_volatile(a.as_ptr::<u8>()) },
+	    None => return i,
+	}
+    }
+
+    dst.len()
+}
+
 pub fn get_ptr_in_hhdm(phys_addr: PhysAddr) -> VirtAddr {
     let hhdm = DIRECT_MAP_OFFSET.get().expect("Could not read HHDM");
     VirtAddr::new(phys_addr.as_u64() + hhdm)
 }
 
+// Anything at or above this is either non-canonical, or kernel space
+pub const USER_ADDRESS_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+// Checks that len bytes starting at a pointer passed in by a task are somewhere it may point, before anything is
+// copied. Kernel tasks pass kernel pointers into syscalls, so only user tasks are confined to the lower half.
+pub fn validate_user_ptr(addr: u64, len: u64) -> Result<VirtAddr, CanonicalError> {
+    let is_user = matches!(*scheduler::get_current_process().task_type.read(), process::TaskType::User(_));
+    validate_ptr(addr, len, is_user)
+}
+
+fn validate_ptr(addr: u64, len: u64, is_user: bool) -> Result<VirtAddr, CanonicalError> {
+    // Nothing is read or written through an empty buffer, so it's allowed to be null, as it often is
+    if addr == 0 && len != 0 {
+	return Err(CanonicalError::Fault);
+    }
+
+    let end = addr.checked_add(len).ok_or(CanonicalError::Fault)?;
+    if is_user && end > USER_ADDRESS_LIMIT {
+	return Err(CanonicalError::Fault);
+    }
+
+    VirtAddr::try_new(addr).map_err(|_| CanonicalError::Fault)
+}
+
 #[derive(Debug)]
 pub enum CopyError {
     Fault,               // page not present / translation failed
@@ -317,8 +771,21 @@ pub enum UserStringCopyError {
     InvalidUtf8,         // couldn't translate the string
 }
 
+// The frame behind a user page, so long as it's mapped with everything in required. The kernel reads and writes user
+// memory through its own mappings of the frames, so this is what stops a process having us write to memory it can
+// only read, or read memory it can't read at all. Pages not mapped at all are a Fault too.
+fn user_frame<A: Allocator + Clone>(
+    mapped_regions: &BTreeMap<VirtAddr, (PhysAddr, PageTableFlags), A>,
+    page: VirtAddr,
+    required: PageTableFlags) -> Result<PhysAddr, CopyError> {
+    match mapped_regions.get(&page) {
+	Some((phys, flags)) if flags.contains(required) => Ok(*phys),
+	_ => Err(CopyError::Fault),
+    }
+}
+
 fn copy_to_user_internal(
-    address_space: &user_address_space::AddressSpace, dest: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
+    address_space: &mut user_address_space::AddressSpace, dest: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
     if src.is_empty() {
 	return Ok(());
     }
@@ -338,10 +805,12 @@ fn copy_to_user_internal(
     for _ in 0..n_pages {
         // Translate the start of this page (virtual address)
         let page_base_vaddr = VirtAddr::new((cur_vaddr / 4096) * 4096);
-        match address_space.mapped_regions.get(&page_base_vaddr) {
-            Some(phys_page_base) => phys_pages.push(*phys_page_base),  // TODO: this should validate permissions. The page should be both user accessible, and writable
-            None => return Err(CopyError::Fault),  // Page is not in the shadow map. Ithasn't been mapped
-        }
+        // Writing through our own mapping of a shared frame would change it for everything sharing it, so the page
+        // is given its own first, just as a write from the process itself would. Likewise, a page only reserved is
+        // backed first
+        fault_in_page(address_space, page_base_vaddr, true)?;
+
+        phys_pages.push(user_frame(&address_space.mapped_regions, page_base_vaddr, USER_WRITABLE_PAGE)?);
 
         // advance
         cur_vaddr = page_base_vaddr.as_u64() + 4096; // next page start (even if dest started in middle)
@@ -369,9 +838,10 @@ fn copy_to_user_internal(
 }
 
 fn copy_from_user_internal(
-    address_space: &user_address_space::AddressSpace, src: VirtAddr, len: usize) -> Result<Vec<u8>, CopyError> {
+    address_space: &mut user_address_space::AddressSpace, src: VirtAddr, dst: &mut [u8]) -> Result<usize, CopyError> {
+    let len = dst.len();
     if len == 0 {
-	return Ok(Vec::new());
+	return Ok(0);
     }
 
     let first_page_index = src.as_u64() / 4096;
@@ -388,10 +858,8 @@ fn copy_from_user_internal(
     for _ in 0..n_pages {
         // Translate the start of this page (virtual address)
         let page_base_vaddr = VirtAddr::new((cur_vaddr / 4096) * 4096);
-        match address_space.mapped_regions.get(&page_base_vaddr) {
-            Some(phys_page_base) => phys_pages.push(*phys_page_base),  // TODO: this should validate permissions. The page should be both user accessible, and writable
-            None => return Err(CopyError::Fault),  // Page is not in the shadow map. Ithasn't been mapped
-        }
+        fault_in_page(address_space, page_base_vaddr, false)?;
+        phys_pages.push(user_frame(&address_space.mapped_regions, page_base_vaddr, USER_READABLE_PAGE)?);
 
         // advance
         cur_vaddr = page_base_vaddr.as_u64() + 4096; // next page start (even if dest started in middle)
@@ -402,7 +870,7 @@ fn copy_from_user_internal(
 	Err(_) => return Err(CopyError::TempAllocFailed),
     };
 
-    let mut result: Vec<u8> = Vec::with_capacity(len);
+    let mut copied = 0;
     let mut remaining = len;
 
     for i in 0 .. n_pages {
@@ -417,12 +885,13 @@ fn copy_from_user_internal(
         let available_in_page = 4096_usize - page_offset;
         let to_copy = if remaining <= available_in_page { remaining } else { available_in_page };
 
-        // Build a slice from mapped kernel VA and append
+        // Build a slice from mapped kernel VA and copy it out
         unsafe {
             let src_slice = core::slice::from_raw_parts(kva.add(page_offset), to_copy);
-            result.extend_from_slice(src_slice);
+            dst[copied .. copied + to_copy].copy_from_slice(src_slice);
         }
 
+        copied += to_copy;
         remaining -= to_copy;
         if remaining == 0 { break; }
     }
@@ -434,11 +903,17 @@ fn copy_from_user_internal(
 	flush.flush();
     }
 
-    Ok(result)
+    Ok(copied)
 }
 
 pub fn copy_to_user(dest: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
-    let process = scheduler::get_current_process();
+    copy_to_process(&scheduler::get_current_process(), dest, src)
+}
+
+// As copy_to_user, but into the given process rather than the running one, e.g. to set up a process being spawned
+pub fn copy_to_process(process: &process::Process, dest: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
+    validate_user_ptr(dest.as_u64(), src.len() as u64).map_err(|_| CopyError::Fault)?;
+
     let mut task_type = process.task_type.write();
     match *task_type {
 	process::TaskType::Kernel => {
@@ -453,18 +928,87 @@ pub fn copy_to_user(dest: VirtAddr, src: &[u8]) -> Result<(), CopyError> {
     }
 }
 
+// The frames behind a buffer in the running process, held so that a device can DMA into them. They can't be freed
+// and reused while held, even if the process unmaps them in the meantime, and are let go of when this is dropped.
+pub struct PinnedUserBuffer {
+    // One for each page the buffer touches, in order
+    pub frames: Vec<PhysAddr>,
+    // Where the buffer starts in the first of them
+    pub offset: u64,
+}
+
+impl Drop for PinnedUserBuffer {
+    fn drop(&mut self) {
+	for phys in self.frames.iter() {
+	    if release_frame(*phys) {
+		let mut frame_allocator = VENIX_FRAME_ALLOCATOR.write();
+		unsafe {
+		    frame_allocator.as_mut().expect("Attempted to use missing frame allocator").deallocate_frame(PhysFrame::containing_address(*phys));
+		}
+	    }
+	}
+    }
+}
+
+// Pins the len bytes at dest for a device to write into. Each page is made ready to write first, as copy_to_user
+// would, and must be somewhere the process could write itself. Kernel tasks have no frames of their own to pin, so
+// get Fault, and should copy instead
+pub fn pin_user_buffer(dest: VirtAddr, len: u64) -> Result<PinnedUserBuffer, CopyError> {
+    validate_user_ptr(dest.as_u64(), len).map_err(|_| CopyError::Fault)?;
+    if len == 0 {
+	return Err(CopyError::Fault);
+    }
+
+    let process = scheduler::get_current_process();
+    let mut task_type = process.task_type.write();
+    let address_space = match *task_type {
+	process::TaskType::User(ref mut address_space) => address_space,
+	process::TaskType::Kernel => return Err(CopyError::Fault),
+    };
+
+    let first_page = dest.align_down(4096_u64);
+    let n_pages = (dest + len).align_up(4096_u64).as_u64().saturating_sub(first_page.as_u64()) / 4096;
+    let mut frames = Vec::with_capacity(n_pages as usize);
+    for i in 0 .. n_pages {
+	let page = first_page + i * 4096;
+	fault_in_page(address_space, page, true)?;
+	frames.push(user_frame(&address_space.mapped_regions, page, USER_WRITABLE_PAGE)?);
+    }
+
+    // Pinned as another sharer would be, so that the frames outlive the process' mapping of them if need be
+    for phys in frames.iter() {
+	share_frame(*phys);
+    }
+
+    Ok(PinnedUserBuffer {
+	frames,
+	offset: dest.as_u64() % 4096,
+    })
+}
+
 pub fn copy_from_user(src: VirtAddr, len: usize) -> Result<Vec<u8>, CopyError> {
+    let mut buf = vec![0; len];
+    copy_from_user_into(src, &mut buf)?;
+    Ok(buf)
+}
+
+// As copy_from_user, but into a buffer the caller already has, so that hot paths needn't allocate for every copy.
+// Returns the number of bytes copied, which is always all of dst
+pub fn copy_from_user_into(src: VirtAddr, dst: &mut [u8]) -> Result<usize, CopyError> {
+    validate_user_ptr(src.as_u64(), dst.len() as u64).map_err(|_| CopyError::Fault)?;
+
     let process = scheduler::get_current_process();
     let mut task_type = process.task_type.write();
     match *task_type {
 	process::TaskType::Kernel => {
-	    let data_to = unsafe {
-		slice::from_raw_parts(src.as_ptr::<u8>(), len)
+	    let data_from = unsafe {
+		slice::from_raw_parts(src.as_ptr::<u8>(), dst.len())
 	    };
+	    dst.copy_from_slice(data_from);
 
-	    Ok(data_to.to_vec())
+	    Ok(dst.len())
 	},
-	process::TaskType::User(ref mut address_space) => copy_from_user_internal(address_space, src, len),
+	process::TaskType::User(ref mut address_space) => copy_from_user_internal(address_space, src, dst),
     }
 }
 
@@ -474,10 +1018,11 @@ pub fn copy_string_from_user(user_buf: VirtAddr) -> Result<String, UserStringCop
 
     let mut cursor = user_buf;
     let mut collected: Vec<u8> = Vec::new();
+    let mut bytes = vec![0; PAGE_SIZE];
 
     loop {
         // Copy one page
-        let bytes = copy_from_user(cursor, PAGE_SIZE)
+        copy_from_user_into(cursor, &mut bytes)
             .map_err(|e| match e {
 		CopyError::Fault => UserStringCopyError::Fault,
 		CopyError::TempAllocFailed => UserStringCopyError::TempAllocFailed,
@@ -547,3 +1092,97 @@ pub fn copy_value_to_user<T: Copy>(user_ptr: VirtAddr, value: &T) -> Result<(),
     };
     copy_to_user(user_ptr, bytes)
 }
+
+#[test]
+fn user_pointers_stay_in_the_lower_half() {
+    assert!(matches!(validate_ptr(0, 8, true), Err(CanonicalError::Fault)));
+    assert!(matches!(validate_ptr(0, 8, false), Err(CanonicalError::Fault)));
+    assert_eq!(validate_ptr(0, 0, true).unwrap(), VirtAddr::new(0));
+    assert!(matches!(validate_ptr(u64::MAX - 4, 8, false), Err(CanonicalError::Fault)));
+
+    assert_eq!(validate_ptr(0x1000, 8, true).unwrap(), VirtAddr::new(0x1000));
+    // Right up to the end of the lower half is fine, but not one byte over
+    assert!(validate_ptr(USER_ADDRESS_LIMIT - 8, 8, true).is_ok());
+    assert!(matches!(validate_ptr(USER_ADDRESS_LIMIT - 8, 9, true), Err(CanonicalError::Fault)));
+
+    // Kernel tasks may pass kernel pointers, but only canonical ones
+    assert!(matches!(validate_ptr(0xffff_8000_0000_0000, 8, true), Err(CanonicalError::Fault)));
+    assert!(validate_ptr(0xffff_8000_0000_0000, 8, false).is_ok());
+    assert!(matches!(validate_ptr(USER_ADDRESS_LIMIT, 8, false), Err(CanonicalError::Fault)));
+}
+
+#[test]
+fn read_only_user_pages_cant_be_copied_to() {
+    let page = |n: u64| VirtAddr::new(0x40_0000 + n * 4096);
+    let frame = |n: u64| PhysAddr::new(0x7_2001_0000 + n * 4096);
+
+    let mut mapped_regions = BTreeMap::new();
+    mapped_regions.insert(page(0), (frame(0), USER_READABLE_PAGE));
+    mapped_regions.insert(page(1), (frame(1), USER_WRITABLE_PAGE | PageTableFlags::NO_EXECUTE));
+    // Shared with a fork, and not written to yet. copy_to_user faults it in before it gets this far
+    mapped_regions.insert(page(2), (frame(2), USER_READABLE_PAGE | COPY_ON_WRITE));
+    // Kernel memory, as far as the process is concerned
+    mapped_regions.insert(page(3), (frame(3), PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+
+    // So copy_to_user gives EFAULT for everything but the writable page
+    assert!(matches!(user_frame(&mapped_regions, page(0), USER_WRITABLE_PAGE), Err(CopyError::Fault)));
+    assert_eq!(user_frame(&mapped_regions, page(1), USER_WRITABLE_PAGE).unwrap(), frame(1));
+    assert!(matches!(user_frame(&mapped_regions, page(2), USER_WRITABLE_PAGE), Err(CopyError::Fault)));
+    assert!(matches!(user_frame(&mapped_regions, page(3), USER_WRITABLE_PAGE), Err(CopyError::Fault)));
+    assert!(matches!(user_frame(&mapped_regions, page(4), USER_WRITABLE_PAGE), Err(CopyError::Fault)));
+
+    // Whereas copy_from_user only needs to be able to read it
+    assert_eq!(user_frame(&mapped_regions, page(0), USER_READABLE_PAGE).unwrap(), frame(0));
+    assert_eq!(user_frame(&mapped_regions, page(2), USER_READABLE_PAGE).unwrap(), frame(2));
+    assert!(matches!(user_frame(&mapped_regions, page(3), USER_READABLE_PAGE), Err(CopyError::Fault)));
+}
+
+#[test]
+fn forked_frames_are_shared_until_one_side_writes() {
+    // A frame nothing else uses, as the sharers are global
+    let frame = PhysAddr::new(0x7_2003_0000);
+
+    // Before the fork the frame is the parent's alone, so writing to it needs no copy
+    assert!(!is_frame_shared(frame));
+    // fork_cow maps it into the child as well
+    share_frame(frame);
+    assert!(is_frame_shared(frame));
+
+    // The child writes first, so break_copy_on_write gives it a frame of its own, which the parent never sees, and
+    // lets go of this one. The parent still has it, so it isn't freed, and isn't shared any more either, so the
+    // parent's own first write just makes it writable again.
+    assert!(!release_frame(frame));
+    assert!(!is_frame_shared(frame));
+
+    // Forking twice more gives three users, and only the last to let go frees it
+    share_frame(frame);
+    share_frame(frame);
+    assert!(!release_frame(frame));
+    assert!(is_frame_shared(frame));
+    assert!(!release_frame(frame));
+    assert!(release_frame(frame));
+}
+
+#[test]
+fn reads_share_the_zero_frame_until_written() {
+    // A stand in, as there's no frame allocator to get a real one from. Nothing else here looks at the zero frame.
+    let zero = ZERO_FRAME.call_once(|| PhysFrame::containing_address(PhysAddr::new(0x7_2002_0000))).start_address();
+
+    // Reading a page all the way through maps it to the zero frame, read only, so a write faults
+    let read = demand_zero_flags(false);
+    assert!(read.contains(COPY_ON_WRITE) && !read.contains(PageTableFlags::WRITABLE));
+    let written = demand_zero_flags(true);
+    assert!(written.contains(PageTableFlags::WRITABLE) && !written.contains(COPY_ON_WRITE));
+
+    // However many pages map it, it's always shared, so the first write to any of them copies it rather than
+    // writing to it. It isn't counted, as it's never freed.
+    for _ in 0 .. 16 {
+	share_frame(zero);
+    }
+    assert!(is_frame_shared(zero));
+    assert!(!FRAME_SHARERS.lock().contains_key(&zero));
+    for _ in 0 .. 17 {
+	assert!(!release_frame(zero));
+    }
+    assert!(is_frame_shared(zero));
+}
@@ -0,0 +1,135 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::allocator;
+use crate::cmdline;
+use crate::scheduler;
+
+// The caches which register here all live on the kernel heap, which is a fixed size, so it's the heap's free space
+// that's watched rather than free frames. Once it drops below the low watermark, caches are asked to give memory back
+// until it's above the high watermark. Having the two apart stops us from thrashing caches on every allocation at the
+// boundary.
+const LOW_WATERMARK_BYTES: u64 = 256 * 1024;
+const HIGH_WATERMARK_BYTES: u64 = 512 * 1024;
+
+// Anything which holds onto heap memory it could give back (block cache, slab caches, etc) should register one of
+// these.
+pub trait Shrinker: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Ask the cache to release up to target bytes of kernel heap, returning how many bytes were actually released.
+    // Only clean entries may be dropped; dirty entries must be written back first, or skipped.
+    fn shrink(&self, target: u64) -> u64;
+}
+
+static SHRINKERS: RwLock<Vec<Arc<dyn Shrinker>>> = RwLock::new(Vec::new());
+
+// When a frame can't be allocated, kill a process to make room rather than failing the allocation
+static OOM_KILL_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Reclaim can cause allocation (e.g. writing back a dirty block), which would otherwise recurse back in here
+static RECLAIM_IN_PROGRESS: Mutex<()> = Mutex::new(());
+
+pub fn register_shrinker(shrinker: Arc<dyn Shrinker>) {
+    SHRINKERS.write().push(shrinker);
+}
+
+// Returns the number of bytes of kernel heap released. This is reached from a failed heap allocation, so mustn't
+// allocate itself, nor wait on a lock the failed allocation might have been made under.
+pub fn reclaim(target: u64) -> u64 {
+    let _guard = match RECLAIM_IN_PROGRESS.try_lock() {
+	Some(g) => g,
+	None => return 0,
+    };
+    let shrinkers = match SHRINKERS.try_read() {
+	Some(s) => s,
+	None => return 0,
+    };
+
+    let mut released = 0;
+
+    for shrinker in shrinkers.iter() {
+	if released >= target {
+	    break;
+	}
+
+	let freed = shrinker.shrink(target - released);
+	if freed > 0 {
+	    log::info!("Reclaimed {} bytes from {}", freed, shrinker.name());
+	}
+	released += freed;
+    }
+
+    released
+}
+
+// How much should be reclaimed with free bytes left on the heap, if any
+fn reclaim_target(free: u64) -> Option<u64> {
+    if free < LOW_WATERMARK_BYTES {
+	Some(HIGH_WATERMARK_BYTES - free)
+    } else {
+	None
+    }
+}
+
+// Called before allocating memory, so that we give caches a chance to shrink before the heap runs out entirely
+pub fn reclaim_if_low() {
+    let target = match reclaim_target(allocator::stats().free as u64) {
+	Some(target) => target,
+	None => return,
+    };
+
+    let released = reclaim(target);
+    let now_free = allocator::stats().free as u64;
+    if now_free < LOW_WATERMARK_BYTES {
+	log::warn!("Kernel heap is low: {} bytes free after reclaiming {}", now_free, released);
+    }
+}
+
+fn set_oom_kill_enabled(enabled: bool) {
+    OOM_KILL_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// oom_kill=0 stops the OOM killer, so allocations fail instead
+pub fn init() {
+    if let Some(enabled) = cmdline::get("oom_kill") {
+	match enabled {
+	    "0" => set_oom_kill_enabled(false),
+	    "1" => set_oom_kill_enabled(true),
+	    _ => log::warn!("oom_kill should be 0 or 1, not {}", enabled),
+	}
+    }
+}
+
+// Runs allocate until it succeeds, killing a process to free its memory after each failure. The caches above only
+// give back heap, so there's no point reclaiming from them first. Only gives up, returning the last error, when the
+// OOM killer is disabled or there's nothing left which can be killed.
+pub fn allocate_or_oom_kill<T, E>(mut allocate: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    loop {
+	let err = match allocate() {
+	    Ok(result) => return Ok(result),
+	    Err(e) => e,
+	};
+
+	if !OOM_KILL_ENABLED.load(Ordering::Relaxed) {
+	    return Err(err);
+	}
+
+	match scheduler::oom_kill() {
+	    Some((pid, pages)) => log::warn!("Out of memory: killed process {}, freeing {} pages", pid, pages),
+	    None => {
+		log::error!("Out of memory, and no process could be killed to free any");
+		return Err(err);
+	    },
+	}
+    }
+}
+
+#[test]
+fn reclaims_up_to_the_high_watermark_once_below_the_low() {
+    assert_eq!(reclaim_target(LOW_WATERMARK_BYTES), None);
+    assert_eq!(reclaim_target(LOW_WATERMARK_BYTES - 1), Some(HIGH_WATERMARK_BYTES - LOW_WATERMARK_BYTES + 1));
+    assert_eq!(reclaim_target(0), Some(HIGH_WATERMARK_BYTES));
+}
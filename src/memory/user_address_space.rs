@@ -6,7 +6,7 @@ use x86_64::structures::paging::{
 	Page,
 	PageRangeInclusive,
     },
-    mapper::CleanUp,
+    mapper::{CleanUp, MapToError},
     OffsetPageTable,
     PageTable,
     PageTableFlags,
@@ -21,31 +21,42 @@ use anyhow::{anyhow, Result};
 use alloc::slice;
 use core::cmp::Ordering;
 
+use crate::allocator::SlabCache;
 use crate::memory;
 
+// Room for a node of mapped_regions, which holds 11 entries, and 12 edges as well if it's an internal node. A node
+// that somehow doesn't fit just comes from the heap instead.
+pub type ShadowMapNode = [u64; 48];
+
+// Every address space's mapped_regions comes and goes a node at a time, so they share one cache
+pub static SHADOW_MAP_CACHE: SlabCache<ShadowMapNode> = SlabCache::new();
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct MemoryRegion {
     pub start: u64,
     pub end: u64,
 }
 
-#[derive(Debug)]
-struct PageVirtPhys {
-    pub virt_start: VirtAddr,
-    pub phys_start: PhysAddr,
-}
-
 #[derive(PartialEq, Eq, Debug)]
 pub struct AddressSpace {
     pt4: PhysFrame,
     free_regions: Vec<MemoryRegion>,
-    pub mapped_regions: BTreeMap<VirtAddr, PhysAddr>,
+    // Each page's frame, and the flags it's mapped with, so that copies to and from userspace can check them without
+    // walking the page tables. Pages reserved but not yet backed aren't PRESENT
+    pub mapped_regions: BTreeMap<VirtAddr, (PhysAddr, PageTableFlags), &'static SlabCache<ShadowMapNode>>,
+    // Pages actually backed by a frame, as opposed to just reserved, which is what counts towards memory use
+    backed_pages: u64,
+    peak_mapped_pages: u64,
 }
 
 impl AddressSpace {
     pub fn new() -> Self {
-	let (virt, phys) = memory::kernel_allocate(
-	    4096, memory::MemoryAllocationType::Ram).expect("Allocation failed");
+	Self::try_new().expect("Allocation failed")
+    }
+
+    // As new, but fails rather than panicking if there's no frame for the top level table
+    fn try_new() -> Result<Self, MapToError<Size4KiB>> {
+	let (virt, phys) = memory::kernel_allocate(4096, memory::MemoryAllocationType::Ram)?;
 	
 	let data_to_z = unsafe {
 	    slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), 4096_usize)
@@ -73,80 +84,84 @@ impl AddressSpace {
 	}
 
 	let p4_size: u64 = 1 << 39;
-	AddressSpace {
+	Ok(AddressSpace {
 	    pt4: frame,
 	    free_regions: Vec::from([MemoryRegion {
 		start: 0x100000,
 		end: p4_size * 255,  // Anywhere in the lower half
             }]),
-	    mapped_regions: BTreeMap::new(),
-	}
+	    mapped_regions: BTreeMap::new_in(&SHADOW_MAP_CACHE),
+	    backed_pages: 0,
+	    peak_mapped_pages: 0,
+	})
     }
 
-    pub fn create_copy_of_address_space(&mut self, other: &Self) {
-	unsafe fn inner(level: u8,
-		 offset_above: u64,
-		 page_table: *const PageTable) -> Vec<PageVirtPhys> {
-	    let p4_size = 1 << 39;
-	    let p3_size = 1 << 30;
-	    let p2_size = 1 << 21;
-	    let p1_size = 1 << 12;
-
-	    if level != 1 {
-		let next_level = match level {
-		    4 => 3,
-		    3 => 2,
-		    2 => 1,
-		    _ => panic!("Invalid page level when copying userspace"),
-		};
-		let idx_size = match level {
-		    4 => p4_size,
-		    3 => p3_size,
-		    2 => p2_size,
-		    _ => panic!("Invalid page level when copying userspace"),
-		};
-		(*page_table).iter()
-		    .enumerate()
-		    .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT))
-		    .filter(|(index, _)| level != 4 || *index < 256_usize)  // Make sure all user allocs are in the LH
-		    .flat_map(|(idx, entry)| inner(
-			next_level, offset_above + (idx as u64 * idx_size),
-			(entry.addr().as_u64() + memory::DIRECT_MAP_OFFSET.get().unwrap()) as *const PageTable))
-		    .collect()
-	    } else {
-		
-		(*page_table).iter()
-		    .enumerate()
-		    .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT))
-		    .map(|(index, entry)| PageVirtPhys {
-			virt_start: VirtAddr::new(offset_above + (index as u64 * p1_size)),
-			phys_start: entry.addr(),
-		    })
-		    .collect()
+    // Makes the address space for a forked child. Rather than copying anything, every page is shared between the two,
+    // and those which were writable become copy-on-write in both, so that whichever writes to a page first gets its
+    // own copy of it. This is cheap, which matters as a fork is usually followed straight away by an execve.
+    //
+    // Fails if a frame can't be found for the child's page tables, having undone whatever was shared with it. The
+    // parent's pages which were made copy-on-write stay that way, which costs nothing more than a fault on the next
+    // write to each.
+    pub fn fork_cow(&mut self) -> Result<Self, MapToError<Size4KiB>> {
+	let mut child = AddressSpace::try_new()?;
+	child.free_regions = self.free_regions.clone();
+
+	let direct_map_offset = *memory::DIRECT_MAP_OFFSET.get().unwrap();
+	let (mut parent_pt, mut child_pt) = unsafe {
+	    let parent_pt4 = &mut *VirtAddr::new(self.pt4.start_address().as_u64() + direct_map_offset).as_mut_ptr::<PageTable>();
+	    let child_pt4 = &mut *VirtAddr::new(child.pt4.start_address().as_u64() + direct_map_offset).as_mut_ptr::<PageTable>();
+	    (OffsetPageTable::new(parent_pt4, VirtAddr::new(direct_map_offset)),
+	     OffsetPageTable::new(child_pt4, VirtAddr::new(direct_map_offset)))
+	};
+
+	let mut frame_allocator = memory::VENIX_FRAME_ALLOCATOR.write();
+	let mut result = Ok(());
+
+	for (virt, (phys, flags)) in self.mapped_regions.iter_mut() {
+	    // Reserved, but not backed by anything yet
+	    if !flags.contains(PageTableFlags::PRESENT) {
+		child.mapped_regions.insert(*virt, (*phys, *flags));
+		continue;
 	    }
-	}
 
-	let complete_map = unsafe {
-	    let pt4_virt = VirtAddr::new(other.pt4.start_address().as_u64() + memory::DIRECT_MAP_OFFSET.get().unwrap());
-	    let pt4 = &mut *pt4_virt.as_mut_ptr::<PageTable>();
-	    inner(4, 0, pt4)
-	};
+	    let page: Page<Size4KiB> = Page::from_start_address(*virt).expect("Malformed start address");
+	    if flags.contains(PageTableFlags::WRITABLE) {
+		*flags = (*flags - PageTableFlags::WRITABLE) | memory::COPY_ON_WRITE;
+		unsafe {
+		    parent_pt.update_flags(page, *flags).expect("Attempting to update page flags failed").flush();
+		}
+	    }
 
-	for entry in complete_map {
-	    memory::user_allocate(
-		4096,
-		memory::MemoryAccessRestriction::UserByStart(entry.virt_start),
-		self).expect("Unable to allocate to copy userspace");
-	    
-	    let data_to = unsafe {
-		slice::from_raw_parts_mut(entry.virt_start.as_mut_ptr::<u8>(), 4096_usize)
-	    };
-	    let data_from = unsafe {
-		slice::from_raw_parts_mut(VirtAddr::new(entry.phys_start.as_u64() + memory::DIRECT_MAP_OFFSET.get().unwrap()).as_mut_ptr::<u8>(), 4096)
+	    let frame = PhysFrame::from_start_address(*phys).expect("Malformed frame address");
+	    let mapped = unsafe {
+		child_pt.map_to_with_table_flags(
+		    page, frame, *flags, memory::USER_PARENT_TABLE_FLAGS,
+		    frame_allocator.as_mut().expect("Attempted to fork before memory initialised"))
 	    };
-	    
-	    data_to.copy_from_slice(data_from);
+	    match mapped {
+		Ok(flush) => flush.flush(),
+		Err(e) => {
+		    result = Err(e);
+		    break;
+		},
+	    }
+
+	    memory::share_frame(*phys);
+	    child.mapped_regions.insert(*virt, (*phys, *flags));
+	    child.backed_pages += 1;
+	}
+
+	// Everything the child got so far is in its mapped_regions, so clearing it gives back each share, and the
+	// tables which were made for them. Only its top level table is left, as it is when any process exits.
+	drop(frame_allocator);
+	if let Err(e) = result {
+	    child.clear_user_space();
+	    return Err(e);
 	}
+
+	child.peak_mapped_pages = child.backed_pages;
+	Ok(child)
     }
 
     pub unsafe fn switch_to(&self) {
@@ -161,14 +176,27 @@ impl AddressSpace {
 	let mut pa = region.start;
 	while pa < region.end {
 	    let va = VirtAddr::new(pa);
-	    self.mapped_regions.insert(va, PhysAddr::new(0));
+	    self.mapped_regions.insert(va, (PhysAddr::new(0), PageTableFlags::empty()));
 	    pa += 4096;
 	}
     }
 
-    pub fn assign_virt_phys(&mut self, virt: VirtAddr, phys: PhysAddr) {
+    pub fn get_mapped_pages(&self) -> u64 {
+	self.backed_pages
+    }
+
+    // High water mark of pages mapped into this address space, for rusage
+    pub fn get_peak_mapped_pages(&self) -> u64 {
+	self.peak_mapped_pages
+    }
+
+    pub fn assign_virt_phys(&mut self, virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) {
 	if let Some(entry) = self.mapped_regions.get_mut(&virt) {
-	    *entry = phys;
+	    if !entry.1.contains(PageTableFlags::PRESENT) && flags.contains(PageTableFlags::PRESENT) {
+		self.backed_pages += 1;
+		self.peak_mapped_pages = core::cmp::max(self.peak_mapped_pages, self.backed_pages);
+	    }
+	    *entry = (phys, flags);
 	}
     }
 
@@ -276,11 +304,14 @@ impl AddressSpace {
 
 	let mut frame_allocator = memory::VENIX_FRAME_ALLOCATOR.write();
 
-	for (virt, _) in self.mapped_regions.iter() {
+	// Pages which were only reserved have nothing to unmap
+	for (virt, _) in self.mapped_regions.iter().filter(|(_, (_, flags))| flags.contains(PageTableFlags::PRESENT)) {
 	    let p: Page<Size4KiB> = Page::from_start_address(*virt).expect("Malformed start address");
 	    let (frame, flush) = offset_pt.unmap(p).expect("Attempting to unmap page failed");
-	    unsafe {
-		frame_allocator.as_mut().expect("Attempted to clear userspace before memory initialised").deallocate_frame(frame);
+	    if memory::release_frame(frame.start_address()) {
+		unsafe {
+		    frame_allocator.as_mut().expect("Attempted to clear userspace before memory initialised").deallocate_frame(frame);
+		}
 	    }
 	    flush.flush();
 	}
@@ -298,6 +329,7 @@ impl AddressSpace {
 	    start: 0x100000,
 	    end: p4_size * 255,  // Anywhere in the lower half
         }]);
-	self.mapped_regions = BTreeMap::new();
+	self.mapped_regions = BTreeMap::new_in(&SHADOW_MAP_CACHE);
+	self.backed_pages = 0;
     }
 }
@@ -2,6 +2,8 @@
 
 use x86_64::instructions::interrupts::without_interrupts;
 
+use crate::sys::kmsg;
+
 #[allow(unused_imports)]
 use {
     limine::framebuffer::{
@@ -79,6 +81,14 @@ impl LockedPrintk {
 	    (printk.width() / get_raster_width(FontWeight::Regular, RasterHeight::Size16)) as u8
 	})
     }
+
+    pub fn get_width(&self) -> usize {
+	without_interrupts(|| self.0.read().width())
+    }
+
+    pub fn get_height(&self) -> usize {
+	without_interrupts(|| self.0.read().height())
+    }
 }
 
 impl log::Log for LockedPrintk {
@@ -88,6 +98,10 @@ impl log::Log for LockedPrintk {
     }
 
     fn log(&self, record: &log::Record) {
+	if record.target() != kmsg::USER_MESSAGE_TARGET {
+	    kmsg::record(kmsg::priority_for_level(record.level()), *record.args());
+	}
+
 	unsafe { self.force_unlock(); }
 	without_interrupts(|| {
             let mut printk = self.0.write();
@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use spin::{RwLock, Mutex};
 use alloc::string::String;
@@ -9,11 +9,16 @@ use alloc::collections::BTreeMap;
 use alloc::boxed::Box;
 use core::pin::Pin;
 use core::future::Future;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
 
 use crate::memory;
 use crate::vfs;
+use crate::sys::random;
 use crate::sys::syscall;
 use crate::gdt;
+use crate::scheduler;
 use crate::scheduler::elf_loader;
 use crate::scheduler::signal;
 
@@ -23,6 +28,7 @@ const AT_PHENT: u64 = 4;
 const AT_PHNUM: u64 = 5;
 const AT_BASE: u64 = 7;
 const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
 
 pub type SyscallFuture = Pin<Box<dyn Future<Output = syscall::SyscallResult> + Send + 'static>>;
 
@@ -46,6 +52,29 @@ pub struct GeneralPurposeRegisters {
     pub rax: u64,
 }
 
+// Pushed to the user stack on entry to a signal handler, and restored from by sigreturn
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SignalFrame {
+    restorer: u64,  // The handler's return address
+    gprs: GeneralPurposeRegisters,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+    sigmask: u64,
+}
+
+// Leave the SysV red zone alone when pushing a signal frame
+const RED_ZONE_SIZE: u64 = 128;
+
+// What init starts with, and so everything inherits unless it's changed
+const DEFAULT_UMASK: u32 = 0o022;
+
+const ALL_CPUS: u64 = u64::MAX;
+
+// Room above the initial thread's pointer for its TCB, more than any libc's needs
+const TCB_SIZE: u64 = 4096;
+
 #[derive(Copy, Clone, Default, Debug)]
 pub struct ProcessContext {
     pub gprs: GeneralPurposeRegisters,
@@ -54,6 +83,31 @@ pub struct ProcessContext {
     rsp: u64,
     cs: u64,
     ss: u64,
+    // Not touched by the context switch asm, so these must stay after the iret frame
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+impl ProcessContext {
+    pub fn is_user(&self) -> bool {
+	self.cs & 0x03 != 0
+    }
+
+    // What's saved on the way into the kernel, which leaves the FS and GS bases as arch_prctl last set them
+    fn save(&mut self, rsp: u64, rip: u64, rflags: u64, registers: &GeneralPurposeRegisters) {
+	self.rsp = rsp;
+	self.rip = rip;
+	self.rflags = rflags;
+	self.gprs = *registers;
+    }
+}
+
+// Accumulated resource usage, reported to the parent via wait4
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ResourceUsage {
+    pub cpu_time_ms: u64,
+    pub peak_rss_pages: u64,
+    pub involuntary_switches: u64,
 }
 
 #[derive(Clone)]
@@ -65,12 +119,21 @@ struct AuxVector {
 #[derive(Clone)]
 pub enum TaskState {
     Running,
+    // By a stop signal, until SIGCONT or SIGKILL is sent
+    Stopped,
     AsyncSyscall {
 	future: Arc<Mutex<SyscallFuture>>,
     },
     Waiting {
 	future: Arc<Mutex<SyscallFuture>>,
     },
+    // The syscall's future is being polled, which may be on another CPU. A wake arriving meanwhile is noted, so the
+    // future is polled again rather than left waiting.
+    Polling {
+	woken: bool,
+    },
+    // Having its memory taken back by the OOM killer, so not to be run until that's done and it's been sent SIGKILL
+    Reaping,
 }
 
 pub enum TaskType {
@@ -78,10 +141,59 @@ pub enum TaskType {
     Kernel,
 }
 
+// Who a process is acting as. The effective IDs are what permission checks use; the saved ones let a set-user-ID
+// program drop privileges and take them back again.
+#[derive(Clone, Copy, Debug)]
+pub struct Credentials {
+    pub uid: u32,
+    pub euid: u32,
+    pub suid: u32,
+    pub gid: u32,
+    pub egid: u32,
+    pub sgid: u32,
+}
+
+impl Credentials {
+    const ROOT: Credentials = Credentials {
+	uid: 0,
+	euid: 0,
+	suid: 0,
+	gid: 0,
+	egid: 0,
+	sgid: 0,
+    };
+
+    pub fn is_privileged(&self) -> bool {
+	self.euid == 0
+    }
+}
+
+// Held by a vfork child, which runs in its parent's address space until it calls execve or exits, and then gives it
+// back. The parent waits on it until then.
+pub struct VforkParent {
+    task_type: Weak<RwLock<TaskType>>,
+    released: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl VforkParent {
+    pub async fn wait(self: Arc<Self>) {
+	poll_fn(move |cx: &mut Context<'_>| {
+	    // Registered before looking, so a release from here on wakes us
+	    *self.waker.lock() = Some(cx.waker().clone());
+	    if self.released.load(Ordering::SeqCst) {
+		Poll::Ready(())
+	    } else {
+		Poll::Pending
+	    }
+	}).await
+    }
+}
+
 #[derive(Clone)]
 pub struct FileDescriptor {
     pub file_handle: Arc<dyn vfs::filesystem::FileHandle>,
-    pub flags: u64,
+    pub flags: vfs::filesystem::OpenFlags,
 }
 
 pub struct Process {
@@ -95,6 +207,35 @@ pub struct Process {
     cwd: RwLock<String>,
     signals: RwLock<BTreeMap<u64, signal::SignalHandler>>,
     sigmask: RwLock<u64>,
+    pending_signals: RwLock<u64>,
+    // Mask to go back to once a signal has been handled, when a syscall (i.e. sigsuspend) temporarily replaced it
+    saved_sigmask: RwLock<Option<u64>>,
+    // Woken when a signal is posted, for syscalls which wait for one
+    signal_waker: Mutex<Option<Waker>>,
+    // A stop or continue which the parent hasn't yet been told about by wait
+    wait_event: Mutex<Option<scheduler::WaitStatus>>,
+    pgid: RwLock<u64>,
+    sid: RwLock<u64>,
+    ppid: RwLock<u64>,
+    // Permission bits cleared from the mode of newly created files
+    umask: RwLock<u32>,
+    credentials: RwLock<Credentials>,
+    // Bitmask of the CPUs the scheduler may run us on
+    cpu_set: RwLock<u64>,
+    // Charged from the timer interrupt, so kept lock-free
+    cpu_time_ms: AtomicU64,
+    // Peak RSS of address spaces discarded by execve
+    peak_rss_pages: AtomicU64,
+    // Ticks left before the scheduler preempts us, also consumed from the timer interrupt
+    time_slice_remaining: AtomicU64,
+    preemptions: AtomicU64,
+    // Whether the program being run asked for an executable stack, which is set up when it's started
+    executable_stack: AtomicBool,
+    // Set while we're a vfork child, running in the parent's address space
+    vfork_parent: Mutex<Option<Arc<VforkParent>>>,
+    // Whether membarrier's private expedited command may be used, which has to be asked for first. Not kept across
+    // fork or execve, as the address space it's for isn't.
+    membarrier_registered: AtomicBool,
 }
 
 unsafe impl Send for Process { }
@@ -104,35 +245,111 @@ impl Process {
     pub fn new_kthread(rip: u64) -> Self {
 	let (kernel_code, kernel_data, _, _) = gdt::get_code_selectors();
 	
-	let (rsp, _) = match memory::kernel_allocate(
-	    8 * 1024 * 1024,  // 8MiB
-	    memory::MemoryAllocationType::Ram) {
+	let rsp = match memory::kernel_allocate_stack(
+	    8 * 1024 * 1024) {  // 8MiB
 	    Ok(i) => i,
 	    Err(e) => panic!("Could not allocate stack memory for process: {:?}", e),
 	};
 
+	Self::new_kernel(ProcessContext {
+	    gprs: GeneralPurposeRegisters::default(),
+	    rflags: 0x202,
+	    rip,
+	    rsp: rsp.as_u64() + (8 * 1024 * 1024),
+	    cs: kernel_code.0 as u64,
+	    ss: kernel_data.0 as u64,
+	    fs_base: 0,
+	    gs_base: 0,
+	})
+    }
+
+    // A kernel task which will start from context, with everything else as a fresh kernel thread has it
+    pub fn new_kernel(context: ProcessContext) -> Self {
 	Process {
 	    file_descriptors: RwLock::new(BTreeMap::new()),
 	    args: RwLock::new(vec!(String::from("init"))),
 	    envvars: RwLock::new(vec!(String::from("PATH=/bin:/usr/bin"))),
 	    auxvs: RwLock::new(Vec::new()),
+	    context: RwLock::new(context),
+	    state: RwLock::new(TaskState::Running),
+	    task_type: Arc::new(RwLock::new(TaskType::Kernel)),
+	    cwd: RwLock::new(String::from("/")),
+	    signals: RwLock::new(BTreeMap::new()),
+	    sigmask: RwLock::new(0),
+	    pending_signals: RwLock::new(0),
+	    saved_sigmask: RwLock::new(None),
+	    signal_waker: Mutex::new(None),
+	    wait_event: Mutex::new(None),
+	    pgid: RwLock::new(0),
+	    sid: RwLock::new(0),
+	    umask: RwLock::new(DEFAULT_UMASK),
+	    // Kernel threads, and so init, run as root
+	    credentials: RwLock::new(Credentials::ROOT),
+	    cpu_set: RwLock::new(ALL_CPUS),
+	    ppid: RwLock::new(0),
+	    cpu_time_ms: AtomicU64::new(0),
+	    peak_rss_pages: AtomicU64::new(0),
+	    time_slice_remaining: AtomicU64::new(0),
+	    preemptions: AtomicU64::new(0),
+	    executable_stack: AtomicBool::new(false),
+	    vfork_parent: Mutex::new(None),
+	    membarrier_registered: AtomicBool::new(false),
+	}
+    }
+
+    // The starting point for posix_spawn: a child of parent, with the given file descriptors, which will run a new
+    // program. It's as if parent had forked then called execve, but without ever copying the parent's address
+    // space. The caller loads the program and calls init_stack_and_start before handing it to the scheduler.
+    pub fn new_spawned(parent: &Self, mut file_descriptors: BTreeMap<u64, FileDescriptor>, args: Vec<String>, envvars: Vec<String>) -> Self {
+	let (_, _, user_code, user_data) = gdt::get_code_selectors();
+
+	file_descriptors.retain(|_, fd| !fd.flags.contains(vfs::filesystem::OpenFlags::CloExec));
+
+	Process {
+	    file_descriptors: RwLock::new(file_descriptors),
+	    args: RwLock::new(args),
+	    envvars: RwLock::new(envvars),
+	    auxvs: RwLock::new(Vec::new()),
 	    context: RwLock::new(ProcessContext {
 		gprs: GeneralPurposeRegisters::default(),
 		rflags: 0x202,
-		rip,
-		rsp: rsp.as_u64() + (8 * 1024 * 1024),
-		cs: kernel_code.0 as u64,
-		ss: kernel_data.0 as u64,
+		rip: 0,  // Both set once the program is loaded
+		rsp: 0,
+		cs: user_code.0 as u64,
+		ss: user_data.0 as u64,
+		fs_base: 0,
+		gs_base: 0,
 	    }),
 	    state: RwLock::new(TaskState::Running),
-	    task_type: Arc::new(RwLock::new(TaskType::Kernel)),
-	    cwd: RwLock::new(String::from("/")),
+	    task_type: Arc::new(RwLock::new(TaskType::User(memory::user_address_space::AddressSpace::new()))),
+	    cwd: RwLock::new(parent.cwd.read().clone()),
+	    // Handlers belong to the parent's program, so are reset, as execve would
 	    signals: RwLock::new(BTreeMap::new()),
-	    sigmask: RwLock::new(0),
+	    sigmask: RwLock::new(*parent.sigmask.read()),
+	    pending_signals: RwLock::new(0),
+	    saved_sigmask: RwLock::new(None),
+	    signal_waker: Mutex::new(None),
+	    wait_event: Mutex::new(None),
+	    pgid: RwLock::new(*parent.pgid.read()),
+	    sid: RwLock::new(*parent.sid.read()),
+	    umask: RwLock::new(*parent.umask.read()),
+	    credentials: RwLock::new(*parent.credentials.read()),
+	    cpu_set: RwLock::new(*parent.cpu_set.read()),
+	    ppid: RwLock::new(0),  // Set by the scheduler
+	    cpu_time_ms: AtomicU64::new(0),
+	    peak_rss_pages: AtomicU64::new(0),
+	    time_slice_remaining: AtomicU64::new(0),
+	    preemptions: AtomicU64::new(0),
+	    executable_stack: AtomicBool::new(false),
+	    vfork_parent: Mutex::new(None),
+	    membarrier_registered: AtomicBool::new(false),
 	}
     }
 
     pub fn execve(self: Arc<Self>, new_args: Vec<String>, new_envvars: Vec<String>) {
+	// A vfork child hands the parent's address space back before it's cleared below
+	self.release_vfork_parent();
+
 	let mut task_type = self.task_type.write();
 	match &mut *task_type {
 	    TaskType::Kernel => {
@@ -140,6 +357,7 @@ impl Process {
 		*task_type = TaskType::User(address_space);
 	    },
 	    TaskType::User(ref mut address_space) => {
+		self.peak_rss_pages.fetch_max(address_space.get_peak_mapped_pages(), Ordering::Relaxed);
 		address_space.clear_user_space();
 		let address_space = memory::user_address_space::AddressSpace::new();
 		*task_type = TaskType::User(address_space);
@@ -151,16 +369,93 @@ impl Process {
 	let mut signals = self.signals.write();
 	let mut args = self.args.write();
 	let mut envvars = self.envvars.write();
+	let mut file_descriptors = self.file_descriptors.write();
+
+	file_descriptors.retain(|_, fd| !fd.flags.contains(vfs::filesystem::OpenFlags::CloExec));
 
 	*args = new_args;
 	*envvars = new_envvars;
 	context.gprs = GeneralPurposeRegisters::default();
 	context.rflags = 0x202;
+	context.fs_base = 0;
+	context.gs_base = 0;
 	*auxvs = Vec::new();
 	*signals = BTreeMap::new();
     }
 
-    pub fn from_existing(old: &Self) -> Self {
+    // Fails with NoMem if there isn't the memory for the child's page tables
+    pub fn from_existing(old: &Self) -> Result<Self, syscall::CanonicalError> {
+	// Written to, as the parent's pages become copy-on-write too
+	let task_type = {
+	    let mut old_task_type = old.task_type.write();
+
+	    match &mut *old_task_type {
+		TaskType::Kernel => TaskType::Kernel,
+		TaskType::User(address_space) => TaskType::User(address_space.fork_cow()
+		    .map_err(|_| syscall::CanonicalError::NoMem)?),
+	    }
+	};
+
+	Ok(Self::copy_of(old, task_type))
+    }
+
+    // A child of old which runs in old's address space, rather than a copy of it, until it calls execve or exits.
+    // The address space is lent, not shared: the child takes it, leaving old an empty one, which is fine as old
+    // mustn't run until it's given back. Whatever's returned is what old waits on.
+    pub fn from_existing_vfork(old: &Self) -> (Self, Arc<VforkParent>) {
+	let is_user = matches!(*old.task_type.read(), TaskType::User(_));
+	let task_type = if is_user {
+	    TaskType::User(memory::user_address_space::AddressSpace::new())
+	} else {
+	    TaskType::Kernel
+	};
+
+	let child = Self::copy_of(old, task_type);
+	core::mem::swap(&mut *old.task_type.write(), &mut *child.task_type.write());
+
+	let vfork_parent = Arc::new(VforkParent {
+	    task_type: Arc::downgrade(&old.task_type),
+	    released: AtomicBool::new(false),
+	    waker: Mutex::new(None),
+	});
+	*child.vfork_parent.lock() = Some(vfork_parent.clone());
+
+	(child, vfork_parent)
+    }
+
+    pub fn register_membarrier(&self) {
+	self.membarrier_registered.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_membarrier_registered(&self) -> bool {
+	self.membarrier_registered.load(Ordering::Relaxed)
+    }
+
+    pub fn is_vfork_child(&self) -> bool {
+	self.vfork_parent.lock().is_some()
+    }
+
+    // Gives the parent its address space back, if we're a vfork child, and lets it carry on. Leaves us with the empty
+    // one it had in the meantime, which is what execve or exit then tidy up. If the parent has gone, we keep it.
+    pub fn release_vfork_parent(&self) {
+	let vfork_parent = match self.vfork_parent.lock().take() {
+	    Some(v) => v,
+	    None => return,
+	};
+
+	if let Some(parent_task_type) = vfork_parent.task_type.upgrade() {
+	    core::mem::swap(&mut *self.task_type.write(), &mut *parent_task_type.write());
+	}
+
+	vfork_parent.released.store(true, Ordering::SeqCst);
+	let waker = vfork_parent.waker.lock().take();
+	if let Some(waker) = waker {
+	    waker.wake();
+	}
+    }
+
+    // Everything a child inherits from old, other than its memory
+    fn copy_of(old: &Self, task_type: TaskType) -> Self {
 	let signals = {
 	    let old_signals = old.signals.read();
 	    old_signals.clone()
@@ -186,23 +481,6 @@ impl Process {
 	    old_envvars.clone()
 	};
 
-	let task_type = {
-	    let old_task_type = old.task_type.read();
-
-	    match &*old_task_type {
-		TaskType::Kernel => TaskType::Kernel,
-		TaskType::User(address_space) => {
-		    let mut new_address_space = memory::user_address_space::AddressSpace::new();
-		    unsafe {
-			new_address_space.switch_to();
-		    }
-		    new_address_space.create_copy_of_address_space(address_space);
-
-		    TaskType::User(new_address_space)
-		},
-	    }
-	};
-
 	let cwd = {
 	    let old_cwd = old.cwd.read();
 	    old_cwd.clone()
@@ -213,6 +491,26 @@ impl Process {
 	    *old_sigmask
 	};
 
+	let pgid = {
+	    let old_pgid = old.pgid.read();
+	    *old_pgid
+	};
+
+	let sid = {
+	    let old_sid = old.sid.read();
+	    *old_sid
+	};
+
+	let umask = {
+	    let old_umask = old.umask.read();
+	    *old_umask
+	};
+
+	let cpu_set = {
+	    let old_cpu_set = old.cpu_set.read();
+	    *old_cpu_set
+	};
+
 	let mut context = {
 	    let old_context = old.context.read();
 	    *old_context
@@ -230,18 +528,73 @@ impl Process {
 	    cwd: RwLock::new(cwd),
 	    signals: RwLock::new(signals),
 	    sigmask: RwLock::new(sigmask),
+	    pending_signals: RwLock::new(0),  // Pending signals aren't inherited
+	    saved_sigmask: RwLock::new(None),
+	    signal_waker: Mutex::new(None),
+	    wait_event: Mutex::new(None),
+	    pgid: RwLock::new(pgid),
+	    sid: RwLock::new(sid),
+	    umask: RwLock::new(umask),
+	    credentials: RwLock::new(*old.credentials.read()),
+	    cpu_set: RwLock::new(cpu_set),
+	    ppid: RwLock::new(0),  // Set by the scheduler, as we don't know our parent's PID here
+	    cpu_time_ms: AtomicU64::new(0),
+	    peak_rss_pages: AtomicU64::new(0),
+	    time_slice_remaining: AtomicU64::new(0),
+	    preemptions: AtomicU64::new(0),
+	    executable_stack: AtomicBool::new(old.executable_stack.load(Ordering::Relaxed)),
+	    vfork_parent: Mutex::new(None),
+	    membarrier_registered: AtomicBool::new(false),
 	}
     }
 
-    pub fn attach_loaded_elf(self: Arc<Self>, elf: elf_loader::Elf, ld_so: elf_loader::Elf) {
+    // Makes the initial thread's TLS block from the program's PT_TLS template, and gives back the thread pointer. On
+    // x86_64, the block sits just below the thread pointer, which points at the TCB, the first word of which points
+    // back at itself. Only that word is filled in, and the rest of the TCB is left to the libc.
+    fn init_tls(&self, tls: &elf_loader::TlsTemplate) -> Result<u64, memory::CopyError> {
+	let block_size = tls.mem_size.next_multiple_of(tls.align);
+	let start = {
+	    let mut task_type = self.task_type.write();
+	    let address_space = match *task_type {
+		TaskType::Kernel => panic!("Attempted to set up TLS for a kernel task"),
+		TaskType::User(ref mut address_space) => address_space,
+	    };
+
+	    // With room to spare to align the thread pointer
+	    let (start, _) = memory::user_allocate(
+		block_size + tls.align + TCB_SIZE,
+		memory::MemoryAccessRestriction::User,
+		address_space).map_err(|_| memory::CopyError::TempAllocFailed)?;
+	    start
+	};
+
+	let thread_pointer = (start.as_u64() + block_size).next_multiple_of(tls.align);
+	let mut block = vec![0_u8; (block_size + TCB_SIZE) as usize];
+	block[.. tls.image.len()].copy_from_slice(&tls.image);
+	block[block_size as usize .. block_size as usize + 8].copy_from_slice(&thread_pointer.to_ne_bytes());
+	memory::copy_to_process(self, VirtAddr::new(thread_pointer - block_size), &block)?;
+
+	Ok(thread_pointer)
+    }
+
+    pub fn attach_loaded_elf(self: Arc<Self>, elf: elf_loader::Elf, ld_so: elf_loader::Elf) -> Result<(), memory::CopyError> {
 	let (_, _, user_code, user_data) = gdt::get_code_selectors();
 
+	// Before the context is locked, as copying into the process has to lock its address space
+	let fs_base = match elf.tls {
+	    Some(ref tls) => self.init_tls(tls)?,
+	    None => 0,
+	};
+	self.executable_stack.store(elf.executable_stack, Ordering::Relaxed);
+	self.membarrier_registered.store(false, Ordering::Relaxed);
+
 	let mut context = self.context.write();
 	let mut auxvs = self.auxvs.write();
 
 	context.cs = user_code.0 as u64;
 	context.ss = user_data.0 as u64;
 	context.rip = ld_so.entry;
+	context.fs_base = fs_base;
 
 	auxvs.push(AuxVector {
 	    auxv_type: AT_BASE,
@@ -263,10 +616,9 @@ impl Process {
 	    auxv_type: AT_PHNUM,
 	    value: elf.program_header_entry_count
 	});
-	auxvs.push(AuxVector {
-	    auxv_type: AT_NUL,
-	    value: 0
-	});
+
+	// AT_RANDOM and AT_NUL are added when the stack is, as AT_RANDOM points into it
+	Ok(())
     }
 
     pub fn init_stack_and_start(self: Arc<Self>) -> Result<(), memory::CopyError> {
@@ -284,18 +636,22 @@ impl Process {
 		TaskType::User(ref mut address_space) => address_space,
 	    };
 
-	    let (rsp, _) = match memory::user_allocate(
+	    // Only the pages the process actually uses get backed
+	    memory::user_reserve(
 		8 * 1024 * 1024,  // 8MiB
 		memory::MemoryAccessRestriction::User,
-		address_space) {
-		Ok(i) => i,
-		Err(e) => panic!("Could not allocate stack memory for process: {:?}", e),
-	    };
-
-	    rsp
+		self.executable_stack.load(Ordering::Relaxed),
+		address_space)
 	};
 	context.rsp = rsp.as_u64() + 8 * 1024 * 1024;  // Start at the end of the stack and grow down
 
+	// AT_RANDOM's bytes go right at the top
+	context.rsp -= 16;
+	let random_p = context.rsp;
+	let mut random_bytes = [0; 16];
+	random::fill(&mut random_bytes);
+	memory::copy_to_process(&self, VirtAddr::new(random_p), &random_bytes)?;
+
 	let envvars_buf_size: usize = envvars.iter()
 	    .map(|env_var| env_var.len() + 1)
 	    .sum();
@@ -311,8 +667,8 @@ impl Process {
 	for envvar in envvars.clone() {
 	    let envvar_len = envvar.len() + 1;
 	    let envvar_cstring = CString::new(envvar.as_str()).unwrap();
-	    memory::copy_to_user(
-		stack_ptr + (current_offs - envvar_len) as u64, envvar_cstring.as_bytes_with_nul())?;
+	    memory::copy_to_process(
+		&self, stack_ptr + (current_offs - envvar_len) as u64, envvar_cstring.as_bytes_with_nul())?;
 	    current_offs -= envvar_len;
 
 	    envvar_p.push(context.rsp + current_offs as u64);
@@ -322,14 +678,14 @@ impl Process {
 	for arg in args.clone() {
 	    let arg_len = arg.len() + 1;
 	    let arg_cstring = CString::new(arg.as_str()).unwrap();
-	    memory::copy_to_user(
-		stack_ptr + (current_offs - arg_len) as u64, arg_cstring.as_bytes_with_nul())?;
+	    memory::copy_to_process(
+		&self, stack_ptr + (current_offs - arg_len) as u64, arg_cstring.as_bytes_with_nul())?;
 
 	    current_offs -= arg_len;
 	    args_p.push(context.rsp + current_offs as u64);
 	}
 
-	context.rsp -= /* auxv = */(auxvs.len() as u64 * 16) +
+	context.rsp -= /* auxv, plus AT_RANDOM and AT_NUL = */((auxvs.len() as u64 + 2) * 16) +
 	    (envvars.len() as u64 * 8) +
 	    (args.len() as u64 * 8) +
 	/* padding = */(3 * 8);
@@ -354,26 +710,32 @@ impl Process {
 	buf.extend_from_slice(&0u64.to_ne_bytes());
 
 	// auxv entries (key, value)
-	for auxv in auxvs.iter() {
+	let random_auxv = AuxVector {
+	    auxv_type: AT_RANDOM,
+	    value: random_p,
+	};
+	let null_auxv = AuxVector {
+	    auxv_type: AT_NUL,
+	    value: 0,
+	};
+	for auxv in auxvs.iter().chain([&random_auxv, &null_auxv]) {
 	    buf.extend_from_slice(&auxv.auxv_type.to_ne_bytes());
 	    buf.extend_from_slice(&auxv.value.to_ne_bytes());
 	}
 
 	// padding
 	buf.resize(buf.len() + alignment as usize, 0);
-	memory::copy_to_user(VirtAddr::new(context.rsp), buf.as_slice())?;
+	memory::copy_to_process(&self, VirtAddr::new(context.rsp), buf.as_slice())?;
 
-	*state = TaskState::Running;
+	// An execve is still being polled, and is left to become runnable when it returns, as any other syscall does
+	if !matches!(*state, TaskState::Polling { .. }) {
+	    *state = TaskState::Running;
+	}
 	Ok(())
     }
 
     pub fn set_registers(self: Arc<Self>, rsp: u64, rip: u64, rflags: u64, registers: &GeneralPurposeRegisters) {
-	let mut context = self.context.write();
-
-	context.rsp = rsp;
-	context.rip = rip;
-	context.rflags = rflags;
-	context.gprs = *registers;
+	self.context.write().save(rsp, rip, rflags, registers);
     }
 
     pub fn get_current_signal_handler(&self, signal: u64) -> Option<signal::SignalHandler> {
@@ -396,6 +758,92 @@ impl Process {
 	*state = TaskState::Running;
     }
 
+    pub fn get_ppid(&self) -> u64 {
+	let ppid = self.ppid.read();
+	*ppid
+    }
+
+    pub fn set_ppid(&self, ppid: u64) {
+	let mut ppid_lock = self.ppid.write();
+	*ppid_lock = ppid;
+    }
+
+    pub fn charge_cpu_time(&self, ms: u64) {
+	self.cpu_time_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    // Frees everything mapped into the process' userspace, returning the number of pages freed. Used by the OOM
+    // killer, which can't wait for the process it's killed to get round to exiting.
+    pub fn release_user_space(&self) -> u64 {
+	let mut task_type = self.task_type.write();
+	match *task_type {
+	    TaskType::User(ref mut address_space) => {
+		let pages = address_space.get_mapped_pages();
+		self.peak_rss_pages.fetch_max(address_space.get_peak_mapped_pages(), Ordering::Relaxed);
+		address_space.clear_user_space();
+		pages
+	    },
+	    TaskType::Kernel => 0,
+	}
+    }
+
+    pub fn get_resource_usage(&self) -> ResourceUsage {
+	let current_peak = match *self.task_type.read() {
+	    TaskType::User(ref address_space) => address_space.get_peak_mapped_pages(),
+	    TaskType::Kernel => 0,
+	};
+
+	ResourceUsage {
+	    cpu_time_ms: self.cpu_time_ms.load(Ordering::Relaxed),
+	    peak_rss_pages: core::cmp::max(current_peak, self.peak_rss_pages.load(Ordering::Relaxed)),
+	    involuntary_switches: self.preemptions.load(Ordering::Relaxed),
+	}
+    }
+
+    pub fn get_time_slice(&self) -> u64 {
+	self.time_slice_remaining.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_time_slice(&self, ticks: u64) {
+	self.time_slice_remaining.store(ticks, Ordering::Relaxed);
+    }
+
+    pub fn consume_time_slice(&self, ticks: u64) {
+	let _ = self.time_slice_remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| Some(t.saturating_sub(ticks)));
+    }
+
+    pub fn count_preemption(&self) {
+	self.preemptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_fs_base(&self, fs_base: u64) {
+	let mut context = self.context.write();
+	context.fs_base = fs_base;
+    }
+
+    pub fn get_fs_base(&self) -> u64 {
+	let context = self.context.read();
+	context.fs_base
+    }
+
+    pub fn set_gs_base(&self, gs_base: u64) {
+	let mut context = self.context.write();
+	context.gs_base = gs_base;
+    }
+
+    pub fn get_gs_base(&self) -> u64 {
+	let context = self.context.read();
+	context.gs_base
+    }
+
+    // There's nowhere to write a core file to yet, so the "dump" is the process' registers, to the log
+    pub fn dump_core(&self, pid: u64, sig: u64) {
+	let context = self.get_context();
+	log::error!("Process {} killed by signal {}", pid, sig);
+	log::error!("rip={:#x} rsp={:#x} rflags={:#x} fs_base={:#x}", context.rip, context.rsp, context.rflags, context.fs_base);
+	log::error!("{:#x?}", context.gprs);
+    }
+
     pub fn get_context(&self) -> ProcessContext {
 	let context = self.context.read();
 	*context
@@ -411,6 +859,48 @@ impl Process {
 	*state = new_state;
     }
 
+    // Takes the syscall's future to be polled, if it's ready to be. The state changes under the one lock, so that
+    // two CPUs can't both take it.
+    pub fn take_syscall_future(&self) -> Option<Arc<Mutex<SyscallFuture>>> {
+	let mut state = self.state.write();
+	let future = match *state {
+	    TaskState::AsyncSyscall { ref future } => future.clone(),
+	    _ => return None,
+	};
+
+	*state = TaskState::Polling {
+	    woken: false,
+	};
+	Some(future)
+    }
+
+    // Puts back a future which is still pending once polled. It waits for a wake, unless one came while it was being
+    // polled.
+    pub fn park_syscall_future(&self, future: Arc<Mutex<SyscallFuture>>) {
+	let mut state = self.state.write();
+	*state = match *state {
+	    TaskState::Polling { woken: true } => TaskState::AsyncSyscall { future },
+	    _ => TaskState::Waiting { future },
+	};
+    }
+
+    // Has the syscall's future polled again, if it's waiting, or once it's been polled, if that's happening now
+    pub fn wake_syscall(&self) {
+	let mut state = self.state.write();
+	let future = match *state {
+	    TaskState::Waiting { ref future } => future.clone(),
+	    TaskState::Polling { ref mut woken } => {
+		*woken = true;
+		return;
+	    },
+	    _ => return,
+	};
+
+	*state = TaskState::AsyncSyscall {
+	    future,
+	};
+    }
+
     pub fn emplace_fd(self: Arc<Self>, fd: FileDescriptor) -> u64 {
 	let mut file_descriptors = self.file_descriptors.write();
 
@@ -425,54 +915,46 @@ impl Process {
 	panic!("No available u64 keys left!");
     }
 
-    // TODO: better error handling for out of FDs
-    pub fn emplace_fd_at(self: Arc<Self>, fd: FileDescriptor, fd_num: u64, try_greater: bool) -> u64 {
+    // Without try_greater, fd_num has to be free. With it, the lowest free fd from fd_num on is used.
+    pub fn emplace_fd_at(self: Arc<Self>, fd: FileDescriptor, fd_num: u64, try_greater: bool) -> Result<u64, syscall::CanonicalError> {
 	let mut file_descriptors = self.file_descriptors.write();
 
 	if file_descriptors.contains_key(&fd_num) && !try_greater {
-	    panic!("No available file descriptors");
+	    return Err(syscall::CanonicalError::Busy);
 	}
 
 	for i in fd_num..=u64::MAX {
 	    if let alloc::collections::btree_map::Entry::Vacant(e) = file_descriptors.entry(i) {
 		e.insert(fd);
-		return i;
+		return Ok(i);
 	    }
 	}
 
-	panic!("No available u64 keys left from {} onward!", fd_num);
+	Err(syscall::CanonicalError::MFile)
     }
 
-    pub fn set_fd_flags(self: Arc<Self>, fd: u64, flags: u64) {
+    pub fn set_fd_flags(self: Arc<Self>, fd: u64, flags: vfs::filesystem::OpenFlags) -> Result<(), syscall::CanonicalError> {
 	let mut file_descriptors = self.file_descriptors.write();
+	let actual_fd = file_descriptors.get_mut(&fd).ok_or(syscall::CanonicalError::Badf)?;
+	actual_fd.flags = flags;
 
-	if let Some(actual_fd) = file_descriptors.get_mut(&fd) {
-	    actual_fd.flags = flags;
-	} else {
-	    panic!("Could not find FD");
-	}
+	Ok(())
     }
 
-    pub fn close_fd(self: Arc<Self>, fd: u64) {
-	let mut file_descriptors = self.file_descriptors.write();
+    pub fn close_fd(self: Arc<Self>, fd: u64) -> Result<(), syscall::CanonicalError> {
+	// Dropped once the table's released, as closing the handle may have more to do
+	let _closed = remove_fd(&mut self.file_descriptors.write(), fd)?;
+	Ok(())
+    }
 
-	match file_descriptors.remove(&fd) {
-	    Some(_) => (),
-	    None => panic!("No open FD found: {}", fd),
-	}
+    pub fn clone_file_descriptors(&self) -> BTreeMap<u64, FileDescriptor> {
+	self.file_descriptors.read().clone()
     }
 
-    pub fn get_file_descriptor(&self, fd: u64) -> FileDescriptor {
-	let file_descriptors = self.file_descriptors.read();
-	
-	if let Some(actual_fd) = file_descriptors.get(&fd) {
-	    actual_fd.clone()
-	} else {
-	    panic!("Could not find FD {}", fd);
-	}
+    pub fn get_file_descriptor(&self, fd: u64) -> Result<FileDescriptor, syscall::CanonicalError> {
+	self.file_descriptors.read().get(&fd).cloned().ok_or(syscall::CanonicalError::Badf)
     }
 
-    #[allow(dead_code)]
     pub fn set_cwd(self: Arc<Self>, new_cwd: String) {
 	let mut cwd = self.cwd.write();
 	*cwd = new_cwd;
@@ -488,18 +970,386 @@ impl Process {
 	*sigmask
     }
 
-    pub fn signal_mask_block(self: Arc<Self>, newset: u64) {
+    // These all return the mask from before the change
+    pub fn signal_mask_block(self: Arc<Self>, newset: u64) -> u64 {
 	let mut sigmask = self.sigmask.write();
-	*sigmask |= newset;
+	let old = *sigmask;
+	*sigmask |= newset & !signal::UNBLOCKABLE_MASK;
+	old
     }
 
-    pub fn signal_mask_unblock(self: Arc<Self>, newset: u64) {
+    pub fn signal_mask_unblock(self: Arc<Self>, newset: u64) -> u64 {
 	let mut sigmask = self.sigmask.write();
+	let old = *sigmask;
 	*sigmask &= !newset;
+	old
     }
 
-    pub fn signal_mask_setmask(self: Arc<Self>, newset: u64) {
+    pub fn signal_mask_setmask(self: Arc<Self>, newset: u64) -> u64 {
 	let mut sigmask = self.sigmask.write();
-	*sigmask = newset;
+	let old = *sigmask;
+	*sigmask = newset & !signal::UNBLOCKABLE_MASK;
+	old
+    }
+
+    // Marks a signal as pending. It's delivered by the scheduler, next time the process returns to userspace.
+    pub fn post_signal(&self, signal: u64) {
+	{
+	    let mut pending_signals = self.pending_signals.write();
+	    // Stopping and continuing cancel out whichever of them is still pending
+	    if signal == signal::SIGCONT {
+		*pending_signals &= !signal::STOP_MASK;
+	    } else if signal::STOP_MASK & (1 << (signal - 1)) != 0 {
+		*pending_signals &= !(1 << (signal::SIGCONT - 1));
+	    }
+	    *pending_signals |= 1 << (signal - 1);
+	}
+
+	// A stopped process never gets as far as delivering a signal, so SIGCONT takes effect as soon as it's sent.
+	// SIGKILL has to get it running again too, so that it can die.
+	if signal == signal::SIGCONT || signal == signal::SIGKILL {
+	    self.resume(signal == signal::SIGCONT);
+	}
+
+	let waker = self.signal_waker.lock().take();
+	if let Some(waker) = waker {
+	    waker.wake();
+	}
+    }
+
+    // Called on the way out to userspace, so the process is never part way through a syscall when it stops
+    pub fn stop(&self, sig: u64) {
+	{
+	    let mut state = self.state.write();
+	    *state = TaskState::Stopped;
+	}
+
+	*self.wait_event.lock() = Some(scheduler::WaitStatus::Stopped(sig));
+	scheduler::notify_child_waiters(self.get_ppid());
+    }
+
+    fn resume(&self, report: bool) {
+	{
+	    let mut state = self.state.write();
+	    if !matches!(*state, TaskState::Stopped) {
+		return;
+	    }
+	    *state = TaskState::Running;
+	}
+
+	if report {
+	    *self.wait_event.lock() = Some(scheduler::WaitStatus::Continued);
+	    scheduler::notify_child_waiters(self.get_ppid());
+	}
+    }
+
+    // Takes the last stop or continue, if it's one the caller is interested in
+    pub fn take_wait_event(&self, stopped: bool, continued: bool) -> Option<scheduler::WaitStatus> {
+	let mut wait_event = self.wait_event.lock();
+	match *wait_event {
+	    Some(scheduler::WaitStatus::Stopped(_)) if stopped => wait_event.take(),
+	    Some(scheduler::WaitStatus::Continued) if continued => wait_event.take(),
+	    _ => None,
+	}
+    }
+
+    pub fn set_signal_waker(&self, waker: Waker) {
+	let mut signal_waker = self.signal_waker.lock();
+	*signal_waker = Some(waker);
+    }
+
+    // Replaces the mask until the next signal is handled, at which point the current mask comes back
+    pub fn signal_mask_suspend(self: Arc<Self>, newset: u64) {
+	let old = self.clone().signal_mask_setmask(newset);
+	let mut saved_sigmask = self.saved_sigmask.write();
+	*saved_sigmask = Some(old);
+    }
+
+    // Whether a signal is waiting which would actually do something on delivery. Anything that would just be ignored
+    // is discarded, so it doesn't interrupt waits.
+    pub fn has_deliverable_signal(&self) -> bool {
+	let sigmask = self.get_current_sigprocmask();
+	let mut pending_signals = self.pending_signals.write();
+
+	let mut deliverable = *pending_signals & !sigmask;
+	while deliverable != 0 {
+	    let sig = deliverable.trailing_zeros() as u64 + 1;
+	    deliverable &= !(1 << (sig - 1));
+
+	    let action = match self.get_current_signal_handler(sig) {
+		Some(h) => h.action(sig),
+		None => signal::default_action(sig),
+	    };
+	    match action {
+		signal::Action::Ignore => *pending_signals &= !(1 << (sig - 1)),
+		_ => return true,
+	    }
+	}
+
+	false
     }
+
+    #[allow(dead_code)]
+    pub fn get_pending_signals(&self) -> u64 {
+	let pending_signals = self.pending_signals.read();
+	*pending_signals
+    }
+
+    // Takes the lowest numbered pending signal which isn't blocked, and either sets the process up to run its handler
+    // or tells the scheduler what else needs to happen. Must be called with the process' address space active.
+    pub fn deliver_pending_signal(self: Arc<Self>) -> signal::Delivery {
+	loop {
+	    let sig = {
+		let sigmask = self.sigmask.read();
+		let mut pending_signals = self.pending_signals.write();
+
+		let deliverable = *pending_signals & !*sigmask;
+		if deliverable == 0 {
+		    drop(pending_signals);
+		    drop(sigmask);
+		    self.clone().restore_saved_sigmask();
+		    return signal::Delivery::None;
+		}
+
+		let sig = deliverable.trailing_zeros() as u64 + 1;
+		*pending_signals &= !(1 << (sig - 1));
+		sig
+	    };
+
+	    let handler = self.get_current_signal_handler(sig);
+	    let action = match handler {
+		Some(ref h) => h.action(sig),
+		None => signal::default_action(sig),
+	    };
+
+	    match action {
+		signal::Action::Ignore => continue,
+		signal::Action::Terminate => return signal::Delivery::Terminate(sig),
+		signal::Action::CoreDump => return signal::Delivery::CoreDump(sig),
+		signal::Action::Stop => return signal::Delivery::Stop(sig),
+		signal::Action::Handler => {
+		    let handler = handler.expect("Signal has a handler action, but no handler");
+		    if self.clone().enter_signal_handler(sig, &handler).is_err() {
+			// Nowhere to put the signal frame, so there's nothing sensible left to do with the process
+			return signal::Delivery::Terminate(signal::SIGKILL);
+		    }
+
+		    return signal::Delivery::Handler;
+		},
+	    }
+	}
+    }
+
+    fn restore_saved_sigmask(self: Arc<Self>) {
+	let saved = self.saved_sigmask.write().take();
+	if let Some(mask) = saved {
+	    self.signal_mask_setmask(mask);
+	}
+    }
+
+    fn enter_signal_handler(self: Arc<Self>, sig: u64, handler: &signal::SignalHandler) -> Result<(), memory::CopyError> {
+	let context = self.get_context();
+	// If the mask was temporarily replaced, the handler's return should take us back to the original
+	let old_mask = match self.saved_sigmask.write().take() {
+	    Some(mask) => mask,
+	    None => self.get_current_sigprocmask(),
+	};
+
+	let frame = SignalFrame {
+	    restorer: handler.restorer() as u64,
+	    gprs: context.gprs,
+	    rflags: context.rflags,
+	    rip: context.rip,
+	    rsp: context.rsp,
+	    sigmask: old_mask,
+	};
+
+	// The handler should be entered as if it had just been called, i.e. with rsp + 8 16-byte aligned
+	// The stack pointer is whatever userspace left it as, so may well not be valid
+	let frame_addr = (context.rsp.wrapping_sub(RED_ZONE_SIZE + core::mem::size_of::<SignalFrame>() as u64) & !0xf).wrapping_sub(8);
+	let frame_ptr = memory::validate_user_ptr(frame_addr, core::mem::size_of::<SignalFrame>() as u64)
+	    .map_err(|_| memory::CopyError::Fault)?;
+	memory::copy_value_to_user::<SignalFrame>(frame_ptr, &frame)?;
+
+	{
+	    let mut context = self.context.write();
+	    context.rsp = frame_addr;
+	    context.rip = handler.entry() as u64;
+	    context.gprs.rdi = sig;
+	    // We don't yet provide siginfo or ucontext for SA_SIGINFO handlers
+	    context.gprs.rsi = 0;
+	    context.gprs.rdx = 0;
+	}
+
+	self.signal_mask_block(handler.handler_mask(sig));
+	Ok(())
+    }
+
+    // Restores the state saved when a signal handler was entered. Must be called from sigreturn, which the handler's
+    // return to the restorer will have called with the frame just above the stack pointer.
+    pub fn return_from_signal_handler(self: Arc<Self>) -> Result<GeneralPurposeRegisters, memory::CopyError> {
+	let rsp = self.get_context().rsp;
+	let frame_ptr = memory::validate_user_ptr(rsp.wrapping_sub(8), core::mem::size_of::<SignalFrame>() as u64)
+	    .map_err(|_| memory::CopyError::Fault)?;
+	let frame = memory::copy_value_from_user::<SignalFrame>(frame_ptr)?;
+
+	{
+	    let mut context = self.context.write();
+	    context.gprs = frame.gprs;
+	    context.rip = frame.rip;
+	    context.rsp = frame.rsp;
+	    // Don't let userspace set anything more privileged than the arithmetic flags and DF
+	    context.rflags = (frame.rflags & 0xcd5) | 0x202;
+	}
+
+	self.signal_mask_setmask(frame.sigmask);
+	Ok(frame.gprs)
+    }
+
+    pub fn get_pgid(&self) -> u64 {
+	let pgid = self.pgid.read();
+	*pgid
+    }
+
+    pub fn set_pgid(&self, new_pgid: u64) {
+	let mut pgid = self.pgid.write();
+	*pgid = new_pgid;
+    }
+
+    pub fn get_sid(&self) -> u64 {
+	let sid = self.sid.read();
+	*sid
+    }
+
+    pub fn set_sid(&self, new_sid: u64) {
+	let mut sid = self.sid.write();
+	*sid = new_sid;
+    }
+
+    pub fn get_umask(&self) -> u32 {
+	let umask = self.umask.read();
+	*umask
+    }
+
+    pub fn get_cpu_set(&self) -> u64 {
+	let cpu_set = self.cpu_set.read();
+	*cpu_set
+    }
+
+    pub fn set_cpu_set(&self, new_cpu_set: u64) {
+	let mut cpu_set = self.cpu_set.write();
+	*cpu_set = new_cpu_set;
+    }
+
+    pub fn may_run_on(&self, cpu: u64) -> bool {
+	cpu < 64 && self.get_cpu_set() & (1 << cpu) != 0
+    }
+
+    pub fn get_credentials(&self) -> Credentials {
+	*self.credentials.read()
+    }
+
+    // As POSIX setuid: root sets all three IDs, anyone else may only switch the effective ID to their real or
+    // saved one
+    pub fn set_uid(&self, uid: u32) -> Result<(), syscall::CanonicalError> {
+	let mut credentials = self.credentials.write();
+	if credentials.is_privileged() {
+	    credentials.uid = uid;
+	    credentials.euid = uid;
+	    credentials.suid = uid;
+	} else if uid == credentials.uid || uid == credentials.suid {
+	    credentials.euid = uid;
+	} else {
+	    return Err(syscall::CanonicalError::Perm);
+	}
+
+	Ok(())
+    }
+
+    // As set_uid, for the group IDs. Whether it's privileged still depends on the effective user ID.
+    pub fn set_gid(&self, gid: u32) -> Result<(), syscall::CanonicalError> {
+	let mut credentials = self.credentials.write();
+	if credentials.is_privileged() {
+	    credentials.gid = gid;
+	    credentials.egid = gid;
+	    credentials.sgid = gid;
+	} else if gid == credentials.gid || gid == credentials.sgid {
+	    credentials.egid = gid;
+	} else {
+	    return Err(syscall::CanonicalError::Perm);
+	}
+
+	Ok(())
+    }
+
+    // Returns the previous mask, as umask(2) does
+    pub fn set_umask(&self, new_umask: u32) -> u32 {
+	let mut umask = self.umask.write();
+	core::mem::replace(&mut *umask, new_umask & 0o777)
+    }
+}
+
+fn remove_fd(file_descriptors: &mut BTreeMap<u64, FileDescriptor>, fd: u64) -> Result<FileDescriptor, syscall::CanonicalError> {
+    file_descriptors.remove(&fd).ok_or(syscall::CanonicalError::Badf)
+}
+
+#[test]
+fn closing_a_bad_fd_is_ebadf() {
+    let mut file_descriptors = BTreeMap::new();
+    assert!(matches!(remove_fd(&mut file_descriptors, 3), Err(syscall::CanonicalError::Badf)));
+}
+
+#[test]
+fn the_tls_base_survives_being_switched_away_from() {
+    let mut context = ProcessContext {
+	cs: 0x23,
+	..ProcessContext::default()
+    };
+    // As ARCH_SET_FS and ARCH_SET_GS leave it
+    context.fs_base = 0x7fff_1000;
+    context.gs_base = 0x7fff_2000;
+
+    // Preempted, and later resumed and preempted again, with different registers each time
+    let mut registers = GeneralPurposeRegisters::default();
+    for rip in [0x40_1000, 0x40_2000] {
+	registers.rax = rip;
+	context.save(0x7ffe_0000, rip, 0x202, &registers);
+    }
+
+    assert_eq!(context.rip, 0x40_2000);
+    assert_eq!(context.gprs.rax, 0x40_2000);
+    assert_eq!(context.fs_base, 0x7fff_1000);
+    assert_eq!(context.gs_base, 0x7fff_2000);
+    assert!(context.is_user());
+}
+
+#[test]
+fn a_signal_raised_while_blocked_is_delivered_once_unblocked() {
+    let process = Arc::new(Process::new_kernel(ProcessContext::default()));
+    let term = 1 << (signal::SIGTERM - 1);
+
+    process.clone().signal_mask_block(term);
+    process.post_signal(signal::SIGTERM);
+    assert!(!process.has_deliverable_signal());
+    assert!(matches!(process.clone().deliver_pending_signal(), signal::Delivery::None));
+    assert_eq!(process.get_pending_signals(), term);
+
+    assert_eq!(process.clone().signal_mask_unblock(term), term);
+    assert!(process.has_deliverable_signal());
+    assert!(matches!(process.clone().deliver_pending_signal(), signal::Delivery::Terminate(signal::SIGTERM)));
+    assert!(matches!(process.clone().deliver_pending_signal(), signal::Delivery::None));
+}
+
+#[test]
+fn a_pinned_task_only_runs_on_the_cpus_it_was_given() {
+    let process = Process::new_kernel(ProcessContext::default());
+    assert!((0..64).all(|cpu| process.may_run_on(cpu)));
+
+    // As sched_setaffinity leaves it, pinned to CPUs 1 and 3
+    process.set_cpu_set(0b1010);
+    let allowed: Vec<u64> = (0..64).filter(|cpu| process.may_run_on(*cpu)).collect();
+    assert_eq!(allowed, [1, 3]);
+
+    // A CPU past the end of the mask is never allowed, rather than overflowing the shift
+    assert!(!process.may_run_on(64));
 }
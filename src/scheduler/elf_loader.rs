@@ -1,26 +1,105 @@
 use alloc::string::String;
+use alloc::sync::Arc;
 use anyhow::{anyhow, Result};
 use xmas_elf::{header, ElfFile, program::{SegmentData, Type}};
 use x86_64::VirtAddr;
 use alloc::vec;
+use alloc::vec::Vec;
 
 use crate::memory;
 use crate::process;
 use crate::scheduler;
+use crate::sys::syscall::CanonicalError;
 use crate::vfs;
 
+// How much of a script is read looking for its "#!" line, which has to fit, or the script won't run
+const MAX_SHEBANG_LINE: usize = 256;
+// How many scripts deep an interpreter can itself be a script, so that scripts naming each other can't go on forever
+const MAX_INTERPRETER_DEPTH: usize = 4;
+
+// Says whether the stack should be executable, and isn't otherwise loaded
+const PT_GNU_STACK: u32 = 0x6474e551;
+
+// The interpreter a "#!" line names, and the argument after it, if any
+async fn read_shebang(path: &str) -> Result<Option<(String, Option<String>)>, CanonicalError> {
+    let fh = vfs::vfs_open(path, vfs::filesystem::OpenFlags::empty(), 0).await?;
+    let start = fh.read(MAX_SHEBANG_LINE as u64).await?;
+    if !start.starts_with(b"#!") {
+	return Ok(None);
+    }
+
+    let line = match start.iter().position(|&c| c == b'\n') {
+	Some(end) => &start[2 .. end],
+	// A script that's only the one line needn't end it
+	None if start.len() < MAX_SHEBANG_LINE => &start[2 ..],
+	None => return Err(CanonicalError::NoExec),
+    };
+    let line = core::str::from_utf8(line).map_err(|_| CanonicalError::NoExec)?.trim();
+
+    // As on Linux, everything after the interpreter is a single argument, spaces and all
+    let (interpreter, arg) = match line.split_once([' ', '\t']) {
+	Some((interpreter, arg)) => (interpreter, Some(arg.trim())),
+	None => (line, None),
+    };
+    if interpreter.is_empty() {
+	return Err(CanonicalError::NoExec);
+    }
+
+    Ok(Some((String::from(interpreter), arg.filter(|arg| !arg.is_empty()).map(String::from))))
+}
+
+// Works out what's actually run for path, and with what arguments, where args[0] is the path. A script starting with
+// "#!" is run by the interpreter it names, which is given the optional argument from that line, then the script's
+// path, then the rest of the script's arguments.
+pub async fn resolve_interpreter(mut path: String, mut args: Vec<String>) -> Result<(String, Vec<String>), CanonicalError> {
+    for depth in 0 ..= MAX_INTERPRETER_DEPTH {
+	let (interpreter, arg) = match read_shebang(&path).await? {
+	    Some(shebang) => shebang,
+	    None => return Ok((path, args)),
+	};
+	if depth == MAX_INTERPRETER_DEPTH {
+	    break;
+	}
+
+	let mut interpreter_args = vec![interpreter.clone()];
+	interpreter_args.extend(arg);
+	interpreter_args.push(path);
+	interpreter_args.extend(args.into_iter().skip(1));
+
+	path = interpreter;
+	args = interpreter_args;
+    }
+
+    Err(CanonicalError::Loop)
+}
+
+// What a thread's TLS block starts out as: the image, followed by zeroes up to mem_size
+pub struct TlsTemplate {
+    pub image: Vec<u8>,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
 pub struct Elf {
     pub entry: u64,
     pub base: u64,
     pub program_header: u64,
     pub program_header_entry_size: u64,
     pub program_header_entry_count: u64,
+    // Only if PT_GNU_STACK asks for it, so a program without one gets a no-execute stack
+    pub executable_stack: bool,
+    pub tls: Option<TlsTemplate>,
 }
 
 impl Elf {
     pub async fn new(file_name: String) -> Result<Elf> {
+	Self::load(scheduler::get_current_process(), file_name).await
+    }
+
+    // Loads into the given process' address space, which needn't be the running one
+    pub async fn load(process: Arc<process::Process>, file_name: String) -> Result<Elf> {
 	log::info!("a");
-	let fh = vfs::vfs_open(&file_name).await?;
+	let fh = vfs::vfs_open(&file_name, vfs::filesystem::OpenFlags::empty(), 0).await?;
 	log::info!("a");
 	let stat = fh.clone().stat()?;
 
@@ -61,7 +140,6 @@ impl Elf {
 
 	log::info!("a");
 	let virt_start_addr = {
-	    let process = scheduler::get_current_process();
 	    let mut task_type = process.task_type.write();
 
 	    match *task_type {
@@ -104,15 +182,38 @@ impl Elf {
 	{
 	    let size_to_zero = highest_virt_addr.expect("No loadable sections were found") - lowest_virt_addr.expect("No loadable sections were found");
 	    let empty_buf = vec![0; size_to_zero as usize];
-	    memory::copy_to_user(virt_start_addr, empty_buf.as_slice())?;
+	    memory::copy_to_process(&process, virt_start_addr, empty_buf.as_slice())?;
 	}
 
 	log::info!("a");
+	let mut executable_stack = false;
+	let mut tls = None;
 	for program_header in elf.program_iter() {
-	    // PT_LOAD is a loadable segment that needs to be in the address space.
-	    // All else can be skipped.
+	    // PT_LOAD is a loadable segment that needs to be in the address space. PT_TLS and PT_GNU_STACK are only
+	    // noted, for when the process is started. All else can be skipped.
 	    match program_header.get_type() {
 		Ok(Type::Load) => (),
+		Ok(Type::Tls) => {
+		    let image = match program_header.get_data(&elf) {
+			Ok(SegmentData::Undefined(data)) => data.to_vec(),
+			Ok(_) => return Err(anyhow!("Could not parse TLS header: invalid SegmentData type")),
+			Err(e) => return Err(anyhow!("Could not parse TLS header: {}", e)),
+		    };
+		    if image.len() as u64 > program_header.mem_size() {
+			return Err(anyhow!("Could not parse TLS header: image is bigger than the TLS block"));
+		    }
+
+		    tls = Some(TlsTemplate {
+			image,
+			mem_size: program_header.mem_size(),
+			align: core::cmp::max(program_header.align(), 1),
+		    });
+		    continue;
+		},
+		Ok(Type::OsSpecific(PT_GNU_STACK)) => {
+		    executable_stack = program_header.flags().is_execute();
+		    continue;
+		},
 		Ok(_) => continue,
 		Err(e) => {
 		    return Err(anyhow!("Could not parse program header: {}", e));
@@ -137,7 +238,7 @@ impl Elf {
 	    };
 
 	    if program_header.file_size() != 0 {
-		memory::copy_to_user(virt_header_start_addr, data)?;
+		memory::copy_to_process(&process, virt_header_start_addr, data)?;
 	    }
 	}
 
@@ -155,6 +256,8 @@ impl Elf {
 	    program_header: virt_start_addr.as_u64() + elf.header.pt2.ph_offset(),
 	    program_header_entry_size: elf.header.pt2.ph_entry_size() as u64,
 	    program_header_entry_count: elf.header.pt2.ph_count() as u64,
+	    executable_stack,
+	    tls,
 	})
     }
 }
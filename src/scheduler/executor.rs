@@ -0,0 +1,191 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::{poll_fn, Future};
+use core::mem::offset_of;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::gdt;
+use crate::process;
+use crate::scheduler;
+use crate::sys::syscall::CanonicalError;
+
+// Kernel background work (device enumeration, writeback and the like) runs here, on its own kthread, rather than
+// each driver needing a kthread of its own or blocking a process' syscall
+const MAX_KERNEL_TASKS: u64 = 64;
+
+type KernelFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct KernelTask {
+    // None once the future has finished
+    future: Mutex<Option<KernelFuture>>,
+    // Set while the task is in READY_TASKS, so that waking it repeatedly only queues it once
+    queued: AtomicBool,
+}
+
+impl Wake for KernelTask {
+    fn wake(self: Arc<Self>) {
+	self.wake_by_ref();
+    }
+
+    // May be called from interrupt context, e.g. by Completion::complete
+    fn wake_by_ref(self: &Arc<Self>) {
+	if self.queued.swap(true, Ordering::SeqCst) {
+	    return;
+	}
+
+	without_interrupts(|| READY_TASKS.lock().push_back(self.clone()));
+	wake_executor();
+    }
+}
+
+// Only ever locked with interrupts disabled, as tasks can be woken from IRQ handlers
+static READY_TASKS: Mutex<VecDeque<Arc<KernelTask>>> = Mutex::new(VecDeque::new());
+static EXECUTOR_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+static TASK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn wake_executor() {
+    let waker = without_interrupts(|| EXECUTOR_WAKER.lock().take());
+    if let Some(waker) = waker {
+	scheduler::defer_wake(waker);
+    }
+}
+
+// Runs future to completion on the executor kthread. Fails with Again if too many tasks are already running.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Result<(), CanonicalError> {
+    if TASK_COUNT.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_KERNEL_TASKS).then_some(n + 1)).is_err() {
+	return Err(CanonicalError::Again);
+    }
+
+    let task = Arc::new(KernelTask {
+	future: Mutex::new(Some(Box::pin(future))),
+	queued: AtomicBool::new(false),
+    });
+    task.wake_by_ref();
+
+    Ok(())
+}
+
+// Polls each task which was ready at the start of the round once. Anything woken during the round, including a task
+// waking itself, waits for the next one, so a busy task can't keep the executor from ever yielding.
+fn run_ready_tasks(cx: &mut Context<'_>) -> Poll<()> {
+    // Registered before looking at the queue, so a wake between the two isn't lost
+    without_interrupts(|| *EXECUTOR_WAKER.lock() = Some(cx.waker().clone()));
+
+    let ready = without_interrupts(|| READY_TASKS.lock().len());
+    for _ in 0 .. ready {
+	let task = match without_interrupts(|| READY_TASKS.lock().pop_front()) {
+	    Some(t) => t,
+	    None => break,
+	};
+	task.queued.store(false, Ordering::SeqCst);
+
+	let waker = Waker::from(task.clone());
+	let mut task_cx = Context::from_waker(&waker);
+	let mut future = task.future.lock();
+	if let Some(ref mut f) = *future {
+	    if f.as_mut().poll(&mut task_cx).is_ready() {
+		*future = None;
+		TASK_COUNT.fetch_sub(1, Ordering::SeqCst);
+	    }
+	}
+    }
+
+    if !without_interrupts(|| READY_TASKS.lock().is_empty()) {
+	wake_executor();
+    }
+
+    Poll::Pending
+}
+
+pub fn init() {
+    scheduler::kthread_start(kthread_executor);
+}
+
+fn kthread_executor() -> ! {
+    // run_ready_tasks never resolves, so this runs for as long as the system does
+    let fut = async {
+	loop {
+	    poll_fn(run_ready_tasks).await;
+	}
+    };
+
+    let process = scheduler::get_current_process();
+    process.set_state(process::TaskState::AsyncSyscall {
+	future: Arc::new(Mutex::new(Box::pin(fut))),
+    });
+
+    unsafe {
+	// Switch to the kernel stack before calling schedule_next, as the non-task kernel depends on a kstack
+	core::arch::asm!(
+	    // Save the stack pointer (note that, because this is a kthread, swapping gs is unnecessary)
+	    // Disable interrupts, as the kernel stack assumes no interrupts
+	    "cli",
+	    "mov gs:[{sp}], rsp",
+	    "mov rsp, gs:[{ksp}]",
+
+	    sp = const(offset_of!(gdt::ProcessorControlBlock, tmp_user_stack_ptr)),
+	    ksp = const(offset_of!(gdt::ProcessorControlBlock, tss) + offset_of!(TaskStateSegment, privilege_stack_table)),
+	);
+    }
+
+    scheduler::schedule_next();
+}
+
+#[test]
+fn a_spawned_task_is_run_and_no_more_than_the_limit_may_be_waiting() {
+    struct Executor(AtomicBool);
+    impl futures_util::task::ArcWake for Executor {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+	    arc_self.0.store(true, Ordering::SeqCst);
+	}
+    }
+
+    let executor = Arc::new(Executor(AtomicBool::new(false)));
+    let waker = futures_util::task::waker(executor.clone());
+    let mut cx = Context::from_waker(&waker);
+    assert!(run_ready_tasks(&mut cx).is_pending());
+
+    // Spawning wakes the executor, which then runs the task
+    let ran = Arc::new(AtomicBool::new(false));
+    let r = ran.clone();
+    spawn(async move { r.store(true, Ordering::SeqCst) }).unwrap();
+    scheduler::run_deferred_wakes();
+    assert!(executor.0.load(Ordering::SeqCst));
+    assert!(run_ready_tasks(&mut cx).is_pending());
+    assert!(ran.load(Ordering::SeqCst));
+    assert_eq!(TASK_COUNT.load(Ordering::SeqCst), 0);
+
+    // Tasks which haven't finished each take up a slot until they do
+    let gate = Arc::new(AtomicBool::new(false));
+    let wakers = Arc::new(Mutex::new(alloc::vec::Vec::new()));
+    for _ in 0 .. MAX_KERNEL_TASKS {
+	let (gate, wakers) = (gate.clone(), wakers.clone());
+	spawn(poll_fn(move |cx| {
+	    if gate.load(Ordering::SeqCst) {
+		Poll::Ready(())
+	    } else {
+		wakers.lock().push(cx.waker().clone());
+		Poll::Pending
+	    }
+	})).unwrap();
+    }
+    assert!(matches!(spawn(async {}), Err(CanonicalError::Again)));
+    assert!(run_ready_tasks(&mut cx).is_pending());
+    assert!(matches!(spawn(async {}), Err(CanonicalError::Again)));
+
+    gate.store(true, Ordering::SeqCst);
+    for waker in core::mem::take(&mut *wakers.lock()) {
+	waker.wake();
+    }
+    assert!(run_ready_tasks(&mut cx).is_pending());
+    assert_eq!(TASK_COUNT.load(Ordering::SeqCst), 0);
+    assert!(spawn(async {}).is_ok());
+    assert!(run_ready_tasks(&mut cx).is_pending());
+}
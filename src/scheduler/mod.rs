@@ -3,18 +3,218 @@ use alloc::vec::Vec;
 use spin::{Once, RwLock, Mutex};
 use alloc::collections::BTreeMap;
 use alloc::boxed::Box;
+use core::future::poll_fn;
+use core::mem::offset_of;
+use core::task::{Poll, Waker};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::{FsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
 
-use crate::drivers::hpet;
-use crate::sys::syscall;
+use crate::cmdline;
+use crate::gdt;
+use crate::interrupts;
+use crate::sys::syscall::CanonicalError;
 use crate::process;
+use crate::utils::rwlock::FairRwLock;
 
 pub mod elf_loader;
+pub mod executor;
 pub mod signal;
+pub mod trace;
 mod process_waker;
 
-pub static PROCESS_TABLE: Once<RwLock<BTreeMap<u64, Arc<process::Process>>>> = Once::new();
-pub static RUNNING_PROCESS: Once<RwLock<Option<u64>>> = Once::new();
+// Writer-preferring, so that fork and exit aren't held up indefinitely by everything looking processes up. That means
+// it mustn't be read again while already held, so nothing holding it may wake a process or post it a signal, both
+// of which look processes up.
+pub static PROCESS_TABLE: Once<FairRwLock<BTreeMap<u64, Arc<process::Process>>>> = Once::new();
 pub static NEXT_PID: Once<Mutex<u64>> = Once::new();
+// Exited processes which haven't yet been reaped by their parent, keyed by PID
+static EXITED_PROCESSES: Once<RwLock<BTreeMap<u64, ExitedProcess>>> = Once::new();
+// Wakers for processes blocked in wait, keyed by the waiting (parent) PID
+static CHILD_WAITERS: Once<Mutex<BTreeMap<u64, Vec<Waker>>>> = Once::new();
+
+// Wakers signalled from interrupt context, to be run the next time we schedule. Waking a task takes the process table
+// lock, which the interrupted code may hold, so it can't be done directly from an IRQ handler.
+static DEFERRED_WAKES: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+// Parents to send SIGCHLD to the next time we schedule, as a child stopped or continued. Looking the parent up takes
+// the process table lock, which whatever posted the signal behind it may hold.
+static DEFERRED_SIGCHLDS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+// Wakers for anything waiting on other CPUs to take an IPI. Also taken by the IPI handler, so only ever locked with
+// interrupts disabled.
+static IPI_WAITERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+const INIT_PID: u64 = 1;
+
+const TICK_NS: u64 = 1_000_000;
+
+// Number of timer ticks (ms) a task may run for before it's preempted, if it doesn't block first
+const DEFAULT_TIME_SLICE_TICKS: u64 = 10;
+static TIME_SLICE_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_TIME_SLICE_TICKS);
+
+// Affinity masks have a bit per CPU, so no more than this many are used
+pub const MAX_CPUS: usize = 64;
+
+// What a CPU is doing
+struct CpuState {
+    // The process the kernel is acting for, which is either the one being run, or the one whose syscall is being
+    // polled. Kept with its PID, so interrupt handlers can find it without the process table.
+    running: RwLock<Option<(u64, Arc<process::Process>)>>,
+    // The process whose registers are live on this CPU. No other CPU may run it until this one is back in the
+    // scheduler, by which point they've been saved. Only changed with the process table held for writing.
+    scheduled: Mutex<Option<u64>>,
+    // Each CPU has an idle thread of its own, which is PID 0, but isn't in the process table
+    idle: Once<Arc<process::Process>>,
+    // Bumped every time the CPU takes an IPI
+    ipis: AtomicU64,
+    // Number of exception handlers running on the CPU
+    fault_depth: AtomicU64,
+}
+
+impl CpuState {
+    const fn new() -> Self {
+	CpuState {
+	    running: RwLock::new(None),
+	    scheduled: Mutex::new(None),
+	    idle: Once::new(),
+	    ipis: AtomicU64::new(0),
+	    fault_depth: AtomicU64::new(0),
+	}
+    }
+}
+
+// By CPU number, as given out by gdt
+static CPUS: [CpuState; MAX_CPUS] = [const { CpuState::new() }; MAX_CPUS];
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+pub fn current_cpu() -> u64 {
+    gdt::current_cpu()
+}
+
+pub fn online_cpu_mask() -> u64 {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+fn this_cpu() -> &'static CpuState {
+    &CPUS[current_cpu() as usize]
+}
+
+pub fn fault_depth() -> &'static AtomicU64 {
+    &this_cpu().fault_depth
+}
+
+// Interrupts every other CPU, and waits until each has taken the interrupt, by which point it has stopped whatever it
+// was running when this was called, if only for a moment. Interrupts are serialising, so whatever it was running now
+// sees memory as this CPU does.
+pub async fn wait_for_other_cpus() {
+    let before = ipi_counts_of_others(current_cpu(), online_cpu_mask());
+    if before.is_empty() {
+	return;
+    }
+
+    interrupts::send_ipi_to_others();
+
+    poll_fn(|cx| {
+	// Before checking, so that an IPI taken in between still wakes us
+	without_interrupts(|| IPI_WAITERS.lock().push(cx.waker().clone()));
+
+	if all_taken_ipi(&before) {
+	    Poll::Ready(())
+	} else {
+	    Poll::Pending
+	}
+    }).await;
+}
+
+// Every online CPU but this one, with how many IPIs it has taken so far
+fn ipi_counts_of_others(this: u64, online: u64) -> Vec<(usize, u64)> {
+    CPUS.iter()
+	.enumerate()
+	.filter(|(cpu, _)| *cpu as u64 != this && online & (1 << cpu) != 0)
+	.map(|(cpu, state)| (cpu, state.ipis.load(Ordering::SeqCst)))
+	.collect()
+}
+
+fn all_taken_ipi(before: &[(usize, u64)]) -> bool {
+    before.iter().all(|(cpu, ipis)| CPUS[*cpu].ipis.load(Ordering::SeqCst) != *ipis)
+}
+
+// Runs from the IPI, so the process table may be held by whatever was interrupted, and the wakes are deferred
+fn ack_ipi() {
+    this_cpu().ipis.fetch_add(1, Ordering::SeqCst);
+
+    let waiters = core::mem::take(&mut *IPI_WAITERS.lock());
+    for waker in waiters {
+	defer_wake(waker);
+    }
+}
+
+// Whatever any CPU is running, or acting for
+fn busy_pids() -> Vec<u64> {
+    CPUS.iter()
+	.flat_map(|cpu| [cpu.running.read().as_ref().map(|(pid, _)| *pid), *cpu.scheduled.lock()])
+	.flatten()
+	.collect()
+}
+
+// Why a process stopped running, as reported to its parent by wait
+#[derive(Clone, Copy, Debug)]
+pub enum WaitStatus {
+    Exited(u64),
+    // Never with the core dump flag, as no core file is written, only the registers to the log
+    Signaled(u64),
+    Stopped(u64),
+    Continued,
+}
+
+impl WaitStatus {
+    // The encoding userspace's WIFEXITED, WEXITSTATUS, WIFSIGNALED and WTERMSIG expect: the exit code in bits 8-15,
+    // or the terminating signal in the low 7 bits. Stopped processes have 0x7f in the low byte and the stopping signal
+    // above, and continued ones are all ones
+    pub fn encode(&self) -> i32 {
+	match *self {
+	    WaitStatus::Exited(exit_code) => ((exit_code & 0xff) << 8) as i32,
+	    WaitStatus::Signaled(signal) => (signal & 0x7f) as i32,
+	    WaitStatus::Stopped(signal) => (((signal & 0xff) << 8) | 0x7f) as i32,
+	    WaitStatus::Continued => 0xffff,
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExitedProcess {
+    ppid: u64,
+    pgid: u64,
+    // Already encoded as wait returns it, see WaitStatus
+    pub status: i32,
+    pub usage: process::ResourceUsage,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WaitOptions {
+    pub nohang: bool,
+    // Also report children which have stopped (WUNTRACED) or been continued (WCONTINUED) since they were last waited on
+    pub stopped: bool,
+    pub continued: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WaitSelector {
+    Any,
+    Pid(u64),
+    Group(u64),
+}
+
+impl WaitSelector {
+    fn matches(&self, pid: u64, pgid: u64) -> bool {
+	match *self {
+	    WaitSelector::Any => true,
+	    WaitSelector::Pid(p) => p == pid,
+	    WaitSelector::Group(g) => g == pgid,
+	}
+    }
+}
 
 fn idle_thread() -> ! {
     loop {
@@ -23,44 +223,68 @@ fn idle_thread() -> ! {
 }
 
 pub fn init() {
-    PROCESS_TABLE.call_once(|| RwLock::new(BTreeMap::new()));
-    RUNNING_PROCESS.call_once(|| RwLock::new(None));
+    PROCESS_TABLE.call_once(|| FairRwLock::new(BTreeMap::new()));
     NEXT_PID.call_once(|| Mutex::new(1));  // PID 0 is idle thread
+    EXITED_PROCESSES.call_once(|| RwLock::new(BTreeMap::new()));
+    CHILD_WAITERS.call_once(|| Mutex::new(BTreeMap::new()));
 
-    let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
-    process_tbl.insert(0, Arc::new(process::Process::new_kthread(idle_thread as *const () as usize as u64)));
+    // The only work done on each tick is CPU time accounting. This provides a stable, monotonic tick to the kernel.
+    // By virtue of the fact that interrutps all return via the scheduler, a new process will always be scheduled as appropriate.
+    // Each CPU ticks off its own local APIC timer, leaving the HPET for keeping time.
+    interrupts::set_apic_timer_handler(Box::new(charge_running_process));
+    interrupts::set_ipi_handler(Box::new(ack_ipi));
+
+    // In milliseconds, which is what a tick is
+    if let Some(slice) = cmdline::get("time_slice") {
+	match slice.parse::<u64>() {
+	    Ok(ticks) => set_time_slice(ticks),
+	    Err(_) => log::warn!("time_slice should be a number of milliseconds, not {}", slice),
+	}
+    }
 }
 
+// Run by every CPU once it's been brought up, to start running processes on it
 pub fn start() -> ! {
-    // This function doesn't actually need to do anything at all. This provides a stable, monotonic tick to the kernel.
-    // By virtue of the fact that interrutps all return via the scheduler, a new process will always be scheduled as appropriate.
-    hpet::add_periodic(1, Box::new(|| {}));
+    this_cpu().idle.call_once(|| Arc::new(process::Process::new_kthread(idle_thread as *const () as usize as u64)));
+    ONLINE_CPUS.fetch_or(1 << current_cpu(), Ordering::SeqCst);
+
+    interrupts::start_apic_timer(TICK_NS);
     schedule_next();
 }
 
-pub fn kthread_start(f: fn() -> !) {
-    let pid = {
-	let mut next_pid = NEXT_PID.get().expect("Attempted to access next PID before it is initialised").lock();
-	let pid = *next_pid;
-	*next_pid += 1;
-	pid
+fn set_time_slice(ticks: u64) {
+    // A zero length slice would never let anything run
+    TIME_SLICE_TICKS.store(core::cmp::max(ticks, 1), Ordering::Relaxed);
+}
+
+// Runs from the timer interrupt, so we can't wait on locks the interrupted code may be holding
+fn charge_running_process() {
+    let running_process = match this_cpu().running.try_read() {
+	Some(r) => r.clone(),
+	None => return,
     };
 
+    if let Some((_, process)) = running_process {
+	process.charge_cpu_time(1);
+	process.consume_time_slice(1);
+    }
+}
+
+pub fn kthread_start(f: fn() -> !) {
+    let pid = allocate_pid();
+    let process = Arc::new(process::Process::new_kthread(f as usize as u64));
+
     {
 	let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
-	process_tbl.insert(pid, Arc::new(process::Process::new_kthread(f as usize as u64)));
+	process_tbl.insert(pid, process.clone());
 
-	let mut running_process = RUNNING_PROCESS.get().expect("Attempted to access running process before it is initialised").write();
-	*running_process = Some(pid);
+	*this_cpu().running.write() = Some((pid, process));
     };
 }
 
-pub fn get_current_pid() -> u64 {    
-    let running_process = RUNNING_PROCESS
-	.get()
-	.expect("RUNNING_PROCESS not initialized")
-	.read();
-    running_process.expect("Couldn't find running PID")
+pub fn get_current_pid() -> u64 {
+    let running_process = this_cpu().running.read();
+    running_process.as_ref().map(|(pid, _)| *pid).expect("Couldn't find running PID")
 }
 
 pub fn get_process_by_id(id: u64) -> Option<Arc<process::Process>> {
@@ -70,34 +294,63 @@ pub fn get_process_by_id(id: u64) -> Option<Arc<process::Process>> {
 }
 
 pub fn get_current_process() -> Arc<process::Process> {
-    let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
-    let running_process = RUNNING_PROCESS.get().expect("Attempted to access running process before it is initialised").read();
+    let running_process = this_cpu().running.read();
 
-    if let Some(pid) = *running_process {
-	process_tbl[&pid].clone()
+    if let Some((_, ref process)) = *running_process {
+	process.clone()
     } else {
 	panic!("Attempted to access user address space when no process is running");
     }
 }
 
-pub fn fork_current_process() -> u64 {
-    let pid = {
-	let mut next_pid = NEXT_PID.get().expect("Attempted to access next PID before it is initialised").lock();
-	let pid = *next_pid;
-	*next_pid += 1;
-	pid
-    };
+fn allocate_pid() -> u64 {
+    let mut next_pid = NEXT_PID.get().expect("Attempted to access next PID before it is initialised").lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+    pid
+}
+
+pub fn fork_current_process() -> Result<u64, CanonicalError> {
+    // Copying the address space may need the OOM killer, which looks through the process table, so it mustn't be
+    // held while we do so
+    let ppid = get_current_pid();
+    let new_process = process::Process::from_existing(&get_current_process())?;
+    new_process.set_ppid(ppid);
 
+    let pid = allocate_pid();
     {
-	let running_process = RUNNING_PROCESS.get().expect("Attempted to access running process before it is initialised").read();
 	let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
+	process_tbl.insert(pid, Arc::new(new_process));
+    };
+
+    Ok(pid)
+}
 
-	let new_process = process::Process::from_existing(
-	    &process_tbl[&running_process.expect("No running process")]);
+// As fork, but the child runs in our address space until it calls execve or exits, which the returned VforkParent
+// waits for
+pub fn vfork_current_process() -> (u64, Arc<process::VforkParent>) {
+    let pid = allocate_pid();
 
+    let ppid = get_current_pid();
+    let (new_process, vfork_parent) = process::Process::from_existing_vfork(&get_current_process());
+    new_process.set_ppid(ppid);
+
+    {
+	let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
 	process_tbl.insert(pid, Arc::new(new_process));
     };
 
+    (pid, vfork_parent)
+}
+
+// Makes a fully set up process (see Process::new_spawned) runnable, as a child of the running process
+pub fn add_spawned_process(process: Arc<process::Process>) -> u64 {
+    let pid = allocate_pid();
+    process.set_ppid(get_current_pid());
+
+    let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
+    process_tbl.insert(pid, process);
+
     pid
 }
 
@@ -106,142 +359,377 @@ pub fn exit(exit_code: u64) -> ! {
 	log::info!("Exited with code {}", exit_code);
     }
 
-    {
+    exit_process(WaitStatus::Exited(exit_code));
+}
+
+pub fn exit_by_signal(sig: u64) -> ! {
+    log::info!("Killed by signal {}", sig);
+    exit_process(WaitStatus::Signaled(sig));
+}
+
+fn exit_process(status: WaitStatus) -> ! {
+    // Before we free our memory, as a vfork child's is the parent's, and before taking the process table, as waking
+    // the parent needs it
+    get_current_process().release_vfork_parent();
+
+    let waiters = {
 	let mut process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
-	let running_process = RUNNING_PROCESS.get().expect("Attempted to access running process before it is initialised").read();
+	let running_process = this_cpu().running.read().as_ref().map(|(pid, _)| *pid);
 
-	if let Some(pid) = *running_process {
+	if let Some(pid) = running_process {
 	    // Free associated memory, and drop the process
 	    let current_process = process_tbl.get_mut(&pid).unwrap().clone();
+	    let usage = current_process.get_resource_usage();
 	    let mut task_type = current_process.task_type.write();
 
 	    if let process::TaskType::User(ref mut address_space) = *task_type {
 		address_space.clear_user_space();
 	    }
 	    process_tbl.remove(&pid);
+
+	    // Our children are inherited by init, and any we haven't reaped never will be
+	    for process in process_tbl.values() {
+		if process.get_ppid() == pid {
+		    process.set_ppid(INIT_PID);
+		}
+	    }
+
+	    let ppid = current_process.get_ppid();
+	    let mut exited = EXITED_PROCESSES.get().expect("Attempted to access exited processes before it is initialised").write();
+	    exited.retain(|_, e| e.ppid != pid);
+	    exited.insert(pid, ExitedProcess {
+		ppid,
+		pgid: current_process.get_pgid(),
+		status: status.encode(),
+		usage,
+	    });
+
+	    CHILD_WAITERS.get().expect("Attempted to access child waiters before it is initialised").lock().remove(&ppid)
 	} else {
 	    panic!("Attempted to access user address space when no process is running");
 	}
+    };
+
+    // Wakers look the parent up in the process table, so can only be called once we've released it
+    for waker in waiters.into_iter().flatten() {
+	waker.wake();
     }
 
     schedule_next();
 }
 
-fn get_futures_to_poll() -> BTreeMap<u64, Arc<Mutex<process::SyscallFuture>>> {
-    let mut r: BTreeMap<u64, Arc<Mutex<process::SyscallFuture>>> = BTreeMap::new();
-    let mut process_tbl = PROCESS_TABLE
-	.get()
-	.expect("PROCESS_TABLE not initialized")
-	.write();
+// What the OOM killer goes by, for each user process
+struct OomCandidate {
+    pid: u64,
+    pages: u64,
+    // Running or stopped, rather than part way through exiting
+    runnable: bool,
+    vfork_child: bool,
+}
 
-    for (pid, process) in process_tbl.iter_mut() {
-	match &mut process.get_state() {
-	    process::TaskState::Running => {},
-	    process::TaskState::Waiting { future: _ } => {},
-	    process::TaskState::AsyncSyscall { future } => {
-		let dummy: process::SyscallFuture = Box::pin(async {
-		    syscall::SyscallResult {
-			return_value: 0,
-			err_num: syscall::CanonicalError::Ok as u64,
-		    }
-		});
-		let future_taken = core::mem::replace(future, Arc::new(Mutex::new(dummy)));
-		r.insert(*pid, future_taken);
-	    }
+// The PID of whichever candidate has the most memory mapped, leaving out init and anything busy (see oom_kill)
+fn choose_oom_victim(candidates: impl Iterator<Item = OomCandidate>, busy: &[u64]) -> Option<u64> {
+    candidates
+	.filter(|c| c.pid != INIT_PID && !busy.contains(&c.pid))
+	.filter(|c| c.runnable)
+	// A vfork child's memory is its parent's, which is still waiting to have it back
+	.filter(|c| !c.vfork_child)
+	.filter(|c| c.pages > 0)
+	.max_by_key(|c| c.pages)
+	.map(|c| c.pid)
+}
+
+// Kills whichever process has the most memory mapped, and frees that memory, returning the victim's PID and the
+// number of pages freed. Init, anything a CPU is running (one of which is trying to allocate) and anything part way
+// through a syscall are never chosen: the last could be using its memory from within the kernel, whereas a process
+// waiting to return to userspace (or stopped) won't touch it again, and exits as soon as it's scheduled and sees the
+// SIGKILL.
+pub fn oom_kill() -> Option<(u64, u64)> {
+    // The victim is chosen and taken off the run queue under the one write lock, as the scheduler picks what to run
+    // under it too, so no CPU can have started running the victim in between, or start to while its memory is freed
+    let (pid, process) = {
+	let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").write();
+	let busy = busy_pids();
+
+	let candidates = process_tbl.iter()
+	    // Skip any whose address space is locked, since someone's busy with it
+	    .filter_map(|(&pid, process)| match *process.task_type.try_read()? {
+		process::TaskType::User(ref address_space) => Some(OomCandidate {
+		    pid,
+		    pages: address_space.get_mapped_pages(),
+		    runnable: matches!(process.get_state(), process::TaskState::Running | process::TaskState::Stopped),
+		    vfork_child: process.is_vfork_child(),
+		}),
+		process::TaskType::Kernel => None,
+	    });
+	let pid = choose_oom_victim(candidates, &busy)?;
+	let process = process_tbl.get(&pid)?.clone();
+
+	process.clone().set_state(process::TaskState::Reaping);
+	(pid, process)
+    };
+
+    let pages = process.release_user_space();
+    process.clone().set_state(process::TaskState::Running);
+    process.post_signal(signal::SIGKILL);
+    Some((pid, pages))
+}
+
+// Sends signal to every user process other than except. Kernel threads don't take signals, so are left alone
+pub fn signal_user_processes(signal: u64, except: u64) {
+    // Posting a signal can wake the process, which looks it up in the process table, so the targets are gathered
+    // first and the table let go of before any are signalled
+    let targets: Vec<Arc<process::Process>> = {
+	let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
+	process_tbl.iter()
+	    .filter(|(pid, _)| **pid != except)
+	    .map(|(_, process)| process.clone())
+	    .collect()
+    };
+
+    for process in targets {
+	let is_user = matches!(*process.task_type.read(), process::TaskType::User(_));
+	if is_user {
+	    process.post_signal(signal);
 	}
     }
+}
+
+// Group 0 is used to mean "no group", so signalling it does nothing
+pub fn signal_process_group(pgid: u64, signal: u64) {
+    if pgid == 0 {
+	return;
+    }
 
-    r
+    // As above, the table can't be held while signalling
+    let targets: Vec<Arc<process::Process>> = {
+	let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
+	process_tbl.values()
+	    .filter(|process| process.get_pgid() == pgid)
+	    .cloned()
+	    .collect()
+    };
+
+    for process in targets {
+	process.post_signal(signal);
+    }
 }
 
-fn poll_process_future(pid: u64, future: Arc<Mutex<process::SyscallFuture>>) {
+// Moves pid (the caller, or one of its children) into process group pgid, creating the group if pgid is pid itself.
+// As with POSIX setpgid, everything involved has to be in the caller's session.
+pub fn set_process_group(caller_pid: u64, pid: u64, pgid: u64) -> Result<(), CanonicalError> {
+    // Held throughout, so the group can't change session between checking and joining it
+    let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
+    let caller = process_tbl.get(&caller_pid).ok_or(CanonicalError::Srch)?;
+    let target = process_tbl.get(&pid).ok_or(CanonicalError::Srch)?;
+
+    if pid != caller_pid && target.get_ppid() != caller_pid {
+	return Err(CanonicalError::Srch);
+    }
+
+    let sid = caller.get_sid();
+    // A session leader's group is the session, so it can't leave it
+    if target.get_sid() != sid || target.get_sid() == pid {
+	return Err(CanonicalError::Perm);
+    }
+
+    if pgid != pid {
+	let group_in_session = process_tbl.values()
+	    .any(|p| p.get_pgid() == pgid && p.get_sid() == sid);
+	if !group_in_session {
+	    return Err(CanonicalError::Perm);
+	}
+    }
+
+    target.set_pgid(pgid);
+    Ok(())
+}
+
+// Makes pid the leader of a new session and process group, both numbered after it
+pub fn create_session(pid: u64) -> Result<u64, CanonicalError> {
+    let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
+    let process = process_tbl.get(&pid).ok_or(CanonicalError::Srch)?;
+
+    // Group leaders can't, and nor can anything else while a group numbered after it exists, as the new group would
+    // end up shared with processes outside the session
+    if process_tbl.values().any(|p| p.get_pgid() == pid) {
+	return Err(CanonicalError::Perm);
+    }
+
+    process.set_sid(pid);
+    process.set_pgid(pid);
+    Ok(pid)
+}
+
+// Takes the exit record of a matching child of ppid, or returns None if nohang is set and none has exited yet.
+// Fails with Child if ppid has no children matching the selector at all. Children which have stopped or continued
+// are reported, if asked for, with a record of their current usage, and stay around to be waited on again.
+pub async fn wait_child(ppid: u64, selector: WaitSelector, options: WaitOptions) -> Result<Option<(u64, ExitedProcess)>, CanonicalError> {
+    poll_fn(|cx| {
+	let process_tbl = PROCESS_TABLE.get().expect("Attempted to access process table before it is initialised").read();
+	let mut exited = EXITED_PROCESSES.get().expect("Attempted to access exited processes before it is initialised").write();
+
+	let reaped = exited.iter()
+	    .find(|(pid, e)| e.ppid == ppid && selector.matches(**pid, e.pgid))
+	    .map(|(pid, _)| *pid);
+	if let Some(pid) = reaped {
+	    let record = exited.remove(&pid).expect("Exit record vanished while holding lock");
+	    return Poll::Ready(Ok(Some((pid, record))));
+	}
+
+	let changed = process_tbl.iter()
+	    .filter(|(pid, p)| p.get_ppid() == ppid && selector.matches(**pid, p.get_pgid()))
+	    .find_map(|(pid, p)| p.take_wait_event(options.stopped, options.continued).map(|status| (*pid, p, status)));
+	if let Some((pid, process, status)) = changed {
+	    return Poll::Ready(Ok(Some((pid, ExitedProcess {
+		ppid,
+		pgid: process.get_pgid(),
+		status: status.encode(),
+		usage: process.get_resource_usage(),
+	    }))));
+	}
+
+	let has_children = process_tbl.iter()
+	    .any(|(pid, p)| p.get_ppid() == ppid && selector.matches(*pid, p.get_pgid()));
+
+	if !has_children {
+	    Poll::Ready(Err(CanonicalError::Child))
+	} else if options.nohang {
+	    Poll::Ready(Ok(None))
+	} else {
+	    let mut waiters = CHILD_WAITERS.get().expect("Attempted to access child waiters before it is initialised").lock();
+	    waiters.entry(ppid).or_default().push(cx.waker().clone());
+	    Poll::Pending
+	}
+    }).await
+}
+
+// Taking a future moves its process to Polling, so no other CPU polls it at the same time
+fn get_futures_to_poll() -> Vec<(u64, Arc<process::Process>, Arc<Mutex<process::SyscallFuture>>)> {
+    let process_tbl = PROCESS_TABLE
+	.get()
+	.expect("PROCESS_TABLE not initialized")
+	.read();
+
+    process_tbl.iter()
+	.filter_map(|(pid, process)| process.take_syscall_future().map(|future| (*pid, process.clone(), future)))
+	.collect()
+}
+
+fn poll_process_future(pid: u64, process: Arc<process::Process>, future: Arc<Mutex<process::SyscallFuture>>) {
     use core::task::Context;
 
     let waker = process_waker::ProcessWaker::new(pid);
     let mut ctx = Context::from_waker(&waker);
 
-    {
-	let mut running_process = RUNNING_PROCESS
-	    .get()
-	    .expect("RUNNING_PROCESS not initialized")
-	    .write();
-	*running_process = Some(pid);
-    }
-
-    match future.clone().lock().as_mut().poll(&mut ctx) {
-        core::task::Poll::Ready(result) => {
-	    let mut process_tbl = PROCESS_TABLE
-		.get()
-		.expect("PROCESS_TABLE not initialized")
-		.write();
-	    process_tbl.get_mut(&pid).unwrap().clone().syscall_return(result.return_value, result.err_num);
-        }
-        core::task::Poll::Pending => {	    
-	    let mut process_tbl = PROCESS_TABLE
-		.get()
-		.expect("PROCESS_TABLE not initialized")
-		.write();
-	    let process = process_tbl.get_mut(&pid).unwrap();
-	    process.clone().set_state(process::TaskState::Waiting {
-                future,
-            });
-	}
+    *this_cpu().running.write() = Some((pid, process.clone()));
+
+    let poll = future.lock().as_mut().poll(&mut ctx);
+    match poll {
+        core::task::Poll::Ready(result) => process.syscall_return(result.return_value, result.err_num),
+        core::task::Poll::Pending => process.park_syscall_future(future),
     }
 }
 
-fn next_task() -> process::ProcessContext {
-    let mut running_process = RUNNING_PROCESS
-	.get()
-	.expect("RUNNING_PROCESS not initialized")
-	.write();
+fn enter_process(pid: u64, process: &Arc<process::Process>, cpu: &CpuState) -> process::ProcessContext {
+    *cpu.running.write() = Some((pid, process.clone()));
+    *cpu.scheduled.lock() = Some(pid);
+
+    // Switch to address space
+    let mut task_type = process.task_type.write();
+    if let process::TaskType::User(ref mut address_space) = *task_type {
+	unsafe {
+	    address_space.switch_to();
+	}
+    }
+
+    process.get_context()
+}
 
-    let mut process_tbl = PROCESS_TABLE
+fn next_task(previous_pid: Option<u64>) -> process::ProcessContext {
+    let process_tbl = PROCESS_TABLE
 	.get()
 	.expect("PROCESS_TABLE not initialized")
 	.write();
 
-    // Pull out idle process context; this is where we go if nothing else is currently runnable
-    let idle_ctx = process_tbl.get(&0).unwrap().get_context();
+    let this = this_cpu();
+    let cpu = current_cpu();
+
+    // Whatever was running here has had its registers saved, so may now be picked up anywhere, but anything another
+    // CPU is running is left alone
+    *this.scheduled.lock() = None;
+    let elsewhere: Vec<u64> = CPUS.iter()
+	.filter_map(|c| *c.scheduled.lock())
+	.collect();
 
     // Convert to vector to allow indexed wraparound search
-    let mut tasks: Vec<(u64, &mut Arc<process::Process>)> = process_tbl.iter_mut().map(|(pid, p)| (*pid, p)).collect();
-    tasks.sort_by_key(|(pid, _)| *pid); // Ensure stable order
+    let tasks: Vec<(u64, &Arc<process::Process>)> = process_tbl.iter()
+	.filter(|(pid, _)| !elsewhere.contains(pid))
+	.map(|(pid, p)| (*pid, p))
+	.collect();
 
-    // Get current PID
-    let current_pid = *running_process;
+    // The previous task keeps the CPU until its time slice runs out, unless it has blocked in the meantime
+    let runnable_previous = previous_pid
+	.filter(|pid| *pid != 0)
+	.and_then(|pid| tasks.iter().find(|(p, _)| *p == pid))
+	.map(|(pid, process)| (*pid, (*process).clone()))
+	.filter(|(_, process)| matches!(process.get_state(), process::TaskState::Running) && process.may_run_on(cpu));
+
+    if let Some((pid, ref process)) = runnable_previous {
+	if process.get_time_slice() > 0 {
+	    return enter_process(pid, process, this);
+	}
+    }
+
+    // Worked out now, while the previous task can still be looked at, for tracing if we do switch away from it
+    let switch_reason = match previous_pid {
+	Some(0) | None => trace::SwitchReason::Yield,
+	Some(pid) => match tasks.iter().find(|(p, _)| *p == pid) {
+	    None => trace::SwitchReason::Exit,
+	    Some((_, process)) if !matches!(process.get_state(), process::TaskState::Running) => trace::SwitchReason::Block,
+	    Some((_, process)) if process.get_time_slice() == 0 => trace::SwitchReason::Tick,
+	    Some(_) => trace::SwitchReason::Yield,
+	},
+    };
+    let trace_switch = |to: u64| {
+	if let Some(from) = previous_pid.filter(|from| *from != to) {
+	    trace::record_switch(from, to, switch_reason);
+	}
+    };
 
     // Find index of current process (if any)
-    let start_idx = current_pid
+    let start_idx = previous_pid
 	.and_then(|pid| tasks.iter().position(|(p, _)| *p == pid))
 	.map(|i| (i + 1) % tasks.len()) // Start just after current PID
 	.unwrap_or(0);
 
     let tasks_len = tasks.len();
     for i in 0..tasks_len {
-	let (pid, process) = &mut tasks[(start_idx + i) % tasks_len];
+	let (pid, process) = tasks[(start_idx + i) % tasks_len];
 
-	// Idle thread is thread of last resort. Only schedule it if nothing else is found.
-	if *pid == 0 {
+	// Affinity may have been changed to exclude this CPU
+	if !process.may_run_on(cpu) {
 	    continue;
 	}
 
 	if let process::TaskState::Running = process.get_state() {
-	    *running_process = Some(*pid);
-
-	    // Switch to address space
-	    let mut task_type = process.task_type.write();
-	    if let process::TaskType::User(ref mut address_space) = *task_type {
-		unsafe {
-		    address_space.switch_to();
+	    if let Some((previous_pid, ref previous)) = runnable_previous {
+		if previous_pid != pid {
+		    previous.count_preemption();
 		}
 	    }
 
-	    return process.get_context();
+	    process.reset_time_slice(TIME_SLICE_TICKS.load(Ordering::Relaxed));
+	    trace_switch(pid);
+	    return enter_process(pid, process, this);
         }
     }
 
-    *running_process = Some(0);
-    idle_ctx
+    // The idle thread is the thread of last resort, only run if nothing else is found
+    trace_switch(0);
+    let idle = this.idle.get().expect("Attempted to schedule on a CPU which hasn't been started");
+    *this.running.write() = Some((0, idle.clone()));
+    idle.get_context()
 }
 
 fn context_switch(context: &process::ProcessContext) -> ! {    
@@ -291,6 +779,12 @@ fn context_switch(context: &process::ProcessContext) -> ! {
 	);
     }
 
+    // The user's GS base sits in KernelGsBase until the swapgs on the way out
+    FsBase::write(VirtAddr::new(context.fs_base));
+    if context.is_user() {
+	KernelGsBase::write(VirtAddr::new(context.gs_base));
+    }
+
     let ptr = context as *const process::ProcessContext;
     unsafe {
 	core::arch::asm!(
@@ -305,13 +799,288 @@ fn context_switch(context: &process::ProcessContext) -> ! {
 }
 
 // TODO: use waker-based queues to avoid the need to continually poll.
+// Safe to call from interrupt context: the waker is run by the scheduler once the handler has finished
+pub fn defer_wake(waker: Waker) {
+    without_interrupts(|| DEFERRED_WAKES.lock().push(waker));
+}
+
+// Runs the wakers interrupt handlers have left for us, which must be done without the process table held
+pub fn run_deferred_wakes() {
+    let deferred_wakes = core::mem::take(&mut *DEFERRED_WAKES.lock());
+    for waker in deferred_wakes {
+	waker.wake();
+    }
+}
+
+// Lets anything in wait on ppid's children know that one has stopped or continued, and sends ppid SIGCHLD unless it
+// asked not to be told with SA_NOCLDSTOP. The signal behind it may have been posted with the process table held, so
+// both are deferred.
+pub fn notify_child_waiters(ppid: u64) {
+    let waiters = CHILD_WAITERS.get().expect("Attempted to access child waiters before it is initialised").lock().remove(&ppid);
+    for waker in waiters.into_iter().flatten() {
+	defer_wake(waker);
+    }
+
+    without_interrupts(|| DEFERRED_SIGCHLDS.lock().push(ppid));
+}
+
+// Moves to this CPU's kernel stack before doing anything else. Nothing that calls this is ever returned to, so the
+// stack it was on is finished with, and may be a kernel thread's, which another CPU can start running again as soon as
+// this one has let it go.
 pub fn schedule_next() -> ! {
+    unsafe {
+	core::arch::asm!(
+	    "mov rsp, gs:[{ksp}]",
+	    "call {schedule}",
+
+	    ksp = const(offset_of!(gdt::ProcessorControlBlock, tss) + offset_of!(TaskStateSegment, privilege_stack_table)),
+	    schedule = sym schedule,
+	    options(noreturn),
+	);
+    }
+}
+
+extern "C" fn schedule() -> ! {
+    // Polling futures changes the running process, so note which one we were actually running beforehand
+    let previous_pid = this_cpu().running.read().as_ref().map(|(pid, _)| *pid);
+
+    run_deferred_wakes();
+
+    let deferred_sigchlds = core::mem::take(&mut *DEFERRED_SIGCHLDS.lock());
+    for ppid in deferred_sigchlds {
+	if let Some(parent) = get_process_by_id(ppid) {
+	    if signal::reports_child_stops(parent.get_current_signal_handler(signal::SIGCHLD).as_ref()) {
+		parent.post_signal(signal::SIGCHLD);
+	    }
+	}
+    }
+
     let futures = get_futures_to_poll();
 
-    for (pid, future) in futures {
-	poll_process_future(pid, future);
+    for (pid, process, future) in futures {
+	poll_process_future(pid, process, future);
+    }
+
+    let mut previous_pid = previous_pid;
+    loop {
+	let mut context = next_task(previous_pid);
+
+	// Pending signals are dealt with on the way out to userspace
+	if context.is_user() {
+	    let process = get_current_process();
+	    match process.clone().deliver_pending_signal() {
+		signal::Delivery::None => (),
+		signal::Delivery::Handler => context = process.get_context(),
+		signal::Delivery::Terminate(sig) => exit_by_signal(sig),
+		signal::Delivery::CoreDump(sig) => {
+		    process.dump_core(get_current_pid(), sig);
+		    exit_by_signal(sig);
+		},
+		signal::Delivery::Stop(sig) => {
+		    // No longer runnable, so find something else to run instead
+		    process.stop(sig);
+		    previous_pid = Some(get_current_pid());
+		    continue;
+		},
+	    }
+	}
+
+	context_switch(&context);
+    }
+}
+
+#[test]
+fn sigabrt_reports_the_signal_without_a_core_flag() {
+    assert!(matches!(signal::default_action(signal::SIGABRT), signal::Action::CoreDump));
+    assert_eq!(WaitStatus::Signaled(signal::SIGABRT).encode(), 6);
+}
+
+#[test]
+fn wait_statuses_decode_as_userspace_expects() {
+    // As libc's WIFEXITED, WEXITSTATUS, WIFSIGNALED, WTERMSIG, WIFSTOPPED, WSTOPSIG and WIFCONTINUED
+    let exited = |status: i32| status & 0x7f == 0;
+    let exit_status = |status: i32| (status >> 8) & 0xff;
+    let signaled = |status: i32| ((status & 0x7f) + 1) as i8 >> 1 > 0;
+    let term_sig = |status: i32| status & 0x7f;
+    let stopped = |status: i32| status & 0xff == 0x7f;
+    let stop_sig = |status: i32| (status >> 8) & 0xff;
+    let continued = |status: i32| status == 0xffff;
+
+    let status = WaitStatus::Exited(42).encode();
+    assert!(exited(status) && !signaled(status) && !stopped(status) && !continued(status));
+    assert_eq!(exit_status(status), 42);
+
+    // Only the low byte of the exit code makes it through
+    assert_eq!(exit_status(WaitStatus::Exited(0x1ff).encode()), 0xff);
+
+    let status = WaitStatus::Signaled(signal::SIGKILL).encode();
+    assert!(signaled(status) && !exited(status) && !stopped(status) && !continued(status));
+    assert_eq!(term_sig(status), signal::SIGKILL as i32);
+    assert_eq!(status & 0x80, 0);
+
+    let status = WaitStatus::Stopped(signal::SIGTSTP).encode();
+    assert!(stopped(status) && !exited(status) && !signaled(status) && !continued(status));
+    assert_eq!(stop_sig(status), signal::SIGTSTP as i32);
+
+    let status = WaitStatus::Continued.encode();
+    assert!(continued(status) && !exited(status) && !signaled(status));
+}
+
+#[test]
+fn the_oom_killer_picks_the_biggest_process_it_may_kill() {
+    let candidate = |pid: u64, pages: u64| OomCandidate {
+	pid,
+	pages,
+	runnable: true,
+	vfork_child: false,
+    };
+    let choose = |candidates: Vec<OomCandidate>, busy: &[u64]| choose_oom_victim(candidates.into_iter(), busy);
+
+    assert_eq!(choose(alloc::vec![candidate(2, 10), candidate(3, 30), candidate(4, 20)], &[]), Some(3));
+
+    // Init, however big, and anything a CPU is running or about to, are left alone
+    assert_eq!(choose(alloc::vec![candidate(INIT_PID, 100), candidate(2, 10), candidate(3, 30)], &[3]), Some(2));
+
+    // As is anything exiting, and a vfork child, whose memory is its parent's
+    let exiting = OomCandidate {
+	runnable: false,
+	..candidate(5, 50)
+    };
+    let vfork_child = OomCandidate {
+	vfork_child: true,
+	..candidate(6, 60)
+    };
+    assert_eq!(choose(alloc::vec![exiting, vfork_child, candidate(2, 10)], &[]), Some(2));
+
+    // Killing something with nothing mapped wouldn't help
+    assert_eq!(choose(alloc::vec![candidate(2, 0)], &[]), None);
+    assert_eq!(choose(alloc::vec![], &[]), None);
+}
+
+#[test]
+fn reaping_a_child_hands_back_the_cpu_time_it_used() {
+    use futures_util::FutureExt;
+
+    PROCESS_TABLE.call_once(|| FairRwLock::new(BTreeMap::new()));
+    EXITED_PROCESSES.call_once(|| RwLock::new(BTreeMap::new()));
+    CHILD_WAITERS.call_once(|| Mutex::new(BTreeMap::new()));
+
+    // As exit leaves it for the parent
+    let (ppid, pid) = (0x1964, 0x1965);
+    EXITED_PROCESSES.get().unwrap().write().insert(pid, ExitedProcess {
+	ppid,
+	pgid: ppid,
+	status: WaitStatus::Exited(0).encode(),
+	usage: process::ResourceUsage {
+	    cpu_time_ms: 1_500,
+	    peak_rss_pages: 3,
+	    involuntary_switches: 2,
+	},
+    });
+
+    let options = WaitOptions {
+	nohang: true,
+	stopped: false,
+	continued: false,
+    };
+    let wait = || wait_child(ppid, WaitSelector::Pid(pid), options).now_or_never().unwrap();
+
+    let (reaped, exited) = wait().unwrap().unwrap();
+    assert_eq!(reaped, pid);
+    assert_eq!(exited.usage.cpu_time_ms, 1_500);
+    assert_eq!(exited.usage.involuntary_switches, 2);
+
+    // Only once
+    assert!(matches!(wait(), Err(CanonicalError::Child)));
+}
+
+#[test]
+fn a_time_slice_runs_down_by_the_tick_and_preemptions_are_counted() {
+    let process = process::Process::new_kernel(process::ProcessContext::default());
+
+    // Anything less than a tick would mean never running at all
+    set_time_slice(0);
+    assert_eq!(TIME_SLICE_TICKS.load(Ordering::Relaxed), 1);
+    set_time_slice(3);
+
+    // As the scheduler hands it out, and the timer interrupt takes it back
+    process.reset_time_slice(TIME_SLICE_TICKS.load(Ordering::Relaxed));
+    for remaining in [2, 1, 0, 0] {
+	process.consume_time_slice(1);
+	assert_eq!(process.get_time_slice(), remaining);
+    }
+
+    process.count_preemption();
+    process.count_preemption();
+    assert_eq!(process.get_resource_usage().involuntary_switches, 2);
+    set_time_slice(DEFAULT_TIME_SLICE_TICKS);
+}
+
+#[test]
+fn setpgid_stays_within_the_session_and_leaves_its_leader_alone() {
+    PROCESS_TABLE.call_once(|| FairRwLock::new(BTreeMap::new()));
+
+    let (leader, child, other_leader, stray) = (0x1976_0, 0x1976_1, 0x1976_2, 0x1976_3);
+    // pid, parent, session
+    let processes = [
+	(leader, 1, leader),
+	(child, leader, leader),
+	(other_leader, 1, other_leader),
+	(stray, leader, other_leader),
+    ];
+    for (pid, ppid, sid) in processes {
+	let process = process::Process::new_kernel(process::ProcessContext::default());
+	process.set_ppid(ppid);
+	process.set_sid(sid);
+	process.set_pgid(sid);
+	PROCESS_TABLE.get().unwrap().write().insert(pid, Arc::new(process));
+    }
+    let pgid_of = |pid: u64| get_process_by_id(pid).unwrap().get_pgid();
+
+    // Into a group of another session, whether it's asked for by the process or its parent
+    assert!(matches!(set_process_group(child, child, other_leader), Err(CanonicalError::Perm)));
+    assert!(matches!(set_process_group(leader, child, other_leader), Err(CanonicalError::Perm)));
+    // A child which has since moved to a session of its own
+    assert!(matches!(set_process_group(leader, stray, leader), Err(CanonicalError::Perm)));
+    // A session leader, even into the group it's already in
+    assert!(matches!(set_process_group(leader, leader, leader), Err(CanonicalError::Perm)));
+    assert!(matches!(set_process_group(other_leader, other_leader, other_leader), Err(CanonicalError::Perm)));
+    assert_eq!((pgid_of(leader), pgid_of(child), pgid_of(stray)), (leader, leader, other_leader));
+
+    // Within the session is fine
+    set_process_group(leader, child, child).unwrap();
+    assert_eq!(pgid_of(child), child);
+    set_process_group(child, child, leader).unwrap();
+    assert_eq!(pgid_of(child), leader);
+
+    for (pid, _, _) in processes {
+	PROCESS_TABLE.get().unwrap().write().remove(&pid);
+    }
+}
+
+#[test]
+fn a_barrier_waits_for_every_cpu_the_process_is_on() {
+    // This is CPU 0, the process is on CPUs 1 and 3, and CPU 4 hasn't been started
+    let pid = 0x1985;
+    *CPUS[1].scheduled.lock() = Some(pid);
+    *CPUS[3].scheduled.lock() = Some(pid);
+    let before = ipi_counts_of_others(0, 0b1111);
+    let targets = before.iter().map(|(cpu, _)| *cpu).collect::<Vec<usize>>();
+    let on = (0..MAX_CPUS).filter(|cpu| *CPUS[*cpu].scheduled.lock() == Some(pid)).collect::<Vec<usize>>();
+    *CPUS[1].scheduled.lock() = None;
+    *CPUS[3].scheduled.lock() = None;
+
+    assert_eq!(targets, [1, 2, 3]);
+    assert!(on.iter().all(|cpu| targets.contains(cpu)));
+
+    // Not done until each has taken the IPI
+    for cpu in [1, 2] {
+	CPUS[cpu].ipis.fetch_add(1, Ordering::SeqCst);
+	assert!(!all_taken_ipi(&before));
     }
+    CPUS[3].ipis.fetch_add(1, Ordering::SeqCst);
+    assert!(all_taken_ipi(&before));
 
-    let context = next_task();
-    context_switch(&context);
+    // Nothing else online means nothing to wait for
+    assert!(ipi_counts_of_others(0, 0b1).is_empty());
 }
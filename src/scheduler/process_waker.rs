@@ -3,7 +3,6 @@ use alloc::task::Wake;
 use core::task::Waker;
 
 use crate::scheduler;
-use crate::process;
 
 pub struct ProcessWaker {
     task_id: u64,
@@ -25,14 +24,7 @@ impl Wake for ProcessWaker {
 	    None => return, // Return on error, as the task has exited before the waker was called
 	};
 
-	match process.clone().get_state() {
-	    process::TaskState::Waiting { future } => {
-		process.clone().set_state(process::TaskState::AsyncSyscall {
-		    future,
-		});
-	    },
-	    process::TaskState::AsyncSyscall { future: _ } => (),  // No need to do anything, we're already in the right state
-	    _ => (),  // Waker is expired, skip
-	}
+	// Does nothing if the waker has expired, or the future is already waiting to be polled
+	process.wake_syscall();
     }
 }
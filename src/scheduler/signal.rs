@@ -1,11 +1,64 @@
 use core::ffi::c_int;
 
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGTRAP: u64 = 5;
+pub const SIGABRT: u64 = 6;
+pub const SIGBUS: u64 = 7;
+pub const SIGFPE: u64 = 8;
+pub const SIGKILL: u64 = 9;
+pub const SIGSEGV: u64 = 11;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+pub const SIGTSTP: u64 = 20;
+pub const SIGTTIN: u64 = 21;
+pub const SIGTTOU: u64 = 22;
+pub const SIGURG: u64 = 23;
+pub const SIGXCPU: u64 = 24;
+pub const SIGXFSZ: u64 = 25;
+pub const SIGWINCH: u64 = 28;
+pub const SIGSYS: u64 = 31;
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const SA_NOCLDSTOP: u64 = 0x1;
+const SA_SIGINFO: u64 = 0x4;
+const SA_NODEFER: u64 = 0x4000_0000;
+
+// SIGKILL and SIGSTOP can be neither blocked nor caught
+pub const UNBLOCKABLE_MASK: u64 = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+
+// Signals whose default action is to stop the process. Sending SIGCONT discards any of these still pending
+pub const STOP_MASK: u64 = (1 << (SIGSTOP - 1)) | (1 << (SIGTSTP - 1)) | (1 << (SIGTTIN - 1)) | (1 << (SIGTTOU - 1));
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct SigAction {
     sa_handler: usize,
     sa_mask: u64,
     sa_flags: c_int,
+    sa_restorer: usize,
+}
+
+// What needs to happen to a process before it next returns to userspace, as a result of its pending signals
+pub enum Delivery {
+    None,
+    Handler,
+    Terminate(u64),
+    CoreDump(u64),
+    Stop(u64),
+}
+
+pub enum Action {
+    Ignore,
+    Terminate,
+    // Terminate, having first dumped the process' state
+    CoreDump,
+    Stop,
+    Handler,
 }
 
 #[derive(Clone)]
@@ -21,14 +74,60 @@ pub struct SignalHandler {
     mask: u64,  // The list of signals to mask off when in the handler
     handler_type: HandlerType,
     flags: u64,  // This will get fleshed out in due course}
+    restorer: usize,  // Where the handler returns to; this calls sigreturn
+}
+
+impl SignalHandler {
+    pub fn action(&self, signal: u64) -> Action {
+	match self.handler {
+	    SIG_DFL => default_action(signal),
+	    SIG_IGN => Action::Ignore,
+	    _ => Action::Handler,
+	}
+    }
+
+    pub fn entry(&self) -> usize {
+	self.handler
+    }
+
+    pub fn restorer(&self) -> usize {
+	self.restorer
+    }
+
+    // Signals to block while the handler runs
+    pub fn handler_mask(&self, signal: u64) -> u64 {
+	let mask = if self.flags & SA_NODEFER != 0 {
+	    self.mask
+	} else {
+	    self.mask | (1 << (signal - 1))
+	};
+
+	mask & !UNBLOCKABLE_MASK
+    }
+}
+
+pub fn default_action(signal: u64) -> Action {
+    match signal {
+	// SIGCONT's work is done when it's sent, see Process::post_signal
+	SIGCHLD | SIGCONT | SIGURG | SIGWINCH => Action::Ignore,
+	SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => Action::Stop,
+	SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ | SIGSYS => Action::CoreDump,
+	_ => Action::Terminate,
+    }
+}
+
+// Whether a parent with this SIGCHLD handler is sent SIGCHLD when a child stops or continues
+pub fn reports_child_stops(handler: Option<&SignalHandler>) -> bool {
+    handler.is_none_or(|handler| handler.flags & SA_NOCLDSTOP == 0)
 }
 
 pub fn parse_sigaction(sigaction: SigAction) -> SignalHandler {
     SignalHandler {
 	handler: sigaction.sa_handler,
 	mask: sigaction.sa_mask,
-	handler_type: if (sigaction.sa_mask & (1 << 4)) != 0 { HandlerType::SigAction } else { HandlerType::Handler },
+	handler_type: if (sigaction.sa_flags as u64 & SA_SIGINFO) != 0 { HandlerType::SigAction } else { HandlerType::Handler },
 	flags: sigaction.sa_flags as u64,
+	restorer: sigaction.sa_restorer,
     }
 }
 
@@ -37,5 +136,39 @@ pub fn create_sigaction(sighandler: SignalHandler) -> SigAction {
 	sa_handler: sighandler.handler,
 	sa_mask: sighandler.mask,
 	sa_flags: sighandler.flags as c_int,
+	sa_restorer: sighandler.restorer,
     }
 }
+
+#[test]
+fn sa_nocldstop_silences_child_stops() {
+    let sigaction = |flags: c_int| SigAction {
+	sa_handler: 0x1000,
+	sa_mask: 0,
+	sa_flags: flags,
+	sa_restorer: 0x2000,
+    };
+
+    assert!(reports_child_stops(None));
+    assert!(reports_child_stops(Some(&parse_sigaction(sigaction(0)))));
+    assert!(!reports_child_stops(Some(&parse_sigaction(sigaction(SA_NOCLDSTOP as c_int)))));
+    assert!(!reports_child_stops(Some(&parse_sigaction(sigaction((SA_NOCLDSTOP | SA_NODEFER) as c_int)))));
+}
+
+#[test]
+fn sa_siginfo_is_taken_from_the_flags_not_the_mask() {
+    let sigaction = |mask: u64, flags: c_int| SigAction {
+	sa_handler: 0x1000,
+	sa_mask: mask,
+	sa_flags: flags,
+	sa_restorer: 0x2000,
+    };
+
+    let handler = parse_sigaction(sigaction(0, SA_SIGINFO as c_int));
+    assert!(matches!(handler.handler_type, HandlerType::SigAction));
+
+    // Blocking SIGTRAP (bit 4) in the handler has nothing to do with how it's called
+    let handler = parse_sigaction(sigaction(1 << (SIGTRAP - 1), 0));
+    assert!(matches!(handler.handler_type, HandlerType::Handler));
+    assert_eq!(create_sigaction(handler).sa_mask, 1 << (SIGTRAP - 1));
+}
@@ -0,0 +1,114 @@
+use alloc::collections::VecDeque;
+use core::fmt::Write;
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::drivers::hpet;
+use crate::fs;
+
+// Enough to cover a few seconds of busy switching, which is usually all that's needed to see a latency problem
+const TRACE_CAPACITY: usize = 4096;
+
+// Why the task that was running gave up the CPU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwitchReason {
+    // Its time slice ran out
+    Tick,
+    // It's waiting on a syscall, or has been stopped
+    Block,
+    // It could have carried on, but something else ran anyway (including the idle thread giving way)
+    Yield,
+    Exit,
+}
+
+impl SwitchReason {
+    fn name(&self) -> &'static str {
+	match self {
+	    SwitchReason::Tick => "tick",
+	    SwitchReason::Block => "block",
+	    SwitchReason::Yield => "yield",
+	    SwitchReason::Exit => "exit",
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SwitchEvent {
+    pub timestamp_ns: u64,
+    pub from: u64,
+    pub to: u64,
+    pub reason: SwitchReason,
+}
+
+// The most recent switches, oldest first. Taken from the scheduler, which interrupts return through, so it's only
+// ever locked with interrupts off
+static TRACE: Mutex<VecDeque<SwitchEvent>> = Mutex::new(VecDeque::new());
+
+// One line per switch, with the time in seconds to the microsecond, e.g. "12.000345 3 -> 0 block"
+fn format_events<'a>(events: impl IntoIterator<Item = &'a SwitchEvent>) -> String {
+    let mut trace = String::new();
+    for event in events {
+	let _ = writeln!(trace, "{}.{:06} {} -> {} {}",
+			 event.timestamp_ns / 1_000_000_000, (event.timestamp_ns / 1_000) % 1_000_000,
+			 event.from, event.to, event.reason.name());
+    }
+    trace
+}
+
+pub fn init() {
+    fs::sysfs::add_attribute("/kernel/sched_trace", || format_events(&events()));
+}
+
+// Makes room for event by dropping the oldest, once the trace is full
+fn push_event(trace: &mut VecDeque<SwitchEvent>, event: SwitchEvent) {
+    if trace.len() == TRACE_CAPACITY {
+	trace.pop_front();
+    }
+    trace.push_back(event);
+}
+
+pub fn record_switch(from: u64, to: u64, reason: SwitchReason) {
+    let event = SwitchEvent {
+	timestamp_ns: hpet::now_ns(),
+	from,
+	to,
+	reason,
+    };
+
+    without_interrupts(|| push_event(&mut TRACE.lock(), event));
+}
+
+pub fn events() -> VecDeque<SwitchEvent> {
+    without_interrupts(|| TRACE.lock().clone())
+}
+
+#[test]
+fn trace_keeps_the_latest_switches() {
+    let event = |n: u64| SwitchEvent {
+	timestamp_ns: n * 1_000,
+	from: n,
+	to: n + 1,
+	reason: SwitchReason::Tick,
+    };
+
+    let mut trace = VecDeque::new();
+    for n in 0 .. TRACE_CAPACITY as u64 + 10 {
+	push_event(&mut trace, event(n));
+    }
+
+    assert_eq!(trace.len(), TRACE_CAPACITY);
+    assert_eq!(trace.front().unwrap().from, 10);
+    assert_eq!(trace.back().unwrap().from, TRACE_CAPACITY as u64 + 9);
+}
+
+#[test]
+fn trace_formats_one_switch_per_line() {
+    let events = [
+	SwitchEvent { timestamp_ns: 12_000_345_678, from: 3, to: 0, reason: SwitchReason::Block },
+	SwitchEvent { timestamp_ns: 999, from: 0, to: 7, reason: SwitchReason::Yield },
+	SwitchEvent { timestamp_ns: 1_500_000_000, from: 7, to: 0, reason: SwitchReason::Exit },
+    ];
+
+    assert_eq!(format_events(&events), "12.000345 3 -> 0 block\n0.000000 0 -> 7 yield\n1.500000 7 -> 0 exit\n");
+}
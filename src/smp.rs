@@ -0,0 +1,293 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::PhysAddr;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+use crate::gdt;
+use crate::interrupts;
+use crate::memory;
+use crate::scheduler;
+use crate::sys::acpi;
+use crate::sys::syscall;
+use crate::sys::time;
+
+// The trampoline's code and data, then the page tables it turns paging on with: a PML4, PDPT and PD
+const TRAMPOLINE_PAGES: u64 = 4;
+const PML4_PAGE: u64 = 1;
+const PDPT_PAGE: u64 = 2;
+const PD_PAGE: u64 = 3;
+
+// Only used until the AP first schedules, and moves to the kernel stack gdt gives it
+const BOOT_STACK_SIZE: u64 = 4096 * 16;
+
+const INIT_DELAY_NS: u64 = 10_000_000;
+// How long each startup IPI is given to get the AP going, before it's sent another (or given up on)
+const STARTUP_TIMEOUT_NS: u64 = 200_000_000;
+
+// Reserved at boot, as only memory in the first 1MiB will do, and DMA allocations take the lowest they can find
+static TRAMPOLINE: Once<PhysAddr> = Once::new();
+
+// Set by the AP being brought up once it's ready to schedule, so the BSP can move on to the next
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+// The BSP's, which the AP switches to once it's in long mode
+static KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+static KERNEL_CR4: AtomicU64 = AtomicU64::new(0);
+
+// Copied to the start of a page in the first 1MiB, which the startup IPI starts the AP at, in real mode. Doesn't know
+// which page until it's running, so works that out from CS, and patches the few absolute addresses it needs. The data
+// at the end is filled in by the BSP before each AP is started.
+core::arch::global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_efer",
+    ".global ap_trampoline_cr0",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "movzx ebx, ax",
+    "shl ebx, 4",
+
+    "lea eax, [ebx + ap_trampoline_gdt - ap_trampoline_start]",
+    "mov dword ptr [ap_trampoline_gdtr - ap_trampoline_start + 2], eax",
+    "lea eax, [ebx + ap_trampoline_protected - ap_trampoline_start]",
+    "mov dword ptr [ap_trampoline_jump32 - ap_trampoline_start], eax",
+    "lea eax, [ebx + ap_trampoline_long - ap_trampoline_start]",
+    "mov dword ptr [ap_trampoline_jump64 - ap_trampoline_start], eax",
+
+    "lgdt [ap_trampoline_gdtr - ap_trampoline_start]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+
+    // jmp dword 0x08:ap_trampoline_protected, with the offset patched in above
+    ".byte 0x66, 0xea",
+    "ap_trampoline_jump32:",
+    ".long 0",
+    ".word 0x08",
+
+    ".code32",
+    "ap_trampoline_protected:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [ebx + ap_trampoline_cr3 - ap_trampoline_start]",
+    "mov cr3, eax",
+
+    "mov ecx, 0xC0000080",
+    "mov eax, dword ptr [ebx + ap_trampoline_efer - ap_trampoline_start]",
+    "mov edx, dword ptr [ebx + ap_trampoline_efer - ap_trampoline_start + 4]",
+    "wrmsr",
+
+    // Turns paging on, which with EFER.LME set, puts us in long mode
+    "mov eax, dword ptr [ebx + ap_trampoline_cr0 - ap_trampoline_start]",
+    "mov cr0, eax",
+
+    // jmp 0x18:ap_trampoline_long, with the offset patched in above
+    ".byte 0xea",
+    "ap_trampoline_jump64:",
+    ".long 0",
+    ".word 0x18",
+
+    ".code64",
+    "ap_trampoline_long:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+
+    "mov ebx, ebx",
+    "mov rsp, qword ptr [rbx + ap_trampoline_stack - ap_trampoline_start]",
+    "call qword ptr [rbx + ap_trampoline_entry - ap_trampoline_start]",
+    "ud2",
+
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF",  // 0x08: 32 bit code
+    ".quad 0x00CF92000000FFFF",  // 0x10: data
+    ".quad 0x00AF9A000000FFFF",  // 0x18: 64 bit code
+    "ap_trampoline_gdtr:",
+    ".word 4 * 8 - 1",
+    ".long 0",
+
+    ".balign 8",
+    "ap_trampoline_cr3:",
+    ".quad 0",
+    "ap_trampoline_efer:",
+    ".quad 0",
+    "ap_trampoline_cr0:",
+    ".quad 0",
+    "ap_trampoline_stack:",
+    ".quad 0",
+    "ap_trampoline_entry:",
+    ".quad 0",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_cr0: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+}
+
+// Where a field is in the copy of the trampoline at trampoline
+unsafe fn trampoline_field(trampoline: PhysAddr, symbol: *const u8) -> *mut u64 {
+    let offset = symbol as u64 - &raw const ap_trampoline_start as u64;
+    memory::get_ptr_in_hhdm(trampoline + offset).as_mut_ptr()
+}
+
+pub fn init() {
+    match memory::allocate_low_frames(TRAMPOLINE_PAGES * 4096) {
+	Some(trampoline) => {
+	    TRAMPOLINE.call_once(|| trampoline);
+	},
+	None => log::warn!("No memory below 1MiB for the AP trampoline, so only the BSP will be used"),
+    }
+}
+
+// Copies the trampoline into place, and builds the page tables it starts with. These identity map the first 2MiB,
+// which the trampoline is in, and share the kernel's half of the address space, so the AP can jump into the kernel
+// once it's in long mode.
+unsafe fn setup_trampoline(trampoline: PhysAddr) {
+    let page = |n: u64| memory::get_ptr_in_hhdm(trampoline + n * 4096);
+
+    let len = &raw const ap_trampoline_end as usize - &raw const ap_trampoline_start as usize;
+    core::ptr::copy_nonoverlapping(&raw const ap_trampoline_start, page(0).as_mut_ptr::<u8>(), len);
+
+    let pml4 = &mut *page(PML4_PAGE).as_mut_ptr::<PageTable>();
+    let pdpt = &mut *page(PDPT_PAGE).as_mut_ptr::<PageTable>();
+    let pd = &mut *page(PD_PAGE).as_mut_ptr::<PageTable>();
+    pml4.zero();
+    pdpt.zero();
+    pd.zero();
+
+    let kernel_pml4 = &*memory::get_ptr_in_hhdm(Cr3::read().0.start_address()).as_ptr::<PageTable>();
+    for (entry, kernel_entry) in pml4.iter_mut().zip(kernel_pml4.iter()).skip(256) {
+	*entry = kernel_entry.clone();
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    pml4[0].set_addr(trampoline + PDPT_PAGE * 4096, flags);
+    pdpt[0].set_addr(trampoline + PD_PAGE * 4096, flags);
+    pd[0].set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+
+    *trampoline_field(trampoline, &raw const ap_trampoline_cr3) = (trampoline + PML4_PAGE * 4096).as_u64();
+    // LMA is set by the CPU once paging is on, not by us
+    let mut efer = Efer::read();
+    efer.remove(EferFlags::LONG_MODE_ACTIVE);
+    *trampoline_field(trampoline, &raw const ap_trampoline_efer) = efer.bits();
+    *trampoline_field(trampoline, &raw const ap_trampoline_cr0) = Cr0::read_raw();
+    *trampoline_field(trampoline, &raw const ap_trampoline_entry) = ap_main as *const () as u64;
+
+    KERNEL_CR3.store(Cr3::read().0.start_address().as_u64(), Ordering::SeqCst);
+    KERNEL_CR4.store(Cr4::read_raw(), Ordering::SeqCst);
+}
+
+// Spins, as nothing is being scheduled yet
+fn wait_for(ns: u64, done: impl Fn() -> bool) -> bool {
+    let start = time::get_monotonic_ns();
+    while time::get_monotonic_ns() - start < ns {
+	if done() {
+	    return true;
+	}
+	core::hint::spin_loop();
+    }
+
+    done()
+}
+
+// INIT, then up to two startup IPIs, as some older CPUs miss the first
+unsafe fn start_ap(trampoline: PhysAddr, apic_id: u32) -> bool {
+    let stack = memory::kernel_allocate_stack(BOOT_STACK_SIZE).expect("Unable to allocate AP boot stack");
+    *trampoline_field(trampoline, &raw const ap_trampoline_stack) = (stack + BOOT_STACK_SIZE).as_u64();
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    interrupts::send_init_ipi(apic_id);
+    wait_for(INIT_DELAY_NS, || false);
+
+    let page = (trampoline.as_u64() / 4096) as u8;
+    for _ in 0 .. 2 {
+	interrupts::send_startup_ipi(apic_id, page);
+	if wait_for(STARTUP_TIMEOUT_NS, || AP_STARTED.load(Ordering::SeqCst)) {
+	    return true;
+	}
+    }
+
+    false
+}
+
+// Brings up every other CPU the MADT lists, one at a time, as they share the trampoline. Needs the scheduler to be
+// ready, as each AP starts scheduling as soon as it's up.
+pub fn start_aps() {
+    let trampoline = match TRAMPOLINE.get() {
+	Some(trampoline) => *trampoline,
+	None => return,
+    };
+
+    let apic_ids = match acpi::interrupts::iterate_madt_lapics() {
+	Ok(apic_ids) => apic_ids,
+	Err(e) => {
+	    log::warn!("Unable to find other CPUs in the MADT: {:?}", e);
+	    return;
+	},
+    };
+
+    unsafe {
+	setup_trampoline(trampoline);
+    }
+
+    let bsp_apic_id = interrupts::bsp_apic_id();
+    let mut cpus = 1;
+    for apic_id in apic_ids.into_iter().filter(|id| *id != bsp_apic_id) {
+	if cpus == scheduler::MAX_CPUS {
+	    log::warn!("Only the first {} CPUs are used", scheduler::MAX_CPUS);
+	    break;
+	}
+
+	log::info!("Initialising CPU{} (APIC ID {})...", cpus, apic_id);
+	// One which didn't start may yet, and would share the trampoline with the next, so no more are tried
+	if !unsafe { start_ap(trampoline, apic_id) } {
+	    log::warn!("CPU with APIC ID {} didn't start", apic_id);
+	    break;
+	}
+
+	cpus += 1;
+    }
+
+    log::info!("{} CPUs online", cpus);
+}
+
+// Where an AP comes out of the trampoline, in long mode on its page tables, on its boot stack
+extern "C" fn ap_main() -> ! {
+    unsafe {
+	core::arch::asm!("mov cr3, {}", in(reg) KERNEL_CR3.load(Ordering::SeqCst), options(nostack, preserves_flags));
+	Cr4::write_raw(KERNEL_CR4.load(Ordering::SeqCst));
+    }
+
+    gdt::init_ap();
+    interrupts::init_ap();
+    syscall::init();
+
+    AP_STARTED.store(true, Ordering::SeqCst);
+    scheduler::start();
+}
@@ -41,6 +41,10 @@ pub struct IoApicData {
     pub isos: Vec<&'static uacpi::acpi_madt_interrupt_source_override>,
 }
 
+// Processor Local APIC flags. CPUs with neither can't be brought up.
+const MADT_LAPIC_ENABLED: u32 = 1 << 0;
+const MADT_LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
 impl IoApicData {
     fn new() -> Self {
 	Self {
@@ -115,3 +119,55 @@ pub fn iterate_madt_ioapics() -> Result<IoApicData, uacpi_status> {
 	Ok(ret)
     }
 }
+
+// The APIC IDs of every CPU which can be brought up, including the one running this. Firmware lists CPUs with IDs of
+// 255 and over as x2APIC entries, and may list the rest as either.
+pub fn iterate_madt_lapics() -> Result<Vec<u32>, uacpi_status> {
+    unsafe {
+	let madt_ref = get_madt()?;
+
+	let base = madt_ref as *const uacpi::acpi_madt as *const u8;
+	let entries_start = base.add(size_of::<uacpi::acpi_madt>());
+	let entries_end = base.add(madt_ref.hdr.length as usize);
+
+	let mut ptr = entries_start;
+	let mut ret = Vec::new();
+
+	while ptr < entries_end {
+	    let hdr = &*(ptr as *const uacpi::acpi_entry_hdr);
+
+	    let entry_len = hdr.length as usize;
+	    if entry_len < size_of::<uacpi::acpi_entry_hdr>() {
+		break; // malformed
+	    }
+
+	    let Ok(entry_type) = uacpi::acpi_madt_entry_type::try_from(hdr.type_) else {
+		ptr = ptr.add(entry_len);
+		continue;
+	    };
+
+	    let cpu = match entry_type {
+		uacpi::acpi_madt_entry_type::ACPI_MADT_ENTRY_TYPE_LAPIC => {
+		    let lapic = &*(ptr as *const uacpi::acpi_madt_lapic);
+		    Some((lapic.id as u32, lapic.flags))
+		},
+		uacpi::acpi_madt_entry_type::ACPI_MADT_ENTRY_TYPE_LOCAL_X2APIC => {
+		    let x2apic = &*(ptr as *const uacpi::acpi_madt_x2apic);
+		    Some((x2apic.id, x2apic.flags))
+		},
+
+		_ => None,
+	    };
+
+	    if let Some((id, flags)) = cpu {
+		if flags & (MADT_LAPIC_ENABLED | MADT_LAPIC_ONLINE_CAPABLE) != 0 && !ret.contains(&id) {
+		    ret.push(id);
+		}
+	    }
+
+	    ptr = ptr.add(entry_len);
+	}
+
+	Ok(ret)
+    }
+}
@@ -8,6 +8,10 @@ pub mod resources;
 pub mod namespace;
 
 pub use uacpi::{uacpi_status, uacpi_interrupt_model, uacpi_namespace_node};
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+
+static POWER_BUTTON_HANDLER: Once<fn()> = Once::new();
 
 pub fn init(rdsp_addr: u64) {
     uacpi::init(rdsp_addr);
@@ -32,3 +36,60 @@ pub fn set_interrupt_model(model: uacpi_interrupt_model) -> Result<(), uacpi_sta
 	e => Err(e),
     }
 }
+
+// Runs handler, from the SCI's interrupt handler, each time the power button is pressed. Only one handler can be set.
+pub fn install_power_button_handler(handler: fn()) -> Result<(), uacpi_status> {
+    unsafe extern "C" fn power_button_pressed(_ctx: uacpi::uacpi_handle) -> uacpi::uacpi_interrupt_ret {
+	if let Some(handler) = POWER_BUTTON_HANDLER.get() {
+	    handler();
+	}
+
+	uacpi::UACPI_INTERRUPT_HANDLED
+    }
+
+    if POWER_BUTTON_HANDLER.get().is_some() {
+	return Err(uacpi_status::UACPI_STATUS_ALREADY_EXISTS);
+    }
+    POWER_BUTTON_HANDLER.call_once(|| handler);
+
+    // Enables the event as well
+    let ret = unsafe {
+	uacpi::uacpi_install_fixed_event_handler(
+	    uacpi::uacpi_fixed_event::UACPI_FIXED_EVENT_POWER_BUTTON,
+	    Some(power_button_pressed),
+	    core::ptr::null_mut())
+    };
+
+    match ret {
+	uacpi_status::UACPI_STATUS_OK => Ok(()),
+	e => Err(e),
+    }
+}
+
+// Only returns if the firmware fails to enter S5
+pub fn power_off() -> Result<(), uacpi_status> {
+    let ret = unsafe {
+	uacpi::uacpi_prepare_for_sleep_state(uacpi::uacpi_sleep_state::UACPI_SLEEP_STATE_S5)
+    };
+    if ret != uacpi_status::UACPI_STATUS_OK {
+	return Err(ret);
+    }
+
+    let ret = without_interrupts(|| unsafe {
+	uacpi::uacpi_enter_sleep_state(uacpi::uacpi_sleep_state::UACPI_SLEEP_STATE_S5)
+    });
+
+    Err(ret)
+}
+
+// Resets through the FADT reset register. Only returns if there isn't one, or writing it didn't take
+pub fn reboot() -> Result<(), uacpi_status> {
+    let ret = unsafe {
+	uacpi::uacpi_reboot()
+    };
+
+    match ret {
+	uacpi_status::UACPI_STATUS_OK => Ok(()),
+	e => Err(e),
+    }
+}
@@ -1,6 +1,8 @@
 use alloc::boxed::Box;
 use alloc::fmt;
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::ffi::CString;
 use bitfield::bitfield;
 use core::any::Any;
 use core::ffi::{c_char, c_void, CStr};
@@ -125,6 +127,27 @@ pub fn enumerate() -> Result<(), uacpi_status> {
     }
 }
 
+// Finds the devices matching a _HID or _CID which _STA reports as present, so drivers can read their
+// _CRS rather than assuming where a legacy device lives
+pub fn find_devices(hid: &str) -> Result<Vec<*mut uacpi::uacpi_namespace_node>, uacpi_status> {
+    unsafe extern "C" fn gather_device(user: *mut c_void, namespace: *mut uacpi::uacpi_namespace_node, _depth: u32) -> uacpi::uacpi_iteration_decision {
+	let devices: &mut Vec<*mut uacpi::uacpi_namespace_node> = unsafe { &mut *(user as *mut Vec<*mut uacpi::uacpi_namespace_node>) };
+	devices.push(namespace);
+	uacpi::uacpi_iteration_decision::UACPI_ITERATION_DECISION_CONTINUE
+    }
+
+    let hid = CString::new(hid).expect("HID contains a NUL");
+    let mut devices: Vec<*mut uacpi::uacpi_namespace_node> = Vec::new();
+    let ret = unsafe {
+	uacpi::uacpi_find_devices(hid.as_ptr(), Some(gather_device), &mut devices as *mut _ as *mut c_void)
+    };
+
+    match ret {
+	uacpi_status::UACPI_STATUS_OK => Ok(devices),
+	_ => Err(ret),
+    }
+}
+
 pub fn get_pci_routing_table(namespace: *mut uacpi::uacpi_namespace_node) -> Result<VecMap<PciInterruptFunction, interrupts::InterruptRoute>, uacpi_status> {
     let mut pci_routing_ptr: *mut uacpi::uacpi_pci_routing_table = core::ptr::null_mut();
     let ret = unsafe {
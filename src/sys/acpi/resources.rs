@@ -17,7 +17,52 @@ pub enum Resource {
 	write_status: u8,
 	address: u32,
 	length: u32,
-    }
+    },
+    Io {
+	decode_16: bool,
+	minimum: u16,
+	maximum: u16,
+	alignment: u8,
+	length: u8,
+    },
+    FixedIo {
+	address: u16,
+	length: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoRange {
+    pub base: u16,
+    pub length: u16,
+}
+
+// I/O port ranges in the order _CRS lists them. For an Io descriptor in _CRS, the minimum and maximum are
+// the same, and are where the device actually decodes
+pub fn io_ranges(resources: &[Resource]) -> Vec<IoRange> {
+    resources.iter()
+	.filter_map(|r| match r {
+	    Resource::Io { minimum, length, .. } => Some(IoRange {
+		base: *minimum,
+		length: *length as u16,
+	    }),
+	    Resource::FixedIo { address, length } => Some(IoRange {
+		base: *address,
+		length: *length as u16,
+	    }),
+	    _ => None,
+	})
+	.collect()
+}
+
+pub fn irqs(resources: &[Resource]) -> Vec<u32> {
+    resources.iter()
+	.filter_map(|r| match r {
+	    Resource::Irq { irqs, .. } => Some(irqs.iter().copied()),
+	    _ => None,
+	})
+	.flatten()
+	.collect()
 }
 
 pub fn get_resources(namespace: *mut uacpi::uacpi_namespace_node) -> Result<Vec<Resource>, uacpi_status> {
@@ -59,6 +104,21 @@ pub fn get_resources(namespace: *mut uacpi::uacpi_namespace_node) -> Result<Vec<
 		    length: resource.__bindgen_anon_1.fixed_memory32.as_ref().length,
 		});
 	    },
+	    uacpi::uacpi_resource_type::UACPI_RESOURCE_TYPE_IO => {
+		resources_vec.push(Resource::Io {
+		    decode_16: resource.__bindgen_anon_1.io.as_ref().decode_type == uacpi::UACPI_DECODE_16 as u8,
+		    minimum: resource.__bindgen_anon_1.io.as_ref().minimum,
+		    maximum: resource.__bindgen_anon_1.io.as_ref().maximum,
+		    alignment: resource.__bindgen_anon_1.io.as_ref().alignment,
+		    length: resource.__bindgen_anon_1.io.as_ref().length,
+		});
+	    },
+	    uacpi::uacpi_resource_type::UACPI_RESOURCE_TYPE_FIXED_IO => {
+		resources_vec.push(Resource::FixedIo {
+		    address: resource.__bindgen_anon_1.fixed_io.as_ref().address,
+		    length: resource.__bindgen_anon_1.fixed_io.as_ref().length,
+		});
+	    },
 	    _ => (),
 	}
 
@@ -83,3 +143,20 @@ pub fn get_resources(namespace: *mut uacpi::uacpi_namespace_node) -> Result<Vec<
 	}
     }
 }
+
+#[test]
+fn io_ranges_and_irqs_come_out_in_crs_order() {
+    use alloc::vec;
+
+    let crs = [
+	Resource::Io { decode_16: true, minimum: 0x1F0, maximum: 0x1F0, alignment: 1, length: 8 },
+	Resource::FixedMemory32 { write_status: 1, address: 0xFED0_0000, length: 0x400 },
+	Resource::Irq { triggering: 0, polarity: 0, sharing: false, wake_capability: false, irqs: vec![14] },
+	Resource::FixedIo { address: 0x3F6, length: 1 },
+	Resource::Irq { triggering: 1, polarity: 1, sharing: true, wake_capability: false, irqs: vec![20, 21] },
+    ];
+
+    assert_eq!(io_ranges(&crs), [IoRange { base: 0x1F0, length: 8 }, IoRange { base: 0x3F6, length: 1 }]);
+    assert_eq!(irqs(&crs), [14, 20, 21]);
+    assert!(io_ranges(&[]).is_empty());
+}
@@ -515,8 +515,7 @@ extern "C" fn uacpi_kernel_handle_firmware_request(_request: *const c_void) -> u
 #[no_mangle]
 #[allow(dead_code)]
 extern "C" fn uacpi_kernel_install_interrupt_handler(irq: u32, handler: unsafe extern "C" fn(u64), ctx: u64, _out_handle: *const c_void) -> uacpi_status {
-    let route = interrupts::InterruptRoute::Irq(irq as u8);
-    route.register_handler(Box::new(move || unsafe { handler(ctx) }));
+    interrupts::enable_acpi_irq(irq as u8, Box::new(move || unsafe { handler(ctx) }));
     uacpi_status::UACPI_STATUS_OK
 }
 #[no_mangle]
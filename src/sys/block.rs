@@ -1,4 +1,5 @@
 use spin::{Mutex, Once, RwLock};
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::string::String;
@@ -6,16 +7,21 @@ use core::ptr;
 use core::ascii;
 use uuid::Uuid;
 use bytes::Bytes;
-use alloc::boxed::Box;
-use core::mem::offset_of;
-use x86_64::structures::tss::TaskStateSegment;
+use alloc::vec;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::format;
 use futures_util::future::BoxFuture;
+use x86_64::PhysAddr;
 
+use crate::cmdline;
+use crate::fs;
 use crate::fs::fat;
-use crate::scheduler;
-use crate::process;
-use crate::gdt;
+use crate::sys::block_cache;
+use crate::scheduler::executor;
 use crate::syscall;
+use crate::vfs;
 
 #[repr(C, packed(1))]
 #[derive(Copy, Clone)]
@@ -27,6 +33,7 @@ struct PackedUuid {
 }
 
 #[repr(C, packed(1))]
+#[derive(Copy, Clone)]
 struct MbrEntry {
     boot_indicator: u8,
     starting_head: u8,
@@ -36,7 +43,8 @@ struct MbrEntry {
     ending_head: u8,
     ending_sect: u8,
     ending_cyl: u8,
-    total_sectors: u8,
+    starting_lba: u32,
+    total_sectors: u32,
 }
 
 #[repr(C, packed(1))]
@@ -46,6 +54,21 @@ struct Mbr {
     boot_sig: u16,
 }
 
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+// Extended partitions hold a chain of logical partitions, rather than a filesystem
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+
+// GPT partitions of this type (Linux root, x86-64) are mounted as root in preference to any other
+const GPT_ROOT_PARTITION_TYPE: Uuid = Uuid::from_u128(0x4f68bce3_e8cd_4db1_96e7_fbcaf984b709);
+
+// A filesystem found on a partition, not yet mounted
+struct FoundFilesystem {
+    name: String,
+    fs: Arc<dyn vfs::filesystem::FileSystem>,
+    root_type: bool,
+}
+
 #[repr(C, packed(1))]
 struct PartitionTableHeader {
     signature: [ascii::Char; 8],
@@ -76,6 +99,34 @@ struct PartitionEntry {
     partition_name: [u16; 36],
 }
 
+// A disk divided up into partitions, which filesystems read from. Blocks are numbered from the start of the partition.
+pub trait PartitionedDevice {
+    fn read(&self, partition: u32, starting_block: u64, size: u64) -> BoxFuture<'_, Result<Bytes, ()>>;
+    // As BlockDevice::read_to_frames, for blocks of a partition
+    fn read_to_frames(&self, _partition: u32, _starting_block: u64, _size: u64, _frames: Vec<PhysAddr>, _frame_offset: u64) -> Option<BoxFuture<'_, Result<(), ()>>> {
+	None
+    }
+    // Flushes the whole disk, as its write cache doesn't know about partitions
+    fn flush(&self) -> BoxFuture<'_, Result<(), ()>>;
+}
+
+// Looks for a filesystem on a partition, advertising the partition either way
+async fn probe_partition(
+    dev: Arc<dyn PartitionedDevice + Send + Sync>,
+    disk_name: &str,
+    partition: usize,
+    info: PartitionInfo,
+    root_type: bool) -> Option<FoundFilesystem> {
+    let found = fat::probe_fat_fs(dev, partition as u32).await;
+    advertise_partition(disk_name, partition, info, found.as_ref().map(|(fs_type, _)| *fs_type));
+
+    found.map(|(_, fs)| FoundFilesystem {
+	name: format!("{}p{}", disk_name, partition + 1),
+	fs,
+	root_type,
+    })
+}
+
 // Allow dead code for now, as it's angry at mbr and pth, which we do actually need for partition code
 // that hasn't yet been written.
 #[allow(dead_code)]
@@ -88,27 +139,29 @@ pub struct GptDevice {
 
 pub trait BlockDevice {
     fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<bytes::Bytes, syscall::CanonicalError>>;
+    // Reads sectors by DMA straight into physical memory, starting frame_offset bytes into the first of frames and
+    // carrying on through the rest, rather than into a buffer which then has to be copied. None if the disk can't do
+    // that for this read, in which case the caller falls back to read
+    fn read_to_frames(self: Arc<Self>, _offset: u64, _size: u64, _frames: Vec<PhysAddr>, _frame_offset: u64) -> Option<BoxFuture<'static, Result<(), syscall::CanonicalError>>> {
+	None
+    }
+    // Writes whole sectors, starting at offset. Disks which can't be written to yet just refuse
+    fn write(self: Arc<Self>, _offset: u64, _data: bytes::Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move { Err(syscall::CanonicalError::RoFs) })
+    }
+    // Makes sure everything written so far is on the disk itself, rather than in its write cache. Disks which don't
+    // cache writes, or which flush after every one, have nothing to do
+    fn flush(self: Arc<Self>) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move { Ok(()) })
+    }
+    // Sizes are in 512 byte sectors, matching the units of read
+    fn size_in_sectors(&self) -> u64;
+    fn model(&self) -> String;
 }
 
 impl GptDevice {
-    async fn new(device: Arc<dyn BlockDevice + Send + Sync>) -> Option<Arc<GptDevice>> {
-	let (mbr, pth, partition_entries) = {
-	    let mbr_buf = match device.clone().read(0, 1).await {
-		Ok(a) => a,
-		Err(e) => {
-		    log::info!("Failed to read boot sector - {:?}", e);
-		    return None;
-		}
-	    };
-
-	    let mbr = unsafe {
-		ptr::read(mbr_buf.as_ptr() as *const Mbr)
-	    };
-
-	    if mbr.partitions[0].system_id != 0xEE {
-		return None;
-	    }
-
+    async fn new(disk_name: &str, device: Arc<dyn BlockDevice + Send + Sync>, mbr: Mbr) -> Option<(Arc<GptDevice>, Vec<FoundFilesystem>)> {
+	let (pth, partition_entries) = {
 	    let pth_buf = device.clone().read(1, 1).await.expect("Failed to read Partition Table Header");
 	    let pth = unsafe {
 		ptr::read(pth_buf.as_ptr() as *const PartitionTableHeader)
@@ -149,7 +202,7 @@ impl GptDevice {
 		log::info!("Found partition {}, type = {}", partition_name, partition_uuid);
 	    }
 
-	    (mbr, pth, partition_entries)
+	    (pth, partition_entries)
 	};
 
 	let device_arc = Arc::new(GptDevice {
@@ -159,94 +212,419 @@ impl GptDevice {
 	    dev: device,
 	});
 
-	for partition in 0 .. partition_entries.len() {
-	    fat::register_fat_fs(device_arc.clone(), partition as u32).await;
+	let mut found = Vec::new();
+	for (partition, entry) in partition_entries.iter().enumerate() {
+	    // Unused entries have a zeroed type
+	    let type_guid = entry.partition_type_guid;
+	    if type_guid.d1 == 0 && type_guid.d2 == 0 && type_guid.d3 == 0 && type_guid.d4 == [0; 8] {
+		continue;
+	    }
+
+	    let name_utf16 = entry.partition_name;
+	    let name = String::from_utf16_lossy(
+		name_utf16.iter().copied()
+		    .filter(|i| *i != 0)
+		    .collect::<Vec<u16>>()
+		    .as_slice());
+	    let type_uuid = Uuid::from_fields(type_guid.d1, type_guid.d2, type_guid.d3, &type_guid.d4);
+	    let starting_lba = entry.starting_lba;
+	    let ending_lba = entry.ending_lba;
+	    let info = PartitionInfo {
+		start: starting_lba,
+		size: ending_lba + 1 - starting_lba,
+		name,
+		part_type: format!("{}", type_uuid),
+	    };
+
+	    let root_type = type_uuid == GPT_ROOT_PARTITION_TYPE;
+	    found.extend(probe_partition(device_arc.clone(), disk_name, partition, info, root_type).await);
 	}
 
-	Some(device_arc)
+	Some((device_arc, found))
     }
+}
 
-    pub async fn read(&self, partition: u32, starting_block: u64, size: u64) -> Result<Bytes, ()> {
-	if partition as usize >= self.pt.len() {
-	    return Err(());
-	}
+impl PartitionedDevice for GptDevice {
+    fn read(&self, partition: u32, starting_block: u64, size: u64) -> BoxFuture<'_, Result<Bytes, ()>> {
+	Box::pin(async move {
+	    if partition as usize >= self.pt.len() {
+		return Err(());
+	    }
 
-	let pt = self.pt[partition as usize];
+	    let pt = self.pt[partition as usize];
+	    if starting_block >= (pt.ending_lba - pt.starting_lba) {
+		return Err(());
+	    }
+
+	    let adjusted_start = starting_block + pt.starting_lba;
+	    if adjusted_start + size >= pt.ending_lba {
+		return Err(());
+	    }
+
+	    match self.dev.clone().read(adjusted_start, size).await {
+		Ok(a) => Ok(a),
+		Err(_) => Err(())
+	    }
+	})
+    }
+
+    fn read_to_frames(&self, partition: u32, starting_block: u64, size: u64, frames: Vec<PhysAddr>, frame_offset: u64) -> Option<BoxFuture<'_, Result<(), ()>>> {
+	let pt = self.pt.get(partition as usize)?;
 	if starting_block >= (pt.ending_lba - pt.starting_lba) {
-	    return Err(());
+	    return None;
 	}
 
 	let adjusted_start = starting_block + pt.starting_lba;
 	if adjusted_start + size >= pt.ending_lba {
-	    return Err(());
+	    return None;
 	}
 
-	match self.dev.clone().read(adjusted_start, size).await {
-	    Ok(a) => Ok(a),
-	    Err(_) => Err(())
-	}
+	let read = self.dev.clone().read_to_frames(adjusted_start, size, frames, frame_offset)?;
+	Some(Box::pin(async move {
+	    read.await.map_err(|_| ())
+	}))
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), ()>> {
+	Box::pin(async move {
+	    self.dev.clone().flush().await.map_err(|_| ())
+	})
     }
 }
 
 unsafe impl Send for GptDevice { }
 unsafe impl Sync for GptDevice { }
 
-static BLOCK_DEVICE_TABLE: Once<RwLock<Vec<Arc<GptDevice>>>> = Once::new();
-static UNINITIALISED_BLOCK_DEVICE_TABLE: Once<RwLock<Vec<Arc<dyn BlockDevice + Send + Sync>>>> = Once::new();
+pub struct MbrDevice {
+    partitions: [MbrEntry; 4],
+    dev: Arc<dyn BlockDevice + Send + Sync>,
+}
+
+impl MbrDevice {
+    async fn new(disk_name: &str, device: Arc<dyn BlockDevice + Send + Sync>, mbr: Mbr) -> Option<(Arc<MbrDevice>, Vec<FoundFilesystem>)> {
+	let device_arc = Arc::new(MbrDevice {
+	    partitions: mbr.partitions,
+	    dev: device,
+	});
+
+	// Only the primary partitions are looked at. Empty slots keep their numbers, so the partitions after them do too.
+	let mut found = Vec::new();
+	for (partition, entry) in mbr.partitions.iter().enumerate() {
+	    let system_id = entry.system_id;
+	    let starting_lba = entry.starting_lba as u64;
+	    let total_sectors = entry.total_sectors as u64;
+	    if system_id == 0 || total_sectors == 0 {
+		continue;
+	    }
+
+	    log::info!("Found MBR partition {}, type = {:#04x}, {} sectors at {}", partition + 1, system_id, total_sectors, starting_lba);
+	    if MBR_EXTENDED_TYPES.contains(&system_id) {
+		log::info!("Extended partitions are not supported, ignoring partition {}", partition + 1);
+		continue;
+	    }
+
+	    let info = PartitionInfo {
+		start: starting_lba,
+		size: total_sectors,
+		name: String::new(),
+		part_type: format!("{:#04x}", system_id),
+	    };
+
+	    // MBR has no type saying a partition is root
+	    found.extend(probe_partition(device_arc.clone(), disk_name, partition, info, false).await);
+	}
+
+	Some((device_arc, found))
+    }
+}
+
+impl PartitionedDevice for MbrDevice {
+    fn read(&self, partition: u32, starting_block: u64, size: u64) -> BoxFuture<'_, Result<Bytes, ()>> {
+	Box::pin(async move {
+	    let entry = self.partitions.get(partition as usize).ok_or(())?;
+	    let starting_lba = entry.starting_lba as u64;
+	    let total_sectors = entry.total_sectors as u64;
+
+	    if starting_block + size > total_sectors {
+		return Err(());
+	    }
+
+	    self.dev.clone().read(starting_lba + starting_block, size).await.map_err(|_| ())
+	})
+    }
+
+    fn read_to_frames(&self, partition: u32, starting_block: u64, size: u64, frames: Vec<PhysAddr>, frame_offset: u64) -> Option<BoxFuture<'_, Result<(), ()>>> {
+	let entry = self.partitions.get(partition as usize)?;
+	if starting_block + size > entry.total_sectors as u64 {
+	    return None;
+	}
+
+	let read = self.dev.clone().read_to_frames(entry.starting_lba as u64 + starting_block, size, frames, frame_offset)?;
+	Some(Box::pin(async move {
+	    read.await.map_err(|_| ())
+	}))
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), ()>> {
+	Box::pin(async move {
+	    self.dev.clone().flush().await.map_err(|_| ())
+	})
+    }
+}
+
+// Every partitioned disk starts with an MBR. A GPT disk has a protective one, with a single partition covering the
+// whole disk, so that tools which only know about MBR leave it alone.
+async fn scan_partition_table(disk_name: &str, device: Arc<dyn BlockDevice + Send + Sync>) -> Option<(Arc<dyn PartitionedDevice + Send + Sync>, Vec<FoundFilesystem>)> {
+    let mbr_buf = match device.clone().read(0, 1).await {
+	Ok(a) => a,
+	Err(e) => {
+	    log::info!("Failed to read boot sector - {:?}", e);
+	    return None;
+	}
+    };
+
+    let mbr = unsafe {
+	ptr::read(mbr_buf.as_ptr() as *const Mbr)
+    };
+
+    let boot_sig = mbr.boot_sig;
+    if boot_sig != MBR_SIGNATURE {
+	log::info!("No partition table on {}", disk_name);
+	return None;
+    }
+
+    if mbr.partitions.iter().any(|entry| entry.system_id == MBR_TYPE_GPT_PROTECTIVE) {
+	let (dev, found) = GptDevice::new(disk_name, device, mbr).await?;
+	Some((dev, found))
+    } else {
+	let (dev, found) = MbrDevice::new(disk_name, device, mbr).await?;
+	Some((dev, found))
+    }
+}
+
+// What sysfs says about a partition. Its type is a GUID on GPT disks, and a single byte on MBR disks, and only GPT
+// partitions have names.
+struct PartitionInfo {
+    start: u64,
+    size: u64,
+    name: String,
+    part_type: String,
+}
+
+fn advertise_partition(disk_name: &str, partition: usize, info: PartitionInfo, fs_type: Option<&'static str>) {
+    // Partitions are numbered from 1, as on other systems
+    let devpath = format!("/block/{}/{}p{}", disk_name, disk_name, partition + 1);
+    fs::sysfs::add_static_attribute(&format!("{}/start", devpath), format!("{}\n", info.start));
+    fs::sysfs::add_static_attribute(&format!("{}/size", devpath), format!("{}\n", info.size));
+    fs::sysfs::add_static_attribute(&format!("{}/name", devpath), format!("{}\n", info.name));
+    fs::sysfs::add_static_attribute(&format!("{}/type", devpath), format!("{}\n", info.part_type));
+
+    let mut vars = vec![
+	("DEVTYPE", String::from("partition")),
+	("PARTN", format!("{}", partition + 1)),
+	("PARTNAME", info.name),
+	("PARTTYPE", info.part_type),
+    ];
+    if let Some(fs_type) = fs_type {
+	vars.push(("FSTYPE", String::from(fs_type)));
+    }
+    fs::sysfs::emit_uevent("add", &devpath, "block", &vars);
+}
+
+static BLOCK_DEVICE_TABLE: Once<RwLock<Vec<Arc<dyn PartitionedDevice + Send + Sync>>>> = Once::new();
+// Disks registered but not yet scanned for partitions, along with their names
+static UNINITIALISED_BLOCK_DEVICE_TABLE: Once<Mutex<Vec<(String, Arc<dyn BlockDevice + Send + Sync>)>>> = Once::new();
+static RESCAN_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+// Filesystems which will be mounted under /mnt, once there's a root filesystem with one to mount them on
+static PENDING_MOUNTS: Mutex<Vec<FoundFilesystem>> = Mutex::new(Vec::new());
+static NEXT_DISK_NUMBER: AtomicU64 = AtomicU64::new(0);
 
 pub fn init() {
+    block_cache::init();
     BLOCK_DEVICE_TABLE.call_once(|| RwLock::new(Vec::new()));
-    UNINITIALISED_BLOCK_DEVICE_TABLE.call_once(|| RwLock::new(Vec::new()));
+    UNINITIALISED_BLOCK_DEVICE_TABLE.call_once(|| Mutex::new(Vec::new()));
 
-    scheduler::kthread_start(kthread_init_block_devices);
+    executor::spawn(init_block_devices()).expect("Unable to start scanning for block devices");
 }
 
-pub fn register_block_device(dev: Arc<dyn BlockDevice + Send + Sync>) {
-    let mut device_tbl = UNINITIALISED_BLOCK_DEVICE_TABLE.get().expect("Attempted to access device table before it is initialised").write();
-    device_tbl.push(dev);
+// Makes a disk available, at boot or at any point after. Its partitions are scanned and probed for filesystems in the
+// background, and a uevent is emitted for the disk and each partition found
+pub fn register_disk(dev: Arc<dyn BlockDevice + Send + Sync>) {
+    let disk_number = NEXT_DISK_NUMBER.fetch_add(1, Ordering::SeqCst);
+    let disk_name = format!("disk{}", disk_number);
+    let dev: Arc<dyn BlockDevice + Send + Sync> = Arc::new(block_cache::CachedDisk::new(disk_number, dev));
+    let devpath = format!("/block/{}", disk_name);
+    fs::sysfs::add_static_attribute(&format!("{}/size", devpath), format!("{}\n", dev.size_in_sectors()));
+    fs::sysfs::add_static_attribute(&format!("{}/model", devpath), format!("{}\n", dev.model()));
+    fs::sysfs::emit_uevent("add", &devpath, "block", &[
+	("DEVTYPE", String::from("disk")),
+	("DEVNAME", disk_name.clone()),
+    ]);
+
+    UNINITIALISED_BLOCK_DEVICE_TABLE
+	.get()
+	.expect("Attempted to access device table before it is initialised")
+	.lock()
+	.push((disk_name, dev));
+
+    if let Some(waker) = RESCAN_WAKER.lock().take() {
+	waker.wake();
+    }
 }
 
-fn kthread_init_block_devices() -> ! {
-    let fut = async {
-	{
-            let mut uninit_device_tbl = UNINITIALISED_BLOCK_DEVICE_TABLE
-		.get()
-		.expect("Attempted to access device table before it is initialised")
-		.write();
-
-            for dev in uninit_device_tbl.drain(..) {
-		if let Some(gpt_device) = GptDevice::new(dev).await {
-                    let mut device_tbl = BLOCK_DEVICE_TABLE
-			.get()
-			.expect("Attempted to access device table before it is initialised")
-			.write();
-                    device_tbl.push(gpt_device);
-		}
-            }
+// Waits until at least one disk is waiting to be scanned, then takes all of them
+async fn next_unscanned_disks() -> Vec<(String, Arc<dyn BlockDevice + Send + Sync>)> {
+    poll_fn(|cx| {
+	let mut uninit_device_tbl = UNINITIALISED_BLOCK_DEVICE_TABLE
+	    .get()
+	    .expect("Attempted to access device table before it is initialised")
+	    .lock();
+
+	if uninit_device_tbl.is_empty() {
+	    // Registered while the table is still locked, so a disk can't be added between checking and sleeping
+	    *RESCAN_WAKER.lock() = Some(cx.waker().clone());
+	    Poll::Pending
+	} else {
+	    Poll::Ready(uninit_device_tbl.drain(..).collect())
 	}
+    }).await
+}
 
-        // Once done, mark thread for exit
-	scheduler::exit(0);
+// Mounts one of a disk's filesystems as root, if there isn't a root yet: the partition root= names, e.g. disk0p2, if
+// it's given, or else the first with the root partition type, or failing that, the first found. The rest go under
+// /mnt, named after their partitions.
+async fn mount_filesystems(mut found: Vec<FoundFilesystem>) {
+    let root_idx = match cmdline::get("root") {
+	Some(root) => {
+	    let root = root.strip_prefix("/dev/").unwrap_or(root);
+	    found.iter().position(|f| f.name == root)
+	},
+	None => found.iter().position(|f| f.root_type).or(if found.is_empty() { None } else { Some(0) }),
     };
+    if let Some(root_idx) = root_idx {
+	let root = found.remove(root_idx);
+	if vfs::mount_root(root.fs.clone()).is_ok() {
+	    log::info!("Mounted {} as root", root.name);
+	} else {
+	    found.insert(root_idx, root);
+	}
+    }
+
+    let mut pending = core::mem::take(&mut *PENDING_MOUNTS.lock());
+    pending.extend(found);
+
+    let mut still_pending = Vec::new();
+    for found in pending {
+	let mountpoint = format!("/mnt/{}", found.name);
+	match vfs::mount(&mountpoint, found.fs.clone()).await {
+	    Ok(()) => log::info!("Mounted {} at {}", found.name, mountpoint),
+	    Err(e) => {
+		log::info!("Unable to mount {} at {} yet: {:?}", found.name, mountpoint, e);
+		still_pending.push(found);
+	    },
+	}
+    }
+
+    PENDING_MOUNTS.lock().extend(still_pending);
+}
+
+// Flushes every disk with a partition table. One failing doesn't stop the rest being flushed, but Io is returned
+pub async fn flush_all() -> Result<(), syscall::CanonicalError> {
+    let devices = BLOCK_DEVICE_TABLE
+	.get()
+	.expect("Attempted to access device table before it is initialised")
+	.read()
+	.clone();
+
+    let mut result = Ok(());
+    for dev in devices {
+	if dev.flush().await.is_err() {
+	    result = Err(syscall::CanonicalError::Io);
+	}
+    }
+
+    result
+}
+
+// Runs for as long as the system does, so that disks added after boot are picked up too
+async fn init_block_devices() {
+    loop {
+	for (disk_name, dev) in next_unscanned_disks().await {
+	    if let Some((partitioned_device, found)) = scan_partition_table(&disk_name, dev).await {
+		BLOCK_DEVICE_TABLE
+		    .get()
+		    .expect("Attempted to access device table before it is initialised")
+		    .write()
+		    .push(partitioned_device);
+
+		mount_filesystems(found).await;
+	    }
+	}
+    }
+}
+
+#[test]
+fn a_disk_added_later_has_its_partitions_scanned() {
+    use core::future::Future;
+    use core::task::Context;
+    use futures_util::FutureExt;
+    use futures_util::task::ArcWake;
+
+    struct MemoryDisk(Vec<u8>);
+    impl BlockDevice for MemoryDisk {
+	fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	    let data = self.0.get((offset * 512) as usize .. ((offset + size) * 512) as usize)
+		.map(Bytes::copy_from_slice)
+		.ok_or(syscall::CanonicalError::Io);
+	    Box::pin(async move { data })
+	}
+
+	fn size_in_sectors(&self) -> u64 {
+	    self.0.len() as u64 / 512
+	}
 
-    let process = scheduler::get_current_process();
-    process.set_state(process::TaskState::AsyncSyscall {
-	future: Arc::new(Mutex::new(Box::pin(fut))),
-    });
-
-    unsafe {
-	// Switch to the kernel stack before calling schedule_next, as the non-task kernel depends on a kstack
-	core::arch::asm!(
-	    // Save the stack pointer (note that, because this is a kthread, swapping gs is unnecessary)
-	    // Disable interrupts, as the kernel stack assumes no interrupts
-	    "cli",
-	    "mov gs:[{sp}], rsp",
-	    "mov rsp, gs:[{ksp}]",
-	    
-	    sp = const(offset_of!(gdt::ProcessorControlBlock, tmp_user_stack_ptr)),
-	    ksp = const(offset_of!(gdt::ProcessorControlBlock, tss) + offset_of!(TaskStateSegment, privilege_stack_table)),
-	);
-    }
-
-    scheduler::schedule_next();
+	fn model(&self) -> String {
+	    String::from("memory")
+	}
+    }
+
+    struct Woken(core::sync::atomic::AtomicBool);
+    impl ArcWake for Woken {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+	    arc_self.0.store(true, Ordering::SeqCst);
+	}
+    }
+
+    // An MBR with one Linux partition of 8 sectors, starting at sector 4. Its second sector is marked so that reading
+    // it through the partition shows where the partition was found
+    let mut disk = vec![0u8; 16 * 512];
+    disk[0x1BE + 4] = 0x83;
+    disk[0x1BE + 8 .. 0x1BE + 12].copy_from_slice(&4u32.to_le_bytes());
+    disk[0x1BE + 12 .. 0x1BE + 16].copy_from_slice(&8u32.to_le_bytes());
+    disk[0x1FE .. 0x200].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+    disk[5 * 512 .. 6 * 512].fill(0xAB);
+
+    fs::sysfs::init();
+    UNINITIALISED_BLOCK_DEVICE_TABLE.call_once(|| Mutex::new(Vec::new()));
+
+    // The scanning task is asleep when the disk turns up
+    let woken = Arc::new(Woken(core::sync::atomic::AtomicBool::new(false)));
+    let waker = futures_util::task::waker(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut unscanned = Box::pin(next_unscanned_disks());
+    assert!(unscanned.as_mut().poll(&mut cx).is_pending());
+
+    register_disk(Arc::new(MemoryDisk(disk)));
+    assert!(woken.0.load(Ordering::SeqCst));
+    let Poll::Ready(mut disks) = unscanned.as_mut().poll(&mut cx) else {
+	panic!("Registering a disk didn't wake the scan");
+    };
+    assert_eq!(disks.len(), 1);
+
+    let (disk_name, dev) = disks.remove(0);
+    let (partitioned, found) = scan_partition_table(&disk_name, dev).now_or_never().unwrap().unwrap();
+    assert!(found.is_empty());
+    assert!(partitioned.read(0, 1, 1).now_or_never().unwrap().unwrap().iter().all(|b| *b == 0xAB));
+    assert!(partitioned.read(0, 8, 1).now_or_never().unwrap().is_err());
+    assert!(partitioned.read(1, 0, 1).now_or_never().unwrap().is_err());
 }
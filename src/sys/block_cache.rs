@@ -0,0 +1,305 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::memory::reclaim;
+use crate::sys::block::BlockDevice;
+use crate::syscall;
+
+// How many 512 byte sectors are kept, across all disks
+const BLOCK_CACHE_SECTORS: usize = 4096;
+// Reads bigger than this are file data far more often than filesystem metadata, and caching them would only push the
+// metadata out, so they go straight to the disk
+const MAX_CACHED_READ_SECTORS: u64 = 16;
+const SECTOR_SIZE: u64 = 512;
+
+// Sectors, keyed by disk and LBA, along with when each was last used, oldest first
+struct BlockCache {
+    sectors: BTreeMap<(u64, u64), (Bytes, u64)>,
+    lru: BTreeMap<u64, (u64, u64)>,
+    clock: u64,
+    // Bumped whenever sectors are invalidated, so a read that raced with a write knows not to cache what it got
+    generation: u64,
+}
+
+impl BlockCache {
+    const fn new() -> BlockCache {
+	BlockCache {
+	    sectors: BTreeMap::new(),
+	    lru: BTreeMap::new(),
+	    clock: 0,
+	    generation: 0,
+	}
+    }
+
+    fn tick(&mut self) -> u64 {
+	self.clock += 1;
+	self.clock
+    }
+
+    fn get(&mut self, key: (u64, u64)) -> Option<Bytes> {
+	let now = self.tick();
+	let (data, last_used) = self.sectors.get_mut(&key)?;
+	self.lru.remove(last_used);
+	self.lru.insert(now, key);
+	*last_used = now;
+
+	Some(data.clone())
+    }
+
+    fn insert(&mut self, key: (u64, u64), data: Bytes) {
+	let now = self.tick();
+	if let Some((_, last_used)) = self.sectors.remove(&key) {
+	    self.lru.remove(&last_used);
+	}
+
+	let full = (self.sectors.len() + 1).saturating_sub(BLOCK_CACHE_SECTORS);
+	self.evict(full as u64);
+
+	self.sectors.insert(key, (data, now));
+	self.lru.insert(now, key);
+    }
+
+    // Drops up to count of the least recently used sectors, returning how many there were
+    fn evict(&mut self, count: u64) -> u64 {
+	let mut evicted = 0;
+	while evicted < count {
+	    match self.lru.pop_first() {
+		Some((_, oldest)) => self.sectors.remove(&oldest),
+		None => break,
+	    };
+	    evicted += 1;
+	}
+
+	evicted
+    }
+
+    // Drops least recently used sectors until at least target bytes have been given back, returning how many were
+    fn shrink(&mut self, target: u64) -> u64 {
+	self.evict(target.div_ceil(SECTOR_SIZE)) * SECTOR_SIZE
+    }
+
+    fn invalidate(&mut self, disk: u64, first_sector: u64, count: u64) {
+	self.generation += 1;
+	for lba in first_sector .. first_sector + count {
+	    if let Some((_, last_used)) = self.sectors.remove(&(disk, lba)) {
+		self.lru.remove(&last_used);
+	    }
+	}
+    }
+}
+
+static BLOCK_CACHE: Mutex<BlockCache> = Mutex::new(BlockCache::new());
+
+struct BlockCacheShrinker;
+
+impl reclaim::Shrinker for BlockCacheShrinker {
+    fn name(&self) -> &str {
+	"block cache"
+    }
+
+    // Writes go straight through, so every sector is clean and can just be dropped
+    fn shrink(&self, target: u64) -> u64 {
+	// Reclaim may have been reached from an allocation made with the cache locked
+	match BLOCK_CACHE.try_lock() {
+	    Some(mut cache) => cache.shrink(target),
+	    None => 0,
+	}
+    }
+}
+
+pub fn init() {
+    reclaim::register_shrinker(Arc::new(BlockCacheShrinker));
+}
+
+// Sits in front of a disk, so everything reading from it goes through the cache, and everything writing to it
+// invalidates what it overwrites
+pub struct CachedDisk {
+    disk: u64,
+    dev: Arc<dyn BlockDevice + Send + Sync>,
+}
+
+impl CachedDisk {
+    pub fn new(disk: u64, dev: Arc<dyn BlockDevice + Send + Sync>) -> CachedDisk {
+	CachedDisk {
+	    disk,
+	    dev,
+	}
+    }
+
+    fn cached(&self, offset: u64, size: u64) -> Option<Bytes> {
+	let mut cache = BLOCK_CACHE.lock();
+	if size == 1 {
+	    return cache.get((self.disk, offset));
+	}
+
+	let mut data = BytesMut::with_capacity((size * 512) as usize);
+	for lba in offset .. offset + size {
+	    data.extend_from_slice(&cache.get((self.disk, lba))?);
+	}
+
+	Some(data.freeze())
+    }
+}
+
+impl BlockDevice for CachedDisk {
+    fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	Box::pin(async move {
+	    if size > MAX_CACHED_READ_SECTORS {
+		return self.dev.clone().read(offset, size).await;
+	    }
+
+	    if let Some(data) = self.cached(offset, size) {
+		return Ok(data);
+	    }
+
+	    let generation = BLOCK_CACHE.lock().generation;
+	    let data = self.dev.clone().read(offset, size).await?;
+
+	    let mut cache = BLOCK_CACHE.lock();
+	    if cache.generation == generation && data.len() as u64 == size * 512 {
+		// Each sector gets its own copy, rather than a slice of what was read, so that evicting one gives its memory
+		// back straight away instead of once its neighbours have gone too
+		for i in 0 .. size {
+		    let sector = &data[(i * SECTOR_SIZE) as usize .. ((i + 1) * SECTOR_SIZE) as usize];
+		    cache.insert((self.disk, offset + i), Bytes::copy_from_slice(sector));
+		}
+	    }
+
+	    Ok(data)
+	})
+    }
+
+    // Writes go straight through, so the disk always has what the cache would have given
+    fn read_to_frames(self: Arc<Self>, offset: u64, size: u64, frames: Vec<PhysAddr>, frame_offset: u64) -> Option<BoxFuture<'static, Result<(), syscall::CanonicalError>>> {
+	self.dev.clone().read_to_frames(offset, size, frames, frame_offset)
+    }
+
+    fn write(self: Arc<Self>, offset: u64, data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	Box::pin(async move {
+	    let count = data.len() as u64 / 512;
+	    let result = self.dev.clone().write(offset, data).await;
+
+	    // Even a failed write may have changed some of the sectors
+	    BLOCK_CACHE.lock().invalidate(self.disk, offset, count);
+	    result
+	})
+    }
+
+    // Nothing is held back here, as writes go straight through, so only the disk's own cache needs flushing
+    fn flush(self: Arc<Self>) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	self.dev.clone().flush()
+    }
+
+    fn size_in_sectors(&self) -> u64 {
+	self.dev.size_in_sectors()
+    }
+
+    fn model(&self) -> String {
+	self.dev.model()
+    }
+}
+
+#[test]
+fn least_recently_used_sectors_go_first() {
+    let mut cache = BlockCache::new();
+    let sector = |n: u8| Bytes::from(alloc::vec![n; 512]);
+
+    for lba in 0 .. BLOCK_CACHE_SECTORS as u64 {
+	cache.insert((0, lba), sector(lba as u8));
+    }
+    // Using the oldest makes the next one along the first to go
+    assert!(cache.get((0, 0)).is_some());
+    cache.insert((1, 0), sector(0xFF));
+
+    assert_eq!(cache.sectors.len(), BLOCK_CACHE_SECTORS);
+    assert!(cache.get((0, 1)).is_none());
+    assert_eq!(cache.get((0, 0)).unwrap(), sector(0));
+    assert_eq!(cache.get((1, 0)).unwrap(), sector(0xFF));
+
+    let generation = cache.generation;
+    cache.invalidate(0, 0, 3);
+    assert!(cache.get((0, 0)).is_none() && cache.get((0, 2)).is_none());
+    assert!(cache.get((0, 3)).is_some());
+    assert!(cache.generation > generation);
+
+    assert_eq!(cache.evict(2), 2);
+    assert_eq!(cache.sectors.len(), cache.lru.len());
+}
+
+#[test]
+fn cached_disk_reads_once_until_written() {
+    struct Disk {
+	reads: core::sync::atomic::AtomicU64,
+    }
+
+    impl BlockDevice for Disk {
+	fn read(self: Arc<Self>, offset: u64, size: u64) -> BoxFuture<'static, Result<Bytes, syscall::CanonicalError>> {
+	    self.reads.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+	    Box::pin(async move { Ok(Bytes::from(alloc::vec![offset as u8; (size * 512) as usize])) })
+	}
+
+	fn write(self: Arc<Self>, _offset: u64, _data: Bytes) -> BoxFuture<'static, Result<(), syscall::CanonicalError>> {
+	    Box::pin(async move { Ok(()) })
+	}
+
+	fn size_in_sectors(&self) -> u64 {
+	    1024
+	}
+
+	fn model(&self) -> String {
+	    String::from("test")
+	}
+    }
+
+    use futures_util::FutureExt;
+    let dev = Arc::new(Disk { reads: core::sync::atomic::AtomicU64::new(0) });
+    // A disk number nothing else uses, as the cache is shared
+    let disk = Arc::new(CachedDisk::new(u64::MAX, dev.clone()));
+    let reads = || dev.reads.load(core::sync::atomic::Ordering::SeqCst);
+
+    let data = disk.clone().read(10, 4).now_or_never().unwrap().unwrap();
+    assert_eq!(data.len(), 4 * 512);
+    assert_eq!(reads(), 1);
+
+    // All of it, and part of it, come from the cache
+    assert_eq!(disk.clone().read(10, 4).now_or_never().unwrap().unwrap(), data);
+    assert_eq!(disk.clone().read(11, 2).now_or_never().unwrap().unwrap(), data.slice(512 .. 3 * 512));
+    assert_eq!(reads(), 1);
+
+    // Writing over a sector means it's read again
+    disk.clone().write(12, Bytes::from(alloc::vec![0; 512])).now_or_never().unwrap().unwrap();
+    disk.clone().read(10, 4).now_or_never().unwrap().unwrap();
+    assert_eq!(reads(), 2);
+
+    // Big reads always go to the disk
+    disk.clone().read(100, MAX_CACHED_READ_SECTORS + 1).now_or_never().unwrap().unwrap();
+    disk.clone().read(100, MAX_CACHED_READ_SECTORS + 1).now_or_never().unwrap().unwrap();
+    assert_eq!(reads(), 4);
+}
+
+#[test]
+fn low_memory_evicts_the_oldest_sectors_and_counts_their_bytes() {
+    let mut cache = BlockCache::new();
+    for lba in 0 .. 100 {
+	cache.insert((0, lba), Bytes::copy_from_slice(&[lba as u8; SECTOR_SIZE as usize]));
+    }
+    assert!(cache.get((0, 0)).is_some());
+
+    // A part of a sector still costs the whole of one
+    assert_eq!(cache.shrink(10 * SECTOR_SIZE + 1), 11 * SECTOR_SIZE);
+    assert_eq!(cache.sectors.len(), 89);
+    assert!(cache.get((0, 0)).is_some());
+    assert!(cache.get((0, 11)).is_none());
+    assert!(cache.get((0, 12)).is_some());
+
+    // Asking for more than there is empties the cache, and only counts what was there
+    assert_eq!(cache.shrink(u64::MAX / 2), 89 * SECTOR_SIZE);
+    assert!(cache.sectors.is_empty() && cache.lru.is_empty());
+}
@@ -1,11 +1,8 @@
-use num_enum::TryFromPrimitive;
-
-#[repr(u64)]
-#[derive(Debug, TryFromPrimitive)]
-pub enum IoCtl {
-    TCGETS = 0x5401,
-    TCSETS = 0x5402,
-    TIOCGPGRP = 0x540F,
-    TIOCSPGRP = 0x5410,
-    TIOCGWINSZ = 0x5413,
-}
+// Requests are passed through to the device as they come from userspace, and each device decodes the ones it knows
+// about. These are the terminal ones, as Linux numbers them; drivers with their own define them alongside the driver.
+pub const TCGETS: u64 = 0x5401;
+pub const TCSETS: u64 = 0x5402;
+pub const TIOCGPGRP: u64 = 0x540F;
+pub const TIOCSPGRP: u64 = 0x5410;
+pub const TIOCGWINSZ: u64 = 0x5413;
+pub const TIOCSWINSZ: u64 = 0x5414;
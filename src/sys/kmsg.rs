@@ -0,0 +1,509 @@
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::format;
+use core::fmt;
+use core::fmt::Write;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::driver;
+use crate::drivers::hpet;
+use crate::scheduler;
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::vfs;
+
+// Enough for the whole of a normal boot, so dmesg can show it once userspace is up
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+// Longer messages are truncated. Records are formatted on the stack, as we log long before there's a heap
+const MAX_MESSAGE_LEN: usize = 1024;
+// Priority, timestamp in microseconds, and message length
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 2;
+
+// Syslog levels, which are the bottom three bits of a priority. The rest is the facility
+const LOG_ERR: u8 = 3;
+const LOG_WARNING: u8 = 4;
+const LOG_INFO: u8 = 6;
+const LOG_DEBUG: u8 = 7;
+const LOG_LEVEL_MASK: u8 = 0x07;
+const LOG_FACILITY_SHIFT: u8 = 3;
+const LOG_USER: u8 = 1 << LOG_FACILITY_SHIFT;
+// What Linux gives messages written to /dev/kmsg without a <level> prefix
+const DEFAULT_USER_LEVEL: u8 = LOG_WARNING;
+
+// Messages written to /dev/kmsg are passed on to the logger with this target, so it knows they're already recorded
+pub const USER_MESSAGE_TARGET: &str = "kmsg";
+
+struct Record {
+    priority: u8,
+    seq: u64,
+    timestamp_us: u64,
+    message: Vec<u8>,
+}
+
+// Records are packed end to end in a circular buffer, and the oldest are dropped to make room for new ones. Sequence
+// numbers are never reused, so readers can tell when what they were going to read next has been dropped
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    // Offset of the oldest record, and how many bytes of records follow it
+    head: usize,
+    used: usize,
+    first_seq: u64,
+    next_seq: u64,
+    // Used when the HPET is busy or not up yet, so that timestamps never go backwards
+    last_timestamp_us: u64,
+    waiters: Vec<Waker>,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+	LogBuffer {
+	    data: [0; LOG_BUFFER_SIZE],
+	    head: 0,
+	    used: 0,
+	    first_seq: 0,
+	    next_seq: 0,
+	    last_timestamp_us: 0,
+	    waiters: Vec::new(),
+	}
+    }
+
+    fn read_bytes(&self, offset: usize, out: &mut [u8]) {
+	for (i, b) in out.iter_mut().enumerate() {
+	    *b = self.data[(offset + i) % LOG_BUFFER_SIZE];
+	}
+    }
+
+    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+	for (i, b) in bytes.iter().enumerate() {
+	    self.data[(offset + i) % LOG_BUFFER_SIZE] = *b;
+	}
+    }
+
+    fn record_size(&self, offset: usize) -> usize {
+	let mut len = [0u8; 2];
+	self.read_bytes(offset + 9, &mut len);
+	RECORD_HEADER_SIZE + u16::from_le_bytes(len) as usize
+    }
+
+    fn push(&mut self, priority: u8, timestamp_us: u64, message: &[u8]) {
+	let size = RECORD_HEADER_SIZE + message.len();
+	while self.used + size > LOG_BUFFER_SIZE {
+	    let oldest_size = self.record_size(self.head);
+	    self.head = (self.head + oldest_size) % LOG_BUFFER_SIZE;
+	    self.used -= oldest_size;
+	    self.first_seq += 1;
+	}
+
+	let offset = (self.head + self.used) % LOG_BUFFER_SIZE;
+	self.write_bytes(offset, &[priority]);
+	self.write_bytes(offset + 1, &timestamp_us.to_le_bytes());
+	self.write_bytes(offset + 9, &(message.len() as u16).to_le_bytes());
+	self.write_bytes(offset + RECORD_HEADER_SIZE, message);
+
+	self.used += size;
+	self.next_seq += 1;
+    }
+
+    fn get(&self, seq: u64) -> Option<Record> {
+	if seq < self.first_seq || seq >= self.next_seq {
+	    return None;
+	}
+
+	let mut offset = self.head;
+	for _ in self.first_seq .. seq {
+	    offset = (offset + self.record_size(offset)) % LOG_BUFFER_SIZE;
+	}
+
+	let mut priority = [0u8; 1];
+	let mut timestamp_us = [0u8; 8];
+	let mut message = Vec::new();
+	message.resize(self.record_size(offset) - RECORD_HEADER_SIZE, 0);
+	self.read_bytes(offset, &mut priority);
+	self.read_bytes(offset + 1, &mut timestamp_us);
+	self.read_bytes(offset + RECORD_HEADER_SIZE, &mut message);
+
+	Some(Record {
+	    priority: priority[0],
+	    seq,
+	    timestamp_us: u64::from_le_bytes(timestamp_us),
+	    message,
+	})
+    }
+
+    // Every record still held, oldest first, in one pass over the buffer
+    fn records(&self) -> Vec<Record> {
+	let mut records = Vec::new();
+	let mut offset = self.head;
+	for seq in self.first_seq .. self.next_seq {
+	    let mut priority = [0u8; 1];
+	    let mut timestamp_us = [0u8; 8];
+	    let mut message = Vec::new();
+	    message.resize(self.record_size(offset) - RECORD_HEADER_SIZE, 0);
+	    self.read_bytes(offset, &mut priority);
+	    self.read_bytes(offset + 1, &mut timestamp_us);
+	    self.read_bytes(offset + RECORD_HEADER_SIZE, &mut message);
+
+	    records.push(Record {
+		priority: priority[0],
+		seq,
+		timestamp_us: u64::from_le_bytes(timestamp_us),
+		message,
+	    });
+	    offset = (offset + self.record_size(offset)) % LOG_BUFFER_SIZE;
+	}
+
+	records
+    }
+}
+
+// Only ever locked with interrupts disabled, as we log from interrupt handlers too
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer::new());
+
+// Formats a message without allocating, dropping whatever doesn't fit
+struct MessageWriter {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for MessageWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	let n = core::cmp::min(s.len(), MAX_MESSAGE_LEN - self.len);
+	self.buf[self.len .. self.len + n].copy_from_slice(&s.as_bytes()[.. n]);
+	self.len += n;
+	Ok(())
+    }
+}
+
+pub fn priority_for_level(level: log::Level) -> u8 {
+    match level {
+	log::Level::Error => LOG_ERR,
+	log::Level::Warn => LOG_WARNING,
+	log::Level::Info => LOG_INFO,
+	log::Level::Debug | log::Level::Trace => LOG_DEBUG,
+    }
+}
+
+fn level_for_priority(priority: u8) -> log::Level {
+    match priority & LOG_LEVEL_MASK {
+	0 ..= LOG_ERR => log::Level::Error,
+	LOG_WARNING => log::Level::Warn,
+	LOG_DEBUG => log::Level::Debug,
+	_ => log::Level::Info,
+    }
+}
+
+// Adds a message to the kernel log buffer. This doesn't print it anywhere; the logger does that
+pub fn record(priority: u8, args: fmt::Arguments) {
+    let mut writer = MessageWriter {
+	buf: [0; MAX_MESSAGE_LEN],
+	len: 0,
+    };
+    let _ = writer.write_fmt(args);
+    let message = writer.buf[.. writer.len].trim_ascii_end();
+
+    let waiters = without_interrupts(|| {
+	let mut log_buffer = LOG_BUFFER.lock();
+	let timestamp_us = match hpet::try_now_ns() {
+	    Some(ns) => core::cmp::max(ns / 1000, log_buffer.last_timestamp_us),
+	    None => log_buffer.last_timestamp_us,
+	};
+	log_buffer.last_timestamp_us = timestamp_us;
+	log_buffer.push(priority, timestamp_us, message);
+	core::mem::take(&mut log_buffer.waiters)
+    });
+
+    for waker in waiters {
+	scheduler::defer_wake(waker);
+    }
+}
+
+// The sequence numbers of the oldest record still held, and of the next one to be logged
+fn get_seq_range() -> (u64, u64) {
+    without_interrupts(|| {
+	let log_buffer = LOG_BUFFER.lock();
+	(log_buffer.first_seq, log_buffer.next_seq)
+    })
+}
+
+// How many bytes of records the log buffer holds, for syslog(2)'s SYSLOG_ACTION_SIZE_BUFFER
+pub fn buffer_size() -> u64 {
+    LOG_BUFFER_SIZE as u64
+}
+
+// The log as syslog(2)'s SYSLOG_ACTION_READ_ALL gives it, for dmesg implementations which read it that way rather
+// than through /dev/kmsg: a "<priority>[seconds.micros] message" line per record. As on Linux, only the newest lines
+// which fit in len bytes are given, and reading doesn't consume anything
+pub fn read_all(len: usize) -> Vec<u8> {
+    let records = without_interrupts(|| LOG_BUFFER.lock().records());
+
+    let mut lines = Vec::new();
+    let mut total = 0;
+    for record in records.iter().rev() {
+	let line = format!("<{}>[{:5}.{:06}] {}\n", record.priority, record.timestamp_us / 1_000_000,
+			   record.timestamp_us % 1_000_000, String::from_utf8_lossy(&record.message));
+	if total + line.len() > len {
+	    break;
+	}
+	total += line.len();
+	lines.push(line);
+    }
+
+    lines.iter().rev().flat_map(|line| line.bytes()).collect()
+}
+
+// Splits a message written to /dev/kmsg into its priority and text. Userspace can't log as the kernel, so a priority
+// without a facility is given the user facility, as Linux does
+fn parse_user_message(buf: &[u8]) -> (u8, &[u8]) {
+    let default = (LOG_USER | DEFAULT_USER_LEVEL, buf);
+    if buf.first() != Some(&b'<') {
+	return default;
+    }
+
+    let end = match buf.iter().position(|b| *b == b'>') {
+	Some(end) if end > 1 => end,
+	_ => return default,
+    };
+
+    let priority = match core::str::from_utf8(&buf[1 .. end]).ok().and_then(|s| s.parse::<u8>().ok()) {
+	Some(priority) => priority,
+	None => return default,
+    };
+
+    let priority = if priority >> LOG_FACILITY_SHIFT == 0 { priority | LOG_USER } else { priority };
+    (priority, &buf[end + 1 ..])
+}
+
+// Records a message from userspace at the priority it gave, then hands it to the logger at the matching level
+fn log_user_message(buf: &[u8]) {
+    let (priority, message) = parse_user_message(buf);
+    let message = String::from_utf8_lossy(message);
+    let message = message.trim_end();
+
+    record(priority, format_args!("{}", message));
+    log::logger().log(&log::Record::builder()
+		      .level(level_for_priority(priority))
+		      .target(USER_MESSAGE_TARGET)
+		      .args(format_args!("{}", message))
+		      .build());
+}
+
+// Both the node and its handles, which don't keep hold of the node
+fn device_stat() -> vfs::filesystem::Stat {
+    vfs::filesystem::Stat {
+	file_name: String::from("kmsg"),
+	size: None,
+	inode: 0,
+	kind: vfs::filesystem::VNodeKind::CharDevice,
+	mode: 0o644,
+	modified: None,
+    }
+}
+
+struct KmsgDevice {
+    fsi: Mutex<vfs::filesystem::FileSystemInstance>,
+}
+
+impl vfs::filesystem::VNode for KmsgDevice {
+    fn inode(&self) -> u64 {
+	0
+    }
+
+    fn kind(&self) -> vfs::filesystem::VNodeKind {
+	vfs::filesystem::VNodeKind::CharDevice
+    }
+
+    fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    // Each open reads the log from its own position, starting with the oldest record still held
+    fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
+	let (first_seq, _) = get_seq_range();
+	Ok(Arc::new(KmsgHandle {
+	    seq: AtomicU64::new(first_seq),
+	}))
+    }
+
+    fn filesystem(&self) -> Arc<dyn vfs::filesystem::FileSystem> {
+	driver::get_devfs()
+    }
+
+    fn fsi(&self) -> vfs::filesystem::FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn vfs::filesystem::VNode>, CanonicalError> {
+	unimplemented!();
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+struct KmsgHandle {
+    // Sequence number of the next record this handle will read
+    seq: AtomicU64,
+}
+
+impl vfs::filesystem::FileHandle for KmsgHandle {
+    // Each read returns one record, in the same format as Linux. If the record this handle was up to has been dropped,
+    // the read fails with EPIPE and the next one carries on from the oldest record still held
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	Box::pin(poll_fn(move |cx| {
+	    let record = without_interrupts(|| {
+		let mut log_buffer = LOG_BUFFER.lock();
+		let seq = self.seq.load(Ordering::SeqCst);
+		if seq < log_buffer.first_seq {
+		    self.seq.store(log_buffer.first_seq, Ordering::SeqCst);
+		    return Err(CanonicalError::Pipe);
+		}
+
+		match log_buffer.get(seq) {
+		    Some(record) => Ok(Some(record)),
+		    None => {
+			log_buffer.waiters.push(cx.waker().clone());
+			Ok(None)
+		    },
+		}
+	    });
+
+	    let record = match record {
+		Ok(Some(record)) => record,
+		Ok(None) => return Poll::Pending,
+		Err(e) => return Poll::Ready(Err(e)),
+	    };
+
+	    let line = format!("{},{},{},-;{}\n", record.priority, record.seq, record.timestamp_us, String::from_utf8_lossy(&record.message));
+	    if line.len() as u64 > len {
+		return Poll::Ready(Err(CanonicalError::Inval));
+	    }
+
+	    self.seq.store(record.seq + 1, Ordering::SeqCst);
+	    Poll::Ready(Ok(bytes::Bytes::from(line.into_bytes())))
+	}))
+    }
+
+    // Each write is a single message, whatever newlines it has in it
+    fn write(self: Arc<Self>, buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    log_user_message(&buf);
+	    Ok(buf.len() as u64)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	Box::pin(poll_fn(move |cx| {
+	    if !events.contains(PollEvents::In) {
+		return Poll::Ready(Ok(events & PollEvents::Out));
+	    }
+
+	    without_interrupts(|| {
+		let mut log_buffer = LOG_BUFFER.lock();
+		if self.seq.load(Ordering::SeqCst) < log_buffer.next_seq {
+		    Poll::Ready(Ok(events & (PollEvents::In | PollEvents::Out)))
+		} else if events.contains(PollEvents::Out) {
+		    Poll::Ready(Ok(PollEvents::Out))
+		} else {
+		    log_buffer.waiters.push(cx.waker().clone());
+		    Poll::Pending
+		}
+	    })
+	}))
+    }
+
+    fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    // Seeking to the start goes back to the oldest record held, and to the end skips to whatever is logged next
+    fn seek(&self, offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
+	let (first_seq, next_seq) = get_seq_range();
+	match offset {
+	    vfs::filesystem::SeekFrom::Set(0) => self.seq.store(first_seq, Ordering::SeqCst),
+	    vfs::filesystem::SeekFrom::End(0) => self.seq.store(next_seq, Ordering::SeqCst),
+	    _ => return Err(CanonicalError::Inval),
+	}
+
+	Ok(0)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+// Messages are recorded from the first thing we log, but can only be read once devfs is up
+pub fn init() {
+    driver::register_devfs(String::from("kmsg"), Arc::new(KmsgDevice {
+	fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
+    }));
+}
+
+#[test]
+fn user_messages_get_the_user_facility() {
+    assert_eq!(parse_user_message(b"hello"), (LOG_USER | LOG_WARNING, &b"hello"[..]));
+    assert_eq!(parse_user_message(b"<3>failed"), (LOG_USER | LOG_ERR, &b"failed"[..]));
+    // A facility given by userspace is kept
+    assert_eq!(parse_user_message(b"<30>daemon"), (30, &b"daemon"[..]));
+
+    // Anything that isn't a priority is left in the message
+    assert_eq!(parse_user_message(b"<>empty"), (LOG_USER | LOG_WARNING, &b"<>empty"[..]));
+    assert_eq!(parse_user_message(b"<x>text"), (LOG_USER | LOG_WARNING, &b"<x>text"[..]));
+    assert_eq!(parse_user_message(b"<6 unclosed"), (LOG_USER | LOG_WARNING, &b"<6 unclosed"[..]));
+    assert_eq!(parse_user_message(b"<999>big"), (LOG_USER | LOG_WARNING, &b"<999>big"[..]));
+}
+
+#[test]
+fn log_buffer_drops_the_oldest_records() {
+    let mut log_buffer = Box::new(LogBuffer::new());
+    let message = [b'x'; 1000];
+    let per_buffer = (LOG_BUFFER_SIZE / (RECORD_HEADER_SIZE + message.len())) as u64;
+
+    log_buffer.push(LOG_INFO, 5, b"first");
+    let record = log_buffer.get(0).unwrap();
+    assert_eq!((record.priority, record.seq, record.timestamp_us), (LOG_INFO, 0, 5));
+    assert_eq!(record.message, b"first");
+    assert!(log_buffer.get(1).is_none());
+
+    // Enough to go round the buffer more than once, so records end up split across its end
+    for i in 0 .. 3 * per_buffer {
+	log_buffer.push(LOG_DEBUG, i, &message);
+    }
+
+    assert_eq!(log_buffer.next_seq, 3 * per_buffer + 1);
+    assert_eq!(log_buffer.next_seq - log_buffer.first_seq, per_buffer);
+    assert!(log_buffer.get(0).is_none());
+    assert!(log_buffer.get(log_buffer.first_seq - 1).is_none());
+
+    for seq in log_buffer.first_seq .. log_buffer.next_seq {
+	let record = log_buffer.get(seq).unwrap();
+	assert_eq!((record.priority, record.seq, record.timestamp_us), (LOG_DEBUG, seq, seq - 1));
+	assert_eq!(record.message, message);
+    }
+}
@@ -0,0 +1,172 @@
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::format;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::Mutex;
+
+use crate::driver;
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::vfs;
+
+// A level by name, or one of Linux's numbers, 0 (emergencies only) to 7 (debug)
+pub fn parse_level(level: &str) -> Option<log::LevelFilter> {
+    match level {
+	"off" => Some(log::LevelFilter::Off),
+	"error" | "0" | "1" | "2" | "3" => Some(log::LevelFilter::Error),
+	"warn" | "4" => Some(log::LevelFilter::Warn),
+	"info" | "5" | "6" => Some(log::LevelFilter::Info),
+	"debug" | "7" => Some(log::LevelFilter::Debug),
+	"trace" => Some(log::LevelFilter::Trace),
+	_ => None,
+    }
+}
+
+fn level_name(level: log::LevelFilter) -> &'static str {
+    match level {
+	log::LevelFilter::Off => "off",
+	log::LevelFilter::Error => "error",
+	log::LevelFilter::Warn => "warn",
+	log::LevelFilter::Info => "info",
+	log::LevelFilter::Debug => "debug",
+	log::LevelFilter::Trace => "trace",
+    }
+}
+
+// Owned by root, so only it may change the level, though anyone may read it
+fn device_stat() -> vfs::filesystem::Stat {
+    vfs::filesystem::Stat {
+	file_name: String::from("loglevel"),
+	size: None,
+	inode: 0,
+	kind: vfs::filesystem::VNodeKind::CharDevice,
+	mode: 0o644,
+	modified: None,
+    }
+}
+
+struct LogLevelDevice {
+    fsi: Mutex<vfs::filesystem::FileSystemInstance>,
+}
+
+impl vfs::filesystem::VNode for LogLevelDevice {
+    fn inode(&self) -> u64 {
+	0
+    }
+
+    fn kind(&self) -> vfs::filesystem::VNodeKind {
+	vfs::filesystem::VNodeKind::CharDevice
+    }
+
+    fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
+	Ok(Arc::new(LogLevelHandle {
+	    read: AtomicBool::new(false),
+	}))
+    }
+
+    fn filesystem(&self) -> Arc<dyn vfs::filesystem::FileSystem> {
+	driver::get_devfs()
+    }
+
+    fn fsi(&self) -> vfs::filesystem::FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn vfs::filesystem::VNode>, CanonicalError> {
+	unimplemented!();
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+struct LogLevelHandle {
+    // The level is read all at once, and after that, reads hit the end of the file until seeking back to the start
+    read: AtomicBool,
+}
+
+impl vfs::filesystem::FileHandle for LogLevelHandle {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    if self.read.swap(true, Ordering::SeqCst) {
+		return Ok(bytes::Bytes::new());
+	    }
+
+	    let line = format!("{}\n", level_name(log::max_level()));
+	    if line.len() as u64 > len {
+		self.read.store(false, Ordering::SeqCst);
+		return Err(CanonicalError::Inval);
+	    }
+
+	    Ok(bytes::Bytes::from(line.into_bytes()))
+	}.boxed()
+    }
+
+    // Takes a level as parse_level does, e.g. "echo warn > /dev/loglevel"
+    fn write(self: Arc<Self>, buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    let level = core::str::from_utf8(&buf).map_err(|_| CanonicalError::Inval)?.trim();
+	    let filter = parse_level(level).ok_or(CanonicalError::Inval)?;
+
+	    log::info!("Log level set to {}", level_name(filter));
+	    log::set_max_level(filter);
+	    Ok(buf.len() as u64)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
+	match offset {
+	    vfs::filesystem::SeekFrom::Set(0) => self.read.store(false, Ordering::SeqCst),
+	    _ => return Err(CanonicalError::Inval),
+	}
+
+	Ok(0)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+// Reading gives the level the kernel logs at, and writing a level changes it
+pub fn init() {
+    driver::register_devfs(String::from("loglevel"), Arc::new(LogLevelDevice {
+	fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
+    }));
+}
@@ -2,10 +2,16 @@ use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
 
 pub mod acpi;
 pub mod block;
+pub mod block_cache;
 
 #[macro_use]
 pub mod syscall;
 pub mod ioctl;
+pub mod kmsg;
+pub mod loglevel;
+pub mod power;
+pub mod random;
+pub mod time;
 
 // CPU init
 pub fn init() {
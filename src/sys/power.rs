@@ -0,0 +1,156 @@
+use core::future::Future;
+use x86_64::instructions::hlt;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::driver;
+use crate::interrupts;
+use crate::scheduler;
+use crate::scheduler::executor;
+use crate::scheduler::signal;
+use crate::sys::acpi;
+use crate::sys::syscall::CanonicalError;
+use crate::sys::time;
+use crate::utils::completion::Completion;
+use crate::vfs;
+
+// How long processes are given to exit after SIGTERM, before they're killed
+const SHUTDOWN_GRACE_PERIOD_MS: u64 = 2000;
+
+// The reset control register most chipsets have, for when there's no ACPI reset register. Setting the reset bit only
+// resets once the CPU reset bit rises from 0 to 1, hence two writes.
+const RESET_CONTROL_PORT: u16 = 0xCF9;
+const RESET_CONTROL_SYS_RST: u8 = 0x02;
+const RESET_CONTROL_RST_CPU: u8 = 0x04;
+
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_COMMAND_PULSE_RESET: u8 = 0xFE;
+
+// Completed from the SCI handler, which can't do the shutting down itself
+static POWER_BUTTON_PRESSED: Completion<()> = Completion::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerAction {
+    Halt,
+    PowerOff,
+    Reboot,
+}
+
+// Brings the system down in order. Processes are asked to exit, then killed, and filesystems are synced. The other
+// CPUs and the devices are then stopped, so that nothing is still running or DMAing into memory, before the firmware
+// is asked to power off or reset. caller_pid is left running, as it's the one doing the shutting down.
+pub async fn shutdown(action: PowerAction, caller_pid: u64) {
+    log::info!("Shutting down ({:?})", action);
+
+    scheduler::signal_user_processes(signal::SIGTERM, caller_pid);
+    time::sleep_ms(SHUTDOWN_GRACE_PERIOD_MS).await;
+    scheduler::signal_user_processes(signal::SIGKILL, caller_pid);
+
+    sync_then_stop(vfs::sync(), || {
+	interrupts::stop_other_cpus();
+	driver::shutdown_devices();
+    }).await;
+
+    match action {
+	PowerAction::Halt => halt(),
+	PowerAction::PowerOff => power_off(),
+	PowerAction::Reboot => reboot(),
+    }
+}
+
+// Filesystems may still be holding on to what the processes wrote, so are synced before the devices under them are
+// stopped, which is done even if the sync failed. Nothing else may be using the devices as they're stopped.
+async fn sync_then_stop(sync: impl Future<Output = Result<(), CanonicalError>>, stop: impl FnOnce()) {
+    if let Err(e) = sync.await {
+	log::warn!("Unable to sync filesystems: {:?}", e);
+    }
+
+    without_interrupts(stop);
+}
+
+// Powers off when the power button is pressed. Processes get SIGTERM and the usual grace period, and anything still
+// running after that is killed, so a hung init can't keep the machine up.
+pub fn init_power_button() {
+    if let Err(e) = acpi::install_power_button_handler(|| POWER_BUTTON_PRESSED.complete(())) {
+	log::warn!("Unable to handle the power button: {:?}", e);
+	return;
+    }
+
+    executor::spawn(async {
+	POWER_BUTTON_PRESSED.wait().await;
+	log::info!("Power button pressed");
+	// No process asked for this, so none is spared
+	shutdown(PowerAction::PowerOff, 0).await;
+    }).expect("Unable to start watching the power button");
+}
+
+fn halt() -> ! {
+    log::info!("System halted");
+    x86_64::instructions::interrupts::disable();
+    loop {
+	hlt();
+    }
+}
+
+fn power_off() -> ! {
+    // uACPI would like interrupts enabled while preparing to sleep, but we're running from the scheduler, where they
+    // can't be
+    if let Err(e) = acpi::power_off() {
+	log::error!("ACPI power off failed: {:?}", e);
+    }
+
+    halt();
+}
+
+// Interrupts are off by now, so there's no sleeping
+fn spin_for_us(us: u64) {
+    let deadline = time::get_monotonic_ns() + us * 1000;
+    while time::get_monotonic_ns() < deadline {
+	core::hint::spin_loop();
+    }
+}
+
+fn reboot() -> ! {
+    if let Err(e) = acpi::reboot() {
+	log::warn!("ACPI reset failed: {:?}, trying the reset control register", e);
+    }
+
+    unsafe {
+	let mut reset_control = Port::<u8>::new(RESET_CONTROL_PORT);
+	reset_control.write(RESET_CONTROL_SYS_RST);
+	spin_for_us(50);
+	reset_control.write(RESET_CONTROL_SYS_RST | RESET_CONTROL_RST_CPU);
+    }
+    spin_for_us(50_000);
+    log::warn!("Reset control register didn't reset, trying the keyboard controller");
+
+    // Not every machine has a reset register, but the 8042 reset line is near universal
+    unsafe {
+	let mut kbc_command = Port::<u8>::new(KBC_COMMAND_PORT);
+	kbc_command.write(KBC_COMMAND_PULSE_RESET);
+    }
+
+    halt();
+}
+
+#[test]
+fn filesystems_are_synced_before_devices_are_stopped() {
+    use futures_util::FutureExt;
+
+    let order = spin::Mutex::new(alloc::vec::Vec::new());
+    let sync = async {
+	order.lock().push("sync");
+	Ok(())
+    };
+    sync_then_stop(sync, || order.lock().push("stop")).now_or_never().unwrap();
+    assert_eq!(*order.lock(), ["sync", "stop"]);
+
+    // Devices are stopped all the same if a filesystem couldn't be synced
+    order.lock().clear();
+    let sync = async {
+	order.lock().push("sync");
+	Err(CanonicalError::Io)
+    };
+    sync_then_stop(sync, || order.lock().push("stop")).now_or_never().unwrap();
+    assert_eq!(*order.lock(), ["sync", "stop"]);
+}
@@ -0,0 +1,319 @@
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::x86_64::{_rdrand64_step, _rdseed64_step, _rdtsc};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use raw_cpuid::CpuId;
+use spin::{Mutex, Once};
+
+use crate::driver;
+use crate::drivers::hpet;
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::vfs;
+
+// The kernel's randomness, for getrandom, /dev/urandom and AT_RANDOM. It's a ChaCha20 keystream, rekeyed after every
+// use so that what's been handed out can't be worked back from the state, and keyed from RDSEED or RDRAND, if the CPU
+// has them, and the jitter between the TSC and the HPET. None of this has been cryptographically audited. It's only
+// meant to be a good deal better than nothing.
+
+// More than this at once is handed back short, as Linux does for large reads
+pub const MAX_READ: u64 = 1024 * 1024;
+
+// How many times the TSC is read against the HPET when seeding
+const JITTER_SAMPLES: usize = 256;
+// RDRAND and RDSEED can fail when they've been drained, and are meant to be retried
+const HARDWARE_RETRIES: usize = 16;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+struct EntropyPool {
+    key: [u32; 8],
+    counter: u64,
+}
+
+impl EntropyPool {
+    const fn new() -> Self {
+	EntropyPool {
+	    key: [0; 8],
+	    counter: 0,
+	}
+    }
+
+    fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(16);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(12);
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(8);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(7);
+    }
+
+    // The ChaCha20 block function, on the whole of its input state
+    fn chacha20(input: &[u32; 16]) -> [u8; 64] {
+	let mut state = *input;
+	for _ in 0 .. 10 {
+	    Self::quarter_round(&mut state, 0, 4, 8, 12);
+	    Self::quarter_round(&mut state, 1, 5, 9, 13);
+	    Self::quarter_round(&mut state, 2, 6, 10, 14);
+	    Self::quarter_round(&mut state, 3, 7, 11, 15);
+	    Self::quarter_round(&mut state, 0, 5, 10, 15);
+	    Self::quarter_round(&mut state, 1, 6, 11, 12);
+	    Self::quarter_round(&mut state, 2, 7, 8, 13);
+	    Self::quarter_round(&mut state, 3, 4, 9, 14);
+	}
+
+	let mut out = [0; 64];
+	for (i, chunk) in out.chunks_mut(4).enumerate() {
+	    chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+	}
+	out
+    }
+
+    // The next block of keystream. The counter takes up what would be the nonce's first word as well, as in the
+    // original ChaCha, and the rest of the nonce is always zero
+    fn block(&mut self) -> [u8; 64] {
+	let mut input = [0; 16];
+	input[.. 4].copy_from_slice(&CHACHA_CONSTANTS);
+	input[4 .. 12].copy_from_slice(&self.key);
+	input[12] = self.counter as u32;
+	input[13] = (self.counter >> 32) as u32;
+	self.counter = self.counter.wrapping_add(1);
+
+	Self::chacha20(&input)
+    }
+
+    // Replaces the key with fresh keystream, which is never handed out
+    fn rekey(&mut self) {
+	let block = self.block();
+	for (word, bytes) in self.key.iter_mut().zip(block.chunks(4)) {
+	    *word = u32::from_le_bytes(bytes.try_into().unwrap());
+	}
+    }
+
+    // Folds a sample into the key. Rekeying straight after spreads it over the whole of it.
+    fn mix(&mut self, sample: u64) {
+	self.key[0] ^= sample as u32;
+	self.key[1] ^= (sample >> 32) as u32;
+	self.rekey();
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+	for chunk in buf.chunks_mut(64) {
+	    let block = self.block();
+	    chunk.copy_from_slice(&block[.. chunk.len()]);
+	}
+	self.rekey();
+    }
+}
+
+static POOL: Once<Mutex<EntropyPool>> = Once::new();
+
+fn hardware_random() -> Option<u64> {
+    let cpu_id = CpuId::new();
+    let has_rdseed = cpu_id.get_extended_feature_info().is_some_and(|f| f.has_rdseed());
+    let has_rdrand = cpu_id.get_feature_info().is_some_and(|f| f.has_rdrand());
+
+    let mut value = 0;
+    for _ in 0 .. HARDWARE_RETRIES {
+	unsafe {
+	    if has_rdseed && _rdseed64_step(&mut value) == 1 {
+		return Some(value);
+	    }
+	    if has_rdrand && _rdrand64_step(&mut value) == 1 {
+		return Some(value);
+	    }
+	}
+    }
+
+    None
+}
+
+// The TSC and the HPET run off different clocks, so how far the one gets while the other is read varies a little
+fn jitter_sample() -> u64 {
+    let before = unsafe { _rdtsc() };
+    let hpet_ns = hpet::now_ns();
+    let after = unsafe { _rdtsc() };
+
+    (after.wrapping_sub(before) << 32) ^ hpet_ns ^ after
+}
+
+// Seeded the first time it's needed, by which point the HPET is running
+fn pool() -> &'static Mutex<EntropyPool> {
+    POOL.call_once(|| {
+	let mut pool = EntropyPool::new();
+	let mut hardware = false;
+	for _ in 0 .. 4 {
+	    if let Some(value) = hardware_random() {
+		pool.mix(value);
+		hardware = true;
+	    }
+	}
+
+	for _ in 0 .. JITTER_SAMPLES {
+	    pool.mix(jitter_sample());
+	}
+
+	if !hardware {
+	    log::warn!("No RDSEED or RDRAND, so randomness is only seeded from timer jitter");
+	}
+	Mutex::new(pool)
+    })
+}
+
+// Each draw stirs in the TSC too, so that two draws are never made from quite the same state
+pub fn fill(buf: &mut [u8]) {
+    let mut pool = pool().lock();
+    pool.mix(unsafe { _rdtsc() });
+    pool.fill(buf);
+}
+
+// Anything written to /dev/urandom is stirred in, though it's never relied on
+fn add_entropy(data: &[u8]) {
+    let mut pool = pool().lock();
+    for chunk in data.chunks(8) {
+	let mut sample = [0; 8];
+	sample[.. chunk.len()].copy_from_slice(chunk);
+	pool.mix(u64::from_le_bytes(sample));
+    }
+}
+
+// Readable and writable by anyone, as on Linux, and the same for the node and its handles
+fn device_stat() -> vfs::filesystem::Stat {
+    vfs::filesystem::Stat {
+	file_name: String::from("urandom"),
+	size: None,
+	inode: 0,
+	kind: vfs::filesystem::VNodeKind::CharDevice,
+	mode: 0o666,
+	modified: None,
+    }
+}
+
+struct URandomDevice {
+    fsi: Mutex<vfs::filesystem::FileSystemInstance>,
+}
+
+impl vfs::filesystem::VNode for URandomDevice {
+    fn inode(&self) -> u64 {
+	0
+    }
+
+    fn kind(&self) -> vfs::filesystem::VNodeKind {
+	vfs::filesystem::VNodeKind::CharDevice
+    }
+
+    fn stat(&self) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    fn open(self: Arc<Self>/*, flags: OpenFlags */) -> Result<Arc<dyn vfs::filesystem::FileHandle>, CanonicalError> {
+	Ok(Arc::new(URandomHandle {}))
+    }
+
+    fn filesystem(&self) -> Arc<dyn vfs::filesystem::FileSystem> {
+	driver::get_devfs()
+    }
+
+    fn fsi(&self) -> vfs::filesystem::FileSystemInstance {
+	*self.fsi.lock()
+    }
+
+    fn parent(&self) -> Result<Arc<dyn vfs::filesystem::VNode>, CanonicalError> {
+	unimplemented!();
+    }
+
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn set_fsi(self: Arc<Self>, fsi: vfs::filesystem::FileSystemInstance) {
+	*(self.fsi.lock()) = fsi;
+    }
+}
+
+struct URandomHandle {}
+
+impl vfs::filesystem::FileHandle for URandomHandle {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<bytes::Bytes, CanonicalError>> {
+	async move {
+	    let mut buf = vec![0; len.min(MAX_READ) as usize];
+	    fill(&mut buf);
+	    Ok(bytes::Bytes::from(buf))
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    add_entropy(&buf);
+	    Ok(buf.len() as u64)
+	}.boxed()
+    }
+
+    // Never runs dry, so is always ready
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	async move {
+	    Ok(events & (PollEvents::In | PollEvents::Out))
+	}.boxed()
+    }
+
+    fn stat(self: Arc<Self>) -> Result<vfs::filesystem::Stat, CanonicalError> {
+	Ok(device_stat())
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    // There's no position to speak of
+    fn seek(&self, _offset: vfs::filesystem::SeekFrom) -> Result<u64, CanonicalError> {
+	Ok(0)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<vfs::filesystem::DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+}
+
+pub fn init() {
+    driver::register_devfs(String::from("urandom"), Arc::new(URandomDevice {
+	fsi: Mutex::new(vfs::filesystem::FileSystemInstance(0)),
+    }));
+}
+
+#[test]
+fn chacha20_matches_rfc_8439() {
+    let hex = |s: &str| (0 .. s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i .. i + 2], 16).unwrap()).collect::<Vec<u8>>();
+
+    // The block function test vector from section 2.3.2: key 00 01 ... 1f, counter 1, nonce 00 00 00 09 00 00 00 4a
+    // 00 00 00 00
+    let mut input = [0; 16];
+    input[.. 4].copy_from_slice(&CHACHA_CONSTANTS);
+    for (i, word) in input[4 .. 12].iter_mut().enumerate() {
+	*word = u32::from_le_bytes([4 * i as u8, 4 * i as u8 + 1, 4 * i as u8 + 2, 4 * i as u8 + 3]);
+    }
+    input[12 .. 16].copy_from_slice(&[1, 0x0900_0000, 0x4a00_0000, 0]);
+    assert_eq!(EntropyPool::chacha20(&input).to_vec(), hex(
+	"10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+	 d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"));
+
+    // Appendix A.1's first test vector, an all zero key, nonce and counter, which the pool's own layout can give
+    let mut pool = EntropyPool::new();
+    assert_eq!(pool.block().to_vec(), hex(
+	"76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+	 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"));
+    assert_eq!(pool.counter, 1);
+}
@@ -2,22 +2,31 @@ use core::mem::offset_of;
 use x86_64::structures::tss::TaskStateSegment;
 use alloc::string::String;
 use x86_64::VirtAddr;
-use x86_64::registers::model_specific::{FsBase, Efer, EferFlags, SFMask, Star, LStar};
+use x86_64::registers::model_specific::{Efer, EferFlags, SFMask, Star, LStar};
 use x86_64::registers::rflags::RFlags;
 use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
 use core::error::Error;
 use alloc::fmt;
 use alloc::boxed::Box;
-use core::future::Future;
+use core::future::{Future, poll_fn};
+use core::task::Poll;
 use core::pin::Pin;
 use alloc::sync::Arc;
 use spin::Mutex;
 use num_enum::TryFromPrimitive;
 use core::slice;
 use core::mem;
+use core::sync::atomic;
 use bitflags::bitflags;
-
-use crate::sys::ioctl;
+use futures_util::FutureExt;
+
+use crate::sys::kmsg;
+use crate::sys::block;
+use crate::sys::power;
+use crate::sys::random;
+use crate::sys::time;
+use crate::drivers::rtc;
 use crate::gdt;
 use crate::scheduler;
 use crate::scheduler::signal;
@@ -25,7 +34,7 @@ use crate::scheduler::elf_loader;
 use crate::vfs;
 use crate::memory;
 use crate::process;
-use crate::vfs::filesystem::VNode;
+use crate::vfs::filesystem::{VNode, OpenFlags, AccessMode};
 
 macro_rules! syscall_try {
     ($expr:expr) => {
@@ -64,16 +73,54 @@ macro_rules! syscall_err {
 #[allow(dead_code)]
 pub enum CanonicalError {
     Ok = 0,
+    Perm = 1,
     NoEnt = 2,
+    Srch = 3,
+    Intr = 4,
     Io = 5,
+    NoExec = 8,
     Badf = 9,
+    Child = 10,
     Again = 11,
+    NoMem = 12,
     Access = 13,
     Fault = 14,
+    Busy = 16,
+    Exist = 17,
+    XDev = 18,
     NotDir = 20,
+    IsDir = 21,
     Inval = 22,
+    MFile = 24,
+    NoTty = 25,
     SPipe = 29,
+    RoFs = 30,
+    Pipe = 32,
     Range = 34,
+    NotEmpty = 39,
+    Loop = 40,
+    NotSock = 88,
+    MsgSize = 90,
+    ProtoNoSupport = 93,
+    AfNoSupport = 97,
+}
+
+const FD_CLOEXEC: u64 = 1;
+
+const SIG_BLOCK: u64 = 1;
+const SIG_UNBLOCK: u64 = 2;
+const SIG_SETMASK: u64 = 3;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 4;
+
+#[repr(u64)]
+#[derive(Debug, TryFromPrimitive)]
+enum ArchPrctlOperation {
+    SetGs = 0x1001,
+    SetFs = 0x1002,
+    GetFs = 0x1003,
+    GetGs = 0x1004,
 }
 
 #[repr(u64)]
@@ -103,6 +150,81 @@ bitflags! {
     }
 }
 
+const LINUX_REBOOT_MAGIC1: u64 = 0xfee1dead;
+const LINUX_REBOOT_MAGIC2: [u64; 4] = [672274793, 85072278, 369367448, 537993216];
+const LINUX_REBOOT_CMD_RESTART: u64 = 0x01234567;
+const LINUX_REBOOT_CMD_HALT: u64 = 0xCDEF0123;
+const LINUX_REBOOT_CMD_POWER_OFF: u64 = 0x4321FEDC;
+const LINUX_REBOOT_CMD_CAD_ON: u64 = 0x89ABCDEF;
+const LINUX_REBOOT_CMD_CAD_OFF: u64 = 0;
+
+// The syslog(2) actions dmesg needs. Clearing the log and the console level aren't supported
+const SYSLOG_ACTION_READ_ALL: u64 = 3;
+const SYSLOG_ACTION_SIZE_BUFFER: u64 = 10;
+
+const WNOHANG: u64 = 1;
+const WUNTRACED: u64 = 2;
+const WCONTINUED: u64 = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct TimeVal {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeSpec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+const MEMBARRIER_CMD_QUERY: u64 = 0;
+const MEMBARRIER_CMD_GLOBAL: u64 = 1 << 0;
+const MEMBARRIER_CMD_GLOBAL_EXPEDITED: u64 = 1 << 1;
+const MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED: u64 = 1 << 2;
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED: u64 = 1 << 3;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: u64 = 1 << 4;
+const MEMBARRIER_SUPPORTED_CMDS: u64 = MEMBARRIER_CMD_GLOBAL | MEMBARRIER_CMD_GLOBAL_EXPEDITED |
+    MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED | MEMBARRIER_CMD_PRIVATE_EXPEDITED | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED;
+
+const GRND_NONBLOCK: u64 = 0x1;
+const GRND_RANDOM: u64 = 0x2;
+const GRND_INSECURE: u64 = 0x4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct RUsage {
+    ru_utime: TimeVal,
+    ru_stime: TimeVal,
+    ru_maxrss: i64,  // KiB
+    ru_ixrss: i64,
+    ru_idrss: i64,
+    ru_isrss: i64,
+    ru_minflt: i64,
+    ru_majflt: i64,
+    ru_nswap: i64,
+    ru_inblock: i64,
+    ru_oublock: i64,
+    ru_msgsnd: i64,
+    ru_msgrcv: i64,
+    ru_nsignals: i64,
+    ru_nvcsw: i64,
+    ru_nivcsw: i64,
+}
+
+// struct epoll_event, which x86_64 Linux packs
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct PollFd {
@@ -141,31 +263,52 @@ pub fn init() {
 }
 
 async fn sys_write(fd: u64, buf: u64, count: u64) -> SyscallResult {
-    if buf == 0 {
-	log::info!("NULL");
-
-	return SyscallResult {
-	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: CanonicalError::Io as u64
-	};
-    }
+    let buf = syscall_try!(memory::validate_user_ptr(buf, count));
 
     let process = scheduler::get_current_process();
-    let kbuf = memory::copy_from_user(VirtAddr::new(buf), count as usize).unwrap();
+    let kbuf = syscall_try!(memory::copy_from_user(buf, count as usize).map_err(|_| CanonicalError::Fault));
 
-    let actual_fd = process.get_file_descriptor(fd);
-    // let actual_fd = match process.get_file_descriptor(fd) {
-    // 	Ok(fd) => fd.file_description,
-    // 	Err(_) => {
-    // 	    return SyscallResult {
-    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-    // 		err_num: CanonicalError::Badf as u64
-    // 	    };
-    // 	},
-    // };
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd));
+    if !actual_fd.flags.is_writable() {
+	syscall_err!(CanonicalError::Badf);
+    }
 
     let w = actual_fd.file_handle;
-    match w.write(bytes::Bytes::from(kbuf)).await {
+    if actual_fd.flags.contains(OpenFlags::Append) {
+	// Appending to something that can't seek (e.g. a terminal) just writes
+	match w.seek(vfs::filesystem::SeekFrom::End(0)) {
+	    Ok(_) | Err(CanonicalError::SPipe) => (),
+	    Err(e) => syscall_err!(e),
+	}
+    }
+
+    // A handle may take only part of what it's offered (e.g. a pipe with little room left), and reports how much it
+    // consumed. Non-blocking writes hand that short count straight back, while blocking ones keep offering the rest
+    let kbuf = bytes::Bytes::from(kbuf);
+    let result = if actual_fd.flags.contains(OpenFlags::NonBlock) {
+	w.write(kbuf).now_or_never().unwrap_or(Err(CanonicalError::Again))
+    } else {
+	let mut written: usize = 0;
+	let mut error = None;
+	while written < kbuf.len() {
+	    match w.clone().write(kbuf.slice(written ..)).await {
+		Ok(0) => break,
+		Ok(len) => written += len as usize,
+		Err(e) => {
+		    error = Some(e);
+		    break;
+		},
+	    }
+	}
+
+	// Anything already written has been consumed, so is reported in preference to a later error
+	match error {
+	    Some(e) if written == 0 => Err(e),
+	    _ => Ok(written as u64),
+	}
+    };
+
+    match result {
 	Ok(len) => SyscallResult {
 	    return_value: len,
 	    err_num: CanonicalError::Ok as u64,
@@ -178,35 +321,48 @@ async fn sys_write(fd: u64, buf: u64, count: u64) -> SyscallResult {
 }
 
 async fn sys_read(fd: u64, buf: u64, count: u64) -> SyscallResult {
+    // Checked before reading, so that nothing is consumed if it can't be handed back
+    let buf = syscall_try!(memory::validate_user_ptr(buf, count));
+
     let process = scheduler::get_current_process();
-    let actual_fd = process.get_file_descriptor(fd);
-    // let actual_fd = match process.get_file_descriptor(fd) {
-    // 	Ok(fd) => fd.file_description,
-    // 	Err(_) => {
-    // 	    return SyscallResult {
-    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-    // 		err_num: CanonicalError::EBADF as u64
-    // 	    };
-    // 	},
-    // };
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd));
+    if !actual_fd.flags.is_readable() {
+	syscall_err!(CanonicalError::Badf);
+    }
 
     let w = actual_fd.file_handle;
-    let read_buffer = syscall_try!(w.read(count).await);
 
-    match memory::copy_to_user(VirtAddr::new(buf), read_buffer.to_vec().as_slice()) {
+    // Only filesystem-backed handles read straight to user memory, and those never block, so NonBlock doesn't apply
+    if let Some(read_fut) = w.clone().read_to_user(buf, count) {
+	syscall_success!(syscall_try!(read_fut.await));
+    }
+
+    let read_fut = w.read(count);
+    let read_buffer = if actual_fd.flags.contains(OpenFlags::NonBlock) {
+	syscall_try!(read_fut.now_or_never().unwrap_or(Err(CanonicalError::Again)))
+    } else {
+	syscall_try!(read_fut.await)
+    };
+
+    match memory::copy_to_user(buf, &read_buffer) {
 	Ok(()) => SyscallResult {
 	    return_value: read_buffer.len() as u64,
 	    err_num: CanonicalError::Ok as u64,
 	},
 	Err(_) => SyscallResult {
 	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: CanonicalError::Io as u64,  // TODO: This is probably not EIO. Look up what it should be canonically
+	    err_num: CanonicalError::Fault as u64,
 	},
     }
 }
 
-pub async fn sys_open(path_ptr: u64, flags: u64) -> SyscallResult {
-    let path = match memory::copy_string_from_user(VirtAddr::new(path_ptr)) {
+// The mode a file or directory is created with: what the caller asked for, less the bits its umask takes away
+fn creation_mode(mode: u64, umask: u32) -> u64 {
+    mode & 0o7777 & !(umask as u64)
+}
+
+pub async fn sys_open(path_ptr: u64, flags: u64, mode: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
 	Ok(path) => path,
 	Err(_) => {
 	    return SyscallResult {
@@ -218,21 +374,21 @@ pub async fn sys_open(path_ptr: u64, flags: u64) -> SyscallResult {
 
     let process = scheduler::get_current_process();
 
-    // TODO: check that the file exists
-    // Bottom 3 bits are mode. We don't currently enforce mode, but in order to progress, let's strip it out.
-    // Similarly, there isn't yet a concept of a controlling TTY, so let's not worry about that either for now
-    // TODO - support read/write/exec/etc modes
-    // TODO - support O_NOCTTY (0x80)
-    // TODO - support O_TRUNC (0x200)
-    // TODO - support O_NOFOLLOW (0x10)
-    if flags & 0xFFFF_FFFF_FFFF_FD68 != 0 {
-	log::info!("Open flags are 0x{:x} for {}", flags, path);
-	unimplemented!();
-    }
+    // There isn't yet a concept of a controlling TTY, so O_NOCTTY is accepted and ignored. The sync flags are
+    // meaningless without a writable FS.
+    let open_flags = match OpenFlags::from_bits(flags) {
+	Some(f) => f,
+	None => {
+	    log::info!("Open flags are 0x{:x} for {}", flags, path);
+	    syscall_err!(CanonicalError::Inval);
+	},
+    };
+    syscall_try!(open_flags.access_mode());
+    let mode = creation_mode(mode, process.get_umask());
 
-    let fh = syscall_try!(vfs::vfs_open(&path).await);
+    let fh = syscall_try!(vfs::vfs_open(&path, open_flags, mode).await);
     let fd = process::FileDescriptor {
-	flags,
+	flags: open_flags,
 	file_handle: fh,
     };
     let fd_num = process.emplace_fd(fd);
@@ -245,7 +401,7 @@ pub async fn sys_open(path_ptr: u64, flags: u64) -> SyscallResult {
 
 async fn sys_close(fd: u64) -> SyscallResult {
     let process = scheduler::get_current_process();
-    process.close_fd(fd);
+    syscall_try!(process.close_fd(fd));
 
     SyscallResult {
 	return_value: 0,
@@ -253,34 +409,70 @@ async fn sys_close(fd: u64) -> SyscallResult {
     }
 }
 
-async fn sys_ioctl(fd_num: u64, ioctl: u64, buf: u64) -> SyscallResult {
-    let op = match ioctl::IoCtl::try_from(ioctl) {
-	Ok(v) => v,
-	Err(_) => {
-	    log::info!("Got ioctl number 0x{:x}", ioctl);
-	    unimplemented!();
-	},
-    };
-
+// The request is passed through as is, for the device to decode
+async fn sys_ioctl(fd_num: u64, request: u64, buf: u64) -> SyscallResult {
     let process = scheduler::get_current_process();
-    let actual_fd = process.get_file_descriptor(fd_num);
-    // let actual_fd = match process.get_file_descriptor(fd) {
-    // 	Ok(fd) => fd.file_description,
-    // 	Err(_) => {
-    // 	    return SyscallResult {
-    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-    // 		err_num: CanonicalError::EBADF as u64
-    // 	    };
-    // 	},
-    // };
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
 
     let r = actual_fd.file_handle;
-    let r = syscall_try!(r.ioctl(op, buf).await);
+    let r = syscall_try!(r.ioctl(request, buf).await);
     syscall_success!(r);
 }
 
-async fn sys_stat(filename: u64, _buf: u64) -> SyscallResult {
-    let path = match memory::copy_string_from_user(VirtAddr::new(filename)) {
+// struct stat, as x86_64 Linux, and so mlibc, lays it out
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(dead_code)]
+struct UserStat {
+    st_dev: u64,
+    st_ino: u64,
+    st_nlink: u64,
+    st_mode: u32,
+    st_uid: u32,
+    st_gid: u32,
+    pad0: u32,
+    st_rdev: u64,
+    st_size: i64,
+    st_blksize: i64,
+    st_blocks: i64,
+    st_atime: i64,
+    st_atime_nsec: i64,
+    st_mtime: i64,
+    st_mtime_nsec: i64,
+    st_ctime: i64,
+    st_ctime_nsec: i64,
+    unused: [i64; 3],
+}
+
+impl UserStat {
+    fn new(stat: &vfs::filesystem::Stat) -> UserStat {
+	let size = stat.size.unwrap_or(0);
+	// Nothing keeps track of access or status change times, so they're given as the modification time
+	let modified = stat.modified.unwrap_or(0) as i64;
+
+	UserStat {
+	    st_ino: stat.inode,
+	    st_nlink: 1,
+	    // The dirent types are the file type bits of the mode, shifted down
+	    st_mode: ((dirent_type(stat.kind) as u32) << 12) | (stat.mode as u32 & 0o7777),
+	    st_size: size as i64,
+	    st_blksize: 512,
+	    st_blocks: size.div_ceil(512) as i64,
+	    st_atime: modified,
+	    st_mtime: modified,
+	    st_ctime: modified,
+	    ..UserStat::default()
+	}
+    }
+}
+
+fn copy_stat_to_user(buf: u64, stat: &vfs::filesystem::Stat) -> Result<(), CanonicalError> {
+    let buf = memory::validate_user_ptr(buf, mem::size_of::<UserStat>() as u64)?;
+    memory::copy_value_to_user(buf, &UserStat::new(stat)).map_err(|_| CanonicalError::Fault)
+}
+
+async fn sys_stat(filename: u64, buf: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(filename, 1))) {
 	Ok(path) => path,
 	Err(_) => {
 	    return SyscallResult {
@@ -290,49 +482,53 @@ async fn sys_stat(filename: u64, _buf: u64) -> SyscallResult {
 	},
     };
 
-    let fh = syscall_try!(vfs::vfs_open(&path).await);
-    match fh.stat() {
-	Ok(_ret) => SyscallResult {
-	    return_value: 0,
-	    err_num: CanonicalError::Ok as u64
-	},
-	Err(e) => SyscallResult {
-	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: e as u64
-	}
+    let vnode = syscall_try!(vfs::vfs_walk_path(&path).await);
+    let stat = syscall_try!(vnode.stat());
+    syscall_try!(copy_stat_to_user(buf, &stat));
+    syscall_success!(0);
+}
+
+async fn sys_fstatat(dirfd: u64, filename: u64, buf: u64, flags: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(filename, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Inval),
+    };
+
+    // TODO: resolve relative paths against dirfd
+    if !path.starts_with('/') && dirfd as i64 != AT_FDCWD {
+	log::info!("fstatat relative to fd {} is not supported", dirfd);
+	syscall_err!(CanonicalError::Inval);
     }
+
+    // Stat the vnode directly, rather than opening it, so that we can get at a symlink itself
+    let vnode = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+	syscall_try!(vfs::vfs_walk_path_nofollow(&path).await)
+    } else {
+	syscall_try!(vfs::vfs_walk_path(&path).await)
+    };
+
+    let stat = syscall_try!(vnode.stat());
+    syscall_try!(copy_stat_to_user(buf, &stat));
+    syscall_success!(0);
 }
 
-async fn sys_fstat(fd: u64, _buf: u64) -> SyscallResult {
+async fn sys_fstat(fd: u64, buf: u64) -> SyscallResult {
     let process = scheduler::get_current_process();
-    let actual_fd = process.get_file_descriptor(fd);
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd));
 
-    match actual_fd.file_handle.stat() {
-	Ok(_ret) => SyscallResult {
-	    return_value: 0,
-	    err_num: CanonicalError::Ok as u64
-	},
-	Err(e) => SyscallResult {
-	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: e as u64
-	}
-    }
+    let stat = syscall_try!(actual_fd.file_handle.stat());
+    syscall_try!(copy_stat_to_user(buf, &stat));
+    syscall_success!(0);
 }
 
 async fn sys_dup(fd_num: u64) -> SyscallResult {
     let process = scheduler::get_current_process();
-    let actual_fd = process.get_file_descriptor(fd_num);
-    // let actual_fd = match process.get_file_descriptor(fd) {
-    // 	Ok(fd) => fd.file_description,
-    // 	Err(_) => {
-    // 	    return SyscallResult {
-    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-    // 		err_num: CanonicalError::EBADF as u64
-    // 	    };
-    // 	},
-    // };
-
-    let new_fd = process.emplace_fd(actual_fd);
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
+
+    let mut new_fd = actual_fd;
+    new_fd.flags.remove(OpenFlags::CloExec);
+
+    let new_fd = process.emplace_fd(new_fd);
     SyscallResult {
 	return_value: new_fd,
 	err_num: CanonicalError::Ok as u64,
@@ -355,47 +551,37 @@ async fn sys_fcntl(fd_num: u64, operation: u64, param: u64) -> SyscallResult {
     match op {
 	FcntlOperation::DupFD => {
 	    let process = scheduler::get_current_process();
-	    let actual_fd = process.get_file_descriptor(fd_num);
-	    // let actual_fd = match process.get_file_descriptor(fd) {
-	    // 	Ok(fd) => fd.file_description,
-	    // 	Err(_) => {
-	    // 	    return SyscallResult {
-	    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    // 		err_num: CanonicalError::EBADF as u64
-	    // 	    };
-	    // 	},
-	    // };
-
-	    let new_fd = process.emplace_fd_at(actual_fd, param, true);
+	    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
+
+	    let mut new_fd = actual_fd;
+	    new_fd.flags.remove(OpenFlags::CloExec);
+
+	    let new_fd = syscall_try!(process.emplace_fd_at(new_fd, param, true));
 	    SyscallResult {
 		return_value: new_fd,
 		err_num: CanonicalError::Ok as u64,
 	    }
 	},
 	FcntlOperation::GetFD => {
-	    // TODO: these are the wrong flags. This should be reading cloexec only.
 	    let process = scheduler::get_current_process();
-	    let actual_fd = process.get_file_descriptor(fd_num);
+	    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
 
 	    SyscallResult {
-		return_value: actual_fd.flags,
+		return_value: if actual_fd.flags.contains(OpenFlags::CloExec) { FD_CLOEXEC } else { 0 },
 		err_num: CanonicalError::Ok as u64,
 	    }
 	},
 	FcntlOperation::SetFD => {
-	    // TODO: these are the wrong flags. This should be reading cloexec only.
-
 	    let process = scheduler::get_current_process();
-	    // Bottom 3 bits are mode. We don't currently enforce mode, but in order to progress, let's strip it out.
-	    // Similarly, there isn't yet a concept of a controlling TTY, so let's not worry about that either for now
-	    // TODO - support read/write/exec/etc modes
-	    // TODO - support O_NOCTTY (0x80)
-	    if param & 0xFFFF_FFFF_FFFF_FF78 != 0 {
+	    let mut flags = syscall_try!(process.get_file_descriptor(fd_num)).flags;
+
+	    if param & !FD_CLOEXEC != 0 {
 		log::info!("SETFD flags are 0x{:x}", param);
-		unimplemented!();
+		syscall_err!(CanonicalError::Inval);
 	    }
 
-	    process.set_fd_flags(fd_num, param);
+	    flags.set(OpenFlags::CloExec, param & FD_CLOEXEC != 0);
+	    syscall_try!(process.set_fd_flags(fd_num, flags));
 	    SyscallResult {
 		return_value: 0,
 		err_num: CanonicalError::Ok as u64,
@@ -403,10 +589,11 @@ async fn sys_fcntl(fd_num: u64, operation: u64, param: u64) -> SyscallResult {
 	},
 	FcntlOperation::GetFlags => {
 	    let process = scheduler::get_current_process();
-	    let actual_fd = process.get_file_descriptor(fd_num);
+	    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
 
+	    // The FD flags are not file status flags, so shouldn't be reported here
 	    SyscallResult {
-		return_value: actual_fd.flags,
+		return_value: (actual_fd.flags - OpenFlags::CloExec).bits(),
 		err_num: CanonicalError::Ok as u64,
 	    }
 	},
@@ -415,16 +602,7 @@ async fn sys_fcntl(fd_num: u64, operation: u64, param: u64) -> SyscallResult {
 
 async fn sys_seek(fd_num: u64, offset: u64, whence: u64) -> SyscallResult {
     let process = scheduler::get_current_process();
-    let actual_fd = process.get_file_descriptor(fd_num);
-    // let actual_fd = match process.get_file_descriptor(fd) {
-    // 	Ok(fd) => fd.file_description,
-    // 	Err(_) => {
-    // 	    return SyscallResult {
-    // 		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-    // 		err_num: CanonicalError::EBADF as u64
-    // 	    };
-    // 	},
-    // };
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
 
     let whence = match whence {
 	1 => vfs::filesystem::SeekFrom::Cur(offset as i64),
@@ -439,6 +617,25 @@ async fn sys_seek(fd_num: u64, offset: u64, whence: u64) -> SyscallResult {
     syscall_success!(offs);
 }
 
+// Also serves as fdatasync, as there's no metadata written apart from the data
+async fn sys_fsync(fd_num: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
+
+    syscall_try!(actual_fd.file_handle.fsync().await);
+    syscall_success!(0);
+}
+
+// Syncs every filesystem, then flushes every disk, including those with nothing mounted. Unlike Linux, this reports
+// whether it worked
+async fn sys_sync() -> SyscallResult {
+    let synced = vfs::sync().await;
+    let flushed = block::flush_all().await;
+
+    syscall_try!(synced.and(flushed));
+    syscall_success!(0);
+}
+
 async fn sys_poll(fds: u64, nfds: u64, _timeout: u64) -> SyscallResult {
     // If there aren't any FDs, just don't do anything
     if nfds == 0 {
@@ -453,10 +650,12 @@ async fn sys_poll(fds: u64, nfds: u64, _timeout: u64) -> SyscallResult {
 	unimplemented!();
     }
 
+    let fds = syscall_try!(memory::validate_user_ptr(fds, nfds * mem::size_of::<PollFd>() as u64));
+
     // Parse the FDs
     let mut fds_vec = Vec::with_capacity(nfds as usize);
     {
-	let mut addr = VirtAddr::new(fds);
+	let mut addr = fds;
 	for _ in 0..nfds {
 	    let fd = match memory::copy_value_from_user::<PollFd>(addr) {
 		Ok(fd) => fd,
@@ -473,18 +672,17 @@ async fn sys_poll(fds: u64, nfds: u64, _timeout: u64) -> SyscallResult {
 
     // Skip if negative
     if let Ok(fd) = TryInto::<u64>::try_into(fds_vec[0].fd) {
-	// Actually poll
+	// Actually poll. A bad fd is reported in revents, rather than failing the whole call
 	let process = scheduler::get_current_process();
-	let actual_fd = process.get_file_descriptor(fd);
-	let r = actual_fd.file_handle;
-
-	let revents = syscall_try!(r.poll(fds_vec[0].events).await);
-	fds_vec[0].revents = revents;
+	fds_vec[0].revents = match process.get_file_descriptor(fd) {
+	    Ok(actual_fd) => syscall_try!(actual_fd.file_handle.poll(fds_vec[0].events).await),
+	    Err(_) => PollEvents::Nval,
+	};
     }
 
     // Copy the results back to userspace
     {
-	let mut addr = VirtAddr::new(fds);
+	let mut addr = fds;
 	for i in 0..nfds {
 	    match memory::copy_value_to_user::<PollFd>(addr, &fds_vec[i as usize]) {
 		Ok(()) => (),
@@ -516,6 +714,13 @@ async fn sys_mmap(start_val: u64, count: u64, r8: u64) -> SyscallResult {
 	unimplemented!();
     }
 
+    // Checked before taking the task type lock, as validating needs it too
+    let start_hint = if start_val != 0 {
+	Some(syscall_try!(memory::validate_user_ptr(start_val, count)))
+    } else {
+	None
+    };
+
     let process = scheduler::get_current_process();
     let mut task_type = process.task_type.write();
     let start = match *task_type {
@@ -541,22 +746,22 @@ async fn sys_mmap(start_val: u64, count: u64, r8: u64) -> SyscallResult {
 	    start
 	},
 	process::TaskType::User(ref mut address_space) => {
-	    let (start, _) = if start_val == 0 {
-		match memory::user_allocate(
+	    // Only anonymous mappings are supported, which start out as the zero page
+	    let start = match start_hint {
+		None => match memory::user_allocate_anonymous(
 		    count,
 		    memory::MemoryAccessRestriction::User,
 		    address_space) {
 		    Ok(i) => i,
 		    Err(e) => panic!("Could not allocate memory for mmap: {:?}", e),
-		}
-	    } else {
-		match memory::user_allocate(
+		},
+		Some(start_hint) => match memory::user_allocate_anonymous(
 		    count,
-		    memory::MemoryAccessRestriction::UserByStart(VirtAddr::new(start_val)),
+		    memory::MemoryAccessRestriction::UserByStart(start_hint),
 		    address_space) {
 		    Ok(i) => i,
 		    Err(e) => panic!("Could not allocate memory for mmap: {:?}", e),
-		}		
+		},
 	    };
 
 	    start
@@ -569,184 +774,615 @@ async fn sys_mmap(start_val: u64, count: u64, r8: u64) -> SyscallResult {
     }
 }
 
+// Gives pipe and socketpair's caller the numbers of the two new fds. If they can't be, the caller will never know
+// what they were, so they're closed again
+fn copy_fd_pair_to_user(process: Arc<process::Process>, dest: VirtAddr, fd1: u64, fd2: u64) -> Result<(), CanonicalError> {
+    let v = [fd1 as u32, fd2 as u32];
+    let copied = unsafe {
+	memory::copy_to_user(dest, slice::from_raw_parts(v.as_ptr() as *const u8, v.len() * mem::size_of::<u32>()))
+    };
+
+    copied.map_err(|_| {
+	let _ = process.clone().close_fd(fd1);
+	let _ = process.close_fd(fd2);
+	CanonicalError::Fault
+    })
+}
+
 async fn sys_pipe(fds: u64, flags: u64) -> SyscallResult {
+    let fds = syscall_try!(memory::validate_user_ptr(fds, 2 * mem::size_of::<u32>() as u64));
     let process = scheduler::get_current_process();
+    let pipe_flags = match OpenFlags::from_bits(flags) {
+	Some(f) if (f - (OpenFlags::CloExec | OpenFlags::NonBlock)).is_empty() => f,
+	_ => syscall_err!(CanonicalError::Inval),
+    };
     let file_description = Arc::new(vfs::fifo::Fifo::new());
 
+    // The first end is only for reading, and the second only for writing
     let fd1 = process::FileDescriptor {
-	flags,
+	flags: pipe_flags.with_access_mode(AccessMode::RdOnly),
 	file_handle: syscall_try!(file_description.clone().open()),
     };
     let fd2 = process::FileDescriptor {
-	flags,
+	flags: pipe_flags.with_access_mode(AccessMode::WrOnly),
 	file_handle: syscall_try!(file_description.clone().open()),
     };
 
     let fd1_number = process.clone().emplace_fd(fd1);
-    let fd2_number = process.emplace_fd(fd2);
-
-    let v = [fd1_number as u32, fd2_number as u32];
-
-    unsafe {
-	memory::copy_to_user(VirtAddr::new(fds), slice::from_raw_parts(
-	    v.as_ptr() as *const u8,
-	    v.len() * mem::size_of::<u32>())).unwrap();
-    }
+    let fd2_number = process.clone().emplace_fd(fd2);
+    syscall_try!(copy_fd_pair_to_user(process, fds, fd1_number, fd2_number));
 
     SyscallResult {
 	return_value: 0,
 	err_num: CanonicalError::Ok as u64,
     }
 }
-    
-async fn sys_getcwd(buf: u64, _count: u64) -> SyscallResult {
-    let process = scheduler::get_current_process();
-    let cwd = process.get_cwd();
 
-    memory::copy_string_to_user(VirtAddr::new(buf), cwd).unwrap();
+// Values follow the mlibc ABI
+const AF_UNIX: u64 = 3;
+const SOCK_DGRAM: u64 = 1;
+const SOCK_SEQPACKET: u64 = 3;
+const SOCK_STREAM: u64 = 4;
+const SOCK_TYPE_MASK: u64 = 0xFFFF;
+const SOCK_NONBLOCK: u64 = 0x10000;
+const SOCK_CLOEXEC: u64 = 0x20000;
 
-    SyscallResult {
-	return_value: 0,
-	err_num: CanonicalError::Ok as u64,
-    }
+const SOL_SOCKET: i32 = 1;
+const SCM_RIGHTS: i32 = 1;
+const SCM_CREDENTIALS: i32 = 2;
+
+const MSG_CTRUNC: i32 = 0x1;
+const MSG_TRUNC: i32 = 0x40;
+const MSG_DONTWAIT: u64 = 0x1000;
+const MSG_CMSG_CLOEXEC: u64 = 0x2000;
+
+// As Linux, to bound how much we copy in for a single sendmsg or recvmsg
+const UIO_MAXIOV: u64 = 1024;
+const SCM_MAX_FD: usize = 253;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct IoVec {
+    base: u64,
+    len: u64,
 }
 
-async fn sys_fork() -> SyscallResult {
-    let pid = scheduler::fork_current_process();
-    SyscallResult {
-	return_value: pid,
-	err_num: CanonicalError::Ok as u64,
-    }
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+struct MsgHdr {
+    name: u64,
+    namelen: u32,
+    iov: u64,
+    iovlen: i32,
+    control: u64,
+    controllen: u32,
+    flags: i32,
 }
 
-pub async fn sys_execve(path_ptr: u64, args_ptr: u64, envvars_ptr: u64) -> SyscallResult {
-    let path = memory::copy_string_from_user(VirtAddr::new(path_ptr)).unwrap();
-    let mut args = {
-	let mut args: Vec<String> = Vec::new();
-	let mut argc_ptr = VirtAddr::new(args_ptr);
-	loop {
-	    let argp = memory::copy_value_from_user::<VirtAddr>(argc_ptr).unwrap();
-	    if argp == VirtAddr::new(0) {
-		break;
-	    }
-	    
-	    let arg = memory::copy_string_from_user(argp).unwrap();
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CmsgHdr {
+    len: u32,
+    level: i32,
+    cmsg_type: i32,
+}
 
-	    args.push(arg);
-	    argc_ptr += 8;
-	}
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UCred {
+    pid: u32,
+    uid: u32,
+    gid: u32,
+}
 
-	args
-    };
-    args.insert(0, path.clone());
+// Control messages, and the data in each, are aligned to a size_t
+fn cmsg_align(len: usize) -> usize {
+    (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1)
+}
 
-    let mut envvars: Vec<String> = Vec::new();
-    let mut envvar_ptr = VirtAddr::new(envvars_ptr);
-    loop {
-	let envvarp = memory::copy_value_from_user::<VirtAddr>(envvar_ptr).unwrap();
-	if envvarp == VirtAddr::new(0) {
-	    break;
-	}
+fn cmsg_len(data_len: usize) -> usize {
+    cmsg_align(mem::size_of::<CmsgHdr>()) + data_len
+}
 
-	let envvar = memory::copy_string_from_user(envvarp).unwrap();
+// Empty entries are left out, as their base may be anything
+fn copy_iovecs_from_user(iov: u64, iovlen: i32) -> Result<Vec<IoVec>, CanonicalError> {
+    if iovlen < 0 || iovlen as u64 > UIO_MAXIOV {
+	return Err(CanonicalError::Inval);
+    }
+    if iovlen == 0 {
+	return Ok(Vec::new());
+    }
 
-	envvars.push(envvar);
-	envvar_ptr += 8;
+    let iov = memory::validate_user_ptr(iov, iovlen as u64 * mem::size_of::<IoVec>() as u64)?;
+    let mut iovecs = Vec::with_capacity(iovlen as usize);
+    for i in 0 .. iovlen as u64 {
+	let iovec = memory::copy_value_from_user::<IoVec>(iov + i * mem::size_of::<IoVec>() as u64).map_err(|_| CanonicalError::Fault)?;
+	if iovec.len > 0 {
+	    memory::validate_user_ptr(iovec.base, iovec.len)?;
+	    iovecs.push(iovec);
+	}
     }
 
-    log::info!("y");
+    Ok(iovecs)
+}
 
-    let process = scheduler::get_current_process();
-    log::info!("y.1");
-    process.clone().execve(args, envvars);
-    log::info!("y.2");
+// Collects what a sendmsg control buffer carries. The close-on-exec flag of passed descriptors belongs to the sender's
+// table, so it's dropped here and the receiver decides for itself
+fn ancillary_from_control(process: &process::Process, control: u64, controllen: u32) -> Result<vfs::socket::Ancillary, CanonicalError> {
+    let mut ancillary = vfs::socket::Ancillary::default();
+    if controllen == 0 {
+	return Ok(ancillary);
+    }
 
-    let elf = elf_loader::Elf::new(path).await.expect("Failed to load ELF");
-    log::info!("y.3");
-    let ld = elf_loader::Elf::new(String::from("/usr/lib/ld.so")).await.expect("Failed to load ld.so");
-    log::info!("y.4");
-    process.clone().attach_loaded_elf(elf, ld);
+    let control = memory::validate_user_ptr(control, controllen as u64)?;
+    let file_descriptors = process.clone_file_descriptors();
+    let mut offset = 0;
+    while offset + mem::size_of::<CmsgHdr>() <= controllen as usize {
+	let header = memory::copy_value_from_user::<CmsgHdr>(control + offset as u64).map_err(|_| CanonicalError::Fault)?;
+	let len = header.len as usize;
+	if len < cmsg_len(0) || offset + len > controllen as usize || header.level != SOL_SOCKET {
+	    return Err(CanonicalError::Inval);
+	}
 
-    log::info!("z");
+	let data = memory::copy_from_user(control + (offset + cmsg_len(0)) as u64, len - cmsg_len(0)).map_err(|_| CanonicalError::Fault)?;
+	match header.cmsg_type {
+	    SCM_RIGHTS => {
+		for fd in data.chunks_exact(mem::size_of::<i32>()) {
+		    let fd = i32::from_ne_bytes(fd.try_into().unwrap());
+		    let mut file_descriptor = u64::try_from(fd).ok()
+			.and_then(|fd| file_descriptors.get(&fd))
+			.ok_or(CanonicalError::Badf)?
+			.clone();
+		    file_descriptor.flags.remove(OpenFlags::CloExec);
+		    ancillary.rights.push(file_descriptor);
+		}
+	    },
+	    SCM_CREDENTIALS => {
+		if data.len() != mem::size_of::<UCred>() {
+		    return Err(CanonicalError::Inval);
+		}
 
-    if let Err(_e) = process.clone().init_stack_and_start() {
-	return SyscallResult {
-	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: CanonicalError::Io as u64,
-	};
+		// A process may only vouch for itself, unless it's root
+		let ucred = unsafe { (data.as_ptr() as *const UCred).read_unaligned() };
+		let credentials = scheduler::get_current_process().get_credentials();
+		let is_self = ucred.pid as u64 == scheduler::get_current_pid() &&
+		    [credentials.uid, credentials.euid, credentials.suid].contains(&ucred.uid) &&
+		    [credentials.gid, credentials.egid, credentials.sgid].contains(&ucred.gid);
+		if !is_self && !credentials.is_privileged() {
+		    return Err(CanonicalError::Perm);
+		}
+		ancillary.credentials = Some(vfs::socket::Credentials {
+		    pid: ucred.pid,
+		    uid: ucred.uid,
+		    gid: ucred.gid,
+		});
+	    },
+	    _ => return Err(CanonicalError::Inval),
+	}
+
+	offset += cmsg_align(len);
     }
 
-    SyscallResult {
-	return_value: 0,
-	err_num: CanonicalError::Ok as u64,
+    if ancillary.rights.len() > SCM_MAX_FD {
+	return Err(CanonicalError::Inval);
     }
+
+    Ok(ancillary)
 }
 
-async fn sys_getpid() -> SyscallResult {
-    let pid = scheduler::get_current_pid();
-    SyscallResult {
-	return_value: pid,
-	err_num: CanonicalError::Ok as u64,
+// Appends a control message to what recvmsg will hand back, if there's room for it in the caller's buffer
+fn put_cmsg(control: &mut Vec<u8>, room: usize, cmsg_type: i32, data: &[u8]) -> bool {
+    let start = cmsg_align(control.len());
+    if start + cmsg_len(data.len()) > room {
+	return false;
     }
-}
 
-async fn sys_getppid() -> SyscallResult {
-    // We don't yet support process parentage
-    SyscallResult {
-	return_value: 0,
-	err_num: CanonicalError::Ok as u64,
+    let header = CmsgHdr {
+	len: cmsg_len(data.len()) as u32,
+	level: SOL_SOCKET,
+	cmsg_type,
+    };
+    control.resize(start, 0);
+    unsafe {
+	control.extend_from_slice(slice::from_raw_parts(
+	    &header as *const CmsgHdr as *const u8,
+	    mem::size_of::<CmsgHdr>()));
     }
+    control.resize(start + cmsg_len(0), 0);
+    control.extend_from_slice(data);
+
+    true
 }
 
-async fn sys_getpgid() -> SyscallResult {
-    // We don't yet support process groups
-    SyscallResult {
-	return_value: 0,
-	err_num: CanonicalError::Ok as u64,
+async fn sys_socketpair(domain: u64, socket_type: u64, protocol: u64, sv: u64) -> SyscallResult {
+    if domain != AF_UNIX {
+	syscall_err!(CanonicalError::AfNoSupport);
     }
-}
+    if socket_type & !(SOCK_TYPE_MASK | SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let datagram = match socket_type & SOCK_TYPE_MASK {
+	SOCK_STREAM => false,
+	SOCK_DGRAM | SOCK_SEQPACKET => true,
+	_ => syscall_err!(CanonicalError::Inval),
+    };
+    if protocol != 0 {
+	syscall_err!(CanonicalError::ProtoNoSupport);
+    }
+    let sv = syscall_try!(memory::validate_user_ptr(sv, 2 * mem::size_of::<u32>() as u64));
 
-async fn sys_tcb_set(new_fs: u64) -> SyscallResult {
-    FsBase::write(VirtAddr::new(new_fs));
-    SyscallResult {
-	return_value: 0,
-	err_num: 0,
+    let mut flags = OpenFlags::empty().with_access_mode(AccessMode::RdWr);
+    if socket_type & SOCK_NONBLOCK != 0 {
+	flags |= OpenFlags::NonBlock;
+    }
+    if socket_type & SOCK_CLOEXEC != 0 {
+	flags |= OpenFlags::CloExec;
     }
+
+    let (a, b) = vfs::socket::UnixSocket::pair(datagram);
+    let process = scheduler::get_current_process();
+    let fd1_number = process.clone().emplace_fd(process::FileDescriptor {
+	flags,
+	file_handle: a,
+    });
+    let fd2_number = process.clone().emplace_fd(process::FileDescriptor {
+	flags,
+	file_handle: b,
+    });
+    syscall_try!(copy_fd_pair_to_user(process, sv, fd1_number, fd2_number));
+
+    syscall_success!(0);
 }
 
-async fn sys_sigaction(signum: u64, new_sigaction: u64, old_sigaction: u64) -> SyscallResult {
+async fn sys_sendmsg(fd: u64, msg: u64, flags: u64) -> SyscallResult {
+    let msg = syscall_try!(memory::validate_user_ptr(msg, mem::size_of::<MsgHdr>() as u64));
+    let header = syscall_try!(memory::copy_value_from_user::<MsgHdr>(msg).map_err(|_| CanonicalError::Fault));
+
     let process = scheduler::get_current_process();
-    if old_sigaction != 0 {
-	if let Some(signal) = process.get_current_signal_handler(signum) {
-	    let sigaction = signal::create_sigaction(signal);
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd));
+    let socket = match actual_fd.file_handle.as_socket() {
+	Some(s) => s,
+	None => syscall_err!(CanonicalError::NotSock),
+    };
 
-	    match memory::copy_value_to_user::<signal::SigAction>(
-		VirtAddr::new(new_sigaction), &sigaction) {
-		Ok(()) => (),
-		Err(_) => {
-		    return SyscallResult {
-			return_value: 0xFFFF_FFFF_FFFF_FFFF,
-			err_num: CanonicalError::Inval as u64
-		    };
+    let iovecs = syscall_try!(copy_iovecs_from_user(header.iov, header.iovlen));
+    let mut data = Vec::new();
+    for iovec in iovecs.iter() {
+	data.extend(syscall_try!(memory::copy_from_user(VirtAddr::new(iovec.base), iovec.len as usize).map_err(|_| CanonicalError::Fault)));
+    }
+    let ancillary = syscall_try!(ancillary_from_control(&process, header.control, header.controllen));
+
+    // As with write, a blocking send keeps going until everything has been taken, with the ancillary data going along
+    // with the first part of it. A datagram is always taken in one go
+    let data = bytes::Bytes::from(data);
+    let result = if actual_fd.flags.contains(OpenFlags::NonBlock) || flags & MSG_DONTWAIT != 0 {
+	socket.send(data, ancillary).now_or_never().unwrap_or(Err(CanonicalError::Again))
+    } else {
+	let mut sent = syscall_try!(socket.clone().send(data.clone(), ancillary).await) as usize;
+	let mut error = None;
+	while sent < data.len() {
+	    match socket.clone().send(data.slice(sent ..), vfs::socket::Ancillary::default()).await {
+		Ok(0) => break,
+		Ok(len) => sent += len as usize,
+		Err(e) => {
+		    error = Some(e);
+		    break;
 		},
 	    }
 	}
-    }
 
-    if new_sigaction != 0 {
-	let sa = match memory::copy_value_from_user::<signal::SigAction>(VirtAddr::new(new_sigaction)) {
-	    Ok(s) => s,
-	    Err(_) => {
-		return SyscallResult {
-		    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-		    err_num: CanonicalError::Inval as u64
-		};
-	    },
-	};
+	match error {
+	    Some(e) if sent == 0 => Err(e),
+	    _ => Ok(sent as u64),
+	}
+    };
 
-	let signal_handler = signal::parse_sigaction(sa);
-	process.install_signal_handler(signum, signal_handler);
-    }
+    syscall_success!(syscall_try!(result));
+}
+
+async fn sys_recvmsg(fd: u64, msg: u64, flags: u64) -> SyscallResult {
+    let msg = syscall_try!(memory::validate_user_ptr(msg, mem::size_of::<MsgHdr>() as u64));
+    let mut header = syscall_try!(memory::copy_value_from_user::<MsgHdr>(msg).map_err(|_| CanonicalError::Fault));
+
+    let process = scheduler::get_current_process();
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd));
+    let socket = match actual_fd.file_handle.as_socket() {
+	Some(s) => s,
+	None => syscall_err!(CanonicalError::NotSock),
+    };
+
+    let iovecs = syscall_try!(copy_iovecs_from_user(header.iov, header.iovlen));
+    let control_ptr = if header.controllen > 0 {
+	Some(syscall_try!(memory::validate_user_ptr(header.control, header.controllen as u64)))
+    } else {
+	None
+    };
+    let len = iovecs.iter().map(|iovec| iovec.len).sum();
+
+    let recv_fut = socket.recv(len);
+    let received = if actual_fd.flags.contains(OpenFlags::NonBlock) || flags & MSG_DONTWAIT != 0 {
+	syscall_try!(recv_fut.now_or_never().unwrap_or(Err(CanonicalError::Again)))
+    } else {
+	syscall_try!(recv_fut.await)
+    };
+    let data = received.data;
+
+    let mut offset = 0;
+    for iovec in iovecs.iter() {
+	let chunk = core::cmp::min(iovec.len as usize, data.len() - offset);
+	syscall_try!(memory::copy_to_user(VirtAddr::new(iovec.base), &data[offset .. offset + chunk]).map_err(|_| CanonicalError::Fault));
+	offset += chunk;
+    }
+
+    header.flags = 0;
+    if received.len > data.len() {
+	header.flags |= MSG_TRUNC;
+    }
+
+    // Control messages which don't fit in the buffer are dropped, and descriptors among them closed rather than
+    // installed, as on Linux
+    let room = header.controllen as usize;
+    let mut control = Vec::new();
+    if let Some(credentials) = received.ancillary.credentials {
+	let ucred = UCred {
+	    pid: credentials.pid,
+	    uid: credentials.uid,
+	    gid: credentials.gid,
+	};
+	let ucred = unsafe {
+	    slice::from_raw_parts(&ucred as *const UCred as *const u8, mem::size_of::<UCred>())
+	};
+	if !put_cmsg(&mut control, room, SCM_CREDENTIALS, ucred) {
+	    header.flags |= MSG_CTRUNC;
+	}
+    }
+
+    let rights = received.ancillary.rights;
+    if !rights.is_empty() {
+	let available = room.saturating_sub(cmsg_align(control.len()) + cmsg_len(0)) / mem::size_of::<i32>();
+	let installed = core::cmp::min(available, rights.len());
+	if installed < rights.len() {
+	    header.flags |= MSG_CTRUNC;
+	}
+
+	if installed > 0 {
+	    let mut fd_flags = OpenFlags::empty();
+	    if flags & MSG_CMSG_CLOEXEC != 0 {
+		fd_flags |= OpenFlags::CloExec;
+	    }
+
+	    let mut fds = Vec::with_capacity(installed * mem::size_of::<i32>());
+	    for right in rights.into_iter().take(installed) {
+		// A passed descriptor keeps its access mode, but not whether the sender had it close on exec
+		let fd = process.clone().emplace_fd(process::FileDescriptor {
+		    flags: (right.flags - OpenFlags::CloExec) | fd_flags,
+		    file_handle: right.file_handle,
+		});
+		fds.extend_from_slice(&(fd as i32).to_ne_bytes());
+	    }
+	    put_cmsg(&mut control, room, SCM_RIGHTS, &fds);
+	}
+    }
+
+    if let Some(control_ptr) = control_ptr {
+	syscall_try!(memory::copy_to_user(control_ptr, &control).map_err(|_| CanonicalError::Fault));
+    }
+    header.controllen = control.len() as u32;
+
+    syscall_try!(memory::copy_value_to_user::<MsgHdr>(msg, &header).map_err(|_| CanonicalError::Fault));
+
+    // With MSG_TRUNC, the length the datagram had is returned, even if less of it was read
+    if flags & MSG_TRUNC as u64 != 0 {
+	syscall_success!(received.len as u64);
+    }
+    syscall_success!(data.len() as u64);
+}
+    
+async fn sys_inotify_init1(flags: u64) -> SyscallResult {
+    // IN_NONBLOCK and IN_CLOEXEC share their values with the open flags
+    let inotify_flags = match OpenFlags::from_bits(flags) {
+	Some(f) if (f - (OpenFlags::CloExec | OpenFlags::NonBlock)).is_empty() => f,
+	_ => syscall_err!(CanonicalError::Inval),
+    };
+
+    let process = scheduler::get_current_process();
+    let fd = process::FileDescriptor {
+	flags: inotify_flags,
+	file_handle: Arc::new(vfs::inotify::Inotify::new()),
+    };
+
+    syscall_success!(process.emplace_fd(fd));
+}
+
+async fn sys_inotify_add_watch(fd: u64, path_ptr: u64, mask: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    let mask = vfs::inotify::InotifyMask::from_bits_truncate(mask as u32);
+
+    let process = scheduler::get_current_process();
+    let inotify = match syscall_try!(process.get_file_descriptor(fd)).file_handle.as_inotify() {
+	Some(i) => i,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+
+    let vnode = if mask.contains(vfs::inotify::InotifyMask::DontFollow) {
+	syscall_try!(vfs::vfs_walk_path_nofollow(&path).await)
+    } else {
+	syscall_try!(vfs::vfs_walk_path(&path).await)
+    };
+
+    let wd = syscall_try!(inotify.add_watch(&vnode, mask));
+    syscall_success!(wd as u64);
+}
+
+async fn sys_inotify_rm_watch(fd: u64, wd: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let inotify = match syscall_try!(process.get_file_descriptor(fd)).file_handle.as_inotify() {
+	Some(i) => i,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+
+    syscall_try!(inotify.remove_watch(wd as i32));
+    syscall_success!(0);
+}
+
+async fn sys_epoll_create1(flags: u64) -> SyscallResult {
+    // EPOLL_CLOEXEC shares its value with O_CLOEXEC
+    let epoll_flags = match OpenFlags::from_bits(flags) {
+	Some(f) if (f - OpenFlags::CloExec).is_empty() => f,
+	_ => syscall_err!(CanonicalError::Inval),
+    };
+
+    let process = scheduler::get_current_process();
+    let fd = process::FileDescriptor {
+	flags: epoll_flags,
+	file_handle: Arc::new(vfs::epoll::Epoll::new()),
+    };
+
+    syscall_success!(process.emplace_fd(fd));
+}
+
+// The size is only a hint, but has to be positive
+async fn sys_epoll_create(size: u64) -> SyscallResult {
+    if size as i32 <= 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    sys_epoll_create1(0).await
+}
+
+async fn sys_epoll_ctl(epfd: u64, op: u64, fd: u64, event_ptr: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let epoll = match syscall_try!(process.get_file_descriptor(epfd)).file_handle.as_epoll() {
+	Some(e) => e,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+    if fd == epfd {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let handle = syscall_try!(process.get_file_descriptor(fd)).file_handle;
+
+    // The event is ignored when removing
+    let event = if op == vfs::epoll::EPOLL_CTL_DEL {
+	None
+    } else {
+	let event_ptr = syscall_try!(memory::validate_user_ptr(event_ptr, mem::size_of::<EpollEvent>() as u64));
+	match memory::copy_value_from_user::<EpollEvent>(event_ptr) {
+	    Ok(e) => Some((vfs::epoll::EpollEvents::from_bits_truncate(e.events), e.data)),
+	    Err(_) => syscall_err!(CanonicalError::Fault),
+	}
+    };
+
+    match (op, event) {
+	(vfs::epoll::EPOLL_CTL_ADD, Some((events, data))) => syscall_try!(epoll.add(fd, handle, events, data)),
+	(vfs::epoll::EPOLL_CTL_MOD, Some((events, data))) => syscall_try!(epoll.modify(fd, events, data)),
+	(vfs::epoll::EPOLL_CTL_DEL, _) => syscall_try!(epoll.remove(fd)),
+	_ => syscall_err!(CanonicalError::Inval),
+    }
+
+    syscall_success!(0);
+}
+
+// A negative timeout waits for as long as it takes, and zero just checks
+async fn sys_epoll_wait(epfd: u64, events_ptr: u64, max_events: u64, timeout_ms: u64) -> SyscallResult {
+    let max_events = max_events as i32;
+    if max_events <= 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let events_ptr = syscall_try!(memory::validate_user_ptr(events_ptr, max_events as u64 * mem::size_of::<EpollEvent>() as u64));
+
+    let process = scheduler::get_current_process();
+    let epoll = match syscall_try!(process.get_file_descriptor(epfd)).file_handle.as_epoll() {
+	Some(e) => e,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+
+    let timeout_ns = match timeout_ms as i32 {
+	t if t < 0 => None,
+	t => Some(t as u64 * 1_000_000),
+    };
+    let ready = epoll.wait(max_events as usize, timeout_ns).await;
+
+    let mut addr = events_ptr;
+    for (events, data) in ready.iter() {
+	let event = EpollEvent {
+	    events: events.bits(),
+	    data: *data,
+	};
+	syscall_try!(memory::copy_value_to_user::<EpollEvent>(addr, &event).map_err(|_| CanonicalError::Fault));
+	addr += mem::size_of::<EpollEvent>() as u64;
+    }
+
+    syscall_success!(ready.len() as u64);
+}
+
+fn dirent_type(kind: vfs::filesystem::VNodeKind) -> u8 {
+    match kind {
+	vfs::filesystem::VNodeKind::Fifo => 1,
+	vfs::filesystem::VNodeKind::CharDevice => 2,
+	vfs::filesystem::VNodeKind::Directory => 4,
+	vfs::filesystem::VNodeKind::BlockDevice => 6,
+	vfs::filesystem::VNodeKind::Regular => 8,
+	vfs::filesystem::VNodeKind::Symlink => 10,
+	vfs::filesystem::VNodeKind::Socket => 12,
+    }
+}
+
+async fn sys_getdents(fd_num: u64, buf: u64, count: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let actual_fd = syscall_try!(process.get_file_descriptor(fd_num));
+    let fh = actual_fd.file_handle;
+
+    let entries = syscall_try!(fh.clone().readdir().await);
+    let at_end = entries.is_empty();
+
+    // Records are laid out as struct dirent64: d_ino, d_off, d_reclen, d_type, then the NUL terminated name,
+    // padded out to 8 byte alignment
+    let mut out: Vec<u8> = Vec::new();
+    let mut last_cookie: Option<u64> = None;
+    for entry in entries {
+	let reclen = (19 + entry.name.len() + 1).next_multiple_of(8);
+	if out.len() + reclen > count as usize {
+	    break;
+	}
+
+	out.extend_from_slice(&entry.inode.to_ne_bytes());
+	out.extend_from_slice(&entry.cookie.to_ne_bytes());
+	out.extend_from_slice(&(reclen as u16).to_ne_bytes());
+	out.push(dirent_type(entry.kind));
+	out.extend_from_slice(entry.name.as_bytes());
+	out.resize(out.len() + reclen - 19 - entry.name.len(), 0);
+
+	last_cookie = Some(entry.cookie);
+    }
+
+    match last_cookie {
+	Some(cookie) => {
+	    syscall_try!(fh.seek(vfs::filesystem::SeekFrom::Set(cookie as i64)));
+	},
+	// There's at least one entry remaining, but it won't fit in the buffer
+	None if !at_end => syscall_err!(CanonicalError::Inval),
+	None => (),
+    }
+
+    syscall_try!(memory::copy_to_user(syscall_try!(memory::validate_user_ptr(buf, out.len() as u64)), out.as_slice()).map_err(|_| CanonicalError::Fault));
+    syscall_success!(out.len() as u64)
+}
+
+async fn sys_getcwd(buf: u64, count: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let cwd = process.get_cwd();
+
+    // With room for the NUL
+    if count < cwd.len() as u64 + 1 {
+	syscall_err!(CanonicalError::Range);
+    }
+
+    let buf = syscall_try!(memory::validate_user_ptr(buf, cwd.len() as u64 + 1));
+    syscall_try!(memory::copy_string_to_user(buf, cwd).map_err(|_| CanonicalError::Fault));
 
     SyscallResult {
 	return_value: 0,
@@ -754,37 +1390,718 @@ async fn sys_sigaction(signum: u64, new_sigaction: u64, old_sigaction: u64) -> S
     }
 }
 
-async fn sys_sigprocmask(how: u64, set: u64, oldset: u64) -> SyscallResult {
+async fn sys_chdir(path_ptr: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    // Only to check it's there, and is a directory
+    syscall_try!(vfs::vfs_open(&path, OpenFlags::Directory, 0).await);
+
+    scheduler::get_current_process().set_cwd(vfs::absolute_path(&path));
+
+    syscall_success!(0);
+}
+
+async fn sys_rename(old_path_ptr: u64, new_path_ptr: u64) -> SyscallResult {
+    let old_path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(old_path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    let new_path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(new_path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    syscall_try!(vfs::vfs_rename(&old_path, &new_path).await);
+    syscall_success!(0);
+}
+
+async fn sys_mkdir(path_ptr: u64, mode: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    let mode = creation_mode(mode, scheduler::get_current_process().get_umask());
+    syscall_try!(vfs::vfs_create(&path, vfs::filesystem::VNodeKind::Directory, mode).await);
+    syscall_success!(0);
+}
+
+// rmdir and unlink only differ in which kind of entry they'll remove
+async fn sys_unlink(path_ptr: u64, directory: bool) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    syscall_try!(vfs::vfs_unlink(&path, directory).await);
+    syscall_success!(0);
+}
+
+async fn sys_symlink(target_ptr: u64, path_ptr: u64) -> SyscallResult {
+    let target = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(target_ptr, 1))) {
+	Ok(target) => target,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    syscall_try!(vfs::vfs_symlink(&target, &path).await);
+    syscall_success!(0);
+}
+
+// As with Linux, the target is truncated to fit, and isn't NUL terminated
+async fn sys_readlink(path_ptr: u64, buf: u64, len: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    let vnode = syscall_try!(vfs::vfs_walk_path_nofollow(&path).await);
+    let target = syscall_try!(vnode.readlink());
+    let count = core::cmp::min(len, target.len() as u64);
+
+    let buf = syscall_try!(memory::validate_user_ptr(buf, count));
+    syscall_try!(memory::copy_to_user(buf, &target.as_bytes()[.. count as usize]).map_err(|_| CanonicalError::Fault));
+    syscall_success!(count);
+}
+
+async fn sys_fork() -> SyscallResult {
+    let pid = syscall_try!(scheduler::fork_current_process());
+    SyscallResult {
+	return_value: pid,
+	err_num: CanonicalError::Ok as u64,
+    }
+}
+
+// The child runs in our address space, on our stack, until it calls execve or exits, and we wait until it has. Our
+// registers were saved on the way in, and the child has its own copy, so we come back out of vfork where we went in,
+// however the child has used the stack since. The child mustn't return from the function which called vfork, though.
+async fn sys_vfork() -> SyscallResult {
+    let (pid, vfork_parent) = scheduler::vfork_current_process();
+    vfork_parent.wait().await;
+    syscall_success!(pid);
+}
+
+// Copies a NULL terminated array of strings, such as argv or envp
+fn copy_string_array_from_user(array_ptr: u64) -> Result<Vec<String>, CanonicalError> {
+    let mut strings: Vec<String> = Vec::new();
+    let mut entry_ptr = memory::validate_user_ptr(array_ptr, 8)?;
+    loop {
+	let string_ptr = memory::copy_value_from_user::<VirtAddr>(entry_ptr).map_err(|_| CanonicalError::Fault)?;
+	if string_ptr == VirtAddr::new(0) {
+	    break;
+	}
+
+	let string = memory::copy_string_from_user(memory::validate_user_ptr(string_ptr.as_u64(), 1)?)
+	    .map_err(|_| CanonicalError::Fault)?;
+
+	strings.push(string);
+	entry_ptr = memory::validate_user_ptr(entry_ptr.as_u64() + 8, 8)?;
+    }
+
+    Ok(strings)
+}
+
+pub async fn sys_execve(path_ptr: u64, args_ptr: u64, envvars_ptr: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    let mut args = syscall_try!(copy_string_array_from_user(args_ptr));
+    args.insert(0, path.clone());
+
+    let envvars = syscall_try!(copy_string_array_from_user(envvars_ptr));
+    let (path, args) = syscall_try!(elf_loader::resolve_interpreter(path, args).await);
+
+    log::info!("y");
+
     let process = scheduler::get_current_process();
+    log::info!("y.1");
+    process.clone().execve(args, envvars);
+    log::info!("y.2");
 
-    let newset = match memory::copy_value_from_user::<u64>(VirtAddr::new(set)) {
-	Ok(s) => s,
-	Err(_) => {
-	    return SyscallResult {
-		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-		err_num: CanonicalError::Inval as u64
+    let elf = elf_loader::Elf::new(path).await.expect("Failed to load ELF");
+    log::info!("y.3");
+    let ld = elf_loader::Elf::new(String::from("/usr/lib/ld.so")).await.expect("Failed to load ld.so");
+    log::info!("y.4");
+    let attached = process.clone().attach_loaded_elf(elf, ld);
+
+    log::info!("z");
+
+    if let Err(_e) = attached.and_then(|_| process.clone().init_stack_and_start()) {
+	return SyscallResult {
+	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
+	    err_num: CanonicalError::Io as u64,
+	};
+    }
+
+    SyscallResult {
+	return_value: 0,
+	err_num: CanonicalError::Ok as u64,
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SpawnFileAction {
+    action: u64,
+    fd: u64,
+    new_fd: u64,
+}
+
+const SPAWN_FA_CLOSE: u64 = 0;
+const SPAWN_FA_DUP2: u64 = 1;
+
+fn apply_spawn_file_action(file_descriptors: &mut BTreeMap<u64, process::FileDescriptor>, action: SpawnFileAction) -> Result<(), CanonicalError> {
+    match action.action {
+	SPAWN_FA_CLOSE => {
+	    file_descriptors.remove(&action.fd).ok_or(CanonicalError::Badf)?;
+	},
+	SPAWN_FA_DUP2 => {
+	    // As with dup2, the new descriptor stays open across the exec, even when it's the same one
+	    let mut fd = file_descriptors.get(&action.fd).cloned().ok_or(CanonicalError::Badf)?;
+	    fd.flags.remove(OpenFlags::CloExec);
+	    file_descriptors.insert(action.new_fd, fd);
+	},
+	_ => return Err(CanonicalError::Inval),
+    }
+
+    Ok(())
+}
+
+// Starts a new program as a child of the caller, as fork then execve would, but without copying the caller's address
+// space first. The file actions are applied, in order, to the child's copy of the caller's file descriptors, letting
+// the caller set up redirections.
+async fn sys_spawn(path_ptr: u64, args_ptr: u64, envvars_ptr: u64, actions_ptr: u64, n_actions: u64) -> SyscallResult {
+    let path = match memory::copy_string_from_user(syscall_try!(memory::validate_user_ptr(path_ptr, 1))) {
+	Ok(path) => path,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    let mut args = syscall_try!(copy_string_array_from_user(args_ptr));
+    args.insert(0, path.clone());
+    let envvars = syscall_try!(copy_string_array_from_user(envvars_ptr));
+    let (path, args) = syscall_try!(elf_loader::resolve_interpreter(path, args).await);
+
+    let mut file_descriptors = scheduler::get_current_process().clone_file_descriptors();
+    if n_actions > 0 {
+	let actions_len = syscall_try!(n_actions.checked_mul(mem::size_of::<SpawnFileAction>() as u64).ok_or(CanonicalError::Inval));
+	let actions_ptr = syscall_try!(memory::validate_user_ptr(actions_ptr, actions_len));
+
+	for i in 0 .. n_actions {
+	    let action = match memory::copy_value_from_user::<SpawnFileAction>(actions_ptr + i * mem::size_of::<SpawnFileAction>() as u64) {
+		Ok(a) => a,
+		Err(_) => syscall_err!(CanonicalError::Fault),
 	    };
+
+	    syscall_try!(apply_spawn_file_action(&mut file_descriptors, action));
+	}
+    }
+
+    let child = Arc::new(process::Process::new_spawned(&scheduler::get_current_process(), file_descriptors, args, envvars));
+
+    let loaded = async {
+	let elf = elf_loader::Elf::load(child.clone(), path).await?;
+	let ld = elf_loader::Elf::load(child.clone(), String::from("/usr/lib/ld.so")).await?;
+	anyhow::Ok((elf, ld))
+    }.await;
+    let (elf, ld) = match loaded {
+	Ok(l) => l,
+	Err(e) => {
+	    child.release_user_space();
+	    syscall_err!(e.downcast::<CanonicalError>().unwrap_or(CanonicalError::Io));
 	},
     };
 
-    if oldset != 0 {
-	let old_val = process.get_current_sigprocmask();
-	if memory::copy_value_to_user::<u64>(VirtAddr::new(oldset), &old_val).is_err() {
-	    return SyscallResult {
-		return_value: 0xFFFF_FFFF_FFFF_FFFF,
-		err_num: CanonicalError::Inval as u64
-	    };
+    let started = child.clone().attach_loaded_elf(elf, ld)
+	.and_then(|_| child.clone().init_stack_and_start());
+    if started.is_err() {
+	child.release_user_space();
+	syscall_err!(CanonicalError::Io);
+    }
+
+    syscall_success!(scheduler::add_spawned_process(child));
+}
+
+async fn sys_getpid() -> SyscallResult {
+    let pid = scheduler::get_current_pid();
+    SyscallResult {
+	return_value: pid,
+	err_num: CanonicalError::Ok as u64,
+    }
+}
+
+async fn sys_getuid() -> SyscallResult {
+    syscall_success!(scheduler::get_current_process().get_credentials().uid as u64);
+}
+
+async fn sys_geteuid() -> SyscallResult {
+    syscall_success!(scheduler::get_current_process().get_credentials().euid as u64);
+}
+
+async fn sys_getgid() -> SyscallResult {
+    syscall_success!(scheduler::get_current_process().get_credentials().gid as u64);
+}
+
+async fn sys_getegid() -> SyscallResult {
+    syscall_success!(scheduler::get_current_process().get_credentials().egid as u64);
+}
+
+async fn sys_setuid(uid: u64) -> SyscallResult {
+    syscall_try!(scheduler::get_current_process().set_uid(uid as u32));
+    syscall_success!(0);
+}
+
+async fn sys_setgid(gid: u64) -> SyscallResult {
+    syscall_try!(scheduler::get_current_process().set_gid(gid as u32));
+    syscall_success!(0);
+}
+
+// There are no threads yet, so every process is its own only thread
+async fn sys_gettid() -> SyscallResult {
+    sys_getpid().await
+}
+
+fn rusage(usage: &process::ResourceUsage) -> RUsage {
+    // All of a process' time is spent in userspace, as syscalls are run by the scheduler rather than on its behalf
+    RUsage {
+	ru_utime: TimeVal {
+	    tv_sec: (usage.cpu_time_ms / 1000) as i64,
+	    tv_usec: ((usage.cpu_time_ms % 1000) * 1000) as i64,
+	},
+	ru_maxrss: (usage.peak_rss_pages * 4) as i64,
+	ru_nivcsw: usage.involuntary_switches as i64,
+	..RUsage::default()
+    }
+}
+
+async fn sys_wait4(pid: u64, status_ptr: u64, options: u64, rusage_ptr: u64) -> SyscallResult {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let wait_options = scheduler::WaitOptions {
+	nohang: options & WNOHANG != 0,
+	stopped: options & WUNTRACED != 0,
+	continued: options & WCONTINUED != 0,
+    };
+
+    let process = scheduler::get_current_process();
+    let selector = match pid as i64 {
+	-1 => scheduler::WaitSelector::Any,
+	0 => scheduler::WaitSelector::Group(process.get_pgid()),
+	p if p < 0 => scheduler::WaitSelector::Group(p.unsigned_abs()),
+	p => scheduler::WaitSelector::Pid(p as u64),
+    };
+    let ppid = scheduler::get_current_pid();
+
+    let (child_pid, exited) = match syscall_try!(scheduler::wait_child(ppid, selector, wait_options).await) {
+	Some(r) => r,
+	None => syscall_success!(0),
+    };
+
+    if status_ptr != 0 {
+	let status_ptr = syscall_try!(memory::validate_user_ptr(status_ptr, mem::size_of::<i32>() as u64));
+	if memory::copy_value_to_user::<i32>(status_ptr, &exited.status).is_err() {
+	    syscall_err!(CanonicalError::Fault);
 	}
     }
 
-    match how {
-	1 => process.signal_mask_block(newset),
-	2 => process.signal_mask_unblock(newset),
-	3 => process.signal_mask_setmask(newset),
-	_ => return SyscallResult {
-	    return_value: 0xFFFF_FFFF_FFFF_FFFF,
-	    err_num: CanonicalError::Inval as u64,
+    if rusage_ptr != 0 {
+	let rusage = rusage(&exited.usage);
+	let rusage_ptr = syscall_try!(memory::validate_user_ptr(rusage_ptr, mem::size_of::<RUsage>() as u64));
+	if memory::copy_value_to_user::<RUsage>(rusage_ptr, &rusage).is_err() {
+	    syscall_err!(CanonicalError::Fault);
+	}
+    }
+
+    syscall_success!(child_pid);
+}
+
+async fn sys_getppid() -> SyscallResult {
+    // We don't yet support process parentage
+    SyscallResult {
+	return_value: 0,
+	err_num: CanonicalError::Ok as u64,
+    }
+}
+
+// A PID of 0 means the calling process, for all of the group and session syscalls
+fn process_or_current(pid: u64) -> Result<Arc<process::Process>, CanonicalError> {
+    if pid == 0 {
+	Ok(scheduler::get_current_process())
+    } else {
+	scheduler::get_process_by_id(pid).ok_or(CanonicalError::Srch)
+    }
+}
+
+// CPU sets are a single u64 for now, which covers far more CPUs than we bring up
+async fn sys_sched_setaffinity(pid: u64, len: u64, mask_ptr: u64) -> SyscallResult {
+    if len < mem::size_of::<u64>() as u64 {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let process = syscall_try!(process_or_current(pid));
+    let mask: u64 = syscall_try!(memory::copy_value_from_user(syscall_try!(memory::validate_user_ptr(mask_ptr, mem::size_of::<u64>() as u64))).map_err(|_| CanonicalError::Fault));
+
+    // The process has to be left somewhere it can actually run
+    if mask & scheduler::online_cpu_mask() == 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    process.set_cpu_set(mask);
+    syscall_success!(0);
+}
+
+async fn sys_sched_getaffinity(pid: u64, len: u64, mask_ptr: u64) -> SyscallResult {
+    if len < mem::size_of::<u64>() as u64 {
+	syscall_err!(CanonicalError::Inval);
+    }
+    let process = syscall_try!(process_or_current(pid));
+    let mask = process.get_cpu_set() & scheduler::online_cpu_mask();
+
+    syscall_try!(memory::copy_to_user(syscall_try!(memory::validate_user_ptr(mask_ptr, mem::size_of::<u64>() as u64)), &mask.to_ne_bytes()).map_err(|_| CanonicalError::Fault));
+    syscall_success!(mem::size_of::<u64>() as u64);
+}
+
+async fn sys_getpgid(pid: u64) -> SyscallResult {
+    let process = syscall_try!(process_or_current(pid));
+    syscall_success!(process.get_pgid());
+}
+
+async fn sys_setpgid(pid: u64, pgid: u64) -> SyscallResult {
+    let caller_pid = scheduler::get_current_pid();
+    let pid = if pid == 0 { caller_pid } else { pid };
+    let pgid = if pgid == 0 { pid } else { pgid };
+
+    syscall_try!(scheduler::set_process_group(caller_pid, pid, pgid));
+    syscall_success!(0);
+}
+
+async fn sys_getpgrp() -> SyscallResult {
+    syscall_success!(scheduler::get_current_process().get_pgid());
+}
+
+async fn sys_getsid(pid: u64) -> SyscallResult {
+    let process = syscall_try!(process_or_current(pid));
+    syscall_success!(process.get_sid());
+}
+
+async fn sys_setsid() -> SyscallResult {
+    let sid = syscall_try!(scheduler::create_session(scheduler::get_current_pid()));
+    syscall_success!(sid);
+}
+
+async fn sys_umask(mask: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    // Only the permission bits mean anything, so the rest are dropped rather than rejected, as Linux does
+    syscall_success!(process.set_umask(mask as u32) as u64);
+}
+
+async fn sys_clock_gettime(clock_id: u64, tp: u64) -> SyscallResult {
+    let now = match clock_id {
+	CLOCK_REALTIME => time::get_realtime_ns(),
+	CLOCK_MONOTONIC => time::get_monotonic_ns(),
+	_ => syscall_err!(CanonicalError::Inval),
+    };
+
+    let timespec = TimeSpec {
+	tv_sec: (now / time::NANOSECONDS_PER_SECOND) as i64,
+	tv_nsec: (now % time::NANOSECONDS_PER_SECOND) as i64,
+    };
+    let tp = syscall_try!(memory::validate_user_ptr(tp, mem::size_of::<TimeSpec>() as u64));
+    if memory::copy_value_to_user::<TimeSpec>(tp, &timespec).is_err() {
+	syscall_err!(CanonicalError::Fault);
+    }
+
+    syscall_success!(0);
+}
+
+// Nothing interrupts a sleep yet, so rem is never written
+async fn sys_nanosleep(req: u64, _rem: u64) -> SyscallResult {
+    let req = syscall_try!(memory::validate_user_ptr(req, mem::size_of::<TimeSpec>() as u64));
+    let timespec = match memory::copy_value_from_user::<TimeSpec>(req) {
+	Ok(t) => t,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    if timespec.tv_sec < 0 || !(0..time::NANOSECONDS_PER_SECOND as i64).contains(&timespec.tv_nsec) {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    let time_ns = (timespec.tv_sec as u64).saturating_mul(time::NANOSECONDS_PER_SECOND).saturating_add(timespec.tv_nsec as u64);
+    if time_ns > 0 {
+	time::sleep_ns(time_ns).await;
+    }
+
+    syscall_success!(0);
+}
+
+// Only the realtime clock can be set, and the RTC is updated to match so the time survives a reboot. Only root may do
+// this.
+// Nanoseconds since the epoch, from whole seconds and the nanoseconds past them. None for times too far off to
+// count in nanoseconds
+fn realtime_ns(secs: i64, nsecs: u64) -> Option<u64> {
+    u64::try_from(secs).ok()?.checked_mul(time::NANOSECONDS_PER_SECOND)?.checked_add(nsecs)
+}
+
+async fn sys_clock_settime(clock_id: u64, tp: u64) -> SyscallResult {
+    if clock_id != CLOCK_REALTIME {
+	syscall_err!(CanonicalError::Inval);
+    }
+    if !scheduler::get_current_process().get_credentials().is_privileged() {
+	syscall_err!(CanonicalError::Perm);
+    }
+
+    let tp = syscall_try!(memory::validate_user_ptr(tp, mem::size_of::<TimeSpec>() as u64));
+    let timespec = match memory::copy_value_from_user::<TimeSpec>(tp) {
+	Ok(t) => t,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    if timespec.tv_sec < 0 || !(0..time::NANOSECONDS_PER_SECOND as i64).contains(&timespec.tv_nsec) {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    let now = match realtime_ns(timespec.tv_sec, timespec.tv_nsec as u64) {
+	Some(ns) => ns,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+    time::set_realtime_ns(now);
+    rtc::write_time(timespec.tv_sec as u64);
+
+    syscall_success!(0);
+}
+
+// Only root may reboot the system
+async fn sys_reboot(magic1: u64, magic2: u64, cmd: u64, _arg: u64) -> SyscallResult {
+    if magic1 != LINUX_REBOOT_MAGIC1 || !LINUX_REBOOT_MAGIC2.contains(&magic2) {
+	syscall_err!(CanonicalError::Inval);
+    }
+    if !scheduler::get_current_process().get_credentials().is_privileged() {
+	syscall_err!(CanonicalError::Perm);
+    }
+
+    let action = match cmd {
+	LINUX_REBOOT_CMD_RESTART => power::PowerAction::Reboot,
+	LINUX_REBOOT_CMD_HALT => power::PowerAction::Halt,
+	LINUX_REBOOT_CMD_POWER_OFF => power::PowerAction::PowerOff,
+	// Ctrl-Alt-Del isn't acted on either way
+	LINUX_REBOOT_CMD_CAD_ON | LINUX_REBOOT_CMD_CAD_OFF => syscall_success!(0),
+	_ => syscall_err!(CanonicalError::Inval),
+    };
+
+    power::shutdown(action, scheduler::get_current_pid()).await;
+    unreachable!("System still running after shutdown");
+}
+
+async fn sys_syslog(action: u64, buf: u64, len: u64) -> SyscallResult {
+    match action {
+	SYSLOG_ACTION_READ_ALL => {
+	    if (len as i64) < 0 {
+		syscall_err!(CanonicalError::Inval);
+	    }
+
+	    let text = kmsg::read_all(len as usize);
+	    if text.is_empty() {
+		syscall_success!(0);
+	    }
+	    let buf = syscall_try!(memory::validate_user_ptr(buf, text.len() as u64));
+	    if memory::copy_to_user(buf, &text).is_err() {
+		syscall_err!(CanonicalError::Fault);
+	    }
+	    syscall_success!(text.len() as u64);
 	},
+	SYSLOG_ACTION_SIZE_BUFFER => syscall_success!(kmsg::buffer_size()),
+	_ => syscall_err!(CanonicalError::Inval),
+    }
+}
+
+// There's only the one pool, and it's seeded before anything can ask, so none of the flags change anything: it never
+// blocks, and GRND_RANDOM draws from the same place as everything else
+async fn sys_getrandom(buf: u64, len: u64, flags: u64) -> SyscallResult {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 || flags & (GRND_RANDOM | GRND_INSECURE) == GRND_RANDOM | GRND_INSECURE {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    let len = len.min(random::MAX_READ);
+    if len == 0 {
+	syscall_success!(0);
+    }
+    let buf = syscall_try!(memory::validate_user_ptr(buf, len));
+
+    let mut bytes = alloc::vec![0; len as usize];
+    random::fill(&mut bytes);
+    if memory::copy_to_user(buf, &bytes).is_err() {
+	syscall_err!(CanonicalError::Fault);
+    }
+
+    syscall_success!(len);
+}
+
+async fn sys_membarrier(cmd: u64, flags: u64, _cpu_id: u64) -> SyscallResult {
+    if flags != 0 {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    match cmd {
+	MEMBARRIER_CMD_QUERY => syscall_success!(MEMBARRIER_SUPPORTED_CMDS),
+	// Every process takes global expedited barriers, so there's nothing to register for them
+	MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED => syscall_success!(0),
+	MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED => {
+	    scheduler::get_current_process().register_membarrier();
+	    syscall_success!(0);
+	},
+	MEMBARRIER_CMD_GLOBAL | MEMBARRIER_CMD_GLOBAL_EXPEDITED | MEMBARRIER_CMD_PRIVATE_EXPEDITED => {
+	    if cmd == MEMBARRIER_CMD_PRIVATE_EXPEDITED && !scheduler::get_current_process().is_membarrier_registered() {
+		syscall_err!(CanonicalError::Perm);
+	    }
+
+	    // Every other CPU is interrupted, rather than only those running this address space for the private
+	    // command, which is more than is needed but never less
+	    atomic::fence(atomic::Ordering::SeqCst);
+	    scheduler::wait_for_other_cpus().await;
+	    syscall_success!(0);
+	},
+	_ => syscall_err!(CanonicalError::Inval),
+    }
+}
+
+// The same clock as CLOCK_REALTIME, to the microsecond. The timezone is obsolete, and left alone.
+async fn sys_gettimeofday(tv: u64, _tz: u64) -> SyscallResult {
+    if tv == 0 {
+	syscall_success!(0);
+    }
+
+    let now = time::get_realtime_ns();
+    let timeval = TimeVal {
+	tv_sec: (now / time::NANOSECONDS_PER_SECOND) as i64,
+	tv_usec: (now % time::NANOSECONDS_PER_SECOND / 1000) as i64,
+    };
+    let tv = syscall_try!(memory::validate_user_ptr(tv, mem::size_of::<TimeVal>() as u64));
+    if memory::copy_value_to_user::<TimeVal>(tv, &timeval).is_err() {
+	syscall_err!(CanonicalError::Fault);
+    }
+
+    syscall_success!(0);
+}
+
+async fn sys_settimeofday(tv: u64, _tz: u64) -> SyscallResult {
+    if !scheduler::get_current_process().get_credentials().is_privileged() {
+	syscall_err!(CanonicalError::Perm);
+    }
+
+    // The timezone is obsolete, and ignored
+    if tv == 0 {
+	syscall_success!(0);
+    }
+
+    let tv = syscall_try!(memory::validate_user_ptr(tv, mem::size_of::<TimeVal>() as u64));
+    let timeval = match memory::copy_value_from_user::<TimeVal>(tv) {
+	Ok(t) => t,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+    if timeval.tv_sec < 0 || !(0..1_000_000).contains(&timeval.tv_usec) {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    let now = match realtime_ns(timeval.tv_sec, timeval.tv_usec as u64 * 1000) {
+	Some(ns) => ns,
+	None => syscall_err!(CanonicalError::Inval),
+    };
+    time::set_realtime_ns(now);
+    rtc::write_time(timeval.tv_sec as u64);
+
+    syscall_success!(0);
+}
+
+async fn sys_tcb_set(new_fs: u64) -> SyscallResult {
+    if new_fs >= memory::USER_ADDRESS_LIMIT {
+	syscall_err!(CanonicalError::Perm);
+    }
+
+    let process = scheduler::get_current_process();
+    process.set_fs_base(new_fs);  // Loaded into the MSR on the way back out to userspace
+
+    SyscallResult {
+	return_value: 0,
+	err_num: 0,
+    }
+}
+
+async fn sys_arch_prctl(operation: u64, addr: u64) -> SyscallResult {
+    let op = match ArchPrctlOperation::try_from(operation) {
+	Ok(v) => v,
+	Err(_) => syscall_err!(CanonicalError::Inval),
+    };
+
+    let process = scheduler::get_current_process();
+    match op {
+	ArchPrctlOperation::SetFs | ArchPrctlOperation::SetGs => {
+	    if addr >= memory::USER_ADDRESS_LIMIT {
+		syscall_err!(CanonicalError::Perm);
+	    }
+
+	    if let ArchPrctlOperation::SetFs = op {
+		process.set_fs_base(addr);
+	    } else {
+		process.set_gs_base(addr);
+	    }
+	},
+	ArchPrctlOperation::GetFs | ArchPrctlOperation::GetGs => {
+	    let base = if let ArchPrctlOperation::GetFs = op {
+		process.get_fs_base()
+	    } else {
+		process.get_gs_base()
+	    };
+
+	    let addr = syscall_try!(memory::validate_user_ptr(addr, mem::size_of::<u64>() as u64));
+	    if memory::copy_value_to_user::<u64>(addr, &base).is_err() {
+		syscall_err!(CanonicalError::Fault);
+	    }
+	},
+    }
+
+    syscall_success!(0);
+}
+
+async fn sys_sigaction(signum: u64, new_sigaction: u64, old_sigaction: u64) -> SyscallResult {
+    if signum == 0 || signum > 64 || (new_sigaction != 0 && signal::UNBLOCKABLE_MASK & (1 << (signum - 1)) != 0) {
+	syscall_err!(CanonicalError::Inval);
+    }
+
+    let process = scheduler::get_current_process();
+    if old_sigaction != 0 {
+	if let Some(signal) = process.get_current_signal_handler(signum) {
+	    let sigaction = signal::create_sigaction(signal);
+
+	    match memory::copy_value_to_user::<signal::SigAction>(
+		syscall_try!(memory::validate_user_ptr(old_sigaction, mem::size_of::<signal::SigAction>() as u64)), &sigaction) {
+		Ok(()) => (),
+		Err(_) => {
+		    return SyscallResult {
+			return_value: 0xFFFF_FFFF_FFFF_FFFF,
+			err_num: CanonicalError::Inval as u64
+		    };
+		},
+	    }
+	}
+    }
+
+    if new_sigaction != 0 {
+	let new_sigaction = syscall_try!(memory::validate_user_ptr(new_sigaction, mem::size_of::<signal::SigAction>() as u64));
+	let sa = match memory::copy_value_from_user::<signal::SigAction>(new_sigaction) {
+	    Ok(s) => s,
+	    Err(_) => {
+		return SyscallResult {
+		    return_value: 0xFFFF_FFFF_FFFF_FFFF,
+		    err_num: CanonicalError::Inval as u64
+		};
+	    },
+	};
+
+	let signal_handler = signal::parse_sigaction(sa);
+	process.install_signal_handler(signum, signal_handler);
     }
 
     SyscallResult {
@@ -793,11 +2110,98 @@ async fn sys_sigprocmask(how: u64, set: u64, oldset: u64) -> SyscallResult {
     }
 }
 
-fn do_syscall(rax: u64, rdi: u64, rsi: u64, rdx: u64, _r10: u64, r8: u64, _r9: u64) -> Pin<Box<dyn Future<Output = SyscallResult> + Send + 'static>> {
+// Any signals this unblocks which are already pending get delivered on the way back out to userspace
+async fn sys_sigprocmask(how: u64, set: u64, oldset: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+
+    // Checked up front, so that the mask isn't changed if the old one can't be handed back
+    let oldset = if oldset != 0 {
+	Some(syscall_try!(memory::validate_user_ptr(oldset, mem::size_of::<u64>() as u64)))
+    } else {
+	None
+    };
+
+    let old_val = if set == 0 {
+	// Just querying the mask
+	process.get_current_sigprocmask()
+    } else {
+	let newset = match memory::copy_value_from_user::<u64>(syscall_try!(memory::validate_user_ptr(set, mem::size_of::<u64>() as u64))) {
+	    Ok(s) => s,
+	    Err(_) => syscall_err!(CanonicalError::Fault),
+	};
+
+	match how {
+	    SIG_BLOCK => process.clone().signal_mask_block(newset),
+	    SIG_UNBLOCK => process.clone().signal_mask_unblock(newset),
+	    SIG_SETMASK => process.clone().signal_mask_setmask(newset),
+	    _ => syscall_err!(CanonicalError::Inval),
+	}
+    };
+
+    if let Some(oldset) = oldset {
+	if memory::copy_value_to_user::<u64>(oldset, &old_val).is_err() {
+	    syscall_err!(CanonicalError::Fault);
+	}
+    }
+
+    syscall_success!(0);
+}
+
+async fn wait_for_signal(process: Arc<process::Process>) {
+    poll_fn(|cx| {
+	// Register first, so a signal posted between the check and sleeping still wakes us
+	process.set_signal_waker(cx.waker().clone());
+	if process.has_deliverable_signal() {
+	    Poll::Ready(())
+	} else {
+	    Poll::Pending
+	}
+    }).await
+}
+
+// The mask is swapped and the wait begun without returning to userspace in between, so no signal can be missed. The
+// handler runs with the temporary mask, and the original comes back when it returns.
+async fn sys_sigsuspend(set: u64) -> SyscallResult {
+    let process = scheduler::get_current_process();
+    let newset = match memory::copy_value_from_user::<u64>(syscall_try!(memory::validate_user_ptr(set, mem::size_of::<u64>() as u64))) {
+	Ok(s) => s,
+	Err(_) => syscall_err!(CanonicalError::Fault),
+    };
+
+    process.clone().signal_mask_suspend(newset);
+    wait_for_signal(process).await;
+
+    // Only ever returns once interrupted
+    syscall_err!(CanonicalError::Intr);
+}
+
+async fn sys_pause() -> SyscallResult {
+    let process = scheduler::get_current_process();
+    wait_for_signal(process).await;
+
+    syscall_err!(CanonicalError::Intr);
+}
+
+// Called by the restorer once a signal handler returns. This puts back the registers from before the handler ran,
+// which we do by "returning" the old rax and rdx, as those are what the scheduler sets on syscall return.
+async fn sys_sigreturn() -> SyscallResult {
+    let process = scheduler::get_current_process();
+
+    match process.return_from_signal_handler() {
+	Ok(gprs) => SyscallResult {
+	    return_value: gprs.rax,
+	    err_num: gprs.rdx,
+	},
+	// The frame's gone, so there's nothing we could return to
+	Err(_) => scheduler::exit_by_signal(signal::SIGKILL),
+    }
+}
+
+fn do_syscall(rax: u64, rdi: u64, rsi: u64, rdx: u64, r10: u64, r8: u64, _r9: u64) -> Pin<Box<dyn Future<Output = SyscallResult> + Send + 'static>> {
     match rax {
 	0x00 => Box::pin(sys_write(rdi, rsi, rdx)),
 	0x01 => Box::pin(sys_read(rdi, rsi, rdx)),
-	0x02 => Box::pin(sys_open(rdi, rsi)),
+	0x02 => Box::pin(sys_open(rdi, rsi, rdx)),
 	0x03 => Box::pin(sys_close(rdi)),
 	0x04 => Box::pin(sys_ioctl(rdi, rsi, rdx)),
 	0x05 => Box::pin(sys_stat(rdi, rsi)),
@@ -809,15 +2213,68 @@ fn do_syscall(rax: u64, rdi: u64, rsi: u64, rdx: u64, _r10: u64, r8: u64, _r9: u
 	0x0b => Box::pin(sys_fstat(rdi, rsi)),
 	0x0c => scheduler::exit(rdi),  // Doesn't return, so no need for async fn here
 	0x0d => Box::pin(sys_poll(rdi, rsi, rdx)),
+	0x0e => Box::pin(sys_getdents(rdi, rsi, rdx)),
+	0x0f => Box::pin(sys_fstatat(rdi, rsi, rdx, r10)),
 	0x10 => Box::pin(sys_sigaction(rdi, rsi, rdx)),
 	0x11 => Box::pin(sys_sigprocmask(rdi, rsi, rdx)),
+	0x12 => Box::pin(sys_sigreturn()),
+	0x13 => Box::pin(sys_sigsuspend(rdi)),
+	0x14 => Box::pin(sys_pause()),
 	0x20 => Box::pin(sys_getcwd(rdi, rsi)),
+	0x23 => Box::pin(sys_nanosleep(rdi, rsi)),
+	0x2e => Box::pin(sys_sendmsg(rdi, rsi, rdx)),
+	0x2f => Box::pin(sys_recvmsg(rdi, rsi, rdx)),
+	0x35 => Box::pin(sys_socketpair(rdi, rsi, rdx, r10)),
 	0x39 => Box::pin(sys_fork()),
+	0x3a => Box::pin(sys_vfork()),
 	0x3b => Box::pin(sys_execve(rdi, rsi, rdx)),
 	0x3c => Box::pin(sys_getpid()),
 	0x3d => Box::pin(sys_getppid()),
-	0x3e => Box::pin(sys_getpgid()),
+	0x3e => Box::pin(sys_getpgid(rdi)),
+	0x3f => Box::pin(sys_wait4(rdi, rsi, rdx, r10)),
+	0x4a => Box::pin(sys_fsync(rdi)),
+	0x4b => Box::pin(sys_fsync(rdi)),
+	0x50 => Box::pin(sys_chdir(rdi)),
+	0x52 => Box::pin(sys_rename(rdi, rsi)),
+	0x53 => Box::pin(sys_mkdir(rdi, rsi)),
+	0x54 => Box::pin(sys_unlink(rdi, true)),
+	0x57 => Box::pin(sys_unlink(rdi, false)),
+	0x58 => Box::pin(sys_symlink(rdi, rsi)),
+	0x59 => Box::pin(sys_readlink(rdi, rsi, rdx)),
+	0x5f => Box::pin(sys_umask(rdi)),
+	0x60 => Box::pin(sys_gettimeofday(rdi, rsi)),
+	0x66 => Box::pin(sys_getuid()),
+	0x67 => Box::pin(sys_syslog(rdi, rsi, rdx)),
+	0x68 => Box::pin(sys_getgid()),
+	0x69 => Box::pin(sys_setuid(rdi)),
+	0x6a => Box::pin(sys_setgid(rdi)),
+	0x6b => Box::pin(sys_geteuid()),
+	0x6c => Box::pin(sys_getegid()),
+	0x6d => Box::pin(sys_setpgid(rdi, rsi)),
+	0x6f => Box::pin(sys_getpgrp()),
+	0x70 => Box::pin(sys_setsid()),
+	0x7c => Box::pin(sys_getsid(rdi)),
+	0x9e => Box::pin(sys_arch_prctl(rdi, rsi)),
+	0xa2 => Box::pin(sys_sync()),
+	0xa4 => Box::pin(sys_settimeofday(rdi, rsi)),
+	0xa9 => Box::pin(sys_reboot(rdi, rsi, rdx, r10)),
+	0xba => Box::pin(sys_gettid()),
+	0xcb => Box::pin(sys_sched_setaffinity(rdi, rsi, rdx)),
+	0xcc => Box::pin(sys_sched_getaffinity(rdi, rsi, rdx)),
+	0xd5 => Box::pin(sys_epoll_create(rdi)),
+	0xe3 => Box::pin(sys_clock_settime(rdi, rsi)),
+	0xe4 => Box::pin(sys_clock_gettime(rdi, rsi)),
+	0xe8 => Box::pin(sys_epoll_wait(rdi, rsi, rdx, r10)),
+	0xe9 => Box::pin(sys_epoll_ctl(rdi, rsi, rdx, r10)),
+	0xfd => Box::pin(sys_inotify_init1(0)),
+	0xfe => Box::pin(sys_inotify_add_watch(rdi, rsi, rdx)),
+	0xff => Box::pin(sys_inotify_rm_watch(rdi, rsi)),
+	0x123 => Box::pin(sys_epoll_create1(rdi)),
+	0x126 => Box::pin(sys_inotify_init1(rdi)),
 	0x12c => Box::pin(sys_tcb_set(rdi)),
+	0x12d => Box::pin(sys_spawn(rdi, rsi, rdx, r10, r8)),
+	0x13e => Box::pin(sys_getrandom(rdi, rsi, rdx)),
+	0x144 => Box::pin(sys_membarrier(rdi, rsi, rdx)),
 	_ => panic!("Invalid syscall 0x{:X}", rax),
     }
 }
@@ -955,3 +2412,114 @@ pub unsafe fn do_syscall6(
     );
     (ret, err)
 }
+
+#[test]
+fn created_files_lose_umask_bits() {
+    use crate::fs::tmpfs::TmpFs;
+    use crate::vfs::filesystem::{FileSystem, FileSystemInstance, VNodeKind};
+
+    let fs = Arc::new(TmpFs::new());
+    let root = fs.clone().root(FileSystemInstance(0x1984));
+    let file = fs.create(FileSystemInstance(0x1984), &root, "f", VNodeKind::Regular, creation_mode(0o666, 0o022))
+	.now_or_never().unwrap().unwrap();
+
+    assert_eq!(file.stat().unwrap().mode, 0o644);
+    assert_eq!(creation_mode(0o4777, 0o077), 0o4700);
+}
+
+#[test]
+fn realtime_ns_rejects_times_out_of_range() {
+    assert_eq!(realtime_ns(1_700_000_000, 5), Some(1_700_000_000_000_000_005));
+    assert_eq!(realtime_ns(-1, 0), None);
+    assert_eq!(realtime_ns(i64::MAX, 0), None);
+    assert_eq!(realtime_ns((u64::MAX / time::NANOSECONDS_PER_SECOND) as i64, 999_999_999), None);
+}
+
+#[test]
+fn rusage_reports_cpu_time_as_user_time() {
+    let usage = process::ResourceUsage {
+	cpu_time_ms: 2_345,
+	peak_rss_pages: 10,
+	involuntary_switches: 7,
+    };
+    let rusage = rusage(&usage);
+
+    assert_eq!((rusage.ru_utime.tv_sec, rusage.ru_utime.tv_usec), (2, 345_000));
+    assert_eq!((rusage.ru_stime.tv_sec, rusage.ru_stime.tv_usec), (0, 0));
+    assert_eq!(rusage.ru_maxrss, 40);
+    assert_eq!(rusage.ru_nivcsw, 7);
+}
+
+#[test]
+fn sigsuspend_wakes_for_a_signal_it_unblocks() {
+    use core::task::Context;
+    use futures_util::task::ArcWake;
+    use crate::scheduler::signal;
+
+    struct Woken(atomic::AtomicBool);
+    impl ArcWake for Woken {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+	    arc_self.0.store(true, atomic::Ordering::SeqCst);
+	}
+    }
+
+    let process = Arc::new(process::Process::new_kernel(process::ProcessContext::default()));
+    let term = 1 << (signal::SIGTERM - 1);
+
+    // Raised while blocked, so it's only the swap to the suspend mask that lets it through. Were the swap and the wait
+    // not one step, this is the signal that would be missed
+    process.clone().signal_mask_block(term);
+    process.post_signal(signal::SIGTERM);
+    process.clone().signal_mask_suspend(0);
+    assert!(wait_for_signal(process.clone()).now_or_never().is_some());
+
+    // Once it's been delivered, the mask from before sigsuspend comes back
+    assert!(matches!(process.clone().deliver_pending_signal(), signal::Delivery::Terminate(signal::SIGTERM)));
+    assert!(matches!(process.clone().deliver_pending_signal(), signal::Delivery::None));
+    assert_eq!(process.get_current_sigprocmask(), term);
+
+    // Raised while waiting
+    let woken = Arc::new(Woken(atomic::AtomicBool::new(false)));
+    let waker = futures_util::task::waker(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    process.clone().signal_mask_suspend(0);
+    let mut wait = Box::pin(wait_for_signal(process.clone()));
+    assert!(wait.as_mut().poll(&mut cx).is_pending());
+
+    process.post_signal(signal::SIGTERM);
+    assert!(woken.0.load(atomic::Ordering::SeqCst));
+    assert!(wait.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test]
+fn spawn_dup2_redirects_the_childs_descriptor() {
+    let pipe = Arc::new(vfs::fifo::Fifo::new());
+    let fd = |flags: OpenFlags| process::FileDescriptor {
+	file_handle: pipe.clone().open().unwrap(),
+	flags,
+    };
+    let action = |action: u64, fd: u64, new_fd: u64| SpawnFileAction {
+	action,
+	fd,
+	new_fd,
+    };
+
+    // The write end of a pipe, which the parent didn't want its other children to inherit
+    let mut file_descriptors = BTreeMap::new();
+    file_descriptors.insert(1, fd(OpenFlags::empty()));
+    file_descriptors.insert(4, fd(OpenFlags::CloExec.with_access_mode(AccessMode::WrOnly)));
+    let pipe_end = file_descriptors[&4].file_handle.clone();
+
+    // As for cmd > pipe
+    apply_spawn_file_action(&mut file_descriptors, action(SPAWN_FA_DUP2, 4, 1)).unwrap();
+    apply_spawn_file_action(&mut file_descriptors, action(SPAWN_FA_CLOSE, 4, 0)).unwrap();
+
+    assert!(Arc::ptr_eq(&file_descriptors[&1].file_handle, &pipe_end));
+    assert_eq!(file_descriptors[&1].flags, OpenFlags::empty().with_access_mode(AccessMode::WrOnly));
+    assert!(!file_descriptors.contains_key(&4));
+
+    assert!(matches!(apply_spawn_file_action(&mut file_descriptors, action(SPAWN_FA_DUP2, 4, 2)), Err(CanonicalError::Badf)));
+    assert!(matches!(apply_spawn_file_action(&mut file_descriptors, action(SPAWN_FA_CLOSE, 4, 0)), Err(CanonicalError::Badf)));
+    assert!(matches!(apply_spawn_file_action(&mut file_descriptors, action(2, 1, 2)), Err(CanonicalError::Inval)));
+    assert!(!file_descriptors.contains_key(&2));
+}
@@ -0,0 +1,41 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::hpet;
+use crate::utils::completion::Completion;
+
+pub const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+// Wall clock time at boot, in nanoseconds since the Unix epoch. The realtime clock is this plus the uptime, so
+// setting the time just moves this.
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+
+pub fn get_monotonic_ns() -> u64 {
+    hpet::now_ns()
+}
+
+pub fn get_realtime_ns() -> u64 {
+    BOOT_TIME_NS.load(Ordering::Relaxed) + get_monotonic_ns()
+}
+
+pub fn set_realtime_ns(now_ns: u64) {
+    BOOT_TIME_NS.store(now_ns.saturating_sub(get_monotonic_ns()), Ordering::Relaxed);
+}
+
+pub async fn sleep_ms(time_ms: u64) {
+    sleep_ns(time_ms.saturating_mul(1_000_000)).await;
+}
+
+// Sleeps for at least time_ns. Waking up again is down to the timer interrupt, so it may be a little longer.
+pub async fn sleep_ns(time_ns: u64) {
+    let timer = Arc::new(Completion::new());
+    let timer_cb = timer.clone();
+    let deadline = hpet::now_ticks().saturating_add(hpet::ns_to_ticks(time_ns));
+    if hpet::add_oneshot(deadline, Box::new(move || timer_cb.complete(()))).is_none() {
+	log::warn!("No timer free, not waiting");
+	return;
+    }
+
+    timer.wait().await;
+}
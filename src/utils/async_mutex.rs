@@ -0,0 +1,149 @@
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::future::{poll_fn, Future};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+// A mutex which can be held across an await. Tasks which find it locked park themselves until it's released rather
+// than spinning, so a driver can hold it for the whole of a command (including waiting for the device to finish)
+// without blocking anything else on the CPU.
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    // Tasks waiting for the lock, oldest first
+    waiters: Mutex<VecDeque<Waker>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutexGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for AsyncMutexGuard<'_, T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(data: T) -> Self {
+	AsyncMutex {
+	    locked: AtomicBool::new(false),
+	    waiters: Mutex::new(VecDeque::new()),
+	    data: UnsafeCell::new(data),
+	}
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+	if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+	    Some(AsyncMutexGuard { mutex: self })
+	} else {
+	    None
+	}
+    }
+
+    pub fn lock(&self) -> impl Future<Output = AsyncMutexGuard<'_, T>> {
+	poll_fn(move |cx| {
+	    if let Some(guard) = self.try_lock() {
+		return Poll::Ready(guard);
+	    }
+
+	    // Queue up first, then try again, in case the holder released it in between and so won't wake us
+	    self.waiters.lock().push_back(cx.waker().clone());
+	    match self.try_lock() {
+		Some(guard) => Poll::Ready(guard),
+		None => Poll::Pending,
+	    }
+	})
+    }
+
+    fn unlock(&self) {
+	self.locked.store(false, Ordering::Release);
+
+	// Wake everyone rather than just the oldest: a waiter may since have given up waiting, and if it were the only one
+	// woken then nobody would take the lock. The losers just queue up again.
+	let waiters = core::mem::take(&mut *self.waiters.lock());
+	for waker in waiters {
+	    waker.wake();
+	}
+    }
+}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+	unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+	unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+	self.mutex.unlock();
+    }
+}
+
+#[test]
+fn contending_tasks_get_the_lock_in_the_order_they_asked() {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::task::Context;
+    use futures_util::task::ArcWake;
+
+    // Records the order tasks are woken in, which is the order an executor would poll them
+    struct Task {
+	id: usize,
+	woken: Arc<Mutex<Vec<usize>>>,
+    }
+    impl ArcWake for Task {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+	    arc_self.woken.lock().push(arc_self.id);
+	}
+    }
+
+    let mutex = AsyncMutex::new(Vec::new());
+    let woken = Arc::new(Mutex::new(Vec::new()));
+    let wakers: Vec<Waker> = (0 .. 3)
+	.map(|id| futures_util::task::waker(Arc::new(Task {
+	    id,
+	    woken: woken.clone(),
+	})))
+	.collect();
+    let mut tasks: Vec<_> = (0 .. 3).map(|_| Box::pin(mutex.lock())).collect();
+
+    let mut guard = mutex.try_lock().unwrap();
+    for (task, waker) in tasks.iter_mut().zip(wakers.iter()) {
+	assert!(task.as_mut().poll(&mut Context::from_waker(waker)).is_pending());
+    }
+
+    // Each time it's released, the tasks still waiting are polled in the order they were woken
+    for _ in 0 .. 3 {
+	drop(guard);
+	let order = core::mem::take(&mut *woken.lock());
+	assert!(!order.is_empty());
+
+	let mut acquired = None;
+	for id in order {
+	    if let Poll::Ready(g) = tasks[id].as_mut().poll(&mut Context::from_waker(&wakers[id])) {
+		assert!(acquired.is_none());
+		acquired = Some((id, g));
+	    }
+	}
+
+	let (id, mut g) = acquired.expect("Nobody took the lock once it was released");
+	g.push(id);
+	guard = g;
+    }
+
+    assert_eq!(*guard, [0, 1, 2]);
+}
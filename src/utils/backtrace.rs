@@ -0,0 +1,45 @@
+use core::arch::asm;
+use x86_64::VirtAddr;
+
+use crate::memory;
+
+// Deep enough for any real call chain, while a corrupt stack that happens to loop can't go on forever
+const MAX_FRAMES: usize = 32;
+// Only kernel code is walked, and its stacks are all in the higher half
+const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+// Logs the return address of each frame from here up, by following the saved frame pointers, which the kernel is built
+// to keep. Every frame is read with peek_kernel, so a corrupt chain ends the walk rather than faulting.
+pub fn log_backtrace() {
+    let mut rbp: u64;
+    unsafe {
+	asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    log::error!("Backtrace:");
+    for depth in 0 .. MAX_FRAMES {
+	if rbp < KERNEL_SPACE_START || rbp % 8 != 0 {
+	    break;
+	}
+
+	// Each frame starts with the caller's frame pointer, then the address it returns to
+	let mut frame = [0u8; 16];
+	if memory::peek_kernel(VirtAddr::new(rbp), &mut frame) != frame.len() {
+	    log::error!("  frame pointer 0x{:x} not mapped", rbp);
+	    break;
+	}
+
+	let next_rbp = u64::from_le_bytes(frame[0 .. 8].try_into().unwrap());
+	let return_address = u64::from_le_bytes(frame[8 .. 16].try_into().unwrap());
+	if return_address == 0 {
+	    break;
+	}
+	log::error!("  #{:<2} 0x{:x}", depth, return_address);
+
+	// Callers' frames are further up the stack, so anything else means the chain is broken
+	if next_rbp <= rbp {
+	    break;
+	}
+	rbp = next_rbp;
+    }
+}
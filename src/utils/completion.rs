@@ -0,0 +1,105 @@
+use core::future::{poll_fn, Future};
+use core::task::{Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::scheduler;
+
+struct CompletionState<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+// Signals a single result from an interrupt handler to the task waiting on it. A driver typically creates one per
+// command, starts the command, then awaits wait(); its IRQ handler calls complete() with the outcome.
+#[allow(dead_code)]
+pub struct Completion<T> {
+    // Also taken by IRQ handlers, so only ever locked with interrupts disabled
+    state: Mutex<CompletionState<T>>,
+}
+
+#[allow(dead_code)]
+impl<T> Completion<T> {
+    pub const fn new() -> Self {
+	Completion {
+	    state: Mutex::new(CompletionState {
+		result: None,
+		waker: None,
+	    }),
+	}
+    }
+
+    // May be called from interrupt context. If complete is called again before the result is collected, the
+    // earlier result is replaced.
+    pub fn complete(&self, result: T) {
+	let waker = without_interrupts(|| {
+	    let mut state = self.state.lock();
+	    state.result = Some(result);
+	    state.waker.take()
+	});
+
+	if let Some(waker) = waker {
+	    scheduler::defer_wake(waker);
+	}
+    }
+
+    pub fn is_complete(&self) -> bool {
+	without_interrupts(|| self.state.lock().result.is_some())
+    }
+
+    // Resolves once complete has been called, taking the result, so the completion can then be reused for the next
+    // command
+    pub fn wait(&self) -> impl Future<Output = T> + '_ {
+	poll_fn(move |cx| {
+	    without_interrupts(|| {
+		let mut state = self.state.lock();
+		match state.result.take() {
+		    Some(result) => Poll::Ready(result),
+		    None => {
+			state.waker = Some(cx.waker().clone());
+			Poll::Pending
+		    },
+		}
+	    })
+	})
+    }
+}
+
+impl<T> Default for Completion<T> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+#[test]
+fn completing_from_an_irq_wakes_the_waiting_task() {
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::Context;
+    use futures_util::task::ArcWake;
+
+    struct Woken(AtomicBool);
+    impl ArcWake for Woken {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+	    arc_self.0.store(true, Ordering::SeqCst);
+	}
+    }
+
+    let completion: Completion<u8> = Completion::new();
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = futures_util::task::waker(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut wait = Box::pin(completion.wait());
+    assert!(wait.as_mut().poll(&mut cx).is_pending());
+
+    // As the IRQ handler does. The wake waits for the scheduler, which runs it once the handler has returned
+    completion.complete(0x50);
+    assert!(completion.is_complete());
+    scheduler::run_deferred_wakes();
+    assert!(woken.0.load(Ordering::SeqCst));
+
+    assert_eq!(wait.as_mut().poll(&mut cx), Poll::Ready(0x50));
+    assert!(!completion.is_complete());
+}
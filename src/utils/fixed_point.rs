@@ -0,0 +1,57 @@
+use alloc::format;
+use alloc::string::String;
+use fixed::{types::extra::U3, FixedU64};
+
+// Fixed point type used for human readable figures in log output
+pub type ReportFixed = FixedU64<U3>;
+
+// Division for diagnostic output, which must never be able to panic the kernel. Dividing by zero gives zero, and
+// anything which doesn't fit saturates.
+pub fn report_div(dividend: u64, divisor: u64) -> ReportFixed {
+    let dividend = ReportFixed::saturating_from_num(dividend);
+    let divisor = ReportFixed::saturating_from_num(divisor);
+
+    if divisor == ReportFixed::ZERO {
+	return ReportFixed::ZERO;
+    }
+
+    dividend.checked_div(divisor).unwrap_or(ReportFixed::MAX)
+}
+
+// Formats a size with the largest binary unit it's at least 1 of, e.g. "1.5 GiB"
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [
+	(1024 * 1024 * 1024, "GiB"),
+	(1024 * 1024, "MiB"),
+	(1024, "KiB"),
+    ];
+
+    for (size, unit) in UNITS {
+	if n >= size {
+	    return format!("{:.1} {}", report_div(n, size), unit);
+	}
+    }
+
+    format!("{} B", n)
+}
+
+#[test]
+fn report_div_never_panics() {
+    assert_eq!(report_div(10, 0), ReportFixed::ZERO);
+    assert_eq!(report_div(0, 0), ReportFixed::ZERO);
+    assert_eq!(report_div(3, 2), ReportFixed::from_num(1.5));
+
+    // Too big for the fixed point type, so both sides saturate
+    assert_eq!(report_div(u64::MAX, 1), ReportFixed::MAX);
+    assert_eq!(report_div(u64::MAX, u64::MAX), ReportFixed::ONE);
+}
+
+#[test]
+fn format_bytes_picks_the_largest_whole_unit() {
+    assert_eq!(format_bytes(0), "0 B");
+    assert_eq!(format_bytes(1023), "1023 B");
+    assert_eq!(format_bytes(1024), "1.0 KiB");
+    assert_eq!(format_bytes(1024 * 1024 - 1), "1023.9 KiB");
+    assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+    assert_eq!(format_bytes(1536 * 1024 * 1024), "1.5 GiB");
+}
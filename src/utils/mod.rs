@@ -1,2 +1,7 @@
 pub mod vector_map;
 pub mod async_kcall;
+pub mod fixed_point;
+pub mod async_mutex;
+pub mod completion;
+pub mod backtrace;
+pub mod rwlock;
@@ -0,0 +1,147 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const WRITER: usize = 1;
+const READER: usize = 2;
+
+// A spinning reader-writer lock which prefers writers. spin::RwLock lets new readers in for as long as there are any
+// others, so a steady stream of them can keep a writer out forever. Here, once a writer is waiting, no new readers
+// are let in, so it gets the lock as soon as the readers already holding it are done.
+//
+// The catch is that readers can't nest. Taking the lock for reading again while already holding it deadlocks if a
+// writer arrives in between.
+pub struct FairRwLock<T: ?Sized> {
+    // WRITER if held for writing, otherwise READER times the number of readers holding it
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for FairRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for FairRwLock<T> {}
+
+pub struct FairRwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a FairRwLock<T>,
+}
+
+pub struct FairRwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a FairRwLock<T>,
+}
+
+impl<T> FairRwLock<T> {
+    pub const fn new(data: T) -> Self {
+	FairRwLock {
+	    state: AtomicUsize::new(0),
+	    writers_waiting: AtomicUsize::new(0),
+	    data: UnsafeCell::new(data),
+	}
+    }
+}
+
+impl<T: ?Sized> FairRwLock<T> {
+    pub fn read(&self) -> FairRwLockReadGuard<'_, T> {
+	loop {
+	    if let Some(guard) = self.try_read() {
+		return guard;
+	    }
+	    spin_loop();
+	}
+    }
+
+    // Fails if the lock is held for writing, or a writer is waiting for it
+    pub fn try_read(&self) -> Option<FairRwLockReadGuard<'_, T>> {
+	if self.writers_waiting.load(Ordering::Acquire) != 0 {
+	    return None;
+	}
+
+	let state = self.state.fetch_add(READER, Ordering::Acquire);
+	if state & WRITER != 0 || self.writers_waiting.load(Ordering::Acquire) != 0 {
+	    // A writer got in first, so back out and let it have the lock
+	    self.state.fetch_sub(READER, Ordering::Release);
+	    return None;
+	}
+
+	Some(FairRwLockReadGuard {
+	    lock: self,
+	})
+    }
+
+    pub fn write(&self) -> FairRwLockWriteGuard<'_, T> {
+	if let Some(guard) = self.try_write() {
+	    return guard;
+	}
+
+	self.writers_waiting.fetch_add(1, Ordering::AcqRel);
+	loop {
+	    if let Some(guard) = self.try_write() {
+		self.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+		return guard;
+	    }
+	    spin_loop();
+	}
+    }
+
+    pub fn try_write(&self) -> Option<FairRwLockWriteGuard<'_, T>> {
+	self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+	Some(FairRwLockWriteGuard {
+	    lock: self,
+	})
+    }
+}
+
+impl<T: ?Sized> Deref for FairRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+	unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for FairRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+	self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for FairRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+	unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for FairRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+	unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for FairRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+	self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[test]
+fn a_waiting_writer_goes_ahead_of_new_readers() {
+    let lock = FairRwLock::new(0);
+    let reader = lock.read();
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+
+    // As write() does while it spins waiting for the readers to go
+    lock.writers_waiting.fetch_add(1, Ordering::AcqRel);
+    assert!(lock.try_read().is_none());
+
+    drop(reader);
+    let mut writer = lock.try_write().unwrap();
+    lock.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+    *writer += 1;
+    assert!(lock.try_read().is_none());
+
+    drop(writer);
+    assert_eq!(*lock.read(), 1);
+}
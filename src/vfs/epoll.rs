@@ -0,0 +1,358 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use bytes::Bytes;
+use core::future::{poll_fn, Future};
+use core::mem;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::Mutex;
+
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::sys::time;
+use crate::vfs::filesystem::{DirEntry, FileHandle, SeekFrom, Stat};
+
+bitflags! {
+    // Values follow Linux
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EpollEvents: u32 {
+	const In            = 0x0000_0001;
+	const Pri           = 0x0000_0002;
+	const Out           = 0x0000_0004;
+	const Err           = 0x0000_0008;
+	const Hup           = 0x0000_0010;
+	const RdHup         = 0x0000_2000;
+
+	// Interest options
+	const OneShot       = 0x4000_0000;
+	const EdgeTriggered = 0x8000_0000;
+    }
+}
+
+impl EpollEvents {
+    // What can be asked for, as opposed to how
+    const READINESS: EpollEvents = EpollEvents::from_bits_truncate(0x201F);
+
+    fn to_poll_events(self) -> PollEvents {
+	POLL_EVENTS.iter()
+	    .filter(|(e, _)| self.contains(*e))
+	    .fold(PollEvents::empty(), |acc, (_, p)| acc | *p)
+    }
+
+    fn from_poll_events(events: PollEvents) -> EpollEvents {
+	POLL_EVENTS.iter()
+	    .filter(|(_, p)| events.contains(*p))
+	    .fold(EpollEvents::empty(), |acc, (e, _)| acc | *e)
+    }
+}
+
+// The two don't number their events the same way
+const POLL_EVENTS: [(EpollEvents, PollEvents); 6] = [
+    (EpollEvents::In, PollEvents::In),
+    (EpollEvents::Pri, PollEvents::Pri),
+    (EpollEvents::Out, PollEvents::Out),
+    (EpollEvents::Err, PollEvents::Err),
+    (EpollEvents::Hup, PollEvents::Hup),
+    (EpollEvents::RdHup, PollEvents::RdHup),
+];
+
+pub const EPOLL_CTL_ADD: u64 = 1;
+pub const EPOLL_CTL_DEL: u64 = 2;
+pub const EPOLL_CTL_MOD: u64 = 3;
+
+// Queues its fd on the instance when woken. Handed to the handle's poll as the waker, which only holds on to it until
+// the next change, and to handles with EpollWatchers, which hold on to it for as long as the interest lasts.
+pub struct Watch {
+    epoll: Weak<Epoll>,
+    fd: u64,
+}
+
+impl Wake for Watch {
+    fn wake(self: Arc<Self>) {
+	self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+	if let Some(epoll) = self.epoll.upgrade() {
+	    epoll.queue(self.fd);
+	}
+    }
+}
+
+// Kept by handles which can tell epoll about every change, rather than only once they've been polled and found not
+// ready. Edge triggered interests rely on this, as nothing else rechecks a handle after it's been reported.
+pub struct EpollWatchers {
+    watches: Mutex<Vec<Weak<Watch>>>,
+}
+
+impl EpollWatchers {
+    pub const fn new() -> Self {
+	Self {
+	    watches: Mutex::new(Vec::new()),
+	}
+    }
+
+    fn add(&self, watch: &Arc<Watch>) {
+	let mut watches = self.watches.lock();
+	watches.retain(|w| w.strong_count() > 0);
+	watches.push(Arc::downgrade(watch));
+    }
+
+    // Called whenever the handle might have become ready
+    pub fn notify(&self) {
+	// Woken with the list released, as waking can take other locks
+	let watches: Vec<Arc<Watch>> = self.watches.lock().iter()
+	    .filter_map(|w| w.upgrade())
+	    .collect();
+
+	for watch in watches {
+	    watch.wake_by_ref();
+	}
+    }
+}
+
+impl Default for EpollWatchers {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+struct Interest {
+    handle: Arc<dyn FileHandle>,
+    // Cleared once a one shot interest has fired, until it's modified again
+    events: EpollEvents,
+    data: u64,
+    watch: Arc<Watch>,
+}
+
+// An epoll instance, as returned by epoll_create. Holds the fds it's interested in, and those which may have become
+// ready since they were last looked at, which are rechecked by epoll_wait.
+pub struct Epoll {
+    interests: Mutex<BTreeMap<u64, Interest>>,
+    ready: Mutex<VecDeque<u64>>,
+    waiter: Mutex<Option<Waker>>,
+}
+
+impl Epoll {
+    pub fn new() -> Self {
+	Self {
+	    interests: Mutex::new(BTreeMap::new()),
+	    ready: Mutex::new(VecDeque::new()),
+	    waiter: Mutex::new(None),
+	}
+    }
+
+    pub fn add(self: &Arc<Self>, fd: u64, handle: Arc<dyn FileHandle>, events: EpollEvents, data: u64) -> Result<(), CanonicalError> {
+	let watch = Arc::new(Watch {
+	    epoll: Arc::downgrade(self),
+	    fd,
+	});
+
+	{
+	    let mut interests = self.interests.lock();
+	    if interests.contains_key(&fd) {
+		return Err(CanonicalError::Exist);
+	    }
+	    interests.insert(fd, Interest {
+		handle: handle.clone(),
+		events,
+		data,
+		watch: watch.clone(),
+	    });
+	}
+
+	if let Some(watchers) = handle.epoll_watchers() {
+	    watchers.add(&watch);
+	}
+
+	// Looked at straight away, so something already ready is reported by the next wait
+	self.queue(fd);
+	Ok(())
+    }
+
+    pub fn modify(&self, fd: u64, events: EpollEvents, data: u64) -> Result<(), CanonicalError> {
+	{
+	    let mut interests = self.interests.lock();
+	    let interest = interests.get_mut(&fd).ok_or(CanonicalError::NoEnt)?;
+	    interest.events = events;
+	    interest.data = data;
+	}
+
+	self.queue(fd);
+	Ok(())
+    }
+
+    pub fn remove(&self, fd: u64) -> Result<(), CanonicalError> {
+	self.interests.lock().remove(&fd).ok_or(CanonicalError::NoEnt)?;
+	self.ready.lock().retain(|&f| f != fd);
+
+	Ok(())
+    }
+
+    fn queue(&self, fd: u64) {
+	{
+	    let mut ready = self.ready.lock();
+	    if !ready.contains(&fd) {
+		ready.push_back(fd);
+	    }
+	}
+
+	let waker = self.waiter.lock().take();
+	if let Some(waker) = waker {
+	    waker.wake();
+	}
+    }
+
+    // Polls the handle once. If it isn't ready, it keeps the interest's watch as its waker, which queues the fd again
+    // when that changes.
+    fn check(handle: &Arc<dyn FileHandle>, events: EpollEvents, watch: &Arc<Watch>) -> Option<EpollEvents> {
+	let waker = Waker::from(watch.clone());
+	let mut poll = handle.clone().poll(events.to_poll_events());
+
+	match poll.as_mut().poll(&mut Context::from_waker(&waker)) {
+	    // Errors and hangups are always reported, whether asked for or not
+	    Poll::Ready(Ok(revents)) => Some(EpollEvents::from_poll_events(revents) & (events | EpollEvents::Err | EpollEvents::Hup))
+		.filter(|r| !r.is_empty()),
+	    Poll::Ready(Err(_)) => Some(EpollEvents::Err),
+	    Poll::Pending => None,
+	}
+    }
+
+    // Rechecks everything which may have become ready, returning up to max_events of those which are. Level
+    // triggered interests which were ready stay queued, to be rechecked next time.
+    fn collect(&self, max_events: usize, waker: &Waker) -> Vec<(EpollEvents, u64)> {
+	// Registered before looking, so that anything becoming ready from here on wakes the caller
+	*self.waiter.lock() = Some(waker.clone());
+
+	let candidates = mem::take(&mut *self.ready.lock());
+	let mut found = Vec::new();
+	let mut still_ready = Vec::new();
+
+	for fd in candidates {
+	    if found.len() >= max_events {
+		still_ready.push(fd);
+		continue;
+	    }
+
+	    let (handle, events, data, watch) = match self.interests.lock().get(&fd) {
+		Some(i) if i.events.intersects(EpollEvents::READINESS) => (i.handle.clone(), i.events, i.data, i.watch.clone()),
+		_ => continue,
+	    };
+
+	    // Polled with the lists released, as the handle may wake the watch straight away
+	    let revents = match Self::check(&handle, events, &watch) {
+		Some(r) => r,
+		None => continue,
+	    };
+	    found.push((revents, data));
+
+	    if events.contains(EpollEvents::OneShot) {
+		if let Some(interest) = self.interests.lock().get_mut(&fd) {
+		    interest.events = EpollEvents::empty();
+		}
+	    } else if !events.contains(EpollEvents::EdgeTriggered) {
+		still_ready.push(fd);
+	    }
+	}
+
+	let mut ready = self.ready.lock();
+	for fd in still_ready {
+	    if !ready.contains(&fd) {
+		ready.push_back(fd);
+	    }
+	}
+
+	found
+    }
+
+    // Waits until something is ready, or for timeout_ns if given, and returns the events and data of up to
+    // max_events ready fds. A zero timeout just checks.
+    pub async fn wait(self: Arc<Self>, max_events: usize, timeout_ns: Option<u64>) -> Vec<(EpollEvents, u64)> {
+	let mut timeout = timeout_ns
+	    .filter(|&ns| ns > 0)
+	    .map(|ns| Box::pin(time::sleep_ns(ns)));
+
+	poll_fn(move |cx: &mut Context<'_>| {
+	    let found = self.collect(max_events, cx.waker());
+	    if !found.is_empty() || timeout_ns == Some(0) {
+		return Poll::Ready(found);
+	    }
+
+	    match timeout.as_mut() {
+		Some(sleep) if sleep.as_mut().poll(cx).is_ready() => Poll::Ready(Vec::new()),
+		_ => Poll::Pending,
+	    }
+	}).await
+    }
+}
+
+impl Default for Epoll {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl FileHandle for Epoll {
+    fn read(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<Bytes, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn write(self: Arc<Self>, _buf: Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    // Readable when something may be ready, without rechecking, so an epoll can itself be waited on
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    if !events.contains(PollEvents::In) {
+		return Poll::Ready(Ok(PollEvents::empty()));
+	    }
+
+	    *self.waiter.lock() = Some(cx.waker().clone());
+	    if self.ready.lock().is_empty() {
+		Poll::Pending
+	    } else {
+		Poll::Ready(Ok(PollEvents::In))
+	    }
+	}))
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, _offset: SeekFrom) -> Result<u64, CanonicalError> {
+	Err(CanonicalError::SPipe)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+
+    fn as_epoll(self: Arc<Self>) -> Option<Arc<Epoll>> {
+	Some(self)
+    }
+}
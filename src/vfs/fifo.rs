@@ -1,6 +1,7 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec::Vec;
 use bytes::BytesMut;
 use core::cmp;
 use core::future::poll_fn;
@@ -9,20 +10,29 @@ use futures_util::future::BoxFuture;
 use spin::Mutex;
 use futures_util::FutureExt;
 
-use crate::sys::ioctl;
 use crate::syscall::{CanonicalError, PollEvents};
-use crate::vfs::filesystem::{SeekFrom, Stat, VNode, VNodeKind, FileHandle, FileSystemInstance, FileSystem};
+use crate::vfs::epoll::EpollWatchers;
+use crate::vfs::filesystem::{DirEntry, SeekFrom, Stat, VNode, VNodeKind, FileHandle, FileSystemInstance, FileSystem};
+
+// Matches the Linux default. Writes beyond this are only partially accepted, or wait for a reader to make room
+const PIPE_CAPACITY: usize = 65536;
+// Writes of up to this many bytes are never split up, so can't be interleaved with another writer's
+const PIPE_BUF: usize = 4096;
 
 pub struct Fifo {
     buffer: Mutex<BytesMut>,
-    read_waker: Mutex<Option<Waker>>,
+    read_wakers: Mutex<Vec<Waker>>,
+    write_wakers: Mutex<Vec<Waker>>,
+    epoll_watchers: EpollWatchers,
 }
 
 impl Fifo {
     pub fn new() -> Self {
 	Self {
 	    buffer: Mutex::new(BytesMut::new()),
-	    read_waker: Mutex::new(None),
+	    read_wakers: Mutex::new(Vec::new()),
+	    write_wakers: Mutex::new(Vec::new()),
+	    epoll_watchers: EpollWatchers::new(),
 	}
     }
 
@@ -30,56 +40,76 @@ impl Fifo {
 	let this = self.clone();
 
 	async move {
-	    let mut buffer = this.buffer.lock();
+	    let data = {
+		let mut buffer = this.buffer.lock();
+
+		let to_read = cmp::min(len as usize, buffer.len());
+		buffer.split_to(to_read).freeze()
+	    };
 
-            let to_read = cmp::min(len as usize, buffer.len());
-            Ok(buffer.split_to(to_read).freeze())
+	    // Room has been made for any writers waiting on a full pipe, though maybe not enough for all of them, which
+	    // are left to find out for themselves
+	    if !data.is_empty() {
+		wake_all(&this.write_wakers);
+		this.epoll_watchers.notify();
+	    }
+
+	    Ok(data)
 	}.boxed()
     }
 
+    // Accepts as much of buf as fits, which may be less than all of it, and waits only if the pipe is already full. Up
+    // to PIPE_BUF bytes are written all at once, waiting until there's room for all of them.
     fn write(self: Arc<Self>, buf: bytes::Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
-	let this = self.clone();
-	async move {
-	    let len = buf.len() as u64;
-	    {
-		let mut buffer = this.buffer.lock();
-		buffer.extend(buf);
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    if buf.is_empty() {
+		return Poll::Ready(Ok(0));
 	    }
 
-	    {
-		let mut read_waker = this.read_waker.lock();
-		if let Some(waker) = read_waker.take() {
-		    waker.wake();
-		    *read_waker = None;
+	    let written = {
+		let mut buffer = self.buffer.lock();
+		let space = PIPE_CAPACITY.saturating_sub(buffer.len());
+		if space == 0 || (buf.len() <= PIPE_BUF && space < buf.len()) {
+		    // Registered with the buffer still locked, so a reader can't drain it between checking and sleeping
+		    self.write_wakers.lock().push(cx.waker().clone());
+		    return Poll::Pending;
 		}
-	    }
 
-            Ok(len)
-	}.boxed()
+		let written = cmp::min(space, buf.len());
+		buffer.extend_from_slice(&buf[.. written]);
+		written
+	    };
+
+	    wake_all(&self.read_wakers);
+	    self.epoll_watchers.notify();
+
+	    Poll::Ready(Ok(written as u64))
+	}))
     }
 
     fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
 	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
 	    let mut revents = PollEvents::empty();
+	    let b = self.buffer.lock();
+	    let l = (*b).len();
 
-	    // We can always write
-	    if events.contains(PollEvents::Out) {
+	    // As on Linux, only once there's room for a write that won't be split up
+	    if events.contains(PollEvents::Out) && PIPE_CAPACITY - l >= PIPE_BUF {
                 revents |= PollEvents::Out;
 	    }
 
-	    if events.contains(PollEvents::In) {
-		let b = self.buffer.lock();
-		let l = (*b).len();
+	    if events.contains(PollEvents::In) && l > 0 {
+		revents |= PollEvents::In;
+	    }
 
-		if l > 0 {
-		    revents |= PollEvents::In;
+	    if revents.is_empty() && events.intersects(PollEvents::In | PollEvents::Out) {
+		if events.contains(PollEvents::In) {
+		    self.read_wakers.lock().push(cx.waker().clone());
 		}
-		
-		if revents.is_empty() {
-		    let mut read_waker = self.read_waker.lock();
-		    *read_waker = Some(cx.waker().clone());
-		    return Poll::Pending;
+		if events.contains(PollEvents::Out) {
+		    self.write_wakers.lock().push(cx.waker().clone());
 		}
+		return Poll::Pending;
 	    }
 
 	    Poll::Ready(Ok(revents))
@@ -87,6 +117,13 @@ impl Fifo {
     }
 }
 
+fn wake_all(wakers: &Mutex<Vec<Waker>>) {
+    let wakers = core::mem::take(&mut *wakers.lock());
+    for waker in wakers {
+	waker.wake();
+    }
+}
+
 impl VNode for Fifo {
     fn inode(&self) -> u64 {
 	0
@@ -100,6 +137,10 @@ impl VNode for Fifo {
 	Ok(Stat {
 	    file_name: String::new(),
 	    size: Some(self.buffer.lock().len() as u64),
+	    inode: self.inode(),
+	    kind: VNodeKind::Fifo,
+	    mode: 0o600,
+	    modified: None,
 	})
     }
 
@@ -120,6 +161,10 @@ impl VNode for Fifo {
 	unimplemented!();
     }
 
+    fn readlink(&self) -> Result<String, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
     fn set_fsi(self: Arc<Self>, _fsi: FileSystemInstance) {
 	unimplemented!();
     }
@@ -157,13 +202,68 @@ impl FileHandle for FifoHandle {
 	self.fifo.stat()
     }
 
-    fn ioctl(self: Arc<Self>, _ioctl: ioctl::IoCtl, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
-	// TODO: This should return a canonical error
-	unimplemented!();
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
     }
 
     fn seek(&self, _offset: SeekFrom) -> Result<u64, CanonicalError> {
 	// TODO: This should return a canonical error
 	unimplemented!();
     }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	// Truncating a FIFO is a no-op
+	async move {
+	    Ok(())
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+
+    fn epoll_watchers(&self) -> Option<&EpollWatchers> {
+	Some(&self.fifo.epoll_watchers)
+    }
+}
+
+#[test]
+fn a_large_write_is_cut_short_by_a_nearly_full_pipe() {
+    let fifo = Arc::new(Fifo::new());
+    let fill = bytes::Bytes::from(alloc::vec![0u8; PIPE_CAPACITY - 100]);
+    assert_eq!(fifo.clone().write(fill).now_or_never().unwrap().unwrap(), (PIPE_CAPACITY - 100) as u64);
+
+    // More than PIPE_BUF, so as much as fits goes in
+    let large = bytes::Bytes::from(alloc::vec![1u8; 2 * PIPE_BUF]);
+    assert_eq!(fifo.clone().write(large.clone()).now_or_never().unwrap().unwrap(), 100);
+    assert_eq!(fifo.stat().unwrap().size, Some(PIPE_CAPACITY as u64));
+
+    // And once the pipe's full, it waits for a reader
+    assert!(fifo.clone().write(large).now_or_never().is_none());
+}
+
+#[test]
+fn a_write_of_up_to_pipe_buf_goes_in_whole_or_not_at_all() {
+    let fifo = Arc::new(Fifo::new());
+    let fill = bytes::Bytes::from(alloc::vec![0u8; PIPE_CAPACITY - 100]);
+    assert_eq!(fifo.clone().write(fill).now_or_never().unwrap().unwrap(), (PIPE_CAPACITY - 100) as u64);
+
+    // Doesn't fit, so none of it is written
+    let small = bytes::Bytes::from(alloc::vec![1u8; PIPE_BUF]);
+    assert!(fifo.clone().write(small.clone()).now_or_never().is_none());
+    assert_eq!(fifo.stat().unwrap().size, Some((PIPE_CAPACITY - 100) as u64));
+
+    // Once a reader has made room, all of it is
+    assert_eq!(fifo.clone().read(PIPE_BUF as u64).now_or_never().unwrap().unwrap().len(), PIPE_BUF);
+    assert_eq!(fifo.clone().write(small).now_or_never().unwrap().unwrap(), PIPE_BUF as u64);
+    assert_eq!(fifo.stat().unwrap().size, Some((PIPE_CAPACITY - 100) as u64));
+
+    // After what was already there
+    let all = fifo.clone().read(PIPE_CAPACITY as u64).now_or_never().unwrap().unwrap();
+    assert!(all[.. all.len() - PIPE_BUF].iter().all(|b| *b == 0));
+    assert!(all[all.len() - PIPE_BUF ..].iter().all(|b| *b == 1));
 }
@@ -1,29 +1,140 @@
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec::Vec;
 use futures_util::future::BoxFuture;
+use bitflags::bitflags;
+use x86_64::VirtAddr;
 
 use crate::sys::syscall::{CanonicalError, PollEvents};
-use crate::sys::ioctl;
+use crate::vfs::epoll;
+use crate::vfs::inotify;
+use crate::vfs::socket;
 
 #[allow(dead_code)]
 pub struct Stat {
     pub file_name: String,
     pub size: Option<u64>,
+    pub inode: u64,
+    pub kind: VNodeKind,
+    // Permission bits only, as the kind gives the file type
+    pub mode: u64,
+    // Seconds since the epoch, for filesystems which keep track
+    pub modified: Option<u64>,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, PartialEq, Eq)]
 pub struct FileSystemInstance(pub u64);
 
+#[allow(dead_code)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub kind: VNodeKind,
+    pub name: String,
+    // Opaque position immediately after this entry. Seeking a directory handle to this resumes iteration from the
+    // next entry, and it must stay valid if unrelated entries are added or removed in the meantime
+    pub cookie: u64,
+}
+
 pub enum SeekFrom {
     Set(i64),
     Cur(i64),
     End(i64),
 }
 
+bitflags! {
+    // Values follow the mlibc ABI. The bottom three bits are the access mode, which is a value rather than a set
+    // of flags, so should be read with access_mode() rather than tested with contains()
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u64 {
+	const AccMode   = 0x0007;
+	const Append    = 0x0008;
+	const Creat     = 0x0010;
+	const Directory = 0x0020;
+	const Excl      = 0x0040;
+	const NoCtty    = 0x0080;
+	const NoFollow  = 0x0100;
+	const Trunc     = 0x0200;
+	const NonBlock  = 0x0400;
+	const DSync     = 0x0800;
+	const RSync     = 0x1000;
+	const Sync      = 0x2000;
+	const CloExec   = 0x4000;
+	const Path      = 0x8000;
+    }
+}
+
+#[repr(u64)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessMode {
+    Exec = 1,
+    RdOnly = 2,
+    RdWr = 3,
+    Search = 4,
+    WrOnly = 5,
+}
+
+impl OpenFlags {
+    pub fn access_mode(&self) -> Result<AccessMode, CanonicalError> {
+	match (*self & OpenFlags::AccMode).bits() {
+	    // Some callers (including the kernel itself) pass 0, so treat that as read only
+	    0 | 2 => Ok(AccessMode::RdOnly),
+	    1 => Ok(AccessMode::Exec),
+	    3 => Ok(AccessMode::RdWr),
+	    4 => Ok(AccessMode::Search),
+	    5 => Ok(AccessMode::WrOnly),
+	    _ => Err(CanonicalError::Inval),
+	}
+    }
+
+    pub fn with_access_mode(self, mode: AccessMode) -> Self {
+	(self - OpenFlags::AccMode) | OpenFlags::from_bits_retain(mode as u64)
+    }
+
+    pub fn is_readable(&self) -> bool {
+	matches!(self.access_mode(), Ok(AccessMode::RdOnly) | Ok(AccessMode::RdWr))
+    }
+
+    pub fn is_writable(&self) -> bool {
+	matches!(self.access_mode(), Ok(AccessMode::RdWr) | Ok(AccessMode::WrOnly))
+    }
+}
+
 pub trait FileSystem: Send + Sync {
     fn root(self: Arc<Self>, fsi: FileSystemInstance) -> Arc<dyn VNode>;
 
     fn lookup(self: Arc<Self>, fsi: FileSystemInstance, parent: &Arc<dyn VNode>, name: &str) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>>;
+
+    // Returns the entries in dir following the position given by cookie. A cookie of 0 is the start of the directory
+    fn readdir(self: Arc<Self>, fsi: FileSystemInstance, dir: &Arc<dyn VNode>, cookie: u64) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>>;
+
+    // Writes out anything the filesystem is holding on to, and waits for it to reach the disk. Filesystems which
+    // live in memory have nowhere to write it
+    fn sync(self: Arc<Self>, _fsi: FileSystemInstance) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	Box::pin(async move { Ok(()) })
+    }
+
+    // Adds a new, empty, file or directory called name to dir, failing with Exist if there's already something there.
+    // Filesystems which can't be changed leave these as they are
+    fn create(self: Arc<Self>, _fsi: FileSystemInstance, _dir: &Arc<dyn VNode>, _name: &str, _kind: VNodeKind, _mode: u64) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	Box::pin(async move { Err(CanonicalError::RoFs) })
+    }
+
+    // Adds a symlink called name to dir, pointing at target, which isn't looked at until the link is followed
+    fn symlink(self: Arc<Self>, _fsi: FileSystemInstance, _dir: &Arc<dyn VNode>, _name: &str, _target: &str) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+	Box::pin(async move { Err(CanonicalError::RoFs) })
+    }
+
+    // Removes name from dir, whatever it is. Directories must be empty first
+    fn unlink(self: Arc<Self>, _fsi: FileSystemInstance, _dir: &Arc<dyn VNode>, _name: &str) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	Box::pin(async move { Err(CanonicalError::RoFs) })
+    }
+
+    // Moves old_name in old_dir to new_name in new_dir, both in this instance, replacing anything already there
+    fn rename(self: Arc<Self>, _fsi: FileSystemInstance, _old_dir: &Arc<dyn VNode>, _old_name: &str, _new_dir: &Arc<dyn VNode>, _new_name: &str) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	Box::pin(async move { Err(CanonicalError::RoFs) })
+    }
 }
 
 pub trait VNode: Send + Sync {
@@ -39,6 +150,9 @@ pub trait VNode: Send + Sync {
 
     fn parent(&self) -> Result<Arc<dyn VNode>, CanonicalError>;
 
+    // Returns the target of a symlink, or Inval for anything else
+    fn readlink(&self) -> Result<String, CanonicalError>;
+
     // For use with procedurally generated filesystems
     fn set_fsi(self: Arc<Self>, fsi: FileSystemInstance);
 }
@@ -61,6 +175,54 @@ pub trait FileHandle: Send + Sync {
     fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>>;
 
     fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError>;
-    fn ioctl(self: Arc<Self>, ioctl: ioctl::IoCtl, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>>;
+    fn ioctl(self: Arc<Self>, request: u64, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>>;
     fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError>;
+    fn truncate(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<(), CanonicalError>>;
+    // Returns the remaining entries from the handle's current position, without advancing it
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>>;
+
+    // Reads up to len bytes straight into the current process' memory at buf, without building the Bytes that read()
+    // returns. Handles which can't do this from their current position return None, and read() is used instead
+    fn read_to_user(self: Arc<Self>, _buf: VirtAddr, _len: u64) -> Option<BoxFuture<'static, Result<u64, CanonicalError>>> {
+	None
+    }
+
+    // Waits until whatever has been written through the handle is on the disk. Only handles to files on a disk have
+    // anything to do
+    fn fsync(self: Arc<Self>) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	Box::pin(async move { Ok(()) })
+    }
+
+    // So that inotify_add_watch and inotify_rm_watch can get at the instance behind an fd
+    fn as_inotify(self: Arc<Self>) -> Option<Arc<inotify::Inotify>> {
+	None
+    }
+
+    // So that sendmsg and recvmsg can get at the socket behind an fd
+    fn as_socket(self: Arc<Self>) -> Option<Arc<socket::UnixSocket>> {
+	None
+    }
+
+    // So that epoll_ctl and epoll_wait can get at the instance behind an fd
+    fn as_epoll(self: Arc<Self>) -> Option<Arc<epoll::Epoll>> {
+	None
+    }
+
+    // Handles which tell epoll about every change, see EpollWatchers
+    fn epoll_watchers(&self) -> Option<&epoll::EpollWatchers> {
+	None
+    }
+}
+
+#[test]
+fn access_modes_say_which_way_data_may_go() {
+    let read = OpenFlags::empty();
+    let write = OpenFlags::Append.with_access_mode(AccessMode::WrOnly);
+    let both = write.with_access_mode(AccessMode::RdWr);
+
+    assert!(read.is_readable() && !read.is_writable());
+    assert!(!write.is_readable() && write.is_writable());
+    assert!(both.is_readable() && both.is_writable());
+    assert!(both.contains(OpenFlags::Append));
+    assert!(!OpenFlags::empty().with_access_mode(AccessMode::Exec).is_readable());
 }
@@ -0,0 +1,465 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use bytes::{BufMut, Bytes, BytesMut};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::{Mutex, RwLock};
+use x86_64::VirtAddr;
+
+use crate::scheduler;
+use crate::sys::syscall::{CanonicalError, PollEvents};
+use crate::vfs::epoll;
+use crate::vfs::filesystem::{DirEntry, FileHandle, FileSystemInstance, SeekFrom, Stat, VNode, VNodeKind};
+
+bitflags! {
+    // Values follow Linux
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InotifyMask: u32 {
+	const Access       = 0x0000_0001;
+	const Modify       = 0x0000_0002;
+	const Attrib       = 0x0000_0004;
+	const CloseWrite   = 0x0000_0008;
+	const CloseNoWrite = 0x0000_0010;
+	const Open         = 0x0000_0020;
+	const MovedFrom    = 0x0000_0040;
+	const MovedTo      = 0x0000_0080;
+	const Create       = 0x0000_0100;
+	const Delete       = 0x0000_0200;
+	const DeleteSelf   = 0x0000_0400;
+	const MoveSelf     = 0x0000_0800;
+
+	// Only ever reported, never asked for
+	const Unmount      = 0x0000_2000;
+	const QOverflow    = 0x0000_4000;
+	const Ignored      = 0x0000_8000;
+
+	// Watch options
+	const OnlyDir      = 0x0100_0000;
+	const DontFollow   = 0x0200_0000;
+	const MaskAdd      = 0x2000_0000;
+	const IsDir        = 0x4000_0000;
+	const OneShot      = 0x8000_0000;
+    }
+}
+
+impl InotifyMask {
+    pub const ALL_EVENTS: InotifyMask = InotifyMask::from_bits_truncate(0xFFF);
+}
+
+// Past this, further events are dropped, and the reader is told with a single QOverflow event
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+// Names are padded with NULs so that each record starts suitably aligned, as Linux does
+const EVENT_HEADER_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Ord, PartialOrd, PartialEq, Eq)]
+struct WatchKey {
+    fsi: FileSystemInstance,
+    inode: u64,
+}
+
+impl WatchKey {
+    fn of(vnode: &Arc<dyn VNode>) -> Self {
+	WatchKey {
+	    fsi: vnode.fsi(),
+	    inode: vnode.inode(),
+	}
+    }
+}
+
+struct Watch {
+    key: WatchKey,
+    mask: InotifyMask,
+}
+
+// Each watched file points back at the instances watching it. Instances remove themselves when they're dropped or
+// the watch is removed, but this only holds weak references, so a stale entry is harmless.
+static WATCHERS: RwLock<BTreeMap<WatchKey, Vec<(Weak<Inotify>, i32)>>> = RwLock::new(BTreeMap::new());
+
+// Pairs up the MovedFrom and MovedTo halves of a rename
+static NEXT_RENAME_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+// An inotify instance, as returned by inotify_init. Reading it returns the queued events as struct inotify_event
+// records.
+pub struct Inotify {
+    watches: Mutex<BTreeMap<i32, Watch>>,
+    events: Mutex<VecDeque<Bytes>>,
+    overflowed: AtomicBool,
+    read_waker: Mutex<Option<Waker>>,
+    next_wd: AtomicI32,
+}
+
+impl Inotify {
+    pub fn new() -> Self {
+	Self {
+	    watches: Mutex::new(BTreeMap::new()),
+	    events: Mutex::new(VecDeque::new()),
+	    overflowed: AtomicBool::new(false),
+	    read_waker: Mutex::new(None),
+	    next_wd: AtomicI32::new(1),
+	}
+    }
+
+    // Returns the watch descriptor. Watching something already watched by this instance updates the existing watch.
+    pub fn add_watch(self: &Arc<Self>, vnode: &Arc<dyn VNode>, mask: InotifyMask) -> Result<i32, CanonicalError> {
+	if (mask & InotifyMask::ALL_EVENTS).is_empty() {
+	    return Err(CanonicalError::Inval);
+	}
+	if mask.contains(InotifyMask::OnlyDir) && vnode.kind() != VNodeKind::Directory {
+	    return Err(CanonicalError::NotDir);
+	}
+
+	let key = WatchKey::of(vnode);
+	let mut watches = self.watches.lock();
+
+	if let Some((&wd, watch)) = watches.iter_mut().find(|(_, w)| w.key == key) {
+	    if mask.contains(InotifyMask::MaskAdd) {
+		watch.mask |= mask;
+	    } else {
+		watch.mask = mask;
+	    }
+	    return Ok(wd);
+	}
+
+	let wd = self.next_wd.fetch_add(1, Ordering::Relaxed);
+	watches.insert(wd, Watch {
+	    key,
+	    mask,
+	});
+	WATCHERS.write().entry(key).or_default().push((Arc::downgrade(self), wd));
+
+	Ok(wd)
+    }
+
+    pub fn remove_watch(&self, wd: i32) -> Result<(), CanonicalError> {
+	let watch = self.watches.lock().remove(&wd).ok_or(CanonicalError::Inval)?;
+	forget_watcher(watch.key, self, wd);
+	self.queue_event(wd, InotifyMask::Ignored, 0, None);
+
+	Ok(())
+    }
+
+    fn queue_event(&self, wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) {
+	{
+	    let mut events = self.events.lock();
+	    if events.len() >= MAX_QUEUED_EVENTS {
+		self.overflowed.store(true, Ordering::Relaxed);
+	    } else {
+		events.push_back(encode_event(wd, mask, cookie, name));
+	    }
+	}
+
+	// Deferred, as events can be raised with the process table held (e.g. closing files on exit), and waking the
+	// reader needs it
+	let waker = self.read_waker.lock().take();
+	if let Some(waker) = waker {
+	    scheduler::defer_wake(waker);
+	}
+    }
+
+    // Called for each event on something this instance has a watch on
+    fn deliver(&self, wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) {
+	let watch_mask = match self.watches.lock().get(&wd) {
+	    Some(w) => w.mask,
+	    None => return,
+	};
+
+	let events = mask & watch_mask & InotifyMask::ALL_EVENTS;
+	if events.is_empty() {
+	    return;
+	}
+
+	self.queue_event(wd, events | (mask & InotifyMask::IsDir), cookie, name);
+
+	if watch_mask.contains(InotifyMask::OneShot) {
+	    // Already gone if another event beat us to it
+	    let _ = self.remove_watch(wd);
+	}
+    }
+
+    fn has_events(&self) -> bool {
+	self.overflowed.load(Ordering::Relaxed) || !self.events.lock().is_empty()
+    }
+}
+
+impl Default for Inotify {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+	let watches = core::mem::take(&mut *self.watches.lock());
+	for (wd, watch) in watches {
+	    forget_watcher(watch.key, self, wd);
+	}
+    }
+}
+
+fn forget_watcher(key: WatchKey, inotify: &Inotify, wd: i32) {
+    let mut watchers = WATCHERS.write();
+    if let Some(list) = watchers.get_mut(&key) {
+	list.retain(|(w, d)| !(*d == wd && core::ptr::eq(w.as_ptr(), inotify)));
+	if list.is_empty() {
+	    watchers.remove(&key);
+	}
+    }
+}
+
+fn encode_event(wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) -> Bytes {
+    // The name is NUL terminated, then padded
+    let len = match name {
+	Some(n) => (n.len() + 1).div_ceil(EVENT_HEADER_SIZE) * EVENT_HEADER_SIZE,
+	None => 0,
+    };
+
+    let mut event = BytesMut::with_capacity(EVENT_HEADER_SIZE + len);
+    event.put_i32_ne(wd);
+    event.put_u32_ne(mask.bits());
+    event.put_u32_ne(cookie);
+    event.put_u32_ne(len as u32);
+    if let Some(n) = name {
+	event.put_slice(n.as_bytes());
+	event.put_bytes(0, len - n.len());
+    }
+
+    event.freeze()
+}
+
+fn notify_key(key: WatchKey, mask: InotifyMask, cookie: u32, name: Option<&str>) {
+    // Delivered once WATCHERS is released, as delivery can remove a one shot watch, and an instance we hold the last
+    // reference to would remove its watches as we drop it
+    let watchers: Vec<(Arc<Inotify>, i32)> = match WATCHERS.read().get(&key) {
+	Some(list) => list.iter()
+	    .filter_map(|(inotify, wd)| Some((inotify.upgrade()?, *wd)))
+	    .collect(),
+	None => return,
+    };
+
+    for (inotify, wd) in watchers {
+	inotify.deliver(wd, mask, cookie, name);
+    }
+}
+
+fn dir_flag(kind: VNodeKind) -> InotifyMask {
+    if kind == VNodeKind::Directory {
+	InotifyMask::IsDir
+    } else {
+	InotifyMask::empty()
+    }
+}
+
+// Reports an event on vnode itself, e.g. Modify after a write
+pub fn notify(vnode: &Arc<dyn VNode>, mask: InotifyMask) {
+    notify_key(WatchKey::of(vnode), mask | dir_flag(vnode.kind()), 0, None);
+}
+
+// Filesystems call these as entries are created, removed and renamed. Watchers on the directory hear about the
+// named entry; watchers on the entry itself get DeleteSelf or MoveSelf.
+pub fn notify_create(dir: &Arc<dyn VNode>, name: &str, kind: VNodeKind) {
+    notify_key(WatchKey::of(dir), InotifyMask::Create | dir_flag(kind), 0, Some(name));
+}
+
+pub fn notify_delete(dir: &Arc<dyn VNode>, name: &str, vnode: &Arc<dyn VNode>) {
+    notify(vnode, InotifyMask::DeleteSelf);
+    notify_key(WatchKey::of(dir), InotifyMask::Delete | dir_flag(vnode.kind()), 0, Some(name));
+}
+
+pub fn notify_rename(old_dir: &Arc<dyn VNode>, old_name: &str, new_dir: &Arc<dyn VNode>, new_name: &str, vnode: &Arc<dyn VNode>) {
+    let cookie = NEXT_RENAME_COOKIE.fetch_add(1, Ordering::Relaxed);
+    let is_dir = dir_flag(vnode.kind());
+
+    notify_key(WatchKey::of(old_dir), InotifyMask::MovedFrom | is_dir, cookie, Some(old_name));
+    notify_key(WatchKey::of(new_dir), InotifyMask::MovedTo | is_dir, cookie, Some(new_name));
+    notify(vnode, InotifyMask::MoveSelf);
+}
+
+impl FileHandle for Inotify {
+    // Returns as many whole events as fit in len. Inval if not even the first one does, as Linux does.
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<Bytes, CanonicalError>> {
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    let mut events = self.events.lock();
+	    let mut buf = BytesMut::new();
+
+	    if self.overflowed.load(Ordering::Relaxed) && len as usize >= EVENT_HEADER_SIZE {
+		self.overflowed.store(false, Ordering::Relaxed);
+		buf.extend_from_slice(&encode_event(-1, InotifyMask::QOverflow, 0, None));
+	    }
+
+	    while let Some(event) = events.front() {
+		if buf.len() + event.len() > len as usize {
+		    break;
+		}
+		buf.extend_from_slice(&events.pop_front().expect("Event queue emptied while reading it"));
+	    }
+
+	    if !buf.is_empty() {
+		return Poll::Ready(Ok(buf.freeze()));
+	    }
+	    if !events.is_empty() {
+		return Poll::Ready(Err(CanonicalError::Inval));
+	    }
+
+	    *self.read_waker.lock() = Some(cx.waker().clone());
+	    Poll::Pending
+	}))
+    }
+
+    fn write(self: Arc<Self>, _buf: Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    if !events.contains(PollEvents::In) {
+		return Poll::Ready(Ok(PollEvents::empty()));
+	    }
+
+	    if self.has_events() {
+		return Poll::Ready(Ok(PollEvents::In));
+	    }
+
+	    *self.read_waker.lock() = Some(cx.waker().clone());
+	    // In case an event was queued in between checking and registering the waker
+	    if self.has_events() {
+		Poll::Ready(Ok(PollEvents::In))
+	    } else {
+		Poll::Pending
+	    }
+	}))
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	Err(CanonicalError::Inval)
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, _offset: SeekFrom) -> Result<u64, CanonicalError> {
+	Err(CanonicalError::SPipe)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+
+    fn as_inotify(self: Arc<Self>) -> Option<Arc<Inotify>> {
+	Some(self)
+    }
+}
+
+// Wraps handles opened by path, so that reads, writes and closes through them are reported to anything watching
+// the file, without every filesystem having to do so itself
+pub struct WatchedFileHandle {
+    inner: Arc<dyn FileHandle>,
+    vnode: Arc<dyn VNode>,
+    writable: bool,
+}
+
+impl WatchedFileHandle {
+    pub fn new(inner: Arc<dyn FileHandle>, vnode: Arc<dyn VNode>, writable: bool) -> Self {
+	notify(&vnode, InotifyMask::Open);
+
+	Self {
+	    inner,
+	    vnode,
+	    writable,
+	}
+    }
+}
+
+impl Drop for WatchedFileHandle {
+    fn drop(&mut self) {
+	let mask = if self.writable {
+	    InotifyMask::CloseWrite
+	} else {
+	    InotifyMask::CloseNoWrite
+	};
+	notify(&self.vnode, mask);
+    }
+}
+
+impl FileHandle for WatchedFileHandle {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<Bytes, CanonicalError>> {
+	async move {
+	    let buf = self.inner.clone().read(len).await?;
+	    notify(&self.vnode, InotifyMask::Access);
+	    Ok(buf)
+	}.boxed()
+    }
+
+    fn read_to_user(self: Arc<Self>, buf: VirtAddr, len: u64) -> Option<BoxFuture<'static, Result<u64, CanonicalError>>> {
+	let read_fut = self.inner.clone().read_to_user(buf, len)?;
+	Some(async move {
+	    let read = read_fut.await?;
+	    notify(&self.vnode, InotifyMask::Access);
+	    Ok(read)
+	}.boxed())
+    }
+
+    fn write(self: Arc<Self>, buf: Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    let written = self.inner.clone().write(buf).await?;
+	    notify(&self.vnode, InotifyMask::Modify);
+	    Ok(written)
+	}.boxed()
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	self.inner.clone().poll(events)
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	self.inner.clone().stat()
+    }
+
+    fn ioctl(self: Arc<Self>, request: u64, arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	self.inner.clone().ioctl(request, arg)
+    }
+
+    fn seek(&self, offset: SeekFrom) -> Result<u64, CanonicalError> {
+	self.inner.seek(offset)
+    }
+
+    fn truncate(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    self.inner.clone().truncate(len).await?;
+	    notify(&self.vnode, InotifyMask::Modify);
+	    Ok(())
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	self.inner.clone().readdir()
+    }
+
+    fn fsync(self: Arc<Self>) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	self.inner.clone().fsync()
+    }
+
+    fn epoll_watchers(&self) -> Option<&epoll::EpollWatchers> {
+	self.inner.epoll_watchers()
+    }
+}
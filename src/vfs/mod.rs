@@ -2,6 +2,9 @@ mod traverse;
 pub mod filesystem;
 mod mount;
 pub mod fifo;
+pub mod epoll;
+pub mod inotify;
+pub mod socket;
 
-pub use traverse::vfs_open;
-pub use mount::{mount, mount_root, init};
+pub use traverse::{vfs_open, vfs_walk_path, vfs_walk_path_nofollow, vfs_create, vfs_symlink, vfs_unlink, vfs_rename, absolute_path};
+pub use mount::{mount, mount_root, sync, init};
@@ -1,5 +1,6 @@
 use alloc::sync::{Arc, Weak};
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering;
 use spin::{Once, RwLock};
@@ -119,6 +120,20 @@ impl MountTable {
 	}
     }
 
+    // Every mounted filesystem, root first, along with the instance it's mounted as
+    pub fn filesystems(&self) -> Vec<(Arc<dyn FileSystem>, FileSystemInstance)> {
+	let mut filesystems = Vec::new();
+	if let Ok(root) = self.root() {
+	    filesystems.push((root, FileSystemInstance(0)));
+	}
+
+	filesystems.extend(self.mounts_by_root
+	    .read()
+	    .values()
+	    .map(|m| (m.fs.clone(), m.fsi)));
+	filesystems
+    }
+
     pub fn mount_root(&self, fs: Arc<dyn FileSystem>) -> Result<(), CanonicalError> {
 	let mut root = self.root_fs.write();
 
@@ -146,3 +161,17 @@ pub async fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), CanonicalE
 pub fn mount_root(fs: Arc<dyn FileSystem>) -> Result<(), CanonicalError> {
     MOUNT_TABLE.get().expect("Accessed mount table before init").mount_root(fs)
 }
+
+// Syncs every mounted filesystem. All of them are synced even if one fails, and the first error is returned
+pub async fn sync() -> Result<(), CanonicalError> {
+    let filesystems = MOUNT_TABLE.get().expect("Accessed mount table before init").filesystems();
+
+    let mut result = Ok(());
+    for (fs, fsi) in filesystems {
+	if let Err(e) = fs.sync(fsi).await {
+	    result = result.and(Err(e));
+	}
+    }
+
+    result
+}
@@ -0,0 +1,418 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use core::cmp;
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use spin::Mutex;
+
+use crate::process::FileDescriptor;
+use crate::syscall::{CanonicalError, PollEvents};
+use crate::vfs::filesystem::{DirEntry, SeekFrom, Stat, FileHandle, VNodeKind};
+
+// As for pipes. Sends beyond this are only partially accepted, or wait for the peer to make room
+const SOCKET_CAPACITY: usize = 65536;
+
+// The sender as given with SCM_CREDENTIALS. There are no users yet, so the ids are always 0
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Control messages travelling alongside the data
+#[derive(Default)]
+pub struct Ancillary {
+    pub rights: Vec<FileDescriptor>,
+    pub credentials: Option<Credentials>,
+}
+
+impl Ancillary {
+    pub fn is_empty(&self) -> bool {
+	self.rights.is_empty() && self.credentials.is_none()
+    }
+}
+
+pub struct Received {
+    pub data: Bytes,
+    pub ancillary: Ancillary,
+    // How long the datagram was before being cut down to fit, which recvmsg reports with MSG_TRUNC
+    pub len: usize,
+}
+
+// What was sent in one go, along with anything passed with it
+struct Segment {
+    data: Bytes,
+    ancillary: Ancillary,
+}
+
+// Data travelling in one direction between the two ends of a socket pair
+struct Queue {
+    segments: VecDeque<Segment>,
+    len: usize,
+    // Everything waiting to read or write, all of which are woken, as there may be more than one
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+    // Once the reading end has gone, sends fail. Once the writing end has, reads see end of file
+    reader_gone: bool,
+    writer_gone: bool,
+}
+
+impl Queue {
+    fn new() -> Self {
+	Queue {
+	    segments: VecDeque::new(),
+	    len: 0,
+	    read_wakers: Vec::new(),
+	    write_wakers: Vec::new(),
+	    reader_gone: false,
+	    writer_gone: false,
+	}
+    }
+}
+
+// One end of a connected AF_UNIX socket, as made by socketpair. Stream sockets carry bytes, while datagram (and
+// seqpacket, which on a connected pair is the same thing) ones keep each send apart as one message
+pub struct UnixSocket {
+    incoming: Arc<Mutex<Queue>>,
+    outgoing: Arc<Mutex<Queue>>,
+    datagram: bool,
+}
+
+impl UnixSocket {
+    pub fn pair(datagram: bool) -> (Arc<UnixSocket>, Arc<UnixSocket>) {
+	let a_to_b = Arc::new(Mutex::new(Queue::new()));
+	let b_to_a = Arc::new(Mutex::new(Queue::new()));
+
+	(Arc::new(UnixSocket {
+	    incoming: b_to_a.clone(),
+	    outgoing: a_to_b.clone(),
+	    datagram,
+	}), Arc::new(UnixSocket {
+	    incoming: a_to_b,
+	    outgoing: b_to_a,
+	    datagram,
+	}))
+    }
+
+    // On a stream socket, accepts as much of data as fits, which may be less than all of it, and waits only if the
+    // peer's queue is already full. Any ancillary data goes along with the first byte accepted. A datagram is only
+    // ever taken whole, so waits until there's room for all of it.
+    //
+    // A socket in flight is only let go of once it's read, or its reader goes. So the peer can't be sent over us: it
+    // would be its own reader, and once closed, nothing could ever free it. Longer cycles, e.g. two pairs each sent
+    // over the other, aren't caught, and leak until something reads them.
+    pub fn send(self: Arc<Self>, data: Bytes, ancillary: Ancillary) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	let to_itself = ancillary.rights.iter()
+	    .filter_map(|fd| fd.file_handle.clone().as_socket())
+	    .any(|socket| Arc::ptr_eq(&socket.incoming, &self.outgoing));
+	if to_itself {
+	    return Box::pin(async { Err(CanonicalError::Inval) });
+	}
+
+	let mut ancillary = Some(ancillary);
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    if self.datagram && data.len() > SOCKET_CAPACITY {
+		return Poll::Ready(Err(CanonicalError::MsgSize));
+	    }
+
+	    let (sent, wakers) = {
+		let mut queue = self.outgoing.lock();
+		if queue.reader_gone {
+		    return Poll::Ready(Err(CanonicalError::Pipe));
+		}
+
+		let space = SOCKET_CAPACITY.saturating_sub(queue.len);
+		if space == 0 || (self.datagram && space < data.len()) {
+		    // Registered with the queue still locked, so the peer can't drain it between checking and sleeping
+		    queue.write_wakers.push(cx.waker().clone());
+		    return Poll::Pending;
+		}
+
+		let sent = cmp::min(space, data.len());
+		let ancillary = ancillary.take().unwrap_or_default();
+		// An empty datagram is still a message, but an empty write to a stream is nothing at all
+		if !self.datagram && sent == 0 && ancillary.is_empty() {
+		    return Poll::Ready(Ok(0));
+		}
+
+		queue.segments.push_back(Segment {
+		    data: data.slice(.. sent),
+		    ancillary,
+		});
+		queue.len += sent;
+		(sent, core::mem::take(&mut queue.read_wakers))
+	    };
+
+	    for waker in wakers {
+		waker.wake();
+	    }
+
+	    Poll::Ready(Ok(sent as u64))
+	}))
+    }
+
+    // Reads up to len bytes, waiting if there's nothing to read yet. On a stream socket, a read never goes past the
+    // start of a later send which carried ancillary data, so that it arrives with the data it was sent with. On a
+    // datagram socket, a read takes exactly one message, and whatever of it doesn't fit in len is lost
+    pub fn recv(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<Received, CanonicalError>> {
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    let received = {
+		let mut queue = self.incoming.lock();
+		if queue.segments.is_empty() {
+		    if queue.writer_gone {
+			return Poll::Ready(Ok(Received {
+			    data: Bytes::new(),
+			    ancillary: Ancillary::default(),
+			    len: 0,
+			}));
+		    }
+
+		    queue.read_wakers.push(cx.waker().clone());
+		    return Poll::Pending;
+		}
+
+		if self.datagram {
+		    let segment = queue.segments.pop_front().unwrap();
+		    queue.len -= segment.data.len();
+		    Received {
+			data: segment.data.slice(.. cmp::min(len as usize, segment.data.len())),
+			len: segment.data.len(),
+			ancillary: segment.ancillary,
+		    }
+		} else {
+		    Self::recv_stream(&mut queue, len as usize)
+		}
+	    };
+
+	    // Room has been made for senders waiting on a full queue, though maybe not enough for all of them, which are
+	    // left to find out for themselves
+	    if received.len > 0 {
+		let wakers = core::mem::take(&mut self.incoming.lock().write_wakers);
+		for waker in wakers {
+		    waker.wake();
+		}
+	    }
+
+	    Poll::Ready(Ok(received))
+	}))
+    }
+
+    fn recv_stream(queue: &mut Queue, len: usize) -> Received {
+	let mut data = BytesMut::new();
+	let mut ancillary = Ancillary::default();
+	let mut remaining = len;
+	while let Some(segment) = queue.segments.front_mut() {
+	    if !segment.ancillary.is_empty() {
+		if !data.is_empty() || !ancillary.is_empty() {
+		    break;
+		}
+		ancillary = core::mem::take(&mut segment.ancillary);
+	    }
+
+	    let taken = cmp::min(remaining, segment.data.len());
+	    data.extend_from_slice(&segment.data.split_to(taken));
+	    remaining -= taken;
+
+	    if segment.data.is_empty() {
+		queue.segments.pop_front();
+	    }
+	    if remaining == 0 {
+		break;
+	    }
+	}
+
+	queue.len -= data.len();
+	Received {
+	    len: data.len(),
+	    data: data.freeze(),
+	    ancillary,
+	}
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+	// Nothing will read what's queued for us now, so it's let go, including any descriptors in flight. That's done
+	// after unlocking, as one of those may be the last reference to a socket which then takes our locks to close
+	let (unread, write_wakers) = {
+	    let mut incoming = self.incoming.lock();
+	    incoming.reader_gone = true;
+	    incoming.len = 0;
+	    (core::mem::take(&mut incoming.segments), core::mem::take(&mut incoming.write_wakers))
+	};
+
+	let read_wakers = {
+	    let mut outgoing = self.outgoing.lock();
+	    outgoing.writer_gone = true;
+	    core::mem::take(&mut outgoing.read_wakers)
+	};
+
+	drop(unread);
+	for waker in write_wakers.into_iter().chain(read_wakers) {
+	    waker.wake();
+	}
+    }
+}
+
+impl FileHandle for UnixSocket {
+    fn read(self: Arc<Self>, len: u64) -> BoxFuture<'static, Result<Bytes, CanonicalError>> {
+	// Descriptors passed to something that doesn't use recvmsg are simply closed, as on Linux
+	self.recv(len).map(|r| r.map(|received| received.data)).boxed()
+    }
+
+    fn write(self: Arc<Self>, buf: Bytes) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	self.send(buf, Ancillary::default())
+    }
+
+    fn poll(self: Arc<Self>, events: PollEvents) -> BoxFuture<'static, Result<PollEvents, CanonicalError>> {
+	Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+	    let mut revents = PollEvents::empty();
+
+	    {
+		let mut incoming = self.incoming.lock();
+		if events.contains(PollEvents::In) && (!incoming.segments.is_empty() || incoming.writer_gone) {
+		    revents |= PollEvents::In;
+		}
+		if incoming.writer_gone {
+		    revents |= PollEvents::Hup;
+		}
+		if revents.is_empty() && events.contains(PollEvents::In) {
+		    incoming.read_wakers.push(cx.waker().clone());
+		}
+	    }
+
+	    {
+		let mut outgoing = self.outgoing.lock();
+		if events.contains(PollEvents::Out) && (outgoing.len < SOCKET_CAPACITY || outgoing.reader_gone) {
+		    revents |= PollEvents::Out;
+		}
+		if revents.is_empty() && events.contains(PollEvents::Out) {
+		    outgoing.write_wakers.push(cx.waker().clone());
+		}
+	    }
+
+	    if revents.is_empty() && events.intersects(PollEvents::In | PollEvents::Out) {
+		return Poll::Pending;
+	    }
+
+	    Poll::Ready(Ok(revents))
+	}))
+    }
+
+    fn stat(self: Arc<Self>) -> Result<Stat, CanonicalError> {
+	Ok(Stat {
+	    file_name: String::new(),
+	    size: Some(0),
+	    inode: 0,
+	    kind: VNodeKind::Socket,
+	    mode: 0o777,
+	    modified: None,
+	})
+    }
+
+    fn ioctl(self: Arc<Self>, _request: u64, _arg: u64) -> BoxFuture<'static, Result<u64, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NoTty)
+	}.boxed()
+    }
+
+    fn seek(&self, _offset: SeekFrom) -> Result<u64, CanonicalError> {
+	Err(CanonicalError::SPipe)
+    }
+
+    fn truncate(self: Arc<Self>, _len: u64) -> BoxFuture<'static, Result<(), CanonicalError>> {
+	async move {
+	    Err(CanonicalError::Inval)
+	}.boxed()
+    }
+
+    fn readdir(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<DirEntry>, CanonicalError>> {
+	async move {
+	    Err(CanonicalError::NotDir)
+	}.boxed()
+    }
+
+    fn as_socket(self: Arc<Self>) -> Option<Arc<UnixSocket>> {
+	Some(self)
+    }
+}
+
+#[test]
+fn descriptors_arrive_with_the_data_they_were_sent_with() {
+    use crate::vfs::filesystem::OpenFlags;
+
+    let (a, b) = UnixSocket::pair(false);
+    let (passed, other_end) = UnixSocket::pair(true);
+    let fd = |socket: &Arc<UnixSocket>| FileDescriptor {
+	file_handle: socket.clone(),
+	flags: OpenFlags::empty(),
+    };
+    let rights = |socket: &Arc<UnixSocket>| Ancillary {
+	rights: alloc::vec![fd(socket)],
+	credentials: None,
+    };
+
+    a.clone().send(Bytes::from_static(b"one"), Ancillary::default()).now_or_never().unwrap().unwrap();
+    a.clone().send(Bytes::from_static(b"two"), rights(&passed)).now_or_never().unwrap().unwrap();
+
+    // The first read stops short of the descriptor, so that it comes with what it was sent with
+    let first = b.clone().recv(16).now_or_never().unwrap().unwrap();
+    assert_eq!(&first.data[..], b"one");
+    assert!(first.ancillary.rights.is_empty());
+    let second = b.clone().recv(16).now_or_never().unwrap().unwrap();
+    assert_eq!(&second.data[..], b"two");
+    assert_eq!(second.ancillary.rights.len(), 1);
+
+    // What arrives is the same socket, still connected to its peer
+    let received = second.ancillary.rights[0].file_handle.clone().as_socket().unwrap();
+    assert!(Arc::ptr_eq(&received, &passed));
+    received.send(Bytes::from_static(b"hi"), Ancillary::default()).now_or_never().unwrap().unwrap();
+    assert_eq!(&other_end.clone().recv(16).now_or_never().unwrap().unwrap().data[..], b"hi");
+
+    // b would be its own reader, so could never be freed, but a can be sent, as b can read it
+    let sent = a.clone().send(Bytes::from_static(b"x"), rights(&b)).now_or_never().unwrap();
+    assert!(matches!(sent, Err(CanonicalError::Inval)));
+    a.clone().send(Bytes::from_static(b"x"), rights(&a)).now_or_never().unwrap().unwrap();
+}
+
+#[test]
+fn datagrams_keep_their_boundaries_and_report_their_length() {
+    let (a, b) = UnixSocket::pair(true);
+    let credentials = Credentials {
+	pid: 7,
+	uid: 0,
+	gid: 0,
+    };
+
+    a.clone().send(Bytes::from_static(b"first"), Ancillary::default()).now_or_never().unwrap().unwrap();
+    a.clone().send(Bytes::from_static(b"second message"), Ancillary {
+	rights: Vec::new(),
+	credentials: Some(credentials),
+    }).now_or_never().unwrap().unwrap();
+    a.clone().send(Bytes::new(), Ancillary::default()).now_or_never().unwrap().unwrap();
+
+    // Room for more doesn't run one message into the next
+    let first = b.clone().recv(64).now_or_never().unwrap().unwrap();
+    assert_eq!(&first.data[..], b"first");
+    assert_eq!(first.len, 5);
+    assert!(first.ancillary.credentials.is_none());
+
+    // Too little room cuts the message short, and the rest is lost, but the whole length is still given for
+    // MSG_TRUNC. The credentials come with the message they were sent with.
+    let second = b.clone().recv(6).now_or_never().unwrap().unwrap();
+    assert_eq!(&second.data[..], b"second");
+    assert_eq!(second.len, 14);
+    assert_eq!(second.ancillary.credentials.map(|c| c.pid), Some(7));
+
+    // An empty datagram is a message all the same
+    let empty = b.clone().recv(64).now_or_never().unwrap().unwrap();
+    assert!(empty.data.is_empty() && empty.len == 0);
+    assert!(b.clone().recv(64).now_or_never().is_none());
+}
@@ -1,13 +1,23 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use alloc::string::String;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
 
 use crate::vfs::mount;
-use crate::vfs::filesystem::{VNode, VNodeKind, FileHandle, FileSystemInstance};
+use crate::vfs::inotify;
+use crate::vfs::filesystem::{VNode, VNodeKind, FileHandle, FileSystemInstance, OpenFlags};
+use crate::scheduler;
 use crate::sys::syscall::CanonicalError;
 
-// const MAX_SYMLINK_DEPTH: u8 = 8;
+const MAX_SYMLINK_DEPTH: u8 = 8;
 
-async fn traverse(current: Arc<dyn VNode>, name: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
+fn root_vnode() -> Result<Arc<dyn VNode>, CanonicalError> {
+    Ok(mount::MOUNT_TABLE.get().expect("Attempted to use mount table before init")
+       .root()?.root(FileSystemInstance(0)))
+}
+
+async fn traverse(current: Arc<dyn VNode>, name: &str, follow: bool, depth: u8) -> Result<Arc<dyn VNode>, CanonicalError> {
     if name == "." {
         return Ok(current);
     }
@@ -26,26 +36,23 @@ async fn traverse(current: Arc<dyn VNode>, name: &str) -> Result<Arc<dyn VNode>,
     let fs = current.filesystem();
     let mut child = fs.lookup(current.fsi(), &current, name).await?;
 
-    // Follow symlinks (for intermediate path components)
-//    let mut depth = 0;
-    while child.kind() == VNodeKind::Symlink {
-	unimplemented!();
-        // if depth >= MAX_SYMLINK_DEPTH {
-        //     return Err(CanonicalError::ELOOP);
-        // }
-
-        // let target = child.readlink()?;
+    // Follow symlinks. This is always done for intermediate path components, but is optional for the last one
+    if follow && child.kind() == VNodeKind::Symlink {
+        if depth >= MAX_SYMLINK_DEPTH {
+            return Err(CanonicalError::Loop);
+        }
 
-        // // Symlink targets may be absolute or relative
-        // child = if target.starts_with('/') {
-        //     // Absolute: restart from VFS root
-        //     vfs_walk_path(&target).await?
-        // } else {
-        //     // Relative: resolve against current directory
-        //     vfs_walk_path_relative(&target, current.clone()).await?
-        // };
+        let target = child.readlink()?;
 
-        // depth += 1;
+        // Symlink targets may be absolute or relative. Either way, the target is resolved in full, so anything it
+        // points to will already have been followed by the time we get back here
+        child = if target.starts_with('/') {
+            // Absolute: restart from VFS root
+            walk_from(root_vnode()?, target, true, depth + 1).await?
+        } else {
+            // Relative: resolve against current directory
+            walk_from(current.clone(), target, true, depth + 1).await?
+        };
     }
 
     // If the child is a mountpoint, pick the root up for that FS
@@ -56,55 +63,343 @@ async fn traverse(current: Arc<dyn VNode>, name: &str) -> Result<Arc<dyn VNode>,
     Ok(child)
 }
 
-// async fn vfs_walk_path_relative(path: &str, current: Arc<dyn VNode>) -> Result<Arc<dyn VNode>, CanonicalError> {
-//     let mut current = current.clone();
-//     let components: Vec<&str> = path
-// 	.split('/')
-// 	.filter(|c| !c.is_empty())
-// 	.collect();
+// Boxed, as symlink resolution makes this recursive
+fn walk_from(start: Arc<dyn VNode>, path: String, follow_final: bool, depth: u8) -> BoxFuture<'static, Result<Arc<dyn VNode>, CanonicalError>> {
+    async move {
+	let components: Vec<&str> = path
+	    .split('/')
+	    .filter(|c| !c.is_empty())
+	    .collect();
 
-//     // Cannot open nothing
-//     if components.is_empty() {
-// 	return Err(CanonicalError::Inval);
-//     }
+	let mut current = start;
+	let last = components.len().saturating_sub(1);
+	for (i, component) in components.iter().enumerate() {
+	    current = traverse(current, component, follow_final || i != last, depth).await?;
+	}
 
-//     for component in components {
-// 	current = traverse(current, component).await?;
-//     }
+	Ok(current)
+    }.boxed()
+}
 
-//     Ok(current)
-// }
-    
-pub async fn vfs_walk_path(path: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
-    let mut current = if path.starts_with('/') {
-	mount::MOUNT_TABLE.get().expect("Attempted to use mount table before init")
-	    .root()?.root(FileSystemInstance(0))
+// Where path leads from cwd, with no . or .. left in it. This goes by the names alone, so .. after a symlink goes back
+// to the directory the symlink is in, as a shell's cd does, rather than to the parent of what it points at.
+fn resolve_path(cwd: &str, path: &str) -> String {
+    let from = if path.starts_with('/') {
+	""
     } else {
-	unimplemented!();
-//	current_process.cwd()
+	cwd
     };
 
-    let components: Vec<&str> = path
-	.split('/')
-	.filter(|c| !c.is_empty())
-	.collect();
+    let mut components: Vec<&str> = Vec::new();
+    for component in from.split('/').chain(path.split('/')) {
+	match component {
+	    "" | "." => (),
+	    ".." => {
+		components.pop();
+	    },
+	    _ => components.push(component),
+	}
+    }
+
+    let mut resolved = String::from("/");
+    resolved.push_str(&components.join("/"));
+    resolved
+}
+
+// The absolute path a process means by path, relative paths being from its current directory
+pub fn absolute_path(path: &str) -> String {
+    resolve_path(&scheduler::get_current_process().get_cwd(), path)
+}
 
+async fn walk_path(path: &str, follow_final: bool) -> Result<Arc<dyn VNode>, CanonicalError> {
     // Cannot open nothing
-    if components.is_empty() {
+    if path.split('/').all(|c| c.is_empty()) {
 	return Err(CanonicalError::Inval);
     }
 
-    for component in components {
-	current = traverse(current, component).await?;
+    let path = if path.starts_with('/') {
+	String::from(path)
+    } else {
+	absolute_path(path)
+    };
+
+    walk_from(root_vnode()?, path, follow_final, 0).await
+}
+
+pub async fn vfs_walk_path(path: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
+    walk_path(path, true).await
+}
+
+// As vfs_walk_path, but if the final component is a symlink, returns the link itself rather than its target
+pub async fn vfs_walk_path_nofollow(path: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
+    walk_path(path, false).await
+}
+
+// mode is what a file created by O_CREAT would get, with the caller's umask already applied
+pub async fn vfs_open(path: &str, flags: OpenFlags, mode: u64) -> Result<Arc<dyn FileHandle>, CanonicalError> {
+    // We don't check permissions against the caller's credentials yet, as nothing records who owns a file. That
+    // would go here
+    let walked = if flags.contains(OpenFlags::NoFollow) {
+	vfs_walk_path_nofollow(path).await
+    } else {
+	vfs_walk_path(path).await
+    };
+    let vnode = match walked {
+	Ok(_) if flags.contains(OpenFlags::Creat | OpenFlags::Excl) => return Err(CanonicalError::Exist),
+	Ok(vnode) => vnode,
+	// Another open may create it in between, in which case we open theirs, unless we were asked for a new file
+	Err(CanonicalError::NoEnt) if flags.contains(OpenFlags::Creat) => {
+	    match vfs_create(path, VNodeKind::Regular, mode).await {
+		Err(CanonicalError::Exist) if !flags.contains(OpenFlags::Excl) => vfs_walk_path(path).await?,
+		res => res?,
+	    }
+	},
+	Err(e) => return Err(e),
+    };
+
+    open_vnode(vnode, flags).await
+}
+
+// What's left of opening once the path has been walked, which is checking the flags against what was found
+async fn open_vnode(vnode: Arc<dyn VNode>, flags: OpenFlags) -> Result<Arc<dyn FileHandle>, CanonicalError> {
+    let kind = vnode.kind();
+
+    // Only with O_NOFOLLOW, which is for refusing to go through a link
+    if kind == VNodeKind::Symlink {
+	return Err(CanonicalError::Loop);
+    }
+    if flags.contains(OpenFlags::Directory) && kind != VNodeKind::Directory {
+	return Err(CanonicalError::NotDir);
+    }
+    if kind == VNodeKind::Directory && flags.is_writable() {
+	return Err(CanonicalError::IsDir);
+    }
+
+    let fh: Arc<dyn FileHandle> = Arc::new(inotify::WatchedFileHandle::new(vnode.open()?, vnode, flags.is_writable()));
+
+    // O_TRUNC only has meaning for regular files, and is ignored otherwise
+    if flags.contains(OpenFlags::Trunc) && flags.is_writable() && kind == VNodeKind::Regular {
+	fh.clone().truncate(0).await?;
+    }
+
+    Ok(fh)
+}
+
+// Walks to the directory path is in, returning it along with the name path has in it
+async fn walk_parent(path: &str) -> Result<(Arc<dyn VNode>, String), CanonicalError> {
+    if path.split('/').all(|c| c.is_empty()) {
+	return Err(CanonicalError::Inval);
+    }
+
+    let path = absolute_path(path);
+    let (parent, name) = path.rsplit_once('/').expect("Absolute path has no /");
+    // Only the root has no name, and it can't be created, removed or moved
+    if name.is_empty() {
+	return Err(CanonicalError::Busy);
+    }
+
+    let dir = walk_from(root_vnode()?, String::from(parent), true, 0).await?;
+    Ok((dir, String::from(name)))
+}
+
+// Anything with a filesystem mounted on it stays put until that's unmounted
+fn check_not_mountpoint(vnode: &Arc<dyn VNode>) -> Result<(), CanonicalError> {
+    match mount::MOUNT_TABLE.get().expect("Used mount before init").lookup_mount(vnode) {
+	Some(_) => Err(CanonicalError::Busy),
+	None => Ok(()),
+    }
+}
+
+// The VFS ends of creating, removing and renaming entries, which let inotify know once the filesystem has done it
+async fn create_in(dir: &Arc<dyn VNode>, name: &str, kind: VNodeKind, mode: u64) -> Result<Arc<dyn VNode>, CanonicalError> {
+    if dir.kind() != VNodeKind::Directory {
+	return Err(CanonicalError::NotDir);
+    }
+
+    let vnode = dir.filesystem().create(dir.fsi(), dir, name, kind, mode).await?;
+    inotify::notify_create(dir, name, kind);
+    Ok(vnode)
+}
+
+async fn symlink_in(dir: &Arc<dyn VNode>, name: &str, target: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
+    if dir.kind() != VNodeKind::Directory {
+	return Err(CanonicalError::NotDir);
+    }
+
+    let vnode = dir.filesystem().symlink(dir.fsi(), dir, name, target).await?;
+    inotify::notify_create(dir, name, VNodeKind::Symlink);
+    Ok(vnode)
+}
+
+// directory is whether the caller is rmdir, which only removes directories, or unlink, which removes anything else
+async fn unlink_in(dir: &Arc<dyn VNode>, name: &str, directory: bool) -> Result<(), CanonicalError> {
+    let vnode = dir.filesystem().lookup(dir.fsi(), dir, name).await?;
+    match (directory, vnode.kind() == VNodeKind::Directory) {
+	(true, false) => return Err(CanonicalError::NotDir),
+	(false, true) => return Err(CanonicalError::IsDir),
+	_ => (),
+    }
+    check_not_mountpoint(&vnode)?;
+
+    dir.filesystem().unlink(dir.fsi(), dir, name).await?;
+    inotify::notify_delete(dir, name, &vnode);
+    Ok(())
+}
+
+async fn rename_in(old_dir: &Arc<dyn VNode>, old_name: &str, new_dir: &Arc<dyn VNode>, new_name: &str) -> Result<(), CanonicalError> {
+    if new_dir.kind() != VNodeKind::Directory {
+	return Err(CanonicalError::NotDir);
+    }
+    // Renames can't move anything from one filesystem to another
+    if old_dir.fsi() != new_dir.fsi() {
+	return Err(CanonicalError::XDev);
+    }
+
+    let vnode = old_dir.filesystem().lookup(old_dir.fsi(), old_dir, old_name).await?;
+    check_not_mountpoint(&vnode)?;
+
+    old_dir.filesystem().rename(old_dir.fsi(), old_dir, old_name, new_dir, new_name).await?;
+    inotify::notify_rename(old_dir, old_name, new_dir, new_name, &vnode);
+    Ok(())
+}
+
+pub async fn vfs_create(path: &str, kind: VNodeKind, mode: u64) -> Result<Arc<dyn VNode>, CanonicalError> {
+    let (dir, name) = walk_parent(path).await?;
+    create_in(&dir, &name, kind, mode).await
+}
+
+// Makes path a link to target. Relative targets are from the directory the link is in, whenever it's followed
+pub async fn vfs_symlink(target: &str, path: &str) -> Result<Arc<dyn VNode>, CanonicalError> {
+    if target.is_empty() {
+	return Err(CanonicalError::NoEnt);
     }
 
-    Ok(current)
+    let (dir, name) = walk_parent(path).await?;
+    symlink_in(&dir, &name, target).await
+}
+
+pub async fn vfs_unlink(path: &str, directory: bool) -> Result<(), CanonicalError> {
+    let (dir, name) = walk_parent(path).await?;
+    unlink_in(&dir, &name, directory).await
 }
 
-pub async fn vfs_open(path: &str/*, flags: OpenFlags, mode: FileMode*/) -> Result<Arc<dyn FileHandle>, CanonicalError> {
-    // We don't do file creation yet. It'd go here otherwise
-    // We don't handle symlinking either, that also belongs here
-    // We don't handle permissions (single user OS), that would go here
-    let vnode = vfs_walk_path(path).await?;
-    vnode.open()
+pub async fn vfs_rename(old_path: &str, new_path: &str) -> Result<(), CanonicalError> {
+    let (old_dir, old_name) = walk_parent(old_path).await?;
+    let (new_dir, new_name) = walk_parent(new_path).await?;
+    rename_in(&old_dir, &old_name, &new_dir, &new_name).await
+}
+
+#[test]
+fn absolute_paths_ignore_cwd() {
+    assert_eq!(resolve_path("/home", "/etc/passwd"), "/etc/passwd");
+    assert_eq!(resolve_path("/home", "/etc/./init.d/../passwd"), "/etc/passwd");
+    assert_eq!(resolve_path("/home", "/.."), "/");
+}
+
+#[test]
+fn relative_paths_are_from_cwd() {
+    assert_eq!(resolve_path("/home", "foo/bar"), "/home/foo/bar");
+    assert_eq!(resolve_path("/home/", "./foo//bar/"), "/home/foo/bar");
+    assert_eq!(resolve_path("/home/user", "../other"), "/home/other");
+    assert_eq!(resolve_path("/", "../../foo"), "/foo");
+    assert_eq!(resolve_path("/home", "."), "/home");
+}
+
+#[test]
+fn tmpfs_changes_reach_inotify() {
+    use crate::fs::tmpfs::TmpFs;
+    use crate::vfs::filesystem::FileSystem;
+    use crate::vfs::inotify::{Inotify, InotifyMask, WatchedFileHandle};
+
+    let root = Arc::new(TmpFs::new()).root(FileSystemInstance(0x1982));
+    let watcher = Arc::new(Inotify::new());
+    let dir_wd = watcher.add_watch(&root, InotifyMask::Create | InotifyMask::Delete | InotifyMask::MovedFrom | InotifyMask::MovedTo).unwrap();
+
+    let file = create_in(&root, "a", VNodeKind::Regular, 0o644).now_or_never().unwrap().unwrap();
+    let file_wd = watcher.add_watch(&file, InotifyMask::Modify).unwrap();
+
+    let fh: Arc<dyn FileHandle> = Arc::new(WatchedFileHandle::new(file.clone().open().unwrap(), file.clone(), true));
+    assert_eq!(fh.clone().write(bytes::Bytes::from_static(b"hello")).now_or_never().unwrap().unwrap(), 5);
+    drop(fh);
+
+    rename_in(&root, "a", &root, "b").now_or_never().unwrap().unwrap();
+    assert!(matches!(unlink_in(&root, "b", true).now_or_never().unwrap(), Err(CanonicalError::NotDir)));
+    unlink_in(&root, "b", false).now_or_never().unwrap().unwrap();
+
+    // Each record is wd, mask, cookie and name length, then the NUL padded name
+    let buf = watcher.clone().read(4096).now_or_never().unwrap().unwrap();
+    let mut events = Vec::new();
+    let mut rest = &buf[..];
+    while !rest.is_empty() {
+	let field = |i: usize| u32::from_ne_bytes(rest[i * 4 .. i * 4 + 4].try_into().unwrap());
+	let len = field(3) as usize;
+	let name = core::str::from_utf8(&rest[16 .. 16 + len]).unwrap().trim_end_matches('\0');
+	events.push((field(0) as i32, field(1), String::from(name)));
+	rest = &rest[16 + len ..];
+    }
+
+    assert_eq!(events, [
+	(dir_wd, InotifyMask::Create.bits(), String::from("a")),
+	(file_wd, InotifyMask::Modify.bits(), String::new()),
+	(dir_wd, InotifyMask::MovedFrom.bits(), String::from("a")),
+	(dir_wd, InotifyMask::MovedTo.bits(), String::from("b")),
+	(dir_wd, InotifyMask::Delete.bits(), String::from("b")),
+    ]);
+}
+
+#[test]
+fn symlinks_are_followed_unless_asked_not_to() {
+    use crate::fs::tmpfs::TmpFs;
+    use crate::vfs::filesystem::FileSystem;
+
+    mount::init();
+    let root = Arc::new(TmpFs::new()).root(FileSystemInstance(0x1960));
+    let dir = create_in(&root, "dir", VNodeKind::Directory, 0o755).now_or_never().unwrap().unwrap();
+    let file = create_in(&dir, "file", VNodeKind::Regular, 0o644).now_or_never().unwrap().unwrap();
+    symlink_in(&root, "link", "dir/file").now_or_never().unwrap().unwrap();
+    // A link through a directory which is itself reached by a link
+    symlink_in(&root, "dirlink", "dir").now_or_never().unwrap().unwrap();
+    symlink_in(&root, "again", "dirlink/file").now_or_never().unwrap().unwrap();
+
+    let walk = |path: &str, follow: bool| walk_from(root.clone(), String::from(path), follow, 0).now_or_never().unwrap();
+
+    // As lstat sees it
+    let link = walk("link", false).unwrap();
+    let stat = link.stat().unwrap();
+    assert!(stat.kind == VNodeKind::Symlink);
+    assert_eq!(stat.size, Some(8));
+    assert_eq!(link.readlink().unwrap(), "dir/file");
+
+    // As stat sees it
+    assert_eq!(walk("link", true).unwrap().inode(), file.inode());
+    assert!(walk("link", true).unwrap().stat().unwrap().kind == VNodeKind::Regular);
+    assert_eq!(walk("again", true).unwrap().inode(), file.inode());
+    assert!(walk("again", false).unwrap().kind() == VNodeKind::Symlink);
+
+    // Too many links in a row gives up rather than going round forever
+    symlink_in(&root, "loop", "loop").now_or_never().unwrap().unwrap();
+    assert!(matches!(walk("loop", true), Err(CanonicalError::Loop)));
+    assert!(matches!(link.readlink().and(file.readlink()), Err(CanonicalError::Inval)));
+}
+
+#[test]
+fn open_flags_are_checked_against_what_was_found() {
+    use crate::fs::tmpfs::TmpFs;
+    use crate::vfs::filesystem::{AccessMode, FileSystem};
+
+    let root = Arc::new(TmpFs::new()).root(FileSystemInstance(0x1957));
+    let file = create_in(&root, "file", VNodeKind::Regular, 0o644).now_or_never().unwrap().unwrap();
+    let open = |flags: OpenFlags| open_vnode(file.clone(), flags).now_or_never().unwrap();
+
+    let fh = open(OpenFlags::empty().with_access_mode(AccessMode::WrOnly)).unwrap();
+    assert_eq!(fh.write(bytes::Bytes::from_static(b"contents")).now_or_never().unwrap().unwrap(), 8);
+    assert_eq!(file.stat().unwrap().size, Some(8));
+
+    assert!(matches!(open(OpenFlags::Directory), Err(CanonicalError::NotDir)));
+
+    // Truncating needs the file open for writing, so a read only open leaves it alone
+    open(OpenFlags::Trunc).unwrap();
+    assert_eq!(file.stat().unwrap().size, Some(8));
+    open(OpenFlags::Trunc.with_access_mode(AccessMode::RdWr)).unwrap();
+    assert_eq!(file.stat().unwrap().size, Some(0));
 }
@@ -4,3 +4,4 @@
 #include <uacpi/utilities.h>
 #include <uacpi/resources.h>
 #include <uacpi/tables.h>
+#include <uacpi/sleep.h>